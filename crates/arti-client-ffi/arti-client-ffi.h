@@ -0,0 +1,413 @@
+/**
+ * # Arti client library header.
+ *
+ * This library lets programs that are not written in Rust
+ * embed a Tor client, and make anonymized connections with it.
+ *
+ * ## Using this library
+ *
+ * - Call `arti_client_new()` to create an `ArtiClient`.
+ * - Optionally, call `arti_client_bootstrap()` to bootstrap it,
+ *   with a callback to report its progress.
+ *   (Otherwise, the client bootstraps itself when it is first used.)
+ * - Call `arti_client_connect()` to open an `ArtiClientStream`
+ *   to a host and port.
+ * - Use `arti_client_stream_read()`, `arti_client_stream_write()`,
+ *   and `arti_client_stream_flush()` to exchange data, much as you would
+ *   with a socket.
+ * - Free streams with `arti_client_stream_free()`,
+ *   and the client with `arti_client_free()`.
+ *
+ * Every function in this library blocks the calling thread until it is done.
+ * Each client runs its own asynchronous runtime;
+ * these functions must not be called from inside one.
+ *
+ * Except when noted otherwise, all functions in this library are thread-safe.
+ *
+ * ## Error handling
+ *
+ * On success, fallible functions return `ARTI_CLIENT_STATUS_SUCCESS`.  On failure,
+ * they return some other status code, and set an `* error_out` parameter
+ * to a newly allocated `ArtiClientError` object.
+ * (If `error_out==NULL`, then no error is allocated.)
+ *
+ * You can access information about an `ArtiClientError`
+ * by calling `arti_client_err_{status,message,os_error_code}()` on it.
+ * When you are done with an error, you should free it with
+ * `arti_client_err_free()`.
+ *
+ * The `error_out` parameter always appears last.
+ *
+ * ## Interface conventions
+ *
+ * - All functions check for NULL pointers in their arguments.
+ *   - As in C tor, `foo_free()` functions treat `foo_free(NULL)` as a no-op.
+ *
+ * - All input strings should be valid UTF-8.  (The library will check.)
+ *   All output strings will be valid UTF-8.
+ *
+ * - All identifiers are prefixed with `ARTI_CLIENT`, `ArtiClient`, or `arti_client`
+ *   as appropriate.
+ *
+ * - Newly allocated objects are returned via out-parameters,
+ *   with `out` in their names.
+ *   In such cases, `* out` will be set to a resulting object,
+ *   or to NULL if no such object is returned.   Any earlier value of `*out` will be replaced
+ *   without freeing it.
+ *   (If `out` is NULL, then any object the library would have returned will instead be
+ *   discarded.)
+ *
+ * - When any object is exposed as a non-const pointer,
+ *   the application becomes the owner of that object.
+ *   The application is expected to eventually free that object via the corresponding
+ *   `arti_client_*_free()` function.
+ *
+ * - Whenever a function returns an error, it returns no other newly allocated objects
+ *   besides the error object itself.
+ *
+ * ## Correctness requirements
+ *
+ * If any correctness requirements stated here or elsewhere are violated,
+ * it is Undefined Behaviour.
+ * Violations will not be detected by the library.
+ *
+ * - If you pass a non-NULL pointer to a function, the pointer must be properly aligned.
+ *   It must point to valid, initialized data of the correct type.
+ *   - As an exception, functions that take a `Type **out` parameter allow the value of `*out`
+ *     (but not `out` itself!) to be uninitialized.
+ * - You may not call any `_free()` function on an object that is currently in use.
+ * - After you have `_freed()` an object, you may not use it again.
+ * - Every object allocated by this library has a corresponding `*_free()` function:
+ *   You must not use libc's free() to free such objects.
+ * - All `const char*` passed as inputs to library functions
+ *   are nul-terminated strings.
+ **/
+
+#ifndef ARTI_CLIENT_FFI_H_
+#define ARTI_CLIENT_FFI_H_
+
+/* Automatically generated by cbindgen. Don't modify manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A Tor client.
+ *
+ * This is a thread-safe type: you may safely use it from multiple threads at once.
+ *
+ * Once you are no longer going to use this client at all, you must free
+ * it with [`arti_client_free`].
+ * Streams that you opened with it keep working until you free them.
+ */
+typedef struct ArtiClient ArtiClient;
+
+/**
+ * A status code returned by an `arti_client` function.
+ *
+ * On success, a function will return `ARTI_CLIENT_STATUS_SUCCESS (0)`.
+ * On failure, a function will return some other status code.
+ */
+typedef uint32_t ArtiClientStatus;
+
+/**
+ * An error returned by the Arti client code, exposed as an object.
+ *
+ * When a function returns an [`ArtiClientStatus`] other than [`ARTI_CLIENT_STATUS_SUCCESS`],
+ * it will also expose a newly allocated value of this type
+ * via its `error_out` parameter.
+ */
+typedef struct ArtiClientError ArtiClientError;
+
+/**
+ * A function to be called with reports of a client's bootstrap progress.
+ *
+ * The arguments are the `user_data` that was passed to [`arti_client_bootstrap`],
+ * the fraction of bootstrapping that is done (between 0.0 and 1.0),
+ * and a human-readable description of the client's status.
+ * The description is only valid until the function returns.
+ */
+typedef void (*ArtiClientBootstrapCallback)(void *user_data, float progress, const char *message);
+
+/**
+ * An anonymized stream, opened with [`arti_client_connect`].
+ *
+ * This is a thread-safe type: you may safely use it from multiple threads at once.
+ * One thread may read from the stream while another one writes to it.
+ *
+ * Once you are done with this stream, you must free it with [`arti_client_stream_free`].
+ */
+typedef struct ArtiClientStream ArtiClientStream;
+
+/**
+ * The function has returned successfully.
+ */
+#define ARTI_CLIENT_STATUS_SUCCESS 0
+
+/**
+ * One or more of the inputs to a library function was invalid.
+ */
+#define ARTI_CLIENT_STATUS_INVALID_INPUT 1
+
+/**
+ * Tried to use some functionality that isn't available in this build.
+ */
+#define ARTI_CLIENT_STATUS_NOT_SUPPORTED 2
+
+/**
+ * The client's configuration was invalid.
+ */
+#define ARTI_CLIENT_STATUS_BAD_CONFIG 3
+
+/**
+ * We couldn't use the client's state or cache directory.
+ *
+ * This may mean that another client is already using them,
+ * or that their permissions are too loose.
+ */
+#define ARTI_CLIENT_STATUS_LOCAL_STATE 4
+
+/**
+ * The client couldn't bootstrap, or isn't bootstrapped enough to do what we asked.
+ */
+#define ARTI_CLIENT_STATUS_BOOTSTRAP_FAILED 5
+
+/**
+ * We couldn't reach the Tor network, or couldn't build a circuit through it.
+ */
+#define ARTI_CLIENT_STATUS_TOR_ACCESS_FAILED 6
+
+/**
+ * We reached the Tor network, but couldn't connect to the target.
+ */
+#define ARTI_CLIENT_STATUS_CONNECT_FAILED 7
+
+/**
+ * The target address was invalid, or we refused to connect to it.
+ */
+#define ARTI_CLIENT_STATUS_BAD_TARGET 8
+
+/**
+ * An error occurred while reading from or writing to a stream.
+ */
+#define ARTI_CLIENT_STATUS_STREAM_IO 9
+
+/**
+ * The client is shutting down.
+ */
+#define ARTI_CLIENT_STATUS_SHUTDOWN 10
+
+/**
+ * An internal error occurred.
+ *
+ * If you see this, there is probably a bug in Arti.
+ */
+#define ARTI_CLIENT_STATUS_INTERNAL 11
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Try to create a new `ArtiClient`, without bootstrapping it.
+ *
+ * If `state_dir` and `cache_dir` are both NULL, the client uses the default
+ * configuration.  Otherwise, they must both be set, and the client keeps its
+ * persistent state and its cache in them.
+ *
+ * The client bootstraps itself when it is first used;
+ * call [`arti_client_bootstrap`] to do so earlier, or to see its progress.
+ *
+ * On success, return `ARTI_CLIENT_STATUS_SUCCESS` and set `*client_out`
+ * to a new `ArtiClient`.
+ * Otherwise return some other status code, set `*client_out` to NULL, and set
+ * `*error_out` (if provided) to a newly allocated error object.
+ *
+ * # Ownership
+ *
+ * The caller is responsible for making sure that `*client_out` and `*error_out`,
+ * if set, are eventually freed.
+ */
+ArtiClientStatus arti_client_new(const char *state_dir,
+                                 const char *cache_dir,
+                                 struct ArtiClient **client_out,
+                                 ArtiClientError **error_out);
+
+/**
+ * Bootstrap `client`, if it isn't bootstrapped already.
+ *
+ * Returns once the client has enough directory information
+ * to connect safely over the Tor network.
+ *
+ * If `callback` is not NULL, it is called with `user_data`
+ * every time that the client's bootstrap status changes,
+ * and once more before this function returns.
+ * It is called from the thread that called this function.
+ *
+ * On success, return `ARTI_CLIENT_STATUS_SUCCESS`.
+ * Otherwise return some other status code, and set
+ * `*error_out` (if provided) to a newly allocated error object.
+ *
+ * # Correctness requirements
+ *
+ * `user_data` is never dereferenced by this library;
+ * it is only passed to `callback`.
+ */
+ArtiClientStatus arti_client_bootstrap(const struct ArtiClient *client,
+                                       ArtiClientBootstrapCallback callback,
+                                       void *user_data,
+                                       ArtiClientError **error_out);
+
+/**
+ * Launch an anonymized connection to `hostname` and `port` over the Tor network.
+ *
+ * `hostname` may be a DNS name, an IP address, or an onion service address
+ * (if this library was built with onion service support).
+ * If it is a DNS name, it is resolved by the exit relay,
+ * so that no DNS requests leak from this host.
+ *
+ * On success, return `ARTI_CLIENT_STATUS_SUCCESS` and set `*stream_out`
+ * to a new `ArtiClientStream`.
+ * Otherwise return some other status code, set `*stream_out` to NULL, and set
+ * `*error_out` (if provided) to a newly allocated error object.
+ *
+ * # Ownership
+ *
+ * The caller is responsible for making sure that `*stream_out` and `*error_out`,
+ * if set, are eventually freed.
+ */
+ArtiClientStatus arti_client_connect(const struct ArtiClient *client,
+                                     const char *hostname,
+                                     uint16_t port,
+                                     struct ArtiClientStream **stream_out,
+                                     ArtiClientError **error_out);
+
+/**
+ * Read up to `len` bytes from `stream` into `buf`.
+ *
+ * Blocks until at least one byte is available, or until the stream is closed.
+ *
+ * On success, return `ARTI_CLIENT_STATUS_SUCCESS`, and set `*n_read_out`
+ * to the number of bytes that were read.
+ * If the stream has been closed by the other side, this is 0.
+ * Otherwise return some other status code, set `*n_read_out` to 0, and set
+ * `*error_out` (if provided) to a newly allocated error object.
+ *
+ * # Correctness requirements
+ *
+ * `buf` must point to at least `len` bytes of writable memory.
+ * It may be NULL only if `len` is 0.
+ */
+ArtiClientStatus arti_client_stream_read(const struct ArtiClientStream *stream,
+                                         uint8_t *buf,
+                                         uintptr_t len,
+                                         uintptr_t *n_read_out,
+                                         ArtiClientError **error_out);
+
+/**
+ * Write up to `len` bytes from `buf` to `stream`.
+ *
+ * Data that is written may be buffered: use [`arti_client_stream_flush`]
+ * to make sure that it has been sent.
+ *
+ * On success, return `ARTI_CLIENT_STATUS_SUCCESS`, and set `*n_written_out`
+ * to the number of bytes that were written.
+ * Otherwise return some other status code, set `*n_written_out` to 0, and set
+ * `*error_out` (if provided) to a newly allocated error object.
+ *
+ * # Correctness requirements
+ *
+ * `buf` must point to at least `len` bytes of readable memory.
+ * It may be NULL only if `len` is 0.
+ */
+ArtiClientStatus arti_client_stream_write(const struct ArtiClientStream *stream,
+                                          const uint8_t *buf,
+                                          uintptr_t len,
+                                          uintptr_t *n_written_out,
+                                          ArtiClientError **error_out);
+
+/**
+ * Send any data that has been written to `stream`, but not sent yet.
+ *
+ * On success, return `ARTI_CLIENT_STATUS_SUCCESS`.
+ * Otherwise return some other status code, and set
+ * `*error_out` (if provided) to a newly allocated error object.
+ */
+ArtiClientStatus arti_client_stream_flush(const struct ArtiClientStream *stream,
+                                          ArtiClientError **error_out);
+
+/**
+ * Close `stream`, and release the storage that it holds.
+ */
+void arti_client_stream_free(struct ArtiClientStream *stream);
+
+/**
+ * Release storage held by an `ArtiClient`.
+ */
+void arti_client_free(struct ArtiClient *client);
+
+/**
+ * Return a string representing the meaning of a given `ArtiClientStatus`.
+ *
+ * The result will always be non-NULL, even if the status is unrecognized.
+ */
+const char *arti_client_status_to_str(ArtiClientStatus status);
+
+/**
+ * Return the status code associated with a given error.
+ *
+ * If `err` is NULL, return [`ARTI_CLIENT_STATUS_INVALID_INPUT`].
+ */
+ArtiClientStatus arti_client_err_status(const ArtiClientError *err);
+
+/**
+ * Return the OS error code underlying `err`, if any.
+ *
+ * This is typically an `errno` on unix-like systems , or the result of `GetLastError()`
+ * on Windows.  It is only present when `err` was caused by the failure of some
+ * OS library call.
+ *
+ * Returns 0 if `err` is NULL, or if `err` was not caused by the failure of an
+ * OS library call.
+ */
+int arti_client_err_os_error_code(const ArtiClientError *err);
+
+/**
+ * Return a human-readable error message associated with a given error.
+ *
+ * The format of these messages may change arbitrarily between versions of this library;
+ * it is a mistake to depend on the actual contents of this message.
+ *
+ * Return NULL if the input `err` is NULL.
+ *
+ * # Correctness requirements
+ *
+ * The resulting string pointer is valid only for as long as the input `err` is not freed.
+ */
+const char *arti_client_err_message(const ArtiClientError *err);
+
+/**
+ * Make and return copy of a provided error.
+ *
+ * Return NULL if the input is NULL.
+ *
+ * # Ownership
+ *
+ * The caller is responsible for making sure that the returned object
+ * is eventually freed with `arti_client_err_free()`.
+ */
+ArtiClientError *arti_client_err_clone(const ArtiClientError *err);
+
+/**
+ * Release storage held by a provided error.
+ */
+void arti_client_err_free(ArtiClientError *err);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ARTI_CLIENT_FFI_H_ */
+/* Generated by cbindgen 0.28.0 */
@@ -0,0 +1,413 @@
+//! Error handling logic for our ffi code.
+
+use std::error::Error as StdError;
+use std::ffi::{c_char, c_int, CString};
+use std::fmt::Display;
+use std::io::Error as IoError;
+
+use arti_ffi_util::{ffi_body_raw, FfiStatusError, InvalidInput};
+use tor_error::{ErrorKind, HasKind as _};
+
+/// A status code returned by an `arti_client` function.
+///
+/// On success, a function will return `ARTI_CLIENT_STATUS_SUCCESS (0)`.
+/// On failure, a function will return some other status code.
+pub type ArtiClientStatus = u32;
+
+/// The function has returned successfully.
+pub const ARTI_CLIENT_STATUS_SUCCESS: ArtiClientStatus = 0;
+
+/// One or more of the inputs to a library function was invalid.
+pub const ARTI_CLIENT_STATUS_INVALID_INPUT: ArtiClientStatus = 1;
+
+/// Tried to use some functionality that isn't available in this build.
+pub const ARTI_CLIENT_STATUS_NOT_SUPPORTED: ArtiClientStatus = 2;
+
+/// The client's configuration was invalid.
+pub const ARTI_CLIENT_STATUS_BAD_CONFIG: ArtiClientStatus = 3;
+
+/// We couldn't use the client's state or cache directory.
+///
+/// This may mean that another client is already using them,
+/// or that their permissions are too loose.
+pub const ARTI_CLIENT_STATUS_LOCAL_STATE: ArtiClientStatus = 4;
+
+/// The client couldn't bootstrap, or isn't bootstrapped enough to do what we asked.
+pub const ARTI_CLIENT_STATUS_BOOTSTRAP_FAILED: ArtiClientStatus = 5;
+
+/// We couldn't reach the Tor network, or couldn't build a circuit through it.
+pub const ARTI_CLIENT_STATUS_TOR_ACCESS_FAILED: ArtiClientStatus = 6;
+
+/// We reached the Tor network, but couldn't connect to the target.
+pub const ARTI_CLIENT_STATUS_CONNECT_FAILED: ArtiClientStatus = 7;
+
+/// The target address was invalid, or we refused to connect to it.
+pub const ARTI_CLIENT_STATUS_BAD_TARGET: ArtiClientStatus = 8;
+
+/// An error occurred while reading from or writing to a stream.
+pub const ARTI_CLIENT_STATUS_STREAM_IO: ArtiClientStatus = 9;
+
+/// The client is shutting down.
+pub const ARTI_CLIENT_STATUS_SHUTDOWN: ArtiClientStatus = 10;
+
+/// An internal error occurred.
+///
+/// If you see this, there is probably a bug in Arti.
+pub const ARTI_CLIENT_STATUS_INTERNAL: ArtiClientStatus = 11;
+
+/// Return a string representing the meaning of a given `ArtiClientStatus`.
+///
+/// The result will always be non-NULL, even if the status is unrecognized.
+#[no_mangle]
+pub extern "C" fn arti_client_status_to_str(status: ArtiClientStatus) -> *const c_char {
+    match status {
+        ARTI_CLIENT_STATUS_SUCCESS => c"Success",
+        ARTI_CLIENT_STATUS_INVALID_INPUT => c"Invalid input",
+        ARTI_CLIENT_STATUS_NOT_SUPPORTED => c"Not supported",
+        ARTI_CLIENT_STATUS_BAD_CONFIG => c"Invalid configuration",
+        ARTI_CLIENT_STATUS_LOCAL_STATE => c"Unable to use state or cache directory",
+        ARTI_CLIENT_STATUS_BOOTSTRAP_FAILED => c"Unable to bootstrap",
+        ARTI_CLIENT_STATUS_TOR_ACCESS_FAILED => c"Unable to access the Tor network",
+        ARTI_CLIENT_STATUS_CONNECT_FAILED => c"Unable to connect to target",
+        ARTI_CLIENT_STATUS_BAD_TARGET => c"Invalid or forbidden target",
+        ARTI_CLIENT_STATUS_STREAM_IO => c"Stream IO error",
+        ARTI_CLIENT_STATUS_SHUTDOWN => c"Client is shutting down",
+        ARTI_CLIENT_STATUS_INTERNAL => c"Internal error; possible bug?",
+        _ => c"(unrecognized status)",
+    }
+    .as_ptr()
+}
+
+/// An error as returned by the Arti client FFI code.
+#[derive(Debug, Clone)]
+pub struct FfiError {
+    /// The status of this error.
+    pub(crate) status: ArtiClientStatus,
+    /// A human-readable message describing this error.
+    message: CString,
+    /// If present, the OS error code that caused this error.
+    //
+    // (Actually, this should be RawOsError, but that type isn't stable.)
+    os_error_code: Option<i32>,
+}
+
+/// Convenience trait to help implement `Into<FfiError>`
+///
+/// Any error that implements this trait will be convertible into an [`FfiError`].
+pub(crate) trait IntoFfiError: Display + Sized {
+    /// Return the status
+    fn status(&self) -> ArtiClientStatus;
+    /// Return this type as an Error, if it is one.
+    fn as_error(&self) -> Option<&(dyn StdError + 'static)>;
+    /// Return a message for this error.
+    ///
+    /// By default, uses the Display of this error, and of its sources, to build a string.
+    /// The format and content of this string is not specified, and is not guaranteed
+    /// to remain stable.
+    fn message(&self) -> String {
+        use tor_error::ErrorReport as _;
+        match self.as_error() {
+            Some(e) => {
+                let msg = e.report().to_string();
+                msg.strip_prefix("error: ")
+                    .map(str::to_string)
+                    .unwrap_or_else(|| msg)
+            }
+            None => self.to_string(),
+        }
+    }
+    /// Return the OS error code (if any) underlying this error.
+    ///
+    /// On unix-like platforms, this is an `errno`; on Windows, it's a
+    /// code from `GetLastError.`
+    fn os_error_code(&self) -> Option<i32> {
+        let mut err = self.as_error()?;
+
+        loop {
+            if let Some(io_error) = err.downcast_ref::<IoError>() {
+                return io_error.raw_os_error() as Option<i32>;
+            }
+            err = err.source()?;
+        }
+    }
+}
+impl<T: IntoFfiError> From<T> for FfiError {
+    fn from(value: T) -> Self {
+        let status = value.status();
+        // Error messages shouldn't contain NULs, but if one does,
+        // we don't want to lose the whole message.
+        let message = CString::new(value.message().replace('\0', "\u{FFFD}"))
+            .expect("NUL in message after removing NULs?");
+        let os_error_code = value.os_error_code();
+        Self {
+            status,
+            message,
+            os_error_code,
+        }
+    }
+}
+impl From<void::Void> for FfiError {
+    fn from(value: void::Void) -> Self {
+        void::unreachable(value)
+    }
+}
+impl FfiStatusError for FfiError {
+    type Status = ArtiClientStatus;
+    const SUCCESS: ArtiClientStatus = ARTI_CLIENT_STATUS_SUCCESS;
+    fn status(&self) -> ArtiClientStatus {
+        self.status
+    }
+}
+
+impl IntoFfiError for InvalidInput {
+    fn status(&self) -> ArtiClientStatus {
+        ARTI_CLIENT_STATUS_INVALID_INPUT
+    }
+    fn as_error(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self)
+    }
+}
+
+/// Return the status that we report for an error of kind `kind`.
+fn status_for_kind(kind: ErrorKind) -> ArtiClientStatus {
+    use ErrorKind as EK;
+    match kind {
+        EK::NotImplemented | EK::FeatureDisabled => ARTI_CLIENT_STATUS_NOT_SUPPORTED,
+        EK::InvalidConfig | EK::InvalidConfigTransition | EK::NoHomeDirectory => {
+            ARTI_CLIENT_STATUS_BAD_CONFIG
+        }
+        EK::PersistentStateAccessFailed
+        | EK::PersistentStateCorrupted
+        | EK::CacheAccessFailed
+        | EK::CacheCorrupted
+        | EK::KeystoreAccessFailed
+        | EK::KeystoreCorrupted
+        | EK::LocalResourceAlreadyInUse
+        | EK::FsPermissions => ARTI_CLIENT_STATUS_LOCAL_STATE,
+        EK::BootstrapRequired | EK::DirectoryExpired | EK::TorDirectoryUnusable | EK::ClockSkew => {
+            ARTI_CLIENT_STATUS_BOOTSTRAP_FAILED
+        }
+        EK::RemoteConnectionRefused
+        | EK::RemoteHostNotFound
+        | EK::RemoteHostResolutionFailed
+        | EK::RemoteNetworkFailed
+        | EK::RemoteNetworkTimeout
+        | EK::RemoteStreamClosed
+        | EK::RemoteStreamReset
+        | EK::RemoteStreamError
+        | EK::RemoteProtocolViolation
+        | EK::ExitPolicyRejected
+        | EK::ExitTimeout
+        | EK::OnionServiceNotFound
+        | EK::OnionServiceNotRunning
+        | EK::OnionServiceProtocolViolation
+        | EK::OnionServiceConnectionFailed
+        | EK::OnionServiceMissingClientAuth
+        | EK::OnionServiceWrongClientAuth => ARTI_CLIENT_STATUS_CONNECT_FAILED,
+        EK::InvalidStreamTarget | EK::ForbiddenStreamTarget | EK::OnionServiceAddressInvalid => {
+            ARTI_CLIENT_STATUS_BAD_TARGET
+        }
+        EK::ArtiShuttingDown | EK::ReactorShuttingDown => ARTI_CLIENT_STATUS_SHUTDOWN,
+        EK::Internal | EK::BadApiUsage => ARTI_CLIENT_STATUS_INTERNAL,
+        _ => ARTI_CLIENT_STATUS_TOR_ACCESS_FAILED,
+    }
+}
+
+impl IntoFfiError for arti_client::Error {
+    fn status(&self) -> ArtiClientStatus {
+        status_for_kind(self.kind())
+    }
+    fn as_error(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self)
+    }
+}
+
+impl IntoFfiError for arti_client::config::ConfigBuildError {
+    fn status(&self) -> ArtiClientStatus {
+        ARTI_CLIENT_STATUS_BAD_CONFIG
+    }
+    fn as_error(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self)
+    }
+}
+
+impl IntoFfiError for IoError {
+    fn status(&self) -> ArtiClientStatus {
+        ARTI_CLIENT_STATUS_STREAM_IO
+    }
+    fn as_error(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self)
+    }
+}
+
+/// An error returned by the Arti client code, exposed as an object.
+///
+/// When a function returns an [`ArtiClientStatus`] other than [`ARTI_CLIENT_STATUS_SUCCESS`],
+/// it will also expose a newly allocated value of this type
+/// via its `error_out` parameter.
+pub type ArtiClientError = FfiError;
+
+/// Return the status code associated with a given error.
+///
+/// If `err` is NULL, return [`ARTI_CLIENT_STATUS_INVALID_INPUT`].
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_err_status(err: *const ArtiClientError) -> ArtiClientStatus {
+    ffi_body_raw!(
+        {
+            let err: Option<&ArtiClientError> [in_ptr_opt];
+        } in {
+            err.map(|e| e.status)
+               .unwrap_or(ARTI_CLIENT_STATUS_INVALID_INPUT)
+            // Safety: Return value is ArtiClientStatus; trivially safe.
+        }
+    )
+}
+
+/// Return the OS error code underlying `err`, if any.
+///
+/// This is typically an `errno` on unix-like systems , or the result of `GetLastError()`
+/// on Windows.  It is only present when `err` was caused by the failure of some
+/// OS library call.
+///
+/// Returns 0 if `err` is NULL, or if `err` was not caused by the failure of an
+/// OS library call.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_err_os_error_code(err: *const ArtiClientError) -> c_int {
+    ffi_body_raw!(
+        {
+            let err: Option<&ArtiClientError> [in_ptr_opt];
+        } in {
+            err.and_then(|e| e.os_error_code)
+               .unwrap_or(0)
+             // Safety: Return value is c_int; trivially safe.
+        }
+    )
+}
+
+/// Return a human-readable error message associated with a given error.
+///
+/// The format of these messages may change arbitrarily between versions of this library;
+/// it is a mistake to depend on the actual contents of this message.
+///
+/// Return NULL if the input `err` is NULL.
+///
+/// # Correctness requirements
+///
+/// The resulting string pointer is valid only for as long as the input `err` is not freed.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_err_message(err: *const ArtiClientError) -> *const c_char {
+    ffi_body_raw!(
+        {
+            let err: Option<&ArtiClientError> [in_ptr_opt];
+        } in {
+            err.map(|e| e.message.as_ptr())
+               .unwrap_or(std::ptr::null())
+            // Safety: returned pointer is null, or semantically borrowed from `err`.
+            // It is only null if `err` was null.
+            // The caller is not allowed to modify it.
+        }
+    )
+}
+
+/// Make and return copy of a provided error.
+///
+/// Return NULL if the input is NULL.
+///
+/// # Ownership
+///
+/// The caller is responsible for making sure that the returned object
+/// is eventually freed with `arti_client_err_free()`.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_err_clone(
+    err: *const ArtiClientError,
+) -> *mut ArtiClientError {
+    ffi_body_raw!(
+        {
+            let err: Option<&ArtiClientError> [in_ptr_opt];
+        } in {
+            err.map(|e| Box::into_raw(Box::new(e.clone())))
+               .unwrap_or(std::ptr::null_mut())
+            // Safety: returned pointer is null, or newly allocated via Box::new().
+            // It is only null if the input was null.
+        }
+    )
+}
+
+/// Release storage held by a provided error.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_err_free(err: *mut ArtiClientError) {
+    ffi_body_raw!(
+        {
+            let err: Option<Box<ArtiClientError>> [in_ptr_consume_opt];
+        } in {
+            drop(err);
+            // Safety: Return value is (); trivially safe.
+            ()
+        }
+    );
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn status_strings() {
+        for status in ARTI_CLIENT_STATUS_SUCCESS..=ARTI_CLIENT_STATUS_INTERNAL {
+            let s = unsafe { CStr::from_ptr(arti_client_status_to_str(status)) };
+            assert_ne!(s.to_str().unwrap(), "(unrecognized status)");
+        }
+        let s = unsafe { CStr::from_ptr(arti_client_status_to_str(9999)) };
+        assert_eq!(s.to_str().unwrap(), "(unrecognized status)");
+    }
+
+    #[test]
+    fn error_accessors() {
+        let err: FfiError = InvalidInput::NullPointer.into();
+        let err = Box::into_raw(Box::new(err));
+        unsafe {
+            assert_eq!(
+                arti_client_err_status(err),
+                ARTI_CLIENT_STATUS_INVALID_INPUT
+            );
+            assert_eq!(arti_client_err_os_error_code(err), 0);
+            let msg = CStr::from_ptr(arti_client_err_message(err));
+            assert_eq!(msg.to_str().unwrap(), "Provided argument was NULL.");
+
+            let err2 = arti_client_err_clone(err);
+            arti_client_err_free(err);
+            assert_eq!(
+                arti_client_err_status(err2),
+                ARTI_CLIENT_STATUS_INVALID_INPUT
+            );
+            arti_client_err_free(err2);
+
+            assert_eq!(
+                arti_client_err_status(std::ptr::null()),
+                ARTI_CLIENT_STATUS_INVALID_INPUT
+            );
+            assert!(arti_client_err_message(std::ptr::null()).is_null());
+            arti_client_err_free(std::ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,482 @@
+#![doc = include_str!("../README.md")]
+// @@ begin lint list maintained by maint/add_warning @@
+#![allow(renamed_and_removed_lints)] // @@REMOVE_WHEN(ci_arti_stable)
+#![allow(unknown_lints)] // @@REMOVE_WHEN(ci_arti_nightly)
+#![warn(missing_docs)]
+#![warn(noop_method_call)]
+#![warn(unreachable_pub)]
+#![warn(clippy::all)]
+#![deny(clippy::await_holding_lock)]
+#![deny(clippy::cargo_common_metadata)]
+#![deny(clippy::cast_lossless)]
+#![deny(clippy::checked_conversions)]
+#![warn(clippy::cognitive_complexity)]
+#![deny(clippy::debug_assert_with_mut_call)]
+#![deny(clippy::exhaustive_enums)]
+#![deny(clippy::exhaustive_structs)]
+#![deny(clippy::expl_impl_clone_on_copy)]
+#![deny(clippy::fallible_impl_from)]
+#![deny(clippy::implicit_clone)]
+#![deny(clippy::large_stack_arrays)]
+#![warn(clippy::manual_ok_or)]
+#![deny(clippy::missing_docs_in_private_items)]
+#![warn(clippy::needless_borrow)]
+#![warn(clippy::needless_pass_by_value)]
+#![warn(clippy::option_option)]
+#![deny(clippy::print_stderr)]
+#![deny(clippy::print_stdout)]
+#![warn(clippy::rc_buffer)]
+#![deny(clippy::ref_option_ref)]
+#![warn(clippy::semicolon_if_nothing_returned)]
+#![warn(clippy::trait_duplication_in_bounds)]
+#![deny(clippy::unchecked_duration_subtraction)]
+#![deny(clippy::unnecessary_wraps)]
+#![warn(clippy::unseparated_literal_suffix)]
+#![deny(clippy::unwrap_used)]
+#![deny(clippy::mod_module_files)]
+#![allow(clippy::let_unit_value)] // This can reasonably be done for explicitness
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::significant_drop_in_scrutinee)] // arti/-/merge_requests/588/#note_2812945
+#![allow(clippy::result_large_err)] // temporary workaround for arti#587
+#![allow(clippy::needless_raw_string_hashes)] // complained-about code is fine, often best
+#![allow(clippy::needless_lifetimes)] // See arti#1765
+//! <!-- @@ end lint list maintained by maint/add_warning @@ -->
+
+// TODO: Possibly add this to our big list of lints.
+#![deny(unsafe_op_in_unsafe_fn)]
+
+pub mod err;
+
+use std::ffi::{c_char, c_void, CString};
+
+use arti_client::blocking::TorClientBlocking;
+use arti_client::config::TorClientConfigBuilder;
+use arti_client::status::BootstrapStatus;
+use arti_client::{DataReader, DataWriter, TorClientConfig};
+use futures::future::{self, Either};
+use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use futures::lock::Mutex;
+use futures::StreamExt as _;
+use tor_rtcompat::{PreferredRuntime, ToplevelBlockOn as _};
+
+use arti_ffi_util::{
+    ffi_body_raw, ffi_body_with_err, InvalidInput, OptOutPtrExt as _, OptOutValExt as _, OutPtr,
+    OutVal,
+};
+use err::{ArtiClientError, ArtiClientStatus};
+
+/// A Tor client.
+///
+/// This is a thread-safe type: you may safely use it from multiple threads at once.
+///
+/// Once you are no longer going to use this client at all, you must free
+/// it with [`arti_client_free`].
+/// Streams that you opened with it keep working until you free them.
+pub struct ArtiClient(TorClientBlocking);
+
+/// An anonymized stream, opened with [`arti_client_connect`].
+///
+/// This is a thread-safe type: you may safely use it from multiple threads at once.
+/// One thread may read from the stream while another one writes to it.
+///
+/// Once you are done with this stream, you must free it with [`arti_client_stream_free`].
+pub struct ArtiClientStream {
+    /// The runtime of the client that opened this stream.
+    runtime: PreferredRuntime,
+    /// The half of the stream that we read from.
+    reader: Mutex<DataReader>,
+    /// The half of the stream that we write to.
+    writer: Mutex<DataWriter>,
+}
+
+/// A function to be called with reports of a client's bootstrap progress.
+///
+/// The arguments are the `user_data` that was passed to [`arti_client_bootstrap`],
+/// the fraction of bootstrapping that is done (between 0.0 and 1.0),
+/// and a human-readable description of the client's status.
+/// The description is only valid until the function returns.
+pub type ArtiClientBootstrapCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, progress: f32, message: *const c_char)>;
+
+/// Try to create a new `ArtiClient`, without bootstrapping it.
+///
+/// If `state_dir` and `cache_dir` are both NULL, the client uses the default
+/// configuration.  Otherwise, they must both be set, and the client keeps its
+/// persistent state and its cache in them.
+///
+/// The client bootstraps itself when it is first used;
+/// call [`arti_client_bootstrap`] to do so earlier, or to see its progress.
+///
+/// On success, return `ARTI_CLIENT_STATUS_SUCCESS` and set `*client_out`
+/// to a new `ArtiClient`.
+/// Otherwise return some other status code, set `*client_out` to NULL, and set
+/// `*error_out` (if provided) to a newly allocated error object.
+///
+/// # Ownership
+///
+/// The caller is responsible for making sure that `*client_out` and `*error_out`,
+/// if set, are eventually freed.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_new(
+    state_dir: *const c_char,
+    cache_dir: *const c_char,
+    client_out: *mut *mut ArtiClient,
+    error_out: *mut *mut ArtiClientError,
+) -> ArtiClientStatus {
+    ffi_body_with_err!(
+        {
+            let state_dir: Option<&str> [in_str_opt];
+            let cache_dir: Option<&str> [in_str_opt];
+            let client_out: Option<OutPtr<ArtiClient>> [out_ptr_opt];
+            err error_out: Option<OutPtr<ArtiClientError>>;
+        } in {
+            let config = match (state_dir, cache_dir) {
+                (None, None) => TorClientConfig::default(),
+                (Some(state_dir), Some(cache_dir)) => {
+                    TorClientConfigBuilder::from_directories(state_dir, cache_dir).build()?
+                }
+                (_, _) => return Err(InvalidInput::NullPointer.into()),
+            };
+            let client = TorClientBlocking::create_unbootstrapped(config)?;
+            client_out.write_boxed_value_if_ptr_set(ArtiClient(client));
+        }
+    )
+}
+
+/// Bootstrap `client`, if it isn't bootstrapped already.
+///
+/// Returns once the client has enough directory information
+/// to connect safely over the Tor network.
+///
+/// If `callback` is not NULL, it is called with `user_data`
+/// every time that the client's bootstrap status changes,
+/// and once more before this function returns.
+/// It is called from the thread that called this function.
+///
+/// On success, return `ARTI_CLIENT_STATUS_SUCCESS`.
+/// Otherwise return some other status code, and set
+/// `*error_out` (if provided) to a newly allocated error object.
+///
+/// # Correctness requirements
+///
+/// `user_data` is never dereferenced by this library;
+/// it is only passed to `callback`.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_bootstrap(
+    client: *const ArtiClient,
+    callback: ArtiClientBootstrapCallback,
+    user_data: *mut c_void,
+    error_out: *mut *mut ArtiClientError,
+) -> ArtiClientStatus {
+    ffi_body_with_err!(
+        {
+            let client: Option<&ArtiClient> [in_ptr_opt];
+            err error_out: Option<OutPtr<ArtiClientError>>;
+        } in {
+            let client = client.ok_or(InvalidInput::NullPointer)?;
+            let tor_client = client.0.client();
+            let report = |status: &BootstrapStatus| {
+                let Some(callback) = callback else { return };
+                let message = CString::new(status.to_string().replace('\0', ""))
+                    .expect("NUL in message after removing NULs?");
+                // Safety: The caller promises that `callback` may be called with `user_data`.
+                unsafe { callback(user_data, status.as_frac(), message.as_ptr()) };
+            };
+
+            let mut events = tor_client.bootstrap_events();
+            let bootstrap = tor_client.bootstrap();
+            futures::pin_mut!(bootstrap);
+            client.0.runtime().block_on(async {
+                loop {
+                    match future::select(bootstrap.as_mut(), events.next()).await {
+                        Either::Left((result, _)) => break result,
+                        Either::Right((Some(status), _)) => report(&status),
+                        Either::Right((None, bootstrap)) => break bootstrap.await,
+                    }
+                }
+            })?;
+            report(&tor_client.bootstrap_status());
+        }
+    )
+}
+
+/// Launch an anonymized connection to `hostname` and `port` over the Tor network.
+///
+/// `hostname` may be a DNS name, an IP address, or an onion service address
+/// (if this library was built with onion service support).
+/// If it is a DNS name, it is resolved by the exit relay,
+/// so that no DNS requests leak from this host.
+///
+/// On success, return `ARTI_CLIENT_STATUS_SUCCESS` and set `*stream_out`
+/// to a new `ArtiClientStream`.
+/// Otherwise return some other status code, set `*stream_out` to NULL, and set
+/// `*error_out` (if provided) to a newly allocated error object.
+///
+/// # Ownership
+///
+/// The caller is responsible for making sure that `*stream_out` and `*error_out`,
+/// if set, are eventually freed.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_connect(
+    client: *const ArtiClient,
+    hostname: *const c_char,
+    port: u16,
+    stream_out: *mut *mut ArtiClientStream,
+    error_out: *mut *mut ArtiClientError,
+) -> ArtiClientStatus {
+    ffi_body_with_err!(
+        {
+            let client: Option<&ArtiClient> [in_ptr_opt];
+            let hostname: Option<&str> [in_str_opt];
+            let stream_out: Option<OutPtr<ArtiClientStream>> [out_ptr_opt];
+            err error_out: Option<OutPtr<ArtiClientError>>;
+        } in {
+            let client = client.ok_or(InvalidInput::NullPointer)?;
+            let hostname = hostname.ok_or(InvalidInput::NullPointer)?;
+            if port == 0 {
+                return Err(InvalidInput::BadPort.into());
+            }
+            let stream = client.0.connect((hostname, port))?;
+            let (reader, writer) = stream.into_inner().split();
+            stream_out.write_boxed_value_if_ptr_set(ArtiClientStream {
+                runtime: client.0.runtime().clone(),
+                reader: Mutex::new(reader),
+                writer: Mutex::new(writer),
+            });
+        }
+    )
+}
+
+/// Read up to `len` bytes from `stream` into `buf`.
+///
+/// Blocks until at least one byte is available, or until the stream is closed.
+///
+/// On success, return `ARTI_CLIENT_STATUS_SUCCESS`, and set `*n_read_out`
+/// to the number of bytes that were read.
+/// If the stream has been closed by the other side, this is 0.
+/// Otherwise return some other status code, set `*n_read_out` to 0, and set
+/// `*error_out` (if provided) to a newly allocated error object.
+///
+/// # Correctness requirements
+///
+/// `buf` must point to at least `len` bytes of writable memory.
+/// It may be NULL only if `len` is 0.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_stream_read(
+    stream: *const ArtiClientStream,
+    buf: *mut u8,
+    len: usize,
+    n_read_out: *mut usize,
+    error_out: *mut *mut ArtiClientError,
+) -> ArtiClientStatus {
+    ffi_body_with_err!(
+        {
+            let stream: Option<&ArtiClientStream> [in_ptr_opt];
+            let n_read_out: Option<OutVal<usize>> [out_val_opt];
+            err error_out: Option<OutPtr<ArtiClientError>>;
+        } in {
+            let stream = stream.ok_or(InvalidInput::NullPointer)?;
+            // Safety: The caller promises that `buf` is valid for `len` bytes.
+            let buf = unsafe { arti_ffi_util::out_bytes(buf, len) }?;
+            let n_read = stream.runtime.block_on(async {
+                stream.reader.lock().await.read(buf).await
+            })?;
+            n_read_out.write_value_if_ptr_set(n_read);
+        }
+    )
+}
+
+/// Write up to `len` bytes from `buf` to `stream`.
+///
+/// Data that is written may be buffered: use [`arti_client_stream_flush`]
+/// to make sure that it has been sent.
+///
+/// On success, return `ARTI_CLIENT_STATUS_SUCCESS`, and set `*n_written_out`
+/// to the number of bytes that were written.
+/// Otherwise return some other status code, set `*n_written_out` to 0, and set
+/// `*error_out` (if provided) to a newly allocated error object.
+///
+/// # Correctness requirements
+///
+/// `buf` must point to at least `len` bytes of readable memory.
+/// It may be NULL only if `len` is 0.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_stream_write(
+    stream: *const ArtiClientStream,
+    buf: *const u8,
+    len: usize,
+    n_written_out: *mut usize,
+    error_out: *mut *mut ArtiClientError,
+) -> ArtiClientStatus {
+    ffi_body_with_err!(
+        {
+            let stream: Option<&ArtiClientStream> [in_ptr_opt];
+            let n_written_out: Option<OutVal<usize>> [out_val_opt];
+            err error_out: Option<OutPtr<ArtiClientError>>;
+        } in {
+            let stream = stream.ok_or(InvalidInput::NullPointer)?;
+            // Safety: The caller promises that `buf` is valid for `len` bytes.
+            let buf = unsafe { arti_ffi_util::in_bytes(buf, len) }?;
+            let n_written = stream.runtime.block_on(async {
+                stream.writer.lock().await.write(buf).await
+            })?;
+            n_written_out.write_value_if_ptr_set(n_written);
+        }
+    )
+}
+
+/// Send any data that has been written to `stream`, but not sent yet.
+///
+/// On success, return `ARTI_CLIENT_STATUS_SUCCESS`.
+/// Otherwise return some other status code, and set
+/// `*error_out` (if provided) to a newly allocated error object.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_stream_flush(
+    stream: *const ArtiClientStream,
+    error_out: *mut *mut ArtiClientError,
+) -> ArtiClientStatus {
+    ffi_body_with_err!(
+        {
+            let stream: Option<&ArtiClientStream> [in_ptr_opt];
+            err error_out: Option<OutPtr<ArtiClientError>>;
+        } in {
+            let stream = stream.ok_or(InvalidInput::NullPointer)?;
+            stream.runtime.block_on(async {
+                stream.writer.lock().await.flush().await
+            })?;
+        }
+    )
+}
+
+/// Close `stream`, and release the storage that it holds.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_stream_free(stream: *mut ArtiClientStream) {
+    ffi_body_raw!(
+        {
+            let stream: Option<Box<ArtiClientStream>> [in_ptr_consume_opt];
+        } in {
+            drop(stream);
+            // Safety: return value is (); trivially safe.
+            ()
+        }
+    );
+}
+
+/// Release storage held by an `ArtiClient`.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_free(client: *mut ArtiClient) {
+    ffi_body_raw!(
+        {
+            let client: Option<Box<ArtiClient>> [in_ptr_consume_opt];
+        } in {
+            drop(client);
+            // Safety: return value is (); trivially safe.
+            ()
+        }
+    );
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use err::{arti_client_err_free, arti_client_err_status, ARTI_CLIENT_STATUS_INVALID_INPUT};
+    use std::ptr::{null, null_mut};
+
+    #[test]
+    fn create_and_free() {
+        let state_tmp = tempfile::tempdir().unwrap();
+        let cache_tmp = tempfile::tempdir().unwrap();
+        let state_dir = CString::new(state_tmp.path().to_str().unwrap()).unwrap();
+        let cache_dir = CString::new(cache_tmp.path().to_str().unwrap()).unwrap();
+
+        let mut client: *mut ArtiClient = null_mut();
+        let mut error: *mut ArtiClientError = null_mut();
+        let status = unsafe {
+            arti_client_new(
+                state_dir.as_ptr(),
+                cache_dir.as_ptr(),
+                &mut client as _,
+                &mut error as _,
+            )
+        };
+        assert_eq!(status, err::ARTI_CLIENT_STATUS_SUCCESS);
+        assert!(!client.is_null());
+        assert!(error.is_null());
+        unsafe { arti_client_free(client) };
+    }
+
+    #[test]
+    fn invalid_inputs() {
+        let state_dir = CString::new("/nonexistent").unwrap();
+        let mut client: *mut ArtiClient = 7 as _; // junk; will be overwritten.
+        let mut error: *mut ArtiClientError = null_mut();
+        // Only one of the directories.
+        let status = unsafe {
+            arti_client_new(
+                state_dir.as_ptr(),
+                null(),
+                &mut client as _,
+                &mut error as _,
+            )
+        };
+        assert_eq!(status, ARTI_CLIENT_STATUS_INVALID_INPUT);
+        assert!(client.is_null());
+        assert_eq!(
+            unsafe { arti_client_err_status(error) },
+            ARTI_CLIENT_STATUS_INVALID_INPUT
+        );
+        unsafe { arti_client_err_free(error) };
+
+        // No client to connect with.
+        let hostname = CString::new("example.com").unwrap();
+        let mut stream: *mut ArtiClientStream = 7 as _;
+        let status = unsafe {
+            arti_client_connect(null(), hostname.as_ptr(), 80, &mut stream as _, null_mut())
+        };
+        assert_eq!(status, ARTI_CLIENT_STATUS_INVALID_INPUT);
+        assert!(stream.is_null());
+
+        // No stream to read from.
+        let mut buf = [0_u8; 16];
+        let mut n_read: usize = 7;
+        let status = unsafe {
+            arti_client_stream_read(
+                null(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut n_read as _,
+                null_mut(),
+            )
+        };
+        assert_eq!(status, ARTI_CLIENT_STATUS_INVALID_INPUT);
+        assert_eq!(n_read, 0);
+
+        // Freeing NULL does nothing.
+        unsafe {
+            arti_client_stream_free(null_mut());
+            arti_client_free(null_mut());
+        }
+    }
+}
@@ -230,6 +230,16 @@ impl TorAddr {
         matches!(&self.host, Host::Ip(_))
     }
 
+    /// Return the host part of this address.
+    pub(crate) fn host(&self) -> &Host {
+        &self.host
+    }
+
+    /// Return this address, with its host replaced by `host`.
+    pub(crate) fn with_host(self, host: Host) -> Self {
+        TorAddr { host, ..self }
+    }
+
     /// Get instructions for how to make a stream to this address
     pub(crate) fn into_stream_instructions(
         self,
@@ -372,7 +382,7 @@ pub enum TorAddrError {
 //  * The stringly-typed .onion domain name must be passed in the
 //    StreamInstructions so that we can send it to the HS for its vhosting.
 #[derive(Clone, Debug, Eq, PartialEq)]
-enum Host {
+pub(crate) enum Host {
     /// A hostname.
     ///
     /// This variant should never be used if the `Ip`
@@ -415,6 +425,14 @@ impl FromStr for Host {
 }
 
 impl Host {
+    /// Return a string that is the same for every spelling of this host,
+    /// for use as a key when looking it up.
+    ///
+    /// (Hostnames are case-insensitive.)
+    pub(crate) fn canonical_name(&self) -> String {
+        self.to_string().to_ascii_lowercase()
+    }
+
     /// Return true if this address is one that is "internal": that is,
     /// relative to the particular host that is resolving it.
     fn is_local(&self) -> bool {
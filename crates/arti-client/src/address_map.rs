@@ -0,0 +1,155 @@
+//! Replacing the hostnames that the client is asked to use.
+//!
+//! This is like the `MapAddress` option in C Tor.  Before we decide how to
+//! connect to an address, or look one up, we replace its host according to
+//! the `address_map` section of the configuration, and to any mappings added
+//! with [`TorClient::map_address`](crate::TorClient::map_address).  This lets
+//! a user give a `.onion` service a memorable name, or point a `.onion`
+//! address at an ordinary host for testing.
+//!
+//! Mappings are not applied recursively: the host that we map an address to
+//! is used as it is.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::address::{Host, TorAddr};
+use crate::config::AddressMapConfig;
+use crate::TorAddrError;
+
+/// The mutable state of an [`AddressMap`].
+#[derive(Debug, Default)]
+struct Inner {
+    /// The mappings from our configuration, by the canonical name of the
+    /// host that they replace.
+    configured: HashMap<String, Host>,
+    /// The mappings added at runtime, by the canonical name of the host
+    /// that they replace.
+    ///
+    /// These take precedence over the configured ones.
+    added: HashMap<String, Host>,
+}
+
+/// The address mappings of a [`TorClient`](crate::TorClient), shared by
+/// every clone of it.
+#[derive(Debug)]
+pub(crate) struct AddressMap {
+    /// The mappings themselves.
+    inner: Mutex<Inner>,
+}
+
+/// Return the mappings in `config`, by the canonical name of the host that
+/// they replace.
+fn configured_mappings(config: &AddressMapConfig) -> HashMap<String, Host> {
+    config
+        .hosts
+        .iter()
+        // Every mapping was checked when the configuration was built.
+        .filter_map(|(from, to)| {
+            let from = from.parse::<Host>().ok()?;
+            Some((from.canonical_name(), to.parse().ok()?))
+        })
+        .collect()
+}
+
+impl AddressMap {
+    /// Return a new `AddressMap`, with the mappings in `config`.
+    pub(crate) fn new(config: &AddressMapConfig) -> Self {
+        AddressMap {
+            inner: Mutex::new(Inner {
+                configured: configured_mappings(config),
+                added: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Replace the mappings from our configuration with those in `config`.
+    ///
+    /// Mappings added at runtime are kept.
+    pub(crate) fn reconfigure(&self, config: &AddressMapConfig) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.configured = configured_mappings(config);
+    }
+
+    /// Replace `from` with `to` in every address that we map from now on.
+    pub(crate) fn insert(&self, from: &str, to: &str) -> Result<(), TorAddrError> {
+        let from = from.parse::<Host>()?.canonical_name();
+        let to = to.parse()?;
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.added.insert(from, to);
+        Ok(())
+    }
+
+    /// Forget a mapping of `from` that was added with
+    /// [`insert`](AddressMap::insert).
+    ///
+    /// Returns true if there was one.
+    pub(crate) fn remove(&self, from: &str) -> bool {
+        let Ok(from) = from.parse::<Host>() else {
+            return false;
+        };
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.added.remove(&from.canonical_name()).is_some()
+    }
+
+    /// Return `addr`, with its host replaced if we have a mapping for it.
+    pub(crate) fn apply(&self, addr: TorAddr) -> TorAddr {
+        let key = addr.host().canonical_name();
+        let inner = self.inner.lock().expect("poisoned lock");
+        match inner.added.get(&key).or_else(|| inner.configured.get(&key)) {
+            Some(to) => addr.with_host(to.clone()),
+            None => addr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::IntoTorAddr as _;
+
+    const ONION: &str = "fpqqmiwzqiv63jczrshh4qcmlxw6gujcai3arobq23wikt7hk7ojadid.onion";
+
+    fn mapped(map: &AddressMap, addr: &str) -> String {
+        map.apply(addr.into_tor_addr().unwrap()).to_string()
+    }
+
+    #[test]
+    fn mappings() {
+        let mut config = AddressMapConfig::builder();
+        config.hosts([("www.example.com".to_string(), ONION.to_string())].into());
+        let map = AddressMap::new(&config.build().unwrap());
+
+        assert_eq!(mapped(&map, "WWW.Example.com:443"), format!("{ONION}:443"));
+        assert_eq!(mapped(&map, "example.com:443"), "example.com:443");
+
+        // Mappings added at runtime win, and can go away again.
+        map.insert("www.example.com", "192.0.2.1").unwrap();
+        map.insert(ONION, "localhost").unwrap();
+        assert_eq!(mapped(&map, "www.example.com:80"), "192.0.2.1:80");
+        assert_eq!(mapped(&map, &format!("{ONION}:80")), "localhost:80");
+        assert!(map.remove("www.example.com"));
+        assert!(!map.remove("www.example.com"));
+        assert_eq!(mapped(&map, "www.example.com:80"), format!("{ONION}:80"));
+
+        // Reconfiguring keeps the runtime mappings.
+        map.reconfigure(&AddressMapConfig::default());
+        assert_eq!(mapped(&map, "www.example.com:80"), "www.example.com:80");
+        assert_eq!(mapped(&map, &format!("{ONION}:80")), "localhost:80");
+
+        assert!(map.insert("www.example.com", "not a host").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Reporting every stream that a client opens, for auditing.
+//!
+//! An application that must keep a record of its connections can give a
+//! [`StreamAuditor`] to
+//! [`TorClient::set_stream_auditor`](crate::TorClient::set_stream_auditor).
+//! Each time a stream closes, the auditor receives a [`StreamAuditRecord`]:
+//! where the stream went, how it was isolated, which relays carried it, and
+//! how much traffic it carried.
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tor_circmgr::isolation::StreamIsolation;
+use tor_linkspec::RelayIds;
+use tor_proto::circuit::{ClientCirc, StreamCloseHook, StreamStats};
+use tor_rtcompat::SleepProvider;
+
+/// An object that receives a [`StreamAuditRecord`] for every stream that a
+/// [`TorClient`](crate::TorClient) opens.
+///
+/// Any `Fn(StreamAuditRecord)` is a `StreamAuditor`.
+pub trait StreamAuditor: Send + Sync {
+    /// Called once for each stream, after it has closed.
+    ///
+    /// This is called from inside Arti, possibly on a task that relays the
+    /// traffic for other streams: it should return quickly, and must not
+    /// block.  An auditor that needs to do something slow (like writing to a
+    /// file) should hand the record to another task or thread.
+    fn stream_closed(&self, record: StreamAuditRecord);
+}
+
+impl<F> StreamAuditor for F
+where
+    F: Fn(StreamAuditRecord) + Send + Sync,
+{
+    fn stream_closed(&self, record: StreamAuditRecord) {
+        self(record);
+    }
+}
+
+/// A record of one stream, given to a [`StreamAuditor`] once the stream has
+/// closed.
+///
+/// The record includes the stream's destination.  Like any other record of
+/// a client's activity, it is sensitive: store it with care.
+#[derive(Clone, Debug)]
+pub struct StreamAuditRecord {
+    /// The hostname or address that the stream was for.
+    target: String,
+    /// The port that the stream was for.
+    port: u16,
+    /// The isolation that the stream was opened with.
+    isolation: StreamIsolation,
+    /// The identities of the relays that carried the stream, in order.
+    path: Vec<RelayIds>,
+    /// When we asked for the stream to be opened.
+    opened_at: SystemTime,
+    /// When the stream closed.
+    closed_at: SystemTime,
+    /// The final traffic on the stream.
+    stats: StreamStats,
+}
+
+impl StreamAuditRecord {
+    /// Return the hostname or address that the stream was for.
+    ///
+    /// This is the target after any
+    /// [address mapping](crate::TorClient::map_address) has been applied.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Return the port that the stream was for.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Return the isolation that the stream was opened with.
+    ///
+    /// Streams whose isolations are not
+    /// [compatible](crate::isolation::Isolation::compatible) never
+    /// share a circuit.  (For a stream opened with
+    /// [`StreamPrefs::isolate_every_stream`](crate::StreamPrefs::isolate_every_stream),
+    /// this is a new isolation, compatible with no other.)
+    pub fn isolation(&self) -> &StreamIsolation {
+        &self.isolation
+    }
+
+    /// Return the identities of the relays that carried the stream, from
+    /// the first hop to the last.
+    ///
+    /// For a stream to an onion service, this only lists our own relays,
+    /// not those that the service chose.
+    pub fn path(&self) -> &[RelayIds] {
+        &self.path
+    }
+
+    /// Return when we asked for the stream to be opened.
+    pub fn opened_at(&self) -> SystemTime {
+        self.opened_at
+    }
+
+    /// Return when the stream closed.
+    pub fn closed_at(&self) -> SystemTime {
+        self.closed_at
+    }
+
+    /// Return the final traffic on the stream, and the circuit that carried it.
+    ///
+    /// A stream that the exit refused has no traffic.
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+}
+
+/// Return a [`StreamCloseHook`] that reports a stream to `target`:`port` on
+/// `circ` to `auditor`, once it closes.
+pub(crate) fn close_hook<R: SleepProvider>(
+    auditor: Arc<dyn StreamAuditor>,
+    runtime: R,
+    target: &str,
+    port: u16,
+    isolation: StreamIsolation,
+    circ: &ClientCirc,
+) -> StreamCloseHook {
+    let target = target.to_owned();
+    let path: Vec<RelayIds> = circ
+        .path_ref()
+        .hops()
+        .iter()
+        .filter_map(|hop| hop.as_chan_target())
+        .map(RelayIds::from_relay_ids)
+        .collect();
+    let opened_at = runtime.wallclock();
+    StreamCloseHook::new(move |stats| {
+        auditor.stream_closed(StreamAuditRecord {
+            target: target.clone(),
+            port,
+            isolation: isolation.clone(),
+            path: path.clone(),
+            opened_at,
+            closed_at: runtime.wallclock(),
+            stats,
+        });
+    })
+}
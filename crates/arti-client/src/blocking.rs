@@ -0,0 +1,243 @@
+//! A synchronous wrapper around [`TorClient`], for programs that don't use
+//! async Rust.
+//!
+//! A [`TorClientBlocking`] creates and owns its own asynchronous runtime, and
+//! blocks the calling thread until each operation is done.  The streams that
+//! it returns are [`DataStreamBlocking`]s, which implement [`std::io::Read`]
+//! and [`std::io::Write`], so they can be used wherever a `TcpStream` could.
+//!
+//! The methods in this module must not be called from inside an
+//! asynchronous runtime: if you have one, use [`TorClient`] directly.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::io::{Read as _, Write as _};
+//! use arti_client::blocking::TorClientBlocking;
+//! use arti_client::TorClientConfig;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = TorClientBlocking::create_bootstrapped(TorClientConfig::default())?;
+//! let mut stream = client.connect(("example.com", 80))?;
+//! stream.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")?;
+//! stream.flush()?;
+//! let mut response = String::new();
+//! stream.read_to_string(&mut response)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tor_rtcompat::{PreferredRuntime, ToplevelBlockOn as _};
+
+use crate::err::ErrorDetail;
+use crate::{DataStream, IntoTorAddr, StreamPrefs, TorClient, TorClientConfig};
+
+/// A [`TorClient`] whose methods block, rather than being `async`.
+///
+/// Cloning a `TorClientBlocking` gives another handle to the same client,
+/// using the same runtime.
+///
+/// See the [module documentation](self) for more information.
+#[derive(Clone)]
+pub struct TorClientBlocking {
+    /// The runtime that we created for the client.
+    runtime: PreferredRuntime,
+    /// The client itself.
+    client: TorClient<PreferredRuntime>,
+}
+
+/// A [`DataStream`] whose reads and writes block, rather than being `async`.
+///
+/// Returned by [`TorClientBlocking::connect`].
+pub struct DataStreamBlocking {
+    /// The runtime that the stream's client uses.
+    runtime: PreferredRuntime,
+    /// The stream itself.
+    stream: DataStream,
+}
+
+/// Return a new runtime for a [`TorClientBlocking`].
+fn create_runtime() -> crate::Result<PreferredRuntime> {
+    PreferredRuntime::create().map_err(|e| ErrorDetail::RuntimeCreation(Arc::new(e)).into())
+}
+
+impl TorClientBlocking {
+    /// Create a client with the provided `config`, and bootstrap it.
+    ///
+    /// Returns once there is enough directory material to connect safely
+    /// over the Tor network.
+    ///
+    /// # Do not fork
+    ///
+    /// The process [**may not fork**](tor_rtcompat#do-not-fork)
+    /// (except, very carefully, before exec)
+    /// after calling this function, because it creates a [`PreferredRuntime`].
+    pub fn create_bootstrapped(config: TorClientConfig) -> crate::Result<Self> {
+        let runtime = create_runtime()?;
+        let client = runtime.block_on(
+            TorClient::with_runtime(runtime.clone())
+                .config(config)
+                .create_bootstrapped(),
+        )?;
+        Ok(TorClientBlocking { runtime, client })
+    }
+
+    /// Create a client with the provided `config`, without bootstrapping it.
+    ///
+    /// The client bootstraps itself when it is first used; call
+    /// [`bootstrap`](TorClientBlocking::bootstrap) to do so earlier.
+    ///
+    /// # Do not fork
+    ///
+    /// The process [**may not fork**](tor_rtcompat#do-not-fork)
+    /// (except, very carefully, before exec)
+    /// after calling this function, because it creates a [`PreferredRuntime`].
+    pub fn create_unbootstrapped(config: TorClientConfig) -> crate::Result<Self> {
+        let runtime = create_runtime()?;
+        let client = runtime.block_on(
+            TorClient::with_runtime(runtime.clone())
+                .config(config)
+                .create_unbootstrapped_async(),
+        )?;
+        Ok(TorClientBlocking { runtime, client })
+    }
+
+    /// Return the underlying [`TorClient`].
+    ///
+    /// Use this for the parts of the API that this wrapper doesn't cover.
+    /// Its `async` methods need to be run with [`runtime`](TorClientBlocking::runtime).
+    pub fn client(&self) -> &TorClient<PreferredRuntime> {
+        &self.client
+    }
+
+    /// Return the runtime that this client uses.
+    pub fn runtime(&self) -> &PreferredRuntime {
+        &self.runtime
+    }
+
+    /// Bootstrap the client, if it isn't bootstrapped already.
+    ///
+    /// See [`TorClient::bootstrap`].
+    pub fn bootstrap(&self) -> crate::Result<()> {
+        self.runtime.block_on(self.client.bootstrap())
+    }
+
+    /// Return a new client that shares this one's state, but whose streams
+    /// are isolated from this one's.
+    ///
+    /// See [`TorClient::isolated_client`].
+    pub fn isolated_client(&self) -> Self {
+        TorClientBlocking {
+            runtime: self.runtime.clone(),
+            client: self.client.isolated_client(),
+        }
+    }
+
+    /// Launch an anonymized connection to the provided address and port
+    /// over the Tor network.
+    ///
+    /// See [`TorClient::connect`].
+    pub fn connect<A: IntoTorAddr>(&self, target: A) -> crate::Result<DataStreamBlocking> {
+        let stream = self.runtime.block_on(self.client.connect(target))?;
+        Ok(self.wrap_stream(stream))
+    }
+
+    /// Launch an anonymized connection to the provided address and port
+    /// over the Tor network, with explicit connection preferences.
+    ///
+    /// See [`TorClient::connect_with_prefs`].
+    pub fn connect_with_prefs<A: IntoTorAddr>(
+        &self,
+        target: A,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<DataStreamBlocking> {
+        let stream = self
+            .runtime
+            .block_on(self.client.connect_with_prefs(target, prefs))?;
+        Ok(self.wrap_stream(stream))
+    }
+
+    /// Look up the IP addresses for `hostname` over the Tor network.
+    ///
+    /// See [`TorClient::resolve`].
+    pub fn resolve(&self, hostname: &str) -> crate::Result<Vec<IpAddr>> {
+        self.runtime.block_on(self.client.resolve(hostname))
+    }
+
+    /// Look up the hostnames for `addr` over the Tor network.
+    ///
+    /// See [`TorClient::resolve_ptr`].
+    pub fn resolve_ptr(&self, addr: IpAddr) -> crate::Result<Vec<String>> {
+        self.runtime.block_on(self.client.resolve_ptr(addr))
+    }
+
+    /// Wrap `stream`, which came from our client.
+    fn wrap_stream(&self, stream: DataStream) -> DataStreamBlocking {
+        DataStreamBlocking {
+            runtime: self.runtime.clone(),
+            stream,
+        }
+    }
+}
+
+impl DataStreamBlocking {
+    /// Return the underlying [`DataStream`].
+    pub fn into_inner(self) -> DataStream {
+        self.stream
+    }
+}
+
+impl Read for DataStreamBlocking {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.stream.read(buf))
+    }
+}
+
+impl Write for DataStreamBlocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.runtime.block_on(self.stream.flush())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::config::TorClientConfigBuilder;
+
+    #[test]
+    fn create_and_resolve_literal() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+            .build()
+            .unwrap();
+        let client = TorClientBlocking::create_unbootstrapped(cfg).unwrap();
+
+        // Addresses don't need to be looked up, so this works without a network.
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(client.resolve("192.0.2.1").unwrap(), [ip]);
+        assert_eq!(client.isolated_client().resolve("192.0.2.1").unwrap(), [ip]);
+    }
+}
@@ -48,6 +48,19 @@ impl<R: Runtime> DirProviderBuilder<R> for DirMgrBuilder {
     }
 }
 
+/// Where a [`TorClient`] should keep its persistent state.
+#[derive(Clone, Debug, Default)]
+enum StateMgrChoice {
+    /// In files in the configured state directory.
+    #[default]
+    StateDir,
+    /// In memory, in a new state manager for each client.
+    Ephemeral,
+    /// In a state manager supplied by the application.
+    #[cfg(feature = "experimental-api")]
+    Custom(tor_persist::DynStateMgr),
+}
+
 /// An object for constructing a [`TorClient`].
 ///
 /// Returned by [`TorClient::builder()`].
@@ -74,6 +87,8 @@ pub struct TorClientBuilder<R: Runtime> {
     /// Only available when `arti-client` is built with the `dirfilter` and `experimental-api` features.
     #[cfg(feature = "dirfilter")]
     dirfilter: tor_dirmgr::filter::FilterConfig,
+    /// Where the client should keep its persistent state.
+    statemgr: StateMgrChoice,
 }
 
 /// Longest allowable duration to wait for local resources to be available
@@ -98,6 +113,7 @@ impl<R: Runtime> TorClientBuilder<R> {
             local_resource_timeout: None,
             #[cfg(feature = "dirfilter")]
             dirfilter: None,
+            statemgr: StateMgrChoice::default(),
         }
     }
 
@@ -150,6 +166,47 @@ impl<R: Runtime> TorClientBuilder<R> {
         self
     }
 
+    /// Keep the client's persistent state (such as its guards and circuit
+    /// timeout history) in memory only, so that it is forgotten once the
+    /// client is dropped.
+    ///
+    /// This is for "amnesiac" clients, which should leave no record of their
+    /// guards or other history behind them.  Note that this does not change
+    /// where the client keeps its directory cache, or its keys: for those,
+    /// use a temporary `storage.cache_dir`, and an ephemeral
+    /// `storage.keystore`.
+    ///
+    /// Onion services keep their own state in the state directory, and can't
+    /// be launched by a client with ephemeral state.  Nor can it use state
+    /// namespaces.
+    pub fn ephemeral_state(mut self) -> Self {
+        self.statemgr = StateMgrChoice::Ephemeral;
+        self
+    }
+
+    /// Keep the client's persistent state (such as its guards and circuit
+    /// timeout history) in `statemgr`, instead of in files in the configured
+    /// state directory.
+    ///
+    /// The client takes the lock on `statemgr` itself, as it would for its
+    /// state directory.  Other data, such as the directory cache and the
+    /// keystore, are still kept on disk as configured.
+    ///
+    /// With a custom state manager,
+    /// [`TorClient::state_namespace`] and [`TorClient::clone_isolated`] give
+    /// errors, and onion services can't be launched.
+    ///
+    /// Only available when compiled with the `experimental-api` feature: this
+    /// code is unstable.
+    #[cfg(feature = "experimental-api")]
+    pub fn state_manager<M>(mut self, statemgr: M) -> Self
+    where
+        M: tor_persist::StateMgr + Send + Sync + 'static,
+    {
+        self.statemgr = StateMgrChoice::Custom(tor_persist::DynStateMgr::new(statemgr));
+        self
+    }
+
     /// Install a [`DirFilter`](tor_dirmgr::filter::DirFilter) to
     ///
     /// Only available when compiled with the `dirfilter` feature: this code
@@ -244,12 +301,22 @@ impl<R: Runtime> TorClientBuilder<R> {
             dirmgr_extensions.filter.clone_from(&self.dirfilter);
         }
 
+        let statemgr = match &self.statemgr {
+            StateMgrChoice::StateDir => None,
+            StateMgrChoice::Ephemeral => Some(tor_persist::DynStateMgr::new(
+                tor_persist::MemoryStateMgr::new(),
+            )),
+            #[cfg(feature = "experimental-api")]
+            StateMgrChoice::Custom(statemgr) => Some(statemgr.clone()),
+        };
+
         let result: Result<TorClient<R>> = TorClient::create_inner(
             self.runtime.clone(),
             &self.config,
             self.bootstrap_behavior,
             self.dirmgr_builder.as_ref(),
             dirmgr_extensions,
+            statemgr,
         )
         .map_err(ErrorDetail::into);
 
@@ -8,43 +8,60 @@
 #[cfg(feature = "rpc")]
 use {derive_deftly::Deftly, tor_rpcbase::templates::*};
 
-use crate::address::{IntoTorAddr, ResolveInstructions, StreamInstructions};
+use crate::address::{IntoTorAddr, ResolveInstructions, StreamInstructions, TorAddr};
+use crate::address_map::AddressMap;
+use crate::audit::{self, StreamAuditor};
+use crate::selftest::{self, CircuitTest, DownloadTest, SelfTestConfig, SelfTestReport};
+use crate::shutdown::{self, Shutdown};
+use crate::statemgr::ClientStateMgr;
 
 use crate::config::{
-    ClientAddrConfig, SoftwareStatusOverrideConfig, StreamTimeoutConfig, TorClientConfig,
+    ClientAddrConfig, DormancyConfig, ReconfigureReport, SoftwareStatusOverrideConfig,
+    StreamTimeoutConfig, TorClientConfig,
 };
 use safelog::{sensitive, Sensitive};
 use tor_async_utils::{DropNotifyWatchSender, PostageWatchSenderExt};
+use tor_chanmgr::BandwidthLimit;
 use tor_circmgr::isolation::{Isolation, StreamIsolation};
-use tor_circmgr::{isolation::StreamIsolationBuilder, IsolationToken, TargetPort};
+use tor_circmgr::{isolation::StreamIsolationBuilder, IsolationToken, RelayRules, TargetPort};
 use tor_config::MutCfg;
 #[cfg(feature = "bridge-client")]
 use tor_dirmgr::bridgedesc::BridgeDescMgr;
 use tor_dirmgr::{DirMgrStore, Timeliness};
-use tor_error::{error_report, internal, Bug};
+use tor_error::{debug_report, error_report, internal, Bug};
 use tor_guardmgr::{GuardMgr, RetireCircuits};
 use tor_keymgr::Keystore;
-use tor_memquota::MemoryQuotaTracker;
+use tor_linkspec::{HasRelayIds as _, RelayId};
+use tor_memquota::cache::CachePool;
+use tor_memquota::{MemoryQuotaTracker, ReclaimEvent};
 use tor_netdir::{params::NetParameters, NetDirProvider};
 #[cfg(feature = "onion-service-service")]
 use tor_persist::state_dir::StateDirectory;
-use tor_persist::{FsStateMgr, StateMgr};
-use tor_proto::circuit::ClientCirc;
+use tor_persist::{DynStateMgr, FsStateMgr, StateMgr};
+use tor_proto::circuit::{AggregateTrafficStats, ClientCirc, TrafficTotals};
+#[cfg(feature = "experimental-udp")]
+use tor_proto::stream::UdpStream;
 use tor_proto::stream::{DataStream, IpVersionPreference, StreamParameters};
 #[cfg(all(
     any(feature = "native-tls", feature = "rustls"),
     any(feature = "async-std", feature = "tokio")
 ))]
 use tor_rtcompat::PreferredRuntime;
-use tor_rtcompat::{Runtime, SleepProviderExt};
+use tor_rtcompat::{DynTimeProvider, Runtime, SleepProviderExt};
 #[cfg(feature = "onion-service-client")]
 use {
     tor_config::BoolOrAuto,
-    tor_hsclient::{HsClientConnector, HsClientDescEncKeypairSpecifier, HsClientSecretKeysBuilder},
+    tor_hsclient::{
+        HsClientConnector, HsClientDescEncKeypairSpecifier, HsClientSecretKeys,
+        HsClientSecretKeysBuilder,
+    },
     tor_hscrypto::pk::{HsClientDescEncKey, HsClientDescEncKeypair, HsClientDescEncSecretKey},
     tor_netdir::DirEvent,
 };
 
+#[cfg(feature = "bridge-client")]
+use crate::config::{BridgeConfig, BridgeList};
+
 #[cfg(all(feature = "onion-service-service", feature = "experimental-api"))]
 use tor_hsservice::HsIdKeypairSpecifier;
 #[cfg(all(feature = "onion-service-client", feature = "experimental-api"))]
@@ -58,15 +75,23 @@ use tor_keymgr::ArtiEphemeralKeystore;
 #[cfg(feature = "ctor-keystore")]
 use tor_keymgr::{CTorClientKeystore, CTorServiceKeystore};
 
+use futures::future::{self, Either};
 use futures::lock::Mutex as AsyncMutex;
 use futures::task::SpawnExt;
 use futures::StreamExt as _;
+use std::future::Future;
 use std::net::IpAddr;
 use std::result::Result as StdResult;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::destination::{self, DestinationIsolation};
+use crate::dns::{DnsCache, DnsQuery};
+use crate::dormancy::{self, Activity};
 use crate::err::ErrorDetail;
-use crate::{status, util, TorClientBuilder};
+use crate::keepalive;
+use crate::{status, util, TorClientBuilder, TorEvents};
+use crate::{DnsAnswer, NetworkSnapshot, PortClass, RetryPolicy};
 #[cfg(feature = "geoip")]
 use tor_geoip::CountryCode;
 use tor_rtcompat::scheduler::TaskHandle;
@@ -154,7 +179,6 @@ pub struct TorClient<R: Runtime> {
     /// Used for accessing the key manager and other persistent state.
     inert_client: InertTorClient,
     /// Guard manager
-    #[cfg_attr(not(feature = "bridge-client"), allow(dead_code))]
     guardmgr: GuardMgr<R>,
     /// Location on disk where we store persistent data containing both location and Mistrust information.
     ///
@@ -164,14 +188,27 @@ pub struct TorClient<R: Runtime> {
     /// to subsystems like `dirmgr`, `keymgr`, and `statemgr` during `TorClient` creation.
     #[cfg(feature = "onion-service-service")]
     state_directory: StateDirectory,
-    /// Location on disk where we store persistent data (cooked state manager).
-    statemgr: FsStateMgr,
+    /// Where we store persistent data (cooked state manager).
+    ///
+    /// Usually this is in the state directory, unless the builder supplied
+    /// another state manager.
+    statemgr: ClientStateMgr,
     /// Client address configuration
     addrcfg: Arc<MutCfg<ClientAddrConfig>>,
     /// Client DNS configuration
     timeoutcfg: Arc<MutCfg<StreamTimeoutConfig>>,
     /// Software status configuration.
     software_status_cfg: Arc<MutCfg<SoftwareStatusOverrideConfig>>,
+    /// The configuration that we were created with, or last reconfigured with.
+    ///
+    /// Used to change our bridges without being given a whole new configuration,
+    /// and to build the circuit managers of isolated sub-clients.
+    config: Arc<MutCfg<TorClientConfig>>,
+    /// The configuration that we were created with.
+    ///
+    /// Options that can't be changed on a running client keep these values,
+    /// whatever we are reconfigured with.
+    startup_config: Arc<TorClientConfig>,
     /// Mutex used to serialize concurrent attempts to reconfigure a TorClient.
     ///
     /// See [`TorClient::reconfigure`] for more information on its use.
@@ -187,6 +224,38 @@ pub struct TorClient<R: Runtime> {
     /// mutex used to prevent two tasks from trying to bootstrap at once.
     bootstrap_in_progress: Arc<AsyncMutex<()>>,
 
+    /// Running totals of the traffic on every data stream that this client
+    /// (or any clone of it) has opened.
+    traffic: Arc<TrafficTotals>,
+
+    /// The auditor that receives a record of every stream that this client
+    /// (or any clone of it) opens, if there is one.
+    stream_auditor: Arc<Mutex<Option<Arc<dyn StreamAuditor>>>>,
+
+    /// Where this client (and every clone of it) obtains new bridges when it
+    /// can't bootstrap with its configured ones, if anywhere.
+    #[cfg(feature = "moat")]
+    bridge_source: Arc<Mutex<Option<Arc<dyn tor_moat::BridgeSource>>>>,
+
+    /// Whether this client (and every clone of it) is shutting down, and the
+    /// circuits to close if its streams don't close in time.
+    shutdown: Arc<Shutdown>,
+
+    /// Cached answers to the DNS lookups that this client (or any clone of
+    /// it) has made.
+    dns_cache: Arc<DnsCache>,
+
+    /// The hostnames that this client (and every clone of it) replaces
+    /// before connecting or resolving.
+    address_map: Arc<AddressMap>,
+
+    /// Our rules for becoming dormant when idle.
+    dormancy_cfg: Arc<MutCfg<DormancyConfig>>,
+
+    /// When this client (or any clone of it) was last asked to use the
+    /// network.
+    activity: Arc<Activity>,
+
     /// Whether or not we should call `bootstrap` before doing things that require
     /// bootstrapping. If this is `false`, we will just call `wait_for_bootstrap`
     /// instead.
@@ -513,21 +582,43 @@ pub struct StreamPrefs {
     ip_ver_pref: IpVersionPreference,
     /// How should we isolate connection(s)?
     isolation: StreamIsolationPreference,
+    /// Whether to isolate streams by the class of their destination port.
+    isolate_by_port_class: bool,
+    /// The first-party domain that streams are made for, if any.
+    ///
+    /// Streams with different first-party domains never share circuits.
+    first_party_domain: Option<String>,
     /// Whether to return the stream optimistically.
     optimistic_stream: bool,
+    /// How hard to try to open the stream.
+    retry_policy: RetryPolicy,
+    /// Whether to race two circuits to open the stream.
+    race_circuits: bool,
+    /// How long the stream may stay idle before we send keep-alive padding,
+    /// if we should send any.
+    keepalive: Option<Duration>,
+    /// Whether the circuit's padding machines should ignore the traffic
+    /// that the stream sends.
+    hide_from_padding: bool,
     // TODO GEOIP Ideally this would be unconditional, with CountryCode maybe being Void
     // This probably applies in many other places, so probably:   git grep 'cfg.*geoip'
     // and consider each one with a view to making it unconditional.  Background:
     //   https://gitlab.torproject.org/tpo/core/arti/-/merge_requests/1537#note_2935256
     //   https://gitlab.torproject.org/tpo/core/arti/-/merge_requests/1537#note_2942214
     #[cfg(feature = "geoip")]
-    /// A country to restrict the exit relay's location to.
-    country_code: Option<CountryCode>,
+    /// Which country to restrict the exit relay's location to.
+    exit_country: ExitCountryPreference,
+    /// Which relays the circuit may use, beyond our configuration.
+    relay_rules: RelayRules,
     /// Whether to try to make connections to onion services.
     ///
     /// `Auto` means to use the client configuration.
     #[cfg(feature = "onion-service-client")]
     pub(crate) connect_to_onion_services: BoolOrAuto,
+    /// Service discovery keys to use for particular onion services,
+    /// instead of any keys in the keystore.
+    #[cfg(feature = "onion-service-client")]
+    service_discovery_keys: Vec<(tor_hscrypto::pk::HsId, HsClientSecretKeys)>,
 }
 
 /// Record of how we are isolating connections
@@ -542,6 +633,19 @@ enum StreamIsolationPreference {
     EveryStream,
 }
 
+/// Record of which country we want our exit relay to be in
+#[cfg(feature = "geoip")]
+#[derive(Debug, Default, Clone, Copy)]
+enum ExitCountryPreference {
+    /// Use the `address_filter.exit_country` configuration option
+    #[default]
+    Auto,
+    /// Any country will do
+    Any,
+    /// The exit relay must be in this country
+    Country(CountryCode),
+}
+
 impl From<DormantMode> for tor_chanmgr::Dormancy {
     fn from(dormant: DormantMode) -> tor_chanmgr::Dormancy {
         match dormant {
@@ -606,25 +710,64 @@ impl StreamPrefs {
     ///
     /// When this option is set, we will only pick exit relays that
     /// have an IP address that matches the country in our GeoIP database.
+    ///
+    /// If neither this nor [`any_exit_country`](StreamPrefs::any_exit_country)
+    /// is called, we use the `address_filter.exit_country` configuration
+    /// option, which allows any country by default.
     #[cfg(feature = "geoip")]
     #[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
     pub fn exit_country(&mut self, country_code: CountryCode) -> &mut Self {
-        self.country_code = Some(country_code);
+        self.exit_country = ExitCountryPreference::Country(country_code);
         self
     }
 
     /// Indicate that we don't care which country a stream appears to come from.
     ///
+    /// This overrides the `address_filter.exit_country` configuration option.
+    ///
     /// This is available even in the case where GeoIP support is compiled out,
     /// to make things easier.
     pub fn any_exit_country(&mut self) -> &mut Self {
         #[cfg(feature = "geoip")]
         {
-            self.country_code = None;
+            self.exit_country = ExitCountryPreference::Any;
         }
         self
     }
 
+    /// Indicate that the stream must not use the relay with `id`, in any
+    /// position on its circuit.
+    ///
+    /// This is in addition to the `path_rules.exclude_relays` configuration
+    /// option.  Streams with different relay rules never share circuits.
+    pub fn exclude_relay(&mut self, id: impl Into<RelayId>) -> &mut Self {
+        self.relay_rules.exclude_relay(id);
+        self
+    }
+
+    /// Indicate that the stream may exit from the relay with `id`.
+    ///
+    /// Once this has been called, the stream will only exit from relays
+    /// passed to it, and only if the `path_rules.exit_relays` configuration
+    /// option allows them too.  Streams with different relay rules never
+    /// share circuits.
+    pub fn exit_relay(&mut self, id: impl Into<RelayId>) -> &mut Self {
+        self.relay_rules.allow_exit(id);
+        self
+    }
+
+    /// Indicate that the stream should use a circuit with `n` hops.
+    ///
+    /// This overrides the `path_rules.hops` configuration option.  `n` must
+    /// be between 3 and 8: opening a stream with any other length will fail.
+    /// Longer circuits are slower, and (since few clients use them) easier to
+    /// tell apart from other clients' circuits.  Streams with different relay
+    /// rules never share circuits.
+    pub fn circuit_hops(&mut self, n: u8) -> &mut Self {
+        self.relay_rules.hops(n);
+        self
+    }
+
     /// Indicate that the stream should be opened "optimistically".
     ///
     /// By default, streams are not "optimistic". When you call
@@ -640,11 +783,81 @@ impl StreamPrefs {
     /// can start sending data on the stream right away, though of
     /// course this data will be lost if the connection is not
     /// actually successful.
+    ///
+    /// This applies to connections to onion services too: the service
+    /// queues any data that we send before it accepts the stream.
     pub fn optimistic(&mut self) -> &mut Self {
         self.optimistic_stream = true;
         self
     }
 
+    /// Indicate how many times, and how patiently, to try to open a stream.
+    ///
+    /// By default, we make a single attempt, and wait for it for as long as
+    /// the `stream_timeouts.connect_timeout` configuration option says.
+    /// See [`RetryPolicy`] for the alternatives.
+    ///
+    /// This applies to [`TorClient::connect`] and
+    /// [`TorClient::connect_with_prefs`].
+    pub fn retry_policy(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Indicate that the stream should be opened on two circuits at once,
+    /// keeping whichever opens first.
+    ///
+    /// This can hide a slow circuit or exit from latency-critical
+    /// applications, at the cost of extra load on the Tor network: use it
+    /// sparingly.  The two circuits have different exits, and both follow
+    /// the same isolation rules as the stream.  When one circuit opens the
+    /// stream, we give up on the other one.
+    ///
+    /// This has no effect on connections to onion services.
+    pub fn race_circuits(&mut self) -> &mut Self {
+        self.race_circuits = true;
+        self
+    }
+
+    /// Indicate that we should keep the stream alive while it is idle, by
+    /// sending padding on its circuit whenever nothing has been sent or
+    /// received on the stream for `interval`.
+    ///
+    /// Relays and exits may close connections that stay idle for too long.
+    /// This is for long-lived connections that can be quiet for longer than
+    /// that, such as IMAP IDLE or messaging sessions.  The padding is a
+    /// `DROP` cell to the last hop of the circuit, which discards it: the
+    /// stream's destination never sees it.
+    ///
+    /// Padding adds traffic to the Tor network, so by default we send none.
+    /// Don't use an `interval` shorter than a few minutes.
+    pub fn keepalive(&mut self, interval: Duration) -> &mut Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Indicate that we should send no keep-alive padding for the stream.
+    ///
+    /// This is the default.  See [`StreamPrefs::keepalive`].
+    pub fn no_keepalive(&mut self) -> &mut Self {
+        self.keepalive = None;
+        self
+    }
+
+    /// Indicate whether the traffic that the stream sends should count
+    /// towards the circuit's padding defenses.
+    ///
+    /// By default it does: a padding machine on the stream's circuit
+    /// reacts to the stream's traffic as it would to any other.  Turn this
+    /// off for a stream whose traffic isn't worth hiding, such as a
+    /// connection that is idle apart from its [keep-alive
+    /// padding](StreamPrefs::keepalive), so that its traffic doesn't make
+    /// the circuit send padding of its own.
+    pub fn circuit_padding(&mut self, enabled: bool) -> &mut Self {
+        self.hide_from_padding = !enabled;
+        self
+    }
+
     /// Return true if this stream has been configured as "optimistic".
     ///
     /// See [`StreamPrefs::optimistic`] for more info.
@@ -676,6 +889,65 @@ impl StreamPrefs {
         self.connect_to_onion_services = connect_to_onion_services;
         self
     }
+
+    /// Use `hs_client_desc_enc_secret_key` as our service discovery key
+    /// (also known as a client authorization key) when connecting to the
+    /// onion service `hsid`.
+    ///
+    /// This key is used instead of any key for `hsid` in the keystore.  It
+    /// lets a program connect to a service in restricted discovery mode without
+    /// storing its key first.
+    ///
+    /// Connections made with clones of these preferences may share circuits
+    /// to the service; connections that were given the same key separately
+    /// will not.
+    #[cfg(all(feature = "onion-service-client", feature = "experimental-api"))]
+    #[cfg_attr(
+        docsrs,
+        doc(cfg(all(feature = "onion-service-client", feature = "experimental-api")))
+    )]
+    pub fn service_discovery_key(
+        &mut self,
+        hsid: HsId,
+        hs_client_desc_enc_secret_key: HsClientDescEncSecretKey,
+    ) -> &mut Self {
+        let client_desc_enc_key = HsClientDescEncKey::from(&hs_client_desc_enc_secret_key);
+        let mut builder = HsClientSecretKeysBuilder::default();
+        builder.ks_hsc_desc_enc(HsClientDescEncKeypair::new(
+            client_desc_enc_key,
+            hs_client_desc_enc_secret_key,
+        ));
+        let keys = builder
+            .build()
+            .expect("Failed to construct HsClientSecretKeys");
+        self.service_discovery_keys.retain(|(id, _)| *id != hsid);
+        self.service_discovery_keys.push((hsid, keys));
+        self
+    }
+
+    /// Return the service discovery keys that we were given for `hsid`, if any.
+    #[cfg(feature = "onion-service-client")]
+    fn service_discovery_keys_for(
+        &self,
+        hsid: tor_hscrypto::pk::HsId,
+    ) -> Option<HsClientSecretKeys> {
+        self.service_discovery_keys
+            .iter()
+            .find(|(id, _)| *id == hsid)
+            .map(|(_, keys)| keys.clone())
+    }
+
+    /// Return the country that our exit relay must be in, if any, using
+    /// `cfg` unless we were told otherwise.
+    #[cfg(feature = "geoip")]
+    fn exit_country_with_config(&self, cfg: &ClientAddrConfig) -> Option<CountryCode> {
+        match self.exit_country {
+            ExitCountryPreference::Auto => cfg.exit_country,
+            ExitCountryPreference::Any => None,
+            ExitCountryPreference::Country(cc) => Some(cc),
+        }
+    }
+
     /// Return a TargetPort to describe what kind of exit policy our
     /// target circuit needs to support.
     fn wrap_target_port(&self, port: u16) -> TargetPort {
@@ -690,7 +962,8 @@ impl StreamPrefs {
         let mut params = StreamParameters::default();
         params
             .ip_version(self.ip_ver_pref)
-            .optimistic(self.optimistic_stream);
+            .optimistic(self.optimistic_stream)
+            .circuit_padding(!self.hide_from_padding);
         params
     }
 
@@ -744,6 +1017,29 @@ impl StreamPrefs {
         self
     }
 
+    /// Indicate that streams to different kinds of service should not share
+    /// circuits.
+    ///
+    /// When this option is set, streams to web ports, mail ports, and other
+    /// ports (see [`PortClass`]) never share a circuit, and neither do DNS
+    /// lookups and streams.  This is in addition to any other isolation.
+    pub fn isolate_by_port_class(&mut self) -> &mut Self {
+        self.isolate_by_port_class = true;
+        self
+    }
+
+    /// Indicate that the stream is made on behalf of the site at `domain`.
+    ///
+    /// Streams made on behalf of different first-party domains never share
+    /// circuits, so that (for example) a tracker embedded in two sites
+    /// can't link a user's visits to them.  Domains are compared without
+    /// regard to case, or to a trailing dot.  This is in addition to any
+    /// other isolation.
+    pub fn first_party_domain(&mut self, domain: &str) -> &mut Self {
+        self.first_party_domain = Some(destination::normalize_domain(domain));
+        self
+    }
+
     /// Return an [`Isolation`] which separates according to these `StreamPrefs` (only)
     ///
     /// This describes which connections or operations might use
@@ -846,6 +1142,7 @@ impl<R: Runtime> TorClient<R> {
         autobootstrap: BootstrapBehavior,
         dirmgr_builder: &dyn crate::builder::DirProviderBuilder<R>,
         dirmgr_extensions: tor_dirmgr::config::DirMgrExtensions,
+        custom_statemgr: Option<DynStateMgr>,
     ) -> StdResult<Self, ErrorDetail> {
         if crate::util::running_as_setuid() {
             return Err(tor_error::bad_api_usage!(
@@ -855,6 +1152,9 @@ impl<R: Runtime> TorClient<R> {
         }
 
         let memquota = MemoryQuotaTracker::new(&runtime, config.system.memory.clone())?;
+        // Shared by the caches of directory documents and of onion service descriptors,
+        // so that they are reclaimed together under memory pressure.
+        let cache_pool = CachePool::new(memquota.clone(), DynTimeProvider::new(runtime.clone()));
 
         let path_resolver = Arc::new(config.path_resolver.clone());
 
@@ -869,8 +1169,13 @@ impl<R: Runtime> TorClient<R> {
             c.extensions = dirmgr_extensions;
             c
         };
-        let statemgr = FsStateMgr::from_path_and_mistrust(&state_dir, mistrust)
-            .map_err(ErrorDetail::StateMgrSetup)?;
+        let statemgr = match custom_statemgr {
+            Some(statemgr) => ClientStateMgr::Custom(statemgr),
+            None => ClientStateMgr::Fs(
+                FsStateMgr::from_path_and_mistrust(&state_dir, mistrust)
+                    .map_err(ErrorDetail::StateMgrSetup)?,
+            ),
+        };
         // Try to take state ownership early, so we'll know if we have it.
         // (At this point we don't yet care if we have it.)
         let _ignore_status = statemgr.try_lock().map_err(ErrorDetail::StateMgrSetup)?;
@@ -888,6 +1193,8 @@ impl<R: Runtime> TorClient<R> {
             &NetParameters::from_map(&config.override_net_params),
             memquota.clone(),
         ));
+        #[cfg(feature = "bridge-client")]
+        set_chanmgr_bridges(&chanmgr, config)?;
         let guardmgr = tor_guardmgr::GuardMgr::new(runtime.clone(), statemgr.clone(), config)
             .map_err(ErrorDetail::GuardMgrSetup)?;
 
@@ -896,12 +1203,15 @@ impl<R: Runtime> TorClient<R> {
             let pt_state_dir = state_dir.as_path().join("pt_state");
             config.storage.permissions().make_directory(&pt_state_dir)?;
 
-            let mgr = Arc::new(tor_ptmgr::PtMgr::new(
-                config.bridges.transports.clone(),
-                pt_state_dir,
-                Arc::clone(&path_resolver),
-                runtime.clone(),
-            )?);
+            let mgr = Arc::new(
+                tor_ptmgr::PtMgr::new(
+                    config.bridges.transports.clone(),
+                    pt_state_dir,
+                    Arc::clone(&path_resolver),
+                    runtime.clone(),
+                )?
+                .with_bandwidth_limiter(chanmgr.bandwidth_limiter().clone()),
+            );
 
             chanmgr.set_pt_mgr(mgr.clone());
 
@@ -920,9 +1230,12 @@ impl<R: Runtime> TorClient<R> {
         );
 
         let timeout_cfg = config.stream_timeouts.clone();
+        let dns_cache = Arc::new(DnsCache::new(config.dns_cache.clone()));
+        let address_map = Arc::new(AddressMap::new(&config.address_map));
 
-        let dirmgr_store =
-            DirMgrStore::new(&dir_cfg, runtime.clone(), false).map_err(ErrorDetail::DirMgrSetup)?;
+        let dirmgr_store = DirMgrStore::new(&dir_cfg, runtime.clone(), false)
+            .map_err(ErrorDetail::DirMgrSetup)?
+            .with_cache_pool(&cache_pool);
         let dirmgr = dirmgr_builder
             .build(
                 runtime.clone(),
@@ -997,7 +1310,13 @@ impl<R: Runtime> TorClient<R> {
             });
             let housekeeping = Box::pin(housekeeping);
 
-            HsClientConnector::new(runtime.clone(), hs_circ_pool.clone(), config, housekeeping)?
+            HsClientConnector::new(
+                runtime.clone(),
+                hs_circ_pool.clone(),
+                config,
+                housekeeping,
+                &cache_pool,
+            )?
         };
 
         runtime
@@ -1011,6 +1330,21 @@ impl<R: Runtime> TorClient<R> {
             ))
             .map_err(|e| ErrorDetail::from_spawn("periodic task dormant monitor", e))?;
 
+        let dormant = Arc::new(Mutex::new(dormant_send));
+        let dormancy_cfg = Arc::new(MutCfg::new(config.dormancy.clone()));
+        let activity = Arc::new(Activity::new(runtime.now()));
+        let traffic = Arc::new(TrafficTotals::new());
+        runtime
+            .spawn(dormancy::run_auto_dormancy(
+                runtime.clone(),
+                Arc::downgrade(&dormancy_cfg),
+                Arc::downgrade(&activity),
+                Arc::downgrade(&traffic),
+                Arc::downgrade(&dormant),
+                Arc::downgrade(&circmgr),
+            ))
+            .map_err(|e| ErrorDetail::from_spawn("automatic dormancy", e))?;
+
         let conn_status = chanmgr.bootstrap_events();
         let dir_status = dirmgr.bootstrap_events();
         let skew_status = circmgr.skew_events();
@@ -1048,11 +1382,22 @@ impl<R: Runtime> TorClient<R> {
             statemgr,
             addrcfg: Arc::new(addr_cfg.into()),
             timeoutcfg: Arc::new(timeout_cfg.into()),
+            config: Arc::new(config.clone().into()),
+            startup_config: Arc::new(config.clone()),
             reconfigure_lock: Arc::new(Mutex::new(())),
             status_receiver,
             bootstrap_in_progress: Arc::new(AsyncMutex::new(())),
+            traffic,
+            stream_auditor: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "moat")]
+            bridge_source: Arc::new(Mutex::new(None)),
+            shutdown: Arc::new(Shutdown::new()),
+            dns_cache,
+            address_map,
+            dormancy_cfg,
+            activity,
             should_bootstrap: autobootstrap,
-            dormant: Arc::new(Mutex::new(dormant_send)),
+            dormant,
             #[cfg(feature = "onion-service-service")]
             state_directory,
             path_resolver,
@@ -1132,10 +1477,13 @@ impl<R: Runtime> TorClient<R> {
         // unlock the state files.
         let unlock_guard = util::StateMgrUnlockGuard::new(&self.statemgr);
 
+        #[cfg(not(feature = "moat"))]
         self.dirmgr
             .bootstrap()
             .await
             .map_err(ErrorDetail::DirMgrBootstrap)?;
+        #[cfg(feature = "moat")]
+        self.bootstrap_dirmgr_or_replace_bridges().await?;
 
         // Since we succeeded, disarm the unlock guard.
         unlock_guard.disarm();
@@ -1143,6 +1491,68 @@ impl<R: Runtime> TorClient<R> {
         Ok(())
     }
 
+    /// Bootstrap our directory manager.  If that fails while we are using
+    /// bridges, and we have a [`BridgeSource`](tor_moat::BridgeSource),
+    /// replace our bridges with new ones from it, and try once more.
+    #[cfg(feature = "moat")]
+    async fn bootstrap_dirmgr_or_replace_bridges(&self) -> StdResult<(), ErrorDetail> {
+        use tor_error::warn_report;
+        use tor_guardmgr::GuardMgrConfig as _;
+
+        let err = match self.dirmgr.bootstrap().await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let source = self
+            .bridge_source
+            .lock()
+            .expect("bridge source lock poisoned")
+            .clone();
+        let config = self.config.get();
+        let Some(source) = source.filter(|_| config.bridges_enabled()) else {
+            return Err(ErrorDetail::DirMgrBootstrap(err));
+        };
+
+        warn_report!(
+            err,
+            "Unable to bootstrap with our configured bridges; obtaining new ones"
+        );
+        #[cfg(feature = "pt-client")]
+        let transports: Vec<String> = config
+            .bridges
+            .transports
+            .iter()
+            .flat_map(|t| t.get_protocols())
+            .map(|p| p.to_string())
+            .collect();
+        #[cfg(not(feature = "pt-client"))]
+        let transports: Vec<String> = vec![];
+        let bridges = source
+            .fetch_bridges(&transports)
+            .await
+            .map_err(ErrorDetail::ObtainBridges)?
+            .iter()
+            .filter_map(|bridge| match bridge.build() {
+                Ok(bridge) => Some(bridge),
+                Err(e) => {
+                    warn_report!(e, "Ignoring unusable bridge");
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if bridges.is_empty() {
+            return Err(ErrorDetail::ObtainBridges(tor_moat::Error::NoBridges));
+        }
+        info!("Replacing our bridges with {} new ones.", bridges.len());
+        self.set_bridges(bridges)
+            .map_err(crate::Error::into_detail)?;
+
+        self.dirmgr
+            .bootstrap()
+            .await
+            .map_err(ErrorDetail::DirMgrBootstrap)
+    }
+
     /// ## For `BootstrapBehavior::OnDemand` clients
     ///
     /// Initiate a bootstrap by calling `bootstrap` (which is idempotent, so attempts to
@@ -1153,6 +1563,7 @@ impl<R: Runtime> TorClient<R> {
     /// Check whether a bootstrap is in progress; if one is, wait until it finishes
     /// and then return. (Otherwise, return immediately.)
     async fn wait_for_bootstrap(&self) -> StdResult<(), ErrorDetail> {
+        self.activity.note_used(self.runtime.now());
         match self.should_bootstrap {
             BootstrapBehavior::OnDemand => {
                 self.bootstrap_inner().await?;
@@ -1187,16 +1598,30 @@ impl<R: Runtime> TorClient<R> {
     /// from the same call to `TorClient::create_*`: even ones whose circuits
     /// are isolated from this handle.
     ///
+    /// On success, returns a [`ReconfigureReport`] listing any options that
+    /// could not be changed, and were ignored.
+    ///
     /// # Limitations
     ///
     /// Although most options are reconfigurable, there are some whose values
-    /// can't be changed on an a running TorClient.  Those options (or their
-    /// sections) are explicitly documented not to be changeable.
-    /// NOTE: Currently, not all of these non-reconfigurable options are
-    /// documented. See [arti#1721][arti-1721].
-    ///
-    /// [arti-1721]: https://gitlab.torproject.org/tpo/core/arti/-/issues/1721
-    ///
+    /// can't be changed on an a running TorClient, and need a restart:
+    /// `storage.state_dir`, `storage.cache_dir`, `storage.permissions`,
+    /// `storage.keystore`, `tor_network.authorities`,
+    /// `preemptive_circuits.initial_predicted_ports`, and enabling
+    /// `system.memory` tracking when it was disabled at startup.
+    /// With [`Reconfigure::WarnOnFailures`](tor_config::Reconfigure::WarnOnFailures),
+    /// changes to these are ignored, and listed in the report; otherwise they
+    /// are an error.
+    ///
+    /// Everything else takes effect without a restart, including bridges and
+    /// pluggable transports, channel options, circuit timing (for onion
+    /// service connections too), vanguards, timeouts, and address and path
+    /// restrictions.
+    ///
+    /// This does not reconfigure any onion services that were launched with
+    /// `TorClient::launch_onion_service`: their configuration is not part of
+    /// the [`TorClientConfig`].  To change one, use
+    /// `RunningOnionService::reconfigure`.
     /// Changing some options do not take effect immediately on all open streams
     /// and circuits, but rather affect only future streams and circuits.  Those
     /// are also explicitly documented.
@@ -1204,7 +1629,7 @@ impl<R: Runtime> TorClient<R> {
         &self,
         new_config: &TorClientConfig,
         how: tor_config::Reconfigure,
-    ) -> crate::Result<()> {
+    ) -> crate::Result<ReconfigureReport> {
         // We need to hold this lock while we're reconfiguring the client: even
         // though the individual fields have their own synchronization, we can't
         // safely let two threads change them at once.  If we did, then we'd
@@ -1229,7 +1654,7 @@ impl<R: Runtime> TorClient<R> {
         // Actually reconfigure
         self.reconfigure_inner(new_config, how, &guard)?;
 
-        Ok(())
+        Ok(self.startup_config.ignored_changes(new_config))
     }
 
     /// This is split out from `reconfigure` so we can do the all-or-nothing
@@ -1253,9 +1678,17 @@ impl<R: Runtime> TorClient<R> {
         let addr_cfg = &new_config.address_filter;
         let timeout_cfg = &new_config.stream_timeouts;
 
-        if state_cfg != self.statemgr.path() {
+        let startup_state_cfg = self
+            .startup_config
+            .storage
+            .expand_state_dir(&self.path_resolver)
+            .map_err(wrap_err)?;
+        if state_cfg != startup_state_cfg {
             how.cannot_change("storage.state_dir").map_err(wrap_err)?;
         }
+        if new_config.keystore() != self.startup_config.keystore() {
+            how.cannot_change("storage.keystore").map_err(wrap_err)?;
+        }
 
         self.memquota
             .reconfigure(new_config.system.memory.clone(), how)
@@ -1284,18 +1717,122 @@ impl<R: Runtime> TorClient<R> {
             .reconfigure(how, new_config.bridges.transports.clone())
             .map_err(wrap_err)?;
 
+        #[cfg(feature = "onion-service-client")]
+        self.hsclient
+            .reconfigure(new_config, how)
+            .map_err(wrap_err)?;
+
         if how == tor_config::Reconfigure::CheckAllOrNothing {
             return Ok(());
         }
 
+        #[cfg(feature = "bridge-client")]
+        set_chanmgr_bridges(&self.chanmgr, new_config).map_err(wrap_err)?;
+
         self.addrcfg.replace(addr_cfg.clone());
         self.timeoutcfg.replace(timeout_cfg.clone());
+        self.dns_cache.reconfigure(&new_config.dns_cache);
+        self.address_map.reconfigure(&new_config.address_map);
+        self.dormancy_cfg.replace(new_config.dormancy.clone());
         self.software_status_cfg
             .replace(new_config.use_obsolete_software.clone());
+        self.config.replace(new_config.clone());
 
         Ok(())
     }
 
+    /// Replace our configured bridges with `bridges`, without restarting.
+    ///
+    /// This is like calling [`reconfigure`](TorClient::reconfigure) with our
+    /// current configuration, with its `bridges.bridges` replaced, and with
+    /// [`Reconfigure::AllOrNothing`](tor_config::Reconfigure::AllOrNothing).
+    /// Unless `bridges.enabled` is explicitly set, we start using bridges
+    /// when there are some, and stop when there are none.
+    ///
+    /// Once our bridges change, we stop using our existing circuits for new
+    /// streams.
+    #[cfg(feature = "bridge-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bridge-client")))]
+    pub fn set_bridges(
+        &self,
+        bridges: impl IntoIterator<Item = BridgeConfig>,
+    ) -> crate::Result<()> {
+        self.change_bridges(|list| *list = bridges.into_iter().collect())
+    }
+
+    /// Add `bridge` to our configured bridges, without restarting.
+    ///
+    /// Does nothing if we already have this bridge.
+    /// See [`set_bridges`](TorClient::set_bridges) for details.
+    #[cfg(feature = "bridge-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bridge-client")))]
+    pub fn add_bridge(&self, bridge: BridgeConfig) -> crate::Result<()> {
+        self.change_bridges(|list| {
+            if !list.contains(&bridge) {
+                list.push(bridge);
+            }
+        })
+    }
+
+    /// Remove `bridge` from our configured bridges, without restarting.
+    ///
+    /// Does nothing if we don't have this bridge.
+    /// See [`set_bridges`](TorClient::set_bridges) for details.
+    #[cfg(feature = "bridge-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bridge-client")))]
+    pub fn remove_bridge(&self, bridge: &BridgeConfig) -> crate::Result<()> {
+        self.change_bridges(|list| list.retain(|b| b != bridge))
+    }
+
+    /// Return the bridges that we are configured with.
+    #[cfg(feature = "bridge-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bridge-client")))]
+    pub fn bridges(&self) -> Vec<BridgeConfig> {
+        self.config.get().bridges.bridges.clone()
+    }
+
+    /// Reconfigure ourselves with our current configuration, after using `f`
+    /// to change its list of bridges.
+    #[cfg(feature = "bridge-client")]
+    fn change_bridges(&self, f: impl FnOnce(&mut BridgeList)) -> crate::Result<()> {
+        // Hold the lock from reading our configuration until we have replaced
+        // it, so that concurrent changes can't be lost.
+        let guard = self.reconfigure_lock.lock().expect("Poisoned lock");
+
+        let mut new_config = TorClientConfig::clone(&self.config.get());
+        f(&mut new_config.bridges.bridges);
+        if new_config.bridges == self.config.get().bridges {
+            return Ok(());
+        }
+        new_config
+            .bridges
+            .validate_bridge_list()
+            .map_err(wrap_err)?;
+
+        self.reconfigure_inner(
+            &new_config,
+            tor_config::Reconfigure::CheckAllOrNothing,
+            &guard,
+        )?;
+        self.reconfigure_inner(&new_config, tor_config::Reconfigure::AllOrNothing, &guard)
+    }
+
+    /// Replace the limits on how fast this client may read from, and write
+    /// to, the Tor network.
+    ///
+    /// `None` means that there is no limit.  The limits apply to all our
+    /// channels together, including those that already exist.  They replace
+    /// the `channel.read_rate` and `channel.write_rate` limits from our
+    /// configuration, until those are next changed by
+    /// [`reconfigure`](TorClient::reconfigure).
+    pub fn set_bandwidth_limits(
+        &self,
+        read: Option<BandwidthLimit>,
+        write: Option<BandwidthLimit>,
+    ) {
+        self.chanmgr.set_bandwidth_limits(read, write);
+    }
+
     /// Return a new isolated `TorClient` handle.
     ///
     /// The two `TorClient`s will share internal state and configuration, but
@@ -1318,6 +1855,115 @@ impl<R: Runtime> TorClient<R> {
         result
     }
 
+    /// Return a new `TorClient` that shares this client's directory
+    /// information and channels, but builds its circuits separately.
+    ///
+    /// This is for applications that act for several users (or accounts) at
+    /// once, and need more separation between them than
+    /// [`isolated_client`](TorClient::isolated_client) gives, without
+    /// bootstrapping a whole new client for each.  The returned client has
+    /// a circuit manager of its own, so that none of its circuits, including
+    /// the ones it builds in advance, are ever used by this client (or vice
+    /// versa), and it is isolated from this client like an `isolated_client`.
+    ///
+    /// If `separate_guards` is true, the new client also chooses guards of
+    /// its own.  Otherwise, it uses the same guards as this client.
+    ///
+    /// The new client keeps its persistent state (such as its circuit
+    /// timeouts, and its guards, if they are separate) in the state namespace
+    /// called `name`: see [`state_namespace`](TorClient::state_namespace).
+    /// Use the same name for the same user every time, so that they keep
+    /// their guards.
+    ///
+    /// Connections to onion services are still made by this client's onion
+    /// service connector, with circuits through this client's guards,
+    /// although they never share circuits either.  Calling
+    /// [`reconfigure`](TorClient::reconfigure) on either client changes the
+    /// parts that they share, but only changes the circuit settings of the
+    /// client on which it was called.
+    ///
+    /// This function is unstable. It is only enabled if the crate was
+    /// built with the `experimental-api` feature.
+    #[cfg(feature = "experimental-api")]
+    pub fn clone_isolated(
+        &self,
+        name: &tor_persist::slug::SlugRef,
+        separate_guards: bool,
+    ) -> crate::Result<TorClient<R>> {
+        self.clone_isolated_inner(name, separate_guards)
+            .map_err(ErrorDetail::into)
+    }
+
+    /// Implementation of [`TorClient::clone_isolated`], split out in order to
+    /// avoid manually specifying double error conversions.
+    #[cfg(feature = "experimental-api")]
+    fn clone_isolated_inner(
+        &self,
+        name: &tor_persist::slug::SlugRef,
+        separate_guards: bool,
+    ) -> StdResult<TorClient<R>, ErrorDetail> {
+        let config = self.config.get();
+        let statemgr = ClientStateMgr::Fs(self.statemgr.namespace(name)?);
+        // As in create_inner, we don't yet care whether we get the lock.
+        let _ignore_status = statemgr.try_lock().map_err(ErrorDetail::StateMgrSetup)?;
+
+        let guardmgr = if separate_guards {
+            GuardMgr::new(self.runtime.clone(), statemgr.clone(), &*config)
+                .map_err(ErrorDetail::GuardMgrSetup)?
+        } else {
+            self.guardmgr.clone()
+        };
+        let circmgr = Arc::new(
+            tor_circmgr::CircMgr::new(
+                &*config,
+                statemgr.clone(),
+                &self.runtime,
+                Arc::clone(&self.chanmgr),
+                &guardmgr,
+            )
+            .map_err(ErrorDetail::CircMgrSetup)?,
+        );
+        let periodic_task_handles = circmgr
+            .launch_background_tasks(&self.runtime, &self.dirmgr, statemgr.clone())
+            .map_err(ErrorDetail::CircMgrSetup)?;
+
+        // With guards of our own, we need a bridge descriptor manager of our own
+        // to tell them about their bridges; bootstrapping will make one.
+        #[cfg(feature = "bridge-client")]
+        let bridge_desc_mgr = if separate_guards {
+            Arc::new(Mutex::new(None))
+        } else {
+            self.bridge_desc_mgr.clone()
+        };
+
+        let dormant_recv = self
+            .dormant
+            .lock()
+            .map_err(|_| internal!("dormant poisoned"))?
+            .subscribe();
+        self.runtime
+            .spawn(tasks_monitor_dormant(
+                dormant_recv,
+                self.dirmgr.clone().upcast_arc(),
+                self.chanmgr.clone(),
+                #[cfg(feature = "bridge-client")]
+                bridge_desc_mgr.clone(),
+                periodic_task_handles,
+            ))
+            .map_err(|e| ErrorDetail::from_spawn("periodic task dormant monitor", e))?;
+
+        let mut result = self.clone();
+        result.client_isolation = IsolationToken::new();
+        result.statemgr = statemgr;
+        result.guardmgr = guardmgr;
+        result.circmgr = circmgr;
+        #[cfg(feature = "bridge-client")]
+        {
+            result.bridge_desc_mgr = bridge_desc_mgr;
+        }
+        Ok(result)
+    }
+
     /// Launch an anonymized connection to the provided address and port over
     /// the Tor network.
     ///
@@ -1396,57 +2042,368 @@ impl<R: Runtime> TorClient<R> {
         target: A,
         prefs: &StreamPrefs,
     ) -> crate::Result<DataStream> {
-        let addr = target.into_tor_addr().map_err(wrap_err)?;
-        let mut stream_parameters = prefs.stream_parameters();
+        let addr = self
+            .address_map
+            .apply(target.into_tor_addr().map_err(wrap_err)?);
+        let policy = &prefs.retry_policy;
+
+        let mut n_failed = 0;
+        loop {
+            match self.try_connect(&addr, prefs).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) if n_failed + 1 < policy.n_attempts() => {
+                    n_failed += 1;
+                    debug_report!(
+                        e,
+                        "Attempt {} to connect to {} failed; retrying",
+                        n_failed,
+                        sensitive(&addr)
+                    );
+                    self.runtime.sleep(policy.delay_after(n_failed)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-        let (circ, addr, port) = match addr.into_stream_instructions(&self.addrcfg.get(), prefs)? {
-            StreamInstructions::Exit {
-                hostname: addr,
-                port,
-            } => {
-                let exit_ports = [prefs.wrap_target_port(port)];
-                let circ = self
-                    .get_or_launch_exit_circ(&exit_ports, prefs)
-                    .await
-                    .map_err(wrap_err)?;
-                debug!("Got a circuit for {}:{}", sensitive(&addr), port);
-                (circ, addr, port)
+    /// Make a single attempt to open a stream to `addr`, as part of
+    /// [`connect_with_prefs`](TorClient::connect_with_prefs).
+    async fn try_connect(&self, addr: &TorAddr, prefs: &StreamPrefs) -> crate::Result<DataStream> {
+        if prefs.race_circuits {
+            if let StreamInstructions::Exit { hostname, port } = addr
+                .clone()
+                .into_stream_instructions(&self.addrcfg.get(), prefs)?
+            {
+                return self.race_exit_streams(&hostname, port, prefs).await;
             }
+        }
 
-            #[cfg(not(feature = "onion-service-client"))]
-            #[allow(unused_variables)] // for hostname and port
-            StreamInstructions::Hs {
-                hsid,
-                hostname,
-                port,
-            } => void::unreachable(hsid.0),
+        let mut stream_parameters = prefs.stream_parameters();
+        let (circ, addr, port) = self
+            .get_or_launch_circ_for_addr(addr.clone(), prefs, &mut stream_parameters)
+            .await?;
+        self.begin_stream_on(&circ, &addr, port, stream_parameters, prefs)
+            .await
+    }
 
-            #[cfg(feature = "onion-service-client")]
-            StreamInstructions::Hs {
-                hsid,
-                hostname,
-                port,
-            } => {
-                self.wait_for_bootstrap().await?;
-                let netdir = self.netdir(Timeliness::Timely, "connect to a hidden service")?;
+    /// Open a stream to `hostname`:`port` through an exit, on two circuits at
+    /// once, and return whichever stream opens first.
+    ///
+    /// See [`StreamPrefs::race_circuits`].
+    async fn race_exit_streams(
+        &self,
+        hostname: &str,
+        port: u16,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<DataStream> {
+        let exit_ports = [prefs.wrap_target_port(port)];
+        let first = self
+            .get_or_launch_exit_circ(&exit_ports, prefs)
+            .await
+            .map_err(wrap_err)?;
 
-                let mut hs_client_secret_keys_builder = HsClientSecretKeysBuilder::default();
+        // Make sure that the second circuit doesn't use the same exit.
+        let exit_ids: Vec<RelayId> = first
+            .path_ref()
+            .hops()
+            .last()
+            .and_then(|hop| hop.as_chan_target())
+            .map(|exit| exit.identities().map(|id| id.to_owned()).collect())
+            .unwrap_or_default();
+        // Get both circuits before we send any BEGIN, so that we are racing
+        // the streams, and not one stream against the building of a circuit.
+        let second = if exit_ids.is_empty() {
+            // We can't tell which exit to avoid; don't race.
+            None
+        } else {
+            let mut second_prefs = prefs.clone();
+            for id in exit_ids {
+                second_prefs.exclude_relay(id);
+            }
+            match self
+                .get_or_launch_exit_circ(&exit_ports, &second_prefs)
+                .await
+            {
+                Ok(circ) => Some(circ),
+                Err(e) => {
+                    debug_report!(
+                        e,
+                        "Couldn't get a second circuit to {}:{}; not racing",
+                        sensitive(hostname),
+                        port
+                    );
+                    None
+                }
+            }
+        };
 
-                if let Some(keymgr) = &self.inert_client.keymgr {
-                    let desc_enc_key_spec = HsClientDescEncKeypairSpecifier::new(hsid);
+        let begin = |circ: Arc<ClientCirc>| async move {
+            self.begin_stream_on(&circ, hostname, port, prefs.stream_parameters(), prefs)
+                .await
+        };
+        match second {
+            Some(second) => race_streams(first, second, begin).await,
+            None => begin(first).await,
+        }
+    }
 
-                    let ks_hsc_desc_enc =
-                        keymgr.get::<HsClientDescEncKeypair>(&desc_enc_key_spec)?;
+    /// Open a stream to `hostname`:`port` on `circ`, following the
+    /// [`RetryPolicy`] in `prefs`.
+    async fn begin_stream_on(
+        &self,
+        circ: &Arc<ClientCirc>,
+        hostname: &str,
+        port: u16,
+        mut stream_parameters: StreamParameters,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<DataStream> {
+        self.check_not_shutting_down("open a stream")?;
+        self.shutdown.note_circ(circ);
+        let policy = &prefs.retry_policy;
+        stream_parameters.count_traffic_in(Arc::clone(&self.traffic));
+        self.audit_stream(&mut stream_parameters, circ, hostname, port, prefs);
+        let stream_future = circ.begin_stream(hostname, port, Some(stream_parameters));
+        let timeout = policy.timeout_or(self.timeoutcfg.get().connect_timeout);
+        // This timeout is needless but harmless for optimistic streams.
+        let result = self
+            .runtime
+            .timeout(timeout, stream_future)
+            .await
+            .map_err(|_| ErrorDetail::ExitTimeout)
+            .and_then(|r| {
+                r.map_err(|cause| ErrorDetail::StreamFailed {
+                    cause,
+                    kind: "data",
+                })
+            });
+
+        if result.is_err() && policy.wants_new_circuit() {
+            // Make sure that the next attempt doesn't use this circuit.
+            self.circmgr.retire_circ(&circ.unique_id());
+        }
+
+        let stream = result?;
+        if let Some(interval) = prefs.keepalive {
+            self.runtime
+                .spawn(keepalive::keep_stream_alive(
+                    self.runtime.clone(),
+                    Arc::downgrade(circ),
+                    stream.stats_handle(),
+                    interval,
+                ))
+                .map_err(|e| ErrorDetail::from_spawn("stream keep-alive", e))?;
+        }
+        Ok(stream)
+    }
+
+    /// If this client has a [`StreamAuditor`], configure `stream_parameters`
+    /// to report the stream to `hostname`:`port` on `circ` to it, once the
+    /// stream closes.
+    fn audit_stream(
+        &self,
+        stream_parameters: &mut StreamParameters,
+        circ: &ClientCirc,
+        hostname: &str,
+        port: u16,
+        prefs: &StreamPrefs,
+    ) {
+        let auditor = self
+            .stream_auditor
+            .lock()
+            .expect("stream auditor lock poisoned")
+            .clone();
+        if let Some(auditor) = auditor {
+            stream_parameters.on_close(audit::close_hook(
+                auditor,
+                self.runtime.clone(),
+                hostname,
+                port,
+                self.isolation(prefs, Some(port)),
+                circ,
+            ));
+        }
+    }
+
+    /// Return an error if this client is shutting down, and so can't `action`.
+    fn check_not_shutting_down(&self, action: &'static str) -> StdResult<(), ErrorDetail> {
+        if self.shutdown.is_started() {
+            return Err(ErrorDetail::ShuttingDown { action });
+        }
+        Ok(())
+    }
+
+    /// Open an anonymized UDP stream to `target`, as described in proposal 339.
+    ///
+    /// Every datagram sent on the returned [`UdpStream`] goes to `target`,
+    /// and every datagram received on it comes from there.
+    /// Datagrams may be dropped, as with UDP.
+    ///
+    /// Few exit relays support UDP streams yet, and we don't know which ones
+    /// do: if the exit that we choose doesn't, this returns an error.
+    #[cfg(feature = "experimental-udp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental-udp")))]
+    pub async fn connect_udp<A: IntoTorAddr>(&self, target: A) -> crate::Result<UdpStream> {
+        self.connect_udp_with_prefs(target, &self.connect_prefs)
+            .await
+    }
+
+    /// Open an anonymized UDP stream to `target`, with the preferences in
+    /// `prefs`.
+    ///
+    /// See [`connect_udp`](TorClient::connect_udp).
+    /// Unlike [`connect_with_prefs`](TorClient::connect_with_prefs),
+    /// this makes only one attempt, whatever the retry policy in `prefs`.
+    #[cfg(feature = "experimental-udp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental-udp")))]
+    pub async fn connect_udp_with_prefs<A: IntoTorAddr>(
+        &self,
+        target: A,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<UdpStream> {
+        let addr = self
+            .address_map
+            .apply(target.into_tor_addr().map_err(wrap_err)?);
+        let mut stream_parameters = prefs.stream_parameters();
+        let (circ, hostname, port) = self
+            .get_or_launch_circ_for_addr(addr, prefs, &mut stream_parameters)
+            .await?;
+        self.check_not_shutting_down("open a stream")?;
+        self.shutdown.note_circ(&circ);
+        stream_parameters.count_traffic_in(Arc::clone(&self.traffic));
+        self.audit_stream(&mut stream_parameters, &circ, &hostname, port, prefs);
+        let timeout = prefs
+            .retry_policy
+            .timeout_or(self.timeoutcfg.get().connect_timeout);
+        let stream = self
+            .runtime
+            .timeout(
+                timeout,
+                circ.begin_udp_stream(&hostname, port, Some(stream_parameters)),
+            )
+            .await
+            .map_err(|_| ErrorDetail::ExitTimeout)?
+            .map_err(|cause| ErrorDetail::StreamFailed { cause, kind: "UDP" })?;
+        Ok(stream)
+    }
+
+    /// Get a circuit that can carry a connection to the provided address and
+    /// port, building one if necessary, but don't connect.
+    ///
+    /// Interactive applications can call this when they expect to connect
+    /// to `target` soon (for example, when a user starts typing an address),
+    /// so that they don't have to wait for a circuit to be built when they
+    /// do.  A later call to [`connect`](TorClient::connect) with the same
+    /// preferences will use the circuit, if it is still usable.
+    ///
+    /// This deliberately doesn't open the connection at the exit: a stream
+    /// that nobody reads from would tie up resources at the exit, and the exit
+    /// may close it if it stays idle for too long.  If you want to open the
+    /// connection ahead of time anyway, call [`connect`](TorClient::connect)
+    /// and keep the [`DataStream`] until it is needed.
+    ///
+    /// If the preferences say to isolate every stream, no later connection
+    /// could use the circuit, so this does nothing.
+    pub async fn preconnect<A: IntoTorAddr>(&self, target: A) -> crate::Result<()> {
+        self.preconnect_with_prefs(target, &self.connect_prefs)
+            .await
+    }
 
-                    if let Some(ks_hsc_desc_enc) = ks_hsc_desc_enc {
-                        debug!("Found descriptor decryption key for {hsid}");
-                        hs_client_secret_keys_builder.ks_hsc_desc_enc(ks_hsc_desc_enc);
+    /// Get a circuit that can carry a connection to the provided address and
+    /// port, with explicit connection preferences, but don't connect.
+    ///
+    /// (See [`TorClient::preconnect()`] for more information.)
+    pub async fn preconnect_with_prefs<A: IntoTorAddr>(
+        &self,
+        target: A,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<()> {
+        let addr = self
+            .address_map
+            .apply(target.into_tor_addr().map_err(wrap_err)?);
+        if matches!(prefs.isolation, StreamIsolationPreference::EveryStream) {
+            debug!(
+                "Not preparing a circuit for {}: every stream is isolated",
+                sensitive(&addr)
+            );
+            return Ok(());
+        }
+        let mut stream_parameters = prefs.stream_parameters();
+        let (_circ, addr, port) = self
+            .get_or_launch_circ_for_addr(addr, prefs, &mut stream_parameters)
+            .await?;
+        debug!("Prepared a circuit for {}:{}", sensitive(&addr), port);
+        Ok(())
+    }
+
+    /// Return a circuit that can carry a stream to `addr`, along with the
+    /// hostname and port to put in the BEGIN message.
+    ///
+    /// Adjusts `stream_parameters` as the circuit requires.
+    #[cfg_attr(not(feature = "onion-service-client"), allow(unused_variables))]
+    async fn get_or_launch_circ_for_addr(
+        &self,
+        addr: TorAddr,
+        prefs: &StreamPrefs,
+        stream_parameters: &mut StreamParameters,
+    ) -> crate::Result<(Arc<ClientCirc>, String, u16)> {
+        self.check_not_shutting_down("build a circuit")?;
+        let circ_addr_port = match addr.into_stream_instructions(&self.addrcfg.get(), prefs)? {
+            StreamInstructions::Exit {
+                hostname: addr,
+                port,
+            } => {
+                let exit_ports = [prefs.wrap_target_port(port)];
+                let circ = self
+                    .get_or_launch_exit_circ(&exit_ports, prefs)
+                    .await
+                    .map_err(wrap_err)?;
+                debug!("Got a circuit for {}:{}", sensitive(&addr), port);
+                (circ, addr, port)
+            }
+
+            #[cfg(not(feature = "onion-service-client"))]
+            #[allow(unused_variables)] // for hostname and port
+            StreamInstructions::Hs {
+                hsid,
+                hostname,
+                port,
+            } => void::unreachable(hsid.0),
+
+            #[cfg(feature = "onion-service-client")]
+            StreamInstructions::Hs {
+                hsid,
+                hostname,
+                port,
+            } => {
+                self.wait_for_bootstrap().await?;
+                let netdir = self.netdir(Timeliness::Timely, "connect to a hidden service")?;
+
+                let hs_client_secret_keys = match prefs.service_discovery_keys_for(hsid) {
+                    Some(keys) => {
+                        debug!("Using provided descriptor decryption key for {hsid}");
+                        keys
                     }
-                };
+                    None => {
+                        let mut hs_client_secret_keys_builder =
+                            HsClientSecretKeysBuilder::default();
 
-                let hs_client_secret_keys = hs_client_secret_keys_builder
-                    .build()
-                    .map_err(ErrorDetail::Configuration)?;
+                        if let Some(keymgr) = &self.inert_client.keymgr {
+                            let desc_enc_key_spec = HsClientDescEncKeypairSpecifier::new(hsid);
+
+                            let ks_hsc_desc_enc =
+                                keymgr.get::<HsClientDescEncKeypair>(&desc_enc_key_spec)?;
+
+                            if let Some(ks_hsc_desc_enc) = ks_hsc_desc_enc {
+                                debug!("Found descriptor decryption key for {hsid}");
+                                hs_client_secret_keys_builder.ks_hsc_desc_enc(ks_hsc_desc_enc);
+                            }
+                        };
+
+                        hs_client_secret_keys_builder
+                            .build()
+                            .map_err(ErrorDetail::Configuration)?
+                    }
+                };
 
                 let circ = self
                     .hsclient
@@ -1454,7 +2411,7 @@ impl<R: Runtime> TorClient<R> {
                         &netdir,
                         hsid,
                         hs_client_secret_keys,
-                        self.isolation(prefs),
+                        self.isolation(prefs, Some(port)),
                     )
                     .await
                     .map_err(|cause| ErrorDetail::ObtainHsCircuit {
@@ -1462,29 +2419,14 @@ impl<R: Runtime> TorClient<R> {
                         hsid: hsid.into(),
                     })?;
                 // On connections to onion services, we have to suppress
-                // everything except the port from the BEGIN message.  We also
-                // disable optimistic data.
-                stream_parameters
-                    .suppress_hostname()
-                    .suppress_begin_flags()
-                    .optimistic(false);
+                // everything except the port from the BEGIN message.
+                // (Onion services accept optimistic data, so we leave that
+                // up to the caller's preferences.)
+                stream_parameters.suppress_hostname().suppress_begin_flags();
                 (circ, hostname, port)
             }
         };
-
-        let stream_future = circ.begin_stream(&addr, port, Some(stream_parameters));
-        // This timeout is needless but harmless for optimistic streams.
-        let stream = self
-            .runtime
-            .timeout(self.timeoutcfg.get().connect_timeout, stream_future)
-            .await
-            .map_err(|_| ErrorDetail::ExitTimeout)?
-            .map_err(|cause| ErrorDetail::StreamFailed {
-                cause,
-                kind: "data",
-            })?;
-
-        Ok(stream)
+        Ok(circ_addr_port)
     }
 
     /// Sets the default preferences for future connections made with this client.
@@ -1521,51 +2463,126 @@ impl<R: Runtime> TorClient<R> {
         hostname: &str,
         prefs: &StreamPrefs,
     ) -> crate::Result<Vec<IpAddr>> {
+        let answer = self.resolve_answer_with_prefs(hostname, prefs).await?;
+        if let Some(cause) = answer.resolve_error() {
+            return Err(ErrorDetail::StreamFailed {
+                cause,
+                kind: "DNS lookup",
+            }
+            .into());
+        }
+        Ok(answer.ips().collect())
+    }
+
+    /// Perform a remote DNS reverse lookup with the provided IP address.
+    ///
+    /// On success, return a list of hostnames.
+    pub async fn resolve_ptr(&self, addr: IpAddr) -> crate::Result<Vec<String>> {
+        self.resolve_ptr_with_prefs(addr, &self.connect_prefs).await
+    }
+
+    /// Perform a remote DNS reverse lookup with the provided IP address.
+    ///
+    /// On success, return a list of hostnames.
+    pub async fn resolve_ptr_with_prefs(
+        &self,
+        addr: IpAddr,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<Vec<String>> {
+        let answer = self.resolve_ptr_answer_with_prefs(addr, prefs).await?;
+        if let Some(cause) = answer.resolve_error() {
+            return Err(ErrorDetail::StreamFailed {
+                cause,
+                kind: "reverse DNS lookup",
+            }
+            .into());
+        }
+        Ok(answer.hostnames().map(str::to_owned).collect())
+    }
+
+    /// Perform a remote DNS lookup, and return every record in the reply,
+    /// with how long each may be cached.
+    ///
+    /// Unlike with [`resolve`](TorClient::resolve), errors reported by the
+    /// exit relay are records in the returned [`DnsAnswer`], not an `Err`.
+    pub async fn resolve_answer(&self, hostname: &str) -> crate::Result<DnsAnswer> {
+        self.resolve_answer_with_prefs(hostname, &self.connect_prefs)
+            .await
+    }
+
+    /// Perform a remote DNS lookup, and return every record in the reply,
+    /// but use prefs.
+    pub async fn resolve_answer_with_prefs(
+        &self,
+        hostname: &str,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<DnsAnswer> {
         // TODO This dummy port is only because `address::Host` is not pub(crate),
         // but I see no reason why it shouldn't be?  Then `into_resolve_instructions`
         // should be a method on `Host`, not `TorAddr`.  -Diziet.
-        let addr = (hostname, 1).into_tor_addr().map_err(wrap_err)?;
+        let addr = self
+            .address_map
+            .apply((hostname, 1).into_tor_addr().map_err(wrap_err)?);
 
         match addr.into_resolve_instructions(&self.addrcfg.get(), prefs)? {
             ResolveInstructions::Exit(hostname) => {
+                let query = DnsQuery::forward(&hostname);
+                let isolation = self.isolation(prefs, None);
+                if let Some(answer) = self.dns_cache.get(&query, &isolation, self.runtime.now()) {
+                    return Ok(answer);
+                }
+
                 let circ = self.get_or_launch_exit_circ(&[], prefs).await?;
 
-                let resolve_future = circ.resolve(&hostname);
-                let addrs = self
+                let resolve_future = circ.resolve_answers(&hostname);
+                let answer = self
                     .runtime
                     .timeout(self.timeoutcfg.get().resolve_timeout, resolve_future)
                     .await
                     .map_err(|_| ErrorDetail::ExitTimeout)?
+                    .and_then(DnsAnswer::from_resolved)
                     .map_err(|cause| ErrorDetail::StreamFailed {
                         cause,
                         kind: "DNS lookup",
                     })?;
 
-                Ok(addrs)
+                self.dns_cache
+                    .insert(query, isolation, &answer, self.runtime.now());
+                Ok(answer)
             }
-            ResolveInstructions::Return(addrs) => Ok(addrs),
+            ResolveInstructions::Return(addrs) => Ok(DnsAnswer::from_literal(addrs)),
         }
     }
 
-    /// Perform a remote DNS reverse lookup with the provided IP address.
+    /// Perform a remote DNS reverse lookup with the provided IP address,
+    /// and return every record in the reply, with how long each may be
+    /// cached.
     ///
-    /// On success, return a list of hostnames.
-    pub async fn resolve_ptr(&self, addr: IpAddr) -> crate::Result<Vec<String>> {
-        self.resolve_ptr_with_prefs(addr, &self.connect_prefs).await
+    /// Unlike with [`resolve_ptr`](TorClient::resolve_ptr), errors reported
+    /// by the exit relay are records in the returned [`DnsAnswer`], not an
+    /// `Err`.
+    pub async fn resolve_ptr_answer(&self, addr: IpAddr) -> crate::Result<DnsAnswer> {
+        self.resolve_ptr_answer_with_prefs(addr, &self.connect_prefs)
+            .await
     }
 
-    /// Perform a remote DNS reverse lookup with the provided IP address.
-    ///
-    /// On success, return a list of hostnames.
-    pub async fn resolve_ptr_with_prefs(
+    /// Perform a remote DNS reverse lookup with the provided IP address,
+    /// and return every record in the reply, but use prefs.
+    pub async fn resolve_ptr_answer_with_prefs(
         &self,
         addr: IpAddr,
         prefs: &StreamPrefs,
-    ) -> crate::Result<Vec<String>> {
+    ) -> crate::Result<DnsAnswer> {
+        let query = DnsQuery::Reverse(addr);
+        let isolation = self.isolation(prefs, None);
+        if let Some(answer) = self.dns_cache.get(&query, &isolation, self.runtime.now()) {
+            return Ok(answer);
+        }
+
         let circ = self.get_or_launch_exit_circ(&[], prefs).await?;
 
-        let resolve_ptr_future = circ.resolve_ptr(addr);
-        let hostnames = self
+        let resolve_ptr_future = circ.resolve_ptr_answers(addr);
+        let answer = self
             .runtime
             .timeout(
                 self.timeoutcfg.get().resolve_ptr_timeout,
@@ -1573,12 +2590,47 @@ impl<R: Runtime> TorClient<R> {
             )
             .await
             .map_err(|_| ErrorDetail::ExitTimeout)?
+            .and_then(DnsAnswer::from_resolved)
             .map_err(|cause| ErrorDetail::StreamFailed {
                 cause,
                 kind: "reverse DNS lookup",
             })?;
 
-        Ok(hostnames)
+        self.dns_cache
+            .insert(query, isolation, &answer, self.runtime.now());
+        Ok(answer)
+    }
+
+    /// Build a new circuit for sending and receiving custom relay messages,
+    /// to the relay with identity `target` if one is given.
+    ///
+    /// Without a `target`, the circuit ends at some exit relay.  With one,
+    /// it goes through three other relays, and then to `target`.  Nothing
+    /// else uses the circuit.  See [`RawCircuit`](crate::RawCircuit) for
+    /// how to use it.
+    ///
+    /// This function is unstable. It is only enabled if the crate was
+    /// built with the `experimental-api` feature.
+    #[cfg(feature = "experimental-api")]
+    pub async fn launch_raw_circuit(
+        &self,
+        target: Option<&RelayId>,
+    ) -> crate::Result<crate::RawCircuit> {
+        self.wait_for_bootstrap().await?;
+        let netdir = self.netdir(Timeliness::Timely, "build a raw circuit")?;
+        let target = target
+            .map(|id| {
+                netdir
+                    .by_id(id)
+                    .ok_or_else(|| ErrorDetail::RelayNotFound(Sensitive::new(id.clone())))
+            })
+            .transpose()?;
+        let circ = self
+            .circmgr
+            .launch_unmanaged(&netdir, target)
+            .await
+            .map_err(ErrorDetail::ObtainRawCircuit)?;
+        Ok(crate::RawCircuit::new(circ).await?)
     }
 
     /// Return a reference to this client's directory manager.
@@ -1622,6 +2674,23 @@ impl<R: Runtime> TorClient<R> {
         &self.hs_circ_pool
     }
 
+    /// Return a state manager for the namespace called `name`, in which an
+    /// application embedding Arti can keep state of its own.
+    ///
+    /// The namespace is kept inside this client's state directory, but is
+    /// separate from Arti's own state, and has its own lock.
+    /// See [`FsStateMgr::namespace`] for details.
+    ///
+    /// Namespaces are not available if this client was built with a
+    /// [custom state manager](crate::TorClientBuilder::state_manager).
+    ///
+    /// This function is unstable. It is only enabled if the crate was
+    /// built with the `experimental-api` feature.
+    #[cfg(feature = "experimental-api")]
+    pub fn state_namespace(&self, name: &tor_persist::slug::SlugRef) -> crate::Result<FsStateMgr> {
+        Ok(self.statemgr.namespace(name)?)
+    }
+
     /// Return a reference to the runtime being used by this client.
     //
     // This API is not a hostage to fortune since we already require that R: Clone,
@@ -1659,6 +2728,7 @@ impl<R: Runtime> TorClient<R> {
         exit_ports: &[TargetPort],
         prefs: &StreamPrefs,
     ) -> StdResult<Arc<ClientCirc>, ErrorDetail> {
+        self.check_not_shutting_down("build a circuit")?;
         // TODO HS probably this netdir ought to be made in connect_with_prefs
         // like for StreamInstructions::Hs.
         self.wait_for_bootstrap().await?;
@@ -1666,12 +2736,13 @@ impl<R: Runtime> TorClient<R> {
 
         let circ = self
             .circmgr
-            .get_or_launch_exit(
+            .get_or_launch_exit_with_rules(
                 dir.as_ref().into(),
                 exit_ports,
-                self.isolation(prefs),
+                self.isolation(prefs, exit_ports.first().map(|p| p.port)),
                 #[cfg(feature = "geoip")]
-                prefs.country_code,
+                prefs.exit_country_with_config(&self.addrcfg.get()),
+                &prefs.relay_rules,
             )
             .await
             .map_err(|cause| ErrorDetail::ObtainExitCircuit {
@@ -1691,12 +2762,34 @@ impl<R: Runtime> TorClient<R> {
     /// This combines isolation information from
     /// [`StreamPrefs::prefs_isolation`]
     /// and the `TorClient`'s isolation (eg from [`TorClient::isolated_client`]).
-    fn isolation(&self, prefs: &StreamPrefs) -> StreamIsolation {
+    ///
+    /// `port` is the stream's destination port, or `None` for a DNS lookup.
+    fn isolation(&self, prefs: &StreamPrefs, port: Option<u16>) -> StreamIsolation {
+        let port_class = port
+            .filter(|_| prefs.isolate_by_port_class)
+            .map(PortClass::of_port);
+        self.isolation_for_class(prefs, port_class)
+    }
+
+    /// As [`isolation`](TorClient::isolation), but for a stream to a port
+    /// in `port_class`, or for a DNS lookup if `port_class` is `None`.
+    fn isolation_for_class(
+        &self,
+        prefs: &StreamPrefs,
+        port_class: Option<PortClass>,
+    ) -> StreamIsolation {
         let mut b = StreamIsolationBuilder::new();
         // Always consider our client_isolation.
         b.owner_token(self.client_isolation);
         // Consider stream isolation too, if it's set.
-        if let Some(tok) = prefs.prefs_isolation() {
+        let tok = prefs.prefs_isolation();
+        if prefs.isolate_by_port_class || prefs.first_party_domain.is_some() {
+            b.stream_isolation(Box::new(DestinationIsolation::new(
+                tok.unwrap_or_else(|| Box::new(IsolationToken::no_isolation())),
+                port_class,
+                prefs.first_party_domain.clone(),
+            )));
+        } else if let Some(tok) = tok {
             b.stream_isolation(tok);
         }
         // Failure should be impossible with this builder.
@@ -1724,6 +2817,7 @@ impl<R: Runtime> TorClient<R> {
         Arc<tor_hsservice::RunningOnionService>,
         impl futures::Stream<Item = tor_hsservice::RendRequest>,
     )> {
+        let state_dir = self.onion_service_state_dir("launch onion service")?;
         let keymgr = self
             .inert_client
             .keymgr
@@ -1732,12 +2826,12 @@ impl<R: Runtime> TorClient<R> {
                 action: "launch onion service",
             })?
             .clone();
-        let state_dir = self.state_directory.clone();
 
+        // For "ephemeral" operation, the keymgr can use an ephemeral keystore
+        // (see `storage.keystore`); but the service's state needs to be on disk.
         let service = tor_hsservice::OnionService::builder()
-            .config(config) // TODO #1186: Allow override of KeyMgr for "ephemeral" operation?
+            .config(config)
             .keymgr(keymgr)
-            // TODO #1186: Allow override of StateMgr for "ephemeral" operation?
             .state_dir(state_dir)
             .build()
             .map_err(ErrorDetail::LaunchOnionService)?;
@@ -1753,6 +2847,23 @@ impl<R: Runtime> TorClient<R> {
         Ok((service, stream))
     }
 
+    /// Return the state directory in which an onion service should keep its
+    /// state.
+    ///
+    /// Onion services can only keep their state on disk, so we refuse to
+    /// launch them if this client keeps its own state elsewhere (for example,
+    /// with [`TorClientBuilder::ephemeral_state`](crate::TorClientBuilder::ephemeral_state)).
+    #[cfg(feature = "onion-service-service")]
+    fn onion_service_state_dir(
+        &self,
+        action: &'static str,
+    ) -> StdResult<StateDirectory, ErrorDetail> {
+        match self.statemgr {
+            ClientStateMgr::Fs(_) => Ok(self.state_directory.clone()),
+            ClientStateMgr::Custom(_) => Err(ErrorDetail::StateDirectoryRequired { action }),
+        }
+    }
+
     /// Try to launch an onion service with a given configuration and provided
     /// [`HsIdKeypair`]. If an onion service with the given nickname already has an
     /// associated `HsIdKeypair`  in this `TorClient`'s `KeyMgr`, then this operation
@@ -1786,6 +2897,9 @@ impl<R: Runtime> TorClient<R> {
         Arc<tor_hsservice::RunningOnionService>,
         impl futures::Stream<Item = tor_hsservice::RendRequest>,
     )> {
+        // Check this before we store the key.
+        let _ = self.onion_service_state_dir("launch onion service ex")?;
+
         let nickname = config.nickname();
         let hsid_spec = HsIdKeypairSpecifier::new(nickname.clone());
         let selector = KeystoreSelector::Primary;
@@ -2027,14 +3141,269 @@ impl<R: Runtime> TorClient<R> {
         self.status_receiver.clone()
     }
 
+    /// Return a stream of every [`TorEvent`](crate::TorEvent) that this client
+    /// reports: changes to its bootstrap status and primary guards, circuits
+    /// that it builds or fails to build, and new directory information.
+    ///
+    /// This is meant for reporting progress to a user.  The stream is lossy:
+    /// see [`TorEvents`] for details.
+    pub fn events(&self) -> TorEvents {
+        TorEvents::merge(
+            self.bootstrap_events(),
+            self.circmgr.circ_events(),
+            self.guardmgr.primary_guard_events(),
+            self.dirmgr.events(),
+        )
+    }
+
+    /// Return a snapshot of the traffic on every data stream that this client
+    /// has opened, and the circuits that carry the ones that are still open.
+    ///
+    /// The totals are shared with every clone of this client, and include
+    /// streams that have since closed.  To learn about a single stream, use
+    /// [`DataStream::stats`].
+    pub fn traffic_stats(&self) -> AggregateTrafficStats {
+        self.traffic.snapshot()
+    }
+
+    /// Return an estimate of how much memory, in bytes, this client's queues
+    /// and caches are using.
+    ///
+    /// This is the usage that counts against the `system.memory.max` limit:
+    /// stream and channel queues, the directory cache in RAM, and the onion
+    /// service descriptor caches.  It is shared with every clone of this
+    /// client.
+    ///
+    /// Returns `None` if memory tracking was disabled when the client was
+    /// created.
+    pub fn memory_used(&self) -> crate::Result<Option<usize>> {
+        let used = self
+            .memquota
+            .used_current_approx()
+            .map_err(|e| ErrorDetail::MemquotaQuery(e.into()))?;
+        // A tracker that was created disabled doesn't count anything.
+        Ok((used != usize::MAX).then_some(used))
+    }
+
+    /// Return a stream of events that tell us when this client is low on
+    /// memory.
+    ///
+    /// An event is yielded each time the memory that this client uses goes
+    /// over `system.memory.max`, and it starts to discard queued data and
+    /// cached documents to get back under `system.memory.low_water`.
+    /// Embedders can use this as a hook to release memory of their own.
+    ///
+    /// Events are dropped if the stream isn't read promptly.  If memory
+    /// tracking is disabled, the stream ends immediately.
+    pub fn low_memory_events(
+        &self,
+    ) -> crate::Result<impl futures::Stream<Item = ReclaimEvent> + Send + Unpin + 'static> {
+        Ok(self
+            .memquota
+            .reclaim_events()
+            .map_err(|e| ErrorDetail::MemquotaQuery(e.into()))?)
+    }
+
+    /// Return a snapshot of the network directory that this client is using:
+    /// the relays that it knows about, and how long its consensus is valid.
+    ///
+    /// Fails if the client doesn't have a usable directory yet.
+    pub fn network_snapshot(&self) -> crate::Result<NetworkSnapshot> {
+        let netdir = self.netdir(Timeliness::Timely, "list relays")?;
+        Ok(NetworkSnapshot::new(netdir))
+    }
+
+    /// Measure how well this client can use the Tor network.
+    ///
+    /// Builds the test circuits that `config` asks for, one at a time, and
+    /// times how long each one takes.  Then, if `config` names an endpoint,
+    /// connects to it on a new circuit, and measures how quickly it can
+    /// download from it.
+    ///
+    /// The test circuits are not used for anything else, and are closed when
+    /// the test is done; they do help the client to learn its circuit build
+    /// timeout, though.  Failing to build a circuit, or to download from the
+    /// endpoint, is recorded in the [`SelfTestReport`]; this method only
+    /// fails if the client can't bootstrap, or has no usable directory.
+    pub async fn run_selftest(&self, config: &SelfTestConfig) -> crate::Result<SelfTestReport> {
+        self.wait_for_bootstrap().await?;
+
+        let mut circuits = Vec::with_capacity(config.n_circuits());
+        for _ in 0..config.n_circuits() {
+            let netdir = self.netdir(Timeliness::Timely, "run a self-test")?;
+            let start = self.runtime.now();
+            let result = self
+                .circmgr
+                .launch_unmanaged(&netdir, None::<tor_netdir::Relay<'_>>)
+                .await
+                .map(|_circ| self.runtime.now().saturating_duration_since(start))
+                .map_err(|e| ErrorDetail::SelfTestCircuit(e).into());
+            circuits.push(CircuitTest::new(result));
+        }
+
+        let download = match config.endpoint_and_request() {
+            Some((target, request)) => Some(self.selftest_download(target, request, config).await),
+            None => None,
+        };
+
+        let build_timeout = self
+            .circmgr
+            .estimate_timeout(&tor_circmgr::timeouts::Action::BuildCircuit { length: 3 });
+
+        Ok(SelfTestReport::new(circuits, build_timeout, download))
+    }
+
+    /// Helper: download from the self-test endpoint `target`, on a circuit
+    /// that no other stream uses.
+    async fn selftest_download(
+        &self,
+        target: &TorAddr,
+        request: &[u8],
+        config: &SelfTestConfig,
+    ) -> DownloadTest {
+        let mut prefs = self.connect_prefs.clone();
+        prefs.new_isolation_group();
+        let start = self.runtime.now();
+        match self.connect_with_prefs(target.clone(), &prefs).await {
+            Ok(stream) => {
+                let connect_time = self.runtime.now().saturating_duration_since(start);
+                selftest::download(&self.runtime, stream, request, config, connect_time).await
+            }
+            Err(e) => DownloadTest::connect_failed(e),
+        }
+    }
+
+    /// Report every stream that this client opens from now on to `auditor`,
+    /// or stop reporting streams if `auditor` is `None`.
+    ///
+    /// The auditor is shared with every clone of this client (including
+    /// [isolated clients](TorClient::isolated_client)), and replaces any
+    /// auditor that was set before.  Streams that are already open are
+    /// reported to the auditor that was set when they were opened.
+    ///
+    /// Each stream is reported once it closes, whether or not it was ever
+    /// successfully opened: see [`StreamAuditRecord`](crate::StreamAuditRecord).
+    /// DNS lookups, and the directory streams that the client opens for
+    /// itself, are not reported.
+    pub fn set_stream_auditor(&self, auditor: Option<Arc<dyn StreamAuditor>>) {
+        *self
+            .stream_auditor
+            .lock()
+            .expect("stream auditor lock poisoned") = auditor;
+    }
+
+    /// Obtain new bridges from `source` whenever we fail to bootstrap with our
+    /// configured bridges, or stop doing so if `source` is `None`.
+    ///
+    /// When [`bootstrap`](TorClient::bootstrap) fails while we are using
+    /// bridges, we ask `source` for new bridges that use our configured
+    /// pluggable transports, replace our bridges with them (as with
+    /// [`set_bridges`](TorClient::set_bridges)), and try to bootstrap once
+    /// more.  Typically `source` is a [`tor_moat::MoatClient`].
+    ///
+    /// The source is shared with every clone of this client, and replaces any
+    /// source that was set before.
+    #[cfg(feature = "moat")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "moat")))]
+    pub fn set_bridge_source(&self, source: Option<Arc<dyn tor_moat::BridgeSource>>) {
+        *self
+            .bridge_source
+            .lock()
+            .expect("bridge source lock poisoned") = source;
+    }
+
+    /// Use `policy` to veto or re-weight the relays on the circuits that this
+    /// client builds from now on, or go back to the usual relay choices if
+    /// `policy` is `None`.
+    ///
+    /// The policy applies to every clone of this client that shares its
+    /// circuits.  Circuits that are already built are still used; see
+    /// [`PathPolicy`](crate::PathPolicy) for which circuits the policy
+    /// applies to, and for what it costs in anonymity.
+    pub fn set_path_policy(&self, policy: Option<Arc<dyn tor_circmgr::PathPolicy>>) {
+        self.circmgr.set_path_policy(policy);
+    }
+
+    /// Tell the client that the application expects to connect to `port`
+    /// soon, so that it can build circuits for that port ahead of time.
+    ///
+    /// The client predicts which ports it will need circuits for from the
+    /// connections that it has made, and from the `preemptive_circuits`
+    /// configuration options.  This hint works as if the application had just
+    /// connected to `port`: circuits that can exit to it are built in the
+    /// background, and kept ready for as long as the client would predict it.
+    pub fn note_upcoming_use(&self, port: u16) {
+        self.circmgr.note_upcoming_use(TargetPort::ipv4(port));
+    }
+
+    /// Stop giving out the circuits that streams opened by this client with
+    /// `prefs` have used, so that later streams with `prefs` get new ones.
+    ///
+    /// This is like a "new identity" request, for a single isolation group:
+    /// streams with other isolation, and other
+    /// [isolated clients](TorClient::isolated_client), keep their circuits.
+    /// Streams that are already open are not closed.  (With
+    /// [`StreamPrefs::isolate_every_stream`], every stream is in a group of
+    /// its own, so this does nothing.)
+    ///
+    /// Circuits are also rotated automatically, according to the
+    /// `circuit_timing.max_dirtiness` and `circuit_timing.max_streams`
+    /// configuration options.
+    pub fn rotate_circuits(&self, prefs: &StreamPrefs) {
+        let mut port_classes = vec![None];
+        if prefs.isolate_by_port_class {
+            port_classes.extend([PortClass::Web, PortClass::Mail, PortClass::Other].map(Some));
+        }
+        for port_class in port_classes {
+            let isolation = self.isolation_for_class(prefs, port_class);
+            self.circmgr.retire_isolated_circuits(&isolation);
+        }
+    }
+
+    /// Forget every cached DNS answer.
+    ///
+    /// The cache is shared with every clone of this client.  This is the
+    /// DNS part of what C Tor does on `SIGNAL NEWNYM`: an application that
+    /// wants a new identity should call this, and use a new
+    /// [`isolated_client`](TorClient::isolated_client) for its later
+    /// connections.
+    pub fn clear_dns_cache(&self) {
+        self.dns_cache.clear();
+    }
+
+    /// Replace the host `from` with `to`, in the addresses that this client
+    /// (and every clone of it) connects to or looks up from now on.
+    ///
+    /// Each of `from` and `to` may be a hostname, a `.onion` address, or an
+    /// IP address.  This mapping takes precedence over any mapping of `from`
+    /// in the `address_map` section of the configuration.
+    pub fn map_address(&self, from: &str, to: &str) -> crate::Result<()> {
+        self.address_map.insert(from, to).map_err(wrap_err)
+    }
+
+    /// Forget a mapping of the host `from` that was added with
+    /// [`map_address`](TorClient::map_address).
+    ///
+    /// Returns true if there was one.  Mappings in the configuration are
+    /// not affected: change those with [`reconfigure`](TorClient::reconfigure).
+    pub fn unmap_address(&self, from: &str) -> bool {
+        self.address_map.remove(from)
+    }
+
     /// Change the client's current dormant mode, putting background tasks to sleep
     /// or waking them up as appropriate.
     ///
     /// This can be used to conserve CPU usage if you aren't planning on using the
     /// client for a while, especially on mobile platforms.
     ///
-    /// See the [`DormantMode`] documentation for more details.
+    /// See the [`DormantMode`] documentation for more details.  To have the
+    /// client become dormant by itself when it is idle, use the `dormancy`
+    /// section of its configuration.
     pub fn set_dormant(&self, mode: DormantMode) {
+        if mode == DormantMode::Normal {
+            // Don't go straight back to sleep.
+            self.activity.note_used(self.runtime.now());
+        }
         *self
             .dormant
             .lock()
@@ -2042,6 +3411,62 @@ impl<R: Runtime> TorClient<R> {
             .borrow_mut() = Some(mode);
     }
 
+    /// Shut this client down gracefully, giving its open streams up to
+    /// `grace` to close.
+    ///
+    /// From the moment this is called, this client and every clone of it
+    /// (including [isolated clients](TorClient::isolated_client)) refuse to
+    /// build circuits for new streams, or to open them, with an error of kind
+    /// [`ArtiShuttingDown`](crate::ErrorKind::ArtiShuttingDown).  Streams that are
+    /// already open keep working until they close, or until `grace` has
+    /// passed: then we close the circuits that carry them, and the
+    /// application sees them fail.  Finally, we put the client's background
+    /// tasks to sleep, and save its persistent state.
+    ///
+    /// Dropping a client without calling this loses whatever data its
+    /// streams had in flight.  After this returns, the client can't be used
+    /// for anything but inspection: drop it, and create a new one if
+    /// needed.  Calling this more than once is harmless.
+    pub async fn shutdown(&self, grace: Duration) -> crate::Result<()> {
+        if !self.shutdown.start() {
+            info!(
+                "Shutting down: waiting up to {} for open streams to close.",
+                humantime::format_duration(grace)
+            );
+        }
+        // Circuits without streams can close now.
+        self.circmgr.retire_all_circuits();
+
+        // (If `grace` is too large to add, we wait forever.)
+        let deadline = self.runtime.now().checked_add(grace);
+        loop {
+            let open = self.traffic.snapshot();
+            if open.circuits().is_empty() {
+                break;
+            }
+            let remaining = deadline.map_or(shutdown::POLL_INTERVAL, |deadline| {
+                deadline.saturating_duration_since(self.runtime.now())
+            });
+            if remaining.is_zero() {
+                let n_closed = self.shutdown.close_circs(open.circuits());
+                info!(
+                    "Closing {} circuit(s) whose streams didn't close in time.",
+                    n_closed
+                );
+                break;
+            }
+            self.runtime
+                .sleep(std::cmp::min(shutdown::POLL_INTERVAL, remaining))
+                .await;
+        }
+
+        self.set_dormant(DormantMode::Soft);
+        self.circmgr
+            .store_persistent_state()
+            .map_err(ErrorDetail::ShutdownStateFlush)?;
+        Ok(())
+    }
+
     /// Return a [`Future`](futures::Future) which resolves
     /// once this TorClient has stopped.
     #[cfg(feature = "experimental-api")]
@@ -2094,6 +3519,49 @@ async fn tasks_monitor_dormant<R: Runtime>(
     }
 }
 
+/// Open a stream with `begin` on each of `first` and `second` at once, and
+/// return whichever stream opens first.
+///
+/// If one attempt fails, return the result of the other.
+/// Dropping the slower attempt cancels it.
+async fn race_streams<C, S, F, Fut>(first: C, second: C, begin: F) -> crate::Result<S>
+where
+    F: Fn(C) -> Fut,
+    Fut: Future<Output = crate::Result<S>>,
+{
+    let on_first = std::pin::pin!(begin(first));
+    let on_second = std::pin::pin!(begin(second));
+    match future::select(on_first, on_second).await {
+        Either::Left((Ok(stream), _)) | Either::Right((Ok(stream), _)) => Ok(stream),
+        Either::Left((Err(e), other)) | Either::Right((Err(e), other)) => {
+            debug_report!(e, "One of two circuits failed to open a stream");
+            other.await
+        }
+    }
+}
+
+/// Tell `chanmgr` which relays are our bridges, according to `config`
+///
+/// The channel manager uses this to choose the padding level for each channel.
+#[cfg(feature = "bridge-client")]
+fn set_chanmgr_bridges<R: Runtime>(
+    chanmgr: &tor_chanmgr::ChanMgr<R>,
+    config: &TorClientConfig,
+) -> StdResult<(), Bug> {
+    use tor_guardmgr::GuardMgrConfig as _;
+
+    let bridges: &[BridgeConfig] = config.as_ref();
+    let ids = if config.bridges_enabled() {
+        bridges
+            .iter()
+            .flat_map(|bridge| bridge.identities().map(|id| id.to_owned()))
+            .collect()
+    } else {
+        tor_linkspec::RelayIdSet::new()
+    };
+    chanmgr.set_bridges(ids)
+}
+
 /// Alias for TorError::from(Error)
 pub(crate) fn wrap_err<T>(err: T) -> crate::Error
 where
@@ -2159,6 +3627,131 @@ mod test {
         });
     }
 
+    #[test]
+    fn preconnect_isolation() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+
+            // `preconnect` and `connect` both ask the circuit manager for an
+            // exit circuit with the isolation from `TorClient::isolation`, and
+            // the circuit manager gives a later request the circuit that it
+            // built for an earlier one when their isolations are compatible
+            // (see `reuse_prepared_circuit` in tor-circmgr).  So a later
+            // `connect` with the same preferences reuses the prepared circuit.
+            let prefs = StreamPrefs::new();
+            let prepared = client.isolation(&prefs, Some(443));
+            let later = client.isolation(&prefs, Some(443));
+            assert!(prepared.compatible(&later));
+
+            let mut by_port = StreamPrefs::new();
+            by_port.isolate_by_port_class();
+            let prepared = client.isolation(&by_port, Some(443));
+            assert!(prepared.compatible(&client.isolation(&by_port, Some(443))));
+
+            // A client isolated from this one can't use its circuits.
+            let other = client.isolated_client();
+            assert!(!prepared.compatible(&other.isolation(&by_port, Some(443))));
+
+            // When every stream is isolated, no later stream could use the
+            // circuit, so `preconnect` doesn't try to build one.
+            let mut every = StreamPrefs::new();
+            every.isolate_every_stream();
+            assert!(!client
+                .isolation(&every, Some(443))
+                .compatible(&client.isolation(&every, Some(443))));
+            client
+                .preconnect_with_prefs("example.com:443", &every)
+                .await
+                .unwrap();
+
+            // Otherwise it does, which needs a bootstrapped client.
+            let result = client.preconnect("example.com:443").await;
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::BootstrapRequired);
+        });
+    }
+
+    #[test]
+    fn race_streams() {
+        use futures::channel::oneshot;
+
+        /// A mock circuit, on which the test decides whether a stream opens.
+        struct MockCirc {
+            /// Which circuit this is.
+            id: u8,
+            /// Tells us whether the stream on this circuit opens.
+            opens: oneshot::Receiver<bool>,
+        }
+
+        /// Make a mock circuit with `id`, and a sender to decide its outcome.
+        fn mock_circ(id: u8) -> (MockCirc, oneshot::Sender<bool>) {
+            let (tx, opens) = oneshot::channel();
+            (MockCirc { id, opens }, tx)
+        }
+
+        /// Try to open a stream on `circ`: return its id if that works.
+        async fn begin(circ: MockCirc) -> crate::Result<u8> {
+            match circ.opens.await {
+                Ok(true) => Ok(circ.id),
+                _ => Err(ErrorDetail::ExitTimeout.into()),
+            }
+        }
+
+        tor_rtcompat::test_with_one_runtime!(|_rt| async {
+            // The first circuit wins; the second never answers.
+            let (c1, tx1) = mock_circ(1);
+            let (c2, _tx2) = mock_circ(2);
+            tx1.send(true).unwrap();
+            assert_eq!(super::race_streams(c1, c2, begin).await.unwrap(), 1);
+
+            // The second circuit wins; the first never answers.
+            let (c1, _tx1) = mock_circ(1);
+            let (c2, tx2) = mock_circ(2);
+            tx2.send(true).unwrap();
+            assert_eq!(super::race_streams(c1, c2, begin).await.unwrap(), 2);
+
+            // The first circuit fails, so we get the second's result.
+            let (c1, tx1) = mock_circ(1);
+            let (c2, tx2) = mock_circ(2);
+            tx1.send(false).unwrap();
+            let race = super::race_streams(c1, c2, begin);
+            let later = async {
+                // Let the race see the failure before the other stream opens.
+                tor_rtcompat::task::yield_now().await;
+                tx2.send(true).unwrap();
+            };
+            let (result, ()) = futures::join!(race, later);
+            assert_eq!(result.unwrap(), 2);
+
+            // The second circuit fails, so we get the first's result.
+            let (c1, tx1) = mock_circ(1);
+            let (c2, tx2) = mock_circ(2);
+            tx2.send(false).unwrap();
+            let race = super::race_streams(c1, c2, begin);
+            let later = async {
+                tor_rtcompat::task::yield_now().await;
+                tx1.send(true).unwrap();
+            };
+            let (result, ()) = futures::join!(race, later);
+            assert_eq!(result.unwrap(), 1);
+
+            // Both fail, so we get an error.
+            let (c1, tx1) = mock_circ(1);
+            let (c2, tx2) = mock_circ(2);
+            tx1.send(false).unwrap();
+            tx2.send(false).unwrap();
+            assert!(super::race_streams(c1, c2, begin).await.is_err());
+        });
+    }
+
     #[test]
     fn streamprefs_isolate_every_stream() {
         let mut observed = StreamPrefs::new();
@@ -2226,6 +3819,26 @@ mod test {
         assert!(observed.optimistic_stream);
     }
 
+    #[test]
+    fn streamprefs_keepalive() {
+        let mut observed = StreamPrefs::new();
+        assert_eq!(observed.keepalive, None);
+        observed.keepalive(Duration::from_secs(300));
+        assert_eq!(observed.keepalive, Some(Duration::from_secs(300)));
+        observed.no_keepalive();
+        assert_eq!(observed.keepalive, None);
+    }
+
+    #[test]
+    fn streamprefs_circuit_padding() {
+        let mut observed = StreamPrefs::new();
+        assert!(!observed.hide_from_padding);
+        observed.circuit_padding(false);
+        assert!(observed.hide_from_padding);
+        observed.circuit_padding(true);
+        assert!(!observed.hide_from_padding);
+    }
+
     #[test]
     fn streamprefs_set_isolation() {
         let mut observed = StreamPrefs::new();
@@ -2236,6 +3849,70 @@ mod test {
         };
     }
 
+    #[test]
+    #[cfg(all(feature = "onion-service-client", feature = "experimental-api"))]
+    fn streamprefs_service_discovery_key() {
+        use tor_llcrypto::pk::curve25519;
+        let sk = |b| curve25519::StaticSecret::from([b; 32]).into();
+        let alice = HsId::from([1; 32]);
+        let bob = HsId::from([2; 32]);
+
+        let mut prefs = StreamPrefs::new();
+        assert!(prefs.service_discovery_keys_for(alice).is_none());
+        prefs.service_discovery_key(alice, sk(7));
+        let first = prefs.service_discovery_keys_for(alice).unwrap();
+        assert!(!first.is_empty());
+        assert!(prefs.service_discovery_keys_for(bob).is_none());
+        // Clones of the preferences share circuits.
+        assert_eq!(
+            prefs.clone().service_discovery_keys_for(alice),
+            Some(first.clone())
+        );
+
+        // A new key for the same service replaces the old one.
+        prefs.service_discovery_key(alice, sk(8));
+        assert_eq!(prefs.service_discovery_keys.len(), 1);
+        assert_ne!(prefs.service_discovery_keys_for(alice), Some(first));
+    }
+
+    #[test]
+    fn destination_isolation() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let tor_client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+
+            let mut prefs = StreamPrefs::new();
+            prefs.isolate_by_port_class();
+            let https = tor_client.isolation(&prefs, Some(443));
+            let http = tor_client.isolation(&prefs, Some(80));
+            let imap = tor_client.isolation(&prefs, Some(993));
+            let lookup = tor_client.isolation(&prefs, None);
+            assert!(https.compatible(&http));
+            assert!(!https.compatible(&imap));
+            assert!(!https.compatible(&lookup));
+
+            let mut prefs_a = prefs.clone();
+            prefs_a.first_party_domain("example.com");
+            let mut prefs_b = prefs.clone();
+            prefs_b.first_party_domain("example.org");
+            let a = tor_client.isolation(&prefs_a, Some(443));
+            let b = tor_client.isolation(&prefs_b, Some(443));
+            assert!(!a.compatible(&b));
+            assert!(!a.compatible(&https));
+            prefs_b.first_party_domain("EXAMPLE.com.");
+            let b = tor_client.isolation(&prefs_b, Some(443));
+            assert!(a.compatible(&b));
+        });
+    }
+
     #[test]
     fn reconfigure_all_or_nothing() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -2254,4 +3931,325 @@ mod test {
                 .unwrap();
         });
     }
+
+    #[test]
+    fn reconfigure_report() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(&state_dir, &cache_dir)
+                .build()
+                .unwrap();
+            let tor_client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+
+            let mut builder = TorClientConfigBuilder::from_directories(&state_dir, &cache_dir);
+            builder
+                .stream_timeouts()
+                .connect_timeout(std::time::Duration::from_secs(5));
+            let report = tor_client
+                .reconfigure(&builder.build().unwrap(), Reconfigure::AllOrNothing)
+                .unwrap();
+            assert!(report.is_complete());
+
+            let other_dir = tempfile::tempdir().unwrap();
+            let moved = TorClientConfigBuilder::from_directories(&other_dir, &cache_dir)
+                .build()
+                .unwrap();
+            assert!(tor_client
+                .reconfigure(&moved, Reconfigure::AllOrNothing)
+                .is_err());
+            let report = tor_client
+                .reconfigure(&moved, Reconfigure::WarnOnFailures)
+                .unwrap();
+            assert_eq!(report.ignored(), ["storage.state_dir"]);
+            assert!(!report.is_complete());
+
+            // Every option that the report lists is one that the subsystems
+            // refuse to change.
+            let mut ports = TorClientConfigBuilder::from_directories(&state_dir, &cache_dir);
+            ports
+                .preemptive_circuits()
+                .initial_predicted_ports()
+                .push(8443);
+            let other_cache = TorClientConfigBuilder::from_directories(&state_dir, &other_dir);
+            for (builder, field) in [
+                (ports, "preemptive_circuits.initial_predicted_ports"),
+                (other_cache, "storage.cache_dir"),
+            ] {
+                let cfg = builder.build().unwrap();
+                assert!(tor_client
+                    .reconfigure(&cfg, Reconfigure::AllOrNothing)
+                    .is_err());
+                let report = tor_client
+                    .reconfigure(&cfg, Reconfigure::WarnOnFailures)
+                    .unwrap();
+                assert_eq!(report.ignored(), [field]);
+            }
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "experimental-api")]
+    fn clone_isolated() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(&state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let tor_client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+            let sub = tor_client
+                .clone_isolated(tor_persist::slug!("alice"), true)
+                .unwrap();
+            assert!(!Arc::ptr_eq(&sub.circmgr, &tor_client.circmgr));
+            assert!(!sub
+                .isolation(&StreamPrefs::new(), None)
+                .compatible(&tor_client.isolation(&StreamPrefs::new(), None)));
+            assert!(state_dir.path().join("namespaces/alice").is_dir());
+
+            // The sub-client is no more usable before bootstrapping than we are.
+            let result = sub.connect("example.com:80").await;
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::BootstrapRequired);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "experimental-api")]
+    fn custom_state_manager() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(&state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let memory = tor_persist::MemoryStateMgr::new();
+            let tor_client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .state_manager(memory.clone())
+                .create_unbootstrapped()
+                .unwrap();
+
+            // The client took the lock on our manager, and keeps its state there.
+            assert!(memory.can_store());
+            assert!(tor_client.circmgr.store_persistent_state().unwrap());
+            assert!(memory
+                .load::<tor_persist::JsonValue>("guards")
+                .unwrap()
+                .is_some());
+            assert!(!state_dir.path().join("state").exists());
+
+            // Namespaces need a state directory.
+            let err = tor_client
+                .state_namespace(tor_persist::slug!("alice"))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BadApiUsage);
+            assert!(tor_client
+                .clone_isolated(tor_persist::slug!("alice"), false)
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn ephemeral_state() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(&state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let builder = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .ephemeral_state();
+
+            // Each client gets state of its own, and holds the lock on it.
+            let tor_client = builder.create_unbootstrapped().unwrap();
+            let tor_client2 = builder.create_unbootstrapped().unwrap();
+            assert!(tor_client.circmgr.store_persistent_state().unwrap());
+            assert!(tor_client2.circmgr.store_persistent_state().unwrap());
+            assert!(!state_dir.path().join("state").exists());
+
+            // Onion services keep their state on disk, so we can't launch them.
+            #[cfg(feature = "onion-service-service")]
+            {
+                let config = crate::config::onion_service::OnionServiceConfigBuilder::default()
+                    .nickname("ephemeral".parse().unwrap())
+                    .build()
+                    .unwrap();
+                let err = tor_client.launch_onion_service(config).err().unwrap();
+                assert_eq!(err.kind(), ErrorKind::BadApiUsage);
+            }
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "bridge-client")]
+    fn change_bridges() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let tor_client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+            let bridge1: BridgeConfig = "192.0.2.66:443 8C00000DFE0046ABCDFAD191144399CB520C29E8"
+                .parse()
+                .unwrap();
+            let bridge2: BridgeConfig = "192.0.2.67:443 8C00000DFE0046ABCDFAD191144399CB520C29E9"
+                .parse()
+                .unwrap();
+            assert!(tor_client.bridges().is_empty());
+
+            tor_client.add_bridge(bridge1.clone()).unwrap();
+            tor_client.add_bridge(bridge1.clone()).unwrap();
+            tor_client.add_bridge(bridge2.clone()).unwrap();
+            assert_eq!(tor_client.bridges(), [bridge1.clone(), bridge2.clone()]);
+
+            tor_client.remove_bridge(&bridge1).unwrap();
+            assert_eq!(tor_client.bridges(), [bridge2]);
+
+            tor_client.set_bridges([bridge1.clone()]).unwrap();
+            assert_eq!(tor_client.bridges(), [bridge1]);
+            tor_client.set_bridges([]).unwrap();
+            assert!(tor_client.bridges().is_empty());
+        });
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "moat",
+        feature = "experimental-api",
+        feature = "error_detail"
+    ))]
+    fn replace_bridges_on_bootstrap_failure() {
+        use crate::DirProviderBuilder;
+        use async_trait::async_trait;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::SystemTime;
+        use tor_dirmgr::{DirBootstrapStatus, DirMgrConfig, DirMgrStore, DirProvider};
+        use tor_guardmgr::bridge::BridgeConfigBuilder;
+        use tor_netdir::{DirEvent, NetDir, NetDirProvider, Timeliness};
+        use tor_netdoc::doc::netstatus::ProtoStatuses;
+
+        /// A directory provider that fails to bootstrap the first time only.
+        #[derive(Default)]
+        struct FlakyDirProvider {
+            /// How many times we have been asked to bootstrap.
+            attempts: AtomicUsize,
+        }
+        impl NetDirProvider for FlakyDirProvider {
+            fn netdir(&self, _: Timeliness) -> tor_netdir::Result<Arc<NetDir>> {
+                Err(tor_netdir::Error::NoInfo)
+            }
+            fn events(&self) -> futures::stream::BoxStream<'static, DirEvent> {
+                Box::pin(futures::stream::pending())
+            }
+            fn params(&self) -> Arc<dyn AsRef<tor_netdir::params::NetParameters>> {
+                Arc::new(tor_netdir::params::NetParameters::default())
+            }
+            fn protocol_statuses(&self) -> Option<(SystemTime, Arc<ProtoStatuses>)> {
+                None
+            }
+        }
+        #[async_trait]
+        impl DirProvider for FlakyDirProvider {
+            fn reconfigure(
+                &self,
+                _: &DirMgrConfig,
+                _: tor_config::Reconfigure,
+            ) -> StdResult<(), tor_config::ReconfigureError> {
+                Ok(())
+            }
+            async fn bootstrap(&self) -> tor_dirmgr::Result<()> {
+                match self.attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(tor_dirmgr::Error::CantAdvanceState),
+                    _ => Ok(()),
+                }
+            }
+            fn bootstrap_events(&self) -> futures::stream::BoxStream<'static, DirBootstrapStatus> {
+                Box::pin(futures::stream::pending())
+            }
+        }
+
+        /// Builds a [`FlakyDirProvider`], and remembers it.
+        #[derive(Default)]
+        struct FlakyBuilder(Mutex<Option<Arc<FlakyDirProvider>>>);
+        impl<R: Runtime> DirProviderBuilder<R> for FlakyBuilder {
+            fn build(
+                &self,
+                _: R,
+                _: DirMgrStore<R>,
+                _: Arc<tor_circmgr::CircMgr<R>>,
+                _: DirMgrConfig,
+            ) -> crate::Result<Arc<dyn DirProvider + 'static>> {
+                let provider = Arc::new(FlakyDirProvider::default());
+                *self.0.lock().unwrap() = Some(provider.clone());
+                Ok(provider)
+            }
+        }
+
+        /// The bridge we are configured with.
+        const BRIDGE1: &str = "192.0.2.66:443 8C00000DFE0046ABCDFAD191144399CB520C29E8";
+        /// The bridge that our bridge source gives us.
+        const BRIDGE2: &str = "192.0.2.67:443 8C00000DFE0046ABCDFAD191144399CB520C29E9";
+
+        /// A bridge source that always returns [`BRIDGE2`].
+        #[derive(Default)]
+        struct FakeSource {
+            /// The transports we have been asked for, once per request.
+            requests: Mutex<Vec<Vec<String>>>,
+        }
+        #[async_trait]
+        impl tor_moat::BridgeSource for FakeSource {
+            async fn fetch_bridges(
+                &self,
+                transports: &[String],
+            ) -> tor_moat::Result<Vec<BridgeConfigBuilder>> {
+                self.requests.lock().unwrap().push(transports.to_vec());
+                Ok(vec![BRIDGE2.parse().unwrap()])
+            }
+        }
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let bridge1: BridgeConfig = BRIDGE1.parse().unwrap();
+            let bridge2: BridgeConfig = BRIDGE2.parse().unwrap();
+
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let mut cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir);
+            cfg.bridges().bridges().push(BRIDGE1.parse().unwrap());
+            let cfg = cfg.build().unwrap();
+            let builder = Arc::new(FlakyBuilder::default());
+            let tor_client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .dirmgr_builder::<FlakyBuilder>(builder.clone())
+                .create_unbootstrapped()
+                .unwrap();
+            let source = Arc::new(FakeSource::default());
+            tor_client.set_bridge_source(Some(source.clone()));
+            assert_eq!(tor_client.bridges(), [bridge1]);
+
+            // The first attempt fails with our configured bridge, so we fetch
+            // a new one, switch to it, and succeed.
+            tor_client.bootstrap().await.unwrap();
+            assert_eq!(tor_client.bridges(), [bridge2]);
+            assert_eq!(*source.requests.lock().unwrap(), [Vec::<String>::new()]);
+            let provider = builder.0.lock().unwrap().clone().unwrap();
+            assert_eq!(provider.attempts.load(Ordering::SeqCst), 2);
+        });
+    }
 }
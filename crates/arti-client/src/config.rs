@@ -7,13 +7,13 @@ use derive_builder::Builder;
 use derive_more::AsRef;
 use fs_mistrust::{Mistrust, MistrustBuilder};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::time::Duration;
 
-pub use tor_chanmgr::{ChannelConfig, ChannelConfigBuilder};
+pub use tor_chanmgr::{ChannelConfig, ChannelConfigBuilder, UpstreamProxy};
 pub use tor_config::convert_helper_via_multi_line_list_builder;
 pub use tor_config::impl_standard_builder;
 pub use tor_config::list_builder::{MultilineListBuilder, MultilineListBuilderError};
@@ -24,20 +24,24 @@ pub use tor_config::{ConfigBuildError, ConfigurationSource, Reconfigure};
 pub use tor_config_path::{CfgPath, CfgPathError, CfgPathResolver};
 pub use tor_linkspec::{ChannelMethod, HasChanMethod, PtTransportName, TransportId};
 
-pub use tor_guardmgr::bridge::BridgeConfigBuilder;
+pub use tor_guardmgr::bridge::{BridgeConfig, BridgeConfigBuilder};
 
 #[cfg(feature = "bridge-client")]
 #[cfg_attr(docsrs, doc(cfg(feature = "bridge-client")))]
 pub use tor_guardmgr::bridge::BridgeParseError;
 
-use tor_guardmgr::bridge::BridgeConfig;
 use tor_keymgr::config::{ArtiKeystoreConfig, ArtiKeystoreConfigBuilder};
+use tor_persist::slug::Slug;
+
+#[cfg(feature = "geoip")]
+use tor_geoip::CountryCode;
 
 /// Types for configuring how Tor circuits are built.
 pub mod circ {
     pub use tor_circmgr::{
-        CircMgrConfig, CircuitTiming, CircuitTimingBuilder, PathConfig, PathConfigBuilder,
-        PreemptiveCircuitConfig, PreemptiveCircuitConfigBuilder,
+        AddrFamilyPolicy, CircMgrConfig, CircuitTiming, CircuitTimingBuilder, ConfluxConfig,
+        ConfluxConfigBuilder, MultipathUse, PathConfig, PathConfigBuilder, PreemptiveCircuitConfig,
+        PreemptiveCircuitConfigBuilder,
     };
 }
 
@@ -92,9 +96,88 @@ pub struct ClientAddrConfig {
     #[cfg(feature = "onion-service-client")]
     #[builder(default = "true")]
     pub(crate) allow_onion_addrs: bool,
+
+    /// A country to restrict the exit relays of our streams to, if any.
+    ///
+    /// This is the default for streams whose [`StreamPrefs`](crate::StreamPrefs)
+    /// don't say which country to use.  By default, exits may be anywhere.
+    //
+    // We make this type a String in the builder, so that it can be serialized.
+    #[cfg(feature = "geoip")]
+    #[builder(
+        setter(custom),
+        field(type = "Option<String>", build = "self.parse_exit_country()?")
+    )]
+    pub(crate) exit_country: Option<CountryCode>,
 }
 impl_standard_builder! { ClientAddrConfig }
 
+#[cfg(feature = "geoip")]
+impl ClientAddrConfigBuilder {
+    /// Restrict the exit relays of our streams to `country`, or, if it is
+    /// `None`, let them be anywhere.
+    pub fn exit_country(&mut self, country: Option<CountryCode>) -> &mut Self {
+        self.exit_country = country.map(|cc| cc.to_string());
+        self
+    }
+
+    /// Helper: Parse the exit_country field.
+    fn parse_exit_country(&self) -> Result<Option<CountryCode>, ConfigBuildError> {
+        self.exit_country
+            .as_deref()
+            .map(|cc| {
+                cc.parse::<CountryCode>()
+                    .map_err(|e| ConfigBuildError::Invalid {
+                        field: "exit_country".to_string(),
+                        problem: e.to_string(),
+                    })
+            })
+            .transpose()
+    }
+}
+
+/// Configuration for replacing the hostnames that the client is asked to
+/// connect to, or to look up.
+///
+/// This type is immutable once constructed. To create an object of this type,
+/// use [`AddressMapConfigBuilder`].
+///
+/// Mappings can also be added, and removed, on a running client, with
+/// [`TorClient::map_address`](crate::TorClient::map_address) and
+/// [`TorClient::unmap_address`](crate::TorClient::unmap_address).
+///
+/// You can replace this configuration on a running Arti client.  Doing so will
+/// affect new streams and requests, but will have no effect on existing streams
+/// and requests.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(validate = "validate_address_map", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct AddressMapConfig {
+    /// Hostnames to replace, and what to replace each one with.
+    ///
+    /// Each hostname (which may be a `.onion` address) is replaced by another
+    /// hostname, a `.onion` address, or an IP address, before we decide how
+    /// to connect to it.  Hostnames are matched exactly, ignoring case.
+    #[builder(default)]
+    pub(crate) hosts: BTreeMap<String, String>,
+}
+impl_standard_builder! { AddressMapConfig }
+
+/// Check that every mapping in an [`AddressMapConfigBuilder`] makes sense.
+fn validate_address_map(map: &AddressMapConfigBuilder) -> Result<(), ConfigBuildError> {
+    for (from, to) in map.hosts.iter().flatten() {
+        for host in [from, to] {
+            host.parse::<crate::address::Host>()
+                .map_err(|e| ConfigBuildError::Invalid {
+                    field: "hosts".to_string(),
+                    problem: format!("{host:?}: {e}"),
+                })?;
+        }
+    }
+    Ok(())
+}
+
 /// Configuration for client behavior relating to stream connection timeouts
 ///
 /// This type is immutable once constructed. To create an object of this type,
@@ -142,6 +225,95 @@ fn default_dns_resolve_ptr_timeout() -> Duration {
     Duration::new(10, 0)
 }
 
+/// Configuration for the client's cache of DNS answers.
+///
+/// This type is immutable once constructed. To create an object of this type,
+/// use [`DnsCacheConfigBuilder`].
+///
+/// Answers are only shared between lookups that could have shared a circuit,
+/// so the cache doesn't link otherwise isolated activity.
+///
+/// You can replace this configuration on a running Arti client.  Doing so
+/// clears the cache.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct DnsCacheConfig {
+    /// Should we cache the answers to DNS lookups at all?
+    #[builder(default = "true")]
+    pub(crate) enabled: bool,
+
+    /// The largest number of answers to keep.
+    ///
+    /// When the cache is full, we forget the answers that would expire
+    /// soonest.
+    #[builder(default = "default_dns_cache_max_entries()")]
+    pub(crate) max_entries: usize,
+
+    /// The longest time for which to keep an answer, whatever its TTL says.
+    #[builder(default = "default_dns_cache_max_ttl()")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) max_ttl: Duration,
+
+    /// How long to keep an answer that says that a name (or address) does
+    /// not exist.
+    ///
+    /// Answers that report transient errors are never kept.
+    #[builder(default = "default_dns_cache_negative_ttl()")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) negative_ttl: Duration,
+}
+impl_standard_builder! { DnsCacheConfig }
+
+/// Return the default number of answers in the DNS cache
+fn default_dns_cache_max_entries() -> usize {
+    1024
+}
+
+/// Return the default longest time to keep a DNS answer
+fn default_dns_cache_max_ttl() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+/// Return the default time to keep a negative DNS answer
+fn default_dns_cache_negative_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Configuration for putting the client to sleep when nobody is using it.
+///
+/// This type is immutable once constructed. To create an object of this type,
+/// use [`DormancyConfigBuilder`].
+///
+/// When `auto_dormant` is set, a client that has had no open streams, and
+/// hasn't been asked to connect or look anything up, for `idle_timeout`
+/// becomes [`DormantMode::Soft`](crate::DormantMode::Soft): it stops
+/// building circuits in advance, retires the circuits it has, and stops
+/// fetching directory information.  The next request wakes it up again.
+///
+/// You can replace this configuration on a running Arti client.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct DormancyConfig {
+    /// Should we become dormant by ourselves when we are idle?
+    #[builder(default)]
+    pub(crate) auto_dormant: bool,
+
+    /// How long we must be idle before we become dormant.
+    #[builder(default = "default_idle_timeout()")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) idle_timeout: Duration,
+}
+impl_standard_builder! { DormancyConfig }
+
+/// Return the default time to be idle before becoming dormant
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
 /// Configuration for overriding the status of our software.
 ///
 /// # Issues
@@ -217,6 +389,16 @@ pub struct StorageConfig {
     #[builder(setter(into), default = "default_state_dir()")]
     state_dir: CfgPath,
 
+    /// A name for this Arti instance, if it shares `cache_dir` and `state_dir`
+    /// with other instances.
+    ///
+    /// If this is set, this instance keeps its cache, state, and keys
+    /// in an `instances/INSTANCE` subdirectory of `cache_dir` and `state_dir`,
+    /// so that it has its own locks, guard state, and keystore.
+    /// (See [`tor_persist::instance`].)
+    #[builder(default)]
+    instance: Option<Slug>,
+
     /// Location on disk for the Arti keystore.
     #[cfg(feature = "keymgr")]
     #[builder(sub_builder)]
@@ -247,6 +429,7 @@ macro_rules! expand_dir {
         $self
             .$dirname
             .path($dircfg)
+            .map(|dir| tor_persist::instance::instance_dir(dir, $self.instance.as_deref()))
             .map_err(|e| ConfigBuildError::Invalid {
                 field: stringify!($dirname).to_owned(),
                 problem: e.to_string(),
@@ -256,6 +439,8 @@ macro_rules! expand_dir {
 
 impl StorageConfig {
     /// Try to expand `state_dir` to be a path buffer.
+    ///
+    /// If we have an `instance`, this is that instance's subdirectory.
     pub(crate) fn expand_state_dir(
         &self,
         path_resolver: &CfgPathResolver,
@@ -263,6 +448,8 @@ impl StorageConfig {
         expand_dir!(self, state_dir, path_resolver)
     }
     /// Try to expand `cache_dir` to be a path buffer.
+    ///
+    /// If we have an `instance`, this is that instance's subdirectory.
     pub(crate) fn expand_cache_dir(
         &self,
         path_resolver: &CfgPathResolver,
@@ -376,7 +563,7 @@ pub struct BridgesConfig {
     /// Configured list of bridges (possibly via pluggable transports)
     #[builder(sub_builder, setter(custom))]
     #[builder_field_attr(serde(default))]
-    bridges: BridgeList,
+    pub(crate) bridges: BridgeList,
 
     /// Configured list of pluggable transports.
     #[builder(sub_builder, setter(custom))]
@@ -470,26 +657,16 @@ fn validate_pt_config(bridges: &BridgesConfigBuilder) -> Result<(), ConfigBuildE
 fn validate_bridges_config(bridges: &BridgesConfigBuilder) -> Result<(), ConfigBuildError> {
     let _ = bridges; // suppresses unused variable for just that argument
 
-    use BoolOrAuto as BoA;
-
     // Ideally we would run this post-build, rather than pre-build;
     // doing it here means we have to recapitulate the defaulting.
     // Happily the defaulting is obvious, cheap, and not going to change.
     //
     // Alternatively we could have derive_builder provide `build_unvalidated`,
     // but that involves re-setting the build fn name for every field.
-    match (
+    check_bridges_enabled(
         bridges.enabled.unwrap_or_default(),
         bridges.bridges.bridges.as_deref().unwrap_or_default(),
-    ) {
-        (BoA::Auto, _) | (BoA::Explicit(false), _) | (BoA::Explicit(true), [_, ..]) => {}
-        (BoA::Explicit(true), []) => {
-            return Err(ConfigBuildError::Inconsistent {
-                fields: ["enabled", "bridges"].map(Into::into).into_iter().collect(),
-                problem: "bridges.enabled=true, but no bridges defined".into(),
-            })
-        }
-    }
+    )?;
     #[cfg(feature = "pt-client")]
     {
         if bridges_enabled(
@@ -503,6 +680,22 @@ fn validate_bridges_config(bridges: &BridgesConfigBuilder) -> Result<(), ConfigB
     Ok(())
 }
 
+/// Check that we have some bridges, if we must use bridges.
+fn check_bridges_enabled(
+    enabled: BoolOrAuto,
+    bridges: &[impl Sized],
+) -> Result<(), ConfigBuildError> {
+    use BoolOrAuto as BoA;
+
+    match (enabled, bridges) {
+        (BoA::Auto, _) | (BoA::Explicit(false), _) | (BoA::Explicit(true), [_, ..]) => Ok(()),
+        (BoA::Explicit(true), []) => Err(ConfigBuildError::Inconsistent {
+            fields: ["enabled", "bridges"].map(Into::into).into_iter().collect(),
+            problem: "bridges.enabled=true, but no bridges defined".into(),
+        }),
+    }
+}
+
 /// Generic logic to check if bridges should be used or not
 fn bridges_enabled(enabled: BoolOrAuto, bridges: &[impl Sized]) -> bool {
     #[cfg(feature = "bridge-client")]
@@ -522,6 +715,17 @@ impl BridgesConfig {
     fn bridges_enabled(&self) -> bool {
         bridges_enabled(self.enabled, &self.bridges)
     }
+
+    /// Check that our list of bridges is consistent with the rest of this
+    /// configuration, after changing it.
+    ///
+    /// Unlike the checks when building a `BridgesConfig`, this doesn't insist
+    /// that some bridge can use one of our pluggable transports: bridges that
+    /// can't are never reachable.
+    #[cfg(feature = "bridge-client")]
+    pub(crate) fn validate_bridge_list(&self) -> Result<(), ConfigBuildError> {
+        check_bridges_enabled(self.enabled, &self.bridges)
+    }
 }
 
 /// List of configured bridges, as found in the built configuration
@@ -650,16 +854,37 @@ pub struct TorClientConfig {
     #[builder_field_attr(serde(default))]
     circuit_timing: circ::CircuitTiming,
 
+    /// Information about when and how to spread streams over several circuits.
+    #[as_ref]
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    conflux: circ::ConfluxConfig,
+
     /// Rules about which addresses the client is willing to connect to.
     #[builder(sub_builder)]
     #[builder_field_attr(serde(default))]
     pub(crate) address_filter: ClientAddrConfig,
 
+    /// Hostnames to replace before connecting or resolving.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) address_map: AddressMapConfig,
+
     /// Information about timing out client requests.
     #[builder(sub_builder)]
     #[builder_field_attr(serde(default))]
     pub(crate) stream_timeouts: StreamTimeoutConfig,
 
+    /// Information about caching the answers to DNS lookups.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) dns_cache: DnsCacheConfig,
+
+    /// Information about becoming dormant when idle.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    pub(crate) dormancy: DormancyConfig,
+
     /// Information about vanguards.
     #[builder(sub_builder)]
     #[builder_field_attr(serde(default))]
@@ -767,7 +992,83 @@ impl tor_guardmgr::GuardMgrConfig for TorClientConfig {
     }
 }
 
+/// What happened when a [`TorClient`](crate::TorClient) was reconfigured.
+///
+/// Returned by [`TorClient::reconfigure`](crate::TorClient::reconfigure).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ReconfigureReport {
+    /// The options that we couldn't change, and so ignored.
+    ignored: Vec<&'static str>,
+}
+
+impl ReconfigureReport {
+    /// Return the names of the options that couldn't be changed on a running
+    /// client, and were left as they were.
+    ///
+    /// This is only ever nonempty when reconfiguring with
+    /// [`Reconfigure::WarnOnFailures`]: otherwise, trying to change these
+    /// options is an error.
+    pub fn ignored(&self) -> &[&'static str] {
+        &self.ignored
+    }
+
+    /// Return true if every change in the new configuration took effect.
+    pub fn is_complete(&self) -> bool {
+        self.ignored.is_empty()
+    }
+}
+
 impl TorClientConfig {
+    /// Return a report of the changes from `self` to `new` that we would
+    /// ignore on a running client, since the options can't be changed.
+    ///
+    /// This must list every option for which `TorClient::reconfigure`, or
+    /// one of the subsystems that it reconfigures, calls
+    /// [`Reconfigure::cannot_change`](tor_config::Reconfigure::cannot_change).
+    pub(crate) fn ignored_changes(&self, new: &TorClientConfig) -> ReconfigureReport {
+        let mut ignored = vec![];
+        let mut check = |field, changed| {
+            if changed {
+                ignored.push(field);
+            }
+        };
+        let (old_dir, new_dir) = (self.dir_mgr_config().ok(), new.dir_mgr_config().ok());
+        let old_dir = old_dir.as_ref();
+        let new_dir = new_dir.as_ref();
+        check(
+            "storage.state_dir",
+            self.storage.expand_state_dir(&self.path_resolver).ok()
+                != new.storage.expand_state_dir(&new.path_resolver).ok(),
+        );
+        check(
+            "storage.cache_dir",
+            old_dir.map(|c| &c.cache_dir) != new_dir.map(|c| &c.cache_dir),
+        );
+        check(
+            "storage.permissions",
+            self.storage.permissions != new.storage.permissions,
+        );
+        check(
+            "storage.keystore",
+            self.storage.keystore() != new.storage.keystore(),
+        );
+        check(
+            "tor_network.authorities",
+            old_dir.map(|c| c.authorities()) != new_dir.map(|c| c.authorities()),
+        );
+        check(
+            "preemptive_circuits.initial_predicted_ports",
+            self.preemptive_circuits.initial_predicted_ports()
+                != new.preemptive_circuits.initial_predicted_ports(),
+        );
+        check(
+            "system.memory",
+            self.system.memory.inner().is_none() && new.system.memory.inner().is_some(),
+        );
+        ReconfigureReport { ignored }
+    }
+
     /// Try to create a DirMgrConfig corresponding to this object.
     #[rustfmt::skip]
     pub fn dir_mgr_config(&self) -> Result<dir::DirMgrConfig, ConfigBuildError> {
@@ -806,6 +1107,16 @@ impl TorClientConfig {
         self.storage.keystore()
     }
 
+    /// Return the directory in which Arti keeps its persistent state,
+    /// and the [`Mistrust`] configuration for checking it.
+    ///
+    /// This is for tools that inspect or repair that state, using
+    /// [`tor_persist::FsStateMgr`].
+    #[cfg(feature = "experimental-api")]
+    pub fn state_dir_and_mistrust(&self) -> crate::Result<(PathBuf, &Mistrust)> {
+        Ok(self.state_dir()?)
+    }
+
     /// Get the state directory and its corresponding
     /// [`Mistrust`] configuration.
     pub(crate) fn state_dir(&self) -> StdResult<(PathBuf, &fs_mistrust::Mistrust), ErrorDetail> {
@@ -950,6 +1261,59 @@ mod test {
         assert_ne!(val, TorClientConfig::default());
     }
 
+    #[test]
+    fn instance_dirs() {
+        let mut bld = TorClientConfig::builder();
+        bld.storage()
+            .cache_dir(CfgPath::new_literal("/var/tmp/foo"))
+            .state_dir(CfgPath::new_literal("/var/tmp/bar"))
+            .instance(Some(Slug::new("system-proxy".into()).unwrap()));
+        let val = bld.build().unwrap();
+
+        assert_eq!(
+            val.state_dir().unwrap().0,
+            Path::new("/var/tmp/bar/instances/system-proxy")
+        );
+        assert_eq!(
+            val.dir_mgr_config().unwrap().cache_dir,
+            Path::new("/var/tmp/foo/instances/system-proxy")
+        );
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn exit_country() {
+        let mut bld = ClientAddrConfig::builder();
+        assert_eq!(bld.build().unwrap().exit_country, None);
+
+        bld.exit_country(Some("de".parse().unwrap()));
+        assert_eq!(
+            bld.build().unwrap().exit_country,
+            Some("DE".parse().unwrap())
+        );
+
+        let bld: ClientAddrConfigBuilder = toml::from_str(r#"exit_country = "se""#).unwrap();
+        assert_eq!(
+            bld.build().unwrap().exit_country,
+            Some("SE".parse().unwrap())
+        );
+
+        let bld: ClientAddrConfigBuilder = toml::from_str(r#"exit_country = "??""#).unwrap();
+        assert!(bld.build().is_err());
+    }
+
+    #[test]
+    fn address_map() {
+        let bld: AddressMapConfigBuilder =
+            toml::from_str(r#"hosts = { "www.example.com" = "192.0.2.1" }"#).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.hosts["www.example.com"], "192.0.2.1");
+
+        let bld: AddressMapConfigBuilder =
+            toml::from_str(r#"hosts = { "www.example.com" = "not a host" }"#).unwrap();
+        assert!(bld.build().is_err());
+    }
+
     #[test]
     fn bridges_supported() {
         /// checks that when s is processed as TOML for a client config,
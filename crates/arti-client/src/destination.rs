@@ -0,0 +1,164 @@
+//! Isolating streams by where they go.
+//!
+//! Tor Browser expects streams to different kinds of service, and streams
+//! made on behalf of different first-party sites, never to share a circuit.
+//! [`StreamPrefs::isolate_by_port_class`](crate::StreamPrefs::isolate_by_port_class)
+//! and [`StreamPrefs::first_party_domain`](crate::StreamPrefs::first_party_domain)
+//! provide that isolation, so that embedders don't need to build it out of
+//! isolation tokens themselves.
+
+use tor_circmgr::isolation::{Isolation, IsolationHelper};
+
+/// A category of destination port, used to keep streams to different kinds
+/// of service on different circuits.
+///
+/// See [`StreamPrefs::isolate_by_port_class`](crate::StreamPrefs::isolate_by_port_class).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum PortClass {
+    /// A port used for web traffic, such as 80 or 443.
+    Web,
+    /// A port used to send or fetch mail, such as 25, 587, or 993.
+    Mail,
+    /// Any other port.
+    Other,
+}
+
+/// Ports that we consider to be [`PortClass::Web`].
+const WEB_PORTS: &[u16] = &[80, 443, 8008, 8080, 8443];
+
+/// Ports that we consider to be [`PortClass::Mail`].
+///
+/// These are SMTP, submission, POP3, and IMAP, with and without TLS.
+const MAIL_PORTS: &[u16] = &[25, 110, 143, 465, 587, 993, 995];
+
+impl PortClass {
+    /// Return the class of the destination port `port`.
+    pub fn of_port(port: u16) -> Self {
+        if WEB_PORTS.contains(&port) {
+            PortClass::Web
+        } else if MAIL_PORTS.contains(&port) {
+            PortClass::Mail
+        } else {
+            PortClass::Other
+        }
+    }
+}
+
+/// An [`Isolation`] that adds destination-based rules to another one.
+///
+/// Two streams with `DestinationIsolation`s may share a circuit only if their
+/// inner isolations are compatible, and their port classes and first-party
+/// domains are the same.
+#[derive(Clone, Debug)]
+pub(crate) struct DestinationIsolation {
+    /// The isolation that we're adding to.
+    inner: Box<dyn Isolation>,
+    /// The class of the destination port, if we isolate by port class.
+    ///
+    /// This is `None` for DNS lookups, which have no port.
+    port_class: Option<PortClass>,
+    /// The first-party domain that the stream is made for, if any.
+    first_party: Option<String>,
+}
+
+impl DestinationIsolation {
+    /// Return a new `DestinationIsolation` that adds `port_class` and
+    /// `first_party` to `inner`.
+    pub(crate) fn new(
+        inner: Box<dyn Isolation>,
+        port_class: Option<PortClass>,
+        first_party: Option<String>,
+    ) -> Self {
+        DestinationIsolation {
+            inner,
+            port_class,
+            first_party,
+        }
+    }
+}
+
+impl IsolationHelper for DestinationIsolation {
+    fn compatible_same_type(&self, other: &Self) -> bool {
+        self.port_class == other.port_class
+            && self.first_party == other.first_party
+            && self.inner.compatible(other.inner.as_ref())
+    }
+
+    fn join_same_type(&self, other: &Self) -> Option<Self> {
+        if self.port_class != other.port_class || self.first_party != other.first_party {
+            return None;
+        }
+        let inner = self.inner.join(other.inner.as_ref())?;
+        Some(DestinationIsolation {
+            inner,
+            port_class: self.port_class,
+            first_party: self.first_party.clone(),
+        })
+    }
+}
+
+/// Return `domain` in the form that we compare first-party domains in.
+///
+/// Domains are case-insensitive, and a trailing dot doesn't change which
+/// domain is meant.
+pub(crate) fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_circmgr::IsolationToken;
+
+    #[test]
+    fn port_class() {
+        assert_eq!(PortClass::of_port(443), PortClass::Web);
+        assert_eq!(PortClass::of_port(8080), PortClass::Web);
+        assert_eq!(PortClass::of_port(993), PortClass::Mail);
+        assert_eq!(PortClass::of_port(25), PortClass::Mail);
+        assert_eq!(PortClass::of_port(22), PortClass::Other);
+    }
+
+    #[test]
+    fn compatible() {
+        let tok = IsolationToken::no_isolation();
+        let iso = |class, domain: Option<&str>| {
+            DestinationIsolation::new(Box::new(tok), class, domain.map(normalize_domain))
+        };
+        let web_a = iso(Some(PortClass::Web), Some("Example.COM."));
+        let web_a2 = iso(Some(PortClass::Web), Some("example.com"));
+        let web_b = iso(Some(PortClass::Web), Some("example.org"));
+        let mail_a = iso(Some(PortClass::Mail), Some("example.com"));
+        let web = iso(Some(PortClass::Web), None);
+
+        assert!(web_a.compatible(&web_a2));
+        assert!(web_a.join(&web_a2).is_some());
+        assert!(!web_a.compatible(&web_b));
+        assert!(!web_a.compatible(&mail_a));
+        assert!(!web_a.compatible(&web));
+        assert!(web_a.join(&mail_a).is_none());
+
+        // The inner isolation still applies.
+        let other = DestinationIsolation::new(
+            Box::new(IsolationToken::new()),
+            Some(PortClass::Web),
+            Some("example.com".into()),
+        );
+        assert!(!web_a.compatible(&other));
+        assert!(web_a.join(&other).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+//! Complete answers to DNS lookups made over Tor.
+//!
+//! [`TorClient::resolve`](crate::TorClient::resolve) returns only the
+//! addresses that an exit relay found.  The `*_answer` lookup methods on
+//! [`TorClient`](crate::TorClient) return a [`DnsAnswer`] instead, with every
+//! record that the exit sent, including errors, and how long each may be
+//! cached.  That is enough for an application to do its own caching, or to
+//! race IPv4 and IPv6 connections.
+//!
+//! The client also keeps its own cache of these answers, configured by the
+//! `dns_cache` section of its configuration, so that repeated lookups don't
+//! each need a round trip to an exit relay.
+
+mod cache;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use tor_cell::relaycell::msg::ResolvedVal;
+
+pub(crate) use cache::{DnsCache, DnsQuery};
+
+/// The value of one record in a [`DnsAnswer`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum DnsValue {
+    /// An IPv4 or IPv6 address, as in an A or AAAA record.
+    Ip(IpAddr),
+    /// A hostname, from a reverse lookup, as in a PTR record.
+    Hostname(String),
+    /// The exit couldn't answer, but a later lookup might succeed.
+    TransientError,
+    /// The exit couldn't answer, and a later lookup won't succeed either.
+    NontransientError,
+    /// A kind of answer that we don't recognize, with its type code.
+    Unrecognized(u8),
+}
+
+/// One record in a [`DnsAnswer`]: a value, and how long it may be cached.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsRecord {
+    /// The value.
+    value: DnsValue,
+    /// How long the value may be cached.
+    ttl: Duration,
+}
+
+/// Every record in the reply to a DNS lookup made over Tor.
+///
+/// Returned by [`TorClient::resolve_answer`](crate::TorClient::resolve_answer)
+/// and [`TorClient::resolve_ptr_answer`](crate::TorClient::resolve_ptr_answer).
+/// The records are in the order that the exit relay sent them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DnsAnswer {
+    /// The records.
+    records: Vec<DnsRecord>,
+}
+
+/// The TTL of answers that we didn't need to look up, because the
+/// hostname was an address already.
+///
+/// This is the largest TTL that a RESOLVED message can express, `u32::MAX`
+/// seconds.
+const LITERAL_TTL: Duration = Duration::from_secs(0xffff_ffff);
+
+impl DnsRecord {
+    /// Return the value of this record.
+    pub fn value(&self) -> &DnsValue {
+        &self.value
+    }
+
+    /// Return how long this record may be cached.
+    ///
+    /// Exit relays round the TTLs that they report, so that they don't
+    /// reveal how long ago they looked the name up.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+}
+
+impl DnsAnswer {
+    /// Build a `DnsAnswer` from the answers in a RESOLVED message.
+    ///
+    /// Returns an error if a hostname is not UTF-8.
+    pub(crate) fn from_resolved(
+        answers: Vec<(ResolvedVal, u32)>,
+    ) -> Result<Self, tor_proto::Error> {
+        let records = answers
+            .into_iter()
+            .map(|(val, ttl)| {
+                let value = match val {
+                    ResolvedVal::Ip(ip) => DnsValue::Ip(ip),
+                    ResolvedVal::Hostname(name) => {
+                        DnsValue::Hostname(String::from_utf8(name).map_err(|_| {
+                            tor_proto::Error::StreamProto("Resolved Hostname was not utf-8".into())
+                        })?)
+                    }
+                    ResolvedVal::TransientError => DnsValue::TransientError,
+                    ResolvedVal::NontransientError => DnsValue::NontransientError,
+                    ResolvedVal::Unrecognized(tag, _) => DnsValue::Unrecognized(tag),
+                    _ => {
+                        return Err(tor_proto::Error::StreamProto(
+                            "Unsupported answer in RESOLVED message".into(),
+                        ))
+                    }
+                };
+                Ok(DnsRecord {
+                    value,
+                    ttl: Duration::from_secs(ttl.into()),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(DnsAnswer { records })
+    }
+
+    /// Build a `DnsAnswer` for addresses that didn't need to be looked up.
+    pub(crate) fn from_literal(addrs: Vec<IpAddr>) -> Self {
+        let records = addrs
+            .into_iter()
+            .map(|ip| DnsRecord {
+                value: DnsValue::Ip(ip),
+                ttl: LITERAL_TTL,
+            })
+            .collect();
+        DnsAnswer { records }
+    }
+
+    /// Return a copy of this answer as it should look `elapsed` after we
+    /// received it: with every TTL reduced by `elapsed`.
+    pub(crate) fn aged(&self, elapsed: Duration) -> Self {
+        let records = self
+            .records
+            .iter()
+            .map(|r| DnsRecord {
+                value: r.value.clone(),
+                ttl: r.ttl.saturating_sub(elapsed),
+            })
+            .collect();
+        DnsAnswer { records }
+    }
+
+    /// Return every record in this answer.
+    pub fn records(&self) -> &[DnsRecord] {
+        &self.records
+    }
+
+    /// Return every IP address in this answer, IPv4 or IPv6.
+    pub fn ips(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.records.iter().filter_map(|r| match r.value {
+            DnsValue::Ip(ip) => Some(ip),
+            _ => None,
+        })
+    }
+
+    /// Return every IPv4 address in this answer.
+    pub fn ipv4(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.ips().filter_map(|ip| match ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    /// Return every IPv6 address in this answer.
+    pub fn ipv6(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        self.ips().filter_map(|ip| match ip {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(ip),
+        })
+    }
+
+    /// Return every hostname in this answer.
+    pub fn hostnames(&self) -> impl Iterator<Item = &str> + '_ {
+        self.records.iter().filter_map(|r| match &r.value {
+            DnsValue::Hostname(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Return true if the exit reported an error that might go away if we
+    /// try again.
+    pub fn has_transient_error(&self) -> bool {
+        self.records
+            .iter()
+            .any(|r| r.value == DnsValue::TransientError)
+    }
+
+    /// Return the error that [`TorClient::resolve`](crate::TorClient::resolve)
+    /// would report for this answer, if it has any error records.
+    pub(crate) fn resolve_error(&self) -> Option<tor_proto::Error> {
+        self.records.iter().find_map(|r| {
+            let err = match r.value {
+                DnsValue::TransientError => tor_proto::ResolveError::Transient,
+                DnsValue::NontransientError => tor_proto::ResolveError::Nontransient,
+                DnsValue::Unrecognized(_) => tor_proto::ResolveError::Unrecognized,
+                DnsValue::Ip(_) | DnsValue::Hostname(_) => return None,
+            };
+            Some(tor_proto::Error::ResolveError(err))
+        })
+    }
+
+    /// Return the shortest TTL of any record in this answer, or `None` if it
+    /// has no records.
+    ///
+    /// This is how long the whole answer may be cached.
+    pub fn min_ttl(&self) -> Option<Duration> {
+        self.records.iter().map(|r| r.ttl).min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn from_resolved() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let answer = DnsAnswer::from_resolved(vec![
+            (ResolvedVal::Ip(v4), 300),
+            (ResolvedVal::Ip(v6), 60),
+            (ResolvedVal::TransientError, 3600),
+            (ResolvedVal::Unrecognized(0xf2, vec![1, 2]), 10),
+        ])
+        .unwrap();
+
+        assert_eq!(answer.records().len(), 4);
+        assert_eq!(answer.ips().collect::<Vec<_>>(), [v4, v6]);
+        assert_eq!(
+            answer.ipv4().collect::<Vec<_>>(),
+            ["192.0.2.1".parse::<Ipv4Addr>().unwrap()]
+        );
+        assert_eq!(
+            answer.ipv6().collect::<Vec<_>>(),
+            ["2001:db8::1".parse::<Ipv6Addr>().unwrap()]
+        );
+        assert!(answer.has_transient_error());
+        assert_eq!(answer.records()[3].value(), &DnsValue::Unrecognized(0xf2));
+        assert_eq!(answer.records()[1].ttl(), Duration::from_secs(60));
+        assert_eq!(answer.min_ttl(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn hostnames() {
+        let answer = DnsAnswer::from_resolved(vec![
+            (ResolvedVal::hostname("example.com"), 300),
+            (ResolvedVal::NontransientError, 300),
+        ])
+        .unwrap();
+        assert_eq!(answer.hostnames().collect::<Vec<_>>(), ["example.com"]);
+        assert!(!answer.has_transient_error());
+
+        assert!(DnsAnswer::from_resolved(vec![(ResolvedVal::Hostname(vec![0xff]), 300)]).is_err());
+        assert_eq!(DnsAnswer::default().min_ttl(), None);
+    }
+
+    #[test]
+    fn literal() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let answer = DnsAnswer::from_literal(vec![ip]);
+        assert_eq!(answer.ips().collect::<Vec<_>>(), [ip]);
+        assert_eq!(answer.min_ttl(), Some(LITERAL_TTL));
+    }
+}
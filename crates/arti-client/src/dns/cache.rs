@@ -0,0 +1,374 @@
+//! A cache of the answers to DNS lookups.
+//!
+//! Each answer is stored along with the [`StreamIsolation`] of the lookup
+//! that got it, and is only used for later lookups that could have shared a
+//! circuit with that one.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tor_circmgr::isolation::{Isolation as _, StreamIsolation};
+
+use super::{DnsAnswer, DnsValue};
+use crate::config::DnsCacheConfig;
+
+/// A lookup whose answer we can cache.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub(crate) enum DnsQuery {
+    /// A lookup of the addresses for a hostname.
+    ///
+    /// The hostname is in lowercase, since DNS names are case-insensitive.
+    Forward(String),
+    /// A reverse lookup of the hostnames for an address.
+    Reverse(IpAddr),
+}
+
+impl DnsQuery {
+    /// Return the query for the addresses of `hostname`.
+    pub(crate) fn forward(hostname: &str) -> Self {
+        DnsQuery::Forward(hostname.to_ascii_lowercase())
+    }
+}
+
+/// One cached answer.
+#[derive(Clone, Debug)]
+struct Entry {
+    /// The isolation of the lookup that got this answer.
+    isolation: StreamIsolation,
+    /// The answer, as we received it.
+    answer: DnsAnswer,
+    /// When we received the answer.
+    received: Instant,
+    /// When we must stop using the answer.
+    expires: Instant,
+}
+
+/// The mutable state of a [`DnsCache`].
+#[derive(Debug)]
+struct Inner {
+    /// Our current configuration.
+    config: DnsCacheConfig,
+    /// The cached answers for each query, for different isolations.
+    entries: HashMap<DnsQuery, Vec<Entry>>,
+    /// The total number of answers in `entries`.
+    n_entries: usize,
+}
+
+/// A cache of the answers to DNS lookups, shared by every clone of a
+/// [`TorClient`](crate::TorClient).
+#[derive(Debug)]
+pub(crate) struct DnsCache {
+    /// The cache itself.
+    inner: Mutex<Inner>,
+}
+
+/// Return how long we may keep `answer`, according to `config`, or `None` if
+/// we must not keep it at all.
+fn lifetime(config: &DnsCacheConfig, answer: &DnsAnswer) -> Option<Duration> {
+    if answer.has_transient_error() {
+        return None;
+    }
+    let has_values = answer
+        .records()
+        .iter()
+        .any(|r| matches!(r.value(), DnsValue::Ip(_) | DnsValue::Hostname(_)));
+    let ttl = if has_values {
+        answer.min_ttl()?.min(config.max_ttl)
+    } else if answer.records().is_empty() {
+        return None;
+    } else {
+        // Nothing but errors that won't go away: a negative answer.
+        config.negative_ttl.min(config.max_ttl)
+    };
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+impl Inner {
+    /// Forget every answer that has expired at `now`.
+    fn remove_expired(&mut self, now: Instant) {
+        self.entries.retain(|_, list| {
+            list.retain(|e| e.expires > now);
+            !list.is_empty()
+        });
+        self.n_entries = self.entries.values().map(Vec::len).sum();
+    }
+
+    /// Forget the answer that would expire soonest.
+    fn remove_soonest_expiring(&mut self) {
+        let soonest = self
+            .entries
+            .iter()
+            .flat_map(|(query, list)| {
+                list.iter()
+                    .enumerate()
+                    .map(move |(idx, e)| (e.expires, query, idx))
+            })
+            .min_by_key(|(expires, _, _)| *expires)
+            .map(|(_, query, idx)| (query.clone(), idx));
+        if let Some((query, idx)) = soonest {
+            if let Some(list) = self.entries.get_mut(&query) {
+                list.swap_remove(idx);
+                if list.is_empty() {
+                    self.entries.remove(&query);
+                }
+            }
+            self.n_entries -= 1;
+        }
+    }
+}
+
+impl DnsCache {
+    /// Return a new, empty, cache with the provided configuration.
+    pub(crate) fn new(config: DnsCacheConfig) -> Self {
+        DnsCache {
+            inner: Mutex::new(Inner {
+                config,
+                entries: HashMap::new(),
+                n_entries: 0,
+            }),
+        }
+    }
+
+    /// Return the cached answer to `query`, if we have one that a lookup
+    /// with `isolation` may use at `now`.
+    ///
+    /// The TTLs of the returned answer are reduced by the time since we
+    /// received it.
+    pub(crate) fn get(
+        &self,
+        query: &DnsQuery,
+        isolation: &StreamIsolation,
+        now: Instant,
+    ) -> Option<DnsAnswer> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        if !inner.config.enabled {
+            return None;
+        }
+        inner
+            .entries
+            .get(query)?
+            .iter()
+            .find(|e| e.expires > now && e.isolation.compatible(isolation))
+            .map(|e| e.answer.aged(now.saturating_duration_since(e.received)))
+    }
+
+    /// Remember `answer` as the answer to `query`, received at `now` by a
+    /// lookup with `isolation`, if we may cache it.
+    pub(crate) fn insert(
+        &self,
+        query: DnsQuery,
+        isolation: StreamIsolation,
+        answer: &DnsAnswer,
+        now: Instant,
+    ) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if !inner.config.enabled || inner.config.max_entries == 0 {
+            return;
+        }
+        let Some(lifetime) = lifetime(&inner.config, answer) else {
+            return;
+        };
+        let entry = Entry {
+            isolation,
+            answer: answer.clone(),
+            received: now,
+            expires: now + lifetime,
+        };
+
+        // Replace any answer that this one supersedes.
+        if let Some(list) = inner.entries.get_mut(&query) {
+            if let Some(old) = list
+                .iter_mut()
+                .find(|e| e.isolation.compatible(&entry.isolation))
+            {
+                *old = entry;
+                return;
+            }
+        }
+
+        if inner.n_entries >= inner.config.max_entries {
+            inner.remove_expired(now);
+        }
+        while inner.n_entries >= inner.config.max_entries {
+            inner.remove_soonest_expiring();
+        }
+        inner.entries.entry(query).or_default().push(entry);
+        inner.n_entries += 1;
+    }
+
+    /// Forget every cached answer.
+    pub(crate) fn clear(&self) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.entries.clear();
+        inner.n_entries = 0;
+    }
+
+    /// Replace our configuration with `config`, and forget every cached
+    /// answer if it changed.
+    pub(crate) fn reconfigure(&self, config: &DnsCacheConfig) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if &inner.config != config {
+            inner.config = config.clone();
+            inner.entries.clear();
+            inner.n_entries = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_cell::relaycell::msg::ResolvedVal;
+    use tor_circmgr::IsolationToken;
+
+    fn answer(vals: Vec<(ResolvedVal, u32)>) -> DnsAnswer {
+        DnsAnswer::from_resolved(vals).unwrap()
+    }
+
+    fn isolation(tok: IsolationToken) -> StreamIsolation {
+        StreamIsolation::builder().owner_token(tok).build().unwrap()
+    }
+
+    #[test]
+    fn ttl() {
+        let cache = DnsCache::new(DnsCacheConfig::default());
+        let now = Instant::now();
+        let iso = isolation(IsolationToken::no_isolation());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let query = DnsQuery::forward("Example.COM");
+        cache.insert(
+            query.clone(),
+            iso.clone(),
+            &answer(vec![(ResolvedVal::Ip(ip), 300)]),
+            now,
+        );
+
+        let got = cache
+            .get(
+                &DnsQuery::forward("example.com"),
+                &iso,
+                now + Duration::from_secs(100),
+            )
+            .unwrap();
+        assert_eq!(got.ips().collect::<Vec<_>>(), [ip]);
+        assert_eq!(got.min_ttl(), Some(Duration::from_secs(200)));
+        assert!(cache
+            .get(&query, &iso, now + Duration::from_secs(300))
+            .is_none());
+
+        // TTLs longer than max_ttl are cut short.
+        cache.insert(
+            query.clone(),
+            iso.clone(),
+            &answer(vec![(ResolvedVal::Ip(ip), u32::MAX)]),
+            now,
+        );
+        assert!(cache
+            .get(&query, &iso, now + Duration::from_secs(1799))
+            .is_some());
+        assert!(cache
+            .get(&query, &iso, now + Duration::from_secs(1800))
+            .is_none());
+    }
+
+    #[test]
+    fn negative() {
+        let cache = DnsCache::new(DnsCacheConfig::default());
+        let now = Instant::now();
+        let iso = isolation(IsolationToken::no_isolation());
+        let query = DnsQuery::Reverse("192.0.2.1".parse().unwrap());
+
+        cache.insert(
+            query.clone(),
+            iso.clone(),
+            &answer(vec![(ResolvedVal::TransientError, 300)]),
+            now,
+        );
+        assert!(cache.get(&query, &iso, now).is_none());
+
+        cache.insert(
+            query.clone(),
+            iso.clone(),
+            &answer(vec![(ResolvedVal::NontransientError, 3600)]),
+            now,
+        );
+        assert!(cache
+            .get(&query, &iso, now + Duration::from_secs(59))
+            .is_some());
+        assert!(cache
+            .get(&query, &iso, now + Duration::from_secs(60))
+            .is_none());
+    }
+
+    #[test]
+    fn isolation_and_clear() {
+        let cache = DnsCache::new(DnsCacheConfig::default());
+        let now = Instant::now();
+        let iso_a = isolation(IsolationToken::new());
+        let iso_b = isolation(IsolationToken::new());
+        let query = DnsQuery::forward("example.com");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        cache.insert(
+            query.clone(),
+            iso_a.clone(),
+            &answer(vec![(ResolvedVal::Ip(ip), 300)]),
+            now,
+        );
+        assert!(cache.get(&query, &iso_a, now).is_some());
+        assert!(cache.get(&query, &iso_b, now).is_none());
+
+        cache.clear();
+        assert!(cache.get(&query, &iso_a, now).is_none());
+    }
+
+    #[test]
+    fn full() {
+        let mut config = DnsCacheConfig::builder();
+        config.max_entries(2);
+        let cache = DnsCache::new(config.build().unwrap());
+        let now = Instant::now();
+        let iso = isolation(IsolationToken::no_isolation());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for (name, ttl) in [("a.example", 100), ("b.example", 50), ("c.example", 200)] {
+            cache.insert(
+                DnsQuery::forward(name),
+                iso.clone(),
+                &answer(vec![(ResolvedVal::Ip(ip), ttl)]),
+                now,
+            );
+        }
+        // The answer that would have expired first is gone.
+        assert!(cache
+            .get(&DnsQuery::forward("a.example"), &iso, now)
+            .is_some());
+        assert!(cache
+            .get(&DnsQuery::forward("b.example"), &iso, now)
+            .is_none());
+        assert!(cache
+            .get(&DnsQuery::forward("c.example"), &iso, now)
+            .is_some());
+
+        // Turning the cache off empties it.
+        let mut config = DnsCacheConfig::builder();
+        config.enabled(false);
+        cache.reconfigure(&config.build().unwrap());
+        assert!(cache
+            .get(&DnsQuery::forward("a.example"), &iso, now)
+            .is_none());
+    }
+}
@@ -0,0 +1,183 @@
+//! Becoming dormant by ourselves when nobody is using the client.
+//!
+//! Mobile embedders, in particular, don't want a client that isn't being
+//! used to keep building circuits and fetching directory information.  When
+//! the `dormancy.auto_dormant` option is set, [`run_auto_dormancy`] puts the
+//! client into [`DormantMode::Soft`] once it has been idle for long enough.
+//! The next request wakes it up again, as it would after a call to
+//! [`TorClient::set_dormant`](crate::TorClient::set_dormant).
+
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use tor_async_utils::DropNotifyWatchSender;
+use tor_config::MutCfg;
+use tor_proto::circuit::TrafficTotals;
+use tor_rtcompat::Runtime;
+use tracing::info;
+
+use crate::config::DormancyConfig;
+use crate::DormantMode;
+
+/// How often to check whether we have become idle, when we can't predict
+/// it.
+///
+/// This is how long we wait while automatic dormancy is disabled, while we
+/// have open streams, and while we are already dormant.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When a client, or any clone of it, was last asked to use the network.
+#[derive(Debug)]
+pub(crate) struct Activity {
+    /// The time of the most recent request.
+    last_used: Mutex<Instant>,
+}
+
+impl Activity {
+    /// Return a new `Activity`, as if the client was last used at `now`.
+    pub(crate) fn new(now: Instant) -> Self {
+        Activity {
+            last_used: Mutex::new(now),
+        }
+    }
+
+    /// Record that the client was asked to use the network at `now`.
+    pub(crate) fn note_used(&self, now: Instant) {
+        let mut last_used = self.last_used.lock().expect("poisoned lock");
+        *last_used = (*last_used).max(now);
+    }
+
+    /// Return how long it has been, at `now`, since the client was last used.
+    fn idle_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(*self.last_used.lock().expect("poisoned lock"))
+    }
+}
+
+/// Return how much longer we must stay idle before becoming dormant,
+/// according to `config`, if we have been idle for `idle_for`.
+///
+/// Returns `None` if we shouldn't become dormant however long we wait: that
+/// is, if automatic dormancy is disabled, or we have open streams.
+fn time_until_dormant(
+    config: &DormancyConfig,
+    idle_for: Duration,
+    have_open_streams: bool,
+) -> Option<Duration> {
+    if !config.auto_dormant || have_open_streams {
+        return None;
+    }
+    Some(config.idle_timeout.saturating_sub(idle_for))
+}
+
+/// Put the client into [`DormantMode::Soft`] whenever it becomes idle,
+/// according to `config`.
+///
+/// Runs until the client is dropped.  We only hold weak references to its
+/// parts, so that we don't keep it alive ourselves.
+pub(crate) async fn run_auto_dormancy<R: Runtime>(
+    runtime: R,
+    config: Weak<MutCfg<DormancyConfig>>,
+    activity: Weak<Activity>,
+    traffic: Weak<TrafficTotals>,
+    dormant: Weak<Mutex<DropNotifyWatchSender<Option<DormantMode>>>>,
+    circmgr: Weak<tor_circmgr::CircMgr<R>>,
+) {
+    loop {
+        let (Some(config), Some(activity), Some(traffic), Some(dormant), Some(circmgr)) = (
+            config.upgrade(),
+            activity.upgrade(),
+            traffic.upgrade(),
+            dormant.upgrade(),
+            circmgr.upgrade(),
+        ) else {
+            return;
+        };
+        let wait = check_idle(
+            &runtime,
+            &config.get(),
+            &activity,
+            &traffic,
+            &dormant,
+            &circmgr,
+        );
+        // Don't keep the client alive while we sleep.
+        drop((config, activity, traffic, dormant, circmgr));
+        runtime.sleep(wait).await;
+    }
+}
+
+/// Become dormant if we have been idle for long enough, and return how long
+/// to wait before checking again.
+fn check_idle<R: Runtime>(
+    runtime: &R,
+    config: &DormancyConfig,
+    activity: &Activity,
+    traffic: &TrafficTotals,
+    dormant: &Mutex<DropNotifyWatchSender<Option<DormantMode>>>,
+    circmgr: &tor_circmgr::CircMgr<R>,
+) -> Duration {
+    let mut dormant = dormant.lock().expect("dormant lock poisoned");
+    if *dormant.borrow() != Some(DormantMode::Normal) {
+        return CHECK_INTERVAL;
+    }
+    let have_open_streams = !traffic.snapshot().circuits().is_empty();
+    match time_until_dormant(config, activity.idle_for(runtime.now()), have_open_streams) {
+        None => CHECK_INTERVAL,
+        Some(remaining) if !remaining.is_zero() => remaining,
+        Some(_) => {
+            info!("Client has been idle; becoming dormant.");
+            *dormant.borrow_mut() = Some(DormantMode::Soft);
+            circmgr.retire_all_circuits();
+            CHECK_INTERVAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn idle() {
+        let now = Instant::now();
+        let activity = Activity::new(now);
+        activity.note_used(now + Duration::from_secs(30));
+        // Time never goes backwards for us.
+        activity.note_used(now + Duration::from_secs(10));
+        assert_eq!(
+            activity.idle_for(now + Duration::from_secs(100)),
+            Duration::from_secs(70)
+        );
+        assert_eq!(activity.idle_for(now), Duration::ZERO);
+    }
+
+    #[test]
+    fn deadline() {
+        let mins = |n| Duration::from_secs(n * 60);
+        let disabled = DormancyConfig::default();
+        assert_eq!(time_until_dormant(&disabled, mins(60), false), None);
+
+        let mut config = DormancyConfig::builder();
+        config.auto_dormant(true).idle_timeout(mins(5));
+        let config = config.build().unwrap();
+        assert_eq!(time_until_dormant(&config, mins(2), false), Some(mins(3)));
+        assert_eq!(
+            time_until_dormant(&config, mins(7), false),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(time_until_dormant(&config, mins(7), true), None);
+    }
+}
@@ -17,6 +17,8 @@ use tor_error::{ErrorKind, HasKind};
 use crate::TorAddrError;
 #[cfg(feature = "onion-service-client")]
 use tor_hscrypto::pk::HsId;
+#[cfg(feature = "experimental-api")]
+use tor_linkspec::RelayId;
 
 pub use hint::HintableError;
 
@@ -128,6 +130,10 @@ enum ErrorDetail {
     #[error("Memory quota error during startup")]
     MemquotaDuringStartup(#[from] tor_memquota::Error),
 
+    /// Memory quota tracker failed while we were asking it about memory use
+    #[error("Unable to query the memory quota tracker")]
+    MemquotaQuery(#[source] tor_memquota::Error),
+
     /// Error setting up the channel manager
     // TODO: should "chanmgr setup error" be its own type in tor-chanmgr
     #[error("Error setting up the channel manager")]
@@ -199,6 +205,28 @@ enum ErrorDetail {
         cause: tor_hsclient::ConnError,
     },
 
+    /// Failed to build a circuit for custom relay messages
+    #[cfg(feature = "experimental-api")]
+    #[error("Failed to build a raw circuit")]
+    ObtainRawCircuit(#[source] tor_circmgr::Error),
+
+    /// We were asked to build a circuit to a relay that isn't in our directory.
+    #[cfg(feature = "experimental-api")]
+    #[error("No such relay in the directory: {0}")]
+    RelayNotFound(Sensitive<RelayId>),
+
+    /// A protocol error while using a circuit for custom relay messages
+    #[cfg(feature = "experimental-api")]
+    #[error("Protocol error while trying to {action} on a raw circuit")]
+    RawCircuit {
+        /// What we were trying to do.
+        action: &'static str,
+
+        /// The error that occurred.
+        #[source]
+        cause: tor_proto::Error,
+    },
+
     /// Directory manager was unable to bootstrap a working directory.
     #[error("Unable to bootstrap a working directory")]
     DirMgrBootstrap(#[source] tor_dirmgr::Error),
@@ -218,10 +246,29 @@ enum ErrorDetail {
     #[error("Error while trying to access persistent state")]
     StateAccess(#[source] tor_persist::Error),
 
+    /// We couldn't save our persistent state while shutting down.
+    #[error("Unable to save persistent state while shutting down")]
+    ShutdownStateFlush(#[source] tor_circmgr::Error),
+
+    /// We were asked to open a stream, but the client is shutting down.
+    #[error("Cannot {action}: client is shutting down")]
+    ShuttingDown {
+        /// What we were trying to do.
+        action: &'static str,
+    },
+
     /// We asked an exit to do something, and waited too long for an answer.
     #[error("Timed out while waiting for answer from exit")]
     ExitTimeout,
 
+    /// We couldn't build a circuit during a self-test.
+    #[error("Unable to build a self-test circuit")]
+    SelfTestCircuit(#[source] tor_circmgr::Error),
+
+    /// We couldn't download from the self-test endpoint.
+    #[error("Unable to download from the self-test endpoint")]
+    SelfTestDownload(#[source] Arc<std::io::Error>),
+
     /// Onion services are not compiled in, but we were asked to connect to one.
     #[error("Rejecting .onion address; feature onion-service-client not compiled in")]
     OnionAddressNotSupported,
@@ -267,6 +314,11 @@ enum ErrorDetail {
     #[error("Unable to change configuration")]
     Reconfigure(#[from] tor_config::ReconfigureError),
 
+    /// Our bridges failed, and we couldn't obtain new ones.
+    #[cfg(feature = "moat")]
+    #[error("Unable to obtain new bridges")]
+    ObtainBridges(#[source] tor_moat::Error),
+
     /// Problem creating or launching a pluggable transport.
     #[cfg(feature="pt-client")]
     #[error("Problem with a pluggable transport")]
@@ -276,6 +328,11 @@ enum ErrorDetail {
     #[error("Problem accessing filesystem")]
     FsMistrust(#[from] fs_mistrust::Error),
 
+    /// Unable to create an asynchronous runtime for a blocking client
+    #[cfg(feature = "blocking")]
+    #[error("Unable to create an asynchronous runtime")]
+    RuntimeCreation(#[source] Arc<std::io::Error>),
+
     /// Unable to spawn task
     #[error("Unable to spawn {spawning}")]
     Spawn {
@@ -317,6 +374,14 @@ enum ErrorDetail {
         action: &'static str
     },
 
+    /// Attempted to use a `TorClient` for something that requires it to keep
+    /// its state in the state directory.
+    #[error("Cannot {action} without keeping state in the state directory")]
+    StateDirectoryRequired {
+        /// What we were trying to do that required the state directory.
+        action: &'static str
+    },
+
     /// Encountered a malformed client specifier.
     #[error("Bad client specifier")]
     BadClientSpecifier(#[from] tor_keymgr::ArtiPathSyntaxError),
@@ -408,6 +473,7 @@ impl tor_error::HasKind for ErrorDetail {
             E::BootstrapRequired { .. } => EK::BootstrapRequired,
             E::MemquotaSetup(e) => e.kind(),
             E::MemquotaDuringStartup(e) => e.kind(),
+            E::MemquotaQuery(e) => e.kind(),
             E::GuardMgrSetup(e) => e.kind(),
             #[cfg(all(
                 feature = "vanguards",
@@ -424,13 +490,30 @@ impl tor_error::HasKind for ErrorDetail {
             #[cfg(feature = "onion-service-service")]
             E::OnionServiceSetup(e) => e.kind(),
             E::DirMgrBootstrap(e) => e.kind(),
+            #[cfg(feature = "moat")]
+            E::ObtainBridges(e) => e.kind(),
             #[cfg(feature = "pt-client")]
             E::PluggableTransport(e) => e.kind(),
             E::StreamFailed { cause, .. } => cause.kind(),
+            E::ShutdownStateFlush(e) => e.kind(),
+            E::ShuttingDown { .. } => EK::ArtiShuttingDown,
+            E::SelfTestCircuit(e) => e.kind(),
+            E::SelfTestDownload(e) => e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<tor_proto::Error>())
+                .map_or(EK::RemoteStreamError, |e| e.kind()),
+            #[cfg(feature = "experimental-api")]
+            E::ObtainRawCircuit(e) => e.kind(),
+            #[cfg(feature = "experimental-api")]
+            E::RelayNotFound(_) => EK::NoPath,
+            #[cfg(feature = "experimental-api")]
+            E::RawCircuit { cause, .. } => cause.kind(),
             E::StateAccess(e) => e.kind(),
             E::Configuration(e) => e.kind(),
             E::Reconfigure(e) => e.kind(),
             E::Spawn { cause, .. } => cause.kind(),
+            #[cfg(feature = "blocking")]
+            E::RuntimeCreation(_) => EK::LocalResourceExhausted,
             E::OnionAddressNotSupported => EK::FeatureDisabled,
             E::OnionAddressResolveRequest => EK::NotImplemented,
             #[cfg(feature = "onion-service-client")]
@@ -446,6 +529,7 @@ impl tor_error::HasKind for ErrorDetail {
             E::NoDir { error, .. } => error.kind(),
             E::Keystore(e) => e.kind(),
             E::KeystoreRequired { .. } => EK::InvalidConfig,
+            E::StateDirectoryRequired { .. } => EK::BadApiUsage,
             E::BadClientSpecifier(_) => EK::InvalidConfig,
             E::FsMistrust(_) => EK::FsPermissions,
             E::MissingProtocol(_) => EK::SoftwareDeprecated,
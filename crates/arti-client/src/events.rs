@@ -0,0 +1,121 @@
+//! A single stream of the events that a [`TorClient`](crate::TorClient)
+//! reports.
+//!
+//! [`TorClient::events`](crate::TorClient::events) merges the client's
+//! bootstrap status, the circuits it builds, its primary guards, and its
+//! directory updates into one [`TorEvents`] stream.  This is meant for
+//! applications that want to show their users what Arti is doing.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use educe::Educe;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use tor_basic_utils::skip_fmt;
+use tor_circmgr::CircEvent;
+use tor_linkspec::RelayIds;
+use tor_netdir::DirEvent;
+
+use crate::status::BootstrapStatus;
+
+/// Something that happened to a [`TorClient`](crate::TorClient).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum TorEvent {
+    /// The client's bootstrap status changed.
+    Bootstrap(BootstrapStatus),
+    /// The client built a circuit, or failed to.
+    Circuit(CircEvent),
+    /// The client's primary guards changed.
+    ///
+    /// Holds the identities of the new primary guards, most preferred first.
+    PrimaryGuards(Vec<RelayIds>),
+    /// The client received new directory information.
+    Directory(DirEvent),
+}
+
+/// A [`Stream`] of [`TorEvent`]s.
+///
+/// Returned by [`TorClient::events`](crate::TorClient::events).
+///
+/// This stream is lossy: if you don't read from it quickly enough, you might
+/// only get the most recent bootstrap status and primary guards, and some
+/// circuit events might be dropped.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct TorEvents {
+    /// The merged stream that implements this stream.
+    #[educe(Debug(method = "skip_fmt"))]
+    inner: BoxStream<'static, TorEvent>,
+}
+
+impl TorEvents {
+    /// Return a new `TorEvents` that yields the events from every one of the
+    /// provided streams, as they arrive.
+    pub(crate) fn merge(
+        bootstrap: impl Stream<Item = BootstrapStatus> + Send + 'static,
+        circuits: impl Stream<Item = CircEvent> + Send + 'static,
+        guards: impl Stream<Item = Vec<RelayIds>> + Send + 'static,
+        dir: impl Stream<Item = DirEvent> + Send + 'static,
+    ) -> Self {
+        let inner = futures::stream::select_all([
+            bootstrap.map(TorEvent::Bootstrap).boxed(),
+            circuits.map(TorEvent::Circuit).boxed(),
+            guards.map(TorEvent::PrimaryGuards).boxed(),
+            dir.map(TorEvent::Directory).boxed(),
+        ])
+        .boxed();
+        TorEvents { inner }
+    }
+}
+
+impl Stream for TorEvents {
+    type Item = TorEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn merge() {
+        let events = TorEvents::merge(
+            stream::iter([BootstrapStatus::default()]),
+            stream::iter([CircEvent::Failed {
+                error: tor_circmgr::Error::CircCanceled,
+            }]),
+            stream::iter([vec![], vec![RelayIds::empty()]]),
+            stream::iter([DirEvent::NewConsensus]),
+        );
+        let events: Vec<TorEvent> = futures::executor::block_on(events.collect());
+
+        assert_eq!(events.len(), 5);
+        let count = |f: fn(&TorEvent) -> bool| events.iter().filter(|e| f(e)).count();
+        assert_eq!(count(|e| matches!(e, TorEvent::Bootstrap(_))), 1);
+        assert_eq!(count(|e| matches!(e, TorEvent::Circuit(_))), 1);
+        assert_eq!(count(|e| matches!(e, TorEvent::PrimaryGuards(_))), 2);
+        assert_eq!(
+            count(|e| matches!(e, TorEvent::Directory(DirEvent::NewConsensus))),
+            1
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Making HTTP requests over Tor with `hyper`.
+//!
+//! An [`ArtiHttpConnector`] is a connector for `hyper-util`'s
+//! [`Client`](hyper_util::client::legacy::Client): every connection that
+//! the client opens goes over Tor, through a [`TorClient`].  `https` URIs
+//! need a TLS implementation, which you choose by implementing
+//! [`HttpTlsConnector`]; with the `hyper-native-tls` feature, there is one
+//! for `tokio-native-tls`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # #[cfg(feature = "hyper-native-tls")]
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use arti_client::http_connector::ArtiHttpConnector;
+//! use arti_client::{TorClient, TorClientConfig};
+//! use hyper_util::client::legacy::Client;
+//! use hyper_util::rt::TokioExecutor;
+//! use tokio_native_tls::{native_tls, TlsConnector};
+//!
+//! let tor_client = TorClient::create_bootstrapped(TorClientConfig::default()).await?;
+//! let tls = TlsConnector::from(native_tls::TlsConnector::new()?);
+//!
+//! let connector = ArtiHttpConnector::with_tls(tor_client, tls);
+//! let http = Client::builder(TokioExecutor::new()).build::<_, String>(connector);
+//! let response = http.get("https://check.torproject.org/".parse()?).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use tokio_crate::io::{AsyncRead, AsyncWrite};
+use tor_error::{ErrorKind, HasKind};
+use tor_rtcompat::Runtime;
+
+use crate::{DataStream, StreamPrefs, TorClient};
+
+/// An error from an [`ArtiHttpConnector`].
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ArtiHttpConnectorError {
+    /// The URI had no host.
+    #[error("Missing hostname in {0}")]
+    MissingHostname(Uri),
+
+    /// The URI's scheme was not `http` or `https`.
+    #[error("Unsupported URI scheme in {0}")]
+    UnsupportedUriScheme(Uri),
+
+    /// The URI was for `https`, but the connector has no TLS implementation.
+    #[error("No TLS implementation available for {0}")]
+    TlsUnavailable(Uri),
+
+    /// We couldn't connect over Tor.
+    #[error("Tor connection failed")]
+    Arti(#[from] crate::Error),
+
+    /// We couldn't negotiate TLS.
+    #[error("TLS negotiation failed")]
+    Tls(#[source] Arc<io::Error>),
+}
+
+impl HasKind for ArtiHttpConnectorError {
+    fn kind(&self) -> ErrorKind {
+        use ArtiHttpConnectorError as E;
+        use ErrorKind as EK;
+        match self {
+            E::MissingHostname(_) => EK::BadApiUsage,
+            E::UnsupportedUriScheme(_) => EK::NotImplemented,
+            E::TlsUnavailable(_) => EK::FeatureDisabled,
+            E::Arti(e) => e.kind(),
+            E::Tls(_) => EK::Other,
+        }
+    }
+}
+
+/// A TLS implementation that an [`ArtiHttpConnector`] can use for `https`
+/// URIs.
+///
+/// Implementations must validate the server's certificate for `host`.
+#[async_trait]
+pub trait HttpTlsConnector: Clone + Send + Sync + 'static {
+    /// The type of the TLS streams that this connector returns.
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// Negotiate TLS over `stream`, with the server `host`.
+    async fn connect(&self, host: &str, stream: DataStream) -> io::Result<Self::Stream>;
+}
+
+/// An [`HttpTlsConnector`] that doesn't support TLS at all.
+///
+/// An [`ArtiHttpConnector`] that uses this can only be used for `http` URIs.
+#[derive(Clone, Copy, Debug, Default)]
+#[non_exhaustive]
+pub struct NoTls;
+
+#[async_trait]
+impl HttpTlsConnector for NoTls {
+    type Stream = DataStream;
+
+    async fn connect(&self, _host: &str, _stream: DataStream) -> io::Result<Self::Stream> {
+        // We check for this before we open the stream.
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS is not available",
+        ))
+    }
+}
+
+#[cfg(feature = "hyper-native-tls")]
+#[async_trait]
+impl HttpTlsConnector for tokio_native_tls::TlsConnector {
+    type Stream = tokio_native_tls::TlsStream<DataStream>;
+
+    async fn connect(&self, host: &str, stream: DataStream) -> io::Result<Self::Stream> {
+        tokio_native_tls::TlsConnector::connect(self, host, stream)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+/// A connector for `hyper-util`'s HTTP client, that makes its connections
+/// over Tor.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone)]
+pub struct ArtiHttpConnector<R: Runtime, T = NoTls> {
+    /// The client that we use to connect.
+    client: TorClient<R>,
+    /// The preferences for the connections that we make.
+    prefs: StreamPrefs,
+    /// The TLS implementation for `https` URIs, if there is one.
+    tls: Option<T>,
+}
+
+impl<R: Runtime> ArtiHttpConnector<R, NoTls> {
+    /// Return a new connector that uses `client`, for `http` URIs only.
+    pub fn new(client: TorClient<R>) -> Self {
+        ArtiHttpConnector {
+            client,
+            prefs: StreamPrefs::default(),
+            tls: None,
+        }
+    }
+}
+
+impl<R: Runtime, T: HttpTlsConnector> ArtiHttpConnector<R, T> {
+    /// Return a new connector that uses `client`, and `tls` for `https` URIs.
+    pub fn with_tls(client: TorClient<R>, tls: T) -> Self {
+        ArtiHttpConnector {
+            client,
+            prefs: StreamPrefs::default(),
+            tls: Some(tls),
+        }
+    }
+
+    /// Use `prefs` for the connections that this connector makes.
+    pub fn stream_prefs(mut self, prefs: StreamPrefs) -> Self {
+        self.prefs = prefs;
+        self
+    }
+
+    /// Open a connection for `uri`.
+    async fn connect(self, uri: Uri) -> Result<ArtiHttpStream<T::Stream>, ArtiHttpConnectorError> {
+        let (host, port, https) = uri_target(&uri)?;
+        let tls = match (https, self.tls) {
+            (false, _) => None,
+            (true, Some(tls)) => Some(tls),
+            (true, None) => return Err(ArtiHttpConnectorError::TlsUnavailable(uri)),
+        };
+        let stream = self
+            .client
+            .connect_with_prefs((host.as_str(), port), &self.prefs)
+            .await?;
+        let inner = match tls {
+            None => StreamInner::Plain(TokioIo::new(stream)),
+            Some(tls) => StreamInner::Tls(TokioIo::new(
+                tls.connect(&host, stream)
+                    .await
+                    .map_err(|e| ArtiHttpConnectorError::Tls(Arc::new(e)))?,
+            )),
+        };
+        Ok(ArtiHttpStream { inner })
+    }
+}
+
+/// Return the host and port that we should connect to for `uri`, and
+/// whether we should use TLS.
+fn uri_target(uri: &Uri) -> Result<(String, u16, bool), ArtiHttpConnectorError> {
+    let https = match uri.scheme_str() {
+        Some("http") => false,
+        Some("https") => true,
+        _ => return Err(ArtiHttpConnectorError::UnsupportedUriScheme(uri.clone())),
+    };
+    let host = uri
+        .host()
+        .filter(|h| !h.is_empty())
+        .ok_or_else(|| ArtiHttpConnectorError::MissingHostname(uri.clone()))?;
+    // IPv6 addresses in URIs are in brackets.
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    Ok((host.to_owned(), port, https))
+}
+
+impl<R: Runtime, T: HttpTlsConnector> tower_service::Service<Uri> for ArtiHttpConnector<R, T> {
+    type Response = ArtiHttpStream<T::Stream>;
+    type Error = ArtiHttpConnectorError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
+
+/// A connection from an [`ArtiHttpConnector`].
+pub struct ArtiHttpStream<S> {
+    /// The connection itself.
+    inner: StreamInner<S>,
+}
+
+/// The connection in an [`ArtiHttpStream`].
+enum StreamInner<S> {
+    /// A connection without TLS.
+    Plain(TokioIo<DataStream>),
+    /// A connection with TLS.
+    Tls(TokioIo<S>),
+}
+
+impl<S> Connection for ArtiHttpStream<S> {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Read for ArtiHttpStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            StreamInner::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            StreamInner::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Write for ArtiHttpStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            StreamInner::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            StreamInner::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            StreamInner::Plain(s) => Pin::new(s).poll_flush(cx),
+            StreamInner::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            StreamInner::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            StreamInner::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    fn target(uri: &str) -> Result<(String, u16, bool), ArtiHttpConnectorError> {
+        uri_target(&uri.parse().unwrap())
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(
+            target("http://example.com/index.html").unwrap(),
+            ("example.com".into(), 80, false)
+        );
+        assert_eq!(
+            target("https://example.com:8443").unwrap(),
+            ("example.com".into(), 8443, true)
+        );
+        assert_eq!(
+            target("https://[2001:db8::1]/").unwrap(),
+            ("2001:db8::1".into(), 443, true)
+        );
+        assert!(matches!(
+            target("ftp://example.com/"),
+            Err(ArtiHttpConnectorError::UnsupportedUriScheme(_))
+        ));
+        assert!(matches!(
+            target("/just/a/path"),
+            Err(ArtiHttpConnectorError::UnsupportedUriScheme(_))
+        ));
+    }
+}
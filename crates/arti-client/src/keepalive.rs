@@ -0,0 +1,60 @@
+//! Keeping idle streams alive with padding.
+//!
+//! Relays may close channels, and exits may close connections, that carry
+//! no traffic for a long time.  That breaks long-lived connections that are
+//! quiet most of the time, like IMAP IDLE or a messaging session.  When a
+//! stream is opened with [`StreamPrefs::keepalive`](crate::StreamPrefs::keepalive),
+//! [`keep_stream_alive`] sends a `DROP` cell to the last hop of its circuit
+//! whenever the stream has been idle for a whole interval.  The last hop
+//! discards the cell, so the stream's destination never sees it.
+
+use std::sync::Weak;
+use std::time::Duration;
+
+use tor_error::debug_report;
+use tor_proto::circuit::{ClientCirc, StreamStatsHandle};
+use tor_rtcompat::Runtime;
+
+/// Send keep-alive padding on `circ` whenever the stream that `stream`
+/// watches has been idle for `interval`.
+///
+/// Runs until the stream is closed, or until we can't send padding on the
+/// circuit.  We only hold a weak reference to the circuit, so that we don't
+/// keep it alive ourselves.
+pub(crate) async fn keep_stream_alive<R: Runtime>(
+    runtime: R,
+    circ: Weak<ClientCirc>,
+    stream: StreamStatsHandle,
+    interval: Duration,
+) {
+    let Some(mut before) = stream.stats().map(|s| s.traffic()) else {
+        return;
+    };
+    loop {
+        runtime.sleep(interval).await;
+        let Some(after) = stream.stats().map(|s| s.traffic()) else {
+            return;
+        };
+        // Our own padding doesn't count as traffic on the stream, so an
+        // unchanged count means that the stream was idle all along.
+        if after != before {
+            before = after;
+            continue;
+        }
+
+        let Some(circ) = circ.upgrade() else {
+            return;
+        };
+        if circ.is_closing() {
+            return;
+        }
+        let sent = match circ.last_hop_num() {
+            Ok(hop) => circ.send_drop(hop).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            debug_report!(e, "Couldn't send keep-alive padding; giving up");
+            return;
+        }
+    }
+}
@@ -47,26 +47,63 @@
 #![cfg_attr(not(all(feature = "full", feature = "experimental")), allow(unused))]
 
 mod address;
+mod address_map;
+mod audit;
+#[cfg(all(
+    feature = "blocking",
+    any(feature = "native-tls", feature = "rustls"),
+    any(feature = "async-std", feature = "tokio")
+))]
+pub mod blocking;
 mod builder;
 mod client;
+mod destination;
+mod dns;
+mod dormancy;
+mod events;
+#[cfg(feature = "hyper")]
+pub mod http_connector;
+mod keepalive;
+mod network;
 mod protostatus;
+#[cfg(feature = "experimental-api")]
+mod raw;
 mod release_date;
+mod retry;
 #[cfg(feature = "rpc")]
 pub mod rpc;
+mod selftest;
+mod shutdown;
+mod statemgr;
 mod util;
 
 pub mod config;
 pub mod status;
 
 pub use address::{DangerouslyIntoTorAddr, IntoTorAddr, TorAddr, TorAddrError};
+pub use audit::{StreamAuditRecord, StreamAuditor};
 pub use builder::{TorClientBuilder, MAX_LOCAL_RESOURCE_TIMEOUT};
 pub use client::{BootstrapBehavior, DormantMode, InertTorClient, StreamPrefs, TorClient};
 pub use config::TorClientConfig;
-
+pub use destination::PortClass;
+pub use dns::{DnsAnswer, DnsRecord, DnsValue};
+pub use events::{TorEvent, TorEvents};
+pub use network::{NetworkSnapshot, RelayFlag, RelayInfo, RelayQuery};
+pub use retry::RetryPolicy;
+pub use selftest::{CircuitTest, DownloadTest, SelfTestConfig, SelfTestReport};
+
+pub use tor_chanmgr::BandwidthLimit;
 pub use tor_circmgr::isolation;
 pub use tor_circmgr::IsolationToken;
-pub use tor_error::{ErrorKind, HasKind};
+pub use tor_circmgr::{CircEvent, CircEvents, PathPolicy, PathPosition};
+pub use tor_error::{ErrorKind, HasKind, RetryHint};
+pub use tor_linkspec::{RelayId, RelayIds};
+pub use tor_memquota::ReclaimEvent;
+pub use tor_proto::circuit::{AggregateTrafficStats, StreamStats, TrafficCounts, TrafficStats};
 pub use tor_proto::stream::{DataReader, DataStream, DataWriter};
+#[cfg(feature = "experimental-udp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "experimental-udp")))]
+pub use tor_proto::stream::{UdpStream, UdpStreamSender};
 
 mod err;
 pub use err::{Error, ErrorHint, HintableError};
@@ -80,13 +117,16 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[cfg(feature = "experimental-api")]
 pub use builder::DirProviderBuilder;
 
+#[cfg(feature = "experimental-api")]
+pub use raw::RawCircuit;
+
 #[cfg(all(feature = "onion-service-client", feature = "experimental-api"))]
 #[cfg_attr(
     docsrs,
     doc(cfg(all(feature = "onion-service-client", feature = "experimental-api")))
 )]
 pub use {
-    tor_hscrypto::pk::{HsClientDescEncKey, HsId},
+    tor_hscrypto::pk::{HsClientDescEncKey, HsClientDescEncSecretKey, HsId},
     tor_keymgr::KeystoreSelector,
 };
 
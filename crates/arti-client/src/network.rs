@@ -0,0 +1,344 @@
+//! Read-only views of the network directory.
+//!
+//! [`TorClient::network_snapshot`](crate::TorClient::network_snapshot)
+//! returns a [`NetworkSnapshot`]: the relays that the client knows about,
+//! and how long its consensus is valid for.  This is meant for monitoring
+//! tools, and for applications that choose relays themselves (for
+//! [`StreamPrefs::exit_relay`](crate::StreamPrefs::exit_relay), say), so that
+//! they don't need to use `tor-netdir` directly.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tor_linkspec::{HasAddrs as _, RelayId, RelayIds};
+use tor_netdir::{NetDir, Relay};
+use tor_netdoc::doc::netstatus::{RelayFlags, RelayWeight};
+use tor_netdoc::types::policy::PortPolicy;
+
+#[cfg(feature = "geoip")]
+use tor_geoip::{CountryCode, HasCountryCode as _};
+
+/// A flag that the directory authorities can give a relay.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum RelayFlag {
+    /// The relay is a directory authority.
+    Authority,
+    /// The relay is believed to be a bad exit, and is never used as one.
+    BadExit,
+    /// The relay is suitable for use as an exit.
+    Exit,
+    /// The relay is fast enough for circuits that need bandwidth.
+    Fast,
+    /// The relay is suitable for use as a guard.
+    Guard,
+    /// The relay stores onion service descriptors.
+    HsDir,
+    /// The relay may only be used in the middle of a circuit.
+    MiddleOnly,
+    /// The relay is suitable for long-lived circuits.
+    Stable,
+    /// The relay was running when the authorities last checked.
+    Running,
+    /// The relay is believed to be valid.
+    Valid,
+    /// The relay is a directory cache.
+    V2Dir,
+}
+
+impl RelayFlag {
+    /// Return the [`RelayFlags`] bit for this flag.
+    fn bit(self) -> RelayFlags {
+        match self {
+            RelayFlag::Authority => RelayFlags::AUTHORITY,
+            RelayFlag::BadExit => RelayFlags::BAD_EXIT,
+            RelayFlag::Exit => RelayFlags::EXIT,
+            RelayFlag::Fast => RelayFlags::FAST,
+            RelayFlag::Guard => RelayFlags::GUARD,
+            RelayFlag::HsDir => RelayFlags::HSDIR,
+            RelayFlag::MiddleOnly => RelayFlags::MIDDLE_ONLY,
+            RelayFlag::Stable => RelayFlags::STABLE,
+            RelayFlag::Running => RelayFlags::RUNNING,
+            RelayFlag::Valid => RelayFlags::VALID,
+            RelayFlag::V2Dir => RelayFlags::V2DIR,
+        }
+    }
+}
+
+/// What the directory says about one relay.
+///
+/// This is a copy: it doesn't change if the client gets a new directory.
+#[derive(Clone, Debug)]
+pub struct RelayInfo {
+    /// The relay's identities.
+    ids: RelayIds,
+    /// The relay's nickname.
+    nickname: String,
+    /// The addresses at which the relay accepts connections.
+    addrs: Vec<SocketAddr>,
+    /// The relay's flags.
+    flags: RelayFlags,
+    /// The relay's weight in the consensus.
+    weight: RelayWeight,
+    /// The relay's IPv4 exit policy, or an empty one if it is a bad exit.
+    ipv4_policy: Arc<PortPolicy>,
+    /// The relay's IPv6 exit policy, or an empty one if it is a bad exit.
+    ipv6_policy: Arc<PortPolicy>,
+    /// The country that the relay is in, if we know it.
+    #[cfg(feature = "geoip")]
+    country_code: Option<CountryCode>,
+}
+
+impl RelayInfo {
+    /// Copy the information about `relay`.
+    fn from_relay(relay: &Relay<'_>) -> Self {
+        let details = relay.low_level_details();
+        RelayInfo {
+            ids: RelayIds::from_relay_ids(relay),
+            nickname: details.nickname().to_owned(),
+            addrs: relay.addrs().to_vec(),
+            flags: details.flags(),
+            weight: details.consensus_weight(),
+            ipv4_policy: details.ipv4_policy(),
+            ipv6_policy: details.ipv6_policy(),
+            #[cfg(feature = "geoip")]
+            country_code: relay.country_code(),
+        }
+    }
+
+    /// Return the relay's identities.
+    ///
+    /// Use one of them with [`StreamPrefs::exit_relay`](crate::StreamPrefs::exit_relay)
+    /// to make connections through this relay.
+    pub fn ids(&self) -> &RelayIds {
+        &self.ids
+    }
+
+    /// Return the relay's nickname.
+    ///
+    /// Nicknames are not unique, and can't be used to identify a relay.
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    /// Return the addresses at which the relay accepts connections.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Return true if the relay has `flag`.
+    pub fn has_flag(&self, flag: RelayFlag) -> bool {
+        self.flags.contains(flag.bit())
+    }
+
+    /// Return the relay's weight in the consensus.
+    ///
+    /// This is roughly proportional to the relay's bandwidth, and so to how
+    /// often clients use it.
+    pub fn consensus_weight(&self) -> u32 {
+        match self.weight {
+            RelayWeight::Unmeasured(w) | RelayWeight::Measured(w) => w,
+            _ => 0,
+        }
+    }
+
+    /// Return true if the relay's consensus weight is based on a measurement
+    /// of its bandwidth.
+    pub fn is_weight_measured(&self) -> bool {
+        self.weight.is_measured()
+    }
+
+    /// Return true if the relay allows exiting to `port`, over IPv4 or IPv6.
+    ///
+    /// This is always false for relays with the `BadExit` flag.
+    pub fn allows_exit_port(&self, port: u16) -> bool {
+        self.ipv4_policy.allows_port(port) || self.ipv6_policy.allows_port(port)
+    }
+
+    /// Return the country that the relay is in, if we know it.
+    #[cfg(feature = "geoip")]
+    pub fn country_code(&self) -> Option<CountryCode> {
+        self.country_code
+    }
+}
+
+/// A description of the relays to list from a [`NetworkSnapshot`].
+///
+/// By default, every relay matches.  Each setting removes the relays that
+/// don't match it.
+///
+/// # Example
+///
+/// ```
+/// use arti_client::{RelayFlag, RelayQuery};
+///
+/// // Stable exits that allow SSH.
+/// let mut query = RelayQuery::new();
+/// query.flag(RelayFlag::Exit).flag(RelayFlag::Stable).exit_port(22);
+/// ```
+#[derive(Clone, Debug)]
+pub struct RelayQuery {
+    /// Flags that a relay must have.
+    flags: RelayFlags,
+    /// Ports that a relay must allow exiting to.
+    exit_ports: Vec<u16>,
+    /// The country that a relay must be in, if any.
+    #[cfg(feature = "geoip")]
+    country_code: Option<CountryCode>,
+}
+
+impl Default for RelayQuery {
+    fn default() -> Self {
+        RelayQuery {
+            flags: RelayFlags::empty(),
+            exit_ports: Vec::new(),
+            #[cfg(feature = "geoip")]
+            country_code: None,
+        }
+    }
+}
+
+impl RelayQuery {
+    /// Return a new `RelayQuery` that matches every relay.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match relays that have `flag`.
+    pub fn flag(&mut self, flag: RelayFlag) -> &mut Self {
+        self.flags |= flag.bit();
+        self
+    }
+
+    /// Only match relays that allow exiting to `port`.
+    pub fn exit_port(&mut self, port: u16) -> &mut Self {
+        self.exit_ports.push(port);
+        self
+    }
+
+    /// Only match relays that we know to be in the country `country_code`.
+    #[cfg(feature = "geoip")]
+    pub fn country(&mut self, country_code: CountryCode) -> &mut Self {
+        self.country_code = Some(country_code);
+        self
+    }
+
+    /// Return true if `relay` matches this query.
+    fn matches(&self, relay: &RelayInfo) -> bool {
+        #[cfg(feature = "geoip")]
+        if self.country_code.is_some() && relay.country_code != self.country_code {
+            return false;
+        }
+        relay.flags.contains(self.flags)
+            && self.exit_ports.iter().all(|p| relay.allows_exit_port(*p))
+    }
+}
+
+/// A snapshot of the network directory that a client is using.
+///
+/// Returned by [`TorClient::network_snapshot`](crate::TorClient::network_snapshot).
+/// It doesn't change when the client gets a new directory: ask for a new
+/// snapshot for that.
+///
+/// Only relays that the client could use are listed: that is, relays for
+/// which it has a microdescriptor as well as a consensus entry.
+#[derive(Clone)]
+pub struct NetworkSnapshot {
+    /// The directory itself.
+    netdir: Arc<NetDir>,
+}
+
+impl std::fmt::Debug for NetworkSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NetworkSnapshot")
+            .field("valid_after", &self.valid_after())
+            .finish_non_exhaustive()
+    }
+}
+
+impl NetworkSnapshot {
+    /// Wrap `netdir`.
+    pub(crate) fn new(netdir: Arc<NetDir>) -> Self {
+        NetworkSnapshot { netdir }
+    }
+
+    /// Return the time at which the consensus became valid.
+    pub fn valid_after(&self) -> SystemTime {
+        self.netdir.lifetime().valid_after()
+    }
+
+    /// Return the time at which the authorities expect to publish the next
+    /// consensus.
+    pub fn fresh_until(&self) -> SystemTime {
+        self.netdir.lifetime().fresh_until()
+    }
+
+    /// Return the time after which the consensus may no longer be used.
+    pub fn valid_until(&self) -> SystemTime {
+        self.netdir.lifetime().valid_until()
+    }
+
+    /// Return every relay in the directory.
+    pub fn relays(&self) -> impl Iterator<Item = RelayInfo> + '_ {
+        self.netdir.relays().map(|r| RelayInfo::from_relay(&r))
+    }
+
+    /// Return every relay in the directory that matches `query`.
+    pub fn relays_matching<'a>(
+        &'a self,
+        query: &'a RelayQuery,
+    ) -> impl Iterator<Item = RelayInfo> + 'a {
+        self.relays().filter(|r| query.matches(r))
+    }
+
+    /// Return the relay with the identity `id`, if there is one.
+    pub fn relay(&self, id: &RelayId) -> Option<RelayInfo> {
+        self.netdir.by_id(id).map(|r| RelayInfo::from_relay(&r))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_linkspec::HasRelayIds as _;
+    use tor_netdir::testnet;
+
+    #[test]
+    fn query() {
+        let snapshot = NetworkSnapshot::new(Arc::new(
+            testnet::construct_netdir().unwrap_if_sufficient().unwrap(),
+        ));
+        assert!(snapshot.valid_after() < snapshot.valid_until());
+        let all: Vec<_> = snapshot.relays().collect();
+        assert!(!all.is_empty());
+
+        let mut query = RelayQuery::new();
+        query.flag(RelayFlag::Exit).exit_port(443);
+        let exits: Vec<_> = snapshot.relays_matching(&query).collect();
+        assert!(!exits.is_empty());
+        assert!(exits.len() < all.len());
+        assert!(exits
+            .iter()
+            .all(|r| r.has_flag(RelayFlag::Exit) && r.allows_exit_port(443)));
+
+        let first = &all[0];
+        let id = first.ids().ed_identity().unwrap();
+        let found = snapshot.relay(&(*id).into()).unwrap();
+        assert_eq!(found.nickname(), first.nickname());
+        assert_eq!(found.addrs(), first.addrs());
+    }
+}
@@ -0,0 +1,203 @@
+//! Circuits for custom protocols.
+//!
+//! [`TorClient::launch_raw_circuit`](crate::TorClient::launch_raw_circuit)
+//! builds a circuit that nothing else uses, and returns it as a
+//! [`RawCircuit`], on which the caller can send and receive relay messages of
+//! its own choosing.  This is meant for tools that speak protocols that Arti
+//! doesn't implement, like bandwidth scanners.
+//!
+//! Note that it is quite possible to use this API to violate the Tor
+//! protocol, and to make a relay close the circuit.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use educe::Educe;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt as _};
+use tor_async_utils::mpsc_channel_no_memquota;
+use tor_basic_utils::skip_fmt;
+use tor_cell::relaycell::msg::AnyRelayMsg;
+use tor_proto::circuit::{ClientCirc, MetaCellDisposition, MsgHandler};
+use tor_proto::HopNum;
+
+use crate::err::ErrorDetail;
+
+/// How many incoming messages we queue for a [`RawCircuit`].
+///
+/// If the caller doesn't read them quickly enough, we close the circuit.
+const INCOMING_QUEUE_LEN: usize = 128;
+
+/// A circuit for sending and receiving custom relay messages.
+///
+/// Returned by
+/// [`TorClient::launch_raw_circuit`](crate::TorClient::launch_raw_circuit).
+///
+/// Messages are sent to, and received from, the last hop of the circuit.
+/// Messages that Arti handles itself, like SENDME and DESTROY, are never
+/// received here.  Any other message that the last hop sends without a
+/// stream ID is delivered by this type's [`Stream`] implementation, and by
+/// [`recv`](RawCircuit::recv).
+///
+/// The circuit closes when this object and every clone of the
+/// [`ClientCirc`] it contains are dropped.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct RawCircuit {
+    /// The circuit itself.
+    circ: Arc<ClientCirc>,
+    /// The last hop of the circuit, to which we send our messages.
+    hop: HopNum,
+    /// The messages that the last hop sent us.
+    #[educe(Debug(method = "skip_fmt"))]
+    incoming: mpsc::Receiver<AnyRelayMsg>,
+}
+
+/// A [`MsgHandler`] that hands every message to a [`RawCircuit`].
+struct Forwarder {
+    /// The sender for the `RawCircuit`'s incoming messages.
+    tx: mpsc::Sender<AnyRelayMsg>,
+}
+
+impl MsgHandler for Forwarder {
+    fn handle_msg(&mut self, msg: AnyRelayMsg) -> tor_proto::Result<MetaCellDisposition> {
+        match self.tx.try_send(msg) {
+            Ok(()) => Ok(MetaCellDisposition::Consumed),
+            // Nobody is listening any more.
+            Err(e) if e.is_disconnected() => Ok(MetaCellDisposition::CloseCirc),
+            Err(_) => Err(tor_proto::Error::CircProto(
+                "Too many unread messages on raw circuit".into(),
+            )),
+        }
+    }
+}
+
+impl RawCircuit {
+    /// Start handling incoming messages on `circ`, and wrap it.
+    pub(crate) async fn new(circ: Arc<ClientCirc>) -> Result<Self, ErrorDetail> {
+        let proto_err = |cause| ErrorDetail::RawCircuit {
+            action: "start a conversation",
+            cause,
+        };
+        let hop = circ.last_hop_num().map_err(proto_err)?;
+        let (tx, incoming) = mpsc_channel_no_memquota(INCOMING_QUEUE_LEN);
+        // The handler stays installed after we drop the conversation.
+        let conversation = circ
+            .start_conversation(None, Forwarder { tx }, hop)
+            .await
+            .map_err(proto_err)?;
+        drop(conversation);
+        Ok(RawCircuit {
+            circ,
+            hop,
+            incoming,
+        })
+    }
+
+    /// Return the underlying circuit.
+    ///
+    /// It can be used to learn about the circuit's path, or to open streams
+    /// on it.
+    pub fn circuit(&self) -> &Arc<ClientCirc> {
+        &self.circ
+    }
+
+    /// Send `msg` to the last hop of the circuit.
+    pub async fn send(&self, msg: AnyRelayMsg) -> crate::Result<()> {
+        self.circ
+            .send_raw_msg(msg, self.hop)
+            .await
+            .map_err(|cause| ErrorDetail::RawCircuit {
+                action: "send a message",
+                cause,
+            })?;
+        Ok(())
+    }
+
+    /// Wait for the next message from the last hop of the circuit.
+    ///
+    /// Returns `None` once the circuit has closed.
+    pub async fn recv(&mut self) -> Option<AnyRelayMsg> {
+        self.incoming.next().await
+    }
+}
+
+impl Stream for RawCircuit {
+    type Item = AnyRelayMsg;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    /// Return a message to hand to a [`Forwarder`].
+    fn drop_msg() -> AnyRelayMsg {
+        AnyRelayMsg::Drop(Default::default())
+    }
+
+    #[test]
+    fn forward() {
+        let (tx, mut rx) = mpsc_channel_no_memquota(INCOMING_QUEUE_LEN);
+        let mut fwd = Forwarder { tx };
+
+        for _ in 0..3 {
+            let disp = fwd.handle_msg(drop_msg()).unwrap();
+            assert!(matches!(disp, MetaCellDisposition::Consumed));
+        }
+        for _ in 0..3 {
+            let msg = rx.try_next().unwrap().unwrap();
+            assert!(matches!(msg, AnyRelayMsg::Drop(_)));
+        }
+        assert!(rx.try_next().is_err());
+    }
+
+    #[test]
+    fn close_on_overflow() {
+        let (tx, _rx) = mpsc_channel_no_memquota(INCOMING_QUEUE_LEN);
+        let mut fwd = Forwarder { tx };
+
+        // The channel may have a little more room than we asked for, but not
+        // much; once it is full, the circuit has to be closed.
+        let mut accepted = 0;
+        let err = loop {
+            match fwd.handle_msg(drop_msg()) {
+                Ok(disp) => {
+                    assert!(matches!(disp, MetaCellDisposition::Consumed));
+                    accepted += 1;
+                    assert!(accepted <= INCOMING_QUEUE_LEN * 2);
+                }
+                Err(e) => break e,
+            }
+        };
+        assert!(accepted >= INCOMING_QUEUE_LEN);
+        assert!(matches!(err, tor_proto::Error::CircProto(_)));
+    }
+
+    #[test]
+    fn close_when_unread() {
+        let (tx, rx) = mpsc_channel_no_memquota(INCOMING_QUEUE_LEN);
+        let mut fwd = Forwarder { tx };
+        drop(rx);
+
+        let disp = fwd.handle_msg(drop_msg()).unwrap();
+        assert!(matches!(disp, MetaCellDisposition::CloseCirc));
+    }
+}
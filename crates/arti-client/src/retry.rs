@@ -0,0 +1,184 @@
+//! How hard to try to open a stream.
+
+use std::time::Duration;
+
+/// The largest delay that a [`RetryPolicy`] will wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Rules for how often, and how patiently, to try to open a stream.
+///
+/// Set one for a stream with
+/// [`StreamPrefs::retry_policy`](crate::StreamPrefs::retry_policy).
+///
+/// By default, we make a single attempt, and give up on it after the
+/// `stream_timeouts.connect_timeout` configuration option.  A
+/// latency-sensitive application might want a shorter timeout; a batch job
+/// might want several attempts, with a growing delay between them, each on a
+/// new circuit.
+///
+/// # Example
+///
+/// ```
+/// use arti_client::{RetryPolicy, StreamPrefs};
+/// use std::time::Duration;
+///
+/// let mut policy = RetryPolicy::new();
+/// policy
+///     .attempts(4)
+///     .attempt_timeout(Duration::from_secs(20))
+///     .backoff(Duration::from_secs(1))
+///     .new_circuit_on_failure(true);
+///
+/// let mut prefs = StreamPrefs::new();
+/// prefs.retry_policy(policy);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// How many attempts to make in total.  Always at least 1.
+    attempts: u32,
+    /// How long to wait for the exit to open the stream, on each attempt.
+    ///
+    /// If `None`, we use the configured `connect_timeout`.
+    attempt_timeout: Option<Duration>,
+    /// How long to wait after the first failure before trying again.
+    ///
+    /// Each later delay is twice the one before it, up to [`MAX_BACKOFF`].
+    backoff: Duration,
+    /// Whether to stop using a circuit after a stream on it fails.
+    new_circuit_on_failure: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            attempt_timeout: None,
+            backoff: Duration::ZERO,
+            new_circuit_on_failure: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Return a new `RetryPolicy` that makes a single attempt, with the
+    /// configured timeout.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Try to open the stream up to `attempts` times in total.
+    ///
+    /// Values less than 1 are treated as 1.
+    pub fn attempts(&mut self, attempts: u32) -> &mut Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// On each attempt, wait at most `timeout` for the exit to open the
+    /// stream.
+    ///
+    /// This overrides the `stream_timeouts.connect_timeout` configuration
+    /// option.  It doesn't limit how long we wait for a circuit: that is
+    /// controlled by the `circuit_timing` configuration options.
+    pub fn attempt_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.attempt_timeout = Some(timeout);
+        self
+    }
+
+    /// Wait `delay` after the first failed attempt before trying again.
+    ///
+    /// After each later failure we wait twice as long as the time before,
+    /// up to a minute.
+    pub fn backoff(&mut self, delay: Duration) -> &mut Self {
+        self.backoff = delay;
+        self
+    }
+
+    /// If `new_circuit` is true, stop using a circuit for new streams after
+    /// a stream on it fails, so that the next attempt uses another circuit.
+    pub fn new_circuit_on_failure(&mut self, new_circuit: bool) -> &mut Self {
+        self.new_circuit_on_failure = new_circuit;
+        self
+    }
+
+    /// Return how many attempts to make in total.
+    pub(crate) fn n_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Return how long to wait for each attempt, given the configured
+    /// `default` timeout.
+    pub(crate) fn timeout_or(&self, default: Duration) -> Duration {
+        self.attempt_timeout.unwrap_or(default)
+    }
+
+    /// Return true if we should stop using a circuit after a stream on it
+    /// fails.
+    pub(crate) fn wants_new_circuit(&self) -> bool {
+        self.new_circuit_on_failure
+    }
+
+    /// Return how long to wait after `n_failed` attempts have failed.
+    pub(crate) fn delay_after(&self, n_failed: u32) -> Duration {
+        let factor = 1_u32
+            .checked_shl(n_failed.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.n_attempts(), 1);
+        assert_eq!(
+            policy.timeout_or(Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+        assert!(!policy.wants_new_circuit());
+        assert_eq!(policy.delay_after(1), Duration::ZERO);
+    }
+
+    #[test]
+    fn settings() {
+        let mut policy = RetryPolicy::new();
+        policy
+            .attempts(0)
+            .attempt_timeout(Duration::from_secs(3))
+            .new_circuit_on_failure(true);
+        assert_eq!(policy.n_attempts(), 1);
+        assert_eq!(
+            policy.timeout_or(Duration::from_secs(10)),
+            Duration::from_secs(3)
+        );
+        assert!(policy.wants_new_circuit());
+    }
+
+    #[test]
+    fn backoff() {
+        let mut policy = RetryPolicy::new();
+        policy.attempts(40).backoff(Duration::from_millis(500));
+        assert_eq!(policy.delay_after(1), Duration::from_millis(500));
+        assert_eq!(policy.delay_after(2), Duration::from_secs(1));
+        assert_eq!(policy.delay_after(3), Duration::from_secs(2));
+        assert_eq!(policy.delay_after(8), MAX_BACKOFF);
+        assert_eq!(policy.delay_after(39), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,364 @@
+//! Measuring how well a client can use the Tor network.
+//!
+//! [`TorClient::run_selftest`](crate::TorClient::run_selftest) builds some
+//! circuits that nothing else uses, and times how long each one takes to
+//! build.  Then, if its [`SelfTestConfig`] names an endpoint, it downloads
+//! from that endpoint, and measures the goodput: how many bytes of the reply
+//! arrive per second.
+//!
+//! The [`SelfTestReport`] is meant for support diagnostics, and for health
+//! checks in applications that embed Arti.
+
+use std::io;
+use std::time::Duration;
+
+use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tor_proto::stream::DataStream;
+use tor_rtcompat::{SleepProvider, SleepProviderExt as _};
+
+use crate::TorAddr;
+
+/// How many test circuits we build by default.
+const DEFAULT_N_CIRCUITS: usize = 3;
+
+/// How many bytes we download from the endpoint by default, at most.
+const DEFAULT_MAX_DOWNLOAD_BYTES: u64 = 1024 * 1024;
+
+/// How long we spend downloading from the endpoint by default, at most.
+const DEFAULT_MAX_DOWNLOAD_TIME: Duration = Duration::from_secs(30);
+
+/// How many bytes we read from the endpoint at a time.
+const READ_BUF_LEN: usize = 16 * 1024;
+
+/// What [`TorClient::run_selftest`](crate::TorClient::run_selftest) should
+/// measure.
+///
+/// By default, the self-test builds three circuits, and doesn't measure
+/// goodput: call [`endpoint`](SelfTestConfig::endpoint) to name a server to
+/// download from.
+#[derive(Clone, Debug)]
+pub struct SelfTestConfig {
+    /// How many test circuits to build.
+    n_circuits: usize,
+    /// The server to download from, and the request to send it.
+    endpoint: Option<(TorAddr, Vec<u8>)>,
+    /// The most bytes to download.
+    max_download_bytes: u64,
+    /// The longest time to spend downloading.
+    max_download_time: Duration,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        SelfTestConfig {
+            n_circuits: DEFAULT_N_CIRCUITS,
+            endpoint: None,
+            max_download_bytes: DEFAULT_MAX_DOWNLOAD_BYTES,
+            max_download_time: DEFAULT_MAX_DOWNLOAD_TIME,
+        }
+    }
+}
+
+impl SelfTestConfig {
+    /// Return a new `SelfTestConfig` with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build `n` test circuits.
+    pub fn circuits(&mut self, n: usize) -> &mut Self {
+        self.n_circuits = n;
+        self
+    }
+
+    /// Measure goodput by connecting to `target`, and sending it `request`.
+    ///
+    /// The endpoint should answer with a large reply: for example, `target`
+    /// could be a web server, and `request` an HTTP request for a large file.
+    /// We count every byte of the reply, headers included.
+    ///
+    /// If `request` is empty, we send nothing, and just read what the
+    /// endpoint sends.
+    pub fn endpoint(&mut self, target: TorAddr, request: impl Into<Vec<u8>>) -> &mut Self {
+        self.endpoint = Some((target, request.into()));
+        self
+    }
+
+    /// Stop downloading from the endpoint after `n` bytes.
+    pub fn max_download_bytes(&mut self, n: u64) -> &mut Self {
+        self.max_download_bytes = n;
+        self
+    }
+
+    /// Stop downloading from the endpoint after `time` has passed.
+    pub fn max_download_time(&mut self, time: Duration) -> &mut Self {
+        self.max_download_time = time;
+        self
+    }
+
+    /// Return how many test circuits to build.
+    pub(crate) fn n_circuits(&self) -> usize {
+        self.n_circuits
+    }
+
+    /// Return the endpoint to download from, and the request to send it.
+    pub(crate) fn endpoint_and_request(&self) -> Option<(&TorAddr, &[u8])> {
+        self.endpoint
+            .as_ref()
+            .map(|(target, request)| (target, &request[..]))
+    }
+}
+
+/// The results of [`TorClient::run_selftest`](crate::TorClient::run_selftest).
+#[derive(Clone, Debug)]
+pub struct SelfTestReport {
+    /// The results of building each test circuit, in order.
+    circuits: Vec<CircuitTest>,
+    /// Our circuit build timeout, as estimated when the test finished.
+    build_timeout: Duration,
+    /// The results of downloading from the endpoint, if there was one.
+    download: Option<DownloadTest>,
+}
+
+impl SelfTestReport {
+    /// Construct a new `SelfTestReport`.
+    pub(crate) fn new(
+        circuits: Vec<CircuitTest>,
+        build_timeout: Duration,
+        download: Option<DownloadTest>,
+    ) -> Self {
+        SelfTestReport {
+            circuits,
+            build_timeout,
+            download,
+        }
+    }
+
+    /// Return the results of building each test circuit, in the order that we
+    /// built them.
+    pub fn circuits(&self) -> &[CircuitTest] {
+        &self.circuits
+    }
+
+    /// Return how many of the test circuits we managed to build.
+    pub fn n_circuits_built(&self) -> usize {
+        self.circuits
+            .iter()
+            .filter(|c| c.build_time().is_some())
+            .count()
+    }
+
+    /// Return the median time that it took to build a test circuit, or
+    /// `None` if we couldn't build any.
+    ///
+    /// (With an even number of circuits, this is the slower of the two
+    /// middle times.)
+    pub fn median_build_time(&self) -> Option<Duration> {
+        let mut times: Vec<_> = self
+            .circuits
+            .iter()
+            .filter_map(CircuitTest::build_time)
+            .collect();
+        times.sort_unstable();
+        times.get(times.len() / 2).copied()
+    }
+
+    /// Return the circuit build timeout (CBT) that the client was using when
+    /// the test finished.
+    ///
+    /// The client learns this timeout from the circuits that it builds, and
+    /// gives up on any circuit that takes longer.  A large timeout suggests a
+    /// slow or congested network connection.
+    pub fn circuit_build_timeout(&self) -> Duration {
+        self.build_timeout
+    }
+
+    /// Return the results of downloading from the endpoint, or `None` if no
+    /// endpoint was configured.
+    pub fn download(&self) -> Option<&DownloadTest> {
+        self.download.as_ref()
+    }
+}
+
+/// The result of building one test circuit.
+#[derive(Clone, Debug)]
+pub struct CircuitTest {
+    /// How long the circuit took to build, or why we couldn't build it.
+    result: Result<Duration, crate::Error>,
+}
+
+impl CircuitTest {
+    /// Construct a new `CircuitTest`.
+    pub(crate) fn new(result: Result<Duration, crate::Error>) -> Self {
+        CircuitTest { result }
+    }
+
+    /// Return how long the circuit took to build, or `None` if we
+    /// couldn't build it.
+    pub fn build_time(&self) -> Option<Duration> {
+        self.result.as_ref().ok().copied()
+    }
+
+    /// Return the reason that we couldn't build the circuit, if we couldn't.
+    pub fn error(&self) -> Option<&crate::Error> {
+        self.result.as_ref().err()
+    }
+}
+
+/// The result of downloading from the self-test endpoint.
+#[derive(Clone, Debug)]
+pub struct DownloadTest {
+    /// How long it took to connect to the endpoint, if we could.
+    connect_time: Option<Duration>,
+    /// How many bytes we received.
+    received: u64,
+    /// How long we spent sending the request and receiving the reply.
+    transfer_time: Duration,
+    /// The error that stopped us, if any.
+    error: Option<crate::Error>,
+}
+
+impl DownloadTest {
+    /// Return a `DownloadTest` for when we couldn't connect to the endpoint.
+    pub(crate) fn connect_failed(error: crate::Error) -> Self {
+        DownloadTest {
+            connect_time: None,
+            received: 0,
+            transfer_time: Duration::ZERO,
+            error: Some(error),
+        }
+    }
+
+    /// Return how long it took to connect to the endpoint (including the time
+    /// to find a circuit), or `None` if we couldn't connect.
+    pub fn connect_time(&self) -> Option<Duration> {
+        self.connect_time
+    }
+
+    /// Return how many bytes we received from the endpoint.
+    pub fn bytes_received(&self) -> u64 {
+        self.received
+    }
+
+    /// Return how long we spent sending our request and receiving the reply.
+    pub fn transfer_time(&self) -> Duration {
+        self.transfer_time
+    }
+
+    /// Return the goodput of the download, in bytes per second, or `None` if
+    /// we received nothing.
+    pub fn goodput(&self) -> Option<f64> {
+        if self.received == 0 || self.transfer_time.is_zero() {
+            return None;
+        }
+        Some(self.received as f64 / self.transfer_time.as_secs_f64())
+    }
+
+    /// Return the error that stopped the download, if any.
+    ///
+    /// Reaching the configured limits is not an error; neither is the
+    /// endpoint closing the connection.
+    pub fn error(&self) -> Option<&crate::Error> {
+        self.error.as_ref()
+    }
+}
+
+/// Send `request` on `stream`, and read the reply until the endpoint closes
+/// the stream, or until we reach the limits in `config`.
+///
+/// `connect_time` is how long it took to open `stream`.
+pub(crate) async fn download<R: SleepProvider>(
+    runtime: &R,
+    mut stream: DataStream,
+    request: &[u8],
+    config: &SelfTestConfig,
+    connect_time: Duration,
+) -> DownloadTest {
+    let start = runtime.now();
+    let mut received = 0_u64;
+    let mut buf = vec![0_u8; READ_BUF_LEN];
+    let outcome: io::Result<()> = async {
+        if !request.is_empty() {
+            stream.write_all(request).await?;
+            stream.flush().await?;
+        }
+        while received < config.max_download_bytes {
+            let elapsed = runtime.now().saturating_duration_since(start);
+            let remaining = config.max_download_time.saturating_sub(elapsed);
+            match runtime.timeout(remaining, stream.read(&mut buf)).await {
+                // We've spent as long as we're allowed to.
+                Err(_) => break,
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => received += n as u64,
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    DownloadTest {
+        connect_time: Some(connect_time),
+        received,
+        transfer_time: runtime.now().saturating_duration_since(start),
+        error: outcome
+            .err()
+            .map(|e| crate::err::ErrorDetail::SelfTestDownload(e.into()).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn median() {
+        let report = SelfTestReport::new(vec![], secs(60), None);
+        assert_eq!(report.median_build_time(), None);
+        assert_eq!(report.n_circuits_built(), 0);
+
+        let report = SelfTestReport::new(
+            vec![
+                CircuitTest::new(Ok(secs(3))),
+                CircuitTest::new(Ok(secs(1))),
+                CircuitTest::new(Ok(secs(2))),
+                CircuitTest::new(Ok(secs(9))),
+            ],
+            secs(60),
+            None,
+        );
+        assert_eq!(report.n_circuits_built(), 4);
+        assert_eq!(report.median_build_time(), Some(secs(3)));
+    }
+
+    #[test]
+    fn goodput() {
+        let d = DownloadTest {
+            connect_time: Some(secs(1)),
+            received: 1000,
+            transfer_time: secs(4),
+            error: None,
+        };
+        assert_eq!(d.goodput(), Some(250.0));
+
+        let d = DownloadTest { received: 0, ..d };
+        assert_eq!(d.goodput(), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Shutting a client down gracefully.
+//!
+//! [`TorClient::shutdown`](crate::TorClient::shutdown) stops the client (and
+//! every clone of it) from opening new streams, and waits for the streams
+//! that are already open to close.  Any stream that is still open once the
+//! grace period is over is closed along with its circuit.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tor_proto::circuit::{ClientCirc, UniqId};
+
+/// How often we check whether every stream has closed, while shutting down.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The shutdown state of a client, shared among all its clones.
+#[derive(Default)]
+pub(crate) struct Shutdown {
+    /// True once shutdown has begun.
+    started: AtomicBool,
+    /// The circuits that we have opened streams on, so that we can close
+    /// them if their streams are still open at the end of the grace period.
+    ///
+    /// We only hold weak references, so that we don't keep any circuit alive
+    /// ourselves.
+    circs: Mutex<HashMap<UniqId, Weak<ClientCirc>>>,
+}
+
+impl Shutdown {
+    /// Return a new `Shutdown`, for a client that is running.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note that shutdown has begun.
+    ///
+    /// Return true if it had already begun.
+    pub(crate) fn start(&self) -> bool {
+        self.started.swap(true, Ordering::SeqCst)
+    }
+
+    /// Return true if shutdown has begun.
+    pub(crate) fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /// Note that we are opening a stream on `circ`.
+    pub(crate) fn note_circ(&self, circ: &Arc<ClientCirc>) {
+        let mut circs = self.circs.lock().expect("shutdown lock poisoned");
+        // Forget the circuits that have gone away, so that this map doesn't
+        // grow without bound.
+        circs.retain(|_, circ| circ.strong_count() > 0);
+        circs.insert(circ.unique_id(), Arc::downgrade(circ));
+    }
+
+    /// Close every circuit in `ids` that we know about, and that still exists.
+    ///
+    /// Return the number of circuits that we closed.
+    pub(crate) fn close_circs(&self, ids: &[UniqId]) -> usize {
+        let circs = self.circs.lock().expect("shutdown lock poisoned");
+        let mut n_closed = 0;
+        for circ in ids
+            .iter()
+            .filter_map(|id| circs.get(id).and_then(Weak::upgrade))
+        {
+            circ.terminate();
+            n_closed += 1;
+        }
+        n_closed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn start() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_started());
+        assert!(!shutdown.start());
+        assert!(shutdown.is_started());
+        assert!(shutdown.start());
+        assert_eq!(shutdown.close_circs(&[]), 0);
+    }
+}
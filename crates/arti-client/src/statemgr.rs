@@ -0,0 +1,112 @@
+//! The state manager that a [`TorClient`](crate::TorClient) uses.
+
+use futures::future::Either;
+use futures::Future;
+use serde::{de::DeserializeOwned, Serialize};
+use tor_persist::{
+    DynStateMgr, Error, FsStateMgr, LockStatus, RecordFormat, StateMgr, Transaction,
+};
+
+/// The state manager that a [`TorClient`](crate::TorClient) uses.
+///
+/// Usually this is an [`FsStateMgr`] in the configured state directory, but
+/// the [`TorClientBuilder`](crate::TorClientBuilder) can choose another.
+#[derive(Clone, Debug)]
+pub(crate) enum ClientStateMgr {
+    /// State kept in files in the state directory.
+    Fs(FsStateMgr),
+    /// State kept somewhere else: in memory, or by a manager that the
+    /// application supplied.
+    Custom(DynStateMgr),
+}
+
+impl ClientStateMgr {
+    /// Return a state manager for the namespace called `name`.
+    ///
+    /// See [`FsStateMgr::namespace`].  Only possible when we keep our state
+    /// in files.
+    #[cfg(feature = "experimental-api")]
+    pub(crate) fn namespace(
+        &self,
+        name: &tor_persist::slug::SlugRef,
+    ) -> Result<FsStateMgr, crate::err::ErrorDetail> {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr
+                .namespace(name)
+                .map_err(crate::err::ErrorDetail::StateAccess),
+            ClientStateMgr::Custom(_) => Err(tor_error::bad_api_usage!(
+                "State namespaces are not available with a custom state manager"
+            )
+            .into()),
+        }
+    }
+
+    /// Return a future that resolves once this state manager (and every
+    /// clone of it) has been dropped.
+    pub(crate) fn wait_for_unlock(&self) -> impl Future<Output = ()> + Send + Sync + 'static {
+        match self {
+            ClientStateMgr::Fs(mgr) => Either::Left(mgr.wait_for_unlock()),
+            ClientStateMgr::Custom(mgr) => Either::Right(mgr.wait_for_drop()),
+        }
+    }
+}
+
+impl StateMgr for ClientStateMgr {
+    fn load<D>(&self, key: &str) -> Result<Option<D>, Error>
+    where
+        D: DeserializeOwned,
+    {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr.load(key),
+            ClientStateMgr::Custom(mgr) => mgr.load(key),
+        }
+    }
+
+    fn store<S>(&self, key: &str, val: &S) -> Result<(), Error>
+    where
+        S: Serialize,
+    {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr.store(key, val),
+            ClientStateMgr::Custom(mgr) => mgr.store(key, val),
+        }
+    }
+
+    fn store_with_format<S>(&self, key: &str, val: &S, format: RecordFormat) -> Result<(), Error>
+    where
+        S: Serialize,
+    {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr.store_with_format(key, val, format),
+            ClientStateMgr::Custom(mgr) => mgr.store_with_format(key, val, format),
+        }
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<(), Error> {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr.commit(txn),
+            ClientStateMgr::Custom(mgr) => mgr.commit(txn),
+        }
+    }
+
+    fn can_store(&self) -> bool {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr.can_store(),
+            ClientStateMgr::Custom(mgr) => mgr.can_store(),
+        }
+    }
+
+    fn try_lock(&self) -> Result<LockStatus, Error> {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr.try_lock(),
+            ClientStateMgr::Custom(mgr) => mgr.try_lock(),
+        }
+    }
+
+    fn unlock(&self) -> Result<(), Error> {
+        match self {
+            ClientStateMgr::Fs(mgr) => mgr.unlock(),
+            ClientStateMgr::Custom(mgr) => mgr.unlock(),
+        }
+    }
+}
@@ -43,6 +43,14 @@ impl BootstrapStatus {
         self.conn_status.frac() * 0.15 + self.dir_status.frac_at(SystemTime::now()) * 0.85
     }
 
+    /// Return a rough percentage (from 0 to 100) representing how far along
+    /// the client's bootstrapping efforts are.
+    ///
+    /// This is [`as_frac`](Self::as_frac), rounded to a whole percentage.
+    pub fn as_percent(&self) -> u8 {
+        (self.as_frac().clamp(0.0, 1.0) * 100.0).round() as u8
+    }
+
     /// Return true if the status indicates that the client is ready for
     /// traffic.
     ///
@@ -186,7 +194,7 @@ impl fmt::Display for BootstrapStatus {
     /// readability, not for machine parsing.  Other code *should not* depend
     /// on particular elements of this string.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let percent = (self.as_frac() * 100.0).round() as u32;
+        let percent = self.as_percent();
         if let Some(problem) = self.blocked() {
             write!(f, "Stuck at {}%: {}", percent, problem)?;
         } else {
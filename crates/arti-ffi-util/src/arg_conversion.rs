@@ -0,0 +1,101 @@
+//! Functions to implement argument conversion.
+//!
+//! Each of these functions corresponds to a conversion mode used in `ffi_initialize!`.
+//!
+//! Every function has all of these properties:
+//!
+//! - It returns  `Err(InvalidInput)` if the conversion fails,
+//!   and `Ok($ty)` if the conversion succeeds.
+//!   (Infallible conversions always return `Ok`.)
+//!
+//! Nothing outside of the `ffi_initialize!` macro should actually invoke these functions!
+
+#![allow(clippy::unnecessary_wraps)]
+
+use crate::err::InvalidInput;
+use crate::ptr::{OutPtr, OutVal};
+use std::ffi::{c_char, CStr};
+use void::Void;
+
+/// Try to convert a const pointer to an optional reference.
+///
+/// A null pointer is allowed, and converted to `None`.
+///
+/// # Safety
+///
+/// As for [`<*const T>::as_ref`](https://doc.rust-lang.org/std/primitive.pointer.html#method.as_ref).
+pub unsafe fn in_ptr_opt<'a, T>(input: *const T) -> Result<Option<&'a T>, Void> {
+    Ok(unsafe { input.as_ref() })
+}
+
+/// Try to convert a `const char *` to a `&str`.
+///
+/// A null pointer is allowed, and converted to `None`.
+/// Non-UTF-8 inputs will give an error.
+///
+/// # Safety
+///
+/// As for [`CStr::from_ptr`](std::ffi::CStr::from_ptr).
+pub unsafe fn in_str_opt<'a>(input: *const c_char) -> Result<Option<&'a str>, InvalidInput> {
+    if input.is_null() {
+        return Ok(None);
+    }
+
+    // Safety: We require that the safety properties of CStr::from_ptr hold.
+    unsafe { CStr::from_ptr(input) }
+        .to_str()
+        .map(Some)
+        .map_err(|_| InvalidInput::BadUtf8)
+}
+
+/// Try to convert a mutable pointer to a `Option<Box<T>>`.
+///
+/// A null pointer is allowed, and converted to `None`.
+///
+/// # Safety
+///
+/// As for  [`Box::from_raw`].
+pub unsafe fn in_ptr_consume_opt<T>(input: *mut T) -> Result<Option<Box<T>>, Void> {
+    Ok(if input.is_null() {
+        None
+    } else {
+        Some(unsafe { Box::from_raw(input) })
+    })
+}
+
+/// Try to convert a mutable pointer-to-pointer into an `Option<OutPtr<T>>`.
+///
+/// A null pointer is allowed, and converted into None.
+///
+/// Whatever the target of the original pointer (`input: *mut *mut T`), if `input` is non-null.
+/// then `*input` is initialized to NULL.
+///
+/// It is safe for `*input` to be uninitialized.
+///
+/// # Safety
+///
+/// As for
+/// [`<*mut *mut T>::as_uninit_mut`](https://doc.rust-lang.org/std/primitive.pointer.html#method.as_uninit_mut).
+pub unsafe fn out_ptr_opt<'a, T>(input: *mut *mut T) -> Result<Option<OutPtr<'a, T>>, Void> {
+    Ok(unsafe { OutPtr::from_opt_ptr(input, std::ptr::null_mut()) })
+}
+
+/// Try to convert a mutable pointer-to-value into an `Option<OutVal<T>>`.
+///
+/// A null pointer is allowed, and converted into None.
+///
+/// Whatever the target of the original pointer (`input: *mut T`), if `input` is non-null.
+/// then `*input` is initialized to T::default().
+///
+/// It is safe for `*input` to be uninitialized.
+///
+/// # Safety
+///
+/// As for
+/// [`<*mut T>::as_uninit_mut`](https://doc.rust-lang.org/std/primitive.pointer.html#method.as_uninit_mut).
+pub unsafe fn out_val_opt<'a, T>(input: *mut T) -> Result<Option<OutVal<'a, T>>, Void>
+where
+    T: Default,
+{
+    Ok(unsafe { OutVal::from_opt_ptr(input, T::default()) })
+}
@@ -0,0 +1,88 @@
+//! Error handling for FFI functions.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::ptr::{OptOutPtrExt as _, OutPtr};
+
+/// Tried to call a ffi function with a not-permitted argument.
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum InvalidInput {
+    /// Tried to convert a NULL pointer to an FFI object.
+    #[error("Provided argument was NULL.")]
+    NullPointer,
+
+    /// Tried to convert a non-UTF string.
+    #[error("Provided string was not UTF-8")]
+    BadUtf8,
+
+    /// Tried to use an invalid port.
+    #[error("Port was not in range 1..65535")]
+    BadPort,
+
+    /// Tried to use an invalid constant
+    #[error("Provided constant was not recognized")]
+    InvalidConstValue,
+}
+
+impl From<void::Void> for InvalidInput {
+    fn from(value: void::Void) -> Self {
+        void::unreachable(value)
+    }
+}
+
+/// An error that an FFI function gives to its caller
+/// through an error out-parameter, along with a status code.
+///
+/// Each crate that exposes a C API has its own error and status types;
+/// this trait lets [`handle_errors`] and
+/// [`ffi_body_with_err!`](crate::ffi_body_with_err) work with any of them.
+pub trait FfiStatusError: From<InvalidInput> {
+    /// The status code that FFI functions return.
+    type Status: Copy;
+
+    /// The status code that an FFI function returns when it succeeds.
+    const SUCCESS: Self::Status;
+
+    /// Return the status code that an FFI function returns along with this error.
+    fn status(&self) -> Self::Status;
+}
+
+/// Run `body` and catch panics.  If one occurs, abort the process.
+///
+/// We wrap the body of every C ffi function with this function
+/// (or with `handle_errors`, which uses this function),
+/// even if we do not think that the body can actually panic.
+pub fn abort_on_panic<F, T>(body: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    // We never observe any state after a panic, since we abort,
+    // so we don't need to care whether `body` is unwind-safe.
+    #[allow(clippy::print_stderr)]
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(x) => x,
+        Err(_panic_info) => {
+            eprintln!("Internal panic in Arti library: aborting!");
+            std::process::abort();
+        }
+    }
+}
+
+/// Call `body`, converting any errors or panics that occur into an `E`,
+/// and storing that error in `error_out`.
+pub fn handle_errors<E, F>(error_out: Option<OutPtr<E>>, body: F) -> E::Status
+where
+    E: FfiStatusError,
+    F: FnOnce() -> Result<(), E>,
+{
+    match abort_on_panic(body) {
+        Ok(()) => E::SUCCESS,
+        Err(e) => {
+            // "body" returned an error.
+            let status = e.status();
+            error_out.write_boxed_value_if_ptr_set(e);
+            status
+        }
+    }
+}
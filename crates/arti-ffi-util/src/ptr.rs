@@ -0,0 +1,206 @@
+//! Helpers for pointers that FFI functions are given.
+
+use std::mem::MaybeUninit;
+
+use crate::err::InvalidInput;
+
+/// Helper for output parameters represented as `*mut T`.
+///
+/// This is for an API which, from a C POV, returns an output via a parameter of type
+/// `Foo *foo_out` .  When an `OutPtr` is constructed, `foo_out` is necessarily non-null;
+///
+/// If `foo_out` is not NULL, then `*foo_out` is always initialized when an `OutPtr`
+/// is constructed, so that even if the FFI code panics, the inner pointer will be initialized to
+/// _something_.
+pub struct OutVal<'a, T>(&'a mut T);
+
+/// Alias for an `OutVal` representing a `*mut *mut T`.
+pub type OutPtr<'a, T> = OutVal<'a, *mut T>;
+
+impl<'a, T> OutVal<'a, T> {
+    /// Construct `Option<Self>` from a possibly NULL pointer; initialize `*ptr` to `initial_value` if possible.
+    ///
+    /// # Safety
+    ///
+    /// The outer pointer, if set, must be valid, and must not alias any other pointers.
+    ///
+    /// See also the requirements on `pointer::as_mut()`.
+    ///
+    /// # No panics!
+    ///
+    /// This method can be invoked in cases where panicking is not allowed (such as
+    /// in a FFI method, outside of `handle_errors()` or `catch_panic()`.)
+    //
+    // (I have tested this using the `no-panic` crate.  But `no-panic` is not suitable
+    // for use in production, since it breaks when run in debug mode.)
+    pub unsafe fn from_opt_ptr(ptr: *mut T, initial_value: T) -> Option<Self> {
+        if ptr.is_null() {
+            None
+        } else {
+            // TODO: Use `.as_mut_uninit` once it is stable.
+            //
+            // SAFETY: See documentation for [`<*mut *mut T>::as_uninit_mut`]
+            // at https://doc.rust-lang.org/std/primitive.pointer.html#method.as_uninit_mut :
+            // This is the same code.
+            let ptr: &mut MaybeUninit<T> = unsafe { &mut *(ptr as *mut MaybeUninit<T>) };
+            let ptr: &mut T = ptr.write(initial_value);
+            Some(OutVal(ptr))
+        }
+    }
+
+    /// Consume this `OutVal` and the provided value, writing the value into the outval.
+    pub fn write_value(self, value: T) {
+        // Note that all the unsafety happened when we constructed a &mut from the pointer.
+        //
+        // Note also that this method consumes `self`.  That's because we want to avoid multiple
+        // writes to the same OutVal: If we did that, we would sometimes have to free a previous
+        // value.
+        *self.0 = value;
+    }
+}
+
+impl<'a, T> OutVal<'a, *mut T> {
+    /// Consume this `OutPtr` and the provided value, writing the value into the outptr.
+    pub fn write_value_boxed(self, value: T) {
+        self.write_value(Box::into_raw(Box::new(value)));
+    }
+}
+
+/// Implement OptOutPtrExt and OptOutValExt.
+///
+/// This is a separate module so we can seal these traits.
+mod out_ptr_ext {
+    use super::OutVal;
+
+    /// Trait to prevent implementation of OptOutPtrExt outside this module.
+    trait Sealed {}
+
+    /// Extension trait on `Option<OutPtr<T>>`
+    #[allow(private_bounds)]
+    pub trait OptOutPtrExt<T>: Sealed {
+        /// Consume this `Option<OutPtr<T>>` and the provided value.
+        ///
+        /// If this is Some, write the value into the outptr.
+        ///
+        /// Otherwise, discard the value.
+        fn write_boxed_value_if_ptr_set(self, value: T);
+    }
+    /// Extension trait on `Option<OutVal<T>>`
+    #[allow(private_bounds)]
+    pub trait OptOutValExt<T>: Sealed {
+        /// Consume this `Option<OutVal<T>>` and the provided value.
+        ///
+        /// If this is Some, write the value into the outptr.
+        ///
+        /// Otherwise, discard the value.
+        fn write_value_if_ptr_set(self, value: T);
+    }
+    impl<'a, T> Sealed for Option<OutVal<'a, T>> {}
+    impl<'a, T> OptOutPtrExt<T> for Option<OutVal<'a, *mut T>> {
+        fn write_boxed_value_if_ptr_set(self, value: T) {
+            if let Some(outptr) = self {
+                outptr.write_value_boxed(value);
+            }
+        }
+    }
+    impl<'a, T> OptOutValExt<T> for Option<OutVal<'a, T>> {
+        fn write_value_if_ptr_set(self, value: T) {
+            if let Some(outptr) = self {
+                outptr.write_value(value);
+            }
+        }
+    }
+}
+pub use out_ptr_ext::{OptOutPtrExt, OptOutValExt};
+
+/// Convert a buffer that we were given for input into a byte slice.
+///
+/// A NULL pointer is allowed only if `len` is zero.
+///
+/// (This is not a conversion for `ffi_initialize!`,
+/// since it needs two arguments.
+/// Call it from the body of the function, after every conversion has been done.)
+///
+/// # Safety
+///
+/// As for [`std::slice::from_raw_parts`], when `len` is nonzero.
+pub unsafe fn in_bytes<'a>(input: *const u8, len: usize) -> Result<&'a [u8], InvalidInput> {
+    if len == 0 {
+        Ok(&[])
+    } else if input.is_null() {
+        Err(InvalidInput::NullPointer)
+    } else {
+        // Safety: We require that the safety properties of from_raw_parts hold.
+        Ok(unsafe { std::slice::from_raw_parts(input, len) })
+    }
+}
+
+/// Convert a buffer that we were given for output into a mutable byte slice.
+///
+/// A NULL pointer is allowed only if `len` is zero.
+///
+/// (See [`in_bytes`] for why this is not a conversion for `ffi_initialize!`.)
+///
+/// # Safety
+///
+/// As for [`std::slice::from_raw_parts_mut`], when `len` is nonzero.
+/// Additionally, the buffer must not alias any other argument.
+pub unsafe fn out_bytes<'a>(output: *mut u8, len: usize) -> Result<&'a mut [u8], InvalidInput> {
+    if len == 0 {
+        Ok(&mut [])
+    } else if output.is_null() {
+        Err(InvalidInput::NullPointer)
+    } else {
+        // Safety: We require that the safety properties of from_raw_parts_mut hold.
+        Ok(unsafe { std::slice::from_raw_parts_mut(output, len) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+
+    unsafe fn outptr_user(ptr: *mut *mut i8, set_to_val: Option<i8>) {
+        let ptr = unsafe { OutPtr::from_opt_ptr(ptr, std::ptr::null_mut()) };
+
+        if let Some(v) = set_to_val {
+            ptr.write_boxed_value_if_ptr_set(v);
+        }
+    }
+
+    #[test]
+    fn outptr() {
+        let mut ptr_to_int: *mut i8 = 7 as _; // This is a junk dangling pointer.  It will get overwritten.
+
+        // Case 1: Don't set to anything.
+        unsafe { outptr_user(&mut ptr_to_int as _, None) };
+        assert!(ptr_to_int.is_null());
+
+        // Cases 2, 3: Provide a null pointer for the output pointer.
+        ptr_to_int = 7 as _; // make it junk again.
+        unsafe { outptr_user(std::ptr::null_mut(), None) };
+        assert_eq!(ptr_to_int, 7 as _); // we didn't pass this in, so it wasn't set.
+        unsafe { outptr_user(std::ptr::null_mut(), Some(5)) };
+        assert_eq!(ptr_to_int, 7 as _); // we didn't pass this in, so it wasn't set.
+
+        // Case 4: Actually set something.
+        unsafe { outptr_user(&mut ptr_to_int as _, Some(123)) };
+        assert!(!ptr_to_int.is_null());
+        let boxed = unsafe { Box::from_raw(ptr_to_int) };
+        assert_eq!(*boxed, 123);
+    }
+}
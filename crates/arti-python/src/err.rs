@@ -0,0 +1,20 @@
+//! Errors that we raise in Python.
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::PyErr;
+use tor_error::ErrorReport as _;
+
+create_exception!(
+    _arti,
+    TorError,
+    PyException,
+    "An error from Arti.\n\nThe format of its message is not stable."
+);
+
+/// Convert `e` into a [`TorError`], with a message describing `e` and its sources.
+pub(crate) fn tor_error(e: impl std::error::Error + 'static) -> PyErr {
+    let msg = e.report().to_string();
+    let msg = msg.strip_prefix("error: ").unwrap_or(&msg);
+    TorError::new_err(msg.to_string())
+}
@@ -0,0 +1,280 @@
+#![doc = include_str!("../README.md")]
+// @@ begin lint list maintained by maint/add_warning @@
+#![allow(renamed_and_removed_lints)] // @@REMOVE_WHEN(ci_arti_stable)
+#![allow(unknown_lints)] // @@REMOVE_WHEN(ci_arti_nightly)
+#![warn(missing_docs)]
+#![warn(noop_method_call)]
+#![warn(unreachable_pub)]
+#![warn(clippy::all)]
+#![deny(clippy::await_holding_lock)]
+#![deny(clippy::cargo_common_metadata)]
+#![deny(clippy::cast_lossless)]
+#![deny(clippy::checked_conversions)]
+#![warn(clippy::cognitive_complexity)]
+#![deny(clippy::debug_assert_with_mut_call)]
+#![deny(clippy::exhaustive_enums)]
+#![deny(clippy::exhaustive_structs)]
+#![deny(clippy::expl_impl_clone_on_copy)]
+#![deny(clippy::fallible_impl_from)]
+#![deny(clippy::implicit_clone)]
+#![deny(clippy::large_stack_arrays)]
+#![warn(clippy::manual_ok_or)]
+#![deny(clippy::missing_docs_in_private_items)]
+#![warn(clippy::needless_borrow)]
+#![warn(clippy::needless_pass_by_value)]
+#![warn(clippy::option_option)]
+#![deny(clippy::print_stderr)]
+#![deny(clippy::print_stdout)]
+#![warn(clippy::rc_buffer)]
+#![deny(clippy::ref_option_ref)]
+#![warn(clippy::semicolon_if_nothing_returned)]
+#![warn(clippy::trait_duplication_in_bounds)]
+#![deny(clippy::unchecked_duration_subtraction)]
+#![deny(clippy::unnecessary_wraps)]
+#![warn(clippy::unseparated_literal_suffix)]
+#![deny(clippy::unwrap_used)]
+#![deny(clippy::mod_module_files)]
+#![allow(clippy::let_unit_value)] // This can reasonably be done for explicitness
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::significant_drop_in_scrutinee)] // arti/-/merge_requests/588/#note_2812945
+#![allow(clippy::result_large_err)] // temporary workaround for arti#587
+#![allow(clippy::needless_raw_string_hashes)] // complained-about code is fine, often best
+#![allow(clippy::needless_lifetimes)] // See arti#1765
+//! <!-- @@ end lint list maintained by maint/add_warning @@ -->
+
+mod err;
+mod onion;
+mod stream;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arti_client::config::onion_service::OnionServiceConfigBuilder;
+use arti_client::config::TorClientConfigBuilder;
+use arti_client::TorClientConfig;
+use futures::lock::Mutex;
+use futures::StreamExt as _;
+use pyo3::exceptions::{PyStopAsyncIteration, PyValueError};
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::future_into_py;
+use tor_hsservice::HsNickname;
+use tor_rtcompat::PreferredRuntime;
+
+use err::{tor_error, TorError};
+use onion::OnionService;
+use stream::DataStream;
+
+/// Return a runtime that runs on the same tokio runtime as our Python awaitables.
+fn runtime() -> PyResult<PreferredRuntime> {
+    let _guard = pyo3_async_runtimes::tokio::get_runtime().enter();
+    PreferredRuntime::current().map_err(tor_error)
+}
+
+/// A Tor client.
+///
+/// Create one with `await TorClient.create()`.
+#[pyclass(module = "arti")]
+struct TorClient {
+    /// The client that we wrap.
+    inner: arti_client::TorClient<PreferredRuntime>,
+}
+
+/// A report of how close a `TorClient` is to being ready for traffic.
+#[pyclass(module = "arti", frozen, get_all)]
+struct BootstrapStatus {
+    /// The fraction of bootstrapping that is done, between 0.0 and 1.0.
+    progress: f32,
+    /// True if the client is ready to make connections.
+    ready_for_traffic: bool,
+    /// If the client is stuck, a description of the problem.
+    blocked: Option<String>,
+    /// A human-readable description of the client's status.
+    description: String,
+}
+
+impl From<arti_client::status::BootstrapStatus> for BootstrapStatus {
+    fn from(status: arti_client::status::BootstrapStatus) -> Self {
+        BootstrapStatus {
+            progress: status.as_frac(),
+            ready_for_traffic: status.ready_for_traffic(),
+            blocked: status.blocked().map(|b| b.to_string()),
+            description: status.to_string(),
+        }
+    }
+}
+
+#[pymethods]
+impl BootstrapStatus {
+    fn __repr__(&self) -> String {
+        format!("<BootstrapStatus: {}>", self.description)
+    }
+}
+
+/// An asynchronous iterator over the changes to a `TorClient`'s bootstrap status.
+#[pyclass(module = "arti")]
+struct BootstrapEvents {
+    /// The events that we yield.
+    inner: Arc<Mutex<arti_client::status::BootstrapEvents>>,
+}
+
+#[pymethods]
+impl BootstrapEvents {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let inner = Arc::clone(&self.inner);
+        future_into_py(py, async move {
+            match inner.lock().await.next().await {
+                Some(status) => Ok(BootstrapStatus::from(status)),
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+}
+
+/// Return the configuration for a client that keeps its state in `state_dir`
+/// and its cache in `cache_dir`.
+///
+/// If both are `None`, use the default configuration.
+fn client_config(
+    state_dir: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+) -> PyResult<TorClientConfig> {
+    match (state_dir, cache_dir) {
+        (None, None) => Ok(TorClientConfig::default()),
+        (Some(state_dir), Some(cache_dir)) => {
+            TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .map_err(tor_error)
+        }
+        (_, _) => Err(PyValueError::new_err(
+            "state_dir and cache_dir must be given together",
+        )),
+    }
+}
+
+#[pymethods]
+impl TorClient {
+    /// Create a client, without bootstrapping it.
+    ///
+    /// If `state_dir` and `cache_dir` are given, the client keeps its
+    /// persistent state and its cache in them; otherwise, it uses the default
+    /// directories.
+    ///
+    /// The client bootstraps itself when it is first used; await `bootstrap()`
+    /// to do so earlier.
+    #[staticmethod]
+    #[pyo3(signature = (state_dir = None, cache_dir = None))]
+    fn create(
+        py: Python<'_>,
+        state_dir: Option<PathBuf>,
+        cache_dir: Option<PathBuf>,
+    ) -> PyResult<Bound<'_, PyAny>> {
+        let config = client_config(state_dir, cache_dir)?;
+        let runtime = runtime()?;
+        future_into_py(py, async move {
+            let inner = arti_client::TorClient::with_runtime(runtime)
+                .config(config)
+                .create_unbootstrapped_async()
+                .await
+                .map_err(tor_error)?;
+            Ok(TorClient { inner })
+        })
+    }
+
+    /// Bootstrap the client, if it isn't bootstrapped already.
+    ///
+    /// The awaitable finishes once the client has enough directory information
+    /// to connect safely over the Tor network.
+    fn bootstrap<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(
+            py,
+            async move { inner.bootstrap().await.map_err(tor_error) },
+        )
+    }
+
+    /// Return the client's current bootstrap status.
+    fn bootstrap_status(&self) -> BootstrapStatus {
+        self.inner.bootstrap_status().into()
+    }
+
+    /// Return an asynchronous iterator over the changes to the client's
+    /// bootstrap status.
+    fn bootstrap_events(&self) -> BootstrapEvents {
+        BootstrapEvents {
+            inner: Arc::new(Mutex::new(self.inner.bootstrap_events())),
+        }
+    }
+
+    /// Return a new client that shares this one's state, but whose streams
+    /// are isolated from this one's.
+    fn isolated_client(&self) -> TorClient {
+        TorClient {
+            inner: self.inner.isolated_client(),
+        }
+    }
+
+    /// Open an anonymized connection to `host` and `port` over the Tor network.
+    ///
+    /// `host` may be a DNS name, an IP address, or an onion address.
+    /// DNS names are resolved by the exit relay.
+    ///
+    /// The awaitable returns a `DataStream`.
+    fn connect<'py>(
+        &self,
+        py: Python<'py>,
+        host: String,
+        port: u16,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let stream = inner
+                .connect((host.as_str(), port))
+                .await
+                .map_err(tor_error)?;
+            Ok(DataStream::new(stream))
+        })
+    }
+
+    /// Launch an onion service.
+    ///
+    /// The service's keys and state are stored under `nickname`, so use the
+    /// same one every time to keep the same onion address.
+    #[pyo3(signature = (nickname, num_intro_points = None))]
+    fn launch_onion_service(
+        &self,
+        nickname: &str,
+        num_intro_points: Option<u8>,
+    ) -> PyResult<OnionService> {
+        let nickname: HsNickname = nickname
+            .parse()
+            .map_err(|e: tor_hsservice::InvalidNickname| PyValueError::new_err(e.to_string()))?;
+        let mut builder = OnionServiceConfigBuilder::default();
+        builder.nickname(nickname);
+        if let Some(n) = num_intro_points {
+            builder.num_intro_points(n);
+        }
+        let config = builder.build().map_err(tor_error)?;
+        let (service, rend_requests) =
+            self.inner.launch_onion_service(config).map_err(tor_error)?;
+        Ok(OnionService::new(
+            service,
+            tor_hsservice::handle_rend_requests(rend_requests).boxed(),
+        ))
+    }
+}
+
+/// The native part of the `arti` Python package.
+#[pymodule]
+fn _arti(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<TorClient>()?;
+    m.add_class::<BootstrapStatus>()?;
+    m.add_class::<BootstrapEvents>()?;
+    m.add_class::<DataStream>()?;
+    m.add_class::<OnionService>()?;
+    m.add("TorError", m.py().get_type::<TorError>())?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+//! Onion services.
+
+use std::sync::Arc;
+
+use futures::lock::Mutex;
+use futures::stream::{BoxStream, StreamExt as _};
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::future_into_py;
+use tor_cell::relaycell::msg::{Connected, End, EndReason};
+use tor_hsservice::{RunningOnionService, StreamRequest};
+use tor_proto::stream::IncomingStreamRequest;
+
+use crate::err::tor_error;
+use crate::stream::DataStream;
+
+/// A running onion service, launched with `TorClient.launch_onion_service()`.
+///
+/// The service keeps running for as long as this object exists.
+#[pyclass(module = "arti")]
+pub(crate) struct OnionService {
+    /// The service itself.
+    service: Arc<RunningOnionService>,
+    /// The stream requests that clients have made to this service.
+    requests: Arc<Mutex<BoxStream<'static, StreamRequest>>>,
+}
+
+impl OnionService {
+    /// Wrap `service`, whose stream requests are `requests`.
+    pub(crate) fn new(
+        service: Arc<RunningOnionService>,
+        requests: BoxStream<'static, StreamRequest>,
+    ) -> Self {
+        OnionService {
+            service,
+            requests: Arc::new(Mutex::new(requests)),
+        }
+    }
+}
+
+/// Wait for the next data stream in `requests`, and accept it.
+///
+/// Returns the port that the client asked for, and the stream.
+async fn accept_next(
+    requests: &Mutex<BoxStream<'static, StreamRequest>>,
+) -> PyResult<Option<(u16, DataStream)>> {
+    let mut requests = requests.lock().await;
+    while let Some(request) = requests.next().await {
+        let port = match request.request() {
+            IncomingStreamRequest::Begin(begin) => begin.port(),
+            _ => {
+                // We only support data streams.
+                let end = End::new_with_reason(EndReason::DONE);
+                request.reject(end).await.map_err(tor_error)?;
+                continue;
+            }
+        };
+        let stream = request
+            .accept(Connected::new_empty())
+            .await
+            .map_err(tor_error)?;
+        return Ok(Some((port, DataStream::new(stream))));
+    }
+    Ok(None)
+}
+
+#[pymethods]
+impl OnionService {
+    /// The onion address of this service, like `"xyz….onion"`.
+    ///
+    /// `None` if the service's identity key could not be found.
+    #[getter]
+    fn onion_address(&self) -> Option<String> {
+        self.service.onion_address().map(|hsid| hsid.to_string())
+    }
+
+    /// Wait for a client to open a stream to this service, and accept it.
+    ///
+    /// The awaitable returns a `(port, DataStream)` tuple, with the port that
+    /// the client asked for; or `None` once the service has stopped.
+    fn accept<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let requests = Arc::clone(&self.requests);
+        future_into_py(py, async move { accept_next(&requests).await })
+    }
+}
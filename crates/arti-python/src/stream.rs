@@ -0,0 +1,93 @@
+//! Anonymized streams.
+
+use std::sync::Arc;
+
+use arti_client::{DataReader, DataStream as ArtiDataStream, DataWriter};
+use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use futures::lock::Mutex;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3_async_runtimes::tokio::future_into_py;
+
+use crate::err::tor_error;
+
+/// The number of bytes that [`DataStream::read`] reads by default.
+const DEFAULT_READ_LEN: usize = 64 * 1024;
+
+/// An anonymized stream over the Tor network.
+///
+/// Every method returns an awaitable.  One task may read from the stream
+/// while another one writes to it.
+///
+/// To use the stream with code that expects `asyncio` streams,
+/// see `arti.open_connection`.
+#[pyclass(module = "arti")]
+pub(crate) struct DataStream {
+    /// The half of the stream that we read from.
+    reader: Arc<Mutex<DataReader>>,
+    /// The half of the stream that we write to.
+    writer: Arc<Mutex<DataWriter>>,
+}
+
+impl DataStream {
+    /// Wrap `stream`.
+    pub(crate) fn new(stream: ArtiDataStream) -> Self {
+        let (reader, writer) = stream.split();
+        DataStream {
+            reader: Arc::new(Mutex::new(reader)),
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+#[pymethods]
+impl DataStream {
+    /// Read up to `n` bytes from the stream.
+    ///
+    /// Returns `b""` once the stream has been closed by the other side.
+    #[pyo3(signature = (n = DEFAULT_READ_LEN))]
+    fn read<'py>(&self, py: Python<'py>, n: usize) -> PyResult<Bound<'py, PyAny>> {
+        let reader = Arc::clone(&self.reader);
+        future_into_py(py, async move {
+            let mut buf = vec![0_u8; n];
+            let n_read = reader
+                .lock()
+                .await
+                .read(&mut buf)
+                .await
+                .map_err(tor_error)?;
+            Python::with_gil(|py| Ok(PyBytes::new(py, &buf[..n_read]).unbind()))
+        })
+    }
+
+    /// Write all of `data` to the stream.
+    ///
+    /// The data may be buffered: await `flush()` to make sure that it has been sent.
+    fn write<'py>(&self, py: Python<'py>, data: Vec<u8>) -> PyResult<Bound<'py, PyAny>> {
+        let writer = Arc::clone(&self.writer);
+        future_into_py(py, async move {
+            writer
+                .lock()
+                .await
+                .write_all(&data)
+                .await
+                .map_err(tor_error)
+        })
+    }
+
+    /// Send any data that has been written, but not sent yet.
+    fn flush<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let writer = Arc::clone(&self.writer);
+        future_into_py(py, async move {
+            writer.lock().await.flush().await.map_err(tor_error)
+        })
+    }
+
+    /// Flush the stream, and close it for writing.
+    fn close<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let writer = Arc::clone(&self.writer);
+        future_into_py(py, async move {
+            writer.lock().await.close().await.map_err(tor_error)
+        })
+    }
+}
@@ -6,13 +6,13 @@
 pub mod err;
 mod util;
 
+use arti_ffi_util::{
+    ffi_body_raw, ffi_body_with_err, OptOutPtrExt as _, OptOutValExt, OutPtr, OutVal,
+};
 use err::{ArtiRpcError, InvalidInput};
 use std::ffi::{c_char, c_int};
 use std::sync::Mutex;
-use util::{
-    ffi_body_raw, ffi_body_with_err, OptOutPtrExt as _, OptOutValExt, OutPtr, OutSocketOwned,
-    OutVal,
-};
+use util::OutSocketOwned;
 
 use crate::{
     conn::{AnyResponse, RequestHandle},
@@ -591,13 +591,13 @@ pub unsafe extern "C" fn arti_rpc_conn_open_stream(
             let on_object: Option<&str> [in_str_opt];
             let hostname: Option<&str> [in_str_opt];
             let isolation: Option<&str> [in_str_opt];
-            let socket_out: Option<OutSocketOwned<'_>> [out_socket_owned_opt];
+            let socket_out: Option<OutVal<ArtiRpcRawSocket>> [out_val_opt];
             let stream_id_out: Option<OutPtr<ArtiRpcStr>> [out_ptr_opt];
             err error_out: Option<OutPtr<ArtiRpcError>>;
         } in {
             let rpc_conn = rpc_conn.ok_or(InvalidInput::NullPointer)?;
             let hostname = hostname.ok_or(InvalidInput::NullPointer)?;
-            let socket_out = socket_out.ok_or(InvalidInput::NullPointer)?;
+            let socket_out = OutSocketOwned::from(socket_out.ok_or(InvalidInput::NullPointer)?);
             let isolation = isolation.ok_or(InvalidInput::NullPointer)?;
 
             let port: u16 = port.try_into().map_err(|_| InvalidInput::BadPort)?;
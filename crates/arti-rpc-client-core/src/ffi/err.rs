@@ -1,18 +1,19 @@
 //! Error handling logic for our ffi code.
 
+use arti_ffi_util::{ffi_body_raw, FfiStatusError};
 use paste::paste;
 use std::error::Error as StdError;
 use std::ffi::{c_char, c_int, CStr};
 use std::fmt::Display;
 use std::io::Error as IoError;
-use std::panic::{catch_unwind, UnwindSafe};
 
 use crate::conn::ErrorResponse;
 use crate::util::Utf8CString;
 
-use super::util::{ffi_body_raw, OptOutPtrExt as _, OutPtr};
 use super::ArtiRpcStatus;
 
+pub(super) use arti_ffi_util::InvalidInput;
+
 /// Helper:
 /// Given a restricted enum defining FfiStatus, also define a series of constants for its variants,
 /// and a string conversion function.
@@ -270,30 +271,11 @@ impl From<void::Void> for FfiError {
         void::unreachable(value)
     }
 }
-
-/// Tried to call a ffi function with a not-permitted argument.
-#[derive(Clone, Debug, thiserror::Error)]
-pub(super) enum InvalidInput {
-    /// Tried to convert a NULL pointer to an FFI object.
-    #[error("Provided argument was NULL.")]
-    NullPointer,
-
-    /// Tried to convert a non-UTF string.
-    #[error("Provided string was not UTF-8")]
-    BadUtf8,
-
-    /// Tried to use an invalid port.
-    #[error("Port was not in range 1..65535")]
-    BadPort,
-
-    /// Tried to use an invalid constant
-    #[error("Provided constant was not recognized")]
-    InvalidConstValue,
-}
-
-impl From<void::Void> for InvalidInput {
-    fn from(value: void::Void) -> Self {
-        void::unreachable(value)
+impl FfiStatusError for FfiError {
+    type Status = ArtiRpcStatus;
+    const SUCCESS: ArtiRpcStatus = ARTI_RPC_STATUS_SUCCESS;
+    fn status(&self) -> ArtiRpcStatus {
+        self.status
     }
 }
 
@@ -584,39 +566,3 @@ pub unsafe extern "C" fn arti_rpc_err_free(err: *mut ArtiRpcError) {
         }
     );
 }
-
-/// Run `body` and catch panics.  If one occurs, return the result of `on_err` instead.
-///
-/// We wrap the body of every C ffi function with this function
-/// (or with `handle_errors`, which uses this function),
-/// even if we do not think that the body can actually panic.
-pub(super) fn abort_on_panic<F, T>(body: F) -> T
-where
-    F: FnOnce() -> T + UnwindSafe,
-{
-    #[allow(clippy::print_stderr)]
-    match catch_unwind(body) {
-        Ok(x) => x,
-        Err(_panic_info) => {
-            eprintln!("Internal panic in arti-rpc library: aborting!");
-            std::process::abort();
-        }
-    }
-}
-
-/// Call `body`, converting any errors or panics that occur into an FfiError,
-/// and storing that error in `error_out`.
-pub(super) fn handle_errors<F>(error_out: Option<OutPtr<FfiError>>, body: F) -> ArtiRpcStatus
-where
-    F: FnOnce() -> Result<(), FfiError> + UnwindSafe,
-{
-    match abort_on_panic(body) {
-        Ok(()) => ARTI_RPC_STATUS_SUCCESS,
-        Err(e) => {
-            // "body" returned an error.
-            let status = e.status;
-            error_out.write_boxed_value_if_ptr_set(e);
-            status
-        }
-    }
-}
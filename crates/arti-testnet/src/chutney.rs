@@ -0,0 +1,312 @@
+//! Launch and manage a chutney test network.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arti_client::TorClientConfig;
+use tor_config::sources::MustRead;
+use tor_config::{ConfigurationSource, ConfigurationSources};
+use tracing::{debug, info, warn};
+
+use crate::{Error, Result};
+
+/// The network we launch if none is specified.
+const DEFAULT_NETWORK: &str = "basic";
+
+/// How long we give the network to bootstrap by default.
+///
+/// This matches the value used by `tests/chutney/setup`.
+const DEFAULT_BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Configuration for launching a chutney test network.
+///
+/// Use [`Chutney::from_env`] to create one, adjust it as needed, and then
+/// call [`Chutney::start`].
+#[derive(Clone, Debug)]
+pub struct Chutney {
+    /// Path to the chutney executable.
+    bin: PathBuf,
+    /// Directory in which chutney keeps the state of its networks.
+    data_dir: PathBuf,
+    /// The name (or path) of the chutney network configuration to launch.
+    network: String,
+    /// How long to wait for the network to bootstrap.
+    bootstrap_timeout: Duration,
+}
+
+impl Chutney {
+    /// Find chutney using the same rules as `tests/chutney/setup`.
+    ///
+    /// We use `CHUTNEY_BIN` if it is set, and otherwise look for `chutney`
+    /// on the `PATH`.  If `CHUTNEY_DATA_DIR` is set, we use it as the data
+    /// directory; otherwise, we use the current directory.
+    pub fn from_env() -> Result<Self> {
+        let bin = match std::env::var_os("CHUTNEY_BIN") {
+            Some(bin) if !bin.is_empty() => PathBuf::from(bin),
+            _ => find_on_path("chutney").ok_or(Error::ChutneyNotFound)?,
+        };
+        if !bin.is_file() {
+            return Err(Error::ChutneyNotFound);
+        }
+        let data_dir = match std::env::var_os("CHUTNEY_DATA_DIR") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => std::env::current_dir().map_err(|e| Error::NoDataDir(Arc::new(e)))?,
+        };
+        Ok(Self::new(bin, data_dir))
+    }
+
+    /// Use the chutney executable at `bin`, storing network state in
+    /// `data_dir`.
+    pub fn new(bin: impl Into<PathBuf>, data_dir: impl Into<PathBuf>) -> Self {
+        Chutney {
+            bin: bin.into(),
+            data_dir: data_dir.into(),
+            network: DEFAULT_NETWORK.to_owned(),
+            bootstrap_timeout: DEFAULT_BOOTSTRAP_TIMEOUT,
+        }
+    }
+
+    /// Set the chutney network configuration to launch.
+    ///
+    /// The default is `basic`.
+    pub fn network(mut self, network: impl Into<String>) -> Self {
+        self.network = network.into();
+        self
+    }
+
+    /// Set how long to wait for the network to bootstrap.
+    pub fn bootstrap_timeout(mut self, timeout: Duration) -> Self {
+        self.bootstrap_timeout = timeout;
+        self
+    }
+
+    /// Configure and start the network, and wait for it to bootstrap.
+    ///
+    /// If bootstrapping fails, the network is stopped again before we
+    /// return the error.
+    pub fn start(self) -> Result<Network> {
+        info!("Launching chutney network {:?}", self.network);
+        self.run("configure", &[])?;
+        self.run("start", &[])?;
+
+        // From here on, dropping the handle will tear the network down.
+        let network = Network {
+            chutney: self,
+            running: true,
+        };
+        let timeout = network.chutney.bootstrap_timeout.as_secs().to_string();
+        network
+            .chutney
+            .run("wait_for_bootstrap", &[("CHUTNEY_START_TIME", timeout)])?;
+        info!("Chutney network {:?} bootstrapped", network.chutney.network);
+        Ok(network)
+    }
+
+    /// Run the chutney subcommand `cmd` on our network, with the extra
+    /// environment variables in `env`.
+    fn run(&self, cmd: &str, env: &[(&str, String)]) -> Result<()> {
+        debug!("Running chutney {} {}", cmd, self.network);
+        let mut command = Command::new(&self.bin);
+        command.arg(cmd).arg(&self.network);
+        // Always tell chutney where to keep its state, so that we know where
+        // to find it.
+        command.env("CHUTNEY_DATA_DIR", &self.data_dir);
+        command.envs(env.iter().cloned());
+
+        let status = command.status().map_err(|e| Error::Spawn {
+            cmd: cmd.to_owned(),
+            source: Arc::new(e),
+        })?;
+        if !status.success() {
+            return Err(Error::CommandFailed {
+                cmd: cmd.to_owned(),
+                status,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A running chutney test network.
+///
+/// The network is stopped when this handle is dropped; use
+/// [`Network::stop`] to find out whether stopping it succeeded.
+#[derive(Debug)]
+pub struct Network {
+    /// The configuration we used to launch this network.
+    chutney: Chutney,
+    /// True if we have not yet stopped this network.
+    running: bool,
+}
+
+impl Network {
+    /// Return the path of the Arti configuration file that chutney
+    /// generated for this network.
+    ///
+    /// This file contains the fallback directories and directory
+    /// authorities of the test network, along with a SOCKS port for an
+    /// Arti proxy.
+    pub fn arti_config_file(&self) -> PathBuf {
+        self.nodes_dir().join("arti.toml")
+    }
+
+    /// Return the directory in which chutney keeps the state of this
+    /// network's nodes.
+    pub fn nodes_dir(&self) -> PathBuf {
+        self.chutney.data_dir.join("nodes")
+    }
+
+    /// Return a set of configuration sources that will configure Arti to
+    /// use this network.
+    ///
+    /// Callers can push further sources or options onto the result before
+    /// loading it; those take precedence over the generated file.
+    pub fn config_sources(&self) -> Result<ConfigurationSources> {
+        let path = self.arti_config_file();
+        if !path.is_file() {
+            return Err(Error::NoArtiConfig(path));
+        }
+        let mut sources = ConfigurationSources::new_empty();
+        sources.push_source(ConfigurationSource::from_path(path), MustRead::MustRead);
+        Ok(sources)
+    }
+
+    /// Return a client configuration that will use this network.
+    pub fn client_config(&self) -> Result<TorClientConfig> {
+        let cfg = self.config_sources()?.load()?;
+        Ok(tor_config::resolve(cfg)?)
+    }
+
+    /// Stop the network.
+    pub fn stop(mut self) -> Result<()> {
+        self.running = false;
+        info!("Stopping chutney network {:?}", self.chutney.network);
+        self.chutney.run("stop", &[])
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        if self.running {
+            self.running = false;
+            if let Err(e) = self.chutney.run("stop", &[]) {
+                warn!("Unable to stop chutney network: {}", e);
+            }
+        }
+    }
+}
+
+/// Look for an executable called `name` in the directories on the `PATH`.
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    let path: OsString = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Return true if `path` is a file that we can (probably) execute.
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use std::os::unix::fs::PermissionsExt as _;
+
+    /// A stand-in for chutney, which logs the commands it is given, and
+    /// writes an Arti configuration on `configure`.
+    ///
+    /// It fails if it isn't told where to keep its state.
+    const FAKE_CHUTNEY: &str = r#"#!/bin/sh
+set -e
+: "${CHUTNEY_DATA_DIR:?}"
+echo "$1 $2" >> "$CHUTNEY_DATA_DIR/log"
+if [ "$1" = configure ]; then
+    mkdir -p "$CHUTNEY_DATA_DIR/nodes"
+    printf '[address_filter]\nallow_local_addrs = true\n' \
+        > "$CHUTNEY_DATA_DIR/nodes/arti.toml"
+fi
+if [ "$1" = wait_for_bootstrap ] && [ -e "$CHUTNEY_DATA_DIR/fail" ]; then
+    exit 1
+fi
+"#;
+
+    /// Install `FAKE_CHUTNEY` in `dir`, and return its path.
+    fn fake_chutney(dir: &Path) -> PathBuf {
+        let bin = dir.join("chutney");
+        std::fs::write(&bin, FAKE_CHUTNEY).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        bin
+    }
+
+    /// Return the commands that the fake chutney in `data_dir` has run.
+    fn log(data_dir: &Path) -> String {
+        std::fs::read_to_string(data_dir.join("log")).unwrap()
+    }
+
+    #[test]
+    fn lifecycle() {
+        let bin_dir = tempfile::tempdir().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let bin = fake_chutney(bin_dir.path());
+
+        let network = Chutney::new(bin, data_dir.path()).start().unwrap();
+        assert_eq!(network.nodes_dir(), data_dir.path().join("nodes"));
+        assert!(network.arti_config_file().is_file());
+        let _config: TorClientConfig = network.client_config().unwrap();
+        assert_eq!(
+            log(data_dir.path()),
+            "configure basic\nstart basic\nwait_for_bootstrap basic\n"
+        );
+
+        network.stop().unwrap();
+        assert!(log(data_dir.path()).ends_with("wait_for_bootstrap basic\nstop basic\n"));
+    }
+
+    #[test]
+    fn stop_on_drop() {
+        let bin_dir = tempfile::tempdir().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let bin = fake_chutney(bin_dir.path());
+
+        let network = Chutney::new(&bin, data_dir.path())
+            .network("other")
+            .start()
+            .unwrap();
+        drop(network);
+        assert!(log(data_dir.path()).ends_with("stop other\n"));
+
+        // If bootstrapping fails, we stop the network before returning.
+        let data_dir = tempfile::tempdir().unwrap();
+        std::fs::write(data_dir.path().join("fail"), "").unwrap();
+        let err = Chutney::new(&bin, data_dir.path()).start().unwrap_err();
+        assert!(matches!(err, Error::CommandFailed { cmd, .. } if cmd == "wait_for_bootstrap"));
+        assert!(log(data_dir.path()).ends_with("wait_for_bootstrap basic\nstop basic\n"));
+    }
+}
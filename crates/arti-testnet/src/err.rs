@@ -0,0 +1,56 @@
+//! Declare an error type for the arti-testnet crate.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+/// An error that occurred while launching or using a test network.
+#[derive(Debug, Clone, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// We couldn't find the chutney executable.
+    #[error("Couldn't locate chutney. Ensure it's on PATH or set CHUTNEY_BIN.")]
+    ChutneyNotFound,
+
+    /// We couldn't run a chutney command at all.
+    #[error("Unable to run {cmd:?}")]
+    Spawn {
+        /// The chutney subcommand we were trying to run.
+        cmd: String,
+        /// The underlying IO error.
+        #[source]
+        source: Arc<std::io::Error>,
+    },
+
+    /// We couldn't find a directory for chutney to keep its state in.
+    #[error("Unable to find the current directory for CHUTNEY_DATA_DIR")]
+    NoDataDir(#[source] Arc<std::io::Error>),
+
+    /// A chutney command ran, but reported failure.
+    #[error("{cmd:?} failed ({status})")]
+    CommandFailed {
+        /// The chutney subcommand that failed.
+        cmd: String,
+        /// The exit status of the command.
+        status: std::process::ExitStatus,
+    },
+
+    /// Chutney didn't generate an Arti configuration for the network.
+    #[error("No Arti configuration found at {}", .0.display())]
+    NoArtiConfig(PathBuf),
+
+    /// We couldn't load the Arti configuration for the network.
+    #[error("Unable to load Arti configuration")]
+    Config(#[from] tor_config::ConfigError),
+
+    /// We couldn't resolve the Arti configuration for the network.
+    #[error("Invalid Arti configuration")]
+    Resolve(#[source] Arc<tor_config::load::ConfigResolveError>),
+}
+
+impl From<tor_config::load::ConfigResolveError> for Error {
+    fn from(e: tor_config::load::ConfigResolveError) -> Self {
+        Error::Resolve(Arc::new(e))
+    }
+}
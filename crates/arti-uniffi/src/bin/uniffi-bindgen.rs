@@ -0,0 +1,8 @@
+//! Generate the Kotlin and Swift bindings for `arti-uniffi`.
+//!
+//! Run this with `--library` and the path to the built library;
+//! see the crate's README.
+
+fn main() {
+    uniffi::uniffi_bindgen_main();
+}
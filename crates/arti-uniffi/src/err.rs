@@ -0,0 +1,146 @@
+//! Errors that we expose to Kotlin and Swift.
+
+use tor_error::{ErrorKind, ErrorReport as _, HasKind};
+
+/// An error from a [`TorClient`](crate::TorClient) or from one of the objects
+/// that it returns.
+///
+/// Every variant carries a human-readable message.  The format of these
+/// messages is not stable.
+#[derive(Clone, Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+#[non_exhaustive]
+pub enum TorError {
+    /// An argument was invalid.
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// The client's configuration was invalid, or we couldn't use its
+    /// state or cache directory.
+    #[error("Configuration problem: {0}")]
+    Config(String),
+
+    /// The client couldn't bootstrap.
+    #[error("Unable to bootstrap: {0}")]
+    Bootstrap(String),
+
+    /// We couldn't make a connection.
+    #[error("Unable to connect: {0}")]
+    Connect(String),
+
+    /// We couldn't read from or write to a stream.
+    #[error("Stream error: {0}")]
+    Stream(String),
+
+    /// We couldn't run an onion service.
+    #[error("Onion service error: {0}")]
+    OnionService(String),
+
+    /// Some other error occurred.
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Return a message describing `e`, and its sources.
+fn message(e: &(dyn std::error::Error + 'static)) -> String {
+    let msg = e.report().to_string();
+    msg.strip_prefix("error: ")
+        .map(str::to_string)
+        .unwrap_or(msg)
+}
+
+impl From<arti_client::Error> for TorError {
+    fn from(e: arti_client::Error) -> Self {
+        use ErrorKind as EK;
+        let msg = message(&e);
+        match e.kind() {
+            EK::InvalidConfig
+            | EK::InvalidConfigTransition
+            | EK::NoHomeDirectory
+            | EK::PersistentStateAccessFailed
+            | EK::PersistentStateCorrupted
+            | EK::CacheAccessFailed
+            | EK::CacheCorrupted
+            | EK::KeystoreAccessFailed
+            | EK::KeystoreCorrupted
+            | EK::LocalResourceAlreadyInUse
+            | EK::FsPermissions => TorError::Config(msg),
+            EK::BootstrapRequired
+            | EK::DirectoryExpired
+            | EK::TorDirectoryUnusable
+            | EK::ClockSkew => TorError::Bootstrap(msg),
+            EK::InvalidStreamTarget
+            | EK::ForbiddenStreamTarget
+            | EK::OnionServiceAddressInvalid => TorError::InvalidArgument(msg),
+            EK::TorAccessFailed
+            | EK::TorNetworkTimeout
+            | EK::RemoteConnectionRefused
+            | EK::RemoteHostNotFound
+            | EK::RemoteHostResolutionFailed
+            | EK::RemoteNetworkFailed
+            | EK::RemoteNetworkTimeout
+            | EK::RemoteStreamClosed
+            | EK::RemoteStreamReset
+            | EK::RemoteStreamError
+            | EK::ExitPolicyRejected
+            | EK::ExitTimeout
+            | EK::NoPath
+            | EK::NoExit
+            | EK::OnionServiceNotFound
+            | EK::OnionServiceNotRunning
+            | EK::OnionServiceConnectionFailed
+            | EK::OnionServiceMissingClientAuth
+            | EK::OnionServiceWrongClientAuth => TorError::Connect(msg),
+            _ => TorError::Other(msg),
+        }
+    }
+}
+
+impl From<arti_client::config::ConfigBuildError> for TorError {
+    fn from(e: arti_client::config::ConfigBuildError) -> Self {
+        TorError::Config(message(&e))
+    }
+}
+
+impl From<std::io::Error> for TorError {
+    fn from(e: std::io::Error) -> Self {
+        TorError::Stream(message(&e))
+    }
+}
+
+impl From<tor_hsservice::InvalidNickname> for TorError {
+    fn from(e: tor_hsservice::InvalidNickname) -> Self {
+        TorError::InvalidArgument(message(&e))
+    }
+}
+
+impl From<tor_hsservice::ClientError> for TorError {
+    fn from(e: tor_hsservice::ClientError) -> Self {
+        TorError::OnionService(message(&e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn io_errors() {
+        let e: TorError = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "gone").into();
+        assert!(matches!(&e, TorError::Stream(m) if m == "gone"));
+        assert_eq!(e.to_string(), "Stream error: gone");
+    }
+}
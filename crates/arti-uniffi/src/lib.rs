@@ -0,0 +1,238 @@
+#![doc = include_str!("../README.md")]
+// @@ begin lint list maintained by maint/add_warning @@
+#![allow(renamed_and_removed_lints)] // @@REMOVE_WHEN(ci_arti_stable)
+#![allow(unknown_lints)] // @@REMOVE_WHEN(ci_arti_nightly)
+#![warn(missing_docs)]
+#![warn(noop_method_call)]
+#![warn(unreachable_pub)]
+#![warn(clippy::all)]
+#![deny(clippy::await_holding_lock)]
+#![deny(clippy::cargo_common_metadata)]
+#![deny(clippy::cast_lossless)]
+#![deny(clippy::checked_conversions)]
+#![warn(clippy::cognitive_complexity)]
+#![deny(clippy::debug_assert_with_mut_call)]
+#![deny(clippy::exhaustive_enums)]
+#![deny(clippy::exhaustive_structs)]
+#![deny(clippy::expl_impl_clone_on_copy)]
+#![deny(clippy::fallible_impl_from)]
+#![deny(clippy::implicit_clone)]
+#![deny(clippy::large_stack_arrays)]
+#![warn(clippy::manual_ok_or)]
+#![deny(clippy::missing_docs_in_private_items)]
+#![warn(clippy::needless_borrow)]
+#![warn(clippy::needless_pass_by_value)]
+#![warn(clippy::option_option)]
+#![deny(clippy::print_stderr)]
+#![deny(clippy::print_stdout)]
+#![warn(clippy::rc_buffer)]
+#![deny(clippy::ref_option_ref)]
+#![warn(clippy::semicolon_if_nothing_returned)]
+#![warn(clippy::trait_duplication_in_bounds)]
+#![deny(clippy::unchecked_duration_subtraction)]
+#![deny(clippy::unnecessary_wraps)]
+#![warn(clippy::unseparated_literal_suffix)]
+#![deny(clippy::unwrap_used)]
+#![deny(clippy::mod_module_files)]
+#![allow(clippy::let_unit_value)] // This can reasonably be done for explicitness
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::significant_drop_in_scrutinee)] // arti/-/merge_requests/588/#note_2812945
+#![allow(clippy::result_large_err)] // temporary workaround for arti#587
+#![allow(clippy::needless_raw_string_hashes)] // complained-about code is fine, often best
+#![allow(clippy::needless_lifetimes)] // See arti#1765
+//! <!-- @@ end lint list maintained by maint/add_warning @@ -->
+
+mod err;
+mod onion;
+mod stream;
+
+use std::sync::Arc;
+
+use arti_client::blocking::TorClientBlocking;
+use arti_client::config::TorClientConfigBuilder;
+use futures::task::SpawnExt as _;
+use futures::StreamExt as _;
+
+pub use err::TorError;
+pub use onion::{IncomingStream, OnionService, OnionServiceConfig};
+pub use stream::TorStream;
+
+uniffi::setup_scaffolding!();
+
+/// The configuration for a [`TorClient`].
+#[derive(Clone, Debug, uniffi::Record)]
+#[non_exhaustive]
+pub struct ClientConfig {
+    /// A directory in which the client keeps its persistent state.
+    ///
+    /// On Android, use a subdirectory of `Context.getFilesDir()`;
+    /// on iOS, one of the Application Support directory.
+    pub state_dir: String,
+    /// A directory in which the client caches directory information.
+    pub cache_dir: String,
+}
+
+/// A report of how close a [`TorClient`] is to being ready for traffic.
+#[derive(Clone, Debug, uniffi::Record)]
+#[non_exhaustive]
+pub struct BootstrapStatus {
+    /// The fraction of bootstrapping that is done, between 0.0 and 1.0.
+    pub progress: f32,
+    /// True if the client is ready to make connections.
+    pub ready_for_traffic: bool,
+    /// If the client is stuck, a description of the problem.
+    pub blocked: Option<String>,
+    /// A human-readable description of the client's status.
+    pub description: String,
+}
+
+impl From<arti_client::status::BootstrapStatus> for BootstrapStatus {
+    fn from(status: arti_client::status::BootstrapStatus) -> Self {
+        BootstrapStatus {
+            progress: status.as_frac(),
+            ready_for_traffic: status.ready_for_traffic(),
+            blocked: status.blocked().map(|b| b.to_string()),
+            description: status.to_string(),
+        }
+    }
+}
+
+/// An object that is told about changes to a [`TorClient`]'s bootstrap status.
+///
+/// Implement this in Kotlin or Swift, and pass it to
+/// [`TorClient::watch_bootstrap_events`].
+#[uniffi::export(callback_interface)]
+pub trait BootstrapListener: Send + Sync {
+    /// The client's bootstrap status is now `status`.
+    ///
+    /// This is called from a thread that belongs to the client:
+    /// it must return quickly, and must not call back into the client.
+    fn on_status(&self, status: BootstrapStatus);
+}
+
+/// A Tor client.
+///
+/// Cloning a `TorClient` (with [`isolated_client`](TorClient::isolated_client))
+/// gives another handle to the same client.
+#[derive(uniffi::Object)]
+pub struct TorClient {
+    /// The client that we wrap.
+    inner: TorClientBlocking,
+}
+
+#[uniffi::export]
+impl TorClient {
+    /// Create a client with the provided `config`, without bootstrapping it.
+    ///
+    /// The client bootstraps itself when it is first used; call
+    /// [`bootstrap`](TorClient::bootstrap) to do so earlier.
+    #[uniffi::constructor]
+    pub fn new(config: ClientConfig) -> Result<Arc<Self>, TorError> {
+        let config =
+            TorClientConfigBuilder::from_directories(config.state_dir, config.cache_dir).build()?;
+        let inner = TorClientBlocking::create_unbootstrapped(config)?;
+        Ok(Arc::new(TorClient { inner }))
+    }
+
+    /// Bootstrap the client, if it isn't bootstrapped already.
+    ///
+    /// Returns once the client has enough directory information to connect
+    /// safely over the Tor network.
+    pub fn bootstrap(&self) -> Result<(), TorError> {
+        Ok(self.inner.bootstrap()?)
+    }
+
+    /// Return the client's current bootstrap status.
+    pub fn bootstrap_status(&self) -> BootstrapStatus {
+        self.inner.client().bootstrap_status().into()
+    }
+
+    /// Tell `listener` about every change to the client's bootstrap status,
+    /// for as long as the client exists.
+    pub fn watch_bootstrap_events(
+        &self,
+        listener: Box<dyn BootstrapListener>,
+    ) -> Result<(), TorError> {
+        let mut events = self.inner.client().bootstrap_events();
+        self.inner
+            .runtime()
+            .spawn(async move {
+                while let Some(status) = events.next().await {
+                    listener.on_status(status.into());
+                }
+            })
+            .map_err(|e| TorError::Other(e.to_string()))
+    }
+
+    /// Return a new client that shares this one's state, but whose streams
+    /// are isolated from this one's.
+    pub fn isolated_client(&self) -> Arc<TorClient> {
+        Arc::new(TorClient {
+            inner: self.inner.isolated_client(),
+        })
+    }
+
+    /// Open an anonymized connection to `host` and `port` over the Tor network.
+    ///
+    /// `host` may be a DNS name, an IP address, or an onion address.
+    /// DNS names are resolved by the exit relay, so no DNS requests leak
+    /// from the device.
+    pub fn connect(&self, host: String, port: u16) -> Result<Arc<TorStream>, TorError> {
+        let stream = self.inner.connect((host.as_str(), port))?;
+        Ok(Arc::new(TorStream::new(
+            self.inner.runtime().clone(),
+            stream.into_inner(),
+        )))
+    }
+
+    /// Launch an onion service with `config`.
+    ///
+    /// The service runs until the returned object is destroyed.
+    pub fn launch_onion_service(
+        &self,
+        config: OnionServiceConfig,
+    ) -> Result<Arc<OnionService>, TorError> {
+        Ok(Arc::new(OnionService::launch(&self.inner, config)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn create_unbootstrapped() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let client = TorClient::new(ClientConfig {
+            state_dir: state_dir.path().to_str().unwrap().into(),
+            cache_dir: cache_dir.path().to_str().unwrap().into(),
+        })
+        .unwrap();
+        let status = client.bootstrap_status();
+        assert!(!status.ready_for_traffic);
+        assert!(status.progress < 1.0);
+
+        let bad_service = OnionServiceConfig {
+            nickname: "not a nickname!".into(),
+            num_intro_points: None,
+        };
+        assert!(matches!(
+            client.launch_onion_service(bad_service),
+            Err(TorError::InvalidArgument(_))
+        ));
+    }
+}
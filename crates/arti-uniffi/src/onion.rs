@@ -0,0 +1,110 @@
+//! Onion services.
+
+use std::sync::Arc;
+
+use arti_client::blocking::TorClientBlocking;
+use arti_client::config::onion_service::OnionServiceConfigBuilder;
+use futures::lock::Mutex;
+use futures::stream::{BoxStream, StreamExt as _};
+use tor_cell::relaycell::msg::{Connected, End, EndReason};
+use tor_hsservice::{handle_rend_requests, HsNickname, RunningOnionService, StreamRequest};
+use tor_proto::stream::IncomingStreamRequest;
+use tor_rtcompat::{PreferredRuntime, ToplevelBlockOn as _};
+
+use crate::{TorError, TorStream};
+
+/// The configuration for an onion service.
+#[derive(Clone, Debug, uniffi::Record)]
+#[non_exhaustive]
+pub struct OnionServiceConfig {
+    /// The nickname of the service.
+    ///
+    /// The service's keys and state are stored under this name, so use the
+    /// same one every time to keep the same onion address.
+    pub nickname: String,
+    /// The number of introduction points to use, or `None` for the default.
+    pub num_intro_points: Option<u8>,
+}
+
+/// A stream that a client opened to an [`OnionService`].
+#[derive(uniffi::Record)]
+#[non_exhaustive]
+pub struct IncomingStream {
+    /// The port that the client asked for.
+    pub port: u16,
+    /// The stream itself.
+    pub stream: Arc<TorStream>,
+}
+
+/// A running onion service.
+///
+/// The service keeps running for as long as this object exists.
+#[derive(uniffi::Object)]
+pub struct OnionService {
+    /// The runtime of the client that launched this service.
+    runtime: PreferredRuntime,
+    /// The service itself.
+    service: Arc<RunningOnionService>,
+    /// The stream requests that clients have made to this service.
+    requests: Mutex<BoxStream<'static, StreamRequest>>,
+}
+
+impl OnionService {
+    /// Launch an onion service with `config`, using `client`.
+    pub(crate) fn launch(
+        client: &TorClientBlocking,
+        config: OnionServiceConfig,
+    ) -> Result<Self, TorError> {
+        let nickname: HsNickname = config.nickname.parse()?;
+        let mut builder = OnionServiceConfigBuilder::default();
+        builder.nickname(nickname);
+        if let Some(n) = config.num_intro_points {
+            builder.num_intro_points(n);
+        }
+        let (service, rend_requests) = client.client().launch_onion_service(builder.build()?)?;
+        Ok(OnionService {
+            runtime: client.runtime().clone(),
+            service,
+            requests: Mutex::new(handle_rend_requests(rend_requests).boxed()),
+        })
+    }
+
+    /// Implementation for [`accept`](OnionService::accept).
+    async fn accept_async(&self) -> Result<Option<IncomingStream>, TorError> {
+        let mut requests = self.requests.lock().await;
+        while let Some(request) = requests.next().await {
+            let port = match request.request() {
+                IncomingStreamRequest::Begin(begin) => begin.port(),
+                _ => {
+                    // We only support data streams.
+                    let end = End::new_with_reason(EndReason::DONE);
+                    request.reject(end).await?;
+                    continue;
+                }
+            };
+            let stream = request.accept(Connected::new_empty()).await?;
+            return Ok(Some(IncomingStream {
+                port,
+                stream: Arc::new(TorStream::new(self.runtime.clone(), stream)),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+#[uniffi::export]
+impl OnionService {
+    /// Return the onion address of this service, like `xyz….onion`.
+    ///
+    /// Returns `None` if the service's identity key could not be found.
+    pub fn onion_address(&self) -> Option<String> {
+        self.service.onion_address().map(|hsid| hsid.to_string())
+    }
+
+    /// Wait for a client to open a stream to this service, and accept it.
+    ///
+    /// Returns `None` once the service has stopped.
+    pub fn accept(&self) -> Result<Option<IncomingStream>, TorError> {
+        self.runtime.block_on(self.accept_async())
+    }
+}
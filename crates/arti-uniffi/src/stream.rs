@@ -0,0 +1,73 @@
+//! Anonymized streams.
+
+use arti_client::{DataReader, DataStream, DataWriter};
+use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use futures::lock::Mutex;
+use tor_rtcompat::{PreferredRuntime, ToplevelBlockOn as _};
+
+use crate::TorError;
+
+/// An anonymized stream over the Tor network.
+///
+/// One thread may read from the stream while another one writes to it.
+#[derive(uniffi::Object)]
+pub struct TorStream {
+    /// The runtime of the client that opened this stream.
+    runtime: PreferredRuntime,
+    /// The half of the stream that we read from.
+    reader: Mutex<DataReader>,
+    /// The half of the stream that we write to.
+    writer: Mutex<DataWriter>,
+}
+
+impl TorStream {
+    /// Wrap `stream`, which belongs to a client that uses `runtime`.
+    pub(crate) fn new(runtime: PreferredRuntime, stream: DataStream) -> Self {
+        let (reader, writer) = stream.split();
+        TorStream {
+            runtime,
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+#[uniffi::export]
+impl TorStream {
+    /// Read up to `max_len` bytes from the stream.
+    ///
+    /// Blocks until at least one byte is available.  Returns an empty array
+    /// once the stream has been closed by the other side.
+    pub fn read(&self, max_len: u32) -> Result<Vec<u8>, TorError> {
+        let mut buf = vec![0_u8; max_len as usize];
+        let n = self
+            .runtime
+            .block_on(async { self.reader.lock().await.read(&mut buf).await })?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// Write all of `data` to the stream.
+    ///
+    /// The data may be buffered: call [`flush`](TorStream::flush) to make sure
+    /// that it has been sent.
+    pub fn write(&self, data: Vec<u8>) -> Result<(), TorError> {
+        self.runtime
+            .block_on(async { self.writer.lock().await.write_all(&data).await })?;
+        Ok(())
+    }
+
+    /// Send any data that has been written, but not sent yet.
+    pub fn flush(&self) -> Result<(), TorError> {
+        self.runtime
+            .block_on(async { self.writer.lock().await.flush().await })?;
+        Ok(())
+    }
+
+    /// Flush the stream, and close it for writing.
+    pub fn close(&self) -> Result<(), TorError> {
+        self.runtime
+            .block_on(async { self.writer.lock().await.close().await })?;
+        Ok(())
+    }
+}
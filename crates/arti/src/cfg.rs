@@ -560,10 +560,23 @@ mod test {
             Recognized,
             &[
                 // Keys that are newer than the oldest-supported example, but otherwise normal.
+                "address_map",
                 "application.allow_running_as_root",
                 "bridges",
+                "channel.bridge_padding",
+                "circuit_timing.build_parallelism",
+                "conflux",
+                "dns_cache",
+                "dormancy",
                 "logging.time_granularity",
+                "path_rules.address_family",
+                "path_rules.exclude_addrs",
+                "path_rules.exclude_relay_families",
+                "path_rules.exclude_relays",
+                "path_rules.exit_relays",
+                "path_rules.hops",
                 "path_rules.long_lived_ports",
+                "preemptive_circuits.always_predicted_ports",
                 "proxy.socks_listen",
                 "proxy.dns_listen",
                 "use_obsolete_software",
@@ -576,6 +589,7 @@ mod test {
             Recognized,
             &[
                 // Examples exist but are not auto-testable
+                "storage.instance",
                 "tor_network.authorities",
                 "tor_network.fallback_caches",
             ],
@@ -628,6 +642,28 @@ mod test {
             ],
         );
 
+        declare_exceptions(
+            None,
+            None, // it's there, but not formatted for auto-testing
+            Recognized,
+            &[
+                // Bandwidth limits, tested by fn bandwidth_limits (below)
+                "channel.read_rate",
+                "channel.read_burst",
+                "channel.write_rate",
+                "channel.write_burst",
+                // Upstream proxy, tested by fn upstream_proxy (below)
+                "channel.proxy",
+                // Vanguard lifetimes, tested by fn vanguard_lifetimes (below)
+                "vanguards.l2_lifetime_min",
+                "vanguards.l2_lifetime_max",
+                "vanguards.l3_lifetime_min",
+                "vanguards.l3_lifetime_max",
+                // Circuit stream limit, tested by fn circuit_max_streams (below)
+                "circuit_timing.max_streams",
+            ],
+        );
+
         declare_exceptions(
             None,
             Some(InNew), // The top-level section is in the new file (only).
@@ -667,6 +703,26 @@ mod test {
             ],
         );
 
+        declare_exceptions(
+            None,
+            None, // There is an example, but it is not formatted for auto-testing
+            FeatureDependent,
+            &[
+                // GeoIP-only settings
+                "address_filter.exit_country",
+            ],
+        );
+
+        declare_exceptions(
+            None,
+            None, // There is an example, but it is not formatted for auto-testing
+            Recognized,
+            &[
+                // Address mappings
+                "address_map.hosts",
+            ],
+        );
+
         declare_exceptions(
             None,
             None, // TODO RPC, these should actually appear in the example config
@@ -1311,6 +1367,143 @@ example config file {which:?}, uncommented={uncommented:?}
         }
     }
 
+    #[test]
+    fn bandwidth_limits() {
+        // Test that uncommenting the example generates a config
+        // with the bandwidth limits that it describes.
+
+        let mut file = ExampleSectionLines::from_string(ARTI_EXAMPLE_CONFIG);
+        file.narrow((r"^\[channel\]", true), (r"^\[", false));
+        file.lines.retain(|line| {
+            [
+                //
+                "[",
+                "#    read_",
+                "#    write_",
+            ]
+            .iter()
+            .any(|t| line.starts_with(t))
+        });
+        file.strip_prefix("#    ");
+
+        let result = file.resolve_return_results::<(TorClientConfig, ArtiConfig)>();
+
+        let result = result.unwrap();
+
+        // Test that the example config doesn't have any unrecognised keys
+        assert_eq!(result.unrecognized, []);
+        assert_eq!(result.deprecated, []);
+
+        let mut expected = TorClientConfig::builder();
+        expected
+            .channel()
+            .read_rate("1 MiB".parse().unwrap())
+            .write_rate("512 KiB".parse().unwrap())
+            .read_burst("4 MiB".parse().unwrap())
+            .write_burst("2 MiB".parse().unwrap());
+        assert_eq!(result.value.0, expected.build().unwrap());
+    }
+
+    #[test]
+    fn upstream_proxy() {
+        // Test that uncommenting the example generates a config
+        // with the proxy that it describes.
+
+        let mut file = ExampleSectionLines::from_string(ARTI_EXAMPLE_CONFIG);
+        file.narrow((r"^\[channel\]", true), (r"^\[", false));
+        file.lines.retain(|line| {
+            [
+                //
+                "[",
+                "#    proxy ",
+            ]
+            .iter()
+            .any(|t| line.starts_with(t))
+        });
+        file.strip_prefix("#    ");
+
+        let result = file.resolve_return_results::<(TorClientConfig, ArtiConfig)>();
+
+        let result = result.unwrap();
+
+        // Test that the example config doesn't have any unrecognised keys
+        assert_eq!(result.unrecognized, []);
+        assert_eq!(result.deprecated, []);
+
+        let mut expected = TorClientConfig::builder();
+        expected
+            .channel()
+            .proxy("socks5://127.0.0.1:1080".parse().unwrap());
+        assert_eq!(result.value.0, expected.build().unwrap());
+    }
+
+    #[test]
+    fn vanguard_lifetimes() {
+        // Test that uncommenting the example generates a config
+        // with the vanguard lifetimes that it describes.
+
+        let mut file = ExampleSectionLines::from_string(ARTI_EXAMPLE_CONFIG);
+        file.narrow((r"^\[vanguards\]", true), (r"^#?\[", false));
+        file.lines.retain(|line| {
+            [
+                //
+                "[", "#    l2_", "#    l3_",
+            ]
+            .iter()
+            .any(|t| line.starts_with(t))
+        });
+        file.strip_prefix("#    ");
+
+        let result = file.resolve_return_results::<(TorClientConfig, ArtiConfig)>();
+
+        let result = result.unwrap();
+
+        // Test that the example config doesn't have any unrecognised keys
+        assert_eq!(result.unrecognized, []);
+        assert_eq!(result.deprecated, []);
+
+        let hours = |n| Duration::from_secs(n * 3600);
+        let mut expected = TorClientConfig::builder();
+        expected
+            .vanguards()
+            .l2_lifetime_min(hours(24))
+            .l2_lifetime_max(hours(12 * 24))
+            .l3_lifetime_min(hours(1))
+            .l3_lifetime_max(hours(2 * 24));
+        assert_eq!(result.value.0, expected.build().unwrap());
+    }
+
+    #[test]
+    fn circuit_max_streams() {
+        // Test that uncommenting the example generates a config
+        // with the stream limit that it describes.
+
+        let mut file = ExampleSectionLines::from_string(ARTI_EXAMPLE_CONFIG);
+        file.narrow((r"^\[circuit_timing\]", true), (r"^\[", false));
+        file.lines.retain(|line| {
+            [
+                //
+                "[",
+                "#    max_streams ",
+            ]
+            .iter()
+            .any(|t| line.starts_with(t))
+        });
+        file.strip_prefix("#    ");
+
+        let result = file.resolve_return_results::<(TorClientConfig, ArtiConfig)>();
+
+        let result = result.unwrap();
+
+        // Test that the example config doesn't have any unrecognised keys
+        assert_eq!(result.unrecognized, []);
+        assert_eq!(result.deprecated, []);
+
+        let mut expected = TorClientConfig::builder();
+        expected.circuit_timing().max_streams(100);
+        assert_eq!(result.value.0, expected.build().unwrap());
+    }
+
     #[test]
     fn metrics() {
         // Test that uncommenting the example generates a config
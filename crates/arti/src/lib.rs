@@ -116,7 +116,11 @@ use clap::{value_parser, Arg, ArgAction, Command};
 #[allow(unused_imports)]
 use tracing::{error, info, warn};
 
-#[cfg(any(feature = "hsc", feature = "onion-service-service"))]
+#[cfg(any(
+    feature = "hsc",
+    feature = "onion-service-service",
+    feature = "state-cli"
+))]
 use clap::Subcommand as _;
 
 #[cfg(feature = "experimental-api")]
@@ -294,6 +298,12 @@ where
         }
     }
 
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "state-cli")] {
+            let clap_app = subcommands::state::StateSubcommands::augment_subcommands(clap_app);
+        }
+    }
+
     // Tracing doesn't log anything when there is no subscriber set.  But we want to see
     // logging messages from config parsing etc.  We can't set the global default subscriber
     // because we can only set it once.  The other ways involve a closure.  So we have a
@@ -402,6 +412,15 @@ where
         }
     }
 
+    // Check for the optional "state" subcommand.
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "state-cli")] {
+            if let Some(state_matches) = matches.subcommand_matches("state") {
+                return subcommands::state::run(state_matches, &client_config);
+            }
+        }
+    }
+
     panic!("Subcommand added to clap subcommand list, but not yet implemented");
 }
 
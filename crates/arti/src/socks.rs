@@ -28,6 +28,9 @@ use anyhow::{anyhow, Context, Result};
 
 use crate::rpc::RpcProxySupport;
 
+#[cfg(feature = "experimental-udp")]
+mod udp;
+
 /// Payload to return when an HTTP connection arrive on a Socks port
 const WRONG_PROTOCOL_PAYLOAD: &[u8] = br#"HTTP/1.0 501 Tor is not an HTTP Proxy
 Content-Type: text/html; charset=utf-8
@@ -373,6 +376,10 @@ fn interpret_socks_auth(auth: &SocksAuth) -> Result<AuthInterpretation> {
 struct SocksConnContext<R: Runtime> {
     /// A TorClient to use (by default) to anonymize requests.
     tor_client: TorClient<R>,
+    /// The address of the listener that accepted the connection, on which
+    /// we bind a socket to relay UDP datagrams.
+    #[cfg(feature = "experimental-udp")]
+    listen_ip: IpAddr,
     /// If present, an RpcMgr to use when for attaching requests to RPC
     /// sessions.
     #[cfg(feature = "rpc")]
@@ -594,6 +601,32 @@ where
                 write_all_and_close(&mut socks_stream, &reply[..]).await?;
             }
         }
+        #[cfg(feature = "experimental-udp")]
+        SocksCmd::UDP_ASSOCIATE => {
+            // We've been asked to relay UDP datagrams.
+            let Some(tor_client) = conn_target_client(&tor_client) else {
+                warn!("Dropping request; UDP ASSOCIATE is unsupported for RPC sessions");
+                let reply = request
+                    .reply(tor_socksproto::SocksStatus::COMMAND_NOT_SUPPORTED, None)
+                    .context("Encoding socks reply")?;
+                write_all_and_close(&mut socks_stream, &reply[..]).await?;
+                return Ok(());
+            };
+            // The request's address is the client's own, not a destination,
+            // so it tells us nothing about which IP version to prefer.
+            let mut prefs = prefs;
+            prefs.ipv4_preferred();
+            return udp::handle_udp_associate(
+                runtime,
+                tor_client.clone(),
+                prefs,
+                &request,
+                socks_stream,
+                context.listen_ip,
+                isolation_info.1,
+            )
+            .await;
+        }
         _ => {
             // We don't support this SOCKS command.
             warn!("Dropping request; {:?} is unsupported", request.command());
@@ -610,6 +643,21 @@ where
     Ok(())
 }
 
+/// Return the TorClient that `target` wraps, or None if it is an RPC object.
+#[cfg(feature = "experimental-udp")]
+fn conn_target_client<R: Runtime>(target: &ConnTarget<R>) -> Option<&TorClient<R>> {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "rpc")] {
+            match target {
+                ConnTarget::Client(client) => Some(client),
+                ConnTarget::Rpc { .. } => None,
+            }
+        } else {
+            Some(target)
+        }
+    }
+}
+
 /// write_all the data to the writer & flush the writer if write_all is successful.
 async fn write_all_and_flush<W>(writer: &mut W, buf: &[u8]) -> Result<()>
 where
@@ -846,7 +894,7 @@ pub(crate) async fn run_socks_proxy<R: Runtime>(
                 rpc_state_sender.set_socks_listeners(&listening_on_addrs[..]);
             }
         } else {
-            let _ = listening_on_addrs;
+            let _ = &listening_on_addrs;
         }
     }
 
@@ -878,6 +926,8 @@ pub(crate) async fn run_socks_proxy<R: Runtime>(
         };
         let socks_context = SocksConnContext {
             tor_client: tor_client.clone(),
+            #[cfg(feature = "experimental-udp")]
+            listen_ip: listening_on_addrs[sock_id].ip(),
             #[cfg(feature = "rpc")]
             rpc_mgr: rpc_mgr.clone(),
         };
@@ -0,0 +1,231 @@
+//! Support for the SOCKS5 `UDP ASSOCIATE` command.
+//!
+//! A `UDP ASSOCIATE` request asks us to relay datagrams for the client.
+//! We bind a UDP socket on the address where we accepted the SOCKS connection,
+//! and tell the client about it.  The client sends each of its datagrams
+//! there, with a header naming its destination.  We open one UDP stream over
+//! Tor for each destination, and relay its replies back to the client with
+//! the same kind of header.
+//!
+//! The association lasts until the client closes its SOCKS connection.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::pin;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use arti_client::{IntoTorAddr as _, StreamPrefs, TorClient, UdpStream, UdpStreamSender};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use futures::stream::StreamExt;
+use futures::task::SpawnExt;
+use safelog::sensitive;
+use tor_rtcompat::{Runtime, UdpProvider as _, UdpSocket};
+use tor_socksproto::{SocksAddr, SocksRequest, SocksStatus, SocksUdpDatagram};
+use tracing::debug;
+
+/// The largest datagram that we accept from the client.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// How many datagrams we queue for a single destination before we start
+/// dropping them.
+const QUEUE_LEN: usize = 32;
+
+/// Handle a SOCKS5 `UDP ASSOCIATE` request that arrived on `socks_stream`,
+/// from a client at `client_ip`.
+///
+/// Binds the relay socket on `bind_ip`, and relays datagrams until the client
+/// closes `socks_stream`.
+pub(super) async fn handle_udp_associate<R, S>(
+    runtime: R,
+    tor_client: TorClient<R>,
+    prefs: StreamPrefs,
+    request: &SocksRequest,
+    mut socks_stream: S,
+    bind_ip: IpAddr,
+    client_ip: IpAddr,
+) -> Result<()>
+where
+    R: Runtime,
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let socket = match runtime.bind(&SocketAddr::new(bind_ip, 0)).await {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            let reply = request
+                .reply(SocksStatus::GENERAL_FAILURE, None)
+                .context("Encoding socks reply")?;
+            // if writing back the error fail, still return the original error
+            let _ = super::write_all_and_close(&mut socks_stream, &reply[..]).await;
+            return Err(e).context("Unable to bind UDP relay socket");
+        }
+    };
+    let relay_addr = socket
+        .local_addr()
+        .context("Unable to find address of UDP relay socket")?;
+    let reply = request
+        .udp_associate_reply(&SocksAddr::Ip(relay_addr.ip()), relay_addr.port())
+        .context("Encoding socks reply")?;
+    super::write_all_and_flush(&mut socks_stream, &reply[..]).await?;
+    debug!("Relaying UDP datagrams on {}", relay_addr);
+
+    // The client may tell us which port it will send its datagrams from.
+    let client_port = Some(request.port()).filter(|port| *port != 0);
+
+    let closed = pin!(async {
+        // The client shouldn't send anything else on this connection.
+        let mut buf = [0_u8; 64];
+        while let Ok(n) = socks_stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+    let relay = pin!(relay_datagrams(
+        runtime,
+        tor_client,
+        prefs,
+        socket,
+        client_ip,
+        client_port
+    ));
+    match future::select(closed, relay).await {
+        Either::Left(((), _)) => {
+            debug!("SOCKS connection closed; ending UDP association");
+            Ok(())
+        }
+        Either::Right((result, _)) => result,
+    }
+}
+
+/// Relay the datagrams that the client sends to `socket` over the Tor network.
+///
+/// Only accepts datagrams from `client_ip` (and from `client_port`, if given).
+/// Runs until `socket` fails.
+async fn relay_datagrams<R: Runtime>(
+    runtime: R,
+    tor_client: TorClient<R>,
+    prefs: StreamPrefs,
+    socket: Arc<R::UdpSocket>,
+    client_ip: IpAddr,
+    client_port: Option<u16>,
+) -> Result<()> {
+    let mut destinations: HashMap<(String, u16), mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0_u8; MAX_DATAGRAM_LEN];
+    loop {
+        let (n, client) = socket
+            .recv(&mut buf)
+            .await
+            .context("Error while receiving UDP datagram")?;
+        if client.ip() != client_ip || client_port.is_some_and(|port| port != client.port()) {
+            debug!(
+                "Dropping UDP datagram from unexpected address {}",
+                sensitive(client)
+            );
+            continue;
+        }
+        let datagram = match SocksUdpDatagram::parse(&buf[..n]) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                tor_error::debug_report!(e, "Dropping malformed UDP datagram");
+                continue;
+            }
+        };
+
+        let key = (datagram.addr().to_string(), datagram.port());
+        let queue = match destinations.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let (tx, rx) = mpsc::channel(QUEUE_LEN);
+                runtime.spawn(relay_destination(
+                    tor_client.clone(),
+                    prefs.clone(),
+                    datagram.addr().clone(),
+                    datagram.port(),
+                    rx,
+                    Arc::clone(&socket),
+                    client,
+                ))?;
+                entry.insert(tx)
+            }
+        };
+        // If the queue is full, we drop the datagram, as a congested network
+        // would.  If the stream has failed, we forget about it, so that the
+        // next datagram to the same destination tries to open a new one.
+        if let Err(e) = queue.try_send(datagram.into_payload()) {
+            if e.is_disconnected() {
+                destinations.remove(&key);
+            }
+        }
+    }
+}
+
+/// Open a UDP stream over the Tor network to `addr`:`port`, send it the
+/// datagrams from `outgoing`, and relay its replies to `client` through
+/// `socket`.
+///
+/// Runs until `outgoing` is closed, or until the stream fails.
+async fn relay_destination<R: Runtime>(
+    tor_client: TorClient<R>,
+    prefs: StreamPrefs,
+    addr: SocksAddr,
+    port: u16,
+    outgoing: mpsc::Receiver<Vec<u8>>,
+    socket: Arc<R::UdpSocket>,
+    client: SocketAddr,
+) {
+    let result: Result<()> = async {
+        let target = (addr.to_string(), port).into_tor_addr()?;
+        let stream = tor_client.connect_udp_with_prefs(target, &prefs).await?;
+        debug!("Got a UDP stream for {}:{}", sensitive(&addr), port);
+
+        let sender = stream.sender();
+        let send = pin!(send_datagrams(sender, outgoing));
+        let recv = pin!(recv_datagrams(stream, &addr, port, &*socket, client));
+        match future::select(send, recv).await {
+            Either::Left((result, _)) | Either::Right((result, _)) => result,
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        debug!(
+            "UDP relay for {}:{} ended: {}",
+            sensitive(&addr),
+            port,
+            tor_error::Report(e)
+        );
+    }
+}
+
+/// Send every datagram from `outgoing` with `sender`.
+async fn send_datagrams(
+    mut sender: UdpStreamSender,
+    mut outgoing: mpsc::Receiver<Vec<u8>>,
+) -> Result<()> {
+    while let Some(datagram) = outgoing.next().await {
+        sender.send(&datagram).await?;
+    }
+    Ok(())
+}
+
+/// Relay every datagram that arrives on `stream` to `client` through
+/// `socket`, labelled as coming from `addr`:`port`.
+async fn recv_datagrams<U: UdpSocket + Sync>(
+    mut stream: UdpStream,
+    addr: &SocksAddr,
+    port: u16,
+    socket: &U,
+    client: SocketAddr,
+) -> Result<()> {
+    loop {
+        let payload = stream.recv().await?;
+        let datagram = SocksUdpDatagram::new(addr.clone(), port, payload).encode()?;
+        socket
+            .send(&datagram, &client)
+            .await
+            .context("Error while sending UDP datagram")?;
+    }
+}
@@ -7,3 +7,6 @@ pub(crate) mod hss;
 pub(crate) mod hsc;
 
 pub(crate) mod proxy;
+
+#[cfg(feature = "state-cli")]
+pub(crate) mod state;
@@ -0,0 +1,189 @@
+//! The `state` subcommand.
+
+use crate::Result;
+
+use anyhow::{anyhow, Context};
+use arti_client::TorClientConfig;
+use clap::{ArgMatches, Args, FromArgMatches, Parser, Subcommand};
+use tor_error::ErrorReport as _;
+use tor_persist::{FsStateMgr, JsonValue, LockStatus, StateMgr as _};
+
+use std::io::{self, Write};
+
+/// The state subcommands the arti CLI will be augmented with.
+#[derive(Parser, Debug)]
+pub(crate) enum StateSubcommands {
+    /// Inspect or repair Arti's persistent state.
+    #[command(subcommand)]
+    State(StateSubcommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum StateSubcommand {
+    /// List the records in the state directory
+    List,
+
+    /// Print a record, as JSON
+    #[command(arg_required_else_help = true)]
+    Show(RecordArgs),
+
+    /// Remove a record, so that Arti starts it afresh.
+    /// Arti must not be running
+    #[command(arg_required_else_help = true)]
+    Remove(RemoveArgs),
+
+    /// Check that every record can be read
+    Verify,
+}
+
+/// The arguments of the [`Show`](StateSubcommand::Show) subcommand.
+#[derive(Debug, Clone, Args)]
+pub(crate) struct RecordArgs {
+    /// The name of the record, as printed by `arti state list`
+    record: String,
+}
+
+/// The arguments of the [`Remove`](StateSubcommand::Remove) subcommand.
+#[derive(Debug, Clone, Args)]
+pub(crate) struct RemoveArgs {
+    /// The record to remove.
+    #[command(flatten)]
+    record: RecordArgs,
+
+    /// With this flag active no prompt will be shown
+    /// and no confirmation will be asked
+    #[arg(long, short, default_value_t = false)]
+    batch: bool,
+}
+
+/// Run the `state` subcommand.
+pub(crate) fn run(state_matches: &ArgMatches, config: &TorClientConfig) -> Result<()> {
+    let subcommand =
+        StateSubcommand::from_arg_matches(state_matches).expect("Could not parse state subcommand");
+
+    match subcommand {
+        StateSubcommand::List => list(config),
+        StateSubcommand::Show(args) => show(&args, config),
+        StateSubcommand::Remove(args) => remove(&args, config),
+        StateSubcommand::Verify => verify(config),
+    }
+}
+
+/// Open the state directory to read, without taking the lock.
+///
+/// This works even while Arti is running.
+fn open_read_only(config: &TorClientConfig) -> Result<FsStateMgr> {
+    let (state_dir, mistrust) = config.state_dir_and_mistrust()?;
+    FsStateMgr::open_read_only(&state_dir, mistrust)
+        .with_context(|| format!("Unable to open state directory {}", state_dir.display()))
+}
+
+/// Run the `state list` subcommand.
+fn list(config: &TorClientConfig) -> Result<()> {
+    let snapshot = open_read_only(config)?.snapshot()?;
+    for key in snapshot.keys() {
+        println!("{key}");
+    }
+
+    Ok(())
+}
+
+/// Run the `state show` subcommand.
+fn show(args: &RecordArgs, config: &TorClientConfig) -> Result<()> {
+    let snapshot = open_read_only(config)?.snapshot()?;
+    let Some(record) = snapshot.load::<JsonValue>(&args.record)? else {
+        return Err(anyhow!("No such record: {}", args.record));
+    };
+    println!("{record:#}");
+
+    Ok(())
+}
+
+/// Run the `state remove` subcommand.
+fn remove(args: &RemoveArgs, config: &TorClientConfig) -> Result<()> {
+    let record = &args.record.record;
+    let snapshot = open_read_only(config)?.snapshot()?;
+    if !snapshot.keys().any(|key| key == record) {
+        return Err(anyhow!("No such record: {record}"));
+    }
+
+    let (state_dir, mistrust) = config.state_dir_and_mistrust()?;
+    let statemgr = FsStateMgr::from_path_and_mistrust(&state_dir, mistrust)?;
+    if statemgr.try_lock()? == LockStatus::NoLock {
+        return Err(anyhow!(
+            "Arti's state is locked; stop any Arti process using it, and try again"
+        ));
+    }
+
+    let msg = format!("remove state record {record}?");
+    if !prompt(&msg, args.batch)? {
+        return Ok(());
+    }
+    statemgr.remove(record)?;
+
+    Ok(())
+}
+
+/// Run the `state verify` subcommand.
+fn verify(config: &TorClientConfig) -> Result<()> {
+    let snapshot = open_read_only(config)?.snapshot()?;
+    let mut n_bad = 0;
+    for key in snapshot.keys() {
+        match snapshot.load::<JsonValue>(key) {
+            Ok(_) => println!("{key}: ok"),
+            Err(e) => {
+                println!("{key}: {}", e.report());
+                n_bad += 1;
+            }
+        }
+    }
+
+    if n_bad > 0 {
+        return Err(anyhow!(
+            "{n_bad} record(s) could not be read; remove them with `arti state remove`"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Prompt the user to confirm by typing yes or no.
+///
+/// Loops until the user confirms or declines,
+/// returning true if they confirmed.
+///
+/// If `batch` is `true` no confirmation will be asked.
+fn prompt(msg: &str, batch: bool) -> Result<bool> {
+    if batch {
+        return Ok(true);
+    }
+
+    /// The accept message.
+    const YES: &str = "YES";
+    /// The decline message.
+    const NO: &str = "no";
+
+    let mut proceed = String::new();
+
+    print!("{} (type {YES} or {NO}): ", msg);
+    io::stdout().flush().map_err(|e| anyhow!(e))?;
+    loop {
+        proceed.clear();
+        if io::stdin()
+            .read_line(&mut proceed)
+            .map_err(|e| anyhow!(e))?
+            == 0
+        {
+            // End of input: nobody confirmed.
+            return Ok(false);
+        }
+
+        if proceed.trim_end() == YES {
+            return Ok(true);
+        }
+
+        if let NO | "n" = proceed.trim_end().to_lowercase().as_str() {
+            return Ok(false);
+        }
+    }
+}
@@ -36,6 +36,9 @@ fn cli_tests() {
         }
     }
 
+    #[cfg(feature = "state-cli")]
+    t.case("tests/testcases/state/*.toml");
+
     t.case("README.md");
 
     // Run the tests.
@@ -62,6 +62,8 @@ mod disable;
 mod err;
 mod file_access;
 mod imp;
+#[cfg(target_family = "unix")]
+mod repair;
 #[cfg(all(
     target_family = "unix",
     not(target_os = "ios"),
@@ -88,6 +90,8 @@ pub use dir::CheckedDir;
 pub use disable::GLOBAL_DISABLE_VAR;
 pub use err::{format_access_bits, Error};
 pub use file_access::FileAccess;
+#[cfg(target_family = "unix")]
+pub use repair::Repair;
 
 /// A result type as returned by this crate
 pub type Result<T> = std::result::Result<T, Error>;
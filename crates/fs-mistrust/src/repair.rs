@@ -0,0 +1,272 @@
+//! Code to repair the permissions and ownership of files and directories
+//! that fail our checks.
+//!
+//! Users who restore a backup, or copy a directory from another machine,
+//! often end up with files that are readable by their group, or owned by
+//! the wrong user.  Rather than making them fix every file by hand, an
+//! application can offer to [plan](Verifier::plan_repairs) and
+//! [make](Verifier::repair) the necessary changes.
+//!
+//! We only ever change the target and its contents: never its ancestors,
+//! since (for example) a group-writable home directory is something the
+//! user should fix deliberately.
+
+use std::fmt;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+
+use crate::{format_access_bits, Error, Result, Verifier};
+
+/// A change to a file or directory that will fix a problem found by a
+/// [`Verifier`].
+///
+/// Returned by [`Verifier::plan_repairs`] and [`Verifier::repair`].  The
+/// [`Display`](fmt::Display) implementation describes the change, for
+/// showing to the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Repair {
+    /// Remove some permission bits from `path`.
+    Chmod {
+        /// The file or directory to change.
+        path: PathBuf,
+        /// The permission bits that `path` has now.
+        mode: u32,
+        /// The permission bits to remove.
+        remove: u32,
+    },
+    /// Change the owner of `path`.
+    Chown {
+        /// The file or directory to change.
+        path: PathBuf,
+        /// The user ID that owns `path` now.
+        old_uid: u32,
+        /// The user ID that should own `path`.
+        new_uid: u32,
+    },
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::Chmod { path, remove, .. } => write!(
+                f,
+                "chmod {} {}",
+                format_access_bits(*remove, '-'),
+                path.display()
+            ),
+            Repair::Chown {
+                path,
+                old_uid,
+                new_uid,
+            } => write!(
+                f,
+                "change owner of {} from UID {} to UID {}",
+                path.display(),
+                old_uid,
+                new_uid
+            ),
+        }
+    }
+}
+
+impl Repair {
+    /// Return the file or directory that this repair changes.
+    pub fn path(&self) -> &Path {
+        match self {
+            Repair::Chmod { path, .. } | Repair::Chown { path, .. } => path,
+        }
+    }
+
+    /// Make this change.
+    ///
+    /// Changing the owner of a file usually requires root privileges.
+    pub fn apply(&self) -> Result<()> {
+        match self {
+            Repair::Chmod { path, mode, remove } => {
+                let perms = std::fs::Permissions::from_mode(mode & !remove & 0o7777);
+                std::fs::set_permissions(path, perms)
+                    .map_err(|e| Error::io(e, path, "change permissions"))
+            }
+            Repair::Chown { path, new_uid, .. } => {
+                std::os::unix::fs::lchown(path, Some(*new_uid), None)
+                    .map_err(|e| Error::io(e, path, "change owner"))
+            }
+        }
+    }
+}
+
+/// Return `path` with its parent directory canonicalized, but its final
+/// component left alone (so that symlinks are not followed).
+fn location(path: &Path) -> Result<PathBuf> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        // This is the root directory, or something like it.
+        return Ok(path.to_path_buf());
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    let parent = parent
+        .canonicalize()
+        .map_err(|e| Error::inspecting(e, parent))?;
+    Ok(parent.join(name))
+}
+
+/// Add every error in `err` to `out`, including errors in directory
+/// contents.
+fn flatten_errors(err: &Error, out: &mut Vec<Error>) {
+    for e in err.errors() {
+        match e {
+            Error::Content(inner) => flatten_errors(inner, out),
+            other => out.push(other.clone()),
+        }
+    }
+}
+
+impl Verifier<'_> {
+    /// Return the changes that would fix every problem that this `Verifier`
+    /// finds with `path`, without making them.
+    ///
+    /// Returns an empty list if there are no problems.
+    ///
+    /// Returns an error if there is a problem that we can't fix: for
+    /// example, if one of the ancestors of `path` has bad permissions, or if
+    /// something has the wrong type.
+    pub fn plan_repairs<P: AsRef<Path>>(&self, path: P) -> Result<Vec<Repair>> {
+        let path = path.as_ref();
+        let err = match self.clone().all_errors().check(path) {
+            Ok(()) => return Ok(vec![]),
+            Err(e) => e,
+        };
+        let target = location(path)?;
+
+        let mut errors = Vec::new();
+        flatten_errors(&err, &mut errors);
+        errors
+            .into_iter()
+            .map(|e| {
+                let repair = match &e {
+                    Error::BadPermission(p, mode, remove) => Repair::Chmod {
+                        path: p.clone(),
+                        mode: *mode,
+                        remove: *remove,
+                    },
+                    #[cfg(all(
+                        not(target_os = "ios"),
+                        not(target_os = "tvos"),
+                        not(target_os = "android")
+                    ))]
+                    Error::BadOwner(p, uid) => Repair::Chown {
+                        path: p.clone(),
+                        old_uid: *uid,
+                        new_uid: self.mistrust.trust_user.unwrap_or(0),
+                    },
+                    _ => return Err(e),
+                };
+                if location(repair.path())?.starts_with(&target) {
+                    Ok(repair)
+                } else {
+                    // Never change the target's ancestors.
+                    Err(e)
+                }
+            })
+            .collect()
+    }
+
+    /// Fix every problem that this `Verifier` finds with `path`, and return
+    /// the changes that we made.
+    ///
+    /// After making the changes, we check `path` again, and return an error
+    /// if there is still a problem.  As with
+    /// [`plan_repairs`](Verifier::plan_repairs), we make no changes if there
+    /// is a problem that we can't fix.
+    pub fn repair<P: AsRef<Path>>(&self, path: P) -> Result<Vec<Repair>> {
+        let path = path.as_ref();
+        let repairs = self.plan_repairs(path)?;
+        for repair in &repairs {
+            repair.apply()?;
+        }
+        self.check(path)?;
+        Ok(repairs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testing::{mistrust_build, Dir, MistrustOp};
+
+    #[test]
+    fn repair_permissions() {
+        let d = Dir::new();
+        d.dir("a/b/c");
+        d.file("a/b/c/f");
+        d.chmod("a", 0o755);
+        d.chmod("a/b", 0o700);
+        d.chmod("a/b/c", 0o770);
+        d.chmod("a/b/c/f", 0o644);
+
+        let m = mistrust_build(&[
+            MistrustOp::IgnorePrefix(d.canonical_root()),
+            MistrustOp::TrustNoGroupId(),
+        ]);
+
+        let target = d.path("a/b");
+        let err = m.verifier().check_content().check(&target).unwrap_err();
+        assert!(err.is_bad_permission());
+
+        // Planning changes nothing.
+        let plan = m.verifier().check_content().plan_repairs(&target).unwrap();
+        assert_eq!(plan.len(), 2);
+        assert!(plan.iter().all(|r| matches!(r, Repair::Chmod { .. })));
+        assert!(plan.iter().any(|r| r.path().ends_with("c/f")));
+        assert!(plan.iter().all(|r| r.to_string().starts_with("chmod ")));
+        assert!(m.verifier().check_content().check(&target).is_err());
+
+        let done = m.verifier().check_content().repair(&target).unwrap();
+        assert_eq!(done, plan);
+        m.verifier().check_content().check(&target).unwrap();
+        assert!(m
+            .verifier()
+            .check_content()
+            .plan_repairs(&target)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn no_repairing_ancestors() {
+        let d = Dir::new();
+        d.dir("a/b");
+        d.chmod("a", 0o777);
+        d.chmod("a/b", 0o700);
+
+        let m = mistrust_build(&[
+            MistrustOp::IgnorePrefix(d.canonical_root()),
+            MistrustOp::TrustNoGroupId(),
+        ]);
+
+        let err = m.verifier().plan_repairs(d.path("a/b")).unwrap_err();
+        assert!(matches!(err, Error::BadPermission(..)));
+        let err = m.verifier().repair(d.path("a/b")).unwrap_err();
+        assert!(matches!(err, Error::BadPermission(..)));
+        // We didn't touch it.
+        use std::os::unix::fs::MetadataExt as _;
+        assert_eq!(d.path("a").metadata().unwrap().mode() & 0o777, 0o777);
+    }
+}
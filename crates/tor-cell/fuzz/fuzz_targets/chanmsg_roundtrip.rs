@@ -0,0 +1,24 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tor_bytes::Reader;
+use tor_cell::chancell::msg::AnyChanMsg;
+use tor_cell::chancell::{ChanCmd, ChanMsg};
+
+/// Decode `body` as a channel message of type `cmd`, and encode it again.
+fn reencode(cmd: ChanCmd, body: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader::from_slice(body);
+    let msg = AnyChanMsg::decode_from_reader(cmd, &mut r).ok()?;
+    let mut out = Vec::new();
+    msg.encode_onto(&mut out).ok()?;
+    Some(out)
+}
+
+fuzz_target!(|data: (ChanCmd, Vec<u8>)| {
+    let (cmd, body) = data;
+    // The first decode/encode cycle may normalize the message;
+    // after that, it must survive unchanged.
+    if let Some(encoded) = reencode(cmd, &body) {
+        let again = reencode(cmd, &encoded).expect("re-encoded message did not decode");
+        assert_eq!(encoded, again);
+    }
+});
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tor_cell::{
+    chancell::{BoxedCellBody, CELL_DATA_LEN},
+    relaycell::{msg::AnyRelayMsg, RelayCellDecoder, RelayCellFormat},
+};
+
+fuzz_target!(|data: (RelayCellFormat, Vec<Vec<u8>>)| {
+    let (format, cells) = data;
+    let mut decoder = RelayCellDecoder::new(format);
+    for cell in cells {
+        let mut body: BoxedCellBody = Box::new([0_u8; CELL_DATA_LEN]);
+        let copy_len = std::cmp::min(cell.len(), body.len());
+        body[..copy_len].copy_from_slice(&cell[..copy_len]);
+        let Ok(result) = decoder.decode(body) else {
+            return;
+        };
+        let (msgs, _incomplete) = result.into_parts();
+        for msg in msgs {
+            let _ = msg.decode::<AnyRelayMsg>();
+        }
+    }
+});
@@ -13,6 +13,7 @@ use std::num::NonZeroU32;
 
 use caret::caret_int;
 use derive_deftly::Deftly;
+use tor_error::{bad_api_usage, internal};
 use tor_memquota::{derive_deftly_template_HasMemoryCost, HasMemoryCostStructural};
 
 /// The amount of data sent in a fixed-length cell.
@@ -78,6 +79,7 @@ caret_int! {
     /// indicates the meaning of the cell, and (possibly) its length.
     #[derive(Deftly)]
     #[derive_deftly(HasMemoryCost)]
+    #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
     pub struct ChanCmd(u8) {
         /// A fixed-length cell that will be dropped.
         PADDING = 0,
@@ -224,4 +226,96 @@ impl<M: ChanMsg> ChanCell<M> {
     pub fn into_circid_and_msg(self) -> (Option<CircId>, M) {
         (self.circid, self.msg)
     }
+
+    /// Consume this cell and encode it into the start of `buf`, in the format
+    /// used by [`ChannelCodec`](codec::ChannelCodec).
+    ///
+    /// Returns the number of bytes written. Unlike
+    /// [`ChannelCodec::write_cell`](codec::ChannelCodec::write_cell),
+    /// this doesn't allocate: a caller that keeps a pool of cell buffers can
+    /// encode into them directly.
+    ///
+    /// Fails if `buf` is too short to hold the cell;
+    /// use [`encoded_len`](ChanCell::encoded_len) to find out how long it must be.
+    pub fn encode_into(self, buf: &mut [u8]) -> crate::Result<usize> {
+        use tor_bytes::Writer as _;
+
+        let too_short = |buf_len: usize| {
+            crate::Error::Internal(bad_api_usage!(
+                "Buffer of {buf_len} bytes is too short for a channel cell"
+            ))
+        };
+
+        let buf_len = buf.len();
+        let ChanCell { circid, msg } = self;
+        let cmd = msg.cmd();
+        let varcell = cmd.is_var_cell();
+        let mut w = crate::slicewriter::SliceWriter::new(&mut *buf);
+        w.write_u32(CircId::get_or_zero(circid));
+        w.write_u8(cmd.into());
+        let body_pos = if varcell {
+            w.advance(2); // Length, which we fill in below.
+            VAR_CELL_HEADER_LEN
+        } else {
+            FIXED_CELL_HEADER_LEN
+        };
+        msg.encode_onto(&mut w)?;
+        let written = w.offset().map_err(|_| too_short(buf_len))?;
+        let body_len = written - body_pos;
+
+        if varcell {
+            let len = u16::try_from(body_len)
+                .map_err(|_| crate::Error::Internal(internal!("ran out of space for varcell")))?;
+            buf[FIXED_CELL_HEADER_LEN..VAR_CELL_HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+            Ok(written)
+        } else {
+            if body_len > CELL_DATA_LEN {
+                return Err(crate::Error::Internal(internal!(
+                    "ran out of space for cell"
+                )));
+            }
+            // pad to end of fixed-length cell
+            buf.get_mut(written..FIXED_CELL_LEN)
+                .ok_or_else(|| too_short(buf_len))?
+                .fill(0);
+            Ok(FIXED_CELL_LEN)
+        }
+    }
+}
+
+impl<M: ChanMsg + Clone> ChanCell<M> {
+    /// Return the number of bytes that [`encode_into`](ChanCell::encode_into)
+    /// would write for this cell.
+    ///
+    /// For a fixed-length cell, this is always the same. For a variable-length
+    /// cell, we find out by encoding a copy of the message, without storing
+    /// the encoded bytes anywhere.
+    pub fn encoded_len(&self) -> crate::Result<usize> {
+        if !self.msg.cmd().is_var_cell() {
+            return Ok(FIXED_CELL_LEN);
+        }
+        let mut counter = LenCounter(0);
+        self.msg.clone().encode_onto(&mut counter)?;
+        Ok(VAR_CELL_HEADER_LEN + counter.0)
+    }
+}
+
+/// Length of the header (circuit ID and command) of a fixed-length cell.
+const FIXED_CELL_HEADER_LEN: usize = 4 + 1;
+
+/// Length of the header (circuit ID, command, and length) of a
+/// variable-length cell.
+const VAR_CELL_HEADER_LEN: usize = 4 + 1 + 2;
+
+/// Total length of a fixed-length cell.
+const FIXED_CELL_LEN: usize = FIXED_CELL_HEADER_LEN + CELL_DATA_LEN;
+
+/// A [`Writer`](tor_bytes::Writer) that throws away what it is given,
+/// and only counts how many bytes that was.
+struct LenCounter(usize);
+
+impl tor_bytes::Writer for LenCounter {
+    fn write_all(&mut self, b: &[u8]) {
+        self.0 += b.len();
+    }
 }
@@ -3,10 +3,10 @@
 use super::{ChanCell, CELL_DATA_LEN};
 use crate::chancell::{ChanCmd, ChanMsg, CircId};
 use crate::Error;
-use tor_bytes::{self, Reader, Writer};
+use tor_bytes::{Reader, Writer};
 use tor_error::internal;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
 /// This object can be used to encode and decode channel cells.
 ///
@@ -91,29 +91,37 @@ impl ChannelCodec {
         &mut self,
         src: &mut BytesMut,
     ) -> crate::Result<Option<ChanCell<M>>> {
-        /// Wrap `be` as an appropriate type.
-        fn wrap_err(be: tor_bytes::Error) -> crate::Error {
-            crate::Error::BytesErr {
-                err: be,
-                parsed: "channel cell",
-            }
-        }
+        self.decode_raw_cell(src)?
+            .map(|raw| raw.decode())
+            .transpose()
+    }
 
+    /// Try to split a single cell off the front of the provided BytesMut
+    /// object, without parsing its message.
+    ///
+    /// The returned [`RawChanCell`] shares its body with `src`, so this does
+    /// not copy or allocate. Its circuit ID has been checked against its
+    /// command, but its body has not been parsed at all:
+    /// use [`RawChanCell::decode`] for that.
+    ///
+    /// On a definite decoding error, return Err(_).  On a cell that might
+    /// just be truncated, return Ok(None).
+    pub fn decode_raw_cell(&mut self, src: &mut BytesMut) -> crate::Result<Option<RawChanCell>> {
         if src.len() < 7 {
             // Smallest possible command: varcell with len 0
             return Ok(None);
         }
         let cmd: ChanCmd = src[4].into();
         let varcell = cmd.is_var_cell();
-        let cell_len: usize = if varcell {
+        let (header_len, cell_len): (usize, usize) = if varcell {
             let msg_len = u16::from_be_bytes(
                 src[5..7]
                     .try_into()
                     .expect("Two-byte slice was not two bytes long!?"),
             );
-            msg_len as usize + 7
+            (7, msg_len as usize + 7)
         } else {
-            514
+            (5, 514)
         };
         if src.len() < cell_len {
             return Ok(None);
@@ -121,10 +129,11 @@ impl ChannelCodec {
 
         let cell = src.split_to(cell_len).freeze();
         //trace!("{:?} cell body ({}) is {:?}", cmd, cell.len(), &cell[..]);
-        let mut r = Reader::from_bytes(&cell);
-        let circid: Option<CircId> = CircId::new(r.take_u32().map_err(wrap_err)?);
-        r.advance(if varcell { 3 } else { 1 }).map_err(wrap_err)?;
-        let msg = M::decode_from_reader(cmd, &mut r).map_err(wrap_err)?;
+        let circid = CircId::new(u32::from_be_bytes(
+            cell[0..4]
+                .try_into()
+                .expect("Four-byte slice was not four bytes long!?"),
+        ));
 
         if !cmd.accepts_circid_val(circid) {
             return Err(Error::ChanProto(format!(
@@ -133,6 +142,61 @@ impl ChannelCodec {
                 cmd
             )));
         }
-        Ok(Some(ChanCell { circid, msg }))
+        Ok(Some(RawChanCell {
+            circid,
+            cmd,
+            body: cell.slice(header_len..),
+        }))
+    }
+}
+
+/// A channel cell whose message has not been parsed.
+///
+/// Returned by [`ChannelCodec::decode_raw_cell`]. The body is a
+/// reference-counted view into the buffer that the cell was read from,
+/// so a relay that only needs to look at the command and circuit ID
+/// (or pass the body on unchanged) can do so without copying it.
+#[derive(Clone, Debug)]
+pub struct RawChanCell {
+    /// The circuit ID of this cell, if it has one.
+    circid: Option<CircId>,
+    /// The command of this cell.
+    cmd: ChanCmd,
+    /// The body of this cell.
+    ///
+    /// For a fixed-length cell, this includes any padding at the end.
+    body: Bytes,
+}
+
+impl RawChanCell {
+    /// Return the circuit ID of this cell, if it has one.
+    pub fn circid(&self) -> Option<CircId> {
+        self.circid
+    }
+
+    /// Return the command of this cell.
+    pub fn cmd(&self) -> ChanCmd {
+        self.cmd
+    }
+
+    /// Return the body of this cell.
+    ///
+    /// For a fixed-length cell, this is always [`CELL_DATA_LEN`] bytes long,
+    /// and includes any padding at the end of the message.
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Parse the body of this cell as a message of type `M`.
+    pub fn decode<M: ChanMsg>(&self) -> crate::Result<ChanCell<M>> {
+        let mut r = Reader::from_bytes(&self.body);
+        let msg = M::decode_from_reader(self.cmd, &mut r).map_err(|err| Error::BytesErr {
+            err,
+            parsed: "channel cell",
+        })?;
+        Ok(ChanCell {
+            circid: self.circid,
+            msg,
+        })
     }
 }
@@ -261,6 +261,22 @@ caret_int! {
     }
 }
 
+impl HandshakeType {
+    /// A hybrid post-quantum variant of ntor-v3, using X25519 and ML-KEM-768.
+    ///
+    /// **Provisional.**  No handshake type has been allocated for this
+    /// handshake in [tor-spec] yet, so we use the next unallocated value,
+    /// which may change.  Only relays running the same experimental code
+    /// recognize it.
+    ///
+    /// Its messages are too long for a single cell, so it can only be used
+    /// in fragmented EXTEND2 messages.
+    ///
+    /// [tor-spec]: https://spec.torproject.org/tor-spec/create-created-cells.html
+    #[cfg(feature = "ntor-v3-pq")]
+    pub const NTOR_V3_MLKEM: HandshakeType = HandshakeType(4);
+}
+
 /// A Create2 message create a circuit on the current channel.
 ///
 /// To create a circuit, the client sends a Create2 cell containing a
@@ -7,7 +7,7 @@ use derive_deftly::Deftly;
 use smallvec::{smallvec, SmallVec};
 use tor_bytes::{EncodeError, EncodeResult, Error, Result};
 use tor_bytes::{Reader, Writer};
-use tor_error::internal;
+use tor_error::{bad_api_usage, internal};
 use tor_memquota::derive_deftly_template_HasMemoryCost;
 
 use caret::caret_int;
@@ -16,9 +16,14 @@ use rand::{CryptoRng, Rng};
 #[cfg(feature = "conflux")]
 pub mod conflux;
 pub mod extend;
+pub mod flow_ctrl;
+#[cfg(feature = "relay-fragment")]
+pub mod fragment;
 #[cfg(feature = "hs")]
 pub mod hs;
 pub mod msg;
+#[cfg(feature = "circ-padding")]
+pub mod padding;
 #[cfg(feature = "experimental-udp")]
 pub mod udp;
 
@@ -26,6 +31,7 @@ caret_int! {
     /// A command that identifies the type of a relay cell
     #[derive(Deftly)]
     #[derive_deftly(HasMemoryCost)]
+    #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
     pub struct RelayCmd(u8) {
         /// Start a new stream
         BEGIN = 1,
@@ -76,6 +82,9 @@ caret_int! {
         /// CONFLUX: Switch to another leg in an already linked circuit construction.
         CONFLUX_SWITCH = 22,
 
+        /// A piece of a relay message too long to fit into a single cell (proposal 319).
+        FRAGMENT = 23,
+
         /// HS: establish an introduction point.
         ESTABLISH_INTRO = 32,
         /// HS: establish a rendezvous point.
@@ -99,6 +108,11 @@ caret_int! {
         PADDING_NEGOTIATE = 41,
         /// Padding: reply to a PADDING_NEGOTIATE
         PADDING_NEGOTIATED = 42,
+
+        /// Flow control: stop sending data on a stream
+        XOFF = 43,
+        /// Flow control: start or resume sending data on a stream
+        XON = 44,
     }
 }
 
@@ -129,7 +143,9 @@ impl RelayCmd {
             | RelayCmd::CONNECTED
             | RelayCmd::RESOLVE
             | RelayCmd::RESOLVED
-            | RelayCmd::BEGIN_DIR => StreamIdReq::WantSome,
+            | RelayCmd::BEGIN_DIR
+            | RelayCmd::XOFF
+            | RelayCmd::XON => StreamIdReq::WantSome,
             // NOTE: Even when a RelayCmd is not implemented (like these UDP-based commands),
             // we need to implement expects_streamid() unconditionally.
             // Otherwise we leak more information than necessary
@@ -168,6 +184,12 @@ impl RelayCmd {
                 // When we're checking for general compatibility, we need to allow V0 or V1.
                 None => StreamIdReq::Any,
             },
+            // A fragment carries the stream ID (if any) of the message it is a
+            // piece of. Fragmentation is only defined for V0 cells.
+            RelayCmd::FRAGMENT => match format {
+                Some(RelayCellFormat::V1) => StreamIdReq::WantNone,
+                Some(RelayCellFormat::V0) | None => StreamIdReq::Any,
+            },
             _ => StreamIdReq::Unrecognized,
         }
     }
@@ -241,13 +263,15 @@ impl StreamId {
 /// Specifies which encoding version of RelayCell to use.
 #[non_exhaustive]
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum RelayCellFormat {
     /// This is the "legacy" pre-prop340 format. No packing or fragmentation.
     V0,
     /// A "transitional" format for use with Counter Galois Onion encryption.
     ///
     /// It provides a 16-byte tag field, and a simplified layout for the rest of
-    /// the cell.
+    /// the cell. Several messages can be packed into a single cell:
+    /// see [`RelayCellPacker`].
     V1,
 }
 
@@ -318,19 +342,23 @@ enum RelayCellDecoderInternal {
 pub struct RelayCellDecoder {
     /// Internal representation.
     internal: RelayCellDecoderInternal,
+    /// Reassembles fragmented messages, for `RelayCellFormat::V0`.
+    #[cfg(feature = "relay-fragment")]
+    fragments: fragment::Reassembler,
 }
 
 impl RelayCellDecoder {
     /// Returns a new `Decoder`, handling a stream of relay cells
     /// of the given `version`.
     pub fn new(version: RelayCellFormat) -> Self {
-        match version {
-            RelayCellFormat::V0 => Self {
-                internal: RelayCellDecoderInternal::V0,
-            },
-            RelayCellFormat::V1 => Self {
-                internal: RelayCellDecoderInternal::V1,
-            },
+        let internal = match version {
+            RelayCellFormat::V0 => RelayCellDecoderInternal::V0,
+            RelayCellFormat::V1 => RelayCellDecoderInternal::V1,
+        };
+        Self {
+            internal,
+            #[cfg(feature = "relay-fragment")]
+            fragments: Default::default(),
         }
     }
     /// Parse a RELAY or RELAY_EARLY cell body.
@@ -340,12 +368,22 @@ impl RelayCellDecoder {
     pub fn decode(&mut self, cell: BoxedCellBody) -> Result<RelayCellDecoderResult> {
         let msg_internal = match &self.internal {
             RelayCellDecoderInternal::V0 => UnparsedRelayMsgInternal::V0(cell),
-            RelayCellDecoderInternal::V1 => UnparsedRelayMsgInternal::V1(cell),
+            RelayCellDecoderInternal::V1 => {
+                return Ok(RelayCellDecoderResult {
+                    msgs: unpack_v1(cell),
+                    incomplete: None,
+                })
+            }
+        };
+        let msg = UnparsedRelayMsg {
+            internal: msg_internal,
         };
+        #[cfg(feature = "relay-fragment")]
+        if matches!(self.internal, RelayCellDecoderInternal::V0) {
+            return self.fragments.push(msg);
+        }
         Ok(RelayCellDecoderResult {
-            msgs: smallvec![UnparsedRelayMsg {
-                internal: msg_internal
-            }],
+            msgs: smallvec![msg],
             incomplete: None,
         })
     }
@@ -354,8 +392,13 @@ impl RelayCellDecoder {
     /// stream.
     pub fn incomplete_info(&self) -> Option<IncompleteRelayMsgInfo> {
         match &self.internal {
-            // V0 and V1 don't support fragmentation, so there is never a pending fragment.
-            RelayCellDecoderInternal::V0 | RelayCellDecoderInternal::V1 => None,
+            #[cfg(feature = "relay-fragment")]
+            RelayCellDecoderInternal::V0 => self.fragments.incomplete_info(),
+            // Without RELAY_FRAGMENT support, V0 doesn't support fragmentation,
+            // and V1 never does, so there is never a pending fragment.
+            #[cfg(not(feature = "relay-fragment"))]
+            RelayCellDecoderInternal::V0 => None,
+            RelayCellDecoderInternal::V1 => None,
         }
     }
 }
@@ -459,9 +502,21 @@ enum UnparsedRelayMsgInternal {
     // different functions here, but that information shouldn't leak out of this module.
     V0(BoxedCellBody),
 
-    /// For `V1` we can also avoid copies, since there is still exactly one
-    /// relay message per cell.
-    V1(BoxedCellBody),
+    /// For `V1` we can also avoid copies in the common case where there is
+    /// exactly one relay message per cell.
+    ///
+    /// When several messages are packed into a cell, each of them has its own
+    /// copy of the cell body.
+    V1 {
+        /// The cell body.
+        body: BoxedCellBody,
+        /// The position of this message (its command byte) within `body`.
+        offset: usize,
+    },
+
+    /// A message that we reassembled from several `FRAGMENT` messages.
+    #[cfg(feature = "relay-fragment")]
+    Reassembled(fragment::ReassembledMsg),
 }
 
 /// An enveloped relay message that has not yet been fully parsed, but where we
@@ -477,14 +532,73 @@ pub struct UnparsedRelayMsg {
 /// Position of the stream ID within the V0 cell body.
 const STREAM_ID_OFFSET_V0: usize = 3;
 
-/// Position of the stream ID within the V1 cell body, if it is present.
-const STREAM_ID_OFFSET_V1: usize = 16 + 1 + 2; // tag, command, length.
+/// Length of the tag at the start of a V1 cell body.
+const TAG_LEN_V1: usize = 16;
+
+/// Position of the stream ID within a V1 message, if it is present.
+const STREAM_ID_OFFSET_V1: usize = 1 + 2; // command, length.
 
 /// Position of the payload data length within the V0 cell body.
 const LENGTH_OFFSET_V0: usize = 1 + 2 + 2 + 4; // command, recognized, stream_id, digest.
 
-/// Position of the payload data length within the V1 cell body.
-const LENGTH_OFFSET_V1: usize = 16 + 1; // tag, command.
+/// Position of the payload data length within a V1 message.
+const LENGTH_OFFSET_V1: usize = 1; // command.
+
+/// Split a V1 cell body into the messages packed into it.
+///
+/// The first message starts right after the tag. Every later message starts
+/// right after the previous one, unless there isn't room for another message
+/// header, or the next byte is zero or an unrecognized command:
+/// in that case, the rest of the cell is padding.
+///
+/// We don't validate the messages here: that happens when they are decoded.
+fn unpack_v1(cell: BoxedCellBody) -> SmallVec<[UnparsedRelayMsg; 1]> {
+    let mut offsets: SmallVec<[usize; 1]> = smallvec![TAG_LEN_V1];
+    let mut offset = TAG_LEN_V1;
+    loop {
+        let cmd: RelayCmd = cell[offset].into();
+        let len = usize::from(u16::from_be_bytes(
+            cell[offset + LENGTH_OFFSET_V1..offset + LENGTH_OFFSET_V1 + 2]
+                .try_into()
+                .expect("two-byte slice was not two bytes long!?"),
+        ));
+        let header_len = match cmd.expects_streamid(Some(RelayCellFormat::V1)) {
+            StreamIdReq::WantNone => STREAM_ID_OFFSET_V1,
+            StreamIdReq::WantSome => STREAM_ID_OFFSET_V1 + 2,
+            // We can't tell where this message ends; decoding it will fail.
+            StreamIdReq::Unrecognized | StreamIdReq::Any => break,
+        };
+        let next = offset + header_len + len;
+        if next + STREAM_ID_OFFSET_V1 > CELL_DATA_LEN
+            || !matches!(
+                RelayCmd::from(cell[next]).expects_streamid(Some(RelayCellFormat::V1)),
+                StreamIdReq::WantNone | StreamIdReq::WantSome
+            )
+        {
+            break;
+        }
+        offsets.push(next);
+        offset = next;
+    }
+
+    let (last, rest) = offsets.split_last().expect("offsets was empty!?");
+    let mut msgs: SmallVec<[UnparsedRelayMsg; 1]> = rest
+        .iter()
+        .map(|&offset| UnparsedRelayMsg {
+            internal: UnparsedRelayMsgInternal::V1 {
+                body: cell.clone(),
+                offset,
+            },
+        })
+        .collect();
+    msgs.push(UnparsedRelayMsg {
+        internal: UnparsedRelayMsgInternal::V1 {
+            body: cell,
+            offset: *last,
+        },
+    });
+    msgs
+}
 
 impl UnparsedRelayMsg {
     /// Wrap a BoxedCellBody as an UnparsedRelayMsg.
@@ -523,11 +637,9 @@ impl UnparsedRelayMsg {
                 const CMD_OFFSET: usize = 0;
                 body[CMD_OFFSET].into()
             }
-            UnparsedRelayMsgInternal::V1(body) => {
-                /// Position of the command within the v1 body.
-                const CMD_OFFSET: usize = 16;
-                body[CMD_OFFSET].into()
-            }
+            UnparsedRelayMsgInternal::V1 { body, offset } => body[*offset].into(),
+            #[cfg(feature = "relay-fragment")]
+            UnparsedRelayMsgInternal::Reassembled(m) => m.cmd,
         }
     }
     /// Return the stream ID for the stream that this msg corresponds to, if any.
@@ -538,17 +650,22 @@ impl UnparsedRelayMsg {
                     .try_into()
                     .expect("two-byte slice was not two bytes long!?"),
             )),
-            UnparsedRelayMsgInternal::V1(body) => {
+            UnparsedRelayMsgInternal::V1 { body, offset } => {
                 match self.cmd().expects_streamid(Some(RelayCellFormat::V1)) {
                     StreamIdReq::WantNone => None,
                     StreamIdReq::Unrecognized | StreamIdReq::Any => None,
-                    StreamIdReq::WantSome => StreamId::new(u16::from_be_bytes(
-                        body[STREAM_ID_OFFSET_V1..STREAM_ID_OFFSET_V1 + 2]
-                            .try_into()
-                            .expect("two-byte slice was not two bytes long!?"),
-                    )),
+                    StreamIdReq::WantSome => {
+                        let pos = offset + STREAM_ID_OFFSET_V1;
+                        StreamId::new(u16::from_be_bytes(
+                            body[pos..pos + 2]
+                                .try_into()
+                                .expect("two-byte slice was not two bytes long!?"),
+                        ))
+                    }
                 }
             }
+            #[cfg(feature = "relay-fragment")]
+            UnparsedRelayMsgInternal::Reassembled(m) => m.stream_id,
         }
     }
     /// Return the "length" field of the cell.
@@ -558,12 +675,49 @@ impl UnparsedRelayMsg {
     pub fn data_len(&self) -> u16 {
         let bytes: [u8; 2] = match &self.internal {
             UnparsedRelayMsgInternal::V0(body) => &body[LENGTH_OFFSET_V0..LENGTH_OFFSET_V0 + 2],
-            UnparsedRelayMsgInternal::V1(body) => &body[LENGTH_OFFSET_V1..LENGTH_OFFSET_V1 + 2],
+            UnparsedRelayMsgInternal::V1 { body, offset } => {
+                &body[offset + LENGTH_OFFSET_V1..offset + LENGTH_OFFSET_V1 + 2]
+            }
+            #[cfg(feature = "relay-fragment")]
+            UnparsedRelayMsgInternal::Reassembled(m) => {
+                // The reassembler never accepts bodies longer than u16::MAX.
+                return m.body.len() as u16;
+            }
         }
         .try_into()
         .expect("two-byte slice was not two bytes long!?");
         u16::from_be_bytes(bytes)
     }
+    /// Return the body of this message (its "data" field), without copying it.
+    ///
+    /// A caller that only needs the raw bytes of a message, such as the
+    /// payload of a DATA message, can use this instead of [`decode`](Self::decode)
+    /// to avoid allocating an owned copy of the body.
+    ///
+    /// Returns an error if the message's length field claims more data than
+    /// the cell holds, or if its command is not one we recognize (in
+    /// [`RelayCellFormat::V1`], we can't tell where such a message begins).
+    pub fn body(&self) -> Result<&[u8]> {
+        let (body, start): (&[u8], usize) = match &self.internal {
+            UnparsedRelayMsgInternal::V0(body) => (&body[..], LENGTH_OFFSET_V0 + 2),
+            UnparsedRelayMsgInternal::V1 { body, offset } => {
+                let header_len = match self.cmd().expects_streamid(Some(RelayCellFormat::V1)) {
+                    StreamIdReq::WantNone => STREAM_ID_OFFSET_V1,
+                    StreamIdReq::WantSome => STREAM_ID_OFFSET_V1 + 2,
+                    StreamIdReq::Unrecognized | StreamIdReq::Any => {
+                        return Err(Error::InvalidMessage(
+                            format!("Unrecognized relay command {}", self.cmd()).into(),
+                        ))
+                    }
+                };
+                (&body[..], offset + header_len)
+            }
+            #[cfg(feature = "relay-fragment")]
+            UnparsedRelayMsgInternal::Reassembled(m) => return Ok(&m.body),
+        };
+        body.get(start..start + usize::from(self.data_len()))
+            .ok_or_else(|| Error::InvalidMessage("Insufficient data in relay cell".into()))
+    }
     /// Decode this unparsed cell into a given cell type.
     pub fn decode<M: RelayMsg>(self) -> Result<RelayMsgOuter<M>> {
         match self.internal {
@@ -571,10 +725,19 @@ impl UnparsedRelayMsg {
                 let mut reader = Reader::from_slice(body.as_ref());
                 RelayMsgOuter::decode_v0_from_reader(&mut reader)
             }
-            UnparsedRelayMsgInternal::V1(body) => {
-                let mut reader = Reader::from_slice(body.as_ref());
+            UnparsedRelayMsgInternal::V1 { body, offset } => {
+                let mut reader = Reader::from_slice(&body[offset..]);
                 RelayMsgOuter::decode_v1_from_reader(&mut reader)
             }
+            #[cfg(feature = "relay-fragment")]
+            UnparsedRelayMsgInternal::Reassembled(m) => {
+                let mut reader = Reader::from_slice(&m.body);
+                let msg = M::decode_from_reader(m.cmd, &mut reader)?;
+                Ok(RelayMsgOuter {
+                    streamid: m.stream_id,
+                    msg,
+                })
+            }
         }
     }
 }
@@ -656,25 +819,83 @@ impl<M: RelayMsg> RelayMsgOuter<M> {
         format: RelayCellFormat,
         rng: &mut R,
     ) -> crate::Result<BoxedCellBody> {
-        /// We skip this much space before adding any random padding to the
-        /// end of the cell
-        const MIN_SPACE_BEFORE_PADDING: usize = 4;
-
         let (mut body, enc_len) = match format {
             RelayCellFormat::V0 => self.encode_to_cell_v0()?,
             RelayCellFormat::V1 => self.encode_to_cell_v1()?,
         };
         debug_assert!(enc_len <= CELL_DATA_LEN);
-        if enc_len < CELL_DATA_LEN - MIN_SPACE_BEFORE_PADDING {
-            rng.fill_bytes(&mut body[enc_len + MIN_SPACE_BEFORE_PADDING..]);
-        }
+        pad_cell_body(&mut body[..], enc_len, rng);
 
         Ok(body)
     }
 
+    /// Consume this relay message and encode it as a 509-byte padded cell
+    /// body, into the first [`CELL_DATA_LEN`] bytes of `buf`.
+    ///
+    /// This is the same as [`encode`](Self::encode), except that it doesn't
+    /// allocate: a caller that keeps a pool of cell buffers can encode into
+    /// them directly. Any previous contents of those bytes are overwritten.
+    ///
+    /// Fails if `buf` is shorter than [`CELL_DATA_LEN`].
+    pub fn encode_into<R: Rng + CryptoRng>(
+        self,
+        format: RelayCellFormat,
+        buf: &mut [u8],
+        rng: &mut R,
+    ) -> crate::Result<()> {
+        let buf_len = buf.len();
+        let body = buf.get_mut(..CELL_DATA_LEN).ok_or_else(|| {
+            crate::Error::Internal(bad_api_usage!(
+                "Buffer of {buf_len} bytes is too short for a relay cell"
+            ))
+        })?;
+        body.fill(0);
+        let enc_len = match format {
+            RelayCellFormat::V0 => self.write_cell_v0(body)?,
+            RelayCellFormat::V1 => self.write_cell_v1(body)?,
+        };
+        debug_assert!(enc_len <= CELL_DATA_LEN);
+        pad_cell_body(body, enc_len, rng);
+
+        Ok(())
+    }
+
+    /// Return the number of bytes of a cell body that this message would
+    /// take up if it were encoded in `format`, not counting padding.
+    ///
+    /// Fails if the message is too long to fit in a cell.
+    ///
+    /// This encodes a copy of the message onto the stack, so it doesn't
+    /// allocate unless cloning the message does.
+    pub fn encoded_len(&self, format: RelayCellFormat) -> crate::Result<usize>
+    where
+        M: Clone,
+    {
+        let mut body = [0_u8; CELL_DATA_LEN];
+        let this = RelayMsgOuter {
+            streamid: self.streamid,
+            msg: self.msg.clone(),
+        };
+        Ok(match format {
+            RelayCellFormat::V0 => this.write_cell_v0(&mut body[..])?,
+            RelayCellFormat::V1 => this.write_cell_v1(&mut body[..])?,
+        })
+    }
+
     /// Consume a relay cell and return its contents, encoded for use
     /// in a RELAY or RELAY_EARLY cell.
     fn encode_to_cell_v0(self) -> EncodeResult<(BoxedCellBody, usize)> {
+        let mut body = Box::new([0_u8; CELL_DATA_LEN]);
+        let written = self.write_cell_v0(&mut body[..])?;
+        Ok((body, written))
+    }
+
+    /// Consume a relay cell and write its contents into `body`, encoded for
+    /// use in a RELAY or RELAY_EARLY cell.
+    ///
+    /// `body` must be [`CELL_DATA_LEN`] bytes long, and zeroed.
+    /// Returns the number of bytes written.
+    fn write_cell_v0(self, body: &mut [u8]) -> EncodeResult<usize> {
         // NOTE: This implementation is a bit optimized, since it happens to
         // literally every relay cell that we produce.
 
@@ -686,8 +907,6 @@ impl<M: RelayMsg> RelayMsgOuter<M> {
         /// The position of the body a relay cell.
         const BODY_POS: usize = 11;
 
-        let body = BodyWrapper(Box::new([0_u8; 509]));
-
         let mut w = crate::slicewriter::SliceWriter::new(body);
         w.write_u8(self.msg.cmd().into());
         w.write_u16(0); // "Recognized"
@@ -699,22 +918,33 @@ impl<M: RelayMsg> RelayMsgOuter<M> {
         w.write_u16(0); // Length.
         w.assert_offset_is(BODY_POS);
         self.msg.encode_onto(&mut w)?; // body
-        let (mut body, written) = w.try_unwrap().map_err(|_| {
+        let (body, written) = w.try_unwrap().map_err(|_| {
             EncodeError::Bug(internal!(
                 "Encoding of relay message was too long to fit into a cell!"
             ))
         })?;
         let payload_len = written - BODY_POS;
         debug_assert!(payload_len < u16::MAX as usize);
-        *(<&mut [u8; 2]>::try_from(&mut body.0[LEN_POS..LEN_POS + 2])
+        *(<&mut [u8; 2]>::try_from(&mut body[LEN_POS..LEN_POS + 2])
             .expect("Two-byte slice was not two bytes long!?")) =
             (payload_len as u16).to_be_bytes();
-        Ok((body.0, written))
+        Ok(written)
     }
 
     /// Consume a relay cell and return its contents, encoded for use
     /// in a RELAY or RELAY_EARLY cell.
     fn encode_to_cell_v1(self) -> EncodeResult<(BoxedCellBody, usize)> {
+        let mut body = Box::new([0_u8; CELL_DATA_LEN]);
+        let written = self.write_cell_v1(&mut body[..])?;
+        Ok((body, written))
+    }
+
+    /// Consume a relay cell and write its contents into `body`, encoded for
+    /// use in a RELAY or RELAY_EARLY cell.
+    ///
+    /// `body` must be [`CELL_DATA_LEN`] bytes long, and zeroed.
+    /// Returns the number of bytes written.
+    fn write_cell_v1(self, body: &mut [u8]) -> EncodeResult<usize> {
         // NOTE: This implementation is a bit optimized, since it happens to
         // literally every relay cell that we produce.
         // TODO -NM: Add a specialized implementation for making a DATA cell from
@@ -724,7 +954,6 @@ impl<M: RelayMsg> RelayMsgOuter<M> {
         const LEN_POS_V1: usize = 16 + 1; // Skipping tag, command.
 
         let cmd = self.msg.cmd();
-        let body = BodyWrapper(Box::new([0_u8; 509]));
         let mut w = crate::slicewriter::SliceWriter::new(body);
         w.advance(16); // Tag: 16 bytes
         w.write_u8(cmd.get()); // Command: 1 byte.
@@ -749,17 +978,17 @@ impl<M: RelayMsg> RelayMsgOuter<M> {
         w.assert_offset_is(body_pos);
 
         self.msg.encode_onto(&mut w)?; // body
-        let (mut body, written) = w.try_unwrap().map_err(|_| {
+        let (body, written) = w.try_unwrap().map_err(|_| {
             EncodeError::Bug(internal!(
                 "Encoding of relay message was too long to fit into a cell!"
             ))
         })?;
         let payload_len = written - body_pos;
         debug_assert!(payload_len < u16::MAX as usize);
-        *(<&mut [u8; 2]>::try_from(&mut body.0[LEN_POS_V1..LEN_POS_V1 + 2])
+        *(<&mut [u8; 2]>::try_from(&mut body[LEN_POS_V1..LEN_POS_V1 + 2])
             .expect("Two-byte slice was not two bytes long!?")) =
             (payload_len as u16).to_be_bytes();
-        Ok((body.0, written))
+        Ok(written)
     }
 
     /// Parse a RELAY or RELAY_EARLY cell body into a RelayMsgOuter.
@@ -798,13 +1027,13 @@ impl<M: RelayMsg> RelayMsgOuter<M> {
         Ok(Self { streamid, msg })
     }
 
-    /// Parse a `RelayCellFormat::V1` RELAY or RELAY_EARLY cell body into a
-    /// RelayMsgOuter from a reader.
+    /// Parse a single message from a `RelayCellFormat::V1` RELAY or RELAY_EARLY
+    /// cell body into a RelayMsgOuter, from a reader positioned at the start of
+    /// the message.
     ///
     /// Requires that the cryptographic checks on the message have already been
     /// performed.
     fn decode_v1_from_reader(r: &mut Reader<'_>) -> Result<Self> {
-        r.advance(16)?; // Tag
         let cmd: RelayCmd = r.take_u8()?.into();
         let len = r.take_u16()?.into();
         let streamid = match cmd.expects_streamid(Some(RelayCellFormat::V1)) {
@@ -849,10 +1078,88 @@ impl<M: RelayMsg> RelayMsgOuter<M> {
     }
 }
 
-/// Wrap a BoxedCellBody and implement AsMut<[u8]>, so we can use it with `SliceWriter`.
-struct BodyWrapper(BoxedCellBody);
-impl AsMut<[u8]> for BodyWrapper {
-    fn as_mut(&mut self) -> &mut [u8] {
-        self.0.as_mut()
+/// Fill the unused part of `body`, after its first `enc_len` bytes, with random padding.
+fn pad_cell_body<R: Rng + CryptoRng>(body: &mut [u8], enc_len: usize, rng: &mut R) {
+    /// We skip this much space before adding any random padding to the
+    /// end of the cell.
+    ///
+    /// (In `RelayCellFormat::V1`, this also keeps the padding from being
+    /// mistaken for another message.)
+    const MIN_SPACE_BEFORE_PADDING: usize = 4;
+
+    if enc_len < CELL_DATA_LEN - MIN_SPACE_BEFORE_PADDING {
+        rng.fill_bytes(&mut body[enc_len + MIN_SPACE_BEFORE_PADDING..]);
+    }
+}
+
+/// Packs several relay messages into a single [`RelayCellFormat::V1`] cell body.
+///
+/// Messages are added with [`push`](RelayCellPacker::push), and the packed
+/// cell body is obtained with [`finish`](RelayCellPacker::finish).
+/// The packer can be reused once it has been finished.
+#[derive(Debug, Default)]
+pub struct RelayCellPacker {
+    /// The cell body that we are filling, if we have added any messages to it.
+    body: Option<BoxedCellBody>,
+    /// The number of bytes of `body` that are in use, including the tag.
+    len: usize,
+    /// The number of messages in `body`.
+    n_msgs: usize,
+}
+
+impl RelayCellPacker {
+    /// Return a new, empty `RelayCellPacker`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return true if we haven't added any messages since the last time the
+    /// packer was finished.
+    pub fn is_empty(&self) -> bool {
+        self.body.is_none()
+    }
+
+    /// Return the number of messages in the cell that we are packing.
+    pub fn n_msgs(&self) -> usize {
+        self.n_msgs
+    }
+
+    /// Add `msg` to the cell that we are packing.
+    ///
+    /// If there isn't room for `msg` in that cell, the cell is finished and
+    /// returned, and `msg` becomes the first message of a new cell.
+    pub fn push<M: RelayMsg, R: Rng + CryptoRng>(
+        &mut self,
+        msg: RelayMsgOuter<M>,
+        rng: &mut R,
+    ) -> crate::Result<Option<BoxedCellBody>> {
+        let (encoded, enc_len) = msg.encode_to_cell_v1()?;
+        let msg_len = enc_len - TAG_LEN_V1;
+
+        if let Some(body) = self.body.as_mut() {
+            if self.len + msg_len <= CELL_DATA_LEN {
+                body[self.len..self.len + msg_len].copy_from_slice(&encoded[TAG_LEN_V1..enc_len]);
+                self.len += msg_len;
+                self.n_msgs += 1;
+                return Ok(None);
+            }
+        }
+
+        let finished = self.finish(rng);
+        self.body = Some(encoded);
+        self.len = enc_len;
+        self.n_msgs = 1;
+        Ok(finished)
+    }
+
+    /// Finish the cell that we are packing, and return its body.
+    ///
+    /// Returns `None` if we haven't added any messages to it.
+    pub fn finish<R: Rng + CryptoRng>(&mut self, rng: &mut R) -> Option<BoxedCellBody> {
+        let mut body = self.body.take()?;
+        pad_cell_body(&mut body[..], self.len, rng);
+        self.len = 0;
+        self.n_msgs = 0;
+        Some(body)
     }
 }
@@ -10,7 +10,9 @@ caret_int! {
         /// Request congestion control be enabled for a circuit.
         CC_REQUEST = 1,
         /// Acknowledge a congestion control request.
-        CC_RESPONSE = 2
+        CC_RESPONSE = 2,
+        /// Request particular subprotocol versions for a circuit.
+        SUBPROTO_REQUEST = 3
     }
 }
 
@@ -29,6 +31,18 @@ pub enum NtorV3Extension {
         /// The exit's current view of the `cc_sendme_inc` consensus parameter.
         sendme_inc: u8,
     },
+    /// Request that the relay use particular subprotocol versions on this
+    /// circuit (client → relay).
+    ///
+    /// A relay that can't honor the request must refuse the handshake.
+    /// See [proposal 346](https://spec.torproject.org/proposals/346-protovers-again.html).
+    ///
+    /// (`EXT_FIELD_TYPE` = 03)
+    SubprotocolRequest {
+        /// The requested subprotocol versions, as `(protocol, version)` pairs
+        /// of numeric identifiers.
+        protocols: Vec<(u8, u8)>,
+    },
     /// An unknown piece of extension data.
     Unrecognized {
         /// The extension type (`EXT_FIELD_TYPE`).
@@ -83,6 +97,17 @@ impl Writeable for NtorV3Extension {
             NtorV3Extension::AckCongestionControl { sendme_inc } => {
                 out.write_all(&[2, 1, *sendme_inc]);
             }
+            NtorV3Extension::SubprotocolRequest { protocols } => {
+                let len = protocols
+                    .len()
+                    .checked_mul(2)
+                    .and_then(|len| u8::try_from(len).ok())
+                    .ok_or(tor_bytes::EncodeError::BadLengthValue)?;
+                out.write_all(&[3, len]);
+                for (protocol, version) in protocols {
+                    out.write_all(&[*protocol, *version]);
+                }
+            }
             NtorV3Extension::Unrecognized { field_type, data } => {
                 // FIXME(eta): This will break if you try and fill `data` with more than 255 bytes.
                 //             This is only a problem if you construct your own `Unrecognized`, though.
@@ -116,6 +141,17 @@ impl Readable for NtorV3Extension {
                 let sendme_inc = reader.take_u8()?;
                 NtorV3Extension::AckCongestionControl { sendme_inc }
             }
+            NtorV3ExtensionType::SUBPROTO_REQUEST => {
+                if len % 2 != 0 {
+                    return Err(tor_bytes::Error::InvalidMessage(
+                        "invalid length for SubprotocolRequest".into(),
+                    ));
+                }
+                let protocols = (0..len / 2)
+                    .map(|_| Ok((reader.take_u8()?, reader.take_u8()?)))
+                    .collect::<tor_bytes::Result<_>>()?;
+                NtorV3Extension::SubprotocolRequest { protocols }
+            }
             x => {
                 let mut data = vec![0; len as usize];
                 reader.take_into(&mut data)?;
@@ -127,3 +163,38 @@ impl Readable for NtorV3Extension {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn subproto_request() {
+        let exts = vec![
+            NtorV3Extension::RequestCongestionControl,
+            NtorV3Extension::SubprotocolRequest {
+                protocols: vec![(2, 6), (11, 1)],
+            },
+        ];
+        let mut msg = Vec::new();
+        NtorV3Extension::write_many_onto(&exts, &mut msg).unwrap();
+        assert_eq!(msg, [2, 1, 0, 3, 4, 2, 6, 11, 1]);
+        assert_eq!(NtorV3Extension::decode(&msg).unwrap(), exts);
+
+        // A request with half a pair in it is malformed.
+        assert!(NtorV3Extension::decode(&[1, 3, 3, 2, 6, 11]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Encoding and decoding for relay messages related to stream flow control.
+//!
+//! When congestion control is in use on a circuit, streams on that circuit
+//! don't use stream-level SENDME messages. Instead, a receiver that can't keep
+//! up sends an `XOFF` message to ask the sender to stop sending data on the
+//! stream, and an `XON` message to let it start again.
+//!
+//! See proposal 324, "RTT-based Congestion Control for Tor".
+
+use super::msg::Body;
+
+use std::num::NonZeroU32;
+
+use amplify::Getters;
+use derive_deftly::Deftly;
+
+use tor_bytes::{EncodeResult, Error, Reader, Result, Writer};
+use tor_memquota::{derive_deftly_template_HasMemoryCost, memory_cost_structural_copy};
+
+/// The supported XON and XOFF version.
+const FLOW_CTRL_VERSION: u8 = 0;
+
+/// Read and check the version byte of a flow control message.
+fn take_version(r: &mut Reader<'_>) -> Result<()> {
+    let version = r.take_u8()?;
+    if version != FLOW_CTRL_VERSION {
+        return Err(Error::InvalidMessage(
+            "Unrecognized XON/XOFF version.".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// An `XOFF` message, asking the other side to stop sending data on a stream.
+#[derive(Clone, Debug, Default, Deftly)]
+#[derive_deftly(HasMemoryCost)]
+#[non_exhaustive]
+pub struct Xoff {}
+
+impl Xoff {
+    /// Create a new `XOFF` message.
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Body for Xoff {
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        take_version(r)?;
+        Ok(Self {})
+    }
+
+    fn encode_onto<W: Writer + ?Sized>(self, w: &mut W) -> EncodeResult<()> {
+        w.write_u8(FLOW_CTRL_VERSION);
+        Ok(())
+    }
+}
+
+/// The rate advice in an [`Xon`] message.
+///
+/// This is the rate, in kilobytes per second, at which the receiver of a stream
+/// has recently been draining the data that we sent it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[allow(clippy::exhaustive_enums)]
+pub enum XonKbpsEwma {
+    /// The sender should send no faster than this many kilobytes per second.
+    Limited(NonZeroU32),
+    /// The sender may send as fast as it likes.
+    Unlimited,
+}
+
+memory_cost_structural_copy!(XonKbpsEwma);
+
+impl From<u32> for XonKbpsEwma {
+    fn from(kbps: u32) -> Self {
+        match NonZeroU32::new(kbps) {
+            Some(kbps) => XonKbpsEwma::Limited(kbps),
+            None => XonKbpsEwma::Unlimited,
+        }
+    }
+}
+
+impl From<XonKbpsEwma> for u32 {
+    fn from(kbps: XonKbpsEwma) -> u32 {
+        match kbps {
+            XonKbpsEwma::Limited(kbps) => kbps.get(),
+            XonKbpsEwma::Unlimited => 0,
+        }
+    }
+}
+
+/// An `XON` message, asking the other side to start (or resume) sending data
+/// on a stream, at no more than the advised rate.
+#[derive(Clone, Debug, Deftly, Getters)]
+#[derive_deftly(HasMemoryCost)]
+pub struct Xon {
+    /// The rate at which the other side should send.
+    #[getter(as_copy)]
+    kbps_ewma: XonKbpsEwma,
+}
+
+impl Xon {
+    /// Create a new `XON` message with the given rate advice.
+    pub fn new(kbps_ewma: XonKbpsEwma) -> Self {
+        Self { kbps_ewma }
+    }
+}
+
+impl Body for Xon {
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        take_version(r)?;
+        let kbps_ewma = r.take_u32()?.into();
+        Ok(Self { kbps_ewma })
+    }
+
+    fn encode_onto<W: Writer + ?Sized>(self, w: &mut W) -> EncodeResult<()> {
+        w.write_u8(FLOW_CTRL_VERSION);
+        w.write_u32(self.kbps_ewma.into());
+        Ok(())
+    }
+}
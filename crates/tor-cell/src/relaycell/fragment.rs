@@ -0,0 +1,245 @@
+//! Splitting long relay messages into `RELAY_FRAGMENT` messages, and
+//! reassembling them, as described in proposal 319.
+//!
+//! A relay message whose body does not fit into a single relay cell is sent
+//! as a sequence of `FRAGMENT` messages, each in its own cell, carrying the
+//! stream ID of the original message.
+//! The first fragment begins with a short header:
+//!
+//! ```text
+//!    u8  command      -- the command of the fragmented message
+//!    u16 total_len    -- the length of the fragmented message's body
+//! ```
+//!
+//! and the rest of that fragment, and the whole of every later fragment,
+//! is a piece of the original message body.
+//!
+//! The fragments of a message must be sent consecutively:
+//! no other relay message may be sent to the same hop until the
+//! message is complete.
+//!
+//! Only [`RelayCellFormat::V0`] supports fragmentation.
+
+use super::msg::{AnyRelayMsg, Unrecognized};
+use super::{
+    IncompleteRelayMsgInfo, RelayCellDecoderResult, RelayCellFormat, RelayCmd, RelayMsg,
+    RelayMsgOuter, StreamId, UnparsedRelayMsg, UnparsedRelayMsgInternal, LENGTH_OFFSET_V0,
+};
+use crate::chancell::{BoxedCellBody, CELL_DATA_LEN};
+
+use derive_deftly::Deftly;
+use rand::{CryptoRng, Rng};
+use smallvec::smallvec;
+use tor_bytes::{Error, Reader, Result, Writer};
+use tor_error::internal;
+use tor_memquota::derive_deftly_template_HasMemoryCost;
+
+/// The largest message body that fits in a single V0 relay cell.
+const MAX_BODY_LEN_V0: usize = CELL_DATA_LEN - (LENGTH_OFFSET_V0 + 2);
+
+/// The length of the header at the start of the first fragment of a message.
+const FIRST_FRAGMENT_HEADER_LEN: usize = 1 + 2; // command, total_len.
+
+/// The largest message body that we can send or receive in fragments.
+pub const MAX_FRAGMENTED_MSG_LEN: usize = u16::MAX as usize;
+
+/// A relay message that we have reassembled from its fragments.
+#[derive(Clone, Debug, Deftly)]
+#[derive_deftly(HasMemoryCost)]
+pub(super) struct ReassembledMsg {
+    /// The command of the message.
+    pub(super) cmd: RelayCmd,
+    /// The stream ID of the message, if any.
+    pub(super) stream_id: Option<StreamId>,
+    /// The body of the message.
+    pub(super) body: Vec<u8>,
+}
+
+/// A fragmented message that we have only partially received.
+#[derive(Clone, Debug)]
+struct PartialMsg {
+    /// The command of the message.
+    cmd: RelayCmd,
+    /// The stream ID of the message, if any.
+    stream_id: Option<StreamId>,
+    /// The total length of the message body, as announced in the first fragment.
+    total_len: usize,
+    /// The part of the message body that we have received so far.
+    body: Vec<u8>,
+}
+
+impl PartialMsg {
+    /// Return an `IncompleteRelayMsgInfo` describing this message.
+    fn info(&self) -> IncompleteRelayMsgInfo {
+        IncompleteRelayMsgInfo {
+            cmd: self.cmd,
+            stream_id: self.stream_id,
+            total_msg_len: self.total_len,
+            num_bytes_present: self.body.len(),
+        }
+    }
+}
+
+/// Reassembles fragmented messages from a stream of V0 relay messages.
+///
+/// At most one message can be partially received at a time.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Reassembler {
+    /// The message that we are currently reassembling, if any.
+    partial: Option<PartialMsg>,
+}
+
+impl Reassembler {
+    /// Process a single message `msg` decoded from a V0 relay cell.
+    ///
+    /// Messages other than `FRAGMENT` are returned unchanged. Fragments are
+    /// accumulated until the message they belong to is complete.
+    pub(super) fn push(&mut self, msg: UnparsedRelayMsg) -> Result<RelayCellDecoderResult> {
+        if msg.cmd() != RelayCmd::FRAGMENT {
+            if let Some(partial) = &self.partial {
+                return Err(Error::InvalidMessage(
+                    format!(
+                        "Received {} while waiting for the rest of a fragmented {}",
+                        msg.cmd(),
+                        partial.cmd
+                    )
+                    .into(),
+                ));
+            }
+            return Ok(RelayCellDecoderResult {
+                msgs: smallvec![msg],
+                incomplete: None,
+            });
+        }
+
+        let stream_id = msg.stream_id();
+        let UnparsedRelayMsgInternal::V0(cell) = &msg.internal else {
+            return Err(Error::Bug(internal!(
+                "Tried to reassemble a fragment from a non-V0 relay cell"
+            )));
+        };
+        let len = usize::from(msg.data_len());
+        let body_pos = LENGTH_OFFSET_V0 + 2;
+        if len > MAX_BODY_LEN_V0 {
+            return Err(Error::InvalidMessage(
+                "Insufficient data in relay cell".into(),
+            ));
+        }
+        let mut r = Reader::from_slice(&cell[body_pos..body_pos + len]);
+
+        let mut partial = match self.partial.take() {
+            Some(partial) => {
+                if partial.stream_id != stream_id {
+                    return Err(Error::InvalidMessage(
+                        "Fragment has the wrong stream ID".into(),
+                    ));
+                }
+                partial
+            }
+            None => {
+                let cmd: RelayCmd = r.take_u8()?.into();
+                let total_len = usize::from(r.take_u16()?);
+                if cmd == RelayCmd::FRAGMENT {
+                    return Err(Error::InvalidMessage("Fragment of a fragment".into()));
+                }
+                if total_len == 0 {
+                    return Err(Error::InvalidMessage(
+                        "Fragmented message with an empty body".into(),
+                    ));
+                }
+                PartialMsg {
+                    cmd,
+                    stream_id,
+                    total_len,
+                    body: Vec::with_capacity(total_len),
+                }
+            }
+        };
+
+        let chunk = r.take_rest();
+        if partial.body.len() + chunk.len() > partial.total_len {
+            return Err(Error::InvalidMessage(
+                "Fragmented message is longer than announced".into(),
+            ));
+        }
+        partial.body.extend_from_slice(chunk);
+
+        if partial.body.len() < partial.total_len {
+            let incomplete = Some(partial.info());
+            self.partial = Some(partial);
+            return Ok(RelayCellDecoderResult {
+                msgs: smallvec![],
+                incomplete,
+            });
+        }
+
+        let msg = UnparsedRelayMsg {
+            internal: UnparsedRelayMsgInternal::Reassembled(ReassembledMsg {
+                cmd: partial.cmd,
+                stream_id: partial.stream_id,
+                body: partial.body,
+            }),
+        };
+        Ok(RelayCellDecoderResult {
+            msgs: smallvec![msg],
+            incomplete: None,
+        })
+    }
+
+    /// Return information about the message we are currently reassembling, if any.
+    pub(super) fn incomplete_info(&self) -> Option<IncompleteRelayMsgInfo> {
+        self.partial.as_ref().map(PartialMsg::info)
+    }
+}
+
+impl<M: RelayMsg> RelayMsgOuter<M> {
+    /// Consume this relay message and encode it as one or more 509-byte
+    /// padded cell bodies.
+    ///
+    /// If the message is too long to fit into a single cell, it is split into
+    /// `FRAGMENT` messages, which must be sent consecutively and in order.
+    /// Otherwise, this returns the same single cell body as [`encode`](Self::encode).
+    ///
+    /// Fragmentation is only supported with [`RelayCellFormat::V0`].
+    pub fn encode_fragmented<R: Rng + CryptoRng>(
+        self,
+        format: RelayCellFormat,
+        rng: &mut R,
+    ) -> crate::Result<Vec<BoxedCellBody>> {
+        let (streamid, msg) = self.into_streamid_and_msg();
+        let cmd = msg.cmd();
+        let mut body = Vec::new();
+        msg.encode_onto(&mut body)?;
+
+        let fits_in_one_cell = match format {
+            RelayCellFormat::V0 => body.len() <= MAX_BODY_LEN_V0,
+            // We let the regular encoder decide: it will fail if the
+            // message doesn't fit.
+            RelayCellFormat::V1 => true,
+        };
+        if fits_in_one_cell {
+            let msg = AnyRelayMsg::Unrecognized(Unrecognized::new(cmd, body));
+            return Ok(vec![RelayMsgOuter::new(streamid, msg).encode(format, rng)?]);
+        }
+        if body.len() > MAX_FRAGMENTED_MSG_LEN {
+            return Err(crate::Error::CantEncode(
+                "Relay message too long to send in fragments",
+            ));
+        }
+
+        let mut first = Vec::with_capacity(MAX_BODY_LEN_V0);
+        first.write_u8(cmd.into());
+        first.write_u16(body.len() as u16); // Checked above.
+        let (head, rest) = body.split_at(MAX_BODY_LEN_V0 - FIRST_FRAGMENT_HEADER_LEN);
+        first.write_all(head);
+
+        std::iter::once(first)
+            .chain(rest.chunks(MAX_BODY_LEN_V0).map(<[u8]>::to_vec))
+            .map(|fragment| {
+                let msg =
+                    AnyRelayMsg::Unrecognized(Unrecognized::new(RelayCmd::FRAGMENT, fragment));
+                RelayMsgOuter::new(streamid, msg).encode(format, rng)
+            })
+            .collect()
+    }
+}
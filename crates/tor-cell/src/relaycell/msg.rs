@@ -20,10 +20,16 @@ use tor_memquota::{derive_deftly_template_HasMemoryCost, memory_cost_structural_
 
 use bitflags::bitflags;
 
+pub use super::flow_ctrl::{Xoff, Xon};
+
 #[cfg(feature = "conflux")]
 #[cfg_attr(docsrs, doc(cfg(feature = "conflux")))]
 pub use super::conflux::{ConfluxLink, ConfluxLinked, ConfluxLinkedAck, ConfluxSwitch};
 
+#[cfg(feature = "circ-padding")]
+#[cfg_attr(docsrs, doc(cfg(feature = "circ-padding")))]
+pub use super::padding::{PaddingNegotiate, PaddingNegotiated};
+
 #[cfg(feature = "hs")]
 #[cfg_attr(docsrs, doc(cfg(feature = "hs")))]
 pub use super::hs::{
@@ -71,6 +77,10 @@ pub enum AnyRelayMsg : RelayMsg {
     Resolved,
     /// Start a directory stream
     BeginDir,
+    /// Ask the other side to stop sending data on a stream
+    Xoff,
+    /// Ask the other side to start sending data on a stream again
+    Xon,
     /// Start a UDP stream.
     [feature = "experimental-udp"]
     ConnectUdp,
@@ -92,6 +102,12 @@ pub enum AnyRelayMsg : RelayMsg {
     /// Switch to another leg in an already linked circuit construction.
     [feature = "conflux"]
     ConfluxSwitch,
+    /// Ask a relay to start or stop a circuit padding machine.
+    [feature = "circ-padding"]
+    PaddingNegotiate,
+    /// Reply to a PaddingNegotiate message.
+    [feature = "circ-padding"]
+    PaddingNegotiated,
     /// Establish Introduction
     [feature = "hs"]
     EstablishIntro,
@@ -995,6 +1011,52 @@ impl Resolve {
             query: query.into_bytes(),
         }
     }
+
+    /// Return the query in this resolve message.
+    ///
+    /// For a reverse lookup, this is an `in-addr.arpa` or `ip6.arpa` name.
+    pub fn query(&self) -> &[u8] {
+        &self.query[..]
+    }
+
+    /// If this is a reverse lookup, return the address that it is looking up.
+    ///
+    /// Return `None` if this is an ordinary hostname lookup, or if the
+    /// `in-addr.arpa` or `ip6.arpa` name is malformed.
+    pub fn reverse_addr(&self) -> Option<IpAddr> {
+        let query = std::str::from_utf8(&self.query).ok()?.to_ascii_lowercase();
+        if let Some(labels) = query.strip_suffix(".in-addr.arpa") {
+            let octets: Vec<u8> = labels
+                .split('.')
+                .map(|label| label.parse().ok())
+                .collect::<Option<_>>()?;
+            let [d, c, b, a] = octets[..] else {
+                return None;
+            };
+            Some(IpAddr::V4([a, b, c, d].into()))
+        } else if let Some(labels) = query.strip_suffix(".ip6.arpa") {
+            let nybbles: Vec<u8> = labels
+                .split('.')
+                .map(|label| {
+                    let mut chars = label.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(ch), None) => ch.to_digit(16).map(|n| n as u8),
+                        _ => None,
+                    }
+                })
+                .collect::<Option<_>>()?;
+            if nybbles.len() != 32 {
+                return None;
+            }
+            let mut octets = [0_u8; 16];
+            for (octet, pair) in octets.iter_mut().rev().zip(nybbles.chunks(2)) {
+                *octet = (pair[1] << 4) | pair[0];
+            }
+            Some(IpAddr::V6(octets.into()))
+        } else {
+            None
+        }
+    }
 }
 impl Body for Resolve {
     fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
@@ -1027,6 +1089,45 @@ pub enum ResolvedVal {
     Unrecognized(u8, Vec<u8>),
 }
 
+impl ResolvedVal {
+    /// Return a hostname answer for `name`.
+    pub fn hostname(name: &str) -> Self {
+        ResolvedVal::Hostname(name.as_bytes().into())
+    }
+
+    /// If this answer is an IP address, return it.
+    pub fn as_ip(&self) -> Option<IpAddr> {
+        match self {
+            ResolvedVal::Ip(ip) => Some(*ip),
+            _ => None,
+        }
+    }
+
+    /// If this answer is a hostname, return it.
+    ///
+    /// (The hostname is not guaranteed to be valid UTF-8.)
+    pub fn as_hostname(&self) -> Option<&[u8]> {
+        match self {
+            ResolvedVal::Hostname(h) => Some(&h[..]),
+            _ => None,
+        }
+    }
+
+    /// Return true if this answer reports an error.
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            ResolvedVal::TransientError | ResolvedVal::NontransientError
+        )
+    }
+
+    /// Return true if this answer reports an error that might go away
+    /// if we try again.
+    pub fn is_transient_error(&self) -> bool {
+        matches!(self, ResolvedVal::TransientError)
+    }
+}
+
 /// Indicates a hostname response
 const RES_HOSTNAME: u8 = 0;
 /// Indicates an IPv4 response
@@ -1155,6 +1256,29 @@ impl Resolved {
         self.answers.push((answer, ttl));
     }
 
+    /// Add a single answer to this Resolved message, and return it.
+    ///
+    /// This makes it easy to build a message with several answers:
+    ///
+    /// ```
+    /// use tor_cell::relaycell::msg::{Resolved, ResolvedVal};
+    /// let resolved = Resolved::new_empty()
+    ///     .with_answer(ResolvedVal::Ip("192.0.2.1".parse().unwrap()), 600)
+    ///     .with_answer(ResolvedVal::hostname("www.example.com"), 600);
+    /// assert_eq!(resolved.answers().len(), 2);
+    /// ```
+    pub fn with_answer(mut self, answer: ResolvedVal, ttl: u32) -> Self {
+        self.add_answer(answer, ttl);
+        self
+    }
+
+    /// Return the answers and TTL values in this Resolved message.
+    ///
+    /// See [`into_answers`](Resolved::into_answers) for a warning about TTLs.
+    pub fn answers(&self) -> &[(ResolvedVal, u32)] {
+        &self.answers[..]
+    }
+
     /// Consume this Resolved message, returning a vector of the
     /// answers and TTL values that it contains.
     ///
@@ -1313,7 +1437,7 @@ macro_rules! msg_impl_relaymsg {
 
 msg_impl_relaymsg!(
     Begin, Data, End, Connected, Sendme, Extend, Extended, Extend2, Extended2, Truncate, Truncated,
-    Drop, Resolve, Resolved, BeginDir,
+    Drop, Resolve, Resolved, BeginDir, Xoff, Xon,
 );
 
 #[cfg(feature = "experimental-udp")]
@@ -1334,3 +1458,6 @@ msg_impl_relaymsg!(
 
 #[cfg(feature = "conflux")]
 msg_impl_relaymsg!(ConfluxSwitch, ConfluxLink, ConfluxLinked, ConfluxLinkedAck);
+
+#[cfg(feature = "circ-padding")]
+msg_impl_relaymsg!(PaddingNegotiate, PaddingNegotiated);
@@ -0,0 +1,182 @@
+//! Encoding and decoding for relay messages related to circuit padding.
+//!
+//! See the "Circuit-level padding" section of `padding-spec.txt`.
+
+use super::msg::Body;
+
+use amplify::Getters;
+use caret::caret_int;
+use derive_deftly::Deftly;
+
+use tor_bytes::{EncodeResult, Error, Reader, Result, Writer};
+use tor_memquota::{derive_deftly_template_HasMemoryCost, memory_cost_structural_copy};
+
+/// The supported PADDING_NEGOTIATE and PADDING_NEGOTIATED version.
+const PADDING_NEGOTIATE_VERSION: u8 = 0;
+
+caret_int! {
+    /// What a [`PaddingNegotiate`] message asks the relay to do.
+    pub struct PaddingCommand(u8) {
+        /// Stop running a padding machine.
+        STOP = 1,
+        /// Start running a padding machine.
+        START = 2,
+    }
+}
+
+caret_int! {
+    /// The outcome reported by a [`PaddingNegotiated`] message.
+    pub struct PaddingResponse(u8) {
+        /// The relay accepted the request.
+        OK = 1,
+        /// The relay rejected the request.
+        ERR = 2,
+    }
+}
+
+memory_cost_structural_copy!(PaddingCommand, PaddingResponse);
+
+/// Read and check the version byte of a padding negotiation message.
+fn take_version(r: &mut Reader<'_>) -> Result<()> {
+    let version = r.take_u8()?;
+    if version != PADDING_NEGOTIATE_VERSION {
+        return Err(Error::InvalidMessage(
+            "Unrecognized PADDING_NEGOTIATE/PADDING_NEGOTIATED version.".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// A `PADDING_NEGOTIATE` message, sent by a client to ask a relay to start
+/// or stop a circuit padding machine.
+#[derive(Clone, Debug, Deftly, Getters)]
+#[derive_deftly(HasMemoryCost)]
+pub struct PaddingNegotiate {
+    /// Whether to start or stop the machine.
+    #[getter(as_copy)]
+    command: PaddingCommand,
+    /// The index of the machine, as known to the relay.
+    #[getter(as_copy)]
+    machine_type: u8,
+    /// If true, the relay should reply with a `DROP` message.
+    #[getter(as_copy)]
+    echo_request: bool,
+    /// A counter identifying this instance of the machine.
+    ///
+    /// This lets the client tell the replies for an old machine apart from
+    /// those for a newer one of the same type.
+    #[getter(as_copy)]
+    machine_ctr: u32,
+}
+
+impl PaddingNegotiate {
+    /// Create a new `PADDING_NEGOTIATE` message asking the relay to start
+    /// machine `machine_type`.
+    pub fn start(machine_type: u8, machine_ctr: u32) -> Self {
+        Self {
+            command: PaddingCommand::START,
+            machine_type,
+            echo_request: false,
+            machine_ctr,
+        }
+    }
+
+    /// Create a new `PADDING_NEGOTIATE` message asking the relay to stop
+    /// machine `machine_type`.
+    pub fn stop(machine_type: u8, machine_ctr: u32) -> Self {
+        Self {
+            command: PaddingCommand::STOP,
+            machine_type,
+            echo_request: false,
+            machine_ctr,
+        }
+    }
+}
+
+impl Body for PaddingNegotiate {
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        take_version(r)?;
+        let command = r.take_u8()?.into();
+        let machine_type = r.take_u8()?;
+        let echo_request = r.take_u8()? != 0;
+        let machine_ctr = r.take_u32()?;
+
+        Ok(Self {
+            command,
+            machine_type,
+            echo_request,
+            machine_ctr,
+        })
+    }
+
+    fn encode_onto<W: Writer + ?Sized>(self, w: &mut W) -> EncodeResult<()> {
+        w.write_u8(PADDING_NEGOTIATE_VERSION);
+        w.write_u8(self.command.into());
+        w.write_u8(self.machine_type);
+        w.write_u8(self.echo_request.into());
+        w.write_u32(self.machine_ctr);
+        Ok(())
+    }
+}
+
+/// A `PADDING_NEGOTIATED` message, sent by a relay in reply to a
+/// [`PaddingNegotiate`].
+#[derive(Clone, Debug, Deftly, Getters)]
+#[derive_deftly(HasMemoryCost)]
+pub struct PaddingNegotiated {
+    /// The command that this message is a reply to.
+    #[getter(as_copy)]
+    command: PaddingCommand,
+    /// Whether the command succeeded.
+    #[getter(as_copy)]
+    response: PaddingResponse,
+    /// The index of the machine, as known to the relay.
+    #[getter(as_copy)]
+    machine_type: u8,
+    /// The counter from the corresponding [`PaddingNegotiate`].
+    #[getter(as_copy)]
+    machine_ctr: u32,
+}
+
+impl PaddingNegotiated {
+    /// Create a new `PADDING_NEGOTIATED` message.
+    pub fn new(
+        command: PaddingCommand,
+        response: PaddingResponse,
+        machine_type: u8,
+        machine_ctr: u32,
+    ) -> Self {
+        Self {
+            command,
+            response,
+            machine_type,
+            machine_ctr,
+        }
+    }
+}
+
+impl Body for PaddingNegotiated {
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        take_version(r)?;
+        let command = r.take_u8()?.into();
+        let response = r.take_u8()?.into();
+        let machine_type = r.take_u8()?;
+        let machine_ctr = r.take_u32()?;
+
+        Ok(Self {
+            command,
+            response,
+            machine_type,
+            machine_ctr,
+        })
+    }
+
+    fn encode_onto<W: Writer + ?Sized>(self, w: &mut W) -> EncodeResult<()> {
+        w.write_u8(PADDING_NEGOTIATE_VERSION);
+        w.write_u8(self.command.into());
+        w.write_u8(self.response.into());
+        w.write_u8(self.machine_type);
+        w.write_u32(self.machine_ctr);
+        Ok(())
+    }
+}
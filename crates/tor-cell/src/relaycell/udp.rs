@@ -33,6 +33,18 @@ pub struct AddressPort {
     port: u16,
 }
 
+impl AddressPort {
+    /// Return the address.
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+
+    /// Return the port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
 impl Readable for AddressPort {
     fn take_from(r: &mut Reader<'_>) -> Result<Self> {
         Ok(Self {
@@ -229,6 +241,16 @@ impl ConnectedUdp {
             their_address,
         })
     }
+
+    /// Return the address that the relay has bound locally.
+    pub fn our_address(&self) -> &AddressPort {
+        &self.our_address
+    }
+
+    /// Return the address that the stream is connected to.
+    pub fn their_address(&self) -> &AddressPort {
+        &self.their_address
+    }
 }
 
 impl msg::Body for ConnectedUdp {
@@ -56,6 +56,26 @@ fn cell(body: &str, msg: msg::AnyChanMsg, id: Option<CircId>, pad_body: bool) {
 
     assert_eq!(encoded1, encoded2);
     assert_eq!(encoded1, body);
+
+    // Splitting off the raw cell and parsing it later gives the same result.
+    let mut bm = BytesMut::new();
+    bm.extend_from_slice(&body[..]);
+    let raw = codec.decode_raw_cell(&mut bm).unwrap().unwrap();
+    assert_eq!(bm.len(), 0);
+    assert_eq!(raw.circid(), id);
+    assert_eq!(raw.cmd(), decoded2.msg().cmd());
+    let header_len = if raw.cmd().is_var_cell() { 7 } else { 5 };
+    assert_eq!(&raw.body()[..], &body[header_len..]);
+    let decoded3 = raw.decode::<AnyChanMsg>().unwrap();
+    assert_eq!(format!("{:?}", decoded3), format!("{:?}", decoded2));
+
+    // Encoding into a buffer gives the same bytes as the codec does.
+    assert_eq!(decoded3.encoded_len().unwrap(), body.len());
+    let mut buf = vec![0x77_u8; body.len() + 3];
+    let n = decoded3.encode_into(&mut buf[..]).unwrap();
+    assert_eq!(n, body.len());
+    assert_eq!(&buf[..n], &body[..]);
+    assert_eq!(&buf[n..], &[0x77; 3]);
 }
 
 fn fcell(body: &str, msg: msg::AnyChanMsg, id: Option<CircId>) {
@@ -66,6 +86,20 @@ fn vcell(body: &str, msg: msg::AnyChanMsg, id: Option<CircId>) {
     cell(body, msg, id, false);
 }
 
+#[test]
+fn encode_into_short_buffer() {
+    let cell = AnyChanCell::new(None, msg::Padding::new().into());
+    let mut buf = [0_u8; FIXED_BODY_LEN - 1];
+    assert!(cell.encode_into(&mut buf[..]).is_err());
+
+    let cell = AnyChanCell::new(
+        CircId::new(7),
+        msg::Unrecognized::new(255.into(), &b"hello"[..]).into(),
+    );
+    let mut buf = [0_u8; 11];
+    assert!(cell.encode_into(&mut buf[..]).is_err());
+}
+
 #[test]
 fn test_simple_cells() {
     fcell("", msg::Padding::new().into(), None);
@@ -74,10 +74,31 @@ fn cell(version: RelayCellFormat, body: &str, id: Option<StreamId>, msg: AnyRela
         format!("{:?}", decoded_from_partial)
     );
 
+    let header_len = match version {
+        RelayCellFormat::V0 => 11,
+        RelayCellFormat::V1 => 16 + 3 + if id.is_some() { 2 } else { 0 },
+        _ => panic!("unexpected format"),
+    };
+    assert_eq!(
+        expected.encoded_len(version).unwrap(),
+        header_len + encoded_msg.len()
+    );
+
+    // Encoding into a buffer that held something else gives the same result.
+    let mut buf = [0x77_u8; CELL_BODY_LEN + 3];
+    let encoded3 = {
+        let copy = AnyRelayMsgOuter::new(expected.stream_id(), expected.msg().clone());
+        copy.encode_into(version, &mut buf[..], &mut bad_rng)
+            .unwrap();
+        buf
+    };
+    assert_eq!(&encoded3[CELL_BODY_LEN..], &[0x77; 3]);
+
     let encoded1 = decoded.encode(version, &mut bad_rng).unwrap();
     let encoded2 = expected.encode(version, &mut bad_rng).unwrap();
 
     assert_eq!(&encoded1[..], &encoded2[..]);
+    assert_eq!(&encoded1[..], &encoded3[..CELL_BODY_LEN]);
 }
 
 #[test]
@@ -98,6 +119,15 @@ fn bad_rng() {
     );
 }
 
+#[test]
+fn encode_into_short_buffer() {
+    let msg = AnyRelayMsgOuter::new(None, msg::Drop::default().into());
+    let mut buf = [0_u8; CELL_BODY_LEN - 1];
+    assert!(msg
+        .encode_into(RelayCellFormat::V0, &mut buf[..], &mut BadRng)
+        .is_err());
+}
+
 #[test]
 fn test_cells_v0() {
     cell(
@@ -130,6 +160,19 @@ fn test_cells_v0() {
     assert_eq!(c.cmd(), RelayCmd::from(2));
     assert_eq!(c.stream_id(), StreamId::new(0x9999));
     assert_eq!(c.data_len(), 0x01f2);
+    let body = c.body().unwrap();
+    assert_eq!(body.len(), 0x01f2);
+    assert_eq!(&body[..12], b"need-to-know");
+
+    // `body` checks the length field, as `decode` does.
+    let m = decode("02 0000 9999 12345678 01f3 6e6565642d746f2d6b6e6f77 00000000");
+    let c = UnparsedRelayMsg::from_singleton_body(RelayCellFormat::V0, m).unwrap();
+    assert_eq!(
+        c.body().err(),
+        Some(Error::InvalidMessage(
+            "Insufficient data in relay cell".into()
+        ))
+    );
 }
 
 #[test]
@@ -168,6 +211,7 @@ fn test_valid_cells_v1() {
     assert_eq!(c.cmd(), RelayCmd::from(2));
     assert_eq!(c.stream_id(), StreamId::new(0x3230));
     assert_eq!(c.data_len(), 0x000c);
+    assert_eq!(c.body().unwrap(), b"need-to-know");
 }
 
 #[test]
@@ -209,6 +253,63 @@ fn test_invalid_cells_v1() {
     }
 }
 
+#[test]
+fn test_packed_cells_v1() {
+    use tor_cell::relaycell::{RelayCellDecoder, RelayCellPacker};
+
+    let sendme = || AnyRelayMsgOuter::new(None, msg::Sendme::new_tag([7; 20]).into());
+    let data = |id: u16, len: usize| {
+        AnyRelayMsgOuter::new(
+            StreamId::new(id),
+            msg::Data::new(&vec![b'x'; len][..]).unwrap().into(),
+        )
+    };
+
+    let mut packer = RelayCellPacker::new();
+    assert!(packer.is_empty());
+    assert!(packer.finish(&mut BadRng).is_none());
+    assert!(packer.push(sendme(), &mut BadRng).unwrap().is_none());
+    assert!(packer.push(data(5, 10), &mut BadRng).unwrap().is_none());
+    assert!(packer.push(data(6, 100), &mut BadRng).unwrap().is_none());
+    assert_eq!(packer.n_msgs(), 3);
+    let body = packer.finish(&mut BadRng).unwrap();
+    assert!(packer.is_empty());
+
+    let mut decoder = RelayCellDecoder::new(RelayCellFormat::V1);
+    let (msgs, incomplete) = decoder.decode(body.clone()).unwrap().into_parts();
+    assert!(incomplete.is_none());
+    let msgs: Vec<_> = msgs
+        .map(|m| m.decode::<AnyRelayMsg>().unwrap())
+        .map(|m| format!("{:?}", m))
+        .collect();
+    let expected: Vec<_> = [sendme(), data(5, 10), data(6, 100)]
+        .into_iter()
+        .map(|m| format!("{:?}", m))
+        .collect();
+    assert_eq!(msgs, expected);
+
+    // A packed cell isn't a singleton.
+    assert_eq!(
+        UnparsedRelayMsg::from_singleton_body(RelayCellFormat::V1, body).err(),
+        Some(Error::ExtraneousBytes)
+    );
+
+    // A message that doesn't fit starts a new cell.
+    assert!(packer.push(data(5, 400), &mut BadRng).unwrap().is_none());
+    let full = packer.push(data(6, 100), &mut BadRng).unwrap().unwrap();
+    assert_eq!(packer.n_msgs(), 1);
+    let (msgs, _) = decoder.decode(full).unwrap().into_parts();
+    assert_eq!(msgs.count(), 1);
+    let (msgs, _) = decoder
+        .decode(packer.finish(&mut BadRng).unwrap())
+        .unwrap()
+        .into_parts();
+    let msgs: Vec<_> = msgs.collect();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].stream_id(), StreamId::new(6));
+    assert_eq!(msgs[0].data_len(), 100);
+}
+
 #[test]
 fn test_streamid() {
     let zero: Option<StreamId> = StreamId::new(0);
@@ -229,6 +330,87 @@ fn test_streamid() {
     assert!(!RelayCmd::EXTEND2.accepts_streamid_val(two));
 }
 
+#[cfg(feature = "relay-fragment")]
+#[test]
+fn test_fragments() {
+    use tor_cell::relaycell::RelayCellDecoder;
+
+    let cmd = RelayCmd::from(200);
+    let body: Vec<u8> = (0..1200_u32).map(|i| i as u8).collect();
+    let big = || {
+        AnyRelayMsgOuter::new(
+            StreamId::new(7),
+            AnyRelayMsg::Unrecognized(msg::Unrecognized::new(cmd, body.clone())),
+        )
+    };
+    let data = || {
+        AnyRelayMsgOuter::new(
+            StreamId::new(7),
+            msg::Data::new(&b"hello"[..]).unwrap().into(),
+        )
+    };
+
+    // 1200 bytes take three cells: 495 + 498 + 207.
+    let cells = big()
+        .encode_fragmented(RelayCellFormat::V0, &mut BadRng)
+        .unwrap();
+    assert_eq!(cells.len(), 3);
+
+    let mut decoder = RelayCellDecoder::new(RelayCellFormat::V0);
+    for cell in &cells[..2] {
+        let res = decoder.decode(cell.clone()).unwrap();
+        let info = res.incomplete_info().unwrap();
+        assert_eq!(info.cmd(), cmd);
+        assert_eq!(info.stream_id(), StreamId::new(7));
+        assert_eq!(info.total_msg_len(), 1200);
+        assert_eq!(res.cmds().collect::<Vec<_>>(), vec![cmd]);
+        let (mut msgs, _) = res.into_parts();
+        assert!(msgs.next().is_none());
+    }
+    let info = decoder.incomplete_info().unwrap();
+    assert_eq!(info.num_bytes_present(), 993);
+    assert_eq!(info.num_bytes_missing(), 207);
+
+    let (mut msgs, incomplete) = decoder.decode(cells[2].clone()).unwrap().into_parts();
+    assert!(incomplete.is_none());
+    assert!(decoder.incomplete_info().is_none());
+    let unparsed = msgs.next().unwrap();
+    assert!(msgs.next().is_none());
+    assert_eq!(unparsed.cmd(), cmd);
+    assert_eq!(unparsed.stream_id(), StreamId::new(7));
+    assert_eq!(unparsed.data_len(), 1200);
+    let decoded = unparsed.decode::<AnyRelayMsg>().unwrap();
+    assert_eq!(format!("{:?}", decoded), format!("{:?}", big()));
+
+    // Short messages are sent as they are.
+    let cells = data()
+        .encode_fragmented(RelayCellFormat::V0, &mut BadRng)
+        .unwrap();
+    assert_eq!(cells.len(), 1);
+    assert_eq!(
+        &cells[0][..],
+        &data().encode(RelayCellFormat::V0, &mut BadRng).unwrap()[..]
+    );
+    let (mut msgs, _) = decoder.decode(cells[0].clone()).unwrap().into_parts();
+    assert_eq!(msgs.next().unwrap().cmd(), RelayCmd::DATA);
+
+    // Nothing may be interleaved with the fragments of a message.
+    let cells = big()
+        .encode_fragmented(RelayCellFormat::V0, &mut BadRng)
+        .unwrap();
+    decoder.decode(cells[0].clone()).unwrap();
+    let interloper = data().encode(RelayCellFormat::V0, &mut BadRng).unwrap();
+    assert!(matches!(
+        decoder.decode(interloper),
+        Err(Error::InvalidMessage(_))
+    ));
+
+    // V1 cells can't be fragmented.
+    assert!(big()
+        .encode_fragmented(RelayCellFormat::V1, &mut BadRng)
+        .is_err());
+}
+
 #[cfg(feature = "experimental-udp")]
 #[test]
 fn test_address() {
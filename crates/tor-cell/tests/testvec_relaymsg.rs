@@ -290,19 +290,91 @@ fn test_truncated() {
     msg(cmd, "08", &msg::Truncated::new(8.into()).into());
 }
 
-/* For circuit padding:
-
+#[cfg(feature = "circ-padding")]
+#[test]
 fn test_padding_negotiate() {
+    use tor_cell::relaycell::padding::{PaddingCommand, PaddingResponse};
+
     let cmd = RelayCmd::PADDING_NEGOTIATE;
     assert_eq!(Into::<u8>::into(cmd), 41_u8);
 
-    msg(cmd, "0002000000000001", ... );
+    msg(
+        cmd,
+        "0002000000000001",
+        &msg::PaddingNegotiate::start(0, 1).into(),
+    );
+    msg(
+        cmd,
+        "0001010000000002",
+        &msg::PaddingNegotiate::stop(1, 2).into(),
+    );
+
+    // Unsupported version.
+    msg_error(
+        cmd,
+        "0102000000000001",
+        BytesError::InvalidMessage(
+            "Unrecognized PADDING_NEGOTIATE/PADDING_NEGOTIATED version.".into(),
+        ),
+    );
+
+    let cmd = RelayCmd::PADDING_NEGOTIATED;
+    assert_eq!(Into::<u8>::into(cmd), 42_u8);
+
+    msg(
+        cmd,
+        "0001010000000001",
+        &msg::PaddingNegotiated::new(PaddingCommand::STOP, PaddingResponse::OK, 0, 1).into(),
+    );
+    msg(
+        cmd,
+        "0002010000000001",
+        &msg::PaddingNegotiated::new(PaddingCommand::START, PaddingResponse::OK, 0, 1).into(),
+    );
+    msg(
+        cmd,
+        "0002010100000001",
+        &msg::PaddingNegotiated::new(PaddingCommand::START, PaddingResponse::OK, 1, 1).into(),
+    );
 }
 
-PADDING_NEGOTIATED, 42, "0001010000000001"
-PADDING_NEGOTIATED, 42, "0002010000000001"
-PADDING_NEGOTIATED, 42, "0002010100000001"
-*/
+#[test]
+fn test_xon_xoff() {
+    use std::num::NonZeroU32;
+    use tor_cell::relaycell::flow_ctrl::XonKbpsEwma;
+
+    let cmd = RelayCmd::XOFF;
+    assert_eq!(Into::<u8>::into(cmd), 43_u8);
+
+    msg(cmd, "00", &msg::Xoff::new().into());
+    msg_error(
+        cmd,
+        "01",
+        BytesError::InvalidMessage("Unrecognized XON/XOFF version.".into()),
+    );
+
+    let cmd = RelayCmd::XON;
+    assert_eq!(Into::<u8>::into(cmd), 44_u8);
+
+    msg(
+        cmd,
+        "00 00000000",
+        &msg::Xon::new(XonKbpsEwma::Unlimited).into(),
+    );
+    let kbps = XonKbpsEwma::Limited(NonZeroU32::new(1234).unwrap());
+    msg(cmd, "00 000004d2", &msg::Xon::new(kbps).into());
+    let xon: msg::Xon = decode(cmd, &unhex("00 000004d2"))
+        .unwrap()
+        .try_into()
+        .unwrap();
+    assert_eq!(xon.kbps_ewma(), kbps);
+    msg_error(
+        cmd,
+        "01 00000000",
+        BytesError::InvalidMessage("Unrecognized XON/XOFF version.".into()),
+    );
+    msg_error(cmd, "00 0000", BytesError::Truncated);
+}
 
 /*  For onion services only:
 
@@ -351,6 +423,58 @@ fn test_resolve() {
     msg(cmd, &body, &msg::Resolve::new_reverse(&addr).into());
 }
 
+#[test]
+fn test_resolve_accessors() {
+    let r = msg::Resolve::new("www.torproject.org");
+    assert_eq!(r.query(), b"www.torproject.org");
+    assert_eq!(r.reverse_addr(), None);
+
+    for addr in ["127.0.0.1", "2001::", "1234:5678::9abc:def0"] {
+        let addr = addr.parse::<IpAddr>().unwrap();
+        let r = msg::Resolve::new_reverse(&addr);
+        assert_eq!(r.reverse_addr(), Some(addr));
+    }
+
+    // Malformed reverse lookups.
+    assert_eq!(msg::Resolve::new("1.2.3.in-addr.arpa").reverse_addr(), None);
+    assert_eq!(
+        msg::Resolve::new("1.2.3.400.in-addr.arpa").reverse_addr(),
+        None
+    );
+    assert_eq!(msg::Resolve::new("1.0.ip6.arpa").reverse_addr(), None);
+}
+
+#[test]
+fn test_resolved_accessors() {
+    let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+    let r = msg::Resolved::new_empty()
+        .with_answer(msg::ResolvedVal::Ip(ip), 3600)
+        .with_answer(msg::ResolvedVal::hostname("www.torproject.org"), 600)
+        .with_answer(msg::ResolvedVal::TransientError, 60);
+    let answers = r.answers();
+    assert_eq!(answers.len(), 3);
+
+    assert_eq!(answers[0].0.as_ip(), Some(ip));
+    assert_eq!(answers[0].0.as_hostname(), None);
+    assert_eq!(answers[0].1, 3600);
+    assert!(!answers[0].0.is_error());
+
+    assert_eq!(answers[1].0.as_ip(), None);
+    assert_eq!(answers[1].0.as_hostname(), Some(&b"www.torproject.org"[..]));
+
+    assert!(answers[2].0.is_error());
+    assert!(answers[2].0.is_transient_error());
+    assert!(!msg::ResolvedVal::NontransientError.is_transient_error());
+
+    msg(
+        RelayCmd::RESOLVED,
+        "04 04 7f000001 00000E10
+         00 12 7777772e746f7270726f6a6563742e6f7267 00000258
+         F0 00 0000003c",
+        &r.into(),
+    );
+}
+
 #[test]
 fn test_resolved() {
     // these values are hand-generated.
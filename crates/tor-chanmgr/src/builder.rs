@@ -4,6 +4,7 @@ use std::io;
 use std::sync::{Arc, Mutex};
 
 use crate::factory::{BootstrapReporter, ChannelFactory, IncomingChannelFactory};
+use crate::ratelimit::{BandwidthLimiter, RateLimitedStream};
 use crate::transport::TransportImplHelper;
 use crate::{event::ChanMgrEventSender, Error};
 
@@ -13,7 +14,7 @@ use tor_linkspec::{BridgeAddr, HasChanMethod, IntoOwnedChanTarget, OwnedChanTarg
 use tor_proto::channel::kist::KistParams;
 use tor_proto::channel::params::ChannelPaddingInstructionsUpdates;
 use tor_proto::memquota::ChannelAccount;
-use tor_rtcompat::{tls::TlsConnector, Runtime, TlsProvider};
+use tor_rtcompat::{tls::TlsConnector, DynTimeProvider, Runtime, TlsProvider};
 
 use async_trait::async_trait;
 use futures::task::SpawnExt;
@@ -40,6 +41,8 @@ where
     transport: H,
     /// Object to build TLS connections.
     tls_connector: <R as TlsProvider<H::Stream>>::Connector,
+    /// The limits on how fast the channels we build may read and write.
+    bandwidth_limiter: BandwidthLimiter,
 }
 
 impl<R: Runtime, H: TransportImplHelper> ChanBuilder<R, H>
@@ -49,12 +52,27 @@ where
     /// Construct a new ChanBuilder.
     pub fn new(runtime: R, transport: H) -> Self {
         let tls_connector = <R as TlsProvider<H::Stream>>::tls_connector(&runtime);
+        let bandwidth_limiter = BandwidthLimiter::new(DynTimeProvider::new(runtime.clone()));
         ChanBuilder {
             runtime,
             transport,
             tls_connector,
+            bandwidth_limiter,
         }
     }
+
+    /// Make the channels we build obey `limiter`, rather than being
+    /// unlimited.
+    #[must_use]
+    pub fn with_bandwidth_limiter(mut self, limiter: BandwidthLimiter) -> Self {
+        self.bandwidth_limiter = limiter;
+        self
+    }
+
+    /// Return the limits that the channels we build obey.
+    pub(crate) fn bandwidth_limiter(&self) -> &BandwidthLimiter {
+        &self.bandwidth_limiter
+    }
 }
 #[async_trait]
 impl<R: Runtime, H: TransportImplHelper> ChannelFactory for ChanBuilder<R, H>
@@ -189,6 +207,7 @@ where
         }
 
         // 2. Set up the channel.
+        let tls = RateLimitedStream::new(tls, self.bandwidth_limiter.clone());
         let mut builder = ChannelBuilder::new();
         builder.set_declared_method(using_method);
         let chan = builder
@@ -260,6 +279,9 @@ impl crate::mgr::AbstractChannel for tor_proto::channel::Channel {
     fn engage_padding_activities(&self) {
         tor_proto::channel::Channel::engage_padding_activities(self);
     }
+    fn uses_pluggable_transport(&self) -> bool {
+        !self.target().chan_method().is_direct()
+    }
 }
 
 #[cfg(test)]
@@ -49,6 +49,7 @@ mod err;
 mod event;
 pub mod factory;
 mod mgr;
+mod ratelimit;
 #[cfg(test)]
 mod testing;
 pub mod transport;
@@ -62,7 +63,7 @@ use std::sync::{Arc, Weak};
 use std::time::Duration;
 use tor_config::ReconfigureError;
 use tor_error::error_report;
-use tor_linkspec::{ChanTarget, OwnedChanTarget};
+use tor_linkspec::{ChanTarget, OwnedChanTarget, RelayIdSet};
 use tor_netdir::{params::NetParameters, NetDirProvider};
 use tor_proto::channel::Channel;
 #[cfg(feature = "experimental-api")]
@@ -73,7 +74,8 @@ use void::{ResultVoidErrExt, Void};
 
 pub use err::Error;
 
-pub use config::{ChannelConfig, ChannelConfigBuilder};
+pub use config::{ChannelConfig, ChannelConfigBuilder, UpstreamProxy, UpstreamProxyParseError};
+pub use ratelimit::{BandwidthLimit, BandwidthLimiter};
 
 use tor_rtcompat::Runtime;
 
@@ -128,6 +130,20 @@ pub struct ChanMgr<R: Runtime> {
     /// Stream of [`ConnStatus`] events.
     bootstrap_status: event::ConnStatusEvents,
 
+    /// The limits on how fast our channels may read and write.
+    bandwidth_limiter: BandwidthLimiter,
+
+    /// The read and write limits from our most recent configuration.
+    ///
+    /// We only apply the configured limits when they change, so that
+    /// reconfiguring doesn't undo [`ChanMgr::set_bandwidth_limits`].
+    configured_limits: std::sync::Mutex<(Option<BandwidthLimit>, Option<BandwidthLimit>)>,
+
+    /// The upstream proxy that new connections to relays go through, if any.
+    ///
+    /// Shared with our default transport.
+    upstream_proxy: Arc<std::sync::Mutex<Option<UpstreamProxy>>>,
+
     /// This currently isn't actually used, but we're keeping a PhantomData here
     /// since probably we'll want it again, sooner or later.
     runtime: std::marker::PhantomData<fn(R) -> R>,
@@ -224,8 +240,13 @@ impl<R: Runtime> ChanMgr<R> {
         let (sender, receiver) = event::channel();
         let sender = Arc::new(std::sync::Mutex::new(sender));
         let reporter = BootstrapReporter(sender);
-        let transport = transport::DefaultTransport::new(runtime.clone());
+        let upstream_proxy = Arc::new(std::sync::Mutex::new(config.proxy().cloned()));
+        let transport =
+            transport::DefaultTransport::with_proxy(runtime.clone(), Arc::clone(&upstream_proxy));
         let builder = builder::ChanBuilder::new(runtime, transport);
+        let bandwidth_limiter = builder.bandwidth_limiter().clone();
+        let configured_limits = (config.read_limit(), config.write_limit());
+        bandwidth_limiter.set_limits(configured_limits.0, configured_limits.1);
         let factory = factory::CompoundFactory::new(
             Arc::new(builder),
             #[cfg(feature = "pt-client")]
@@ -236,6 +257,9 @@ impl<R: Runtime> ChanMgr<R> {
         ChanMgr {
             mgr,
             bootstrap_status: receiver,
+            bandwidth_limiter,
+            configured_limits: std::sync::Mutex::new(configured_limits),
+            upstream_proxy,
             runtime: std::marker::PhantomData,
         }
     }
@@ -328,6 +352,15 @@ impl<R: Runtime> ChanMgr<R> {
         self.mgr.set_dormancy(dormancy, netparams)
     }
 
+    /// Tell the channel manager which relays are our bridges.
+    ///
+    /// Channels to these relays, like all channels via a pluggable transport,
+    /// use the padding level from `bridge_padding` in our [`ChannelConfig`].
+    /// This replaces any set of bridges that we were given before.
+    pub fn set_bridges(&self, bridges: RelayIdSet) -> StdResult<(), tor_error::Bug> {
+        self.mgr.set_bridges(bridges)
+    }
+
     /// Reconfigure all channels
     pub fn reconfigure(
         &self,
@@ -348,9 +381,45 @@ impl<R: Runtime> ChanMgr<R> {
         // Check that `self.mgr.reconfigure` returns an error type of `Bug` (see comment above).
         let _: Option<&tor_error::Bug> = r.as_ref().err();
 
+        {
+            let limits = (config.read_limit(), config.write_limit());
+            let mut configured = self.configured_limits.lock().expect("Lock poisoned");
+            if *configured != limits {
+                self.bandwidth_limiter.set_limits(limits.0, limits.1);
+                *configured = limits;
+            }
+        }
+
+        // This only affects new connections: existing channels keep going
+        // however they were made.
+        *self.upstream_proxy.lock().expect("Lock poisoned") = config.proxy().cloned();
+
         Ok(r?)
     }
 
+    /// Replace the limits on how fast all our channels together may read and
+    /// write.
+    ///
+    /// `None` means that there is no limit.  These limits apply to existing
+    /// channels as well as new ones.  They replace the limits from the
+    /// [`ChannelConfig`], until the configured limits next change.
+    pub fn set_bandwidth_limits(
+        &self,
+        read: Option<BandwidthLimit>,
+        write: Option<BandwidthLimit>,
+    ) {
+        self.bandwidth_limiter.set_limits(read, write);
+    }
+
+    /// Return a handle to the limits on how fast our channels may read and
+    /// write.
+    ///
+    /// Give this to [`ChanBuilder::with_bandwidth_limiter`](builder::ChanBuilder::with_bandwidth_limiter)
+    /// so that the channels it builds obey the same limits.
+    pub fn bandwidth_limiter(&self) -> &BandwidthLimiter {
+        &self.bandwidth_limiter
+    }
+
     /// Replace the transport registry with one that may know about
     /// more transports.
     ///
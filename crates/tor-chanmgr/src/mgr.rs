@@ -12,7 +12,7 @@ use std::result::Result as StdResult;
 use std::sync::Arc;
 use std::time::Duration;
 use tor_error::{error_report, internal};
-use tor_linkspec::HasRelayIds;
+use tor_linkspec::{HasRelayIds, RelayIdSet};
 use tor_netdir::params::NetParameters;
 use tor_proto::channel::kist::KistParams;
 use tor_proto::channel::params::ChannelPaddingInstructionsUpdates;
@@ -56,6 +56,12 @@ pub(crate) trait AbstractChannel: HasRelayIds {
     ///
     /// [`Channel::engage_padding_activities`]: tor_proto::channel::Channel::engage_padding_activities
     fn engage_padding_activities(&self);
+
+    /// Return true if this channel goes via a pluggable transport.
+    ///
+    /// Such channels always go to a bridge, and so use the padding level
+    /// for bridges; see [`ChannelConfig`](crate::ChannelConfig).
+    fn uses_pluggable_transport(&self) -> bool;
 }
 
 /// Trait to describe how channels-like objects are created.
@@ -382,6 +388,11 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
             .reconfigure_general(None, Some(dormancy), netparams)
     }
 
+    /// Replace the set of relays that we treat as bridges
+    pub(crate) fn set_bridges(&self, bridges: RelayIdSet) -> StdResult<(), tor_error::Bug> {
+        self.channels.set_bridges(bridges)
+    }
+
     /// Reconfigure all channels
     pub(crate) fn reconfigure(
         &self,
@@ -505,6 +516,9 @@ mod test {
             Ok(())
         }
         fn engage_padding_activities(&self) {}
+        fn uses_pluggable_transport(&self) -> bool {
+            false
+        }
     }
 
     impl HasRelayIds for FakeChannel {
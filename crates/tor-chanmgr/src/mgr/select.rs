@@ -250,6 +250,9 @@ mod test {
             Ok(())
         }
         fn engage_padding_activities(&self) {}
+        fn uses_pluggable_transport(&self) -> bool {
+            false
+        }
     }
 
     impl HasRelayIds for FakeChannel {
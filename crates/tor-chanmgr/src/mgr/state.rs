@@ -15,9 +15,9 @@ use tor_basic_utils::RngExt as _;
 use tor_cell::chancell::msg::PaddingNegotiate;
 use tor_config::PaddingLevel;
 use tor_error::{error_report, internal, into_internal};
-use tor_linkspec::{HasRelayIds, ListByRelayIds, RelayIds};
+use tor_linkspec::{HasRelayIds, ListByRelayIds, RelayIdSet, RelayIds};
 use tor_netdir::{params::NetParameters, params::CHANNEL_PADDING_TIMEOUT_UPPER_BOUND};
-use tor_proto::channel::kist::{KistMode, KistParams};
+use tor_proto::channel::kist::{KistMode, KistParams, DEFAULT_CIRCUIT_PRIORITY_HALFLIFE};
 use tor_proto::channel::padding::Parameters as PaddingParameters;
 use tor_proto::channel::padding::ParametersBuilder as PaddingParametersBuilder;
 use tor_proto::channel::ChannelPaddingInstructionsUpdates;
@@ -48,10 +48,26 @@ struct ChannelParams {
     /// Channel padding instructions
     padding: ChannelPaddingInstructions,
 
+    /// Channel padding instructions for channels to bridges
+    ///
+    /// These differ from `padding` only if the configuration says so.
+    bridge_padding: ChannelPaddingInstructions,
+
     /// KIST parameters
     kist: KistParams,
 }
 
+impl ChannelParams {
+    /// Return the padding instructions for a channel, given whether it is to a bridge.
+    fn padding(&self, to_bridge: bool) -> &ChannelPaddingInstructions {
+        if to_bridge {
+            &self.bridge_padding
+        } else {
+            &self.padding
+        }
+    }
+}
+
 /// A map from channel id to channel state, plus necessary auxiliary state - inside lock
 struct Inner<C: AbstractChannelFactory> {
     /// The channel factory type that we store.
@@ -81,6 +97,12 @@ struct Inner<C: AbstractChannelFactory> {
     /// Updated via `MgrState::set_dormancy` and hence `MgrState::reconfigure_general`,
     /// which then uses it to calculate how to reconfigure the channels.
     dormancy: Dormancy,
+
+    /// The identities of our bridges
+    ///
+    /// Channels to these relays (and all channels via a pluggable transport)
+    /// use `channels_params.bridge_padding`.
+    bridges: RelayIdSet,
 }
 
 /// The state of a channel (or channel build attempt) within a map.
@@ -219,7 +241,11 @@ impl From<&NetParameters> for NetParamsExtract {
         //
         // See the `NetParamaters::kist_tcp_notsent_lowat docs for more details.
         let tcp_notsent_lowat = u32::from(p.kist_tcp_notsent_lowat);
-        let kist = KistParams::new(kist_enabled, tcp_notsent_lowat);
+        // This can't fail, since the parameter is bounded to be positive;
+        // but if it somehow does, fall back to the default.
+        let circuit_priority_halflife = Duration::try_from(p.circuit_priority_half_life)
+            .unwrap_or(DEFAULT_CIRCUIT_PRIORITY_HALFLIFE);
+        let kist = KistParams::new(kist_enabled, tcp_notsent_lowat, circuit_priority_halflife);
 
         NetParamsExtract {
             nf_ito: [
@@ -309,14 +335,21 @@ impl<C: AbstractChannelFactory> MgrState<C> {
         netparams: &NetParameters,
     ) -> Self {
         let mut padding_params = ChannelPaddingInstructions::default();
+        let mut bridge_padding_params = ChannelPaddingInstructions::default();
         let netparams = NetParamsExtract::from(netparams);
         let kist_params = netparams.kist;
-        let update = parameterize(&mut padding_params, &config, dormancy, &netparams)
-            .unwrap_or_else(|e: tor_error::Bug| panic!("bug detected on startup: {:?}", e));
-        let _: Option<_> = update; // there are no channels yet, that would need to be told
+        for (params, level) in [
+            (&mut padding_params, config.padding),
+            (&mut bridge_padding_params, config.bridge_padding_level()),
+        ] {
+            let update = parameterize(params, level, dormancy, &netparams)
+                .unwrap_or_else(|e: tor_error::Bug| panic!("bug detected on startup: {:?}", e));
+            let _: Option<_> = update; // there are no channels yet, that would need to be told
+        }
 
         let channels_params = ChannelParams {
             padding: padding_params,
+            bridge_padding: bridge_padding_params,
             kist: kist_params,
         };
 
@@ -327,6 +360,7 @@ impl<C: AbstractChannelFactory> MgrState<C> {
                 config,
                 channels_params,
                 dormancy,
+                bridges: RelayIdSet::new(),
             }),
         }
     }
@@ -514,7 +548,8 @@ impl<C: AbstractChannelFactory> MgrState<C> {
         // manager lock acquisition span as the one where we insert the
         // channel into the table so it will receive updates.  I.e.,
         // here.
-        let update = inner.channels_params.padding.initial_update();
+        let to_bridge = is_bridge(&inner.bridges, &*channel);
+        let update = inner.channels_params.padding(to_bridge).initial_update();
         if let Some(update) = update {
             channel
                 .reparameterize(update.into())
@@ -533,6 +568,44 @@ impl<C: AbstractChannelFactory> MgrState<C> {
         Ok(())
     }
 
+    /// Replace the set of relays that we treat as bridges
+    ///
+    /// Open channels that become, or stop being, channels to a bridge
+    /// are told to follow the padding instructions for their new kind.
+    pub(super) fn set_bridges(&self, bridges: RelayIdSet) -> StdResult<(), tor_error::Bug> {
+        use ChannelState as CS;
+
+        let mut inner = self
+            .inner
+            .lock()
+            .map_err(|_| internal!("poisoned channel manager"))?;
+        let inner = &mut *inner;
+
+        for channel in inner.channels.values() {
+            let channel = match channel {
+                CS::Open(OpenEntry { channel, .. }) => channel,
+                CS::Building(_) => continue,
+            };
+
+            let was_bridge = is_bridge(&inner.bridges, &**channel);
+            let now_bridge = is_bridge(&bridges, &**channel);
+            if was_bridge == now_bridge {
+                continue;
+            }
+            let update = inner
+                .channels_params
+                .padding(now_bridge)
+                .update_from(inner.channels_params.padding(was_bridge));
+            if let Some(update) = update {
+                // Ignore error (which simply means the channel is closed or gone)
+                let _ = channel.reparameterize(Arc::new(update));
+            }
+        }
+
+        inner.bridges = bridges;
+        Ok(())
+    }
+
     /// Reconfigure all channels as necessary
     ///
     /// (By reparameterizing channels as needed)
@@ -573,12 +646,18 @@ impl<C: AbstractChannelFactory> MgrState<C> {
 
         let update = parameterize(
             &mut inner.channels_params.padding,
-            &inner.config,
+            inner.config.padding,
             inner.dormancy,
             &netdir,
-        )?;
-
-        let update = update.map(Arc::new);
+        )?
+        .map(Arc::new);
+        let bridge_update = parameterize(
+            &mut inner.channels_params.bridge_padding,
+            inner.config.bridge_padding_level(),
+            inner.dormancy,
+            &netdir,
+        )?
+        .map(Arc::new);
 
         let new_kist_params = netdir.kist;
         let kist_params = if new_kist_params != inner.channels_params.kist {
@@ -592,7 +671,7 @@ impl<C: AbstractChannelFactory> MgrState<C> {
             None
         };
 
-        if update.is_none() && kist_params.is_none() {
+        if update.is_none() && bridge_update.is_none() && kist_params.is_none() {
             // Return early, nothing to reconfigure
             return Ok(());
         }
@@ -603,7 +682,12 @@ impl<C: AbstractChannelFactory> MgrState<C> {
                 CS::Building(_) => continue,
             };
 
-            if let Some(ref update) = update {
+            let update = if is_bridge(&inner.bridges, &**channel) {
+                &bridge_update
+            } else {
+                &update
+            };
+            if let Some(update) = update {
                 // Ignore error (which simply means the channel is closed or gone)
                 let _ = channel.reparameterize(Arc::clone(update));
             }
@@ -719,7 +803,14 @@ fn remove_pending<C: AbstractChannel>(
     handle.chan_has_been_removed();
 }
 
-/// Converts config, dormancy, and netdir, into parameter updates
+/// Return true if `channel` is to a bridge, given the identities of our `bridges`
+///
+/// Channels via a pluggable transport always go to a bridge.
+fn is_bridge<C: AbstractChannel>(bridges: &RelayIdSet, channel: &C) -> bool {
+    channel.uses_pluggable_transport() || channel.identities().any(|id| bridges.contains(id))
+}
+
+/// Converts a configured padding level, dormancy, and netdir, into parameter updates
 ///
 /// Calculates new parameters, updating `channels_params` as appropriate.
 /// If anything changed, the corresponding update instruction is returned.
@@ -733,18 +824,21 @@ fn remove_pending<C: AbstractChannel>(
 ///     and construct a corresponding ChannelPaddingInstructions.
 ///
 ///  2. During reconfiguration.
+///
+/// In each case it is called once for each set of channels that can be configured
+/// with their own padding level (see [`ChannelConfig`]).
 fn parameterize(
     channels_params: &mut ChannelPaddingInstructions,
-    config: &ChannelConfig,
+    level: PaddingLevel,
     dormancy: Dormancy,
     netdir: &NetParamsExtract,
 ) -> StdResult<Option<ChannelPaddingInstructionsUpdates>, tor_error::Bug> {
-    // Everything in this calculation applies to *all* channels, disregarding
-    // channel usage.  Usage is handled downstream, in the channel frontend.
+    // Everything in this calculation applies to *all* channels with this `level`,
+    // disregarding channel usage.  Usage is handled downstream, in the channel frontend.
     // See the module doc in `crates/tor-proto/src/channel/padding.rs`.
 
     let padding_of_level = |level| padding_parameters(level, netdir);
-    let send_padding = padding_of_level(config.padding)?;
+    let send_padding = padding_of_level(level)?;
     let padding_default = padding_of_level(PaddingLevel::default())?;
 
     let send_padding = match dormancy {
@@ -752,7 +846,7 @@ fn parameterize(
         Dormancy::Dormant => None,
     };
 
-    let recv_padding = match config.padding {
+    let recv_padding = match level {
         PaddingLevel::Reduced => None,
         PaddingLevel::Normal => send_padding,
         PaddingLevel::None => None,
@@ -871,6 +965,7 @@ mod test {
     use crate::factory::BootstrapReporter;
     use async_trait::async_trait;
     use std::sync::{Arc, Mutex};
+    use tor_linkspec::RelayId;
     use tor_llcrypto::pk::ed25519::Ed25519Identity;
     use tor_proto::channel::params::ChannelPaddingInstructionsUpdates;
     use tor_proto::memquota::ChannelAccount;
@@ -921,6 +1016,7 @@ mod test {
         ed_ident: Ed25519Identity,
         usable: bool,
         unused_duration: Option<u64>,
+        pluggable: bool,
         params_update: Arc<Mutex<Option<Arc<ChannelPaddingInstructionsUpdates>>>>,
     }
     impl AbstractChannel for FakeChannel {
@@ -941,6 +1037,9 @@ mod test {
             Ok(())
         }
         fn engage_padding_activities(&self) {}
+        fn uses_pluggable_transport(&self) -> bool {
+            self.pluggable
+        }
     }
     impl tor_linkspec::HasRelayIds for FakeChannel {
         fn identity(
@@ -963,6 +1062,7 @@ mod test {
             ed_ident: str_to_ed(ident),
             usable: true,
             unused_duration: None,
+            pluggable: false,
             params_update: Arc::new(Mutex::new(None)),
         };
         ChannelState::Open(OpenEntry {
@@ -979,6 +1079,7 @@ mod test {
             ed_ident: str_to_ed(ident),
             usable: true,
             unused_duration,
+            pluggable: false,
             params_update: Arc::new(Mutex::new(None)),
         };
         ChannelState::Open(OpenEntry {
@@ -991,6 +1092,7 @@ mod test {
             ed_ident: str_to_ed(ident),
             usable: false,
             unused_duration: None,
+            pluggable: false,
             params_update: Arc::new(Mutex::new(None)),
         };
         ChannelState::Open(OpenEntry {
@@ -1082,6 +1184,83 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn reparameterize_bridge_channels() -> Result<()> {
+        let map = new_test_state();
+
+        map.with_channels(|map| {
+            map.insert(ch("direct"));
+            map.insert(ch("bridge"));
+            map.insert(ChannelState::Open(OpenEntry {
+                channel: Arc::new(FakeChannel {
+                    ed_ident: str_to_ed("pluggable"),
+                    usable: true,
+                    unused_duration: None,
+                    pluggable: true,
+                    params_update: Arc::new(Mutex::new(None)),
+                }),
+                max_unused_duration: Duration::from_secs(180),
+            }));
+        })?;
+
+        let take_update = |ident: &str| {
+            let inner = map.inner.lock().unwrap();
+            let mut ch = inner.channels.by_ed25519(&str_to_ed(ident));
+            let ch = ch.next().unwrap().unwrap_open();
+            let update = ch.params_update.lock().unwrap().take();
+            update
+        };
+        let assert_disabled = |update: Option<Arc<ChannelPaddingInstructionsUpdates>>| {
+            let update = update.unwrap();
+            assert_eq!(update.padding_enable(), Some(&false));
+            assert_eq!(update.padding_negotiate(), Some(&PaddingNegotiate::stop()));
+        };
+        let assert_enabled = |update: Option<Arc<ChannelPaddingInstructionsUpdates>>| {
+            let update = update.unwrap();
+            assert_eq!(update.padding_enable(), Some(&true));
+            assert_eq!(
+                update.padding_negotiate(),
+                Some(&PaddingNegotiate::start_default())
+            );
+        };
+
+        eprintln!("-- tell the manager about our bridge --");
+        let bridges: RelayIdSet = [RelayId::from(str_to_ed("b"))].into_iter().collect();
+        map.set_bridges(bridges).unwrap();
+        // Padding is the same for bridges and other relays, so nothing changes.
+        assert!(take_update("d").is_none());
+        assert!(take_update("b").is_none());
+        assert!(take_update("p").is_none());
+
+        eprintln!("-- disable padding on channels to bridges only --");
+        let config = ChannelConfig::builder()
+            .bridge_padding(tor_config::ExplicitOrAuto::Explicit(PaddingLevel::None))
+            .build()
+            .unwrap();
+        let netparams = Arc::new(NetParameters::default());
+        map.reconfigure_general(Some(&config), None, netparams.clone())
+            .unwrap();
+        assert!(take_update("d").is_none());
+        assert_disabled(take_update("b"));
+        assert_disabled(take_update("p"));
+
+        eprintln!("-- stop treating that relay as a bridge --");
+        map.set_bridges(RelayIdSet::new()).unwrap();
+        assert!(take_update("d").is_none());
+        assert_enabled(take_update("b"));
+        // Channels via a pluggable transport are always to a bridge.
+        assert!(take_update("p").is_none());
+
+        eprintln!("-- go back to following the main padding level --");
+        map.reconfigure_general(Some(&ChannelConfig::default()), None, netparams)
+            .unwrap();
+        assert!(take_update("d").is_none());
+        assert!(take_update("b").is_none());
+        assert_enabled(take_update("p"));
+
+        Ok(())
+    }
+
     #[test]
     fn expire_channels() -> Result<()> {
         let map = new_test_state();
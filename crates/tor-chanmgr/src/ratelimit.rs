@@ -0,0 +1,436 @@
+//! Limits on how fast our channels may read and write.
+//!
+//! Every channel that a [`ChanMgr`](crate::ChanMgr) builds shares a single
+//! [`BandwidthLimiter`], which holds one token bucket for reading and one for
+//! writing.  Each byte that a channel reads from its TLS stream, or writes to
+//! it, takes a token from the matching bucket.  When a bucket is empty, the
+//! channel waits until it has been refilled.
+//!
+//! Because the limits apply to the TLS streams, they count everything that
+//! goes over the network for our channels: cells, padding, and TLS overhead.
+//! They don't count directory requests made over other connections, or
+//! traffic that a pluggable transport adds.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::{AsyncRead, AsyncWrite, Future};
+use tor_rtcompat::{DynTimeProvider, SleepProvider, StreamOps};
+
+/// The fewest bytes that we wait for before letting a channel read or write.
+///
+/// Without this, a channel that was waiting on an empty bucket would be
+/// woken to move one byte at a time.  We use the length of a cell.
+const MIN_GRANT: u64 = 514;
+
+/// A limit on the rate at which our channels may read, or write.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BandwidthLimit {
+    /// The sustained rate, in bytes per second.
+    rate: u64,
+    /// The number of bytes that may be moved at once, after a quiet period.
+    burst: u64,
+}
+
+impl BandwidthLimit {
+    /// Return a new limit of `rate` bytes per second on average, with bursts
+    /// of up to `burst` bytes.
+    ///
+    /// A `rate` or `burst` of zero is treated as one.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        BandwidthLimit {
+            rate: rate.max(1),
+            burst: burst.max(1),
+        }
+    }
+
+    /// Return the sustained rate of this limit, in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Return the largest burst that this limit allows, in bytes.
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+}
+
+/// A token bucket, enforcing a single [`BandwidthLimit`].
+#[derive(Debug)]
+struct TokenBucket {
+    /// The limit we enforce.
+    limit: BandwidthLimit,
+    /// The number of bytes that may be moved right now.
+    ///
+    /// Never more than `limit.burst`.
+    tokens: f64,
+    /// When we last added tokens to the bucket.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Return a new, full bucket for `limit`.
+    fn new(limit: BandwidthLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Add the tokens that have accumulated since the last refill.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = self.last_refill.max(now);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.rate as f64)
+            .min(self.limit.burst as f64);
+    }
+
+    /// Try to take tokens to move up to `want` bytes.
+    ///
+    /// On success, return the number of bytes that may be moved, which is
+    /// at least one.  Otherwise, return how long to wait before trying
+    /// again.
+    fn take(&mut self, now: Instant, want: usize) -> Result<usize, Duration> {
+        self.refill(now);
+        let want = u64::try_from(want).unwrap_or(u64::MAX);
+        let need = want.min(self.limit.burst).min(MIN_GRANT) as f64;
+        if self.tokens >= need {
+            let n = want.min(self.tokens as u64);
+            self.tokens -= n as f64;
+            Ok(usize::try_from(n).unwrap_or(usize::MAX))
+        } else {
+            Err(Duration::from_secs_f64(
+                (need - self.tokens) / self.limit.rate as f64,
+            ))
+        }
+    }
+
+    /// Return tokens for `n` bytes that were taken, but not moved.
+    fn refund(&mut self, n: usize) {
+        self.tokens = (self.tokens + n as f64).min(self.limit.burst as f64);
+    }
+}
+
+/// Which way bytes are moving.
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    /// From the network to us.
+    Read,
+    /// From us to the network.
+    Write,
+}
+
+/// The buckets of a [`BandwidthLimiter`].
+#[derive(Debug, Default)]
+struct Buckets {
+    /// The bucket for reading, if reading is limited.
+    read: Option<TokenBucket>,
+    /// The bucket for writing, if writing is limited.
+    write: Option<TokenBucket>,
+}
+
+impl Buckets {
+    /// Return the bucket for `dir`, if there is one.
+    fn get_mut(&mut self, dir: Direction) -> Option<&mut TokenBucket> {
+        match dir {
+            Direction::Read => self.read.as_mut(),
+            Direction::Write => self.write.as_mut(),
+        }
+    }
+}
+
+/// A handle to the bandwidth limits shared by a set of channels.
+///
+/// Clones of a `BandwidthLimiter` share the same limits, and the same
+/// buckets.  Get one from [`ChanMgr::bandwidth_limiter`](crate::ChanMgr::bandwidth_limiter).
+#[derive(Clone, Debug)]
+pub struct BandwidthLimiter {
+    /// The buckets.
+    buckets: Arc<Mutex<Buckets>>,
+    /// Used to refill the buckets, and to wait for them.
+    time: DynTimeProvider,
+}
+
+impl BandwidthLimiter {
+    /// Return a new `BandwidthLimiter` that doesn't limit anything.
+    pub(crate) fn new(time: DynTimeProvider) -> Self {
+        BandwidthLimiter {
+            buckets: Default::default(),
+            time,
+        }
+    }
+
+    /// Replace the limits on reading and writing.
+    ///
+    /// `None` means that there is no limit.  A limit that hasn't changed
+    /// keeps its bucket; otherwise, the bucket starts out full.
+    pub fn set_limits(&self, read: Option<BandwidthLimit>, write: Option<BandwidthLimit>) {
+        let now = self.time.now();
+        let mut buckets = self.buckets.lock().expect("Lock poisoned");
+        for (bucket, limit) in [(&mut buckets.read, read), (&mut buckets.write, write)] {
+            if bucket.as_ref().map(|b| b.limit) != limit {
+                *bucket = limit.map(|limit| TokenBucket::new(limit, now));
+            }
+        }
+    }
+
+    /// Return the current limits on reading and writing.
+    pub fn limits(&self) -> (Option<BandwidthLimit>, Option<BandwidthLimit>) {
+        let buckets = self.buckets.lock().expect("Lock poisoned");
+        (
+            buckets.read.as_ref().map(|b| b.limit),
+            buckets.write.as_ref().map(|b| b.limit),
+        )
+    }
+
+    /// Try to take tokens to move up to `want` bytes in direction `dir`.
+    ///
+    /// See [`TokenBucket::take`].
+    fn take(&self, dir: Direction, want: usize) -> Result<usize, Duration> {
+        let now = self.time.now();
+        let mut buckets = self.buckets.lock().expect("Lock poisoned");
+        match buckets.get_mut(dir) {
+            Some(bucket) => bucket.take(now, want),
+            None => Ok(want),
+        }
+    }
+
+    /// Return tokens for `n` bytes in direction `dir` that were taken, but
+    /// not moved.
+    fn refund(&self, dir: Direction, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let mut buckets = self.buckets.lock().expect("Lock poisoned");
+        if let Some(bucket) = buckets.get_mut(dir) {
+            bucket.refund(n);
+        }
+    }
+
+    /// Wait until we may move some of `want` bytes in direction `dir`, and
+    /// return how many.
+    ///
+    /// `wait` holds the timer that we are waiting for, if any.
+    fn poll_grant(
+        &self,
+        dir: Direction,
+        wait: &mut Option<<DynTimeProvider as SleepProvider>::SleepFuture>,
+        cx: &mut Context<'_>,
+        want: usize,
+    ) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = wait {
+                ready!(sleep.as_mut().poll(cx));
+                *wait = None;
+            }
+            match self.take(dir, want) {
+                Ok(n) => return Poll::Ready(n),
+                Err(delay) => *wait = Some(self.time.sleep(delay)),
+            }
+        }
+    }
+}
+
+/// A stream whose reads and writes obey a [`BandwidthLimiter`].
+pub(crate) struct RateLimitedStream<S> {
+    /// The underlying stream.
+    inner: S,
+    /// The limits we obey.
+    limiter: BandwidthLimiter,
+    /// The timer that a blocked read is waiting for.
+    read_wait: Option<<DynTimeProvider as SleepProvider>::SleepFuture>,
+    /// The timer that a blocked write is waiting for.
+    write_wait: Option<<DynTimeProvider as SleepProvider>::SleepFuture>,
+}
+
+impl<S> RateLimitedStream<S> {
+    /// Wrap `inner`, so that it obeys `limiter`.
+    pub(crate) fn new(inner: S, limiter: BandwidthLimiter) -> Self {
+        RateLimitedStream {
+            inner,
+            limiter,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimitedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n =
+            ready!(this
+                .limiter
+                .poll_grant(Direction::Read, &mut this.read_wait, cx, buf.len()));
+        let r = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..n]);
+        let used = match &r {
+            Poll::Ready(Ok(got)) => *got,
+            _ => 0,
+        };
+        this.limiter.refund(Direction::Read, n - used);
+        r
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimitedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let n =
+            ready!(this
+                .limiter
+                .poll_grant(Direction::Write, &mut this.write_wait, cx, buf.len()));
+        let r = Pin::new(&mut this.inner).poll_write(cx, &buf[..n]);
+        let used = match &r {
+            Poll::Ready(Ok(wrote)) => *wrote,
+            _ => 0,
+        };
+        this.limiter.refund(Direction::Write, n - used);
+        r
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+impl<S: StreamOps> StreamOps for RateLimitedStream<S> {
+    fn set_tcp_notsent_lowat(&self, notsent_lowat: u32) -> io::Result<()> {
+        self.inner.set_tcp_notsent_lowat(notsent_lowat)
+    }
+
+    fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
+        self.inner.new_handle()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(BandwidthLimit::new(1024, 2048), start);
+
+        // The bucket starts out full.
+        assert_eq!(bucket.take(start, 1536), Ok(1536));
+        // We need MIN_GRANT bytes' worth before we can go on.
+        assert_eq!(
+            bucket.take(start, 1536),
+            Err(Duration::from_nanos(1_953_125))
+        );
+        // But a small read or write can go ahead.
+        assert_eq!(bucket.take(start, 100), Ok(100));
+
+        bucket.refund(200);
+        assert_eq!(bucket.take(start, 5000), Ok(612));
+
+        // Refilling stops when the bucket is full.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(later, 5000), Ok(2048));
+        assert_eq!(bucket.take(later + Duration::from_secs(1), 5000), Ok(1024));
+    }
+
+    #[test]
+    fn small_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(BandwidthLimit::new(10, 10), start);
+        assert_eq!(bucket.take(start, 100), Ok(10));
+        assert_eq!(bucket.take(start, 100), Err(Duration::from_secs(1)));
+        assert_eq!(
+            bucket.take(start + Duration::from_millis(500), 100),
+            Err(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn set_limits() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let limiter = BandwidthLimiter::new(DynTimeProvider::new(rt.clone()));
+            assert_eq!(limiter.limits(), (None, None));
+            assert_eq!(limiter.take(Direction::Read, 1 << 20), Ok(1 << 20));
+
+            let limit = BandwidthLimit::new(1000, 1000);
+            limiter.set_limits(Some(limit), None);
+            assert_eq!(limiter.limits(), (Some(limit), None));
+            assert_eq!(limiter.take(Direction::Read, 5000), Ok(1000));
+            assert_eq!(limiter.take(Direction::Write, 5000), Ok(5000));
+
+            // Setting the same limit again doesn't refill the bucket.
+            limiter.set_limits(Some(limit), None);
+            assert!(limiter.take(Direction::Read, 5000).is_err());
+
+            limiter.set_limits(None, Some(limit));
+            assert_eq!(limiter.take(Direction::Read, 5000), Ok(5000));
+            assert_eq!(limiter.take(Direction::Write, 5000), Ok(1000));
+        });
+    }
+
+    #[test]
+    fn stream() {
+        use futures::{AsyncReadExt as _, AsyncWriteExt as _};
+        use tor_rtmock::io::stream_pair;
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let limiter = BandwidthLimiter::new(DynTimeProvider::new(rt.clone()));
+            limiter.set_limits(None, Some(BandwidthLimit::new(1000, 1000)));
+
+            let (a, mut b) = stream_pair();
+            let mut a = RateLimitedStream::new(a, limiter);
+            let start = rt.now();
+            let writer = rt.spawn_join("writer", {
+                let rt = rt.clone();
+                async move {
+                    a.write_all(&[7_u8; 3000]).await.unwrap();
+                    a.flush().await.unwrap();
+                    rt.now()
+                }
+            });
+            let reader = rt.spawn_join("reader", async move {
+                let mut got = vec![0_u8; 3000];
+                b.read_exact(&mut got).await.unwrap();
+                got
+            });
+
+            rt.advance_until_stalled().await;
+            assert_eq!(reader.await, [7_u8; 3000]);
+            // The first 1000 bytes were free; the rest took two seconds.
+            assert!(writer.await - start >= Duration::from_secs(2));
+        });
+    }
+}
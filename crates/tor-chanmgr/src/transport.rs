@@ -5,10 +5,12 @@ use futures::{AsyncRead, AsyncWrite};
 use tor_linkspec::OwnedChanTarget;
 
 pub(crate) mod default;
+pub mod https_proxy;
 pub mod proxied;
 
 pub(crate) use default::DefaultTransport;
 
+pub use https_proxy::{HttpsProxyDialer, ProxyTlsConnector};
 #[cfg(feature = "pt-client")]
 #[cfg_attr(docsrs, doc(cfg(feature = "experimental-api")))]
 pub use proxied::ExternalProxyPlugin;
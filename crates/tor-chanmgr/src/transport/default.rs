@@ -1,7 +1,11 @@
 //! Implement the default transport, which opens TCP connections using a
 //! happy-eyeballs style parallel algorithm.
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt, TryFutureExt};
@@ -11,6 +15,8 @@ use tor_linkspec::{ChannelMethod, HasChanMethod, OwnedChanTarget};
 use tor_rtcompat::{NetStreamProvider, Runtime};
 use tracing::trace;
 
+use crate::config::UpstreamProxy;
+use crate::transport::proxied::connect_via_proxy;
 use crate::Error;
 
 /// A default transport object that opens TCP connections for a
@@ -18,16 +24,29 @@ use crate::Error;
 ///
 /// It opens almost-simultaneous parallel TCP connections to each address, and
 /// chooses the first one to succeed.
+///
+/// If an upstream proxy is configured, it instead asks the proxy to connect to
+/// each address in turn.
 #[derive(Clone, Debug)]
 pub(crate) struct DefaultTransport<R: Runtime> {
     /// The runtime that we use for connecting.
     runtime: R,
+    /// The upstream proxy to connect through, if any.
+    ///
+    /// Shared with the `ChanMgr`, which replaces it on reconfiguration.
+    proxy: Arc<Mutex<Option<UpstreamProxy>>>,
 }
 
 impl<R: Runtime> DefaultTransport<R> {
     /// Construct a new DefaultTransport
     pub(crate) fn new(runtime: R) -> Self {
-        Self { runtime }
+        Self::with_proxy(runtime, Arc::new(Mutex::new(None)))
+    }
+
+    /// Construct a new DefaultTransport that connects through whichever
+    /// upstream proxy is in `proxy` when it is asked to connect.
+    pub(crate) fn with_proxy(runtime: R, proxy: Arc<Mutex<Option<UpstreamProxy>>>) -> Self {
+        Self { runtime, proxy }
     }
 }
 
@@ -51,9 +70,17 @@ impl<R: Runtime> crate::transport::TransportImplHelper for DefaultTransport<R> {
             }
         };
 
-        trace!("Launching direct connection for {}", target);
-
-        let (stream, addr) = connect_to_one(&self.runtime, &direct_addrs).await?;
+        let proxy = self.proxy.lock().expect("poisoned lock").clone();
+        let (stream, addr) = match proxy {
+            Some(proxy) => {
+                trace!("Launching proxied connection for {}", target);
+                connect_via_upstream_proxy(&self.runtime, &proxy, &direct_addrs).await?
+            }
+            None => {
+                trace!("Launching direct connection for {}", target);
+                connect_to_one(&self.runtime, &direct_addrs).await?
+            }
+        };
         let mut using_target = target.clone();
         let _ignore = using_target.chan_method_mut().retain_addrs(|a| a == &addr);
 
@@ -61,6 +88,35 @@ impl<R: Runtime> crate::transport::TransportImplHelper for DefaultTransport<R> {
     }
 }
 
+/// Connect to one of the addresses in `addrs` through `proxy`, trying each
+/// address in turn until one works.
+async fn connect_via_upstream_proxy<R: Runtime>(
+    rt: &R,
+    proxy: &UpstreamProxy,
+    addrs: &[SocketAddr],
+) -> crate::Result<(<R as NetStreamProvider>::Stream, SocketAddr)> {
+    let mut last_error = None;
+    for addr in addrs {
+        let target = tor_linkspec::PtTargetAddr::IpPort(*addr);
+        match connect_via_proxy(rt, &proxy.addr(), proxy.protocol(), &target).await {
+            Ok(stream) => return Ok((stream, *addr)),
+            Err(e) => {
+                tor_error::warn_report!(
+                    e,
+                    "Connection to {} through proxy at {} failed",
+                    sv(addr),
+                    proxy.addr()
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(match last_error {
+        Some(e) => e.into(),
+        None => Error::UnusableTarget(bad_api_usage!("No addresses for chosen relay")),
+    })
+}
+
 /// Time to wait between starting parallel connections to the same relay.
 static CONNECTION_DELAY: Duration = Duration::from_millis(150);
 
@@ -0,0 +1,313 @@
+//! Connect to relays through an HTTPS proxy.
+//!
+//! An HTTPS proxy is an HTTP proxy that we reach over TLS: we negotiate TLS
+//! with the proxy, and then send it a `CONNECT` request inside the TLS
+//! session, so that a network observer between us and the proxy can't see
+//! which relay we ask for, or our proxy credentials.
+//!
+//! The streams that come out of such a proxy are TLS streams, not the
+//! runtime's own TCP streams, so we can't open them from the
+//! [`ChannelConfig`](crate::ChannelConfig)'s `proxy` option.  Instead,
+//! build an [`HttpsProxyDialer`], and wrap your runtime with it using
+//! [`RuntimeSubstExt::with_tcp_dialer`](tor_rtcompat::RuntimeSubstExt::with_tcp_dialer):
+//! every connection that the wrapped runtime opens to a relay then goes
+//! through the proxy.
+//!
+//! TLS with the proxy is negotiated by a [`ProxyTlsConnector`], which the
+//! application can supply.  With the `native-tls` feature,
+//! [`NativeTlsProxyConnector`] is one that uses the platform's TLS library.
+
+use std::io;
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use futures::future::Either;
+use futures::{AsyncRead, AsyncWrite};
+use tor_linkspec::PtTargetAddr;
+use tor_rtcompat::{dialer::TcpDialer, NetStreamProvider, StreamOps};
+use tracing::trace;
+
+use super::proxied::http_connect;
+
+/// An object that negotiates TLS with an HTTPS proxy, on a stream of type `S`.
+///
+/// Implementations must validate the proxy's certificate for the hostname
+/// that they are given.
+#[async_trait]
+pub trait ProxyTlsConnector<S>: Clone + Send + Sync + 'static
+where
+    S: Send + 'static,
+{
+    /// The type of the TLS streams that this connector returns.
+    type Stream: AsyncRead + AsyncWrite + StreamOps + Send + Sync + Unpin + 'static;
+
+    /// Negotiate TLS on `stream`, which is connected to the proxy, expecting
+    /// the proxy to have a certificate for `hostname`.
+    async fn negotiate(&self, hostname: &str, stream: S) -> io::Result<Self::Stream>;
+}
+
+/// A [`TcpDialer`] that opens its connections through an HTTPS proxy.
+///
+/// Connections to loopback addresses (such as the local proxies of
+/// pluggable transports) are opened directly, without the proxy.
+///
+/// See the [module documentation](self) for how to use one.
+#[derive(Clone, Debug)]
+pub struct HttpsProxyDialer<R, C> {
+    /// The runtime that we use to connect to the proxy, and to loopback
+    /// addresses.
+    ///
+    /// This must not be a runtime that is itself wrapped with this dialer.
+    runtime: R,
+    /// The address of the proxy.
+    proxy_addr: SocketAddr,
+    /// The hostname that the proxy's certificate must be for.
+    hostname: String,
+    /// Used to negotiate TLS with the proxy.
+    tls: C,
+    /// A username and password to send with HTTP basic authentication, if
+    /// any.
+    auth: Option<(String, String)>,
+}
+
+impl<R, C> HttpsProxyDialer<R, C>
+where
+    R: NetStreamProvider + Clone,
+    C: ProxyTlsConnector<R::Stream>,
+{
+    /// Return a new dialer that connects through the HTTPS proxy at
+    /// `proxy_addr`, whose certificate must be for `hostname`, negotiating
+    /// TLS with `tls`.
+    ///
+    /// `runtime` is used to connect to the proxy: it must be the runtime that
+    /// you are wrapping with this dialer, not the wrapped one.
+    pub fn new(runtime: R, proxy_addr: SocketAddr, hostname: impl Into<String>, tls: C) -> Self {
+        HttpsProxyDialer {
+            runtime,
+            proxy_addr,
+            hostname: hostname.into(),
+            tls,
+            auth: None,
+        }
+    }
+
+    /// Authenticate to the proxy with `user` and `password`, using HTTP basic
+    /// authentication.
+    pub fn with_auth(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = Some((user.into(), password.into()));
+        self
+    }
+}
+
+#[async_trait]
+impl<R, C> TcpDialer for HttpsProxyDialer<R, C>
+where
+    R: NetStreamProvider + Clone,
+    C: ProxyTlsConnector<R::Stream>,
+{
+    /// Connections through the proxy are `Left`; direct connections to
+    /// loopback addresses are `Right`.
+    type Stream = Either<C::Stream, R::Stream>;
+
+    async fn dial(&self, addr: &SocketAddr) -> io::Result<Self::Stream> {
+        if addr.ip().is_loopback() {
+            return Ok(Either::Right(self.runtime.connect(addr).await?));
+        }
+
+        trace!(
+            "Connecting to {} via HTTPS proxy at {}",
+            addr,
+            self.proxy_addr
+        );
+        let stream = self.runtime.connect(&self.proxy_addr).await?;
+        let mut stream = self.tls.negotiate(&self.hostname, stream).await?;
+        http_connect(
+            &mut stream,
+            &self.proxy_addr,
+            self.auth.as_ref(),
+            &PtTargetAddr::IpPort(*addr),
+        )
+        .await
+        .map_err(io::Error::other)?;
+        Ok(Either::Left(stream))
+    }
+}
+
+/// A [`ProxyTlsConnector`] that uses the platform's TLS library, and
+/// validates the proxy's certificate against the platform's root
+/// certificates.
+#[cfg(feature = "native-tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "native-tls")))]
+#[derive(Clone, Default)]
+pub struct NativeTlsProxyConnector {
+    /// Root certificates to trust as well as the platform's.
+    roots: Vec<async_native_tls::Certificate>,
+}
+
+#[cfg(feature = "native-tls")]
+impl std::fmt::Debug for NativeTlsProxyConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeTlsProxyConnector")
+            .field("roots", &self.roots.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "native-tls")]
+impl NativeTlsProxyConnector {
+    /// Construct a new connector that trusts the platform's root certificates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also trust the DER-encoded certificate `der` as a root certificate.
+    pub fn with_root_certificate(mut self, der: &[u8]) -> io::Result<Self> {
+        let cert = async_native_tls::Certificate::from_der(der).map_err(io::Error::other)?;
+        self.roots.push(cert);
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "native-tls")]
+#[async_trait]
+impl<S> ProxyTlsConnector<S> for NativeTlsProxyConnector
+where
+    S: AsyncRead + AsyncWrite + StreamOps + Send + Sync + Unpin + 'static,
+{
+    type Stream = async_native_tls::TlsStream<S>;
+
+    async fn negotiate(&self, hostname: &str, stream: S) -> io::Result<Self::Stream> {
+        let tls = self
+            .roots
+            .iter()
+            .fold(async_native_tls::TlsConnector::new(), |tls, cert| {
+                tls.add_root_certificate(cert.clone())
+            });
+        tls.connect(hostname, stream)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(all(test, feature = "native-tls"))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use native_tls_crate as native_tls;
+    use std::io::{Read as _, Write as _};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread::JoinHandle;
+    use tor_rtcompat::RuntimeSubstExt as _;
+
+    /// A self-signed certificate for `proxy.example`; see `testdata/README.md`.
+    const PROXY_DER: &[u8] = include_bytes!("../../testdata/proxy.der");
+    /// The same certificate, with its private key.
+    const PROXY_PFX: &[u8] = include_bytes!("../../testdata/proxy.pfx");
+    /// The password for [`PROXY_PFX`].
+    const PROXY_PFX_PASSWORD: &str = "abc";
+
+    /// The relay that we ask the proxy to connect to.
+    const RELAY: &str = "192.0.2.1:9001";
+
+    /// Run a fake HTTPS proxy that accepts a single `CONNECT` request, and
+    /// then sends back whatever it receives, as though it were the relay.
+    ///
+    /// Returns the address it listens on, and a handle that returns the
+    /// request it received, or `None` if the TLS handshake failed.
+    fn fake_proxy() -> (SocketAddr, JoinHandle<Option<String>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let identity = native_tls::Identity::from_pkcs12(PROXY_PFX, PROXY_PFX_PASSWORD).unwrap();
+        let handle = std::thread::spawn(move || {
+            let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+            let (conn, _) = listener.accept().unwrap();
+            let mut conn = acceptor.accept(conn).ok()?;
+            let mut req = Vec::new();
+            let mut byte = [0];
+            while !req.ends_with(b"\r\n\r\n") {
+                assert_eq!(conn.read(&mut byte).unwrap(), 1);
+                req.push(byte[0]);
+            }
+            conn.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+            let mut buf = [0; 5];
+            conn.read_exact(&mut buf).unwrap();
+            conn.write_all(&buf).unwrap();
+            conn.shutdown().unwrap();
+            Some(String::from_utf8(req).unwrap())
+        });
+        (addr, handle)
+    }
+
+    /// Return a connector that trusts the certificate of our fake proxy.
+    fn connector() -> NativeTlsProxyConnector {
+        NativeTlsProxyConnector::new()
+            .with_root_certificate(PROXY_DER)
+            .unwrap()
+    }
+
+    #[test]
+    fn via_https_proxy() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let (proxy, server) = fake_proxy();
+            let dialer = HttpsProxyDialer::new(rt.clone(), proxy, "proxy.example", connector())
+                .with_auth("me", "secret");
+            let wrapped = rt.with_tcp_dialer(dialer);
+
+            let mut stream = wrapped.connect(&RELAY.parse().unwrap()).await.unwrap();
+            assert!(matches!(stream, Either::Left(Either::Left(_))));
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // The request went inside the TLS session, to the proxy.
+            let req = server.join().unwrap().unwrap();
+            assert!(req.starts_with(&format!("CONNECT {RELAY} HTTP/1.1\r\n")));
+            assert!(req.contains("\r\nProxy-Authorization: Basic bWU6c2VjcmV0\r\n"));
+        });
+    }
+
+    #[test]
+    fn wrong_certificate() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            // The proxy's certificate is for proxy.example, not other.example.
+            let (proxy, server) = fake_proxy();
+            let dialer = HttpsProxyDialer::new(rt.clone(), proxy, "other.example", connector());
+
+            assert!(dialer.dial(&RELAY.parse().unwrap()).await.is_err());
+            assert!(server.join().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn loopback_is_direct() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let addr = listener.local_addr().unwrap();
+            // No proxy listens here.
+            let unused = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let proxy = unused.local_addr().unwrap();
+            drop(unused);
+            let dialer = HttpsProxyDialer::new(rt.clone(), proxy, "proxy.example", connector());
+
+            let stream = dialer.dial(&addr).await.unwrap();
+            assert!(matches!(stream, Either::Right(_)));
+            listener.accept().unwrap();
+        });
+    }
+}
@@ -90,7 +90,7 @@ pub trait Timebound<T>: Sized {
 
     /// Unwrap this Timebound object if it is valid now.
     fn check_valid_now(self) -> Result<T, Self::Error> {
-        self.check_valid_at(&time::SystemTime::now())
+        self.check_valid_at(&wallclock_now())
     }
 
     /// Unwrap this object if it is valid at the provided time t.
@@ -103,6 +103,22 @@ pub trait Timebound<T>: Sized {
     }
 }
 
+/// Return the current wall-clock time.
+///
+/// On `wasm32-unknown-unknown`, `std` has no clock (`SystemTime::now` panics),
+/// so we ask the browser instead.
+fn wallclock_now() -> time::SystemTime {
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    {
+        use web_time::web::SystemTimeExt as _;
+        web_time::SystemTime::now().to_std()
+    }
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    {
+        time::SystemTime::now()
+    }
+}
+
 /// A cryptographically signed object that can be validated without
 /// additional public keys.
 ///
@@ -9,7 +9,7 @@ use futures::Future;
 use oneshot_fused_workaround as oneshot;
 use std::sync::{
     atomic::{AtomicU32, Ordering},
-    Arc,
+    Arc, Mutex,
 };
 use std::time::{Duration, Instant};
 use tor_chanmgr::{ChanMgr, ChanProvenance, ChannelUsage};
@@ -375,6 +375,8 @@ pub struct CircuitBuilder<R: Runtime> {
     builder: Arc<Builder<R, ClientCirc>>,
     /// Configuration for how to choose paths for circuits.
     path_config: tor_config::MutCfg<crate::PathConfig>,
+    /// A policy that may veto or re-weight the relays on our paths, if any.
+    path_policy: Mutex<Option<Arc<dyn crate::PathPolicy>>>,
     /// State-manager object to use in storing current state.
     storage: crate::TimeoutStateHandle,
     /// Guard manager to tell us which guards nodes to use for the circuits
@@ -402,6 +404,7 @@ impl<R: Runtime> CircuitBuilder<R> {
         CircuitBuilder {
             builder: Arc::new(Builder::new(runtime, chanmgr, timeouts)),
             path_config: path_config.into(),
+            path_policy: Mutex::new(None),
             storage,
             guardmgr,
             #[cfg(all(feature = "vanguards", feature = "hs-common"))]
@@ -419,6 +422,16 @@ impl<R: Runtime> CircuitBuilder<R> {
         self.path_config.replace(new_config);
     }
 
+    /// Return this builder's [`PathPolicy`](crate::PathPolicy), if it has one.
+    pub(crate) fn path_policy(&self) -> Option<Arc<dyn crate::PathPolicy>> {
+        self.path_policy.lock().expect("poisoned lock").clone()
+    }
+
+    /// Replace this builder's [`PathPolicy`](crate::PathPolicy).
+    pub(crate) fn set_path_policy(&self, policy: Option<Arc<dyn crate::PathPolicy>>) {
+        *self.path_policy.lock().expect("poisoned lock") = policy;
+    }
+
     /// Flush state to the state manager if we own the lock.
     ///
     /// Return `Ok(true)` if we saved, and `Ok(false)` if we didn't hold the lock.
@@ -7,12 +7,14 @@
 use tor_basic_utils::define_accessor_trait;
 use tor_config::impl_standard_builder;
 use tor_config::{define_list_builder_accessors, define_list_builder_helper, ConfigBuildError};
-use tor_guardmgr::{GuardFilter, GuardMgrConfig};
+use tor_guardmgr::{AddrFamilyPolicy, GuardFilter, GuardMgrConfig};
 
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
+use tor_linkspec::{RelayId, RelayIdSet};
+use tor_netdir::{FamilyRules, NetDir, SubnetConfig};
 use tor_netdoc::types::policy::AddrPortPattern;
-use tor_relay_selection::RelaySelectionConfig;
+use tor_relay_selection::{RelayExclusion, RelaySelectionConfig};
 
 use std::collections::HashSet;
 use std::time::Duration;
@@ -27,7 +29,7 @@ use std::time::Duration;
 /// attached to existing circuits, if the configuration has become more
 /// restrictive.
 #[derive(Debug, Clone, Builder, Eq, PartialEq)]
-#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
 #[builder(derive(Debug, Serialize, Deserialize))]
 pub struct PathConfig {
     /// Set the length of a bit-prefix for a default IPv4 subnet-family.
@@ -53,9 +55,119 @@ pub struct PathConfig {
     #[builder(sub_builder, setter(custom))]
     #[builder_field_attr(serde(default))]
     pub(crate) reachable_addrs: ReachableAddrs,
+
+    /// Which IP address families we're willing to use for direct connections,
+    /// and which we prefer.
+    ///
+    /// Set this to [`AddrFamilyPolicy::Ipv6Only`] on an IPv6-only network.
+    #[builder(default)]
+    pub(crate) address_family: AddrFamilyPolicy,
+
+    /// Relays that we must never use, in any position on any circuit.
+    ///
+    /// Each relay is given by one of its identities: for example,
+    /// `"$0123456789ABCDEF0123456789ABCDEF01234567"` (an RSA identity),
+    /// or `"ed25519:..."`.
+    #[builder(sub_builder, setter(custom))]
+    #[builder_field_attr(serde(default))]
+    pub(crate) exclude_relays: RelayIdList,
+
+    /// If true, we also never use any relay in the same declared family as one
+    /// of the `exclude_relays`.
+    ///
+    /// Relays that are merely in the same subnet are not excluded.
+    #[builder(default)]
+    pub(crate) exclude_relay_families: bool,
+
+    /// Relays that we must never use, in any position on any circuit,
+    /// because they have an address matching one of these patterns.
+    #[builder(sub_builder, setter(custom))]
+    #[builder_field_attr(serde(default))]
+    pub(crate) exclude_addrs: ExcludedAddrs,
+
+    /// If this is not empty, the only relays that we may use as exits.
+    ///
+    /// This does not affect onion service circuits.
+    #[builder(sub_builder, setter(custom))]
+    #[builder_field_attr(serde(default))]
+    pub(crate) exit_relays: RelayIdList,
+
+    /// The number of relays on each exit circuit, including the guard and
+    /// the exit.
+    ///
+    /// Must be between 3 and 8.  Extra hops make circuits slower, and make
+    /// them stand out from those of most other clients.
+    ///
+    /// This does not affect onion service circuits, or the circuits that we
+    /// build to learn our circuit build timeout.
+    #[builder(default = "DEFAULT_HOPS")]
+    pub(crate) hops: u8,
 }
 impl_standard_builder! { PathConfig }
 
+/// The default number of relays on an exit circuit.
+pub(crate) const DEFAULT_HOPS: u8 = 3;
+
+/// The fewest relays that we allow on an exit circuit.
+pub(crate) const MIN_HOPS: u8 = 3;
+
+/// The most relays that we allow on an exit circuit.
+///
+/// (Relays only let us extend a circuit a limited number of times.)
+pub(crate) const MAX_HOPS: u8 = 8;
+
+impl PathConfigBuilder {
+    /// Check that this configuration is valid.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        if let Some(hops) = self.hops {
+            if !(MIN_HOPS..=MAX_HOPS).contains(&hops) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "hops".into(),
+                    problem: format!("must be between {MIN_HOPS} and {MAX_HOPS}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Type alias for a list of relay identities.
+type RelayIdList = Vec<RelayId>;
+
+define_list_builder_helper! {
+    struct RelayIdListBuilder {
+        ids: [RelayId],
+    }
+    built: RelayIdList = ids;
+    default = vec![];
+    item_build: |id| Ok(*id);
+}
+
+define_list_builder_accessors! {
+    struct PathConfigBuilder {
+        pub exclude_relays: [RelayId],
+        pub exit_relays: [RelayId],
+    }
+}
+
+/// Type alias for a list of excluded addresses.
+type ExcludedAddrs = Vec<AddrPortPattern>;
+
+define_list_builder_helper! {
+    struct ExcludedAddrsBuilder {
+        pub(crate) patterns: [AddrPortPattern],
+    }
+    built: ExcludedAddrs = patterns;
+    default = vec![];
+    item_build: |pat| Ok(pat.clone());
+}
+
+define_list_builder_accessors! {
+    struct PathConfigBuilder {
+        pub exclude_addrs: [AddrPortPattern],
+    }
+}
+
 /// Type alias for a list of reachable addresses.
 type ReachableAddrs = Vec<AddrPortPattern>;
 
@@ -133,15 +245,83 @@ impl PathConfig {
         self.ipv4_subnet_family_prefix >= other.ipv4_subnet_family_prefix
             && self.ipv6_subnet_family_prefix >= other.ipv6_subnet_family_prefix
             && self.reachable_addrs == other.reachable_addrs
+            && (self.address_family != AddrFamilyPolicy::Ipv6Only
+                || other.address_family == AddrFamilyPolicy::Ipv6Only)
+            && self.exclude_relays == other.exclude_relays
+            && self.exclude_relay_families == other.exclude_relay_families
+            && self.exclude_addrs == other.exclude_addrs
+            && self.exit_relays == other.exit_relays
+            && self.hops == other.hops
     }
 
     /// Return a new [`GuardFilter`] reflecting the rules in this configuration.
     pub(crate) fn build_guard_filter(&self) -> GuardFilter {
         let mut filt = GuardFilter::default();
         filt.push_reachable_addresses(self.reachable_addrs.clone());
+        filt.push_addr_family_policy(self.address_family);
+        filt.push_unwanted_relays(
+            self.exclude_relays.iter().copied().collect(),
+            self.exclude_addrs.clone(),
+        );
         filt
     }
 
+    /// Return a [`RelayExclusion`] for every relay in `netdir` that this
+    /// configuration says never to use.
+    pub(crate) fn unwanted_relays<'a>(
+        &self,
+        netdir: &'a NetDir,
+        family_rules: FamilyRules,
+    ) -> RelayExclusion<'a> {
+        let mut exclusion = RelayExclusion::exclude_unwanted(
+            self.exclude_relays.iter().copied().collect(),
+            self.exclude_addrs.clone(),
+        );
+        if self.exclude_relay_families {
+            // Only declared families count here: don't exclude whole subnets.
+            let cfg = RelaySelectionConfig {
+                long_lived_ports: &self.long_lived_ports,
+                subnet_config: SubnetConfig::no_addresses_match(),
+            };
+            let relays = self
+                .exclude_relays
+                .iter()
+                .filter_map(|id| netdir.by_id(id))
+                .collect();
+            exclusion.extend(&RelayExclusion::exclude_relays_in_same_family(
+                &cfg,
+                relays,
+                family_rules,
+            ));
+        }
+        exclusion
+    }
+
+    /// Return the identities of the relays in `netdir` that are in the same
+    /// family as one of our `exclude_relays`, if we should avoid them.
+    ///
+    /// Our [`GuardFilter`] already forbids the `exclude_relays` themselves,
+    /// but it can't know about their families.
+    pub(crate) fn unwanted_guards(&self, netdir: &NetDir) -> RelayIdSet {
+        let mut ids = RelayIdSet::new();
+        if self.exclude_relay_families {
+            for relay in self.exclude_relays.iter().filter_map(|id| netdir.by_id(id)) {
+                ids.extend(netdir.known_family_members(&relay).map(|r| *r.id()));
+            }
+        }
+        ids
+    }
+
+    /// Return the number of relays to put on each exit circuit.
+    pub(crate) fn hops(&self) -> u8 {
+        self.hops
+    }
+
+    /// Return the relays that we may use as exits, or `None` if we may use any.
+    pub(crate) fn exit_relays(&self) -> Option<RelayIdSet> {
+        (!self.exit_relays.is_empty()).then(|| self.exit_relays.iter().copied().collect())
+    }
+
     /// Return a new [`RelaySelectionConfig`] reflecting the rules in this
     /// configuration.
     pub(crate) fn relay_selection_config(&self) -> RelaySelectionConfig<'_> {
@@ -184,6 +364,16 @@ pub struct PreemptiveCircuitConfig {
     #[builder(sub_builder, setter(custom))]
     pub(crate) initial_predicted_ports: PredictedPortsList,
 
+    /// Which exit ports should we always expect that the client will want,
+    /// whether or not it has used them lately?
+    ///
+    /// Unlike the `initial_predicted_ports`, these ports never stop being
+    /// predicted.
+    ///
+    /// The default is empty.
+    #[builder(sub_builder, setter(custom))]
+    pub(crate) always_predicted_ports: AlwaysPredictedPortsList,
+
     /// After we see the client request a connection to a new port, how long
     /// should we predict that the client will still want to have circuits
     /// available for that port?
@@ -198,6 +388,13 @@ pub struct PreemptiveCircuitConfig {
 }
 impl_standard_builder! { PreemptiveCircuitConfig }
 
+impl PreemptiveCircuitConfig {
+    /// Return the exit ports that we expect the client to want at startup.
+    pub fn initial_predicted_ports(&self) -> &[u16] {
+        &self.initial_predicted_ports
+    }
+}
+
 /// Configuration for circuit timeouts, expiration, and so on.
 ///
 /// This type is immutable once constructed. To create an object of this type,
@@ -209,7 +406,7 @@ impl_standard_builder! { PreemptiveCircuitConfig }
 /// requests.  However, there are currently bugs: see bug
 /// [#263](https://gitlab.torproject.org/tpo/core/arti/-/issues/263).
 #[derive(Debug, Clone, Builder, Eq, PartialEq)]
-#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
 #[builder(derive(Debug, Serialize, Deserialize))]
 // TODO Use a getters derive macro which lets us only generate getters
 // for fields we explicitly request, rather than having to mark the rest with `skip`.
@@ -223,6 +420,16 @@ pub struct CircuitTiming {
     #[getter(skip)]
     pub(crate) max_dirtiness: Duration,
 
+    /// How many streams should we give a circuit out for, before we stop
+    /// giving it out for new requests?
+    ///
+    /// Like `max_dirtiness`, this applies to each circuit separately, and so
+    /// to each isolation group.  If unset, only `max_dirtiness` limits how
+    /// long we use a circuit.
+    #[builder(default, setter(strip_option))]
+    #[getter(skip)]
+    pub(crate) max_streams: Option<u32>,
+
     /// When a circuit is requested, we stop retrying new circuits
     /// after this much time.
     // TODO: Impose a maximum or minimum?
@@ -246,6 +453,19 @@ pub struct CircuitTiming {
     #[getter(skip)]
     pub(crate) request_loyalty: Duration,
 
+    /// When a request needs a circuit to an exit, and none is open or being
+    /// built, build this many circuits at once, along disjoint paths.
+    ///
+    /// The request uses whichever circuit is built first; the others stay
+    /// available for later requests.  Racing circuits this way makes slow
+    /// requests rarer on unreliable networks, at the cost of building more
+    /// circuits.
+    ///
+    /// Must be between 1 and 3.
+    #[builder(default = "default_build_parallelism()")]
+    #[getter(skip)]
+    pub(crate) build_parallelism: usize,
+
     /// When an HS connection is attempted, we stop trying more hsdirs after this many attempts
     //
     // This parameter is honoured by tor-hsclient, not here.
@@ -267,6 +487,30 @@ pub struct CircuitTiming {
 }
 impl_standard_builder! { CircuitTiming }
 
+/// The most circuits that we build at once for a single request.
+const MAX_BUILD_PARALLELISM: usize = 3;
+
+impl CircuitTimingBuilder {
+    /// Check that this configuration is valid.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        if let Some(Some(0)) = self.max_streams {
+            return Err(ConfigBuildError::Invalid {
+                field: "max_streams".into(),
+                problem: "must be at least 1".into(),
+            });
+        }
+        if let Some(n) = self.build_parallelism {
+            if !(1..=MAX_BUILD_PARALLELISM).contains(&n) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "build_parallelism".into(),
+                    problem: format!("must be between 1 and {MAX_BUILD_PARALLELISM}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Return default threshold
 fn default_preemptive_threshold() -> usize {
     12
@@ -284,9 +528,22 @@ define_list_builder_helper! {
     item_build: |&port| Ok(port);
 }
 
+/// Built list of ports that we always predict.
+type AlwaysPredictedPortsList = Vec<u16>;
+
+define_list_builder_helper! {
+    struct AlwaysPredictedPortsListBuilder {
+        pub(crate) ports: [u16],
+    }
+    built: AlwaysPredictedPortsList = ports;
+    default = vec![];
+    item_build: |&port| Ok(port);
+}
+
 define_list_builder_accessors! {
     struct PreemptiveCircuitConfigBuilder {
         pub initial_predicted_ports: [u16],
+        pub always_predicted_ports: [u16],
     }
 }
 
@@ -334,6 +591,122 @@ fn default_request_loyalty() -> Duration {
     Duration::from_millis(50)
 }
 
+/// Return the default value for `build_parallelism`.
+fn default_build_parallelism() -> usize {
+    1
+}
+
+/// When to spread a stream's traffic over several circuits at once.
+///
+/// A set of circuits that share an exit relay, and over which the exit
+/// spreads the data of each stream, is called a conflux set (see
+/// [proposal 329](https://spec.torproject.org/proposals/329-traffic-splitting.html)).
+///
+/// Streams are *interactive* if their port is one of the `long_lived_ports`
+/// in the [`PathConfig`], and *bulk* otherwise.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum MultipathUse {
+    /// Never use conflux sets.
+    #[default]
+    Never,
+    /// Use conflux sets for bulk streams only.
+    Bulk,
+    /// Use conflux sets for interactive streams only.
+    Interactive,
+    /// Use conflux sets for every stream to an exit.
+    Always,
+}
+
+impl MultipathUse {
+    /// Return true if we should use a conflux set for a stream that is
+    /// `interactive` (or bulk, if not).
+    pub fn wants_multipath(self, interactive: bool) -> bool {
+        match self {
+            MultipathUse::Never => false,
+            MultipathUse::Bulk => !interactive,
+            MultipathUse::Interactive => interactive,
+            MultipathUse::Always => true,
+        }
+    }
+}
+
+/// Configuration for conflux sets: circuits to a single exit, over which the
+/// exit spreads the data of each stream.
+///
+/// Conflux sets are only built when this crate is compiled with the
+/// `conflux` feature; otherwise this configuration is ignored.
+///
+/// This type is immutable once constructed.  To create an object of this
+/// type, use [`ConfluxConfigBuilder`].
+///
+/// You can change the ConfluxConfig on a running Arti client.  Doing so
+/// affects the conflux sets that are built in the future.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+#[derive(amplify::Getters)]
+pub struct ConfluxConfig {
+    /// Which streams should use conflux sets, instead of single circuits.
+    ///
+    /// If we can't build a conflux set for a stream, we fall back to a single
+    /// circuit.
+    #[builder(default)]
+    #[getter(as_copy)]
+    pub(crate) multipath: MultipathUse,
+
+    /// How many circuits to put in each conflux set.
+    ///
+    /// The circuits all end at the same exit, but their other relays are
+    /// disjoint.  Must be between 2 and 4.
+    #[builder(default = "default_conflux_legs()")]
+    #[getter(as_copy)]
+    pub(crate) legs: u8,
+
+    /// The largest difference that we accept between the estimated
+    /// round-trip times of the circuits in a conflux set.
+    ///
+    /// If a circuit is much slower than the others, it would hold back the
+    /// data sent over them, so we replace it with another.
+    #[builder(default = "default_max_rtt_difference()")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    #[getter(as_copy)]
+    pub(crate) max_rtt_difference: Duration,
+}
+impl_standard_builder! { ConfluxConfig }
+
+/// The fewest circuits that we put in a conflux set.
+const MIN_CONFLUX_LEGS: u8 = 2;
+
+/// The most circuits that we put in a conflux set.
+const MAX_CONFLUX_LEGS: u8 = 4;
+
+impl ConfluxConfigBuilder {
+    /// Check that this configuration is valid.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        if let Some(legs) = self.legs {
+            if !(MIN_CONFLUX_LEGS..=MAX_CONFLUX_LEGS).contains(&legs) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "legs".into(),
+                    problem: format!("must be between {MIN_CONFLUX_LEGS} and {MAX_CONFLUX_LEGS}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Return the default value for `legs`.
+fn default_conflux_legs() -> u8 {
+    2
+}
+
+/// Return the default value for `max_rtt_difference`.
+fn default_max_rtt_difference() -> Duration {
+    Duration::from_millis(500)
+}
+
 define_accessor_trait! {
     /// Configuration for a circuit manager
     ///
@@ -366,6 +739,7 @@ define_accessor_trait! {
         path_rules: PathConfig,
         circuit_timing: CircuitTiming,
         preemptive_circuits: PreemptiveCircuitConfig,
+        conflux: ConfluxConfig,
         +
         // Note: ideally this would be defined in the same way as `path_rules`,
         // `circuit_timing`, etc., but define_accessor_trait unconditionally adds
@@ -396,6 +770,7 @@ pub(crate) mod test_config {
         pub path_rules: PathConfig,
         pub circuit_timing: CircuitTiming,
         pub preemptive_circuits: PreemptiveCircuitConfig,
+        pub conflux: ConfluxConfig,
         pub guardmgr: tor_guardmgr::TestConfig,
         #[cfg(all(feature = "vanguards", feature = "hs-common"))]
         pub vanguard_config: VanguardConfig,
@@ -425,6 +800,9 @@ pub(crate) mod test_config {
         fn preemptive_circuits(&self) -> &PreemptiveCircuitConfig {
             &self.preemptive_circuits
         }
+        fn conflux(&self) -> &ConfluxConfig {
+            &self.conflux
+        }
         #[cfg(all(feature = "vanguards", feature = "hs-common"))]
         fn vanguard_config(&self) -> &tor_guardmgr::VanguardConfig {
             &self.vanguard_config
@@ -470,5 +848,90 @@ mod test {
         assert!(!pc1.at_least_as_permissive_as(&pc2));
         assert!(!pc1.at_least_as_permissive_as(&pc3));
         assert!(!pc3.at_least_as_permissive_as(&pc2));
+
+        // Restricting ourselves to IPv6 makes us less permissive; merely
+        // preferring IPv6 doesn't.
+        let pc4 = PathConfig::builder()
+            .address_family(AddrFamilyPolicy::Ipv6Only)
+            .build()
+            .unwrap();
+        let pc5 = PathConfig::builder()
+            .address_family(AddrFamilyPolicy::PreferIpv6)
+            .build()
+            .unwrap();
+        assert!(pc1.at_least_as_permissive_as(&pc4));
+        assert!(!pc4.at_least_as_permissive_as(&pc1));
+        assert!(pc4.at_least_as_permissive_as(&pc4));
+        assert!(pc5.at_least_as_permissive_as(&pc1));
+        assert!(pc1.at_least_as_permissive_as(&pc5));
+    }
+
+    #[test]
+    fn hops() {
+        assert_eq!(PathConfig::default().hops(), 3);
+
+        // Circuits of one length can't be used where we want another.
+        let pc1 = PathConfig::default();
+        let pc2 = PathConfig::builder().hops(4).build().unwrap();
+        assert!(!pc1.at_least_as_permissive_as(&pc2));
+        assert!(!pc2.at_least_as_permissive_as(&pc1));
+
+        assert!(PathConfig::builder().hops(2).build().is_err());
+        assert!(PathConfig::builder().hops(9).build().is_err());
+        assert!(PathConfig::builder().hops(8).build().is_ok());
+    }
+
+    #[test]
+    fn max_streams() {
+        assert_eq!(CircuitTiming::default().max_streams, None);
+        assert!(CircuitTiming::builder().max_streams(0).build().is_err());
+        assert_eq!(
+            CircuitTiming::builder()
+                .max_streams(10)
+                .build()
+                .unwrap()
+                .max_streams,
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn build_parallelism() {
+        assert_eq!(CircuitTiming::default().build_parallelism, 1);
+        assert!(CircuitTiming::builder()
+            .build_parallelism(0)
+            .build()
+            .is_err());
+        assert!(CircuitTiming::builder()
+            .build_parallelism(4)
+            .build()
+            .is_err());
+        assert_eq!(
+            CircuitTiming::builder()
+                .build_parallelism(3)
+                .build()
+                .unwrap()
+                .build_parallelism,
+            3
+        );
+    }
+
+    #[test]
+    fn conflux() {
+        let cfg = ConfluxConfig::default();
+        assert_eq!(cfg.multipath, MultipathUse::Never);
+        assert_eq!(cfg.legs, 2);
+        assert!(ConfluxConfig::builder().legs(1).build().is_err());
+        assert!(ConfluxConfig::builder().legs(5).build().is_err());
+        assert!(ConfluxConfig::builder().legs(4).build().is_ok());
+
+        assert!(!MultipathUse::Never.wants_multipath(false));
+        assert!(!MultipathUse::Never.wants_multipath(true));
+        assert!(MultipathUse::Bulk.wants_multipath(false));
+        assert!(!MultipathUse::Bulk.wants_multipath(true));
+        assert!(!MultipathUse::Interactive.wants_multipath(false));
+        assert!(MultipathUse::Interactive.wants_multipath(true));
+        assert!(MultipathUse::Always.wants_multipath(false));
+        assert!(MultipathUse::Always.wants_multipath(true));
     }
 }
@@ -0,0 +1,578 @@
+//! Conflux sets: circuits that end at the same exit, linked into a single
+//! tunnel over which the exit spreads the data of each stream.
+//!
+//! See [proposal 329](https://spec.torproject.org/proposals/329-traffic-splitting.html).
+//!
+//! We build the circuits of a set (its "legs") one at a time.  The first leg
+//! picks an exit that supports conflux; the others end at the same exit, but
+//! avoid the relays between the guard and the exit of the legs before them.
+//!
+//! A leg that is much slower than the others would hold back the data sent
+//! over all of them, so we estimate the round-trip time of each leg, and
+//! replace any leg that is too slow.  Then we ask tor-proto to link the legs.
+//! We start from an estimate based on how long the leg took to build, and use
+//! the round-trip time measured from its SENDMEs once we have one.
+//!
+//! Once a set is linked, we give it out to the requests that it supports, like
+//! any other circuit, until it is too dirty.  Each time, we check the
+//! round-trip times measured on its legs, and stop giving it out if they have
+//! drifted too far apart.
+//!
+//! A request that arrives while we are building a set for the same target
+//! waits for that set, rather than building another.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{FutureExt as _, Shared};
+use oneshot_fused_workaround as oneshot;
+use tor_error::{debug_report, internal};
+use tor_linkspec::HasRelayIds as _;
+use tor_netdir::NetDir;
+use tor_rtcompat::{Runtime, SleepProvider as _};
+use tracing::debug;
+
+use crate::isolation::{IsolationHelper as _, StreamIsolation};
+use crate::mgr::{AbstractCirc, AbstractCircBuilder};
+use crate::usage::{SupportedCircUsage, TargetCircUsage};
+use crate::{CircMgrInner, ConfluxConfig, DirInfo, Error, RelayRules, Result};
+
+/// How many legs we replace in a set, at most, because they were too slow,
+/// before we give up on building it.
+const MAX_LEG_REPLACEMENTS: usize = 3;
+
+/// A circuit that we have built to be part of a conflux set.
+struct Leg<C> {
+    /// The circuit itself.
+    circ: Arc<C>,
+    /// Our estimate of the round-trip time over the whole circuit.
+    ///
+    /// At first, this is based on how long the circuit took to build.
+    rtt: Duration,
+}
+
+impl<C: AbstractCirc> Leg<C> {
+    /// Replace our estimate of the round-trip time with the one measured from
+    /// the SENDMEs of the circuit's last hop, if we have one yet.
+    async fn update_rtt(&mut self) {
+        if let Ok(rtts) = self.circ.leg_rtts().await {
+            if let [Some(rtt)] = rtts[..] {
+                self.rtt = rtt;
+            }
+        }
+    }
+}
+
+/// A conflux set that we have linked, and may give out again.
+struct OpenSet<C> {
+    /// The first leg of the set, through which we use all of them.
+    tunnel: Arc<C>,
+    /// What the set can be used for.
+    spec: SupportedCircUsage,
+    /// When we stop giving the set out.
+    expires: Instant,
+}
+
+/// A conflux set that we are building.
+struct PendingSet {
+    /// Identifies this build, so that its [`PendingGuard`] can remove it.
+    id: u64,
+    /// The usage that the set is being built for.
+    usage: TargetCircUsage,
+    /// Resolves once the build is over, whether it succeeded or not.
+    done: Shared<oneshot::Receiver<()>>,
+}
+
+/// The conflux sets that a circuit manager has linked, or is building.
+pub(crate) struct ConfluxSets<C> {
+    /// The sets, in the order that we linked them.
+    sets: Vec<OpenSet<C>>,
+    /// The sets that we are building.
+    pending: Vec<PendingSet>,
+    /// The `id` to give the next [`PendingSet`].
+    next_pending_id: u64,
+}
+
+impl<C: AbstractCirc> ConfluxSets<C> {
+    /// Return a new, empty, `ConfluxSets`.
+    pub(crate) fn new() -> Self {
+        ConfluxSets {
+            sets: Vec::new(),
+            pending: Vec::new(),
+            next_pending_id: 0,
+        }
+    }
+
+    /// Return a set that supports `usage`, if we have one, and restrict it
+    /// to `usage`.
+    ///
+    /// Forget every set that has expired (as of `now`) or closed.
+    fn find(&mut self, usage: &TargetCircUsage, now: Instant) -> Option<Arc<C>> {
+        self.sets
+            .retain(|set| set.expires > now && set.tunnel.usable());
+        self.sets.iter_mut().find_map(|set| {
+            (set.spec.supports(usage) && set.spec.restrict_mut(usage).is_ok())
+                .then(|| Arc::clone(&set.tunnel))
+        })
+    }
+
+    /// Remember a newly linked set, which can be used for `spec` until
+    /// `expires`.
+    fn add(&mut self, tunnel: Arc<C>, spec: SupportedCircUsage, expires: Instant) {
+        self.sets.push(OpenSet {
+            tunnel,
+            spec,
+            expires,
+        });
+    }
+
+    /// Stop giving out `tunnel`.
+    fn retire(&mut self, tunnel: &Arc<C>) {
+        self.sets.retain(|set| !Arc::ptr_eq(&set.tunnel, tunnel));
+    }
+
+    /// If we are building a set for the same target as `usage`, return a
+    /// future that resolves once that build is over.
+    fn find_pending(&self, usage: &TargetCircUsage) -> Option<Shared<oneshot::Receiver<()>>> {
+        self.pending
+            .iter()
+            .find(|pending| same_target(&pending.usage, usage))
+            .map(|pending| pending.done.clone())
+    }
+
+    /// Record that we are building a set for `usage`.
+    ///
+    /// Return the id of the build, and a sender to drop once it is over.
+    fn add_pending(&mut self, usage: &TargetCircUsage) -> (u64, oneshot::Sender<()>) {
+        let id = self.next_pending_id;
+        self.next_pending_id = id.wrapping_add(1);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.push(PendingSet {
+            id,
+            usage: usage.clone(),
+            done: receiver.shared(),
+        });
+        (id, sender)
+    }
+
+    /// Stop giving out any of our sets.
+    pub(crate) fn retire_all(&mut self) {
+        self.sets.clear();
+    }
+
+    /// Stop giving out the sets that have been used by a stream with an
+    /// isolation compatible with `isolation`.
+    pub(crate) fn retire_isolated(&mut self, isolation: &StreamIsolation) {
+        self.sets.retain(|set| {
+            !set.spec
+                .isolation()
+                .is_some_and(|i| i.compatible_same_type(isolation))
+        });
+    }
+}
+
+/// Removes a [`PendingSet`] from its [`ConfluxSets`] when dropped: that is,
+/// when the build succeeds, fails, or is cancelled.
+struct PendingGuard<'a, C> {
+    /// The sets that the pending set belongs to.
+    sets: &'a Mutex<ConfluxSets<C>>,
+    /// The id of the pending set.
+    id: u64,
+    /// Dropped after the pending set is removed, to wake up the requests
+    /// that are waiting for it.
+    _done: oneshot::Sender<()>,
+}
+
+impl<C> Drop for PendingGuard<'_, C> {
+    fn drop(&mut self) {
+        if let Ok(mut sets) = self.sets.lock() {
+            sets.pending.retain(|pending| pending.id != self.id);
+        }
+    }
+}
+
+/// Return true if a request for `usage` should wait for a set that we are
+/// building for `pending`, because the set will probably support it too.
+///
+/// We only know for sure once the set is built: then, the request looks for a
+/// set again, and builds its own if the new one doesn't support it.
+fn same_target(pending: &TargetCircUsage, usage: &TargetCircUsage) -> bool {
+    match (pending, usage) {
+        (
+            TargetCircUsage::Exit {
+                ports: pending_ports,
+                isolation: pending_isolation,
+                require_stability: pending_stability,
+                ..
+            },
+            TargetCircUsage::Exit {
+                ports,
+                isolation,
+                require_stability,
+                ..
+            },
+        ) => {
+            (*pending_stability || !*require_stability)
+                && ports.iter().all(|port| pending_ports.contains(port))
+                && pending_isolation.compatible_same_type(isolation)
+        }
+        _ => false,
+    }
+}
+
+/// Return true if the round-trip times measured from the SENDMEs of the legs
+/// of `tunnel` are still within `max_difference` of each other.
+///
+/// The legs can drift apart after we link them, as the load on their relays
+/// changes.  A tunnel that has closed is never in step.
+async fn legs_in_step<C: AbstractCirc>(tunnel: &C, max_difference: Duration) -> bool {
+    match tunnel.leg_rtts().await {
+        Ok(rtts) => slowest_outlier(rtts.into_iter().flatten(), max_difference).is_none(),
+        Err(_) => false,
+    }
+}
+
+/// Estimate the round-trip time over a circuit of `n_hops` relays, given that
+/// it took `build_time` to build.
+///
+/// Building a circuit takes a round trip to each of its hops in turn.  If
+/// every hop adds about the same delay, the round trip to hop `k` takes `k/n`
+/// of the round trip over the whole circuit, and so the build takes about
+/// `(n+1)/2` of those.
+fn estimate_rtt(build_time: Duration, n_hops: usize) -> Duration {
+    let n_hops = u32::try_from(n_hops).unwrap_or(u32::MAX).max(1);
+    (build_time * 2) / n_hops.saturating_add(1)
+}
+
+/// If the round-trip times in `rtts` differ by more than `max_difference`,
+/// return the index of the slowest.
+fn slowest_outlier(
+    rtts: impl IntoIterator<Item = Duration>,
+    max_difference: Duration,
+) -> Option<usize> {
+    let rtts: Vec<_> = rtts.into_iter().collect();
+    let fastest = rtts.iter().min()?;
+    let (slowest_idx, slowest) = rtts.iter().enumerate().max_by_key(|(_, rtt)| **rtt)?;
+    (slowest.saturating_sub(*fastest) > max_difference).then_some(slowest_idx)
+}
+
+/// Add the relays between the guard and the exit of `circ` to the relays
+/// that `rules` exclude.
+fn exclude_middles<C: AbstractCirc>(rules: &mut RelayRules, circ: &C) {
+    let path = circ.path_ref();
+    let hops = path.hops();
+    let middles = hops.get(1..hops.len().saturating_sub(1)).unwrap_or(&[]);
+    for relay in middles.iter().filter_map(|hop| hop.as_chan_target()) {
+        for id in relay.identities() {
+            rules.exclude_relay(id.to_owned());
+        }
+    }
+}
+
+impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
+    /// Return a conflux set for `usage`, linking a new one if necessary.
+    ///
+    /// Return `None` if our [`ConfluxConfig`] says not to use a conflux set
+    /// for `usage`, or if we couldn't build one: in that case, the caller
+    /// should use a single circuit instead.
+    ///
+    /// We don't use conflux sets for requests with their own [`RelayRules`],
+    /// or their own exit country.
+    pub(crate) async fn get_or_launch_conflux(
+        &self,
+        dir: DirInfo<'_>,
+        usage: &TargetCircUsage,
+    ) -> Option<Arc<B::Circ>> {
+        let TargetCircUsage::Exit {
+            country_code: None,
+            relay_rules: None,
+            require_stability,
+            ..
+        } = usage
+        else {
+            return None;
+        };
+        let DirInfo::Directory(netdir) = dir else {
+            return None;
+        };
+        let config = self.conflux_config.get();
+        // Streams to long-lived ports are the ones that need stable circuits.
+        let interactive = *require_stability;
+        if !config.multipath.wants_multipath(interactive) {
+            return None;
+        }
+
+        let now = self.mgr.peek_runtime().now();
+        let (existing, pending) = {
+            let mut sets = self.conflux_sets.lock().expect("poisoned lock");
+            let existing = sets.find(usage, now);
+            let pending = existing
+                .is_none()
+                .then(|| sets.find_pending(usage))
+                .flatten();
+            (existing, pending)
+        };
+        let existing = match pending {
+            Some(done) => {
+                // The sender is dropped (never used) once the build is over.
+                let _: std::result::Result<(), oneshot::Canceled> = done.await;
+                let now = self.mgr.peek_runtime().now();
+                self.conflux_sets
+                    .lock()
+                    .expect("poisoned lock")
+                    .find(usage, now)
+            }
+            None => existing,
+        };
+        if let Some(tunnel) = existing {
+            if legs_in_step(&*tunnel, config.max_rtt_difference).await {
+                return Some(tunnel);
+            }
+            debug!("Retiring a conflux set whose legs' round-trip times have drifted apart");
+            self.conflux_sets
+                .lock()
+                .expect("poisoned lock")
+                .retire(&tunnel);
+        }
+
+        let (id, done) = self
+            .conflux_sets
+            .lock()
+            .expect("poisoned lock")
+            .add_pending(usage);
+        let _guard = PendingGuard {
+            sets: &self.conflux_sets,
+            id,
+            _done: done,
+        };
+        match self.launch_conflux_set(netdir, usage, &config).await {
+            Ok((spec, tunnel)) => {
+                let expires = now + self.mgr.circuit_timing().max_dirtiness;
+                self.conflux_sets.lock().expect("poisoned lock").add(
+                    Arc::clone(&tunnel),
+                    spec,
+                    expires,
+                );
+                Some(tunnel)
+            }
+            Err(e) => {
+                debug_report!(e, "Unable to build a conflux set; using a single circuit");
+                None
+            }
+        }
+    }
+
+    /// Build the legs of a new conflux set for `usage`, and link them.
+    ///
+    /// Return the first leg, through which we use the set, and what the set
+    /// can be used for.
+    async fn launch_conflux_set(
+        &self,
+        netdir: &NetDir,
+        usage: &TargetCircUsage,
+        config: &ConfluxConfig,
+    ) -> Result<(SupportedCircUsage, Arc<B::Circ>)> {
+        let TargetCircUsage::Exit {
+            ports,
+            isolation,
+            require_stability,
+            ..
+        } = usage
+        else {
+            return Err(internal!("Tried to build a conflux set for a non-exit usage").into());
+        };
+        let leg_usage = |rules: &RelayRules| TargetCircUsage::Exit {
+            ports: ports.clone(),
+            isolation: isolation.clone(),
+            country_code: None,
+            relay_rules: Some(Arc::new(rules.clone())),
+            require_stability: *require_stability,
+        };
+
+        // The first leg picks the exit, and the length of the others.
+        let mut rules = RelayRules::new();
+        rules.require_conflux_exit();
+        let (spec, first) = self.launch_conflux_leg(netdir, &leg_usage(&rules)).await?;
+        {
+            let path = first.circ.path_ref();
+            let exit = path
+                .hops()
+                .last()
+                .and_then(|hop| hop.as_chan_target())
+                .ok_or_else(|| internal!("Conflux leg has no exit relay"))?;
+            for id in exit.identities() {
+                rules.allow_exit(id.to_owned());
+            }
+            let n_hops = u8::try_from(path.n_hops())
+                .map_err(|_| internal!("Conflux leg has too many hops"))?;
+            rules.hops(n_hops);
+        }
+        exclude_middles(&mut rules, &*first.circ);
+
+        let mut legs = vec![first];
+        let mut n_replaced = 0;
+        while legs.len() < usize::from(config.legs) {
+            let (_, leg) = self.launch_conflux_leg(netdir, &leg_usage(&rules)).await?;
+            exclude_middles(&mut rules, &*leg.circ);
+            legs.push(leg);
+            for leg in &mut legs {
+                leg.update_rtt().await;
+            }
+
+            if let Some(slowest) =
+                slowest_outlier(legs.iter().map(|leg| leg.rtt), config.max_rtt_difference)
+            {
+                if n_replaced == MAX_LEG_REPLACEMENTS {
+                    return Err(Error::ConfluxRttMismatch);
+                }
+                n_replaced += 1;
+                debug!(
+                    "Replacing a conflux leg with an estimated round-trip time of {:?}",
+                    legs[slowest].rtt
+                );
+                // Dropping the leg closes it, since nothing else refers to it.
+                legs.remove(slowest);
+            }
+        }
+
+        let mut circs = legs.into_iter().map(|leg| leg.circ);
+        let tunnel = circs
+            .next()
+            .ok_or_else(|| internal!("Conflux set has no legs"))?;
+        tunnel
+            .link_circuits(circs.collect())
+            .await
+            .map_err(|error| Error::Protocol {
+                action: "linking conflux legs",
+                peer: None, // Any of the relays could be to blame.
+                unique_id: Some(tunnel.unique_id()),
+                error,
+            })?;
+        debug!("Linked a conflux set with {} legs", config.legs);
+
+        Ok((spec, tunnel))
+    }
+
+    /// Build a single leg of a conflux set for `usage`, and estimate its
+    /// round-trip time.
+    async fn launch_conflux_leg(
+        &self,
+        netdir: &NetDir,
+        usage: &TargetCircUsage,
+    ) -> Result<(SupportedCircUsage, Leg<B::Circ>)> {
+        let runtime = self.mgr.peek_runtime();
+        let start = runtime.now();
+        let (spec, circ) = self.mgr.launch_unmanaged(usage, netdir.into()).await?;
+        let build_time = runtime.now().saturating_duration_since(start);
+        let rtt = estimate_rtt(build_time, circ.n_hops());
+        Ok((spec, Leg { circ, rtt }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::isolation::{IsolationToken, StreamIsolationBuilder};
+    use crate::mocks::FakeCirc;
+    use crate::TargetPort;
+    use futures::FutureExt as _;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn rtt_estimate() {
+        // A 3-hop circuit takes about two round trips over the whole
+        // circuit to build.
+        assert_eq!(estimate_rtt(ms(600), 3), ms(300));
+        assert_eq!(estimate_rtt(ms(900), 5), ms(300));
+        assert_eq!(estimate_rtt(ms(600), 1), ms(600));
+        assert_eq!(estimate_rtt(ms(600), 0), ms(600));
+    }
+
+    fn exit_usage(ports: &[u16], isolation: &StreamIsolation, stable: bool) -> TargetCircUsage {
+        TargetCircUsage::Exit {
+            ports: ports.iter().map(|p| TargetPort::ipv4(*p)).collect(),
+            isolation: isolation.clone(),
+            country_code: None,
+            relay_rules: None,
+            require_stability: stable,
+        }
+    }
+
+    #[test]
+    fn pending_sets() {
+        let isolation = |token| {
+            StreamIsolationBuilder::new()
+                .owner_token(token)
+                .build()
+                .unwrap()
+        };
+        let iso1 = isolation(IsolationToken::new());
+        let iso2 = isolation(IsolationToken::new());
+        let sets: Mutex<ConfluxSets<FakeCirc>> = Mutex::new(ConfluxSets::new());
+        let find_pending = |usage| sets.lock().unwrap().find_pending(&usage);
+
+        let web = exit_usage(&[80, 443], &iso1, true);
+        let (id, done) = sets.lock().unwrap().add_pending(&web);
+        let guard = PendingGuard {
+            sets: &sets,
+            id,
+            _done: done,
+        };
+
+        // Requests that the set will probably support wait for it.
+        let waiter = find_pending(exit_usage(&[443], &iso1, false)).unwrap();
+        assert!(find_pending(exit_usage(&[80, 443], &iso1, true)).is_some());
+        // Other requests don't.
+        assert!(find_pending(exit_usage(&[22], &iso1, false)).is_none());
+        assert!(find_pending(exit_usage(&[443], &iso2, false)).is_none());
+        assert!(find_pending(TargetCircUsage::Dir).is_none());
+        assert!(waiter.clone().now_or_never().is_none());
+
+        // Once the build is over, the waiters wake up, and later requests
+        // don't wait any more.
+        drop(guard);
+        assert!(waiter.now_or_never().is_some());
+        assert!(find_pending(web).is_none());
+
+        let interactive = exit_usage(&[22], &iso1, false);
+        let (id, done) = sets.lock().unwrap().add_pending(&interactive);
+        let _guard = PendingGuard {
+            sets: &sets,
+            id,
+            _done: done,
+        };
+        // A set without stable relays won't do for a request that needs them.
+        assert!(find_pending(exit_usage(&[22], &iso1, true)).is_none());
+        assert!(find_pending(exit_usage(&[22], &iso1, false)).is_some());
+    }
+
+    #[test]
+    fn outliers() {
+        let max = ms(100);
+        assert_eq!(slowest_outlier(vec![], max), None);
+        assert_eq!(slowest_outlier(vec![ms(300)], max), None);
+        assert_eq!(slowest_outlier(vec![ms(300), ms(400)], max), None);
+        assert_eq!(slowest_outlier(vec![ms(300), ms(401)], max), Some(1));
+        assert_eq!(slowest_outlier(vec![ms(900), ms(300)], max), Some(0));
+        assert_eq!(
+            slowest_outlier(vec![ms(300), ms(350), ms(500), ms(320)], max),
+            Some(2)
+        );
+    }
+}
@@ -95,6 +95,12 @@ pub enum Error {
     #[error("Unable to create vanguard manager")]
     VanguardMgrInit(#[from] tor_guardmgr::vanguards::VanguardMgrError),
 
+    /// We built circuits for a conflux set, but couldn't find enough of them
+    /// with similar round-trip times.
+    #[cfg(feature = "conflux")]
+    #[error("Couldn't build conflux legs with similar round-trip times")]
+    ConfluxRttMismatch,
+
     /// Unable to get or build a circuit, despite retrying.
     #[error("{0}")]
     RequestFailed(RetryError<Box<Error>>),
@@ -192,6 +198,8 @@ impl HasKind for Error {
             E::Guard(e) => e.kind(),
             #[cfg(all(feature = "vanguards", feature = "hs-common"))]
             E::VanguardMgrInit(e) => e.kind(),
+            #[cfg(feature = "conflux")]
+            E::ConfluxRttMismatch => EK::TransientFailure,
             E::Spawn { cause, .. } => cause.kind(),
         }
     }
@@ -230,6 +238,11 @@ impl HasRetryTime for Error {
                 RT::AfterWaiting
             }
 
+            // Circuits with different relays may have more similar
+            // round-trip times.
+            #[cfg(feature = "conflux")]
+            E::ConfluxRttMismatch => RT::AfterWaiting,
+
             // For Channel errors, we can mostly delegate the retry_time decision to
             // the inner error.
             //
@@ -302,6 +315,8 @@ impl Error {
             E::CircCanceled => 20,
             E::CircTimeout(_) => 30,
             E::RequestTimeout => 30,
+            #[cfg(feature = "conflux")]
+            E::ConfluxRttMismatch => 30,
             E::NoRelay { .. } => 40,
             E::GuardMgr(_) => 40,
             E::Guard(_) => 40,
@@ -341,6 +356,8 @@ impl Error {
             Error::LostUsabilityRace(_) => true,
             #[cfg(all(feature = "vanguards", feature = "hs-common"))]
             Error::VanguardMgrInit(_) => false,
+            #[cfg(feature = "conflux")]
+            Error::ConfluxRttMismatch => false,
             Error::PendingCanceled
             | Error::PendingFailed(_)
             | Error::UsageMismatched(_)
@@ -0,0 +1,145 @@
+//! Code to notify other crates when we build circuits, or fail to.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use educe::Educe;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use tor_async_utils::mpsc_channel_no_memquota;
+use tor_basic_utils::skip_fmt;
+use tor_proto::circuit::{Path, UniqId};
+
+use crate::Error;
+
+/// How many events we queue for each [`CircEvents`] before we start dropping
+/// them.
+const EVENT_QUEUE_LEN: usize = 64;
+
+/// Something that happened to a circuit that a [`CircMgr`](crate::CircMgr)
+/// tried to build.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum CircEvent {
+    /// We built a circuit.
+    Built {
+        /// The circuit's unique identifier.
+        id: UniqId,
+        /// The relays that the circuit goes through.
+        path: Arc<Path>,
+    },
+    /// We tried to build a circuit, and failed.
+    Failed {
+        /// Why we failed.
+        error: Error,
+    },
+}
+
+/// A stream of [`CircEvent`]s.
+///
+/// Returned by [`CircMgr::circ_events`](crate::CircMgr::circ_events).
+///
+/// Note that this stream can be lossy: if you don't read from it quickly
+/// enough, some events will be dropped.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct CircEvents {
+    /// The receiver that we're wrapping.
+    #[educe(Debug(method = "skip_fmt"))]
+    inner: mpsc::Receiver<CircEvent>,
+}
+
+impl Stream for CircEvents {
+    type Item = CircEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// An object that sends [`CircEvent`]s to every [`CircEvents`] made from it.
+#[derive(Default)]
+pub(crate) struct CircEventSender {
+    /// A sender for each [`CircEvents`] that hasn't been dropped.
+    subscribers: Mutex<Vec<mpsc::Sender<CircEvent>>>,
+}
+
+impl CircEventSender {
+    /// Return a new stream of the events that we send from now on.
+    pub(crate) fn subscribe(&self) -> CircEvents {
+        let (tx, rx) = mpsc_channel_no_memquota(EVENT_QUEUE_LEN);
+        self.subscribers.lock().expect("Poisoned lock").push(tx);
+        CircEvents { inner: rx }
+    }
+
+    /// Send the event made by `event` to every subscriber.
+    ///
+    /// Doesn't call `event` if there are no subscribers.  If a subscriber's
+    /// queue is full, it doesn't get this event.
+    pub(crate) fn send(&self, event: impl FnOnce() -> CircEvent) {
+        let mut subscribers = self.subscribers.lock().expect("Poisoned lock");
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = event();
+        subscribers.retain_mut(|tx| match tx.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(e) => !e.is_disconnected(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use futures::FutureExt as _;
+
+    #[test]
+    fn send() {
+        let sender = CircEventSender::default();
+        let failed = || CircEvent::Failed {
+            error: Error::CircCanceled,
+        };
+        // Nobody is listening yet.
+        sender.send(|| panic!("made an event with no subscribers"));
+
+        let mut events1 = sender.subscribe();
+        let events2 = sender.subscribe();
+        drop(events2);
+        sender.send(failed);
+        assert_eq!(sender.subscribers.lock().unwrap().len(), 1);
+        assert!(matches!(
+            events1.next().now_or_never(),
+            Some(Some(CircEvent::Failed {
+                error: Error::CircCanceled
+            }))
+        ));
+        assert!(events1.next().now_or_never().is_none());
+
+        // A subscriber that doesn't keep up misses events, but isn't dropped.
+        for _ in 0..EVENT_QUEUE_LEN * 2 {
+            sender.send(failed);
+        }
+        assert_eq!(sender.subscribers.lock().unwrap().len(), 1);
+        let mut n = 0;
+        while let Some(Some(_)) = events1.next().now_or_never() {
+            n += 1;
+        }
+        assert!(n >= EVENT_QUEUE_LEN);
+        assert!(n < EVENT_QUEUE_LEN * 2);
+    }
+}
@@ -17,7 +17,7 @@ use crate::{
 };
 use futures::{task::SpawnExt, StreamExt, TryFutureExt};
 use once_cell::sync::OnceCell;
-use tor_error::{bad_api_usage, internal};
+use tor_error::{bad_api_usage, internal, into_internal};
 use tor_error::{debug_report, Bug};
 use tor_guardmgr::VanguardMode;
 use tor_linkspec::{
@@ -65,6 +65,14 @@ pub enum HsCircKind {
 }
 
 impl HsCircKind {
+    /// Return true if this kind of circuit is only used by onion services.
+    fn is_service(&self) -> bool {
+        matches!(
+            self,
+            HsCircKind::SvcHsDir | HsCircKind::SvcIntro | HsCircKind::SvcRend
+        )
+    }
+
     /// Return the [`HsCircStemKind`] needed to build this type of circuit.
     fn stem_kind(&self) -> HsCircStemKind {
         match self {
@@ -301,6 +309,10 @@ pub(crate) struct HsCircPoolInner<B: AbstractCircBuilder<R> + 'static, R: Runtim
 struct Inner<C: AbstractCirc> {
     /// A collection of pre-constructed circuits.
     pool: pool::Pool<C>,
+    /// The vanguard mode that the circuits in `pool` were built for.
+    ///
+    /// When the mode changes, we empty the pool.
+    vanguard_mode: VanguardMode,
 }
 
 impl<R: Runtime> HsCircPoolInner<CircuitBuilder<R>, R> {
@@ -318,7 +330,10 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> HsCircPoolInner<B, R> {
         Self {
             circmgr,
             launcher_handle: OnceCell::new(),
-            inner: Mutex::new(Inner { pool }),
+            inner: Mutex::new(Inner {
+                pool,
+                vanguard_mode: VanguardMode::default(),
+            }),
         }
     }
 
@@ -413,6 +428,10 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> HsCircPoolInner<B, R> {
             return Err(bad_api_usage!("get_or_launch_specific with ClientRend circuit!?").into());
         }
 
+        if kind.is_service() {
+            self.note_onion_service();
+        }
+
         let wanted_kind = kind.stem_kind();
 
         // For most* of these circuit types, we want to build our circuit with
@@ -539,6 +558,17 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> HsCircPoolInner<B, R> {
         let found_usable_circ = {
             let mut inner = self.inner.lock().expect("lock poisoned");
 
+            // If the vanguard mode has changed (because we were reconfigured,
+            // or because the consensus now asks for a different mode),
+            // the circuits in the pool have the wrong shape.
+            if inner.vanguard_mode != vanguard_mode {
+                inner.vanguard_mode = vanguard_mode;
+                inner
+                    .pool
+                    .retire_all_circuits()
+                    .map_err(into_internal!("unable to empty onion circuit pool"))?;
+            }
+
             let restrictions = |circ: &HsCircStem<B::Circ>| {
                 // If vanguards are enabled, we no longer apply same-family or same-subnet
                 // restrictions, and we allow the guard to appear as either of the last
@@ -795,6 +825,24 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> HsCircPoolInner<B, R> {
         }
     }
 
+    /// Note that we are running an onion service, so that the vanguard manager
+    /// can choose the vanguard mode for onion services.
+    fn note_onion_service(&self) {
+        #[cfg(all(feature = "vanguards", feature = "hs-common"))]
+        if self
+            .circmgr
+            .mgr
+            .peek_builder()
+            .vanguardmgr()
+            .note_onion_service()
+        {
+            debug!(
+                "Running an onion service: now using {} vanguards",
+                self.vanguard_mode()
+            );
+        }
+    }
+
     /// Internal implementation for [`HsCircPool::estimate_timeout`].
     pub(crate) fn estimate_timeout(
         &self,
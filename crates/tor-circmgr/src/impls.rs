@@ -52,6 +52,19 @@ impl mgr::AbstractCirc for tor_proto::circuit::ClientCirc {
     ) -> tor_proto::Result<()> {
         self.extend_ntor(target, params).await
     }
+
+    #[cfg(feature = "conflux")]
+    async fn link_circuits(&self, others: Vec<Arc<Self>>) -> tor_proto::Result<()>
+    where
+        Self: Send + Sync,
+    {
+        self.link_circuits(others).await
+    }
+
+    #[cfg(feature = "conflux")]
+    async fn leg_rtts(&self) -> tor_proto::Result<Vec<Option<std::time::Duration>>> {
+        self.leg_rtts().await
+    }
 }
 
 /// The information generated by circuit planning, and used to build a
@@ -96,6 +109,7 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder<R> for crate::build::CircuitBui
             #[cfg(all(feature = "vanguards", feature = "hs-common"))]
             self.vanguardmgr(),
             self.path_config().as_ref(),
+            self.path_policy().as_ref(),
             self.runtime().wallclock(),
         )?;
 
@@ -181,6 +195,10 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder<R> for crate::build::CircuitBui
         self.launch_parallelism(spec)
     }
 
+    fn plans_overlap(&self, a: &Plan, b: &Plan) -> bool {
+        a.path.overlaps(&b.path)
+    }
+
     fn learning_timeouts(&self) -> bool {
         CircuitBuilder::learning_timeouts(self)
     }
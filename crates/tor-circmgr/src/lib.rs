@@ -52,16 +52,16 @@ use tor_basic_utils::retry::RetryDelay;
 use tor_chanmgr::ChanMgr;
 use tor_error::{error_report, warn_report};
 use tor_guardmgr::RetireCircuits;
-use tor_linkspec::ChanTarget;
+use tor_linkspec::{ChanTarget, CircTarget, HasRelayIds as _};
 use tor_netdir::{DirEvent, NetDir, NetDirProvider, Timeliness};
 use tor_proto::circuit::{CircParameters, ClientCirc, UniqId};
-use tor_rtcompat::Runtime;
+use tor_rtcompat::{Runtime, SleepProviderExt};
 
 #[cfg(any(feature = "specific-relay", feature = "hs-common"))]
 use tor_linkspec::IntoOwnedChanTarget;
 
 use futures::task::SpawnExt;
-use futures::StreamExt;
+use futures::{StreamExt, TryFutureExt};
 use std::sync::{Arc, Mutex, Weak};
 use std::time::{Duration, Instant};
 use tracing::{debug, info, trace, warn};
@@ -71,7 +71,10 @@ pub use config::test_config::TestConfig;
 
 pub mod build;
 mod config;
+#[cfg(feature = "conflux")]
+mod conflux;
 mod err;
+mod events;
 #[cfg(feature = "hs-common")]
 pub mod hspool;
 mod impls;
@@ -80,19 +83,25 @@ mod mgr;
 #[cfg(test)]
 mod mocks;
 pub(crate) mod path;
+mod path_policy;
 mod preemptive;
+mod relay_rules;
 pub mod timeouts;
 mod usage;
 
 pub use err::Error;
+pub use events::{CircEvent, CircEvents};
 pub use isolation::IsolationToken;
+pub use path_policy::{PathPolicy, PathPosition};
+pub use relay_rules::RelayRules;
 use tor_guardmgr::fallback::FallbackList;
-pub use tor_guardmgr::{ClockSkewEvents, GuardMgrConfig, SkewEstimate};
+pub use tor_guardmgr::{AddrFamilyPolicy, ClockSkewEvents, GuardMgrConfig, SkewEstimate};
 pub use usage::{TargetPort, TargetPorts};
 
 pub use config::{
-    CircMgrConfig, CircuitTiming, CircuitTimingBuilder, PathConfig, PathConfigBuilder,
-    PreemptiveCircuitConfig, PreemptiveCircuitConfigBuilder,
+    CircMgrConfig, CircuitTiming, CircuitTimingBuilder, ConfluxConfig, ConfluxConfigBuilder,
+    MultipathUse, PathConfig, PathConfigBuilder, PreemptiveCircuitConfig,
+    PreemptiveCircuitConfigBuilder,
 };
 
 use crate::isolation::StreamIsolation;
@@ -119,8 +128,22 @@ pub type Result<T> = std::result::Result<T, Error>;
 type TimeoutStateHandle = tor_persist::DynStorageHandle<timeouts::pareto::ParetoTimeoutState>;
 
 /// Key used to load timeout state information.
+///
+/// The state is stored in a versioned record; older versions of Arti, which
+/// stored it bare, will start learning timeouts afresh.
 const PARETO_TIMEOUT_DATA_KEY: &str = "circuit_timeouts";
 
+/// Return a handle for loading and storing our timeout state in `storage`.
+fn timeout_state_handle<SM>(storage: SM) -> TimeoutStateHandle
+where
+    SM: tor_persist::StateMgr + Send + Sync + 'static,
+{
+    storage.create_versioned_handle(
+        PARETO_TIMEOUT_DATA_KEY,
+        timeouts::pareto::ParetoTimeoutState::schema(),
+    )
+}
+
 /// Represents what we know about the Tor network.
 ///
 /// This can either be a complete directory, or a list of fallbacks.
@@ -230,6 +253,43 @@ impl<R: Runtime> CircMgr<R> {
                 isolation,
                 #[cfg(feature = "geoip")]
                 country_code,
+                None,
+            )
+            .await
+    }
+
+    /// Note that the client expects to make connections to `port` soon, so
+    /// that we should have circuits ready for it.
+    ///
+    /// This works as if the client had just connected to `port`: we keep
+    /// predicting it for the configured `prediction_lifetime`, and build
+    /// circuits for it preemptively, starting right away.
+    pub fn note_upcoming_use(&self, port: TargetPort) {
+        self.0.note_upcoming_use(port);
+    }
+
+    /// Return a circuit suitable for exiting to all of the provided
+    /// `ports`, and obeying `rules`, launching it if necessary.
+    ///
+    /// This is like [`get_or_launch_exit`](CircMgr::get_or_launch_exit),
+    /// but the circuit also obeys `rules`, in addition to our [`PathConfig`].
+    pub async fn get_or_launch_exit_with_rules(
+        &self,
+        netdir: DirInfo<'_>, // TODO: This has to be a NetDir.
+        ports: &[TargetPort],
+        isolation: StreamIsolation,
+        #[cfg(feature = "geoip")] country_code: Option<CountryCode>,
+        rules: &RelayRules,
+    ) -> Result<Arc<ClientCirc>> {
+        let rules = (!rules.is_empty()).then(|| Arc::new(rules.clone()));
+        self.0
+            .get_or_launch_exit(
+                netdir,
+                ports,
+                isolation,
+                #[cfg(feature = "geoip")]
+                country_code,
+                rules,
             )
             .await
     }
@@ -280,6 +340,75 @@ impl<R: Runtime> CircMgr<R> {
         self.0.retire_circ(circ_id);
     }
 
+    /// Mark every circuit that we have launched so far as unsuitable for
+    /// any future requests.
+    ///
+    /// Circuits with streams attached stay open until those streams close;
+    /// the others close once nothing else refers to them.  Future requests
+    /// will need new circuits, so don't call this haphazardly.
+    pub fn retire_all_circuits(&self) {
+        self.0.retire_all_circuits();
+    }
+
+    /// Mark every circuit that has been used by a stream with an isolation
+    /// compatible with `isolation` as unsuitable for any future requests.
+    ///
+    /// This is like [`retire_all_circuits`](CircMgr::retire_all_circuits),
+    /// but only for a single isolation group: later streams in that group
+    /// get new circuits, and other groups keep theirs.  Circuits that have
+    /// not yet been used by any stream are kept.
+    pub fn retire_isolated_circuits(&self, isolation: &StreamIsolation) {
+        self.0.retire_isolated_circuits(isolation);
+    }
+
+    /// Use `policy` to veto or re-weight the relays on the paths of the
+    /// circuits that we build from now on.
+    ///
+    /// With `None`, go back to choosing relays in the usual way.
+    ///
+    /// Circuits that we have already built are still used: to stop using
+    /// them, call [`retire_all_circuits`](CircMgr::retire_all_circuits).
+    ///
+    /// See [`PathPolicy`] for the circuits that the policy applies to.
+    pub fn set_path_policy(&self, policy: Option<Arc<dyn PathPolicy>>) {
+        self.0.set_path_policy(policy);
+    }
+
+    /// Save our circuit build timeouts and guard state to the state manager
+    /// now, instead of waiting for the next periodic save.
+    ///
+    /// Return true if we saved something; false if we don't have the lock on
+    /// the state manager.
+    pub fn store_persistent_state(&self) -> Result<bool> {
+        self.0.store_persistent_state()
+    }
+
+    /// Build a new anonymous circuit for the caller's exclusive use, extended
+    /// to `target` if one is given.
+    ///
+    /// Without a `target`, the circuit ends at some exit relay.  With one,
+    /// the circuit goes through three relays other than `target`, and then
+    /// to `target` itself.
+    ///
+    /// This circuit is not registered with this `CircMgr`: it will never be
+    /// given out for any other request, and it closes when the last reference
+    /// to it is dropped.  This makes it suitable for sending custom relay
+    /// messages, with [`ClientCirc::start_conversation`] and
+    /// [`ClientCirc::send_raw_msg`].
+    ///
+    /// Only makes a single attempt; the caller needs to loop if they want to
+    /// retry.
+    pub async fn launch_unmanaged<T>(
+        &self,
+        netdir: &NetDir,
+        target: Option<T>,
+    ) -> Result<Arc<ClientCirc>>
+    where
+        T: CircTarget + Sync,
+    {
+        self.0.launch_unmanaged(netdir, target).await
+    }
+
     /// Record that a failure occurred on a circuit with a given guard, in a way
     /// that makes us unwilling to use that guard for future circuits.
     ///
@@ -312,6 +441,15 @@ impl<R: Runtime> CircMgr<R> {
         self.0.skew_events()
     }
 
+    /// Return a stream of events about the circuits that we build, and the
+    /// ones that we fail to build.
+    ///
+    /// Note that this stream can be lossy: if you don't read from it quickly
+    /// enough, some events will be dropped.
+    pub fn circ_events(&self) -> CircEvents {
+        self.0.circ_events()
+    }
+
     /// Try to change our configuration settings to `new_config`.
     ///
     /// The actual behavior here will depend on the value of `how`.
@@ -369,6 +507,15 @@ pub(crate) struct CircMgrInner<B: AbstractCircBuilder<R> + 'static, R: Runtime>
     mgr: Arc<mgr::AbstractCircMgr<B, R>>,
     /// A preemptive circuit predictor, for, uh, building circuits preemptively.
     predictor: Arc<Mutex<PreemptiveCircuitPredictor>>,
+    /// A handle to the task that builds circuits preemptively, once it has
+    /// been launched, so that we can wake it up when we learn of a new port.
+    preemptive_task: Arc<Mutex<Option<TaskHandle>>>,
+    /// Configuration for the conflux sets that we build.
+    #[cfg(feature = "conflux")]
+    conflux_config: Arc<tor_config::MutCfg<ConfluxConfig>>,
+    /// The conflux sets that we have linked, and may give out again.
+    #[cfg(feature = "conflux")]
+    conflux_sets: Arc<Mutex<conflux::ConfluxSets<B::Circ>>>,
 }
 
 impl<R: Runtime> CircMgrInner<CircuitBuilder<R>, R> {
@@ -390,10 +537,9 @@ impl<R: Runtime> CircMgrInner<CircuitBuilder<R>, R> {
     {
         #[cfg(all(feature = "vanguards", feature = "hs-common"))]
         let vanguardmgr = {
-            // TODO(#1382): we need a way of checking if this arti instance
-            // is running an onion service or not.
-            //
-            // Perhaps this information should be provided by CircMgrConfig.
+            // We don't know yet whether this arti instance will run an onion
+            // service: the HsCircPool tells the VanguardMgr once it builds its
+            // first onion service circuit.
             let has_onion_svc = false;
             VanguardMgr::new(
                 config.vanguard_config(),
@@ -403,7 +549,7 @@ impl<R: Runtime> CircMgrInner<CircuitBuilder<R>, R> {
             )?
         };
 
-        let storage_handle = storage.create_handle(PARETO_TIMEOUT_DATA_KEY);
+        let storage_handle = timeout_state_handle(storage);
 
         let builder = build::CircuitBuilder::new(
             runtime.clone(),
@@ -417,6 +563,11 @@ impl<R: Runtime> CircMgrInner<CircuitBuilder<R>, R> {
 
         Ok(Self::new_generic(config, runtime, guardmgr, builder))
     }
+
+    /// Use `policy` for the paths of the circuits that we build from now on.
+    pub(crate) fn set_path_policy(&self, policy: Option<Arc<dyn PathPolicy>>) {
+        self.mgr.peek_builder().set_path_policy(policy);
+    }
 }
 
 impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
@@ -439,6 +590,11 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
         CircMgrInner {
             mgr: Arc::new(mgr),
             predictor: preemptive,
+            preemptive_task: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "conflux")]
+            conflux_config: Arc::new(config.conflux().clone().into()),
+            #[cfg(feature = "conflux")]
+            conflux_sets: Arc::new(Mutex::new(conflux::ConfluxSets::new())),
         }
     }
 
@@ -490,6 +646,10 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
             .map_err(|e| Error::from_spawn("timeout-probe circuit launcher", e))?;
 
         let (sched, handle) = TaskSchedule::new(runtime.clone());
+        *self
+            .preemptive_task
+            .lock()
+            .expect("preemptive task lock poisoned") = Some(handle.clone());
         ret.push(handle);
 
         runtime
@@ -538,6 +698,7 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
         // TODO GEOIP: this cannot be stabilised like this, since Cargo features need to be
         //             additive. The function should be refactored to be builder-like.
         #[cfg(feature = "geoip")] country_code: Option<CountryCode>,
+        relay_rules: Option<Arc<RelayRules>>,
     ) -> Result<Arc<B::Circ>> {
         self.expire_circuits();
         let time = Instant::now();
@@ -565,8 +726,13 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
             ports,
             isolation,
             country_code,
+            relay_rules,
             require_stability,
         };
+        #[cfg(feature = "conflux")]
+        if let Some(tunnel) = self.get_or_launch_conflux(netdir, &usage).await {
+            return Ok(tunnel);
+        }
         self.mgr.get_or_launch(&usage, netdir).await.map(|(c, _)| c)
     }
 
@@ -622,9 +788,13 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
             .vanguardmgr()
             .reconfigure(new_config.vanguard_config())?;
 
-        let new_reachable = &new_config.path_rules().reachable_addrs;
-        if new_reachable != &old_path_rules.reachable_addrs {
-            let filter = new_config.path_rules().build_guard_filter();
+        let new_path_rules = new_config.path_rules();
+        if new_path_rules.reachable_addrs != old_path_rules.reachable_addrs
+            || new_path_rules.address_family != old_path_rules.address_family
+            || new_path_rules.exclude_relays != old_path_rules.exclude_relays
+            || new_path_rules.exclude_addrs != old_path_rules.exclude_addrs
+        {
+            let filter = new_path_rules.build_guard_filter();
             self.mgr.peek_builder().guardmgr().set_filter(filter);
         }
 
@@ -643,6 +813,8 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
         self.mgr
             .set_circuit_timing(new_config.circuit_timing().clone());
         predictor.set_config(new_config.preemptive_circuits().clone());
+        #[cfg(feature = "conflux")]
+        self.conflux_config.replace(new_config.conflux().clone());
 
         if discard_all_circuits {
             // TODO(nickm): Someday, we might want to take a more lenient approach, and only
@@ -839,6 +1011,22 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
         Ok(())
     }
 
+    /// Note that the client expects to make connections to `port` soon, and
+    /// build circuits for it preemptively.
+    pub(crate) fn note_upcoming_use(&self, port: TargetPort) {
+        self.predictor
+            .lock()
+            .expect("preemptive lock poisoned")
+            .note_usage(Some(port), Instant::now());
+        if let Some(task) = &*self
+            .preemptive_task
+            .lock()
+            .expect("preemptive task lock poisoned")
+        {
+            task.fire();
+        }
+    }
+
     /// Run indefinitely, launching circuits where the preemptive circuit
     /// predictor thinks it'd be a good idea to have them.
     ///
@@ -975,6 +1163,61 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
         Ok(client_circ)
     }
 
+    /// Internal implementation for [`CircMgr::launch_unmanaged`].
+    pub(crate) async fn launch_unmanaged<T>(
+        &self,
+        netdir: &NetDir,
+        target: Option<T>,
+    ) -> Result<Arc<B::Circ>>
+    where
+        T: CircTarget + Sync,
+    {
+        // The circuit must not use the target before it gets there.
+        let mut rules = RelayRules::new();
+        for id in target.iter().flat_map(|t| t.identities()) {
+            rules.exclude_relay(id.to_owned());
+        }
+        let usage = TargetCircUsage::Exit {
+            ports: vec![],
+            isolation: StreamIsolation::no_isolation(),
+            country_code: None,
+            relay_rules: (!rules.is_empty()).then(|| Arc::new(rules)),
+            require_stability: false,
+        };
+        let (_, circ) = self.mgr.launch_unmanaged(&usage, netdir.into()).await?;
+        let Some(target) = target else {
+            return Ok(circ);
+        };
+
+        // Estimate how long it will take to extend it one more hop, and
+        // construct a timeout as appropriate.
+        let n_hops = circ.n_hops();
+        let (extend_timeout, _) =
+            self.mgr
+                .peek_builder()
+                .estimator()
+                .timeouts(&timeouts::Action::ExtendCircuit {
+                    initial_length: n_hops,
+                    final_length: n_hops + 1,
+                });
+        let params = build::exit_circparams_from_netparams(netdir.params())?;
+        let extend_future = circ
+            .extend_ntor(&target, params)
+            .map_err(|error| Error::Protocol {
+                action: "extending to chosen hop",
+                peer: None, // Either party could be to blame.
+                unique_id: Some(circ.unique_id()),
+                error,
+            });
+        self.mgr
+            .peek_runtime()
+            .timeout(extend_timeout, extend_future)
+            .await
+            .map_err(|_| Error::CircTimeout(Some(circ.unique_id())))??;
+
+        Ok(circ)
+    }
+
     /// Return true if `netdir` has enough information to be used for this
     /// circuit manager.
     ///
@@ -1027,11 +1270,24 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
     /// any future requests.  This won't close existing circuits that have
     /// streams attached to them, but it will prevent any future streams from
     /// being attached.
-    ///
-    /// TODO: we may want to expose this eventually.  If we do, we should
-    /// be very clear that you don't want to use it haphazardly.
     pub(crate) fn retire_all_circuits(&self) {
         self.mgr.retire_all_circuits();
+        #[cfg(feature = "conflux")]
+        self.conflux_sets
+            .lock()
+            .expect("poisoned lock")
+            .retire_all();
+    }
+
+    /// Mark every circuit in the isolation group of `isolation` as
+    /// unsuitable for any future requests.
+    pub(crate) fn retire_isolated_circuits(&self, isolation: &StreamIsolation) {
+        self.mgr.retire_isolated_circuits(isolation);
+        #[cfg(feature = "conflux")]
+        self.conflux_sets
+            .lock()
+            .expect("poisoned lock")
+            .retire_isolated(isolation);
     }
 
     /// If `circ_id` is the unique identifier for a circuit that we're
@@ -1051,6 +1307,12 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> CircMgrInner<B, R> {
         self.mgr.peek_builder().guardmgr().skew_events()
     }
 
+    /// Return a stream of events about the circuits that we build, and the
+    /// ones that we fail to build.
+    pub(crate) fn circ_events(&self) -> CircEvents {
+        self.mgr.circ_events()
+    }
+
     /// Record that a failure occurred on a circuit with a given guard, in a way
     /// that makes us unwilling to use that guard for future circuits.
     ///
@@ -1106,7 +1368,7 @@ mod test {
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use mocks::FakeBuilder;
     use tor_guardmgr::GuardMgr;
-    use tor_linkspec::OwnedChanTarget;
+    use tor_linkspec::{HasRelayIds, OwnedChanTarget, OwnedCircTarget, RelayIds};
     use tor_netdir::testprovider::TestNetDirProvider;
     use tor_persist::TestingStateMgr;
 
@@ -1198,4 +1460,54 @@ mod test {
             ret_rx.await.unwrap().unwrap();
         });
     }
+
+    #[test]
+    fn test_launch_unmanaged() {
+        tor_rtmock::MockRuntime::test_with_various(|runtime| async move {
+            let circmgr = make_circmgr(runtime.clone());
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap_if_sufficient()
+                .unwrap();
+
+            let (ret_tx, ret_rx) = tor_async_utils::oneshot::channel();
+            let circmgr2 = circmgr.clone();
+            runtime.spawn_identified("launch_unmanaged", async move {
+                let target = netdir.relays().next().unwrap();
+                let target = OwnedCircTarget::from_circ_target(&target);
+                let target_ids = RelayIds::from_relay_ids(&target);
+                let untargeted = circmgr2
+                    .launch_unmanaged::<OwnedCircTarget>(&netdir, None)
+                    .await;
+                let targeted = circmgr2.launch_unmanaged(&netdir, Some(target)).await;
+                ret_tx.send((target_ids, untargeted, targeted)).unwrap();
+            });
+            runtime.advance_by(Duration::from_millis(120)).await;
+            let (target, untargeted, targeted) = ret_rx.await.unwrap();
+
+            // Without a target, we don't extend the circuit at all.
+            let untargeted = untargeted.unwrap();
+            assert!(untargeted.extended_to.lock().unwrap().is_empty());
+
+            // With a target, we extend the circuit to it, once.
+            let targeted = targeted.unwrap();
+            assert_eq!(*targeted.extended_to.lock().unwrap(), vec![target.clone()]);
+
+            // The circuit that we extended had to avoid the target; the
+            // other one had no rules at all.
+            let planned = circmgr.mgr.peek_builder().planned.lock().unwrap();
+            let rules: Vec<_> = planned
+                .iter()
+                .filter_map(|usage| match usage {
+                    TargetCircUsage::Exit {
+                        ports, relay_rules, ..
+                    } if ports.is_empty() => Some(relay_rules.clone()),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(rules.len(), 2);
+            assert!(rules[0].is_none());
+            let excluded = rules[1].as_ref().unwrap().excluded();
+            assert!(target.identities().all(|id| excluded.contains(id)));
+        });
+    }
 }
@@ -22,6 +22,8 @@
 //    - Error reported by restrict_mut?
 
 use crate::config::CircuitTiming;
+use crate::events::{CircEvent, CircEventSender, CircEvents};
+use crate::isolation::{IsolationHelper, StreamIsolation};
 use crate::usage::{SupportedCircUsage, TargetCircUsage};
 use crate::{timeouts, DirInfo, Error, PathConfig, Result};
 
@@ -120,6 +122,19 @@ pub(crate) trait AbstractCirc: Debug {
         target: &T,
         params: CircParameters,
     ) -> tor_proto::Result<()>;
+
+    /// Link this circuit with `others` into a single conflux tunnel.
+    ///
+    /// All the circuits must end at the same exit relay.
+    #[cfg(feature = "conflux")]
+    async fn link_circuits(&self, others: Vec<Arc<Self>>) -> tor_proto::Result<()>
+    where
+        Self: Send + Sync;
+
+    /// Return the round-trip time of each leg of this tunnel, as measured from
+    /// the SENDMEs sent by its last hop, or `None` for a leg without an estimate.
+    #[cfg(feature = "conflux")]
+    async fn leg_rtts(&self) -> tor_proto::Result<Vec<Option<Duration>>>;
 }
 
 /// A plan for an `AbstractCircBuilder` that can maybe be mutated by tests.
@@ -223,6 +238,19 @@ pub(crate) trait AbstractCircBuilder<R: Runtime>: Send + Sync {
         1
     }
 
+    /// Return true if the circuits planned in `a` and `b` would share a
+    /// relay that they don't have to share.
+    ///
+    /// When we launch several circuits at once for a single request, we try
+    /// to plan them so that this is false for every pair, so that one slow
+    /// relay can't hold them all up.
+    ///
+    /// The default implementation returns false.
+    fn plans_overlap(&self, a: &Self::Plan, b: &Self::Plan) -> bool {
+        let _ = (a, b); // default implementation ignores these.
+        false
+    }
+
     /// Return true if we are currently attempting to learn circuit
     /// timeouts by building testing circuits.
     fn learning_timeouts(&self) -> bool;
@@ -318,6 +346,8 @@ pub(crate) struct OpenEntry<C> {
     /// which does not actually close them until there are no more
     /// references to them.)
     expiration: ExpirationInfo,
+    /// How many stream requests this circuit has been given out for.
+    n_streams: u32,
 }
 
 impl<C: AbstractCirc> OpenEntry<C> {
@@ -327,6 +357,7 @@ impl<C: AbstractCirc> OpenEntry<C> {
             spec,
             circ,
             expiration,
+            n_streams: 0,
         }
     }
 
@@ -342,9 +373,18 @@ impl<C: AbstractCirc> OpenEntry<C> {
     fn restrict_mut(&mut self, usage: &TargetCircUsage, now: Instant) -> Result<()> {
         self.spec.restrict_mut(usage)?;
         self.expiration.mark_dirty(now);
+        if matches!(usage, TargetCircUsage::Exit { .. }) {
+            self.n_streams = self.n_streams.saturating_add(1);
+        }
         Ok(())
     }
 
+    /// Return true if this circuit has been given out for as many streams
+    /// as `max_streams` allows.
+    fn streams_exhausted(&self, max_streams: Option<u32>) -> bool {
+        max_streams.is_some_and(|max| self.n_streams >= max)
+    }
+
     /// Find the "best" entry from a slice of OpenEntry for supporting
     /// a given `usage`.
     ///
@@ -602,6 +642,22 @@ impl<B: AbstractCircBuilder<R>, R: Runtime> CircList<B, R> {
         }
     }
 
+    /// Remove every open or pending circuit that has been assigned to an
+    /// isolation group compatible with `isolation`.
+    fn retire_isolated_circs(&mut self, isolation: &StreamIsolation) {
+        let in_group = |spec: &SupportedCircUsage| {
+            spec.isolation()
+                .is_some_and(|i| i.compatible_same_type(isolation))
+        };
+        self.open_circs.retain(|_k, v| !in_group(&v.spec));
+        // As in clear_all_circuits, the tasks building these circuits will
+        // find out that they were cancelled.
+        self.pending_circs.retain(|p| {
+            let assignment = p.tentative_assignment.lock().expect("poisoned lock");
+            !in_group(&assignment)
+        });
+    }
+
     /// Add `pending` to the set of in-progress circuits.
     fn add_pending_circ(&mut self, pending: Arc<PendingEntry<B, R>>) {
         self.pending_circs.insert(pending);
@@ -732,6 +788,9 @@ pub(crate) struct AbstractCircMgr<B: AbstractCircBuilder<R>, R: Runtime> {
     ///
     /// Derived from the network parameters.
     unused_timing: sync::Mutex<UnusedTimings>,
+
+    /// Object to tell our observers when we build circuits, or fail to.
+    events: CircEventSender,
 }
 
 /// An action to take in order to satisfy a request for a circuit.
@@ -758,9 +817,27 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
             circs,
             circuit_timing: circuit_timing.into(),
             unused_timing: sync::Mutex::new(unused_timing),
+            events: CircEventSender::default(),
         }
     }
 
+    /// Return a stream of events telling when we build circuits, or fail to.
+    pub(crate) fn circ_events(&self) -> CircEvents {
+        self.events.subscribe()
+    }
+
+    /// Tell our observers about the `outcome` of an attempt to build a
+    /// circuit.
+    fn note_build_outcome(&self, outcome: &Result<(SupportedCircUsage, Arc<B::Circ>)>) {
+        self.events.send(|| match outcome {
+            Ok((_, circ)) => CircEvent::Built {
+                id: circ.unique_id(),
+                path: circ.path_ref(),
+            },
+            Err(e) => CircEvent::Failed { error: e.clone() },
+        });
+    }
+
     /// Reconfigure this manager using the latest set of network parameters.
     pub(crate) fn update_network_parameters(&self, p: &tor_netdir::params::NetParameters) {
         let mut u = self
@@ -810,10 +887,7 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
         // We compute the maximum number of failures by dividing the maximum
         // number of circuits to attempt by the number that will be launched in
         // parallel for each iteration.
-        let max_failures = usize::div_ceil(
-            max_tries as usize,
-            std::cmp::max(1, self.builder.launch_parallelism(usage)),
-        );
+        let max_failures = usize::div_ceil(max_tries as usize, self.launch_parallelism(usage));
 
         let mut retry_schedule = RetryDelay::from_msec(100);
         let mut retry_err = RetryError::<Box<Error>>::in_attempt_to("find or build a circuit");
@@ -941,7 +1015,7 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
     ) -> Result<Action<B, R>> {
         let mut list = self.circs.lock().expect("poisoned lock");
 
-        if let Some(mut open) = list.find_open(usage) {
+        let best_open = if let Some(mut open) = list.find_open(usage) {
             // We have open circuits that meet the spec: return the best one.
             let parallelism = self.builder.select_parallelism(usage);
             let best = OpenEntry::find_best(&mut open, usage, parallelism);
@@ -952,7 +1026,17 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
             // TODO: If we have fewer circuits here than our select
             // parallelism, perhaps we should launch more?
 
-            return Ok(Action::Open(best.circ.clone()));
+            let exhausted = best.streams_exhausted(self.circuit_timing().max_streams);
+            Some((best.circ.clone(), exhausted))
+        } else {
+            None
+        };
+        if let Some((circ, exhausted)) = best_open {
+            if exhausted {
+                // That was the last stream that this circuit may have.
+                list.take_open(&circ.id());
+            }
+            return Ok(Action::Open(circ));
         }
 
         if let Some(pending) = list.find_pending_circs(usage) {
@@ -976,11 +1060,11 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
         }
 
         // Okay, we need to launch circuits here.
-        let parallelism = std::cmp::max(1, self.builder.launch_parallelism(usage));
+        let parallelism = self.launch_parallelism(usage);
         let mut plans = Vec::new();
         let mut last_err = None;
         for _ in 0..parallelism {
-            match self.plan_by_usage(dir, usage) {
+            match self.plan_disjoint(dir, usage, &plans) {
                 Ok((pending, plan)) => {
                     list.add_pending_circ(pending);
                     plans.push(plan);
@@ -1120,7 +1204,11 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
                                         now + self.circuit_timing().max_dirtiness,
                                     );
                                 }
-                                return Ok((ent.circ.clone(), CircProvenance::NewlyCreated));
+                                let circ = ent.circ.clone();
+                                if ent.streams_exhausted(self.circuit_timing().max_streams) {
+                                    list.take_open(id);
+                                }
+                                return Ok((circ, CircProvenance::NewlyCreated));
                             }
                             Err(e) => {
                                 // In this case, a `UsageMismatched` error just means that we lost the race
@@ -1208,6 +1296,52 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
         Ok((pending, plan))
     }
 
+    /// As [`plan_by_usage`](Self::plan_by_usage), but try to plan a circuit
+    /// that doesn't share relays with any of `others`.
+    ///
+    /// If we can't, we settle for a circuit that does.
+    fn plan_disjoint(
+        &self,
+        dir: DirInfo<'_>,
+        usage: &TargetCircUsage,
+        others: &[CircBuildPlan<B, R>],
+    ) -> Result<(Arc<PendingEntry<B, R>>, CircBuildPlan<B, R>)> {
+        /// How many times we plan a circuit again, at most, because its path
+        /// overlaps with that of another.
+        const MAX_REPLANS: usize = 4;
+
+        let overlaps = |plan: &CircBuildPlan<B, R>| {
+            others
+                .iter()
+                .any(|other| self.builder.plans_overlap(&other.plan, &plan.plan))
+        };
+
+        let mut planned = self.plan_by_usage(dir, usage)?;
+        for _ in 0..MAX_REPLANS {
+            if !overlaps(&planned.1) {
+                break;
+            }
+            match self.plan_by_usage(dir, usage) {
+                Ok(replanned) => planned = replanned,
+                Err(e) => {
+                    debug!("Unable to plan a disjoint circuit for {:?}: {}", usage, e);
+                    break;
+                }
+            }
+        }
+        Ok(planned)
+    }
+
+    /// Return the number of circuits to launch at once for `usage`.
+    fn launch_parallelism(&self, usage: &TargetCircUsage) -> usize {
+        let configured = match usage {
+            TargetCircUsage::Exit { .. } => self.circuit_timing().build_parallelism,
+            _ => 1,
+        };
+        let builder = self.builder.launch_parallelism(usage);
+        std::cmp::max(1, std::cmp::max(builder, configured))
+    }
+
     /// Launch a managed circuit for a target usage, without checking
     /// whether one already exists or is pending.
     ///
@@ -1309,6 +1443,7 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
         pending: Arc<PendingEntry<B, R>>,
     ) -> (Option<SupportedCircUsage>, PendResult<B, R>) {
         let outcome = self.builder.build_circuit(plan).await;
+        self.note_build_outcome(&outcome);
 
         match outcome {
             Err(e) => (None, Err(e)),
@@ -1370,7 +1505,9 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
         dir: DirInfo<'_>,
     ) -> Result<(SupportedCircUsage, Arc<B::Circ>)> {
         let (_, plan) = self.plan_by_usage(dir, usage)?;
-        self.builder.build_circuit(plan.plan).await
+        let outcome = self.builder.build_circuit(plan.plan).await;
+        self.note_build_outcome(&outcome);
+        outcome
     }
 
     /// Remove the circuit with a given `id` from this manager.
@@ -1402,6 +1539,14 @@ impl<B: AbstractCircBuilder<R> + 'static, R: Runtime> AbstractCircMgr<B, R> {
         list.clear_all_circuits();
     }
 
+    /// Stop giving out any circuit that has been assigned to an isolation
+    /// group compatible with `isolation`, so that later requests in that
+    /// group get new circuits.
+    pub(crate) fn retire_isolated_circuits(&self, isolation: &StreamIsolation) {
+        let mut list = self.circs.lock().expect("poisoned lock");
+        list.retire_isolated_circs(isolation);
+    }
+
     /// Expire circuits according to the rules in `config` and the
     /// current time `now`.
     ///
@@ -1531,7 +1676,7 @@ mod test {
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
     use crate::isolation::test::{assert_isoleq, IsolationTokenEq};
-    use crate::mocks::{FakeBuilder, FakeCirc, FakeId, FakeOp};
+    use crate::mocks::{FakeBuilder, FakeCirc, FakeOp};
     use crate::usage::{ExitPolicy, SupportedCircUsage};
     use crate::{Error, IsolationToken, StreamIsolation, TargetCircUsage, TargetPort, TargetPorts};
     use once_cell::sync::Lazy;
@@ -1558,11 +1703,13 @@ mod test {
                 ports,
                 isolation,
                 country_code,
+                relay_rules,
                 require_stability,
             } => SupportedCircUsage::Exit {
                 policy: ExitPolicy::from_target_ports(&TargetPorts::from(&ports[..])),
                 isolation: Some(isolation.clone()),
                 country_code: country_code.clone(),
+                relay_rules: relay_rules.clone(),
                 all_relays_stable: *require_stability,
             },
             _ => unimplemented!(),
@@ -1632,6 +1779,7 @@ mod test {
                 ports: vec![TargetPort::ipv4(53)],
                 isolation: StreamIsolation::builder().build().unwrap(),
                 country_code: None,
+                relay_rules: None,
                 require_stability: false,
             };
 
@@ -1674,6 +1822,114 @@ mod test {
         });
     }
 
+    #[test]
+    fn race_circuits() {
+        MockRuntime::test_with_various(|rt| async move {
+            use crate::config::CircuitTimingBuilder;
+            #[allow(deprecated)] // TODO #1885
+            let rt = MockSleepRuntime::new(rt);
+
+            let ports = TargetCircUsage::new_from_ipv4_ports(&[80, 443]);
+
+            // We race two circuits: one fails, and the other succeeds.
+            let builder = make_builder(&rt);
+            builder.set(&ports, vec![FakeOp::Fail, FakeOp::Succeed]);
+
+            let circuit_timing = CircuitTimingBuilder::default()
+                .build_parallelism(2)
+                .build()
+                .unwrap();
+            let mgr = Arc::new(AbstractCircMgr::new(builder, rt.clone(), circuit_timing));
+
+            let c1 = rt.wait_for(mgr.get_or_launch(&ports, di())).await;
+            assert!(c1.is_ok());
+            assert!(mgr.peek_builder().script.lock().unwrap().is_empty());
+            assert_eq!(mgr.n_circs(), 1);
+
+            // This time, both succeed: we use one, and keep the other.
+            let ports = TargetCircUsage::new_from_ipv4_ports(&[22]);
+            let c2 = rt.wait_for(mgr.get_or_launch(&ports, di())).await;
+            assert!(c2.is_ok());
+            assert_eq!(mgr.n_circs() + mgr.n_pending_circs(), 3);
+        });
+    }
+
+    #[test]
+    fn max_streams() {
+        MockRuntime::test_with_various(|rt| async move {
+            use crate::config::CircuitTimingBuilder;
+            #[allow(deprecated)] // TODO #1885
+            let rt = MockSleepRuntime::new(rt);
+            let builder = make_builder(&rt);
+            let circuit_timing = CircuitTimingBuilder::default()
+                .max_streams(2)
+                .build()
+                .unwrap();
+            let mgr = Arc::new(AbstractCircMgr::new(builder, rt.clone(), circuit_timing));
+
+            let ports = TargetCircUsage::new_from_ipv4_ports(&[443]);
+            let c1 = rt.wait_for(mgr.get_or_launch(&ports, di())).await;
+            let c1 = c1.unwrap().0;
+            let c2 = rt.wait_for(mgr.get_or_launch(&ports, di())).await;
+            let c2 = c2.unwrap().0;
+            assert!(FakeCirc::eq(&c1, &c2));
+
+            // That circuit has had both its streams, so we don't give it out
+            // again.
+            assert_eq!(mgr.n_circs(), 0);
+            let c3 = rt.wait_for(mgr.get_or_launch(&ports, di())).await;
+            let c3 = c3.unwrap().0;
+            assert!(!FakeCirc::eq(&c1, &c3));
+        });
+    }
+
+    #[test]
+    fn retire_isolated() {
+        MockRuntime::test_with_various(|rt| async move {
+            #[allow(deprecated)] // TODO #1885
+            let rt = MockSleepRuntime::new(rt);
+            let builder = make_builder(&rt);
+            let mgr = Arc::new(AbstractCircMgr::new(
+                builder,
+                rt.clone(),
+                CircuitTiming::default(),
+            ));
+
+            let isolation = |token| {
+                StreamIsolation::builder()
+                    .owner_token(token)
+                    .build()
+                    .unwrap()
+            };
+            let usage = |token| TargetCircUsage::Exit {
+                ports: vec![TargetPort::ipv4(443)],
+                isolation: isolation(token),
+                country_code: None,
+                relay_rules: None,
+                require_stability: false,
+            };
+            let tok1 = IsolationToken::new();
+            let tok2 = IsolationToken::new();
+
+            let c1 = rt.wait_for(mgr.get_or_launch(&usage(tok1), di())).await;
+            let c1 = c1.unwrap().0;
+            let c2 = rt.wait_for(mgr.get_or_launch(&usage(tok2), di())).await;
+            let c2 = c2.unwrap().0;
+            assert!(!FakeCirc::eq(&c1, &c2));
+
+            // Only the circuit for the first group is retired.
+            mgr.retire_isolated_circuits(&isolation(tok1));
+            assert_eq!(mgr.n_circs(), 1);
+
+            let c1b = rt.wait_for(mgr.get_or_launch(&usage(tok1), di())).await;
+            let c1b = c1b.unwrap().0;
+            assert!(!FakeCirc::eq(&c1, &c1b));
+            let c2b = rt.wait_for(mgr.get_or_launch(&usage(tok2), di())).await;
+            let c2b = c2b.unwrap().0;
+            assert!(FakeCirc::eq(&c2, &c2b));
+        });
+    }
+
     #[test]
     fn request_timeout() {
         MockRuntime::test_with_various(|rt| async move {
@@ -1864,6 +2120,7 @@ mod test {
                     .build()
                     .unwrap(),
                 country_code: None,
+                relay_rules: None,
                 require_stability: false,
             };
             let iso2 = TargetCircUsage::Exit {
@@ -1873,6 +2130,7 @@ mod test {
                     .build()
                     .unwrap(),
                 country_code: None,
+                relay_rules: None,
                 require_stability: false,
             };
             let no_iso1 = TargetCircUsage::new_from_ipv4_ports(&[443]);
@@ -1927,6 +2185,63 @@ mod test {
         });
     }
 
+    #[test]
+    fn reuse_prepared_circuit() {
+        MockRuntime::test_with_various(|rt| async move {
+            #[allow(deprecated)] // TODO #1885
+            let rt = MockSleepRuntime::new(rt);
+            let builder = make_builder(&rt);
+            let mgr = Arc::new(AbstractCircMgr::new(
+                builder,
+                rt.clone(),
+                CircuitTiming::default(),
+            ));
+
+            // A request for port 443 from a client (as made by arti-client's
+            // `TorClient::preconnect`), with its own StreamIsolation each time,
+            // as every request from that client gets.
+            let client = IsolationToken::new();
+            let usage = |owner, stream: Option<IsolationToken>| {
+                let mut isolation = StreamIsolation::builder();
+                isolation.owner_token(owner);
+                if let Some(stream) = stream {
+                    isolation.stream_isolation(Box::new(stream));
+                }
+                TargetCircUsage::Exit {
+                    ports: vec![TargetPort::ipv4(443)],
+                    isolation: isolation.build().unwrap(),
+                    country_code: None,
+                    relay_rules: None,
+                    require_stability: false,
+                }
+            };
+
+            let prepared = rt
+                .wait_for(mgr.get_or_launch(&usage(client, None), di()))
+                .await;
+            let prepared = prepared.unwrap().0;
+            assert_eq!(mgr.n_circs(), 1);
+
+            // A later stream with matching isolation gets the prepared circuit,
+            // without building another.
+            let later = mgr.get_or_launch(&usage(client, None), di()).await;
+            assert!(FakeCirc::eq(&prepared, &later.unwrap().0));
+            assert_eq!(mgr.n_circs(), 1);
+
+            // A stream from an isolated client, or one that is isolated from
+            // every other stream, gets a circuit of its own.
+            let isolated_client = rt
+                .wait_for(mgr.get_or_launch(&usage(IsolationToken::new(), None), di()))
+                .await;
+            assert!(!FakeCirc::eq(&prepared, &isolated_client.unwrap().0));
+            let isolated_stream = rt
+                .wait_for(mgr.get_or_launch(&usage(client, Some(IsolationToken::new())), di()))
+                .await;
+            assert!(!FakeCirc::eq(&prepared, &isolated_stream.unwrap().0));
+            assert_eq!(mgr.n_circs(), 3);
+        });
+    }
+
     #[test]
     fn opportunistic() {
         MockRuntime::test_with_various(|rt| async move {
@@ -2097,7 +2412,7 @@ mod test {
     #[test]
     fn test_find_supported() {
         let (ep_none, ep_web, ep_full) = get_exit_policies();
-        let fake_circ = Arc::new(FakeCirc { id: FakeId::next() });
+        let fake_circ = Arc::new(FakeCirc::new());
         let expiration = ExpirationInfo::Unused {
             use_before: Instant::now() + Duration::from_secs(60 * 60),
         };
@@ -2107,6 +2422,7 @@ mod test {
                 policy: ep_none,
                 isolation: None,
                 country_code: None,
+                relay_rules: None,
                 all_relays_stable: true,
             },
             fake_circ.clone(),
@@ -2118,6 +2434,7 @@ mod test {
                 policy: ep_web,
                 isolation: None,
                 country_code: None,
+                relay_rules: None,
                 all_relays_stable: true,
             },
             fake_circ.clone(),
@@ -2129,6 +2446,7 @@ mod test {
                 policy: ep_full,
                 isolation: None,
                 country_code: None,
+                relay_rules: None,
                 all_relays_stable: true,
             },
             fake_circ,
@@ -1,10 +1,11 @@
+use crate::timeouts::readonly::ReadonlyTimeoutEstimator;
 use crate::usage::{SupportedCircUsage, TargetCircUsage};
 use crate::{timeouts, DirInfo, Error, PathConfig, Result};
 
 #[cfg(feature = "vanguards")]
 use tor_guardmgr::vanguards::VanguardMgr;
 use tor_guardmgr::{GuardMgr, TestConfig, VanguardConfig};
-use tor_linkspec::CircTarget;
+use tor_linkspec::{CircTarget, RelayIds};
 use tor_persist::StateMgr;
 use tor_proto::circuit::{CircParameters, Path, UniqId};
 use tor_rtcompat::Runtime;
@@ -34,11 +35,33 @@ impl FakeId {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Eq)]
+/// The number of hops in every [`FakeCirc`] that we build.
+const FAKE_CIRC_HOPS: usize = 3;
+
+#[derive(Debug, Clone)]
 pub(crate) struct FakeCirc {
     pub(crate) id: FakeId,
+    /// The relays to which this circuit has been extended, in order.
+    pub(crate) extended_to: Arc<sync::Mutex<Vec<RelayIds>>>,
+}
+
+impl FakeCirc {
+    pub(crate) fn new() -> Self {
+        FakeCirc {
+            id: FakeId::next(),
+            extended_to: Default::default(),
+        }
+    }
 }
 
+impl PartialEq for FakeCirc {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for FakeCirc {}
+
 #[async_trait]
 impl AbstractCirc for FakeCirc {
     type Id = FakeId;
@@ -54,7 +77,7 @@ impl AbstractCirc for FakeCirc {
     }
 
     fn n_hops(&self) -> usize {
-        todo!()
+        FAKE_CIRC_HOPS + self.extended_to.lock().expect("poisoned lock").len()
     }
 
     fn is_closing(&self) -> bool {
@@ -67,9 +90,26 @@ impl AbstractCirc for FakeCirc {
 
     async fn extend_ntor<T: CircTarget + std::marker::Sync>(
         &self,
-        _target: &T,
+        target: &T,
         _params: CircParameters,
     ) -> tor_proto::Result<()> {
+        self.extended_to
+            .lock()
+            .expect("poisoned lock")
+            .push(RelayIds::from_relay_ids(target));
+        Ok(())
+    }
+
+    #[cfg(feature = "conflux")]
+    async fn link_circuits(&self, _others: Vec<Arc<Self>>) -> tor_proto::Result<()>
+    where
+        Self: Send + Sync,
+    {
+        todo!()
+    }
+
+    #[cfg(feature = "conflux")]
+    async fn leg_rtts(&self) -> tor_proto::Result<Vec<Option<Duration>>> {
         todo!()
     }
 }
@@ -86,6 +126,9 @@ pub(crate) struct FakeBuilder<RT: Runtime> {
     #[cfg(feature = "vanguards")]
     vanguardmgr: Arc<VanguardMgr<RT>>,
     pub(crate) script: sync::Mutex<Vec<(TargetCircUsage, FakeOp)>>,
+    /// Every usage for which we have been asked to plan a circuit, in order.
+    pub(crate) planned: sync::Mutex<Vec<TargetCircUsage>>,
+    estimator: timeouts::Estimator,
 }
 
 #[derive(Debug, Clone)]
@@ -119,6 +162,10 @@ impl<RT: Runtime> AbstractCircBuilder<RT> for FakeBuilder<RT> {
         spec: &TargetCircUsage,
         _dir: DirInfo<'_>,
     ) -> Result<(FakePlan, SupportedCircUsage)> {
+        self.planned
+            .lock()
+            .expect("Couldn't get lock on planned")
+            .push(spec.clone());
         let next_op = self.next_op(spec);
         if matches!(next_op, FakeOp::NoPlan) {
             return Err(Error::NoRelay {
@@ -132,6 +179,7 @@ impl<RT: Runtime> AbstractCircBuilder<RT> for FakeBuilder<RT> {
                 ports,
                 isolation,
                 country_code,
+                relay_rules,
                 require_stability,
             } => SupportedCircUsage::Exit {
                 policy: ExitPolicy::from_target_ports(&TargetPorts::from(&ports[..])),
@@ -141,6 +189,7 @@ impl<RT: Runtime> AbstractCircBuilder<RT> for FakeBuilder<RT> {
                     Some(isolation.clone())
                 },
                 country_code: *country_code,
+                relay_rules: relay_rules.clone(),
                 all_relays_stable: *require_stability,
             },
             #[cfg(feature = "hs-common")]
@@ -160,8 +209,8 @@ impl<RT: Runtime> AbstractCircBuilder<RT> for FakeBuilder<RT> {
         self.runtime.allow_one_advance(FAKE_CIRC_DELAY);
         sl.await;
         match op {
-            FakeOp::Succeed => Ok((plan.spec, Arc::new(FakeCirc { id: FakeId::next() }))),
-            FakeOp::WrongSpec(s) => Ok((s, Arc::new(FakeCirc { id: FakeId::next() }))),
+            FakeOp::Succeed => Ok((plan.spec, Arc::new(FakeCirc::new()))),
+            FakeOp::WrongSpec(s) => Ok((s, Arc::new(FakeCirc::new()))),
             FakeOp::Fail => Err(Error::CircTimeout(None)),
             FakeOp::Delay(d) => {
                 let sl = self.runtime.sleep(d);
@@ -198,7 +247,7 @@ impl<RT: Runtime> AbstractCircBuilder<RT> for FakeBuilder<RT> {
     }
 
     fn estimator(&self) -> &timeouts::Estimator {
-        todo!()
+        &self.estimator
     }
 
     #[cfg(feature = "vanguards")]
@@ -238,6 +287,8 @@ impl<RT: Runtime> FakeBuilder<RT> {
                     .expect("Create VanguardMgr"),
             ),
             script: sync::Mutex::new(vec![]),
+            planned: sync::Mutex::new(vec![]),
+            estimator: timeouts::Estimator::new(ReadonlyTimeoutEstimator::new()),
         }
     }
 
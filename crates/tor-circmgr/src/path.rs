@@ -34,6 +34,8 @@ use tor_rtcompat::Runtime;
 #[cfg(all(feature = "vanguards", feature = "hs-common"))]
 use tor_guardmgr::vanguards::Vanguard;
 
+use crate::config::{DEFAULT_HOPS, MAX_HOPS, MIN_HOPS};
+use crate::path_policy::{self, PathPolicy, PathPosition};
 use crate::usage::ExitPolicy;
 use crate::{DirInfo, Error, PathConfig, Result};
 
@@ -264,6 +266,21 @@ impl OwnedPath {
             OwnedPath::Normal(p) => p.len(),
         }
     }
+
+    /// Return true if any relay after the first hop of this path is also on
+    /// `other`, after its first hop.
+    ///
+    /// (We ignore the first hops, since circuits built at the same time
+    /// will usually share a guard on purpose.)
+    pub(crate) fn overlaps(&self, other: &OwnedPath) -> bool {
+        match (self, other) {
+            (OwnedPath::Normal(a), OwnedPath::Normal(b)) => a
+                .iter()
+                .skip(1)
+                .any(|r1| b.iter().skip(1).any(|r2| r1.same_relay_ids(r2))),
+            _ => false,
+        }
+    }
 }
 
 /// A path builder that builds multi-hop, anonymous paths.
@@ -271,6 +288,23 @@ trait AnonymousPathBuilder {
     /// Return the "target" that every chosen relay must be able to share a circuit with with.
     fn compatible_with(&self) -> Option<&OwnedChanTarget>;
 
+    /// Return the relays that this path must not use, beyond those that the
+    /// [`PathConfig`] excludes.
+    fn excluded_relays(&self) -> Option<&RelayIdSet> {
+        None
+    }
+
+    /// Return the number of relays to put on this path, including the guard
+    /// and the exit.
+    fn n_hops(&self) -> u8 {
+        DEFAULT_HOPS
+    }
+
+    /// Return the [`PathPolicy`] that this path must obey, if any.
+    fn path_policy(&self) -> Option<&dyn PathPolicy> {
+        None
+    }
+
     /// Return a short description of the path we're trying to build,
     /// for error reporting purposes.
     fn path_kind(&self) -> &'static str;
@@ -307,10 +341,32 @@ fn pick_path<'a, B: AnonymousPathBuilder, R: Rng, RT: Runtime>(
             .into())
         }
     };
+    let n_hops = builder.n_hops();
+    if !(MIN_HOPS..=MAX_HOPS).contains(&n_hops) {
+        return Err(bad_api_usage!(
+            "Tried to build a {}-hop path; the length must be between {} and {}",
+            n_hops,
+            MIN_HOPS,
+            MAX_HOPS
+        )
+        .into());
+    }
     let rs_cfg = config.relay_selection_config();
     let family_rules = FamilyRules::from(netdir.params());
+    let (unwanted, mut unwanted_guards) =
+        unwanted_relays(config, builder.excluded_relays(), netdir, family_rules);
+    if let Some(policy) = builder.path_policy() {
+        // The guard manager chooses our guards, so all we can do is veto them.
+        for relay in netdir.relays() {
+            if relay.low_level_details().is_suitable_as_guard()
+                && !policy.permits(&relay, PathPosition::Guard)
+            {
+                unwanted_guards.extend(relay.identities().map(|id| id.to_owned()));
+            }
+        }
+    }
 
-    let target_exclusion = match builder.compatible_with() {
+    let mut target_exclusion = match builder.compatible_with() {
         Some(ct) => {
             // Exclude the target from appearing in other positions in the path.
             let ids = RelayIdSet::from_iter(ct.identities().map(|id_ref| id_ref.to_owned()));
@@ -323,10 +379,13 @@ fn pick_path<'a, B: AnonymousPathBuilder, R: Rng, RT: Runtime>(
         }
         None => RelayExclusion::no_relays_excluded(),
     };
+    // Every relay that we must never use is, in effect, another target to avoid.
+    target_exclusion.extend(&unwanted);
 
     // TODO-SPEC: Because of limitations in guard selection, we have to
     // pick the guard before the exit, which is not what our spec says.
-    let (guard, mon, usable) = select_guard(netdir, guards, builder.compatible_with())?;
+    let (guard, mon, usable) =
+        select_guard(netdir, guards, builder.compatible_with(), unwanted_guards)?;
 
     let guard_exclusion = match &guard {
         MaybeOwnedRelay::Relay(r) => RelayExclusion::exclude_relays_in_same_family(
@@ -345,25 +404,33 @@ fn pick_path<'a, B: AnonymousPathBuilder, R: Rng, RT: Runtime>(
     exclusion.extend(&target_exclusion);
     let (exit, middle_usage) = builder.pick_exit(rng, netdir, exclusion, &rs_cfg)?;
 
-    let mut family_exclusion =
-        RelayExclusion::exclude_relays_in_same_family(&rs_cfg, vec![exit.clone()], family_rules);
-    family_exclusion.extend(&guard_exclusion);
-    let mut exclusion = family_exclusion;
-    exclusion.extend(&target_exclusion);
-
-    let selector = RelaySelector::new(middle_usage, exclusion);
-    let (middle, info) = selector.select_relay(rng, netdir);
-    let middle = middle.ok_or_else(|| Error::NoRelay {
-        path_kind: builder.path_kind(),
-        role: "middle relay",
-        problem: info.to_string(),
-    })?;
-
-    let hops = vec![
-        guard,
-        MaybeOwnedRelay::from(middle),
-        MaybeOwnedRelay::from(exit),
-    ];
+    // Every middle relay must avoid the families of the exit, the guard, and
+    // the middle relays that we have already chosen.
+    let mut chosen = vec![exit.clone()];
+    let mut hops = vec![guard];
+    for _ in 2..n_hops {
+        let mut exclusion =
+            RelayExclusion::exclude_relays_in_same_family(&rs_cfg, chosen.clone(), family_rules);
+        exclusion.extend(&guard_exclusion);
+        exclusion.extend(&target_exclusion);
+
+        let selector = RelaySelector::new(middle_usage.clone(), exclusion);
+        let (middle, info) = path_policy::select_relay(
+            &selector,
+            rng,
+            netdir,
+            builder.path_policy(),
+            PathPosition::Middle,
+        );
+        let middle = middle.ok_or_else(|| Error::NoRelay {
+            path_kind: builder.path_kind(),
+            role: "middle relay",
+            problem: info.to_string(),
+        })?;
+        chosen.push(middle.clone());
+        hops.push(MaybeOwnedRelay::from(middle));
+    }
+    hops.push(MaybeOwnedRelay::from(exit));
 
     ensure_unique_hops(&hops)?;
 
@@ -388,25 +455,50 @@ fn ensure_unique_hops<'a>(hops: &'a [MaybeOwnedRelay<'a>]) -> StdResult<(), Bug>
     Ok(())
 }
 
+/// Return every relay in `netdir` that we must never use on a path, according
+/// to `config` and to the `excluded` relays of a path builder.
+///
+/// Returns a [`RelayExclusion`] for non-guard relays, and the identities of
+/// guards to avoid beyond those that the [`GuardFilter`](tor_guardmgr::GuardFilter)
+/// from `config` already forbids.
+fn unwanted_relays<'a>(
+    config: &PathConfig,
+    excluded: Option<&RelayIdSet>,
+    netdir: &'a NetDir,
+    family_rules: FamilyRules,
+) -> (RelayExclusion<'a>, RelayIdSet) {
+    let mut unwanted = config.unwanted_relays(netdir, family_rules);
+    let mut unwanted_guards = config.unwanted_guards(netdir);
+    if let Some(ids) = excluded {
+        unwanted.extend(&RelayExclusion::exclude_unwanted(ids.clone(), vec![]));
+        unwanted_guards.extend(ids.iter().map(|id| id.to_owned()));
+    }
+    (unwanted, unwanted_guards)
+}
+
 /// Try to select a guard corresponding to the requirements of
 /// this builder.
+///
+/// The guard will not have any of the identities in `avoid`.
 fn select_guard<'a, RT: Runtime>(
     netdir: &'a NetDir,
     guardmgr: &GuardMgr<RT>,
     compatible_with: Option<&OwnedChanTarget>,
+    mut avoid: RelayIdSet,
 ) -> Result<(MaybeOwnedRelay<'a>, GuardMonitor, GuardUsable)> {
     // TODO: Extract this section into its own function, and see
     // what it can share with tor_relay_selection.
     let mut b = tor_guardmgr::GuardUsageBuilder::default();
     b.kind(tor_guardmgr::GuardUsageKind::Data);
     if let Some(avoid_target) = compatible_with {
-        let mut family = RelayIdSet::new();
-        family.extend(avoid_target.identities().map(|id| id.to_owned()));
+        avoid.extend(avoid_target.identities().map(|id| id.to_owned()));
         if let Some(avoid_relay) = netdir.by_ids(avoid_target) {
-            family.extend(netdir.known_family_members(&avoid_relay).map(|r| *r.id()));
+            avoid.extend(netdir.known_family_members(&avoid_relay).map(|r| *r.id()));
         }
+    }
+    if !avoid.is_empty() {
         b.restrictions()
-            .push(tor_guardmgr::GuardRestriction::AvoidAllIds(family));
+            .push(tor_guardmgr::GuardRestriction::AvoidAllIds(avoid));
     }
     let guard_usage = b.build().expect("Failed while building guard usage!");
     let (guard, mon, usable) = guardmgr.select_guard(guard_usage)?;
@@ -1,20 +1,25 @@
 //! Code for building paths to an exit relay.
 
+use std::sync::Arc;
 use std::time::SystemTime;
 
 use rand::Rng;
 
 use super::{AnonymousPathBuilder, TorPath};
+use crate::config::DEFAULT_HOPS;
 use crate::path::pick_path;
-use crate::{DirInfo, Error, PathConfig, Result, TargetPort};
+use crate::path_policy::{self, PathPolicy, PathPosition, RequireConfluxExit};
+use crate::{DirInfo, Error, PathConfig, RelayRules, Result, TargetPort};
 
+#[cfg(feature = "geoip")]
+use tor_geoip::CountryCode;
 use tor_guardmgr::{GuardMgr, GuardMonitor, GuardUsable};
-use tor_linkspec::OwnedChanTarget;
+use tor_linkspec::{OwnedChanTarget, RelayIdSet};
 use tor_netdir::{NetDir, Relay};
-use tor_relay_selection::{RelayExclusion, RelaySelectionConfig, RelaySelector, RelayUsage};
+use tor_relay_selection::{
+    RelayExclusion, RelayRestriction, RelaySelectionConfig, RelaySelector, RelayUsage,
+};
 use tor_rtcompat::Runtime;
-#[cfg(feature = "geoip")]
-use {tor_geoip::CountryCode, tor_relay_selection::RelayRestriction};
 
 /// Internal representation of PathBuilder.
 enum ExitPathBuilderInner {
@@ -55,6 +60,17 @@ pub(crate) struct ExitPathBuilder {
     compatible_with: Option<OwnedChanTarget>,
     /// If true, all relays on this path must be Stable.
     require_stability: bool,
+    /// Sets of relays, each of which must contain the exit.
+    exit_in: Vec<RelayIdSet>,
+    /// Relays that this path must not use, beyond those that the
+    /// [`PathConfig`] excludes.
+    exclude: Option<RelayIdSet>,
+    /// A policy that may veto or re-weight the relays on this path.
+    policy: Option<Arc<dyn PathPolicy>>,
+    /// The number of relays on this path, including the guard and the exit.
+    hops: u8,
+    /// If true, the exit must support conflux.
+    require_conflux: bool,
 }
 
 impl ExitPathBuilder {
@@ -71,6 +87,11 @@ impl ExitPathBuilder {
             inner: ExitPathBuilderInner::WantsPorts(ports),
             compatible_with: None,
             require_stability: true,
+            exit_in: vec![],
+            exclude: None,
+            policy: None,
+            hops: DEFAULT_HOPS,
+            require_conflux: false,
         }
     }
 
@@ -90,6 +111,11 @@ impl ExitPathBuilder {
             inner: ExitPathBuilderInner::ExitInCountry { country, ports },
             compatible_with: None,
             require_stability: true,
+            exit_in: vec![],
+            exclude: None,
+            policy: None,
+            hops: DEFAULT_HOPS,
+            require_conflux: false,
         }
     }
 
@@ -99,6 +125,11 @@ impl ExitPathBuilder {
             inner: ExitPathBuilderInner::AnyExit { strict: true },
             compatible_with: None,
             require_stability: false,
+            exit_in: vec![],
+            exclude: None,
+            policy: None,
+            hops: DEFAULT_HOPS,
+            require_conflux: false,
         }
     }
 
@@ -122,6 +153,11 @@ impl ExitPathBuilder {
             inner: ExitPathBuilderInner::AnyExit { strict: false },
             compatible_with: None,
             require_stability: false,
+            exit_in: vec![],
+            exclude: None,
+            policy: None,
+            hops: DEFAULT_HOPS,
+            require_conflux: false,
         }
     }
 
@@ -131,6 +167,35 @@ impl ExitPathBuilder {
         self.require_stability = require_stability;
         self
     }
+
+    /// Require that the exit relay on this circuit be one of `ids`, if
+    /// there are any.
+    pub(crate) fn require_exit_in(&mut self, ids: Option<RelayIdSet>) -> &mut Self {
+        self.exit_in.extend(ids);
+        self
+    }
+
+    /// Make this circuit obey `rules`, in addition to the [`PathConfig`].
+    pub(crate) fn obey_rules(&mut self, rules: &RelayRules) -> &mut Self {
+        self.exclude = Some(rules.excluded().clone());
+        self.require_conflux = rules.conflux_exit();
+        if let Some(hops) = rules.n_hops() {
+            self.hops(hops);
+        }
+        self.require_exit_in(rules.exits().cloned())
+    }
+
+    /// Put `n` relays on this path, including the guard and the exit.
+    pub(crate) fn hops(&mut self, n: u8) -> &mut Self {
+        self.hops = n;
+        self
+    }
+
+    /// Make this circuit obey `policy`, if there is one.
+    pub(crate) fn obey_policy(&mut self, policy: Option<Arc<dyn PathPolicy>>) -> &mut Self {
+        self.policy = policy;
+        self
+    }
 }
 
 impl AnonymousPathBuilder for ExitPathBuilder {
@@ -138,6 +203,18 @@ impl AnonymousPathBuilder for ExitPathBuilder {
         self.compatible_with.as_ref()
     }
 
+    fn excluded_relays(&self) -> Option<&RelayIdSet> {
+        self.exclude.as_ref()
+    }
+
+    fn n_hops(&self) -> u8 {
+        self.hops
+    }
+
+    fn path_policy(&self) -> Option<&dyn PathPolicy> {
+        self.policy.as_deref()
+    }
+
     fn pick_exit<'a, R: Rng>(
         &self,
         rng: &mut R,
@@ -145,7 +222,7 @@ impl AnonymousPathBuilder for ExitPathBuilder {
         guard_exclusion: RelayExclusion<'a>,
         rs_cfg: &RelaySelectionConfig<'_>,
     ) -> Result<(Relay<'a>, RelayUsage)> {
-        let selector = match &self.inner {
+        let mut selector = match &self.inner {
             ExitPathBuilderInner::AnyExit { strict } => {
                 let mut selector =
                     RelaySelector::new(RelayUsage::any_exit(rs_cfg), guard_exclusion);
@@ -171,7 +248,18 @@ impl AnonymousPathBuilder for ExitPathBuilder {
             ),
         };
 
-        let (relay, info) = selector.select_relay(rng, netdir);
+        for ids in &self.exit_in {
+            selector.push_restriction(RelayRestriction::require_identities(ids.clone()));
+        }
+
+        let conflux_policy = RequireConfluxExit(self.path_policy());
+        let policy = if self.require_conflux {
+            Some(&conflux_policy as &dyn PathPolicy)
+        } else {
+            self.path_policy()
+        };
+        let (relay, info) =
+            path_policy::select_relay(&selector, rng, netdir, policy, PathPosition::Exit);
         let relay = relay.ok_or_else(|| Error::NoRelay {
             path_kind: self.path_kind(),
             role: "final hop",
@@ -213,7 +301,7 @@ mod test {
     use std::collections::HashSet;
     use tor_basic_utils::test_rng::testing_rng;
     use tor_guardmgr::TestConfig;
-    use tor_linkspec::{HasRelayIds, RelayIds};
+    use tor_linkspec::{HasRelayIds, RelayId, RelayIds};
     use tor_netdir::{testnet, FamilyRules, SubnetConfig};
     use tor_persist::TestingStateMgr;
     use tor_relay_selection::LowLevelRelayPredicate;
@@ -338,6 +426,151 @@ mod test {
         });
     }
 
+    #[test]
+    fn obey_rules() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let mut rng = testing_rng();
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let dirinfo = (&netdir).into();
+            let statemgr = TestingStateMgr::new();
+            let guards =
+                tor_guardmgr::GuardMgr::new(rt.clone(), statemgr, &TestConfig::default()).unwrap();
+            guards.install_test_netdir(&netdir);
+            let now = SystemTime::now();
+
+            let exits: Vec<_> = netdir
+                .relays()
+                .filter(|r| r.low_level_details().ipv4_policy().allows_port(443))
+                .map(|r| *r.id())
+                .collect();
+            let (pinned, excluded_exit) = (&exits[1..], exits[0]);
+            let mut config = PathConfig::builder();
+            config
+                .exit_relays()
+                .extend(exits.iter().map(|id| RelayId::from(*id)));
+            let config = config.build().unwrap();
+            let mut rules = RelayRules::new();
+            rules.exclude_relay(excluded_exit);
+
+            for _ in 0..100 {
+                let (path, _, _) = ExitPathBuilder::from_target_ports([TargetPort::ipv4(443)])
+                    .require_exit_in(config.exit_relays())
+                    .obey_rules(&rules)
+                    .pick_path(&mut rng, dirinfo, &guards, &config, now)
+                    .unwrap();
+                let TorPathInner::Path(p) = path.inner else {
+                    panic!("Generated the wrong kind of path");
+                };
+                assert!(pinned.iter().any(|id| p[2].has_identity(id.into())));
+                assert!(p.iter().all(|r| !r.has_identity((&excluded_exit).into())));
+            }
+        });
+    }
+
+    #[test]
+    fn longer_paths() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let mut rng = testing_rng();
+            let family_rules = FamilyRules::all_family_info();
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let dirinfo = (&netdir).into();
+            let config = PathConfig::default();
+            let statemgr = TestingStateMgr::new();
+            let guards =
+                tor_guardmgr::GuardMgr::new(rt.clone(), statemgr, &TestConfig::default()).unwrap();
+            guards.install_test_netdir(&netdir);
+            let now = SystemTime::now();
+            let subnet_config = SubnetConfig::default();
+
+            for _ in 0..100 {
+                let (path, _, _) = ExitPathBuilder::from_target_ports([TargetPort::ipv4(443)])
+                    .hops(4)
+                    .pick_path(&mut rng, dirinfo, &guards, &config, now)
+                    .unwrap();
+                assert_same_path_when_owned(&path);
+                let TorPathInner::Path(p) = path.inner else {
+                    panic!("Generated the wrong kind of path");
+                };
+                assert_eq!(p.len(), 4);
+                for (i, r1) in p.iter().enumerate() {
+                    for r2 in &p[i + 1..] {
+                        assert!(!r1.same_relay_ids(r2));
+                        assert!(r1.can_share_circuit(r2, subnet_config, family_rules));
+                    }
+                }
+            }
+
+            let outcome = ExitPathBuilder::from_target_ports([TargetPort::ipv4(443)])
+                .hops(2)
+                .pick_path(&mut rng, dirinfo, &guards, &config, now);
+            assert!(outcome.is_err());
+        });
+    }
+
+    #[test]
+    fn obey_policy() {
+        /// A policy that vetoes one relay everywhere, and optionally every
+        /// middle relay.
+        struct Veto {
+            relay: tor_llcrypto::pk::ed25519::Ed25519Identity,
+            all_middles: bool,
+        }
+        impl PathPolicy for Veto {
+            fn permits(&self, relay: &Relay<'_>, position: PathPosition) -> bool {
+                !(relay.id() == &self.relay
+                    || (self.all_middles && position == PathPosition::Middle))
+            }
+        }
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let mut rng = testing_rng();
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let dirinfo = (&netdir).into();
+            let config = PathConfig::default();
+            let statemgr = TestingStateMgr::new();
+            let guards =
+                tor_guardmgr::GuardMgr::new(rt.clone(), statemgr, &TestConfig::default()).unwrap();
+            guards.install_test_netdir(&netdir);
+            let now = SystemTime::now();
+
+            let vetoed = *netdir
+                .relays()
+                .find(|r| r.low_level_details().ipv4_policy().allows_port(443))
+                .unwrap()
+                .id();
+            let policy: Arc<dyn PathPolicy> = Arc::new(Veto {
+                relay: vetoed,
+                all_middles: false,
+            });
+            for _ in 0..100 {
+                let (path, _, _) = ExitPathBuilder::from_target_ports([TargetPort::ipv4(443)])
+                    .obey_policy(Some(Arc::clone(&policy)))
+                    .pick_path(&mut rng, dirinfo, &guards, &config, now)
+                    .unwrap();
+                let TorPathInner::Path(p) = path.inner else {
+                    panic!("Generated the wrong kind of path");
+                };
+                assert!(p.iter().all(|r| !r.has_identity((&vetoed).into())));
+            }
+
+            // If the policy vetoes every middle relay, we can't build a path.
+            let policy: Arc<dyn PathPolicy> = Arc::new(Veto {
+                relay: vetoed,
+                all_middles: true,
+            });
+            let outcome = ExitPathBuilder::from_target_ports([TargetPort::ipv4(443)])
+                .obey_policy(Some(policy))
+                .pick_path(&mut rng, dirinfo, &guards, &config, now);
+            assert!(matches!(
+                outcome,
+                Err(Error::NoRelay {
+                    role: "middle relay",
+                    ..
+                })
+            ));
+        });
+    }
+
     #[test]
     fn empty_path() {
         // This shouldn't actually be constructable IRL, but let's test to
@@ -354,6 +587,24 @@ mod test {
         assert!(owned.is_err());
     }
 
+    #[test]
+    fn overlapping_paths() {
+        let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        let relays: Vec<_> = netdir.relays().take(5).collect();
+        let owned = |idx: &[usize]| -> OwnedPath {
+            let path = TorPath::new_multihop(idx.iter().map(|i| relays[*i].clone()));
+            (&path).try_into().unwrap()
+        };
+
+        let p1 = owned(&[0, 1, 2]);
+        // Sharing a guard is fine.
+        assert!(!p1.overlaps(&owned(&[0, 3, 4])));
+        // Sharing any other relay is not.
+        assert!(p1.overlaps(&owned(&[0, 3, 2])));
+        assert!(p1.overlaps(&owned(&[3, 4, 1])));
+        assert!(p1.overlaps(&p1));
+    }
+
     #[test]
     fn no_exits() {
         tor_rtcompat::test_with_all_runtimes!(|rt| async move {
@@ -83,11 +83,12 @@ use {
 
 #[cfg(feature = "vanguards")]
 use {
-    crate::path::{select_guard, MaybeOwnedRelay},
+    crate::path::{select_guard, unwanted_relays, MaybeOwnedRelay},
     tor_error::bad_api_usage,
     tor_guardmgr::vanguards::Layer,
     tor_guardmgr::vanguards::VanguardMgr,
     tor_guardmgr::VanguardMode,
+    tor_netdir::FamilyRules,
 };
 
 #[cfg(feature = "vanguards")]
@@ -161,7 +162,7 @@ impl HsPathBuilder {
             compatible_with: self.compatible_with.clone(),
         };
 
-        vanguard_path_builder.pick_path(rng, netdir, guards, vanguards)
+        vanguard_path_builder.pick_path(rng, netdir, guards, vanguards, config)
     }
 }
 
@@ -218,6 +219,7 @@ impl VanguardHsPathBuilder {
         netdir: DirInfo<'a>,
         guards: &GuardMgr<RT>,
         vanguards: &VanguardMgr<RT>,
+        config: &PathConfig,
     ) -> Result<(TorPath<'a>, GuardMonitor, GuardUsable)> {
         let netdir = match netdir {
             DirInfo::Directory(d) => d,
@@ -229,26 +231,37 @@ impl VanguardHsPathBuilder {
             }
         };
 
+        // Relays that the configuration forbids are never allowed,
+        // even though the usual family restrictions don't apply here.
+        let family_rules = FamilyRules::from(netdir.params());
+        let (unwanted, unwanted_guards) = unwanted_relays(config, None, netdir, family_rules);
+
         // Select the guard, allowing it to appear as
         // either of the last two hops of the circuit.
-        let (l1_guard, mon, usable) = select_guard(netdir, guards, None)?;
+        let (l1_guard, mon, usable) = select_guard(netdir, guards, None, unwanted_guards)?;
 
-        let target_exclusion = if let Some(target) = self.compatible_with.as_ref() {
+        let mut target_exclusion = if let Some(target) = self.compatible_with.as_ref() {
             RelayExclusion::exclude_identities(
                 target.identities().map(|id| id.to_owned()).collect(),
             )
         } else {
             RelayExclusion::no_relays_excluded()
         };
+        target_exclusion.extend(&unwanted);
 
         let mode = vanguards.mode();
         let path = match mode {
             VanguardMode::Lite => {
                 self.pick_lite_vanguard_path(rng, netdir, vanguards, l1_guard, &target_exclusion)?
             }
-            VanguardMode::Full => {
-                self.pick_full_vanguard_path(rng, netdir, vanguards, l1_guard, &target_exclusion)?
-            }
+            VanguardMode::Full => self.pick_full_vanguard_path(
+                rng,
+                netdir,
+                vanguards,
+                l1_guard,
+                &target_exclusion,
+                &unwanted,
+            )?,
             VanguardMode::Disabled => {
                 return Err(internal!(
                     "VanguardHsPathBuilder::pick_path called, but vanguards are disabled?!"
@@ -283,12 +296,13 @@ impl VanguardHsPathBuilder {
         vanguards: &VanguardMgr<RT>,
         l1_guard: MaybeOwnedRelay<'n>,
         target_exclusion: &RelayExclusion<'n>,
+        unwanted: &RelayExclusion<'n>,
     ) -> Result<TorPath<'n>> {
         // NOTE: if the we are using full vanguards and building an GUARDED circuit stem,
         // we do *not* exclude the target from occurring as the second hop
         // (circuits of the form G - L2 - L3 - M - L2 are valid)
         let l2_target_exclusion = match self.kind {
-            HsCircStemKind::Guarded => RelayExclusion::no_relays_excluded(),
+            HsCircStemKind::Guarded => unwanted.clone(),
             HsCircStemKind::Naive => target_exclusion.clone(),
         };
 
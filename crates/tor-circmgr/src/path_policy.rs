@@ -0,0 +1,114 @@
+//! Pluggable policies for choosing the relays on a path.
+
+use rand::Rng;
+use tor_netdir::{NetDir, Relay};
+use tor_relay_selection::{RelaySelector, SelectionInfo};
+
+/// A position on a path, for which a [`PathPolicy`] is asked about a relay.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum PathPosition {
+    /// The first hop of the path.
+    Guard,
+    /// A hop between the guard and the exit.
+    Middle,
+    /// The last hop of the path.
+    Exit,
+}
+
+/// A policy that can veto or re-weight the relays that a
+/// [`CircMgr`](crate::CircMgr) considers for each position of a path.
+///
+/// Install one with [`CircMgr::set_path_policy`](crate::CircMgr::set_path_policy).
+///
+/// The policy only ever narrows or reshapes the choices that the circuit
+/// manager would make anyway: it is consulted about relays that already obey
+/// the [`PathConfig`](crate::PathConfig), the family and subnet rules, and
+/// the requirements of the circuit.
+///
+/// The policy applies to the multi-hop circuits that the circuit manager
+/// builds to exit relays (including preemptive and timeout-testing circuits).
+/// It does not apply to directory circuits, or to onion service circuits.
+///
+/// # Guards
+///
+/// Guards are chosen from a persistent sample, by the guard manager, so that
+/// they change as rarely as possible.  The policy can veto a guard for a
+/// path, but its weights are ignored for guards.  Vetoing many guards
+/// makes the client try guards outside its primary set, which is easier for
+/// an attacker to notice: policies should veto guards sparingly, if at all.
+///
+/// # Anonymity
+///
+/// Every choice that differs from the default makes a client's circuits
+/// more distinguishable from those of other clients.  A policy is meant for
+/// research deployments and specialized clients, which accept that cost.
+pub trait PathPolicy: Send + Sync {
+    /// Return true if `relay` may be used in `position`.
+    ///
+    /// The default implementation permits every relay.
+    fn permits(&self, relay: &Relay<'_>, position: PathPosition) -> bool {
+        let _ = (relay, position);
+        true
+    }
+
+    /// Return a factor by which to multiply the usual weight of `relay`
+    /// when choosing a relay for `position`.
+    ///
+    /// The usual weight is the relay's share of the network's bandwidth for
+    /// that position.  A factor of `1.0` keeps it; `0.0` means that the relay
+    /// is never chosen, as if [`permits`](PathPolicy::permits) had returned
+    /// false.  Negative or non-finite factors count as zero.
+    ///
+    /// This is not consulted for [`PathPosition::Guard`].
+    ///
+    /// The default implementation returns `1.0` for every relay.
+    fn weight(&self, relay: &Relay<'_>, position: PathPosition) -> f64 {
+        let _ = (relay, position);
+        1.0
+    }
+}
+
+/// A [`PathPolicy`] that only permits exits that support conflux, and
+/// otherwise defers to another policy, if there is one.
+pub(crate) struct RequireConfluxExit<'a>(pub(crate) Option<&'a dyn PathPolicy>);
+
+impl PathPolicy for RequireConfluxExit<'_> {
+    fn permits(&self, relay: &Relay<'_>, position: PathPosition) -> bool {
+        if position == PathPosition::Exit
+            && !relay
+                .low_level_details()
+                .protovers()
+                .supports_named_subver(tor_protover::named::CONFLUX_BASE)
+        {
+            return false;
+        }
+        self.0
+            .map_or(true, |policy| policy.permits(relay, position))
+    }
+
+    fn weight(&self, relay: &Relay<'_>, position: PathPosition) -> f64 {
+        self.0.map_or(1.0, |policy| policy.weight(relay, position))
+    }
+}
+
+/// Use `selector` to pick a random relay from `netdir` for `position`,
+/// obeying `policy` (if any).
+pub(crate) fn select_relay<'s, 'd, R: Rng>(
+    selector: &'s RelaySelector<'_>,
+    rng: &mut R,
+    netdir: &'d NetDir,
+    policy: Option<&dyn PathPolicy>,
+    position: PathPosition,
+) -> (Option<Relay<'d>>, SelectionInfo<'s>) {
+    match policy {
+        None => selector.select_relay(rng, netdir),
+        Some(policy) => selector.select_relay_reweighted(rng, netdir, |relay| {
+            if policy.permits(relay, position) {
+                policy.weight(relay, position)
+            } else {
+                0.0
+            }
+        }),
+    }
+}
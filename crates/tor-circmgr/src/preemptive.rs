@@ -59,7 +59,8 @@ impl PreemptiveCircuitPredictor {
         let config = self.config();
         let now = Instant::now();
         let circs = config.min_exit_circs_for_port;
-        self.usages
+        let mut ports: Vec<_> = self
+            .usages
             .iter()
             .filter(|(_, &time)| {
                 time.checked_add(config.prediction_lifetime)
@@ -71,10 +72,23 @@ impl PreemptiveCircuitPredictor {
                         false
                     })
             })
-            .map(|(&port, _)| {
-                let require_stability = port.is_some_and(|p| path_config.long_lived_ports.contains(&p.port));
+            .map(|(&port, _)| port)
+            .collect();
+        for port in &config.always_predicted_ports {
+            let port = Some(TargetPort::ipv4(*port));
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+        ports
+            .into_iter()
+            .map(|port| {
+                let require_stability =
+                    port.is_some_and(|p| path_config.long_lived_ports.contains(&p.port));
                 TargetCircUsage::Preemptive {
-                    port, circs, require_stability,
+                    port,
+                    circs,
+                    require_stability,
                 }
             })
             .collect()
@@ -191,6 +205,30 @@ mod test {
             })));
     }
 
+    #[test]
+    fn predicts_always_predicted_ports() {
+        let path_config = PathConfig::default();
+        let mut cfg = PreemptiveCircuitConfig::builder();
+        cfg.set_initial_predicted_ports(vec![]);
+        cfg.set_always_predicted_ports(vec![22]);
+        cfg.prediction_lifetime(Duration::from_secs(2));
+        let mut predictor = PreemptiveCircuitPredictor::new(cfg.build().unwrap());
+        let three_seconds_ago = Instant::now() - Duration::from_secs(2 + 1);
+
+        // Even once its prediction has expired, the port is still predicted.
+        predictor.note_usage(Some(TargetPort::ipv4(22)), three_seconds_ago);
+
+        let results = predictor.predict(&path_config);
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .any(|r| r.isol_eq(&TargetCircUsage::Preemptive {
+                port: Some(TargetPort::ipv4(22)),
+                circs: 2,
+                require_stability: true,
+            })));
+    }
+
     #[test]
     fn does_not_predict_old_ports() {
         let path_config = PathConfig::default();
@@ -0,0 +1,96 @@
+//! Rules about which relays a single circuit may use.
+
+use tor_linkspec::{RelayId, RelayIdSet};
+
+/// Rules about which relays a circuit may use, and how many, in addition to
+/// those in the [`PathConfig`](crate::PathConfig).
+///
+/// Pass this to
+/// [`CircMgr::get_or_launch_exit_with_rules`](crate::CircMgr::get_or_launch_exit_with_rules)
+/// to choose relays for a single request, rather than for every circuit.
+///
+/// A circuit built for one set of rules is only shared with requests that
+/// have the same rules, or no rules at all.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RelayRules {
+    /// Relays that the circuit must not use, in any position.
+    exclude: RelayIdSet,
+    /// Relays that the circuit may use as its exit.
+    ///
+    /// If this is empty, any exit will do.
+    exits: RelayIdSet,
+    /// The number of relays on the circuit, if it isn't the configured
+    /// number.
+    hops: Option<u8>,
+    /// True if the exit must support conflux.
+    conflux_exit: bool,
+}
+
+impl RelayRules {
+    /// Return a new `RelayRules` that permits every relay.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Never use the relay with `id`, in any position.
+    pub fn exclude_relay(&mut self, id: impl Into<RelayId>) -> &mut Self {
+        self.exclude.insert(id.into());
+        self
+    }
+
+    /// Permit the relay with `id` to be used as an exit.
+    ///
+    /// Once this has been called, only the relays passed to it may be used
+    /// as exits.
+    pub fn allow_exit(&mut self, id: impl Into<RelayId>) -> &mut Self {
+        self.exits.insert(id.into());
+        self
+    }
+
+    /// Build the circuit with `n` relays, including the guard and the exit,
+    /// instead of the number that the [`PathConfig`](crate::PathConfig) asks
+    /// for.
+    ///
+    /// `n` must be between 3 and 8, or we won't be able to build the circuit.
+    pub fn hops(&mut self, n: u8) -> &mut Self {
+        self.hops = Some(n);
+        self
+    }
+
+    /// Only use an exit that supports conflux.
+    #[cfg(feature = "conflux")]
+    pub(crate) fn require_conflux_exit(&mut self) -> &mut Self {
+        self.conflux_exit = true;
+        self
+    }
+
+    /// Return true if these rules permit every relay, and don't change the
+    /// length of the circuit.
+    pub fn is_empty(&self) -> bool {
+        self.exclude.is_empty()
+            && self.exits.is_empty()
+            && self.hops.is_none()
+            && !self.conflux_exit
+    }
+
+    /// Return the relays that must not be used, in any position.
+    pub(crate) fn excluded(&self) -> &RelayIdSet {
+        &self.exclude
+    }
+
+    /// Return the relays that may be used as exits, or `None` if any may.
+    pub(crate) fn exits(&self) -> Option<&RelayIdSet> {
+        (!self.exits.is_empty()).then_some(&self.exits)
+    }
+
+    /// Return the number of relays on the circuit, or `None` to use the
+    /// configured number.
+    pub(crate) fn n_hops(&self) -> Option<u8> {
+        self.hops
+    }
+
+    /// Return true if the exit must support conflux.
+    pub(crate) fn conflux_exit(&self) -> bool {
+        self.conflux_exit
+    }
+}
@@ -248,4 +248,50 @@ mod test {
         let to_3 = est2.timeouts(&act);
         assert!(to_3.0 < to_2.0);
     }
+
+    #[test]
+    fn upgrade_unversioned_state() {
+        let params = NetParameters::default();
+        let act = Action::BuildCircuit { length: 3 };
+
+        // Before we used versioned records, we stored the state as it was.
+        let mut pareto = ParetoTimeoutEstimator::default();
+        for _ in 0..500 {
+            pareto.note_hop_completed(2, Duration::from_secs(3), true);
+        }
+        let storage = tor_persist::TestingStateMgr::new();
+        assert!(storage.try_lock().unwrap().held());
+        storage
+            .store(
+                crate::PARETO_TIMEOUT_DATA_KEY,
+                &pareto.build_state().unwrap(),
+            )
+            .unwrap();
+
+        // We can still load it.
+        let handle = crate::timeout_state_handle(storage.clone());
+        let est = Estimator::from_storage(&handle);
+        est.update_params(&params);
+        assert!(!est.learning_timeouts());
+        assert_ne!(
+            est.timeouts(&act),
+            (Duration::from_secs(60), Duration::from_secs(60))
+        );
+
+        // Once we store it again, it is in a versioned record.
+        est.save_state(&handle).unwrap();
+        let raw: tor_persist::JsonValue = storage
+            .load(crate::PARETO_TIMEOUT_DATA_KEY)
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw["schema_version"], 0);
+        assert_eq!(raw["value"]["version"], 1);
+
+        // And it loads the same way.
+        let est2 = Estimator::from_storage(&handle);
+        est2.update_params(&params);
+        let to_1_secs = est.timeouts(&act).0.as_secs_f64();
+        let to_2_secs = est2.timeouts(&act).0.as_secs_f64();
+        assert!((to_1_secs - to_2_secs).abs() < 0.001);
+    }
 }
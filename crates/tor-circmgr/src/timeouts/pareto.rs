@@ -19,7 +19,7 @@ use std::time::Duration;
 use tor_netdir::params::NetParameters;
 
 use super::Action;
-use tor_persist::JsonValue;
+use tor_persist::{JsonValue, Schema};
 
 /// How many circuit build time observations do we record?
 const TIME_HISTORY_LEN: usize = 1000;
@@ -490,6 +490,15 @@ pub(crate) struct ParetoTimeoutState {
 }
 
 impl ParetoTimeoutState {
+    /// Return the schema with which we store a `ParetoTimeoutState`.
+    ///
+    /// Add a migration here if this type ever changes in a way that older
+    /// states can't be read as.  (The `version` field predates our use of
+    /// versioned records, and stays at 1.)
+    pub(crate) fn schema() -> Schema {
+        Schema::new()
+    }
+
     /// Return the latest base timeout estimate, as recorded in this state.
     pub(crate) fn latest_estimate(&self) -> Option<Duration> {
         self.current_timeout
@@ -588,6 +597,14 @@ impl super::TimeoutEstimator for ParetoTimeoutEstimator {
 
         tracing::trace!(%hop, ?delay, %have_seen_recent_activity, "Circuit timeout");
 
+        if hop > self.p.significant_hop {
+            // This circuit was longer than the ones that our estimates are
+            // about, and it got past the hop that we measure: we have already
+            // recorded how long that took.  That it timed out later on
+            // doesn't tell us that our estimate was too short.
+            return;
+        }
+
         if hop > 0 && have_seen_recent_activity {
             self.history.add_success(false);
             if self.history.n_recent_timeouts() > self.p.reset_after_timeouts {
@@ -922,6 +939,23 @@ mod test {
         assert_eq!(est.timeouts(&b3()).0.as_micros(), 120_000_000);
     }
 
+    #[test]
+    fn long_circuit_timeouts() {
+        let mut est = ParetoTimeoutEstimator::default();
+        let params = NetParameters::from_map(&"cbtmincircs=1 cbtnummodes=2".parse().unwrap());
+        est.update_params(&params);
+
+        // A 4-hop circuit that times out after building 3 hops has already
+        // told us how long its first 3 hops took, so it isn't a timeout...
+        est.note_hop_completed(2, Duration::from_millis(300), false);
+        est.note_circ_timeout(3, Duration::from_secs(2000));
+        assert_eq!(est.history.n_recent_timeouts(), 0);
+
+        // ... but one that times out before then is.
+        est.note_circ_timeout(2, Duration::from_secs(2000));
+        assert_eq!(est.history.n_recent_timeouts(), 1);
+    }
+
     #[test]
     fn default_params() {
         let p1 = Params::default();
@@ -9,6 +9,7 @@ use tracing::trace;
 use void::Void;
 
 use crate::path::{dirpath::DirPathBuilder, exitpath::ExitPathBuilder, TorPath};
+use crate::{PathPolicy, RelayRules};
 use tor_chanmgr::ChannelUsage;
 #[cfg(feature = "geoip")]
 use tor_error::internal;
@@ -146,6 +147,8 @@ pub(crate) enum TargetCircUsage {
         isolation: StreamIsolation,
         /// Restrict the circuit to only exits in the provided country code.
         country_code: Option<CountryCode>,
+        /// Further restrict the relays that the circuit may use.
+        relay_rules: Option<Arc<RelayRules>>,
         /// If true, all relays on this circuit need to have the Stable flag.
         //
         // TODO #504: It would be good to remove this field, if we can.
@@ -209,6 +212,9 @@ pub(crate) enum SupportedCircUsage {
         isolation: Option<StreamIsolation>,
         /// Country code the exit is in, or `None` if no country could be determined.
         country_code: Option<CountryCode>,
+        /// The rules that the circuit was built to obey, beyond the
+        /// [`PathConfig`](crate::PathConfig), if any.
+        relay_rules: Option<Arc<RelayRules>>,
         /// Whether every relay in this circuit has the "Stable" flag.
         //
         // TODO #504: It would be good to remove this field, if we can.
@@ -237,6 +243,7 @@ impl TargetCircUsage {
         guards: &GuardMgr<RT>,
         #[cfg(all(feature = "vanguards", feature = "hs-common"))] vanguards: &VanguardMgr<RT>,
         config: &crate::PathConfig,
+        policy: Option<&Arc<dyn PathPolicy>>,
         now: SystemTime,
     ) -> Result<(
         TorPath<'a>,
@@ -257,6 +264,9 @@ impl TargetCircUsage {
                 // FIXME(eta): this is copypasta from `TargetCircUsage::Exit`.
                 let (path, mon, usable) = ExitPathBuilder::from_target_ports(port.iter().copied())
                     .require_stability(*require_stability)
+                    .require_exit_in(config.exit_relays())
+                    .hops(config.hops())
+                    .obey_policy(policy.cloned())
                     .pick_path(rng, netdir, guards, config, now)?;
                 let policy = path
                     .exit_policy()
@@ -272,6 +282,7 @@ impl TargetCircUsage {
                         policy,
                         isolation: None,
                         country_code,
+                        relay_rules: None,
                         all_relays_stable,
                     },
                     Some(mon),
@@ -282,6 +293,7 @@ impl TargetCircUsage {
                 ports: p,
                 isolation,
                 country_code,
+                relay_rules,
                 require_stability,
             } => {
                 #[cfg(feature = "geoip")]
@@ -293,7 +305,14 @@ impl TargetCircUsage {
                 #[cfg(not(feature = "geoip"))]
                 let mut builder = ExitPathBuilder::from_target_ports(p.clone());
 
-                builder.require_stability(*require_stability);
+                builder
+                    .require_stability(*require_stability)
+                    .require_exit_in(config.exit_relays())
+                    .hops(config.hops())
+                    .obey_policy(policy.cloned());
+                if let Some(rules) = relay_rules {
+                    builder.obey_rules(rules);
+                }
 
                 let (path, mon, usable) = builder.pick_path(rng, netdir, guards, config, now)?;
                 let policy = path
@@ -320,6 +339,7 @@ impl TargetCircUsage {
                         policy,
                         isolation: Some(isolation.clone()),
                         country_code: resulting_cc,
+                        relay_rules: relay_rules.clone(),
                         all_relays_stable,
                     },
                     Some(mon),
@@ -327,8 +347,12 @@ impl TargetCircUsage {
                 ))
             }
             TargetCircUsage::TimeoutTesting => {
+                // These circuits always have the default length, whatever the
+                // configuration says: our timeout estimates are in terms of them.
                 let (path, mon, usable) = ExitPathBuilder::for_timeout_testing()
                     .require_stability(false)
+                    .require_exit_in(config.exit_relays())
+                    .obey_policy(policy.cloned())
                     .pick_path(rng, netdir, guards, config, now)?;
                 let policy = path.exit_policy();
                 #[cfg(feature = "geoip")]
@@ -340,6 +364,7 @@ impl TargetCircUsage {
                         policy,
                         isolation: None,
                         country_code,
+                        relay_rules: None,
                         all_relays_stable: path.appears_stable(),
                     },
                     _ => SupportedCircUsage::NoUsage,
@@ -382,6 +407,7 @@ impl TargetCircUsage {
             ports: ports.iter().map(|p| TargetPort::ipv4(*p)).collect(),
             isolation: StreamIsolation::no_isolation(),
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         }
     }
@@ -411,12 +437,14 @@ impl SupportedCircUsage {
                     policy: p1,
                     isolation: i1,
                     country_code: cc1,
+                    relay_rules: rr1,
                     all_relays_stable,
                 },
                 TargetCircUsage::Exit {
                     ports: p2,
                     isolation: i2,
                     country_code: cc2,
+                    relay_rules: rr2,
                     require_stability,
                 },
             ) => {
@@ -428,6 +456,7 @@ impl SupportedCircUsage {
                     && (!require_stability || *all_relays_stable)
                     && p2.iter().all(|port| p1.allows_port(*port))
                     && (cc2.is_none() || cc1 == cc2)
+                    && (rr2.is_none() || rr1 == rr2)
             }
             (
                 Exit {
@@ -469,6 +498,15 @@ impl SupportedCircUsage {
         }
     }
 
+    /// Return the isolation group that this circuit has been assigned to, if
+    /// any.
+    pub(crate) fn isolation(&self) -> Option<&StreamIsolation> {
+        match self {
+            SupportedCircUsage::Exit { isolation, .. } => isolation.as_ref(),
+            _ => None,
+        }
+    }
+
     /// Change the value of this spec based on the circuit having been used for `usage`.
     ///
     /// Returns an error and makes no changes to `self` if `usage` was not supported by this spec.
@@ -592,15 +630,17 @@ pub(crate) mod test {
                         ports: p1,
                         isolation: is1,
                         country_code: cc1,
+                        relay_rules: rr1,
                         ..
                     },
                     Exit {
                         ports: p2,
                         isolation: is2,
                         country_code: cc2,
+                        relay_rules: rr2,
                         ..
                     },
-                ) => p1 == p2 && cc1 == cc2 && is1.isol_eq(is2),
+                ) => p1 == p2 && cc1 == cc2 && rr1 == rr2 && is1.isol_eq(is2),
                 (TimeoutTesting, TimeoutTesting) => true,
                 (
                     Preemptive {
@@ -629,15 +669,17 @@ pub(crate) mod test {
                         policy: p1,
                         isolation: is1,
                         country_code: cc1,
+                        relay_rules: rr1,
                         ..
                     },
                     Exit {
                         policy: p2,
                         isolation: is2,
                         country_code: cc2,
+                        relay_rules: rr2,
                         ..
                     },
-                ) => p1 == p2 && is1.isol_eq(is2) && cc1 == cc2,
+                ) => p1 == p2 && is1.isol_eq(is2) && cc1 == cc2 && rr1 == rr2,
                 (NoUsage, NoUsage) => true,
                 _ => false,
             }
@@ -727,18 +769,21 @@ pub(crate) mod test {
             policy: policy.clone(),
             isolation: Some(isolation.clone()),
             country_code: None,
+            relay_rules: None,
             all_relays_stable: true,
         };
         let supp_exit_iso2 = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation2.clone()),
             country_code: None,
+            relay_rules: None,
             all_relays_stable: true,
         };
         let supp_exit_no_iso = SupportedCircUsage::Exit {
             policy,
             isolation: None,
             country_code: None,
+            relay_rules: None,
             all_relays_stable: true,
         };
         let supp_none = SupportedCircUsage::NoUsage;
@@ -747,18 +792,21 @@ pub(crate) mod test {
             ports: vec![TargetPort::ipv4(80)],
             isolation: isolation.clone(),
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         };
         let targ_80_v4_iso2 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: isolation2,
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         };
         let targ_80_23_v4 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80), TargetPort::ipv4(23)],
             isolation: isolation.clone(),
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         };
 
@@ -766,12 +814,14 @@ pub(crate) mod test {
             ports: vec![TargetPort::ipv4(80), TargetPort::ipv6(23)],
             isolation: isolation.clone(),
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         };
         let targ_999_v6 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv6(999)],
             isolation,
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         };
         let targ_testing = TargetCircUsage::TimeoutTesting;
@@ -799,6 +849,43 @@ pub(crate) mod test {
         assert!(supp_none.supports(&targ_testing));
     }
 
+    #[test]
+    fn usage_relay_rules() {
+        let policy = ExitPolicy {
+            v4: Arc::new("accept 80".parse().unwrap()),
+            v6: Arc::new("reject 1-65535".parse().unwrap()),
+        };
+        let mut rules = RelayRules::new();
+        rules.exclude_relay(Ed25519Identity::from([3; 32]));
+        let rules = Some(Arc::new(rules));
+
+        let supp = |relay_rules: Option<Arc<RelayRules>>| SupportedCircUsage::Exit {
+            policy: policy.clone(),
+            isolation: None,
+            country_code: None,
+            relay_rules,
+            all_relays_stable: true,
+        };
+        let targ = |relay_rules: Option<Arc<RelayRules>>| TargetCircUsage::Exit {
+            ports: vec![TargetPort::ipv4(80)],
+            isolation: StreamIsolation::no_isolation(),
+            country_code: None,
+            relay_rules,
+            require_stability: false,
+        };
+
+        // A circuit built without rules can't be used by a request with them...
+        assert!(supp(None).supports(&targ(None)));
+        assert!(!supp(None).supports(&targ(rules.clone())));
+        // ...but one built with rules can be used by requests with the same
+        // rules, or with none.
+        assert!(supp(rules.clone()).supports(&targ(rules.clone())));
+        assert!(supp(rules.clone()).supports(&targ(None)));
+        let mut other = RelayRules::new();
+        other.allow_exit(Ed25519Identity::from([3; 32]));
+        assert!(!supp(rules).supports(&targ(Some(Arc::new(other)))));
+    }
+
     #[test]
     fn restrict_mut() {
         let policy = ExitPolicy {
@@ -823,18 +910,21 @@ pub(crate) mod test {
             policy: policy.clone(),
             isolation: Some(isolation.clone()),
             country_code: None,
+            relay_rules: None,
             all_relays_stable: true,
         };
         let supp_exit_iso2 = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation2.clone()),
             country_code: None,
+            relay_rules: None,
             all_relays_stable: true,
         };
         let supp_exit_no_iso = SupportedCircUsage::Exit {
             policy,
             isolation: None,
             country_code: None,
+            relay_rules: None,
             all_relays_stable: true,
         };
         let supp_none = SupportedCircUsage::NoUsage;
@@ -842,12 +932,14 @@ pub(crate) mod test {
             ports: vec![TargetPort::ipv4(80)],
             isolation,
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         };
         let targ_exit_iso2 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: isolation2,
             country_code: None,
+            relay_rules: None,
             require_stability: false,
         };
         let targ_testing = TargetCircUsage::TimeoutTesting;
@@ -936,6 +1028,7 @@ pub(crate) mod test {
                     #[cfg(all(feature = "vanguards", feature = "hs-common"))]
                     &vanguards,
                     &config,
+                    None,
                     now,
                 )
                 .unwrap();
@@ -953,6 +1046,7 @@ pub(crate) mod test {
                 ports: vec![TargetPort::ipv4(995)],
                 isolation: isolation.clone(),
                 country_code: None,
+                relay_rules: None,
                 require_stability: false,
             };
             let (p_exit, u_exit, _, _) = exit_usage
@@ -963,6 +1057,7 @@ pub(crate) mod test {
                     #[cfg(all(feature = "vanguards", feature = "hs-common"))]
                     &vanguards,
                     &config,
+                    None,
                     now,
                 )
                 .unwrap();
@@ -985,6 +1080,7 @@ pub(crate) mod test {
                     #[cfg(all(feature = "vanguards", feature = "hs-common"))]
                     &vanguards,
                     &config,
+                    None,
                     now,
                 )
                 .unwrap();
@@ -1007,6 +1103,7 @@ pub(crate) mod test {
                     policy,
                     isolation: None,
                     country_code: None,
+                    relay_rules: None,
                     all_relays_stable: true
                 }
             );
@@ -1046,6 +1143,7 @@ pub(crate) mod test {
                     #[cfg(all(feature = "vanguards", feature = "hs-common"))]
                     &vanguards,
                     &config,
+                    None,
                     now,
                 )
                 .unwrap();
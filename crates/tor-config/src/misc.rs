@@ -334,6 +334,8 @@ impl TryFrom<PaddingLevelSerde> for PaddingLevel {
     }
 }
 
+impl_not_auto_value!(PaddingLevel);
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -370,8 +370,10 @@ async fn load_once<R: Runtime>(
     attempt_id: AttemptId,
     changed_out: &mut bool,
 ) -> Result<()> {
-    let missing = state.missing_docs();
     let mut changed = false;
+    // Before we go to the store, use whatever we have already parsed.
+    state.add_from_memory(&mut changed);
+    let missing = state.missing_docs();
     let outcome: Result<()> = if missing.is_empty() {
         trace!("Found no missing documents; can't advance current state");
         Ok(())
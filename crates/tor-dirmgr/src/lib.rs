@@ -55,6 +55,7 @@ mod docid;
 mod docmeta;
 mod err;
 mod event;
+mod obj_cache;
 mod retry;
 mod shared_ref;
 mod state;
@@ -67,6 +68,7 @@ pub mod filter;
 
 use crate::docid::{CacheUsage, ClientRequest, DocQuery};
 use crate::err::BootstrapAction;
+use crate::obj_cache::ObjectCache;
 #[cfg(not(feature = "experimental-api"))]
 use crate::shared_ref::SharedMutArc;
 #[cfg(feature = "experimental-api")]
@@ -80,6 +82,7 @@ use scopeguard::ScopeGuard;
 use tor_circmgr::CircMgr;
 use tor_dirclient::SourceInfo;
 use tor_error::{info_report, into_internal, warn_report};
+use tor_memquota::cache::{CachePool, CacheStats};
 use tor_netdir::params::NetParameters;
 use tor_netdir::{DirEvent, MdReceiver, NetDir, NetDirProvider};
 
@@ -128,6 +131,9 @@ pub struct DirMgrStore<R: Runtime> {
     /// The actual store
     pub(crate) store: Arc<Mutex<crate::DynStore>>,
 
+    /// An in-memory cache of parsed documents, if we have one
+    pub(crate) obj_cache: Option<ObjectCache>,
+
     /// Be parameterized by Runtime even though we don't use it right now
     pub(crate) runtime: PhantomData<R>,
 }
@@ -138,7 +144,21 @@ impl<R: Runtime> DirMgrStore<R> {
         let store = Arc::new(Mutex::new(config.open_store(offline)?));
         drop(runtime);
         let runtime = PhantomData;
-        Ok(DirMgrStore { store, runtime })
+        Ok(DirMgrStore {
+            store,
+            obj_cache: None,
+            runtime,
+        })
+    }
+
+    /// Keep the consensus and microdescriptors that we parse in memory, in `pool`
+    ///
+    /// Parsed documents are cached only while memory allows,
+    /// according to the memory quota tracker of the pool;
+    /// otherwise we load and parse them again from the store.
+    pub fn with_cache_pool(mut self, pool: &CachePool) -> Self {
+        self.obj_cache = Some(ObjectCache::new(pool));
+        self
     }
 }
 
@@ -273,6 +293,8 @@ pub struct DirMgr<R: Runtime> {
     // rusqlite::Connection isn't Sync.
     // TODO is needed?
     store: Arc<Mutex<DynStore>>,
+    /// An in-memory cache of the parsed documents in `store`, if we have one.
+    obj_cache: Option<ObjectCache>,
     /// Our latest sufficiently bootstrapped directory, if we have one.
     ///
     /// We use the RwLock so that we can give this out to a bunch of other
@@ -655,6 +677,7 @@ impl<R: Runtime> DirMgr<R> {
                 dirmgr.config.get(),
                 CacheUsage::CacheOkay,
                 Some(dirmgr.netdir.clone()),
+                dirmgr.obj_cache.clone(),
                 #[cfg(feature = "dirfilter")]
                 dirmgr
                     .filter
@@ -918,6 +941,7 @@ impl<R: Runtime> DirMgr<R> {
         Ok(DirMgr {
             config: config.into(),
             store: store.store,
+            obj_cache: store.obj_cache,
             netdir,
             protocols: Mutex::new(protocols),
             default_parameters,
@@ -945,6 +969,7 @@ impl<R: Runtime> DirMgr<R> {
             self.config.get(),
             CacheUsage::CacheOnly,
             None,
+            self.obj_cache.clone(),
             #[cfg(feature = "dirfilter")]
             self.filter
                 .clone()
@@ -1005,6 +1030,15 @@ impl<R: Runtime> DirMgr<R> {
         Ok(result)
     }
 
+    /// Return statistics about our in-memory caches of parsed documents,
+    /// if we have any.
+    ///
+    /// Returns the statistics for the cache of consensus documents,
+    /// and for the cache of microdescriptors.
+    pub fn object_cache_stats(&self) -> Option<(CacheStats, CacheStats)> {
+        self.obj_cache.as_ref().map(ObjectCache::stats)
+    }
+
     /// Given a request we sent and the response we got from a
     /// directory server, see whether we should expand that response
     /// into "something larger".
@@ -0,0 +1,124 @@
+//! In-memory cache of directory documents that we have parsed.
+//!
+//! Parsing a consensus, or thousands of microdescriptors, is expensive.  So
+//! when we load or download them, we keep the parsed objects here, and the
+//! directory state machines look here before they go back to the store.
+//!
+//! The cache participates in the memory quota system, so that it can't grow
+//! without bound on small devices: under memory pressure it is flushed, and
+//! we load and parse the documents from the store again.
+//!
+//! We keep each consensus as it was when we parsed it, before we applied any
+//! filter or checked its timeliness or signatures: whoever takes it from the
+//! cache must do all of that again.
+
+use std::sync::Arc;
+
+use tor_memquota::cache::{CachePool, CacheStats, MemCache};
+use tor_memquota::{EnabledToken, HasMemoryCost};
+use tor_netdoc::doc::microdesc::{MdDigest, Microdesc};
+use tor_netdoc::doc::netstatus::{ConsensusFlavor, UncheckedMdConsensus};
+
+use crate::docmeta::ConsensusMeta;
+
+/// Maximum number of consensus documents to keep: one for each flavor.
+const MAX_CONSENSUSES: usize = 2;
+
+/// Maximum number of microdescriptors to keep.
+///
+/// This is comfortably more than the number of relays in the network.
+const MAX_MICRODESCS: usize = 16384;
+
+/// A parsed consensus, as kept in an [`ObjectCache`].
+#[derive(Clone, Debug)]
+pub(crate) struct CachedConsensus {
+    /// Metadata for the consensus.
+    pub(crate) meta: ConsensusMeta,
+    /// The consensus, as we parsed it.
+    pub(crate) consensus: Arc<UncheckedMdConsensus>,
+    /// The length of the text from which we parsed the consensus.
+    text_len: usize,
+}
+
+/// A parsed microdescriptor, as kept in an [`ObjectCache`].
+#[derive(Clone, Debug)]
+struct CachedMicrodesc {
+    /// The microdescriptor.
+    md: Microdesc,
+    /// The length of the text from which we parsed the microdescriptor.
+    text_len: usize,
+}
+
+// We don't know exactly how much memory a parsed document uses, but it is
+// roughly in proportion to the length of its text.
+
+impl HasMemoryCost for CachedConsensus {
+    fn memory_cost(&self, _: EnabledToken) -> usize {
+        self.text_len
+    }
+}
+
+impl HasMemoryCost for CachedMicrodesc {
+    fn memory_cost(&self, _: EnabledToken) -> usize {
+        self.text_len
+    }
+}
+
+/// A memory-tracked cache of parsed consensus documents and microdescriptors.
+#[derive(Clone, Debug)]
+pub(crate) struct ObjectCache {
+    /// The latest consensus documents we have parsed, by flavor.
+    consensus: MemCache<ConsensusFlavor, CachedConsensus>,
+    /// Microdescriptors, by digest.
+    microdescs: MemCache<MdDigest, CachedMicrodesc>,
+}
+
+impl ObjectCache {
+    /// Create a new, empty, cache, whose memory is claimed from `pool`.
+    pub(crate) fn new(pool: &CachePool) -> Self {
+        ObjectCache {
+            consensus: MemCache::new(pool, "parsed consensus", MAX_CONSENSUSES),
+            microdescs: MemCache::new(pool, "parsed microdescs", MAX_MICRODESCS),
+        }
+    }
+
+    /// Return the latest microdesc consensus that we have parsed, if we still
+    /// have it.
+    pub(crate) fn md_consensus(&self) -> Option<CachedConsensus> {
+        self.consensus.get(&ConsensusFlavor::Microdesc)
+    }
+
+    /// Remember `consensus`, which we parsed from `text_len` bytes of text,
+    /// in place of any older microdesc consensus.
+    pub(crate) fn insert_md_consensus(
+        &self,
+        meta: ConsensusMeta,
+        consensus: UncheckedMdConsensus,
+        text_len: usize,
+    ) {
+        self.consensus.insert(
+            ConsensusFlavor::Microdesc,
+            CachedConsensus {
+                meta,
+                consensus: Arc::new(consensus),
+                text_len,
+            },
+        );
+    }
+
+    /// Return the microdescriptor with digest `digest`, if we have it.
+    pub(crate) fn microdesc(&self, digest: &MdDigest) -> Option<Microdesc> {
+        self.microdescs.get(digest).map(|cached| cached.md)
+    }
+
+    /// Remember `md`, which we parsed from `text_len` bytes of text.
+    pub(crate) fn insert_microdesc(&self, md: Microdesc, text_len: usize) {
+        self.microdescs
+            .insert(*md.digest(), CachedMicrodesc { md, text_len });
+    }
+
+    /// Return statistics about the consensus and microdescriptor caches.
+    pub(crate) fn stats(&self) -> (CacheStats, CacheStats) {
+        (self.consensus.stats(), self.microdescs.stats())
+    }
+}
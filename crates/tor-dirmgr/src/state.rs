@@ -17,7 +17,7 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use time::OffsetDateTime;
 use tor_basic_utils::RngExt as _;
-use tor_error::{internal, warn_report};
+use tor_error::{debug_report, internal, warn_report};
 use tor_netdir::{MdReceiver, NetDir, PartialNetDir};
 use tor_netdoc::doc::authcert::UncheckedAuthCert;
 use tor_netdoc::doc::netstatus::{Lifetime, ProtoStatuses};
@@ -25,6 +25,7 @@ use tracing::{debug, warn};
 
 use crate::event::DirProgress;
 
+use crate::obj_cache::ObjectCache;
 use crate::storage::DynStore;
 use crate::{
     docmeta::{AuthCertMeta, ConsensusMeta},
@@ -45,7 +46,7 @@ use tor_netdoc::{
     doc::{
         authcert::{AuthCert, AuthCertKeyIds},
         microdesc::MicrodescReader,
-        netstatus::{ConsensusFlavor, UnvalidatedMdConsensus},
+        netstatus::{ConsensusFlavor, UncheckedMdConsensus, UnvalidatedMdConsensus},
     },
     AllowAnnotations,
 };
@@ -127,6 +128,14 @@ pub(crate) trait DirState: Send {
         changed: &mut bool,
     ) -> Result<()>;
 
+    /// Add any documents that we have kept in memory, already parsed.
+    ///
+    /// This is called before we look for documents in the store.
+    /// Documents that turn out to be unusable are ignored.
+    ///
+    /// Set `changed` to true if any semantic changes in this state were made.
+    fn add_from_memory(&mut self, _changed: &mut bool) {}
+
     /// Add information that we have just downloaded to this state.
     ///
     /// This method receives a copy of the original request, and should reject
@@ -204,6 +213,8 @@ pub(crate) struct GetConsensusState<R: Runtime> {
     config: Arc<DirMgrConfig>,
     /// If one exists, the netdir we're trying to update.
     prev_netdir: Option<Arc<dyn PreviousNetDir>>,
+    /// If we have one, the in-memory cache of documents that we have parsed.
+    obj_cache: Option<ObjectCache>,
 
     /// A filter that gets applied to directory objects before we use them.
     #[cfg(feature = "dirfilter")]
@@ -219,6 +230,7 @@ impl<R: Runtime> GetConsensusState<R> {
         config: Arc<DirMgrConfig>,
         cache_usage: CacheUsage,
         prev_netdir: Option<Arc<dyn PreviousNetDir>>,
+        obj_cache: Option<ObjectCache>,
         #[cfg(feature = "dirfilter")] filter: Arc<dyn crate::filter::DirFilter>,
     ) -> Self {
         let authority_ids = config
@@ -239,6 +251,7 @@ impl<R: Runtime> GetConsensusState<R> {
             rt,
             config,
             prev_netdir,
+            obj_cache,
             #[cfg(feature = "dirfilter")]
             filter,
         }
@@ -311,6 +324,22 @@ impl<R: Runtime> DirState for GetConsensusState<R> {
         )?;
         Ok(())
     }
+    fn add_from_memory(&mut self, changed: &mut bool) {
+        // A consensus in memory may be pending, so we can only use it if
+        // a pending consensus from the store would do.
+        if self.next.is_some() || self.cache_usage != CacheUsage::CacheOkay {
+            return;
+        }
+        let Some(cached) = self.obj_cache.as_ref().and_then(ObjectCache::md_consensus) else {
+            return;
+        };
+        let parsed = Arc::unwrap_or_clone(cached.consensus);
+        if let Err(e) =
+            self.add_consensus(DocSource::LocalCache, &cached.meta, parsed, None, changed)
+        {
+            debug_report!(e, "Not using our parsed consensus");
+        }
+    }
     fn add_from_download(
         &mut self,
         text: &str,
@@ -362,9 +391,41 @@ impl<R: Runtime> GetConsensusState<R> {
         changed: &mut bool,
     ) -> Result<&ConsensusMeta> {
         // Try to parse it and get its metadata.
+        let (signedval, remainder, parsed) =
+            MdConsensus::parse(text).map_err(|e| Error::from_netdoc(source.clone(), e))?;
+        let meta = ConsensusMeta::from_unvalidated(signedval, remainder, parsed.dangerously_peek());
+        let to_cache = self
+            .obj_cache
+            .is_some()
+            .then(|| (meta.clone(), parsed.clone()));
+
+        self.add_consensus(source, &meta, parsed, cutoff, changed)?;
+
+        // We only keep consensus documents that we could use.
+        if let (Some(cache), Some((meta, parsed))) = (&self.obj_cache, to_cache) {
+            cache.insert_md_consensus(meta, parsed, text.len());
+        }
+
+        // Unwrap should be safe because `next` was just assigned
+        #[allow(clippy::unwrap_used)]
+        Ok(&self.next.as_ref().unwrap().consensus_meta)
+    }
+
+    /// Helper: try to set the current consensus to `parsed`, whose metadata
+    /// (as of before filtering) is `meta`.  Refuse it if the authorities
+    /// could never be correct.
+    ///
+    /// If `cutoff` is provided, treat any consensus older than `cutoff` as
+    /// older-than-requested.
+    fn add_consensus(
+        &mut self,
+        source: DocSource,
+        meta: &ConsensusMeta,
+        parsed: UncheckedMdConsensus,
+        cutoff: Option<SystemTime>,
+        changed: &mut bool,
+    ) -> Result<()> {
         let (consensus_meta, unvalidated) = {
-            let (signedval, remainder, parsed) =
-                MdConsensus::parse(text).map_err(|e| Error::from_netdoc(source.clone(), e))?;
             #[cfg(feature = "dirfilter")]
             let parsed = self.filter.filter_consensus(parsed)?;
             let parsed = self.config.tolerance.extend_tolerance(parsed);
@@ -375,7 +436,12 @@ impl<R: Runtime> GetConsensusState<R> {
                     return Err(Error::Unwanted("consensus was older than requested"));
                 }
             }
-            let meta = ConsensusMeta::from_unvalidated(signedval, remainder, &timely);
+            // The filter may have changed the lifetime.
+            let meta = ConsensusMeta::new(
+                timely.peek_lifetime().clone(),
+                *meta.sha3_256_of_signed(),
+                *meta.sha3_256_of_whole(),
+            );
             (meta, timely)
         };
 
@@ -409,14 +475,12 @@ impl<R: Runtime> GetConsensusState<R> {
             rt: self.rt.clone(),
             config: self.config.clone(),
             prev_netdir: self.prev_netdir.take(),
+            obj_cache: self.obj_cache.clone(),
             protocol_statuses: None,
             #[cfg(feature = "dirfilter")]
             filter: self.filter.clone(),
         });
-
-        // Unwrap should be safe because `next` was just assigned
-        #[allow(clippy::unwrap_used)]
-        Ok(&self.next.as_ref().unwrap().consensus_meta)
+        Ok(())
     }
 
     /// Return true if `id` is an authority identity we recognize
@@ -470,6 +534,8 @@ struct GetCertsState<R: Runtime> {
     config: Arc<DirMgrConfig>,
     /// If one exists, the netdir we're trying to update.
     prev_netdir: Option<Arc<dyn PreviousNetDir>>,
+    /// If we have one, the in-memory cache of documents that we have parsed.
+    obj_cache: Option<ObjectCache>,
 
     /// If present a set of protocols to install as our latest recommended set.
     protocol_statuses: Option<(SystemTime, Arc<ProtoStatuses>)>,
@@ -713,6 +779,7 @@ impl<R: Runtime> DirState for GetCertsState<R> {
                 self.rt,
                 self.config,
                 self.prev_netdir,
+                self.obj_cache,
                 #[cfg(feature = "dirfilter")]
                 self.filter,
             )),
@@ -752,6 +819,7 @@ impl<R: Runtime> DirState for GetCertsState<R> {
             self.config,
             cache_usage,
             self.prev_netdir,
+            self.obj_cache,
             #[cfg(feature = "dirfilter")]
             self.filter,
         ))
@@ -784,6 +852,8 @@ struct GetMicrodescsState<R: Runtime> {
     config: Arc<DirMgrConfig>,
     /// If one exists, the netdir we're trying to update.
     prev_netdir: Option<Arc<dyn PreviousNetDir>>,
+    /// If we have one, the in-memory cache of documents that we have parsed.
+    obj_cache: Option<ObjectCache>,
 
     /// A filter that gets applied to directory objects before we use them.
     #[cfg(feature = "dirfilter")]
@@ -932,6 +1002,7 @@ impl<R: Runtime> GetMicrodescsState<R> {
         rt: R,
         config: Arc<DirMgrConfig>,
         prev_netdir: Option<Arc<dyn PreviousNetDir>>,
+        obj_cache: Option<ObjectCache>,
         #[cfg(feature = "dirfilter")] filter: Arc<dyn crate::filter::DirFilter>,
     ) -> Self {
         let reset_time = consensus.lifetime().valid_until() + config.tolerance.post_valid_tolerance;
@@ -964,12 +1035,21 @@ impl<R: Runtime> GetMicrodescsState<R> {
             rt,
             config,
             prev_netdir,
+            obj_cache,
 
             #[cfg(feature = "dirfilter")]
             filter,
         }
     }
 
+    /// Keep `md`, which we parsed from `text_len` bytes of text, in our
+    /// in-memory cache, if we have one.
+    fn remember_microdesc(&self, md: &Microdesc, text_len: usize) {
+        if let Some(cache) = &self.obj_cache {
+            cache.insert_microdesc(md.clone(), text_len);
+        }
+    }
+
     /// Add a bunch of microdescriptors to the in-progress netdir.
     fn register_microdescs<I>(&mut self, mds: I, _source: &DocSource, changed: &mut bool)
     where
@@ -1059,8 +1139,10 @@ impl<R: Runtime> DirState for GetMicrodescsState<R> {
         let mut microdescs = Vec::new();
         for (id, text) in docs {
             if let DocId::Microdesc(digest) = id {
-                if let Ok(md) = Microdesc::parse(text.as_str().map_err(Error::BadUtf8InCache)?) {
+                let text = text.as_str().map_err(Error::BadUtf8InCache)?;
+                if let Ok(md) = Microdesc::parse(text) {
                     if md.digest() == &digest {
+                        self.remember_microdesc(&md, text.len());
                         microdescs.push(md);
                         continue;
                     }
@@ -1072,6 +1154,17 @@ impl<R: Runtime> DirState for GetMicrodescsState<R> {
         self.register_microdescs(microdescs, &DocSource::LocalCache, changed);
         Ok(())
     }
+    fn add_from_memory(&mut self, changed: &mut bool) {
+        let Some(cache) = &self.obj_cache else {
+            return;
+        };
+        let microdescs: Vec<_> = self
+            .partial
+            .missing_microdescs()
+            .filter_map(|digest| cache.microdesc(digest))
+            .collect();
+        self.register_microdescs(microdescs, &DocSource::LocalCache, changed);
+    }
 
     fn add_from_download(
         &mut self,
@@ -1112,6 +1205,7 @@ impl<R: Runtime> DirState for GetMicrodescsState<R> {
                 nonfatal_err.get_or_insert(Error::Unwanted("un-requested microdescriptor"));
                 continue;
             }
+            self.remember_microdesc(&md, txt.len());
             new_mds.push((txt, md));
         }
 
@@ -1185,6 +1279,7 @@ impl<R: Runtime> DirState for GetMicrodescsState<R> {
             self.config,
             cache_usage,
             self.prev_netdir,
+            self.obj_cache,
             #[cfg(feature = "dirfilter")]
             self.filter,
         ))
@@ -1439,6 +1534,7 @@ mod test {
                 cfg,
                 CacheUsage::CacheOkay,
                 None,
+                None,
                 #[cfg(feature = "dirfilter")]
                 Arc::new(crate::filter::NilFilter),
             );
@@ -1527,6 +1623,7 @@ mod test {
                 cfg,
                 CacheUsage::CacheOkay,
                 None,
+                None,
                 #[cfg(feature = "dirfilter")]
                 Arc::new(crate::filter::NilFilter),
             );
@@ -1559,6 +1656,7 @@ mod test {
                 cfg,
                 CacheUsage::CacheOkay,
                 None,
+                None,
                 #[cfg(feature = "dirfilter")]
                 Arc::new(crate::filter::NilFilter),
             );
@@ -1584,6 +1682,7 @@ mod test {
                     cfg,
                     CacheUsage::CacheOkay,
                     None,
+                    None,
                     #[cfg(feature = "dirfilter")]
                     Arc::new(crate::filter::NilFilter),
                 );
@@ -1727,6 +1826,7 @@ mod test {
                     rt,
                     cfg,
                     None,
+                    None,
                     #[cfg(feature = "dirfilter")]
                     Arc::new(crate::filter::NilFilter),
                 )
@@ -1842,4 +1942,103 @@ mod test {
             assert!(missing.is_empty());
         });
     }
+
+    #[test]
+    fn object_cache() {
+        use tor_memquota::{cache::CachePool, MemoryQuotaTracker};
+        use tor_rtcompat::DynTimeProvider;
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let rt = make_time_shifted_runtime(test_time(), rt);
+            let pool = CachePool::new(
+                MemoryQuotaTracker::new_noop(),
+                DynTimeProvider::new(rt.clone()),
+            );
+            let cache = ObjectCache::new(&pool);
+            let new_consensus_state = |cache_usage| {
+                GetConsensusState::new(
+                    rt.clone(),
+                    make_dirmgr_config(Some(test_authorities())),
+                    cache_usage,
+                    None,
+                    Some(cache.clone()),
+                    #[cfg(feature = "dirfilter")]
+                    Arc::new(crate::filter::NilFilter),
+                )
+            };
+
+            // Nothing in memory yet.
+            let mut state = new_consensus_state(CacheUsage::CacheOkay);
+            let mut changed = false;
+            state.add_from_memory(&mut changed);
+            assert!(!changed);
+            assert!(!state.can_advance());
+
+            // Downloading a consensus keeps it in memory...
+            let source = DocSource::DirServer { source: None };
+            let req = tor_dirclient::request::ConsensusRequest::new(ConsensusFlavor::Microdesc);
+            let req = crate::docid::ClientRequest::Consensus(req);
+            state
+                .add_from_download(CONSENSUS, &req, source, None, &mut changed)
+                .unwrap();
+            assert_eq!(cache.stats().0.entries, 1);
+
+            // ... so that the next state can use it without parsing it again.
+            let mut state = new_consensus_state(CacheUsage::CacheOkay);
+            let mut changed = false;
+            state.add_from_memory(&mut changed);
+            assert!(changed);
+            assert!(state.can_advance());
+            assert_eq!(state.missing_docs(), Vec::new());
+
+            // But not if we must download a new one.
+            let mut state = new_consensus_state(CacheUsage::MustDownload);
+            let mut changed = false;
+            state.add_from_memory(&mut changed);
+            assert!(!changed);
+            assert!(!state.can_advance());
+
+            // Microdescriptors that we load from the store are kept in memory too.
+            let new_microdescs_state = || {
+                let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS2).unwrap();
+                let consensus = consensus
+                    .dangerously_assume_timely()
+                    .dangerously_assume_wellsigned();
+                let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+                GetMicrodescsState::new(
+                    CacheUsage::CacheOkay,
+                    consensus,
+                    meta,
+                    rt.clone(),
+                    make_dirmgr_config(Some(test_authorities())),
+                    None,
+                    Some(cache.clone()),
+                    #[cfg(feature = "dirfilter")]
+                    Arc::new(crate::filter::NilFilter),
+                )
+            };
+            let md_text = microdescs();
+            let (md1, text1) = md_text.iter().next().unwrap();
+            let mut state = new_microdescs_state();
+            let text: crate::storage::InputString = text1.clone().into();
+            let docs = [(DocId::Microdesc(*md1), text.into())]
+                .into_iter()
+                .collect();
+            state.add_from_cache(docs, &mut changed).unwrap();
+            assert_eq!(state.missing_docs().len(), 3);
+
+            let mut state = new_microdescs_state();
+            assert_eq!(state.missing_docs().len(), 4);
+            let mut changed = false;
+            state.add_from_memory(&mut changed);
+            assert!(changed);
+            let missing = state.missing_docs();
+            assert_eq!(missing.len(), 3);
+            assert!(!missing.contains(&DocId::Microdesc(*md1)));
+
+            let md_stats = cache.stats().1;
+            assert_eq!(md_stats.entries, 1);
+            assert_eq!(md_stats.hits, 1);
+        });
+    }
 }
@@ -54,6 +54,9 @@ pub use report::*;
 mod retriable;
 pub use retriable::*;
 
+mod retry_hint;
+pub use retry_hint::*;
+
 mod misc;
 pub use misc::*;
 
@@ -120,8 +123,13 @@ pub mod tracing;
 // # Avoid exposing implementation details
 //
 // ErrorKinds should not relate to particular code paths in the Arti codebase.
+//
+// # Never rename a kind
+//
+// The name of each ErrorKind is its stable code (see `ErrorKind::code`),
+// which applications and the RPC system may compare against.
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, strum::IntoStaticStr)]
 #[non_exhaustive]
 pub enum ErrorKind {
     /// Error connecting to the Tor network
@@ -731,6 +739,22 @@ pub enum ErrorKind {
     Other,
 }
 
+impl ErrorKind {
+    /// Return a stable, machine-readable code for this kind of error.
+    ///
+    /// The code is the name of the variant (for example, `"TorAccessFailed"`):
+    /// the same name that the RPC system reports after `arti:`.
+    /// Codes are part of Arti's semver-stable API.
+    /// Unlike the `Display` output of an `ErrorKind`,
+    /// they are never changed or reused,
+    /// so foreign-function interfaces and applications can compare against them.
+    ///
+    /// See also [`ErrorKind::retry_hint`].
+    pub fn code(&self) -> &'static str {
+        self.into()
+    }
+}
+
 /// Errors that can be categorized as belonging to an [`ErrorKind`]
 ///
 /// The most important implementation of this trait is
@@ -0,0 +1,170 @@
+//! Declare the `RetryHint` enumeration, and classify each `ErrorKind` with it.
+
+use derive_more::Display;
+
+use crate::ErrorKind;
+
+/// Advice about whether, and how, to retry an operation that failed.
+///
+/// Unlike [`RetryTime`](crate::RetryTime), which says _when_ Arti thinks an
+/// internal operation may be retried, a `RetryHint` is meant for applications
+/// that embed Arti (directly, or through a foreign-function interface): it
+/// tells them what kind of retry, if any, is worth attempting after a
+/// high-level operation (like opening a stream) has failed.
+///
+/// Arti has usually retried already before reporting an error, so these
+/// hints are coarse.  An application should still limit the number of times
+/// that it retries, and wait between attempts.
+///
+/// The hint for each [`ErrorKind`] is part of Arti's semver-stable API:
+/// we only change it in a breaking release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+#[non_exhaustive]
+pub enum RetryHint {
+    /// The operation might succeed if it is retried over the same circuit.
+    ///
+    /// The problem was beyond the Tor network (for example, a timeout at
+    /// the remote host), so there is no reason to blame the circuit.
+    /// With `arti-client`, just retry with the same
+    /// [`StreamPrefs`](https://docs.rs/arti-client/latest/arti_client/struct.StreamPrefs.html)
+    /// and isolation.
+    #[display("retry on the same circuit")]
+    RetrySameCircuit,
+
+    /// The operation might succeed if it is retried over a different circuit.
+    ///
+    /// The problem was with the circuit, or with a relay on it, or somewhere
+    /// else in the Tor network.  With `arti-client`, retry from an
+    /// [isolated client](https://docs.rs/arti-client/latest/arti_client/struct.TorClient.html#method.isolated_client),
+    /// or with new isolation, so that a new circuit is built.
+    #[display("retry on a new circuit")]
+    RetryNewCircuit,
+
+    /// Retrying the operation is not expected to help.
+    ///
+    /// The request itself can't be satisfied (for example, the target address
+    /// is invalid, or the remote host doesn't exist), or the failure is a bug.
+    #[display("do not retry")]
+    DontRetry,
+
+    /// Retrying the operation will not help until the user (or the
+    /// application) fixes something.
+    ///
+    /// For example, the configuration might be invalid, the state directory
+    /// might be unreadable, or the system clock might be wrong.
+    #[display("user action needed")]
+    UserActionNeeded,
+}
+
+impl ErrorKind {
+    /// Return advice about how to retry an operation that failed with an
+    /// error of this kind.
+    ///
+    /// See [`RetryHint`].
+    pub fn retry_hint(&self) -> RetryHint {
+        use ErrorKind as EK;
+        use RetryHint as RH;
+        // Do not add a wildcard arm here: every new kind must be classified.
+        match self {
+            EK::RemoteStreamClosed
+            | EK::RemoteStreamReset
+            | EK::RemoteStreamError
+            | EK::RemoteNetworkTimeout
+            | EK::RemoteHostResolutionFailed => RH::RetrySameCircuit,
+
+            EK::TorAccessFailed
+            | EK::DirectoryExpired
+            | EK::LocalNetworkError
+            | EK::LocalResourceExhausted
+            | EK::RelayIdMismatch
+            | EK::CircuitCollapse
+            | EK::TorNetworkTimeout
+            | EK::TorDirectoryError
+            | EK::TorProtocolViolation
+            | EK::ExitPolicyRejected
+            | EK::ExitTimeout
+            | EK::RemoteNetworkFailed
+            | EK::OnionServiceNotRunning
+            | EK::OnionServiceConnectionFailed
+            | EK::RelayTooBusy
+            | EK::TransientFailure
+            | EK::CircuitRefused => RH::RetryNewCircuit,
+
+            EK::ReactorShuttingDown
+            | EK::ArtiShuttingDown
+            | EK::NotImplemented
+            | EK::FeatureDisabled
+            | EK::LocalProtocolViolation
+            | EK::RemoteConnectionRefused
+            | EK::RemoteHostNotFound
+            | EK::RemoteProtocolViolation
+            | EK::OnionServiceNotFound
+            | EK::OnionServiceProtocolViolation
+            | EK::OnionServiceAddressInvalid
+            | EK::InvalidStreamTarget
+            | EK::ForbiddenStreamTarget
+            | EK::BadApiUsage
+            | EK::NoPath
+            | EK::NoExit
+            | EK::Internal
+            | EK::Other => RH::DontRetry,
+
+            EK::BootstrapRequired
+            | EK::PersistentStateAccessFailed
+            | EK::LocalResourceAlreadyInUse
+            | EK::FsPermissions
+            | EK::PersistentStateCorrupted
+            | EK::CacheCorrupted
+            | EK::CacheAccessFailed
+            | EK::KeystoreCorrupted
+            | EK::KeystoreAccessFailed
+            | EK::SoftwareDeprecated
+            | EK::InvalidConfig
+            | EK::InvalidConfigTransition
+            | EK::NoHomeDirectory
+            | EK::ExternalToolFailed
+            | EK::OnionServiceMissingClientAuth
+            | EK::OnionServiceWrongClientAuth
+            | EK::TorDirectoryUnusable
+            | EK::ClockSkew => RH::UserActionNeeded,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn hints() {
+        use ErrorKind as EK;
+        use RetryHint as RH;
+        assert_eq!(EK::RemoteNetworkTimeout.retry_hint(), RH::RetrySameCircuit);
+        assert_eq!(EK::CircuitCollapse.retry_hint(), RH::RetryNewCircuit);
+        assert_eq!(EK::InvalidStreamTarget.retry_hint(), RH::DontRetry);
+        assert_eq!(EK::InvalidConfig.retry_hint(), RH::UserActionNeeded);
+        assert_eq!(RH::RetryNewCircuit.to_string(), "retry on a new circuit");
+    }
+
+    #[test]
+    fn codes() {
+        use ErrorKind as EK;
+        assert_eq!(EK::TorAccessFailed.code(), "TorAccessFailed");
+        assert_eq!(EK::RemoteHostNotFound.code(), "RemoteHostNotFound");
+        // The code is the same as the name that RPC uses, after `arti:`.
+        assert_eq!(EK::Internal.code(), format!("{:?}", EK::Internal));
+    }
+}
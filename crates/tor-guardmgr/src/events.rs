@@ -7,6 +7,7 @@ use crate::skew::SkewEstimate;
 use educe::Educe;
 use futures::{Stream, StreamExt};
 use tor_basic_utils::skip_fmt;
+use tor_linkspec::RelayIds;
 
 /// A stream of [`SkewEstimate`] events.
 ///
@@ -42,3 +43,37 @@ impl ClockSkewEvents {
         self.inner.borrow().clone()
     }
 }
+
+/// A stream of events telling us which guards are primary.
+///
+/// Each event is the complete list of our primary guards, most preferred
+/// first.  The first event is the current list; after that, there is an
+/// event whenever the list changes.
+///
+/// Note that this stream can be lossy: if the list changes several times
+/// before you read from it, you will only get the most recent list.
+#[derive(Clone, Educe)]
+#[educe(Debug)]
+pub struct PrimaryGuardEvents {
+    /// The `postage::watch::Receiver` that we're wrapping.
+    #[educe(Debug(method = "skip_fmt"))]
+    pub(crate) inner: postage::watch::Receiver<Vec<RelayIds>>,
+}
+
+impl Stream for PrimaryGuardEvents {
+    type Item = Vec<RelayIds>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+impl PrimaryGuardEvents {
+    /// Return our current primary guards, most preferred first.
+    pub fn get(&self) -> Vec<RelayIds> {
+        self.inner.borrow().clone()
+    }
+}
@@ -1,10 +1,13 @@
 //! Implement GuardFilter and related types.
 
-use tor_linkspec::ChanTarget;
+use serde::{Deserialize, Serialize};
+use tor_linkspec::{ChanTarget, ChannelMethod, HasAddrs as _, HasRelayIds as _, RelayIdSet};
 // TODO(nickm): Conceivably, this type should be exposed from a lower-level crate than
 // tor-netdoc.
 use tor_netdoc::types::policy::AddrPortPattern;
-use tor_relay_selection::{LowLevelRelayPredicate, RelayRestriction, RelaySelector, RelayUsage};
+use tor_relay_selection::{
+    LowLevelRelayPredicate, RelayExclusion, RelayRestriction, RelaySelector, RelayUsage,
+};
 
 /// An object specifying which relays are eligible to be guards.
 ///
@@ -32,6 +35,41 @@ enum SingleFilter {
     /// This list of patterns has "or" semantics: a guard is permitted by this filter
     /// if ANY pattern in this list permits one of the guard's addresses.
     ReachableAddrs(Vec<AddrPortPattern>),
+
+    /// A rule about which IP address families we may use, and in which order.
+    AddrFamily(AddrFamilyPolicy),
+
+    /// A set of relays that we have been told never to use.
+    ///
+    /// A guard is permitted by this filter if it has none of these identities,
+    /// and none of its addresses matches any of these patterns.
+    Unwanted {
+        /// The identities of the unwanted relays.
+        ids: RelayIdSet,
+        /// Patterns matching the addresses of unwanted relays.
+        addrs: Vec<AddrPortPattern>,
+    },
+}
+
+/// Which IP address families we should use when connecting directly to relays.
+///
+/// This only affects the first hop of our circuits (guards, fallback
+/// directories, and directly-connected bridges): we never connect directly to
+/// any other relay.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum AddrFamilyPolicy {
+    /// Use IPv4 or IPv6 addresses, in whatever order the relay lists them.
+    #[default]
+    Any,
+    /// Use IPv4 or IPv6 addresses, but try IPv6 addresses first.
+    PreferIpv6,
+    /// Only use IPv6 addresses.
+    ///
+    /// This is suitable for clients on IPv6-only networks.
+    /// Relays that do not advertise an IPv6 ORPort will not be used as guards.
+    Ipv6Only,
 }
 
 impl GuardFilter {
@@ -48,6 +86,38 @@ impl GuardFilter {
             .push(SingleFilter::ReachableAddrs(addrs.into_iter().collect()));
     }
 
+    /// Restrict this filter according to `policy`.
+    ///
+    /// Adding [`AddrFamilyPolicy::Any`] has no effect.
+    pub fn push_addr_family_policy(&mut self, policy: AddrFamilyPolicy) {
+        if policy != AddrFamilyPolicy::Any {
+            self.filters.push(SingleFilter::AddrFamily(policy));
+        }
+    }
+
+    /// Restrict this filter to forbid every relay with an identity in `ids`,
+    /// or with an address permitted by one of the patterns in `addrs`.
+    ///
+    /// Adding an empty set of identities and patterns has no effect.
+    pub fn push_unwanted_relays(
+        &mut self,
+        ids: RelayIdSet,
+        addrs: impl IntoIterator<Item = AddrPortPattern>,
+    ) {
+        let addrs: Vec<_> = addrs.into_iter().collect();
+        if !(ids.is_empty() && addrs.is_empty()) {
+            self.filters.push(SingleFilter::Unwanted { ids, addrs });
+        }
+    }
+
+    /// Return true if this filter only permits relays that can be reached
+    /// over IPv6.
+    pub(crate) fn requires_ipv6(&self) -> bool {
+        self.filters
+            .iter()
+            .any(|filt| matches!(filt, SingleFilter::AddrFamily(AddrFamilyPolicy::Ipv6Only)))
+    }
+
     /// Return true if this filter permits the provided `target`.
     pub(crate) fn permits<C: ChanTarget>(&self, target: &C) -> bool {
         self.filters.iter().all(|filt| filt.permits(target))
@@ -114,11 +184,23 @@ impl GuardFilter {
         //
         // But before we do that, we should let the rest of #504 settle.
         for filt in &self.filters {
-            selector.push_restriction(match filt {
+            match filt {
                 SingleFilter::ReachableAddrs(addrs) => {
-                    RelayRestriction::require_address(addrs.clone())
+                    selector.push_restriction(RelayRestriction::require_address(addrs.clone()));
+                }
+                SingleFilter::AddrFamily(AddrFamilyPolicy::Ipv6Only) => {
+                    selector.push_restriction(RelayRestriction::require_address(vec![
+                        AddrPortPattern::new_all_ipv6(),
+                    ]));
                 }
-            });
+                // Preferences don't restrict anything.
+                SingleFilter::AddrFamily(_) => {}
+                SingleFilter::Unwanted { ids, addrs } => {
+                    selector.push_restriction(
+                        RelayExclusion::exclude_unwanted(ids.clone(), addrs.clone()).into(),
+                    );
+                }
+            }
         }
     }
 }
@@ -140,6 +222,20 @@ impl SingleFilter {
                     }
                 })
             }
+            SingleFilter::AddrFamily(AddrFamilyPolicy::Ipv6Only) => {
+                let method = target.chan_method();
+                // Pluggable transports decide for themselves how to reach
+                // their targets, so we only restrict direct connections.
+                !method.is_direct() || method.addrs().iter().any(|addr| addr.is_ipv6())
+            }
+            SingleFilter::AddrFamily(_) => true,
+            SingleFilter::Unwanted { ids, addrs } => {
+                let unwanted_addr = target.chan_method().socket_addrs().is_some_and(|a| {
+                    a.iter()
+                        .any(|a| addrs.iter().any(|p| p.matches_sockaddr(a)))
+                });
+                !unwanted_addr && !target.identities().any(|id| ids.contains(id))
+            }
         }
     }
 
@@ -178,11 +274,45 @@ impl SingleFilter {
                     .into());
                 }
             }
+            SingleFilter::AddrFamily(policy) => {
+                let method = first_hop.chan_target_mut().chan_method_mut();
+                if !apply_addr_family_policy(*policy, method) {
+                    // (See comment above about why this check is necessary.)
+                    return Err(tor_error::internal!(
+                        "Tried to apply an IPv6-only filter to a guard without IPv6 addresses"
+                    )
+                    .into());
+                }
+            }
+            // We never use part of an unwanted relay: `permits` rejects the
+            // whole thing.
+            SingleFilter::Unwanted { .. } => {}
         }
         Ok(first_hop)
     }
 }
 
+/// Remove or reorder the addresses in `method` as required by `policy`.
+///
+/// Only direct connections are affected.  Return false if we removed every
+/// address.
+fn apply_addr_family_policy(policy: AddrFamilyPolicy, method: &mut ChannelMethod) -> bool {
+    match policy {
+        AddrFamilyPolicy::Ipv6Only if method.is_direct() => {
+            method.retain_addrs(|addr| addr.is_ipv6()).is_ok()
+        }
+        AddrFamilyPolicy::PreferIpv6 => {
+            if let ChannelMethod::Direct(addrs) = method {
+                // This sort is stable, so we keep the relay's order within
+                // each address family.
+                addrs.sort_by_key(|addr| !addr.is_ipv6());
+            }
+            true
+        }
+        _ => true,
+    }
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -200,6 +330,7 @@ mod test {
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
     use float_eq::assert_float_eq;
+    use tor_linkspec::{HasAddrs, HasRelayIds};
     use tor_netdir::testnet;
 
     #[test]
@@ -222,5 +353,79 @@ mod test {
             f
         };
         assert_float_eq!(net_1_only.frac_bw_permitted(&nd), 0.28, abs <= TOL);
+
+        // Nobody in the test network has an IPv6 address.
+        let ipv6_only = {
+            let mut f = GuardFilter::default();
+            f.push_addr_family_policy(AddrFamilyPolicy::Ipv6Only);
+            f
+        };
+        assert!(ipv6_only.requires_ipv6());
+        assert_float_eq!(ipv6_only.frac_bw_permitted(&nd), 0.0, abs <= TOL);
+        let prefer_ipv6 = {
+            let mut f = GuardFilter::default();
+            f.push_addr_family_policy(AddrFamilyPolicy::PreferIpv6);
+            f
+        };
+        assert!(!prefer_ipv6.requires_ipv6());
+        assert_float_eq!(prefer_ipv6.frac_bw_permitted(&nd), 1.0, abs <= TOL);
+
+        let mut any = GuardFilter::default();
+        any.push_addr_family_policy(AddrFamilyPolicy::Any);
+        assert!(any.is_unfiltered());
+
+        let mut none_unwanted = GuardFilter::default();
+        none_unwanted.push_unwanted_relays(RelayIdSet::new(), vec![]);
+        assert!(none_unwanted.is_unfiltered());
+        let net_1_unwanted = {
+            let mut f = GuardFilter::default();
+            f.push_unwanted_relays(RelayIdSet::new(), vec!["1.0.0.0/8:*".parse().unwrap()]);
+            f
+        };
+        assert_float_eq!(net_1_unwanted.frac_bw_permitted(&nd), 0.72, abs <= TOL);
+    }
+
+    #[test]
+    fn unwanted() {
+        let nd = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        let id_0: tor_linkspec::RelayId =
+            "$0000000000000000000000000000000000000000".parse().unwrap();
+        let mut f = GuardFilter::default();
+        f.push_unwanted_relays(
+            [id_0].into_iter().collect(),
+            vec!["1.0.0.0/8:*".parse().unwrap()],
+        );
+
+        for relay in nd.relays() {
+            let ip = relay.addrs()[0].ip();
+            let expected =
+                !(relay.has_identity(id_0.as_ref()) || ip == std::net::IpAddr::from([1, 0, 0, 3]));
+            assert_eq!(f.permits(&relay), expected);
+        }
+    }
+
+    #[test]
+    fn addr_family_policy() {
+        let v4: std::net::SocketAddr = "192.0.2.7:9001".parse().unwrap();
+        let v6: std::net::SocketAddr = "[2001:db8::7]:9001".parse().unwrap();
+
+        let mut m = ChannelMethod::Direct(vec![v4, v6]);
+        assert!(apply_addr_family_policy(AddrFamilyPolicy::Any, &mut m));
+        assert_eq!(m, ChannelMethod::Direct(vec![v4, v6]));
+        assert!(apply_addr_family_policy(
+            AddrFamilyPolicy::PreferIpv6,
+            &mut m
+        ));
+        assert_eq!(m, ChannelMethod::Direct(vec![v6, v4]));
+
+        let mut m = ChannelMethod::Direct(vec![v4, v6]);
+        assert!(apply_addr_family_policy(AddrFamilyPolicy::Ipv6Only, &mut m));
+        assert_eq!(m, ChannelMethod::Direct(vec![v6]));
+
+        let mut m = ChannelMethod::Direct(vec![v4]);
+        assert!(!apply_addr_family_policy(
+            AddrFamilyPolicy::Ipv6Only,
+            &mut m
+        ));
     }
 }
@@ -61,7 +61,7 @@ use std::sync::{Arc, Mutex, Weak};
 use std::time::{Duration, Instant, SystemTime};
 #[cfg(feature = "bridge-client")]
 use tor_error::internal;
-use tor_linkspec::{OwnedChanTarget, OwnedCircTarget, RelayId, RelayIdSet};
+use tor_linkspec::{OwnedChanTarget, OwnedCircTarget, RelayId, RelayIdSet, RelayIds};
 use tor_netdir::NetDirProvider;
 use tor_proto::ClockSkew;
 use tor_units::BoundedInt32;
@@ -71,7 +71,7 @@ use tor_config::{define_list_builder_accessors, define_list_builder_helper};
 use tor_config::{impl_not_auto_value, ReconfigureError};
 use tor_config::{impl_standard_builder, ExplicitOrAuto};
 use tor_netdir::{params::NetParameters, NetDir, Relay};
-use tor_persist::{DynStorageHandle, StateMgr};
+use tor_persist::{DynStorageHandle, Schema, StateMgr};
 use tor_rtcompat::Runtime;
 
 #[cfg(feature = "bridge-client")]
@@ -104,8 +104,8 @@ use oneshot_fused_workaround as oneshot;
 
 pub use config::GuardMgrConfig;
 pub use err::{GuardMgrConfigError, GuardMgrError, PickGuardError};
-pub use events::ClockSkewEvents;
-pub use filter::GuardFilter;
+pub use events::{ClockSkewEvents, PrimaryGuardEvents};
+pub use filter::{AddrFamilyPolicy, GuardFilter};
 pub use ids::FirstHopId;
 pub use pending::{GuardMonitor, GuardStatus, GuardUsable};
 pub use skew::SkewEstimate;
@@ -210,6 +210,13 @@ struct GuardMgrInner {
     /// changes in our estimated clock skew.
     recv_skew: events::ClockSkewEvents,
 
+    /// A sender object to publish changes in our primary guards.
+    send_primary: postage::watch::Sender<Vec<RelayIds>>,
+
+    /// A receiver object to hand out to observers who want to know about
+    /// changes in our primary guards.
+    recv_primary: events::PrimaryGuardEvents,
+
     /// A netdir provider that we can use for adding new guards when
     /// insufficient guards are available.
     ///
@@ -302,6 +309,10 @@ struct GuardSets {
 ///
 /// We used to store this in a different format in a filename called
 /// "default_guards" (before Arti 0.1.0).
+///
+/// The state is stored in a versioned record (see [`GuardSets::schema`]).
+/// Before that, it was stored bare, as it still is by older versions of
+/// Arti, which won't find any guards in a versioned record.
 const STORAGE_KEY: &str = "guards";
 
 /// A description of which circuits to retire because of a configuration change.
@@ -332,7 +343,8 @@ impl<R: Runtime> GuardMgr<R> {
         S: StateMgr + Send + Sync + 'static,
     {
         let (ctrl, rcv) = mpsc::unbounded();
-        let storage: DynStorageHandle<GuardSets> = state_mgr.create_handle(STORAGE_KEY);
+        let storage: DynStorageHandle<GuardSets> =
+            state_mgr.create_versioned_handle(STORAGE_KEY, GuardSets::schema());
         // TODO(nickm): We should do something about the old state in
         // `default_guards`.  Probably it would be best to delete it.  We could
         // try to migrate it instead, but that's beyond the stability guarantee
@@ -341,6 +353,10 @@ impl<R: Runtime> GuardMgr<R> {
 
         let (send_skew, recv_skew) = postage::watch::channel();
         let recv_skew = ClockSkewEvents { inner: recv_skew };
+        let (send_primary, recv_primary) = postage::watch::channel();
+        let recv_primary = PrimaryGuardEvents {
+            inner: recv_primary,
+        };
 
         let inner = Arc::new(Mutex::new(GuardMgrInner {
             guards: state,
@@ -354,6 +370,8 @@ impl<R: Runtime> GuardMgr<R> {
             storage,
             send_skew,
             recv_skew,
+            send_primary,
+            recv_primary,
             netdir_provider: None,
             #[cfg(feature = "bridge-client")]
             bridge_desc_provider: None,
@@ -702,6 +720,14 @@ impl<R: Runtime> GuardMgr<R> {
         inner.recv_skew.clone()
     }
 
+    /// Return a stream of events about which guards are primary.
+    ///
+    /// See [`PrimaryGuardEvents`] for details.
+    pub fn primary_guard_events(&self) -> PrimaryGuardEvents {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.recv_primary.clone()
+    }
+
     /// Ensure that the message queue is flushed before proceeding to
     /// the next step.  Used for testing.
     #[cfg(test)]
@@ -729,6 +755,14 @@ pub enum ExternalActivity {
 }
 
 impl GuardSets {
+    /// Return the schema with which we store our `GuardSets`.
+    ///
+    /// Add a migration here if this type ever changes in a way that
+    /// `#[serde(default)]` can't cover.
+    fn schema() -> Schema {
+        Schema::new()
+    }
+
     /// Return a reference to the currently active set of guards.
     ///
     /// (That's easy enough for now, since there is never more than one set of
@@ -892,6 +926,21 @@ impl GuardMgrInner {
             #[cfg(not(feature = "bridge-client"))]
             let _ = now;
         });
+        self.publish_primary_guards();
+    }
+
+    /// Tell anybody watching our primary guards about them, if they have
+    /// changed.
+    fn publish_primary_guards(&mut self) {
+        let primary: Vec<RelayIds> = self
+            .guards
+            .active_guards()
+            .primary_guard_ids()
+            .map(|id| id.0.clone())
+            .collect();
+        if *self.recv_primary.inner.borrow() != primary {
+            *self.send_primary.borrow_mut() = primary;
+        }
     }
 
     /// Replace our bridge configuration with the one from `new_config`.
@@ -925,6 +974,7 @@ impl GuardMgrInner {
             (_, true) => {
                 self.configured_bridges = Some(new_config.bridges().into());
                 self.guards.active_set = GuardSetSelector::Bridges;
+                self.warn_if_no_bridges_permitted();
             }
             (_, false) => {
                 self.configured_bridges = None;
@@ -1119,10 +1169,18 @@ impl GuardMgrInner {
             self.guards.active_set = new_choice;
 
             if frac_permitted < self.params.extreme_threshold {
-                warn!(
-                      "The number of guards permitted is smaller than the recommended minimum of {:.0}%.",
-                      self.params.extreme_threshold * 100.0,
-                );
+                if self.filter.requires_ipv6() {
+                    warn!(
+                        "We are configured to use only IPv6, but only {:.0}% of guard bandwidth is reachable over IPv6; the recommended minimum is {:.0}%.",
+                        frac_permitted * 100.0,
+                        self.params.extreme_threshold * 100.0,
+                    );
+                } else {
+                    warn!(
+                          "The number of guards permitted is smaller than the recommended minimum of {:.0}%.",
+                          self.params.extreme_threshold * 100.0,
+                    );
+                }
             }
         }
     }
@@ -1151,9 +1209,37 @@ impl GuardMgrInner {
     /// Replace the current GuardFilter with `filter`.
     fn set_filter(&mut self, filter: GuardFilter, wallclock: SystemTime, now: Instant) {
         self.filter = filter;
+        #[cfg(feature = "bridge-client")]
+        self.warn_if_no_bridges_permitted();
         self.update(wallclock, now);
     }
 
+    /// Log a warning if we are configured to use bridges, but our filter
+    /// doesn't permit any of them.
+    ///
+    /// (The usual case here is an IPv6-only client whose bridge lines
+    /// all have IPv4 addresses.)
+    #[cfg(feature = "bridge-client")]
+    fn warn_if_no_bridges_permitted(&self) {
+        let Some(bridges) = &self.configured_bridges else {
+            return;
+        };
+        if bridges.is_empty() || bridges.iter().any(|b| self.filter.permits(b)) {
+            return;
+        }
+        if self.filter.requires_ipv6() {
+            warn!(
+                "We are configured to use only IPv6, but none of our {} configured bridges has an IPv6 address. We will not be able to connect to the Tor network.",
+                bridges.len(),
+            );
+        } else {
+            warn!(
+                "None of our {} configured bridges is permitted by our reachable address configuration. We will not be able to connect to the Tor network.",
+                bridges.len(),
+            );
+        }
+    }
+
     /// Called when the circuit manager reports (via [`GuardMonitor`]) that
     /// a guard succeeded or failed.
     ///
@@ -1262,6 +1348,7 @@ impl GuardMgrInner {
         self.guards
             .active_guards_mut()
             .select_primary_guards(&self.params);
+        self.publish_primary_guards();
 
         // Some waiting request may just have become ready (usable or
         // not); we need to give them the information they're waiting
@@ -1467,6 +1554,7 @@ impl GuardMgrInner {
                 Some(univ),
             );
             if extended == ExtendedStatus::Yes {
+                this.publish_primary_guards();
                 match this.select_guard_once(usage, now) {
                     Ok(res) => return Some(res),
                     Err(e) => {
@@ -1900,13 +1988,57 @@ impl_not_auto_value!(VanguardMode);
 
 /// Vanguards configuration.
 #[derive(Debug, Default, Clone, Eq, PartialEq, derive_builder::Builder)]
-#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
 #[builder(derive(Debug, Serialize, Deserialize))]
 pub struct VanguardConfig {
     /// The kind of vanguards to use.
+    ///
+    /// If this is `auto`, we use the kind that the consensus asks for:
+    /// by default, lite vanguards, or full vanguards once we are running an
+    /// onion service.
     #[builder_field_attr(serde(default))]
     #[builder(default)]
     mode: ExplicitOrAuto<VanguardMode>,
+
+    /// The shortest time for which we keep each layer 2 vanguard.
+    ///
+    /// If unset, we use the `guard-hs-l2-lifetime-min` network parameter.
+    #[builder(
+        setter(custom),
+        field(type = "Option<Duration>", build = "self.l2_lifetime_min")
+    )]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    l2_lifetime_min: Option<Duration>,
+
+    /// The longest time for which we keep each layer 2 vanguard.
+    ///
+    /// If unset, we use the `guard-hs-l2-lifetime-max` network parameter.
+    #[builder(
+        setter(custom),
+        field(type = "Option<Duration>", build = "self.l2_lifetime_max")
+    )]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    l2_lifetime_max: Option<Duration>,
+
+    /// The shortest time for which we keep each layer 3 vanguard.
+    ///
+    /// If unset, we use the `guard-hs-l3-lifetime-min` network parameter.
+    #[builder(
+        setter(custom),
+        field(type = "Option<Duration>", build = "self.l3_lifetime_min")
+    )]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    l3_lifetime_min: Option<Duration>,
+
+    /// The longest time for which we keep each layer 3 vanguard.
+    ///
+    /// If unset, we use the `guard-hs-l3-lifetime-max` network parameter.
+    #[builder(
+        setter(custom),
+        field(type = "Option<Duration>", build = "self.l3_lifetime_max")
+    )]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    l3_lifetime_max: Option<Duration>,
 }
 
 impl VanguardConfig {
@@ -1914,12 +2046,89 @@ impl VanguardConfig {
     ///
     /// Returns the [`Default`] `VanguardMode`
     /// if the mode is [`Auto`](ExplicitOrAuto) or unspecified.
+    ///
+    /// (When vanguards are in use, the vanguard manager resolves an `Auto`
+    /// mode from the consensus instead.)
     pub fn mode(&self) -> VanguardMode {
         match self.mode {
             ExplicitOrAuto::Auto => Default::default(),
             ExplicitOrAuto::Explicit(mode) => mode,
         }
     }
+
+    /// Return the configured shortest lifetime of a layer 2 vanguard, if any.
+    pub fn l2_lifetime_min(&self) -> Option<Duration> {
+        self.l2_lifetime_min
+    }
+
+    /// Return the configured longest lifetime of a layer 2 vanguard, if any.
+    pub fn l2_lifetime_max(&self) -> Option<Duration> {
+        self.l2_lifetime_max
+    }
+
+    /// Return the configured shortest lifetime of a layer 3 vanguard, if any.
+    pub fn l3_lifetime_min(&self) -> Option<Duration> {
+        self.l3_lifetime_min
+    }
+
+    /// Return the configured longest lifetime of a layer 3 vanguard, if any.
+    pub fn l3_lifetime_max(&self) -> Option<Duration> {
+        self.l3_lifetime_max
+    }
+}
+
+impl VanguardConfigBuilder {
+    /// Keep each layer 2 vanguard for at least `lifetime`.
+    pub fn l2_lifetime_min(&mut self, lifetime: Duration) -> &mut Self {
+        self.l2_lifetime_min = Some(lifetime);
+        self
+    }
+
+    /// Keep each layer 2 vanguard for at most `lifetime`.
+    pub fn l2_lifetime_max(&mut self, lifetime: Duration) -> &mut Self {
+        self.l2_lifetime_max = Some(lifetime);
+        self
+    }
+
+    /// Keep each layer 3 vanguard for at least `lifetime`.
+    pub fn l3_lifetime_min(&mut self, lifetime: Duration) -> &mut Self {
+        self.l3_lifetime_min = Some(lifetime);
+        self
+    }
+
+    /// Keep each layer 3 vanguard for at most `lifetime`.
+    pub fn l3_lifetime_max(&mut self, lifetime: Duration) -> &mut Self {
+        self.l3_lifetime_max = Some(lifetime);
+        self
+    }
+
+    /// Check that the configured vanguard lifetimes are consistent.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        for (min_field, min, max_field, max) in [
+            (
+                "l2_lifetime_min",
+                self.l2_lifetime_min,
+                "l2_lifetime_max",
+                self.l2_lifetime_max,
+            ),
+            (
+                "l3_lifetime_min",
+                self.l3_lifetime_min,
+                "l3_lifetime_max",
+                self.l3_lifetime_max,
+            ),
+        ] {
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    return Err(ConfigBuildError::Inconsistent {
+                        fields: vec![min_field.into(), max_field.into()],
+                        problem: format!("{min_field} is greater than {max_field}"),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// The kind of vanguards to use.
@@ -2026,6 +2235,56 @@ mod test {
         });
     }
 
+    #[test]
+    fn upgrade_unversioned_state() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, statemgr, netdir) = init(rt.clone());
+            guardmgr.install_test_netdir(&netdir);
+            let (id, mon, usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+            mon.succeeded();
+            assert!(usable.await.unwrap());
+            guardmgr.flush_msg_queue().await;
+
+            // Store our guards the way we did before we used versioned records.
+            {
+                let inner = guardmgr.inner.lock().unwrap();
+                statemgr.store(STORAGE_KEY, &inner.guards).unwrap();
+            }
+            drop(guardmgr);
+
+            // A new guard manager can read them, and uses the same guard.
+            let guardmgr2 =
+                GuardMgr::new(rt.clone(), statemgr.clone(), &TestConfig::default()).unwrap();
+            guardmgr2.install_test_netdir(&netdir);
+            let (id2, _mon, _usable) = guardmgr2.select_guard(GuardUsage::default()).unwrap();
+            assert!(id2.same_relay_ids(&id));
+
+            // When it stores them again, they are in a versioned record.
+            guardmgr2.store_persistent_state().unwrap();
+            let raw: tor_persist::JsonValue = statemgr.load(STORAGE_KEY).unwrap().unwrap();
+            assert_eq!(raw["schema_version"], 0);
+            assert!(raw["value"]["default"].is_object());
+        });
+    }
+
+    #[test]
+    fn primary_guard_events() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt.clone());
+            let events = guardmgr.primary_guard_events();
+            assert!(events.get().is_empty());
+
+            guardmgr.install_test_netdir(&netdir);
+            // We asked for two primary guards in `init`.
+            let primary = events.get();
+            assert_eq!(primary.len(), 2);
+
+            // The guard we select is one of them.
+            let (id, _mon, _usable) = guardmgr.select_guard(GuardUsage::default()).unwrap();
+            assert!(primary.iter().any(|ids| id.same_relay_ids(ids)));
+        });
+    }
+
     #[test]
     fn simple_waiting() {
         // TODO(nickm): This test fails in rare cases; I suspect a
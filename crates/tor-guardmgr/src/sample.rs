@@ -571,6 +571,11 @@ impl GuardSet {
         self.primary_guards_invalidated = false;
     }
 
+    /// Return the identities of our primary guards, most preferred first.
+    pub(crate) fn primary_guard_ids(&self) -> impl Iterator<Item = &GuardId> {
+        self.primary.iter()
+    }
+
     /// Remove all guards which should expire `now`, according to the settings
     /// in `params`.
     pub(crate) fn expire_old_guards(&mut self, params: &GuardParams, now: SystemTime) {
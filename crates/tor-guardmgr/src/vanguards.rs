@@ -20,7 +20,7 @@ use postage::watch;
 use rand::RngCore;
 
 use tor_async_utils::PostageWatchSenderExt as _;
-use tor_config::ReconfigureError;
+use tor_config::{ExplicitOrAuto, ReconfigureError};
 use tor_error::{error_report, internal, into_internal};
 use tor_netdir::{DirEvent, NetDir, NetDirProvider, Timeliness};
 use tor_persist::{DynStorageHandle, StateMgr};
@@ -54,11 +54,17 @@ pub struct VanguardMgr<R: Runtime> {
 /// The mutable inner state of [`VanguardMgr`].
 struct Inner {
     /// The current vanguard parameters.
+    ///
+    /// These come from the consensus, with any vanguard lifetimes from
+    /// `config` applied on top.
     params: VanguardParams,
+    /// The current configuration.
+    config: VanguardConfig,
     /// Whether to use full, lite, or no vanguards.
     ///
-    // TODO(#1382): we should derive the mode from the
-    // vanguards-enabled and vanguards-hs-service consensus params.
+    /// If the configured mode is [`Auto`](ExplicitOrAuto::Auto), this is
+    /// derived from `params` and `has_onion_svc`;
+    /// see [`update_mode`](Inner::update_mode).
     mode: VanguardMode,
     /// The L2 and L3 vanguards.
     ///
@@ -96,9 +102,9 @@ struct Inner {
     vanguard_sets: VanguardSets,
     /// Whether we're running an onion service.
     ///
-    // TODO(#1382): This should be used for deciding whether to use the `vanguards_hs_service` or the
-    // `vanguards_enabled` [`NetParameter`](tor_netdir::params::NetParameters).
-    #[allow(unused)]
+    /// Decides whether an `Auto` mode follows the `vanguards-hs-service`
+    /// or the `vanguards-enabled` [`NetParameter`](tor_netdir::params::NetParameters).
+    /// Once set, this stays set.
     has_onion_svc: bool,
     /// A channel for sending VanguardConfig changes to the vanguard maintenance task.
     config_tx: watch::Sender<VanguardConfig>,
@@ -131,7 +137,7 @@ impl<R: Runtime> VanguardMgr<R> {
     {
         // Note: we start out with default vanguard params, but we adjust them
         // as soon as we obtain a NetDir (see Self::run_once()).
-        let params = VanguardParams::default();
+        let params = VanguardParams::default().with_config(config);
         let storage: DynStorageHandle<VanguardSets> = state_mgr.create_handle(STORAGE_KEY);
 
         let vanguard_sets = match storage.load()? {
@@ -153,13 +159,15 @@ impl<R: Runtime> VanguardMgr<R> {
         };
 
         let (config_tx, _config_rx) = watch::channel();
-        let inner = Inner {
+        let mut inner = Inner {
             params,
-            mode: config.mode(),
+            config: config.clone(),
+            mode: VanguardMode::Disabled,
             vanguard_sets,
             has_onion_svc,
             config_tx,
         };
+        inner.update_mode();
 
         Ok(Self {
             inner: RwLock::new(inner),
@@ -199,29 +207,54 @@ impl<R: Runtime> VanguardMgr<R> {
     }
 
     /// Replace the configuration in this `VanguardMgr` with the specified `config`.
+    ///
+    /// New vanguard lifetimes only apply to the vanguards that we select
+    /// from now on.
     pub fn reconfigure(&self, config: &VanguardConfig) -> Result<RetireCircuits, ReconfigureError> {
-        // TODO(#1382): abolish VanguardConfig and derive the mode from the VanguardParams
-        // and has_onion_svc instead.
-        //
-        // TODO(#1382): update has_onion_svc if the new config enables onion svc usage
-        //
-        // Perhaps we should always escalate to Full if we start running an onion service,
-        // but not decessarily downgrade to lite if we stop.
-        // See <https://gitlab.torproject.org/tpo/core/arti/-/merge_requests/2083#note_3018173>
         let mut inner = self.inner.write().expect("poisoned lock");
-        let new_mode = config.mode();
-        if new_mode != inner.mode {
-            inner.mode = new_mode;
+        if *config == inner.config {
+            return Ok(RetireCircuits::None);
+        }
+        inner.config = config.clone();
+        inner.params = inner.params.clone().with_config(config);
+        let mode_changed = inner.update_mode();
 
-            // Wake up the maintenance task to replenish the vanguard pools.
-            inner.config_tx.maybe_send(|_| config.clone());
+        // Wake up the maintenance task to apply the new configuration,
+        // and to replenish the vanguard pools.
+        inner.config_tx.maybe_send(|_| config.clone());
 
+        if mode_changed {
             Ok(RetireCircuits::All)
         } else {
             Ok(RetireCircuits::None)
         }
     }
 
+    /// Note that we are running an onion service.
+    ///
+    /// If the configured mode is `auto`, this makes us use the kind of vanguards
+    /// that the consensus asks onion services to use
+    /// (by default, full vanguards) for all our onion service circuits,
+    /// from now on.
+    ///
+    /// Returns true if this changed the [`mode`](VanguardMgr::mode):
+    /// if so, the caller should retire any onion service circuits
+    /// that it built before.
+    pub fn note_onion_service(&self) -> bool {
+        let mut inner = self.inner.write().expect("poisoned lock");
+        if inner.has_onion_svc {
+            return false;
+        }
+        inner.has_onion_svc = true;
+        let mode_changed = inner.update_mode();
+        if mode_changed {
+            // Wake up the maintenance task to replenish the vanguard pools.
+            let config = inner.config.clone();
+            *inner.config_tx.borrow_mut() = config;
+        }
+        mode_changed
+    }
+
     /// Return a [`Vanguard`] relay for use in the specified layer.
     ///
     /// The `neighbor_exclusion` must contain the relays that would neighbor this vanguard
@@ -452,6 +485,9 @@ impl<R: Runtime> VanguardMgr<R> {
     }
 
     /// Get the current [`VanguardMode`].
+    ///
+    /// If the configured mode is `auto`, this is the mode that the consensus
+    /// asks for: see [`note_onion_service`](VanguardMgr::note_onion_service).
     pub fn mode(&self) -> VanguardMode {
         self.inner.read().expect("poisoned lock").mode
     }
@@ -478,10 +514,14 @@ impl Inner {
         netdir: &Arc<NetDir>,
     ) -> Result<(), VanguardMgrError> {
         let params = VanguardParams::try_from(netdir.params())
-            .map_err(into_internal!("invalid NetParameters"))?;
+            .map_err(into_internal!("invalid NetParameters"))?
+            .with_config(&self.config);
 
         // Update our params with the new values.
         self.update_params(params.clone());
+        if self.update_mode() {
+            info!("Now using {} vanguards, as the consensus asks", self.mode);
+        }
 
         self.vanguard_sets.remove_unlisted(netdir);
 
@@ -507,6 +547,20 @@ impl Inner {
         self.params = new_params;
     }
 
+    /// Recompute our [`VanguardMode`] from our configuration, our params, and
+    /// whether we are running an onion service.
+    ///
+    /// Returns true if the mode changed.
+    fn update_mode(&mut self) -> bool {
+        let new_mode = match self.config.mode {
+            ExplicitOrAuto::Explicit(mode) => mode,
+            ExplicitOrAuto::Auto => self.params.mode(self.has_onion_svc),
+        };
+        let changed = new_mode != self.mode;
+        self.mode = new_mode;
+        changed
+    }
+
     /// Flush the vanguard sets to storage, if the mode is "vanguards-full".
     fn flush_to_storage(
         &self,
@@ -524,8 +578,8 @@ impl Inner {
 
 #[cfg(any(test, feature = "testing"))]
 use {
-    tor_config::ExplicitOrAuto, tor_netdir::testprovider::TestNetDirProvider,
-    tor_persist::TestingStateMgr, tor_rtmock::MockRuntime,
+    tor_netdir::testprovider::TestNetDirProvider, tor_persist::TestingStateMgr,
+    tor_rtmock::MockRuntime,
 };
 
 /// Helpers for tests involving vanguards
@@ -538,6 +592,7 @@ impl VanguardMgr<MockRuntime> {
     ) -> Result<Arc<VanguardMgr<MockRuntime>>, VanguardMgrError> {
         let config = VanguardConfig {
             mode: ExplicitOrAuto::Explicit(mode),
+            ..Default::default()
         };
         let statemgr = TestingStateMgr::new();
         let lock = statemgr.try_lock()?;
@@ -608,9 +663,9 @@ mod test {
     use std::{fmt, time};
 
     use set::TimeBoundVanguard;
-    use tor_config::ExplicitOrAuto;
 
     use super::*;
+    use crate::VanguardConfigBuilder;
 
     use tor_basic_utils::test_rng::testing_rng;
     use tor_linkspec::{HasRelayIds, RelayIds};
@@ -900,9 +955,8 @@ mod test {
 
     /// Switch the vanguard "mode" of the VanguardMgr to `mode`,
     /// by setting the vanguards-hs-service parameter.
-    //
-    // TODO(#1382): use this instead of switch_hs_mode_config.
-    #[allow(unused)]
+    ///
+    /// Only works for a `VanguardMgr` in `auto` mode, that is running an onion service.
     async fn switch_hs_mode(
         rt: &MockRuntime,
         vanguardmgr: &VanguardMgr<MockRuntime>,
@@ -926,6 +980,7 @@ mod test {
         let _ = vanguardmgr
             .reconfigure(&VanguardConfig {
                 mode: ExplicitOrAuto::Explicit(mode),
+                ..Default::default()
             })
             .unwrap();
 
@@ -1093,6 +1148,84 @@ mod test {
         });
     }
 
+    #[test]
+    fn auto_mode() {
+        MockRuntime::test_with_various(|rt| async move {
+            let statemgr = TestingStateMgr::new();
+            let lock = statemgr.try_lock().unwrap();
+            assert!(lock.held());
+            let vanguardmgr = Arc::new(
+                VanguardMgr::new(&VanguardConfig::default(), rt.clone(), statemgr, false).unwrap(),
+            );
+
+            let netdir =
+                construct_custom_netdir_with_params(|_, _, _| {}, ENABLE_FULL_VANGUARDS, None)
+                    .unwrap()
+                    .unwrap_if_sufficient()
+                    .unwrap();
+            let netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            let mut rng = testing_rng();
+            let exclusion = RelayExclusion::no_relays_excluded();
+
+            // We aren't running an onion service, so we follow vanguards-enabled.
+            assert_eq!(vanguardmgr.mode(), VanguardMode::Lite);
+            assert!(vanguardmgr
+                .select_vanguard(&mut rng, &netdir, Layer3, &exclusion)
+                .is_err());
+
+            // Once we are, we follow vanguards-hs-service.
+            assert!(vanguardmgr.note_onion_service());
+            assert!(!vanguardmgr.note_onion_service());
+            assert_eq!(vanguardmgr.mode(), VanguardMode::Full);
+            rt.progress_until_stalled().await;
+            assert!(vanguardmgr
+                .select_vanguard(&mut rng, &netdir, Layer3, &exclusion)
+                .is_ok());
+            // The full vanguard sets are persisted.
+            assert!(vanguardmgr.storage.load().unwrap().is_some());
+
+            switch_hs_mode(&rt, &vanguardmgr, &netdir_provider, VanguardMode::Lite).await;
+            switch_hs_mode(&rt, &vanguardmgr, &netdir_provider, VanguardMode::Full).await;
+        });
+    }
+
+    #[test]
+    fn configured_lifetimes() {
+        MockRuntime::test_with_various(|rt| async move {
+            let lifetime = Duration::from_secs(3600 * 100);
+            let config = VanguardConfigBuilder::default()
+                .mode(ExplicitOrAuto::Explicit(VanguardMode::Full))
+                .l2_lifetime_min(lifetime)
+                .l2_lifetime_max(lifetime)
+                .l3_lifetime_min(lifetime)
+                .l3_lifetime_max(lifetime)
+                .build()
+                .unwrap();
+            let statemgr = TestingStateMgr::new();
+            let lock = statemgr.try_lock().unwrap();
+            assert!(lock.held());
+            let vanguardmgr =
+                Arc::new(VanguardMgr::new(&config, rt.clone(), statemgr, false).unwrap());
+
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            let mut rng = testing_rng();
+            let exclusion = RelayExclusion::no_relays_excluded();
+            for layer in [Layer2, Layer3] {
+                let vanguard = vanguardmgr
+                    .select_vanguard(&mut rng, &netdir, layer, &exclusion)
+                    .unwrap();
+                let expiry = duration_until_expiry(
+                    &RelayIds::from_relay_ids(vanguard.relay()),
+                    &vanguardmgr,
+                    &rt,
+                    layer,
+                );
+                assert_eq!(expiry, lifetime);
+            }
+        });
+    }
+
     #[test]
     fn full_vanguards_persistence() {
         MockRuntime::test_with_various(|rt| async move {
@@ -1178,6 +1311,7 @@ mod test {
 
             let config = VanguardConfig {
                 mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                ..Default::default()
             };
 
             // The state file contains no vanguards
@@ -1242,6 +1376,7 @@ mod test {
         MockRuntime::test_with_various(|rt| async move {
             let config = VanguardConfig {
                 mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                ..Default::default()
             };
             let (statemgr, _dir) = state_dir_with_vanguards(INVALID_VANGUARDS_JSON);
             let res = VanguardMgr::new(&config, rt.clone(), statemgr, false);
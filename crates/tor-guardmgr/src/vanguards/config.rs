@@ -4,7 +4,7 @@ use std::time::Duration;
 
 use tor_netdir::params::NetParameters;
 
-use crate::VanguardMode;
+use crate::{VanguardConfig, VanguardMode};
 
 /// The default L2 pool size.
 const DEFAULT_L2_POOL_SIZE: usize = 4;
@@ -125,3 +125,123 @@ impl TryFrom<&NetParameters> for VanguardParams {
         })
     }
 }
+
+impl VanguardParams {
+    /// Return these parameters, with the vanguard lifetimes replaced by any
+    /// that are set in `config`.
+    ///
+    /// If only one end of a lifetime range is configured, and it falls
+    /// outside the range from the consensus, we move the other end to meet it.
+    pub(crate) fn with_config(mut self, config: &VanguardConfig) -> Self {
+        /// Replace `min` and `max` with `config_min` and `config_max`, if they are set.
+        fn override_lifetime(
+            min: &mut Duration,
+            max: &mut Duration,
+            config_min: Option<Duration>,
+            config_max: Option<Duration>,
+        ) {
+            if let Some(config_min) = config_min {
+                *min = config_min;
+                *max = (*max).max(config_min);
+            }
+            if let Some(config_max) = config_max {
+                *max = config_max;
+                *min = (*min).min(config_max);
+            }
+        }
+
+        override_lifetime(
+            &mut self.l2_lifetime_min,
+            &mut self.l2_lifetime_max,
+            config.l2_lifetime_min(),
+            config.l2_lifetime_max(),
+        );
+        override_lifetime(
+            &mut self.l3_lifetime_min,
+            &mut self.l3_lifetime_max,
+            config.l3_lifetime_min(),
+            config.l3_lifetime_max(),
+        );
+        self
+    }
+
+    /// Return the kind of vanguards that the consensus asks us to use.
+    ///
+    /// If we are running an onion service, that is the stronger of
+    /// `vanguards-enabled` and `vanguards-hs-service`.
+    pub(crate) fn mode(&self, has_onion_svc: bool) -> VanguardMode {
+        if has_onion_svc {
+            self.vanguards_enabled.max(self.vanguards_hs_service)
+        } else {
+            self.vanguards_enabled
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::VanguardConfigBuilder;
+
+    fn hours(n: u64) -> Duration {
+        Duration::from_secs(3600 * n)
+    }
+
+    #[test]
+    fn configured_lifetimes() {
+        let params = VanguardParams::default().with_config(&VanguardConfig::default());
+        assert_eq!(params.l2_lifetime_min(), DEFAULT_L2_GUARD_LIFETIME_MIN);
+        assert_eq!(params.l3_lifetime_max(), DEFAULT_L3_GUARD_LIFETIME_MAX);
+
+        let config = VanguardConfigBuilder::default()
+            .l2_lifetime_min(hours(48))
+            .l2_lifetime_max(hours(72))
+            .l3_lifetime_max(hours(12))
+            .build()
+            .unwrap();
+        let params = VanguardParams::default().with_config(&config);
+        assert_eq!(params.l2_lifetime_min(), hours(48));
+        assert_eq!(params.l2_lifetime_max(), hours(72));
+        assert_eq!(params.l3_lifetime_min(), DEFAULT_L3_GUARD_LIFETIME_MIN);
+        assert_eq!(params.l3_lifetime_max(), hours(12));
+
+        // A minimum above the consensus maximum raises the maximum.
+        let config = VanguardConfigBuilder::default()
+            .l3_lifetime_min(hours(100))
+            .build()
+            .unwrap();
+        let params = VanguardParams::default().with_config(&config);
+        assert_eq!(params.l3_lifetime_min(), hours(100));
+        assert_eq!(params.l3_lifetime_max(), hours(100));
+
+        let err = VanguardConfigBuilder::default()
+            .l2_lifetime_min(hours(2))
+            .l2_lifetime_max(hours(1))
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            tor_config::ConfigBuildError::Inconsistent { .. }
+        ));
+    }
+
+    #[test]
+    fn mode() {
+        let params = VanguardParams::default();
+        assert_eq!(params.mode(false), VanguardMode::Lite);
+        assert_eq!(params.mode(true), VanguardMode::Full);
+    }
+}
@@ -5,8 +5,9 @@ use std::time::Duration;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 use async_trait::async_trait;
 use educe::Educe;
@@ -39,6 +40,8 @@ use tor_hscrypto::pk::{HsBlindId, HsId, HsIdKey};
 use tor_hscrypto::RendCookie;
 use tor_linkspec::{CircTarget, HasRelayIds, OwnedCircTarget, RelayId};
 use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_memquota::cache::MemCache;
+use tor_memquota::{EnabledToken, HasMemoryCost};
 use tor_netdir::{NetDir, Relay};
 use tor_netdoc::doc::hsdesc::{HsDesc, IntroPointDesc};
 use tor_proto::circuit::{CircParameters, ClientCirc, MetaCellDisposition, MsgHandler};
@@ -88,15 +91,69 @@ macro_rules! ClientCirc { { $R:ty, $M:ty } => {
 // This type is actually crate-private, since it isn't re-exported, but it must
 // be `pub` because it appears as a default for a type parameter in HsClientConnector.
 pub struct Data {
-    /// The latest known onion service descriptor for this service.
-    desc: DataHsDesc,
+    /// Key under which the latest known onion service descriptor for this service
+    /// is kept in the connector's [`DescCache`].
+    desc: DescCacheKey,
     /// Information about the latest status of trying to connect to this service
     /// through each of its introduction points.
     ipts: DataIpts,
 }
 
-/// Part of `Data` that relates to the HS descriptor
-type DataHsDesc = Option<TimerangeBound<HsDesc>>;
+/// Cache of HS descriptors, shared by all the services a connector knows about
+///
+/// The cache participates in the memory quota system,
+/// so descriptors may be discarded under memory pressure;
+/// they will then be re-fetched when next needed.
+pub(crate) type DescCache = MemCache<DescCacheKey, CachedHsDesc>;
+
+/// Maximum number of HS descriptors to keep in a [`DescCache`]
+pub(crate) const DESC_CACHE_MAX_ENTRIES: usize = 1000;
+
+/// Key of the descriptor for a particular `Data` in the [`DescCache`]
+///
+/// Each `Data` gets a fresh key, so that descriptors are never shared
+/// between isolation groups, just as when they were stored in the `Data` itself.
+///
+/// When a `Data` is expired, its descriptor stays in the cache until it is
+/// evicted (least recently used first) or reclaimed.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub(crate) struct DescCacheKey(u64);
+
+impl Default for DescCacheKey {
+    fn default() -> Self {
+        /// Next key to hand out
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        DescCacheKey(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// An HS descriptor, as stored in a [`DescCache`]
+#[derive(Clone, Debug)]
+pub(crate) struct CachedHsDesc(Arc<TimerangeBound<HsDesc>>);
+
+impl CachedHsDesc {
+    /// Return the descriptor, if it is valid at `now`
+    fn valid_at(&self, now: &SystemTime) -> Option<&HsDesc> {
+        let bound: &TimerangeBound<HsDesc> = &self.0;
+        bound.as_ref().check_valid_at(now).ok()
+    }
+
+    /// Return the descriptor, which the caller has already checked is timely
+    fn assume_timely(&self) -> &HsDesc {
+        let bound: &TimerangeBound<HsDesc> = &self.0;
+        bound.as_ref().dangerously_assume_timely()
+    }
+}
+
+impl HasMemoryCost for CachedHsDesc {
+    fn memory_cost(&self, _: EnabledToken) -> usize {
+        // This is an underestimate, since it ignores the heap allocations
+        // inside each introduction point; but those are small and bounded.
+        let intro_points = self.assume_timely().intro_points().len();
+        std::mem::size_of::<HsDesc>()
+            .saturating_add(intro_points.saturating_mul(std::mem::size_of::<IntroPointDesc>()))
+    }
+}
 
 /// Part of `Data` that relates to our information about introduction points
 type DataIpts = HashMap<RelayIdForExperience, IptExperience>;
@@ -158,6 +215,7 @@ pub(crate) async fn connect<R: Runtime>(
     Context::new(
         &connector.runtime,
         &*connector.circpool,
+        &connector.desc_cache,
         netdir,
         config,
         hsid,
@@ -181,6 +239,8 @@ struct Context<'c, R: Runtime, M: MocksForConnect<R>> {
     runtime: &'c R,
     /// Circpool
     circpool: &'c M::HsCircPool,
+    /// Cache of HS descriptors
+    desc_cache: &'c DescCache,
     /// Netdir
     //
     // TODO holding onto the netdir for the duration of our attempts is not ideal
@@ -359,6 +419,7 @@ impl<'c, R: Runtime, M: MocksForConnect<R>> Context<'c, R, M> {
     fn new(
         runtime: &'c R,
         circpool: &'c M::HsCircPool,
+        desc_cache: &'c DescCache,
         netdir: Arc<NetDir>,
         config: Arc<Config>,
         hsid: HsId,
@@ -384,6 +445,7 @@ impl<'c, R: Runtime, M: MocksForConnect<R>> Context<'c, R, M> {
             hs_blind_id,
             subcredential,
             circpool,
+            desc_cache,
             runtime,
             secret_keys,
             mocks,
@@ -412,7 +474,9 @@ impl<'c, R: Runtime, M: MocksForConnect<R>> Context<'c, R, M> {
 
         let mocks = self.mocks.clone();
 
-        let desc = self.descriptor_ensure(&mut data.desc).await?;
+        let desc = self.descriptor_ensure(data.desc).await?;
+        // descriptor_ensure has checked that the descriptor is timely.
+        let desc = desc.assume_timely();
 
         mocks.test_got_desc(desc);
 
@@ -422,16 +486,16 @@ impl<'c, R: Runtime, M: MocksForConnect<R>> Context<'c, R, M> {
         Ok(circ)
     }
 
-    /// Ensure that `Data.desc` contains the HS descriptor
+    /// Ensure that the descriptor cache contains the HS descriptor under `key`
     ///
     /// If we have a previously-downloaded descriptor, which is still valid,
-    /// just returns a reference to it.
+    /// just returns it.
     ///
     /// Otherwise, tries to obtain the descriptor by downloading it from hsdir(s).
     ///
     /// Does all necessary retries and timeouts.
     /// Returns an error if no valid descriptor could be found.
-    async fn descriptor_ensure<'d>(&self, data: &'d mut DataHsDesc) -> Result<&'d HsDesc, CE> {
+    async fn descriptor_ensure(&self, key: DescCacheKey) -> Result<CachedHsDesc, CE> {
         // Maximum number of hsdir connection and retrieval attempts we'll make
         let max_total_attempts = self
             .config
@@ -453,19 +517,13 @@ impl<'c, R: Runtime, M: MocksForConnect<R>> Context<'c, R, M> {
         // When it expires, we discard it completely and try to obtain a new one.
         //   https://gitlab.torproject.org/tpo/core/arti/-/issues/913#note_2914448
         // TODO SPEC: Discuss HS descriptor lifetime and expiry client behaviour
-        if let Some(previously) = data {
+        if let Some(previously) = self.desc_cache.get(&key) {
             let now = self.runtime.wallclock();
-            if let Ok(_desc) = previously.as_ref().check_valid_at(&now) {
-                // Ideally we would just return desc but that confuses borrowck.
-                // https://github.com/rust-lang/rust/issues/51545
-                return Ok(data
-                    .as_ref()
-                    .expect("Some but now None")
-                    .as_ref()
-                    .check_valid_at(&now)
-                    .expect("Ok but now Err"));
+            if previously.valid_at(&now).is_some() {
+                return Ok(previously);
             }
             // Seems to be not valid now.  Try to fetch a fresh one.
+            let _: Option<CachedHsDesc> = self.desc_cache.remove(&key);
         }
 
         let hs_dirs = self.netdir.hs_dirs_download(
@@ -523,17 +581,13 @@ impl<'c, R: Runtime, M: MocksForConnect<R>> Context<'c, R, M> {
             }
         };
 
-        // Store the bounded value in the cache for reuse,
-        // but return a reference to the unwrapped `HsDesc`.
+        // Store the bounded value in the cache for reuse.
         //
-        // The `HsDesc` must be owned by `data.desc`,
-        // so first add it to `data.desc`,
-        // and then dangerously_assume_timely to get a reference out again.
-        //
-        // It is safe to dangerously_assume_timely,
+        // Our caller may assume that it is timely,
         // as descriptor_fetch_attempt has already checked the timeliness of the descriptor.
-        let ret = data.insert(desc);
-        Ok(ret.as_ref().dangerously_assume_timely())
+        let desc = CachedHsDesc(Arc::new(desc));
+        self.desc_cache.insert(key, desc.clone());
+        Ok(desc)
     }
 
     /// Make one attempt to fetch the descriptor from a specific hsdir
@@ -1522,8 +1576,10 @@ mod test {
     use tor_basic_utils::test_rng::{testing_rng, TestingRng};
     use tor_hscrypto::pk::{HsClientDescEncKey, HsClientDescEncKeypair};
     use tor_llcrypto::pk::curve25519;
+    use tor_memquota::{cache::CachePool, MemoryQuotaTracker};
     use tor_netdoc::doc::{hsdesc::test_data, netstatus::Lifetime};
     use tor_rtcompat::tokio::TokioNativeTlsRuntime;
+    use tor_rtcompat::DynTimeProvider;
     use tor_rtcompat::RuntimeSubstExt as _;
     #[allow(deprecated)] // TODO #1885
     use tor_rtmock::time::MockSleepProvider;
@@ -1668,9 +1724,18 @@ mod test {
         secret_keys_builder.ks_hsc_desc_enc(HsClientDescEncKeypair::new(pk.clone(), sk));
         let secret_keys = secret_keys_builder.build().unwrap();
 
+        let desc_cache = DescCache::new(
+            &CachePool::new(
+                MemoryQuotaTracker::new_noop(),
+                DynTimeProvider::new(runtime.clone()),
+            ),
+            "hs descriptors",
+            DESC_CACHE_MAX_ENTRIES,
+        );
         let ctx = Context::new(
             &runtime,
             &mocks,
+            &desc_cache,
             netdir,
             Default::default(),
             hsid,
@@ -1711,7 +1776,7 @@ mod test {
         );
 
         // Check how long the descriptor is valid for
-        let (start_time, end_time) = data.desc.as_ref().unwrap().bounds();
+        let (start_time, end_time) = desc_cache.get(&data.desc).unwrap().0.bounds();
         assert_eq!(start_time, None);
 
         let desc_valid_until = humantime::parse_rfc3339("2023-02-11T20:00:00Z").unwrap();
@@ -66,6 +66,7 @@ use tor_circmgr::hspool::HsCircPool;
 use tor_circmgr::isolation::StreamIsolation;
 use tor_error::{internal, Bug};
 use tor_hscrypto::pk::HsId;
+use tor_memquota::cache::CachePool;
 use tor_netdir::NetDir;
 use tor_proto::circuit::ClientCirc;
 use tor_rtcompat::Runtime;
@@ -100,6 +101,10 @@ pub struct HsClientConnector<R: Runtime, D: state::MockableConnectorData = conne
     circpool: Arc<HsCircPool<R>>,
     /// Information we are remembering about different onion services.
     services: Arc<Mutex<state::Services<D>>>,
+    /// The descriptors of the onion services we have connected to.
+    ///
+    /// Only used with the real `connect::Data`.
+    desc_cache: connect::DescCache,
     /// For mocking in tests of `state.rs`
     mock_for_state: D::MockGlobalState,
 }
@@ -114,6 +119,9 @@ impl<R: Runtime> HsClientConnector<R, connect::Data> {
     ///
     /// Housekeeping events shouldn't arrive while we're dormant,
     /// since the housekeeping might involve processing that ought to be deferred.
+    ///
+    /// Downloaded descriptors are cached in `cache_pool`,
+    /// so that their memory use is tracked by the memory quota system.
     // This ^ is why we don't have a separate "launch background tasks" method.
     // It is fine for this background task to be launched pre-bootstrap, since it willp
     // do nothing until it gets events.
@@ -122,6 +130,7 @@ impl<R: Runtime> HsClientConnector<R, connect::Data> {
         circpool: Arc<HsCircPool<R>>,
         config: &impl HsClientConnectorConfig,
         housekeeping_prompt: BoxStream<'static, ()>,
+        cache_pool: &CachePool,
     ) -> Result<Self, StartupError> {
         let config = Config {
             retry: config.as_ref().clone(),
@@ -130,6 +139,11 @@ impl<R: Runtime> HsClientConnector<R, connect::Data> {
             runtime,
             circpool,
             services: Arc::new(Mutex::new(Services::new(config))),
+            desc_cache: connect::DescCache::new(
+                cache_pool,
+                "hs descriptors",
+                connect::DESC_CACHE_MAX_ENTRIES,
+            ),
             mock_for_state: (),
         };
         connector.spawn_housekeeping_task(housekeeping_prompt)?;
@@ -197,6 +211,24 @@ impl<R: Runtime, D: MockableConnectorData> HsClientConnector<R, D> {
             .map_err(|_| internal!("HS connector poisoned"))
     }
 
+    /// Change the configuration of this connector to `config`.
+    ///
+    /// Connection attempts that are already underway keep using the
+    /// configuration that they started with.
+    pub fn reconfigure(
+        &self,
+        config: &impl HsClientConnectorConfig,
+        how: tor_config::Reconfigure,
+    ) -> Result<(), tor_config::ReconfigureError> {
+        if how == tor_config::Reconfigure::CheckAllOrNothing {
+            return Ok(());
+        }
+        self.services()?.set_config(Config {
+            retry: config.as_ref().clone(),
+        });
+        Ok(())
+    }
+
     /// Spawn a task which watches `prompt` and calls [`Services::run_housekeeping`]
     fn spawn_housekeeping_task(
         &self,
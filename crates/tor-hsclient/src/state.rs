@@ -516,6 +516,13 @@ impl<D: MockableConnectorData> Services<D> {
         }
     }
 
+    /// Replace our configuration with `config`
+    ///
+    /// Connection tasks that are already running keep their old configuration.
+    pub(crate) fn set_config(&mut self, config: Config) {
+        self.config = Arc::new(config);
+    }
+
     /// Connect to a hidden service
     // We *do* drop guard.  There is *one* await point, just after drop(guard).
     pub(crate) async fn get_or_launch_connection(
@@ -745,15 +752,17 @@ pub(crate) mod test {
     #![allow(clippy::needless_pass_by_value)]
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
+    use crate::connect::{DescCache, DESC_CACHE_MAX_ENTRIES};
     use crate::*;
     use futures::{poll, SinkExt};
     use std::fmt;
     use std::task::Poll::{self, *};
     use tokio::pin;
     use tokio_crate as tokio;
-    use tor_memquota::ArcMemoryQuotaTrackerExt as _;
+    use tor_memquota::cache::CachePool;
+    use tor_memquota::{ArcMemoryQuotaTrackerExt as _, MemoryQuotaTracker};
     use tor_proto::memquota::ToplevelAccount;
-    use tor_rtcompat::{test_with_one_runtime, SleepProvider};
+    use tor_rtcompat::{test_with_one_runtime, DynTimeProvider, SleepProvider};
     use tor_rtmock::MockRuntime;
     use tracing_test::traced_test;
 
@@ -885,11 +894,16 @@ pub(crate) mod test {
         let circpool = Arc::new(HsCircPool::new(&circmgr));
         let (give_send, give) = postage::watch::channel_with(Ready(Ok(())));
         let mock_for_state = MockGlobalState { give };
+        let cache_pool = CachePool::new(
+            MemoryQuotaTracker::new_noop(),
+            DynTimeProvider::new(runtime.clone()),
+        );
         #[allow(clippy::let_and_return)] // we'll probably add more in this function
         let hscc = HsClientConnector {
             runtime,
             circpool,
             services: Default::default(),
+            desc_cache: DescCache::new(&cache_pool, "hs descriptors", DESC_CACHE_MAX_ENTRIES),
             mock_for_state,
         };
         let keys = HsClientSecretKeysBuilder::default().build().unwrap();
@@ -952,6 +966,40 @@ pub(crate) mod test {
         });
     }
 
+    #[test]
+    fn reconfigure() {
+        /// A configuration with only circuit timing.
+        struct TimingConfig(tor_circmgr::CircuitTiming);
+        impl AsRef<tor_circmgr::CircuitTiming> for TimingConfig {
+            fn as_ref(&self) -> &tor_circmgr::CircuitTiming {
+                &self.0
+            }
+        }
+        impl HsClientConnectorConfig for TimingConfig {}
+
+        test_with_one_runtime!(|runtime| async {
+            let (hsconn, _keys, _give_send) = mk_hsconn(runtime);
+            let retry = |hsconn: &HsClientConnector<_, MockData>| {
+                hsconn.services().unwrap().config.retry.clone()
+            };
+            let old = retry(&hsconn);
+            let new = tor_circmgr::CircuitTiming::builder()
+                .hs_desc_fetch_attempts(old.hs_desc_fetch_attempts() + 10)
+                .build()
+                .unwrap();
+            let config = TimingConfig(new.clone());
+
+            hsconn
+                .reconfigure(&config, tor_config::Reconfigure::CheckAllOrNothing)
+                .unwrap();
+            assert_eq!(retry(&hsconn), old);
+            hsconn
+                .reconfigure(&config, tor_config::Reconfigure::AllOrNothing)
+                .unwrap();
+            assert_eq!(retry(&hsconn), new);
+        });
+    }
+
     #[test]
     #[traced_test]
     fn expiry() {
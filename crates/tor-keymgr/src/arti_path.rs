@@ -5,7 +5,7 @@ use std::str::FromStr;
 use derive_deftly::{define_derive_deftly, Deftly};
 use derive_more::{Deref, Display, Into};
 use serde::{Deserialize, Serialize};
-use tor_persist::slug::{self, BadSlug};
+use tor_persist::slug::{self, BadSlug, SlugPath, SlugSeparator};
 
 use crate::{ArtiPathRange, ArtiPathSyntaxError, KeySpecifierComponent};
 
@@ -204,17 +204,12 @@ impl ArtiPath {
             return Ok(path);
         }
 
-        let path: String = [Ok(path.0)]
-            .into_iter()
-            .chain(
-                cert_denotators
-                    .iter()
-                    .map(|s| s.to_slug().map(|s| s.to_string())),
-            )
-            .collect::<Result<Vec<_>, _>>()?
-            .join(&DENOTATOR_SEP.to_string());
-
-        ArtiPath::new(path)
+        let mut path = SlugPath::parse(&path.0)?;
+        for denotator in cert_denotators {
+            path.push(SlugSeparator::Plus, &denotator.to_slug()?);
+        }
+
+        ArtiPath::new(path.into())
     }
 }
 
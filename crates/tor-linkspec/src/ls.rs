@@ -100,14 +100,18 @@ impl From<RelayId> for LinkSpec {
 impl LinkSpec {
     /// Helper: return the position in the list of identifiers
     /// in which a given linkspec should occur.
+    ///
+    /// This depends only on the type of the linkspec, so that an
+    /// `Unrecognized` linkspec with a known type sorts in the same place
+    /// as a recognized one.
     fn sort_pos(&self) -> u8 {
-        use LinkSpec::*;
-        match self {
-            OrPort(IpAddr::V4(_), _) => 0,
-            RsaId(_) => 1,
-            Ed25519Id(_) => 2,
-            OrPort(IpAddr::V6(_), _) => 3,
-            Unrecognized(n, _) => (*n).into(),
+        use LinkSpecType as LST;
+        match self.lstype() {
+            LST::ORPORT_V4 => 0,
+            LST::RSAID => 1,
+            LST::ED25519ID => 2,
+            LST::ORPORT_V6 => 3,
+            other => other.into(),
         }
     }
 
@@ -185,6 +189,104 @@ impl LinkSpec {
     }
 }
 
+/// A builder for the list of link specifiers in an EXTEND2 message.
+///
+/// The builder accepts link specifiers of any type, including ones that we
+/// don't recognize, and [`build`](LinkSpecListBuilder::build)s them into
+/// the order that tor-spec requires.
+/// Link specifiers that we can't parse are passed on with their type and
+/// body unchanged, so that re-encoding a list never drops them.
+///
+/// # Example
+///
+/// ```
+/// use tor_linkspec::{EncodedLinkSpec, LinkSpecListBuilder};
+/// use tor_llcrypto::pk::rsa::RsaIdentity;
+///
+/// let rsa = RsaIdentity::from_bytes(&[7; 20]).unwrap();
+/// let future = EncodedLinkSpec::new(77.into(), &b"something new"[..]);
+/// let encoded = LinkSpecListBuilder::new()
+///     .raw(future.clone())
+///     .rsa_id(rsa)
+///     .orport("192.0.2.1:9001".parse().unwrap())
+///     .build()
+///     .unwrap();
+/// assert_eq!(encoded.len(), 3);
+/// assert_eq!(encoded[2], future);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LinkSpecListBuilder {
+    /// The link specifiers that we have added so far.
+    specs: Vec<LinkSpec>,
+}
+
+impl LinkSpecListBuilder {
+    /// Create a new, empty `LinkSpecListBuilder`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an OR port address; this will be encoded as an IPv4 or IPv6
+    /// link specifier, depending on the address.
+    pub fn orport(&mut self, addr: SocketAddr) -> &mut Self {
+        self.push(addr.into())
+    }
+
+    /// Add an RSA identity.
+    pub fn rsa_id(&mut self, id: RsaIdentity) -> &mut Self {
+        self.push(id.into())
+    }
+
+    /// Add an Ed25519 identity.
+    pub fn ed25519_id(&mut self, id: ed25519::Ed25519Identity) -> &mut Self {
+        self.push(id.into())
+    }
+
+    /// Add a relay identity of any type.
+    pub fn relay_id(&mut self, id: RelayId) -> &mut Self {
+        self.push(id.into())
+    }
+
+    /// Add an already-parsed [`LinkSpec`].
+    pub fn push(&mut self, ls: LinkSpec) -> &mut Self {
+        self.specs.push(ls);
+        self
+    }
+
+    /// Add an encoded link specifier.
+    ///
+    /// If we can parse it, it is added as the corresponding [`LinkSpec`];
+    /// otherwise, it is kept as [`LinkSpec::Unrecognized`], and its body is
+    /// encoded exactly as we received it.
+    pub fn raw(&mut self, ls: EncodedLinkSpec) -> &mut Self {
+        let parsed = ls
+            .parse()
+            .unwrap_or_else(|_| LinkSpec::Unrecognized(ls.lstype, ls.body));
+        self.push(parsed)
+    }
+
+    /// Return the number of link specifiers that have been added.
+    pub fn len(&self) -> usize {
+        self.specs.len()
+    }
+
+    /// Return true if no link specifiers have been added.
+    pub fn is_empty(&self) -> bool {
+        self.specs.is_empty()
+    }
+
+    /// Encode the link specifiers, sorted into the order that tor-spec
+    /// requires.
+    ///
+    /// Link specifiers of the same type keep the order in which they
+    /// were added.
+    pub fn build(&self) -> EncodeResult<Vec<EncodedLinkSpec>> {
+        let mut specs = self.specs.clone();
+        LinkSpec::sort_by_type(&mut specs[..]);
+        specs.iter().map(LinkSpec::encode).collect()
+    }
+}
+
 /// An unparsed piece of information about a relay and how to connect to it.
 ///
 /// Unlike [`LinkSpec`], this can't be used directly; we only pass it on.
@@ -319,6 +421,46 @@ mod test {
         assert_eq!(t(&hex!("99 07 010203")), Error::new_incomplete_for_test(4));
     }
 
+    #[test]
+    fn test_list_builder() {
+        let rsa = RsaIdentity::from_bytes(&[7; 20]).unwrap();
+        let ed: ed25519::Ed25519Identity = [9; 32].into();
+        let v4: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let future = EncodedLinkSpec::new(77.into(), &b"strange"[..]);
+        // A known type, with a body that we can't parse.
+        let bad_rsa = EncodedLinkSpec::new(LinkSpecType::RSAID, &b"short"[..]);
+
+        let mut builder = LinkSpecListBuilder::new();
+        assert!(builder.is_empty());
+        builder
+            .raw(future.clone())
+            .orport(v6)
+            .ed25519_id(ed)
+            .raw(bad_rsa.clone())
+            .rsa_id(rsa)
+            .orport(v4);
+        assert_eq!(builder.len(), 6);
+
+        let encoded = builder.build().unwrap();
+        let expected = vec![
+            LinkSpec::from(v4).encode().unwrap(),
+            bad_rsa,
+            LinkSpec::from(rsa).encode().unwrap(),
+            LinkSpec::from(ed).encode().unwrap(),
+            LinkSpec::from(v6).encode().unwrap(),
+            future,
+        ];
+        assert_eq!(encoded, expected);
+
+        // Feeding the encoded list back in gives the same result.
+        let mut again = LinkSpecListBuilder::new();
+        for ls in &encoded {
+            again.raw(ls.clone());
+        }
+        assert_eq!(again.build().unwrap(), encoded);
+    }
+
     #[test]
     fn test_unparsed() {
         fn t(b: &[u8], val: &EncodedLinkSpec) {
@@ -334,13 +334,17 @@ pub trait CircTarget: ChanTarget {
     // of link specifiers, but that's not so easy to do, since it seems
     // doing so correctly would require default associated types.
     fn linkspecs(&self) -> tor_bytes::EncodeResult<Vec<crate::EncodedLinkSpec>> {
-        let mut result: Vec<_> = self.identities().map(|id| id.to_owned().into()).collect();
+        let mut builder = crate::LinkSpecListBuilder::new();
+        for id in self.identities() {
+            builder.relay_id(id.to_owned());
+        }
         #[allow(irrefutable_let_patterns)]
         if let ChannelMethod::Direct(addrs) = self.chan_method() {
-            result.extend(addrs.into_iter().map(crate::LinkSpec::from));
+            for addr in addrs {
+                builder.orport(addr);
+            }
         }
-        crate::LinkSpec::sort_by_type(&mut result[..]);
-        result.into_iter().map(|ls| ls.encode()).collect()
+        builder.build()
     }
     /// Return the ntor onion key for this relay
     fn ntor_onion_key(&self) -> &pk::curve25519::PublicKey;
@@ -8,7 +8,7 @@
 /// These ciphers implement the `cipher::StreamCipher` trait, so use
 /// the [`cipher`](https://docs.rs/cipher) crate to access them.
 #[cfg_attr(docsrs, doc(cfg(all())))]
-#[cfg(not(feature = "with-openssl"))]
+#[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
 pub mod aes {
     // These implement StreamCipher.
     /// AES128 in counter mode as used by Tor.
@@ -126,3 +126,118 @@ pub mod aes {
         }
     }
 }
+
+/// Compatibility layer between aws-lc-rs and `cipher::StreamCipher`.
+///
+/// These ciphers implement the `cipher::StreamCipher` trait, so use
+/// the [`cipher`](https://docs.rs/cipher) crate to access them.
+///
+/// (If `with-openssl` is enabled too, the OpenSSL versions are used instead.)
+#[cfg_attr(docsrs, doc(cfg(all())))]
+#[cfg(all(feature = "with-aws-lc", not(feature = "with-openssl")))]
+pub mod aes {
+    use aws_lc_rs::cipher::{
+        Algorithm, EncryptionContext, StreamingEncryptingKey, UnboundCipherKey, AES_128, AES_256,
+    };
+    use aws_lc_rs::iv::FixedLength;
+    use cipher::generic_array::GenericArray;
+    use cipher::inout::InOutBuf;
+    use cipher::{InnerIvInit, IvSizeUser, StreamCipher, StreamCipherError};
+    use digest::crypto_common::{InnerUser, KeyInit, KeySizeUser};
+    use zeroize::{Zeroize, ZeroizeOnDrop};
+
+    /// Define an AES counter-mode cipher and its key type, backed by aws-lc-rs.
+    macro_rules! aws_lc_aes_ctr {
+        ($name:ident, $key:ident, $alg:expr, $key_size:ty, $key_len:literal, $doc:literal, $key_doc:literal) => {
+            #[doc = $doc]
+            pub struct $name(StreamingEncryptingKey);
+
+            #[doc = $key_doc]
+            #[derive(Zeroize, ZeroizeOnDrop)]
+            pub struct $key([u8; $key_len]);
+
+            impl KeySizeUser for $key {
+                type KeySize = $key_size;
+            }
+
+            impl KeyInit for $key {
+                fn new(key: &GenericArray<u8, Self::KeySize>) -> Self {
+                    $key((*key).into())
+                }
+            }
+
+            impl InnerUser for $name {
+                type Inner = $key;
+            }
+
+            impl IvSizeUser for $name {
+                type IvSize = typenum::consts::U16;
+            }
+
+            impl StreamCipher for $name {
+                fn try_apply_keystream_inout(
+                    &mut self,
+                    buf: InOutBuf<'_, '_, u8>,
+                ) -> Result<(), StreamCipherError> {
+                    apply_keystream(&mut self.0, buf)
+                }
+            }
+
+            impl InnerIvInit for $name {
+                fn inner_iv_init(inner: Self::Inner, iv: &GenericArray<u8, Self::IvSize>) -> Self {
+                    $name(new_ctr($alg, &inner.0, (*iv).into()))
+                }
+            }
+        };
+    }
+
+    aws_lc_aes_ctr!(
+        Aes128Ctr,
+        Aes128Key,
+        &AES_128,
+        typenum::consts::U16,
+        16,
+        "AES 128 in counter mode as used by Tor.",
+        "AES 128 key"
+    );
+    aws_lc_aes_ctr!(
+        Aes256Ctr,
+        Aes256Key,
+        &AES_256,
+        typenum::consts::U32,
+        32,
+        "AES 256 in counter mode as used by Tor.",
+        "AES 256 key"
+    );
+
+    /// The length of an AES block.
+    const BLOCK_LEN: usize = 16;
+
+    /// Return a new counter-mode cipher for `alg`, with `key`, starting at `iv`.
+    fn new_ctr(alg: &'static Algorithm, key: &[u8], iv: [u8; 16]) -> StreamingEncryptingKey {
+        let key = UnboundCipherKey::new(alg, key).expect("aws-lc error while loading AES key");
+        let context = EncryptionContext::Iv128(FixedLength::from(iv));
+        StreamingEncryptingKey::less_safe_ctr(key, context)
+            .expect("aws-lc error while initializing AES-CTR")
+    }
+
+    /// Apply the next part of `cipher`'s keystream to `buf`.
+    fn apply_keystream(
+        cipher: &mut StreamingEncryptingKey,
+        mut buf: InOutBuf<'_, '_, u8>,
+    ) -> Result<(), StreamCipherError> {
+        // aws-lc-rs wants room for a whole extra block in the output, even
+        // though counter mode never buffers anything.
+        let in_buf = buf.get_in().to_vec();
+        let mut out_buf = vec![0_u8; in_buf.len() + BLOCK_LEN];
+        let update = cipher
+            .update(&in_buf, &mut out_buf)
+            .map_err(|_| StreamCipherError)?;
+        let written = update.written();
+        if written.len() != in_buf.len() {
+            return Err(StreamCipherError);
+        }
+        buf.get_out().copy_from_slice(written);
+        Ok(())
+    }
+}
@@ -6,41 +6,183 @@
 //!
 //! Other code should access these digests via the traits in the
 //! [`digest`] crate.
+//!
+//! With the `with-openssl` or `with-aws-lc` feature, SHA1, SHA256, and
+//! SHA512 are provided by OpenSSL or by aws-lc-rs rather than by RustCrypto.
+//! (If both features are enabled, OpenSSL is used.) These versions only
+//! implement the high-level [`digest::Digest`] API (and
+//! [`BlockSizeUser`](digest::core_api::BlockSizeUser)), so HMACs and HKDFs
+//! over them need the slower "simple" implementations from the `hmac` and
+//! `hkdf` crates. Use the [`Hmac`] and [`Hkdf`] aliases here, which pick
+//! whichever implementation works with our digests.
+//!
+//! SHA3 and SHAKE always come from RustCrypto.
+
+/// HMAC, as computed with one of our digests.
+///
+/// This is [`hmac::Hmac`], except with `with-openssl` or `with-aws-lc`,
+/// where it is [`hmac::SimpleHmac`].
+#[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
+pub type Hmac<D> = hmac::Hmac<D>;
+/// HMAC, as computed with one of our digests.
+///
+/// This is [`hmac::Hmac`], except with `with-openssl` or `with-aws-lc`,
+/// where it is [`hmac::SimpleHmac`].
+#[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+pub type Hmac<D> = hmac::SimpleHmac<D>;
 
+/// HKDF, as computed with one of our digests.
+///
+/// This is [`hkdf::Hkdf`], except with `with-openssl` or `with-aws-lc`,
+/// where it is [`hkdf::SimpleHkdf`].
+#[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
+pub type Hkdf<D> = hkdf::Hkdf<D>;
+/// HKDF, as computed with one of our digests.
+///
+/// This is [`hkdf::Hkdf`], except with `with-openssl` or `with-aws-lc`,
+/// where it is [`hkdf::SimpleHkdf`].
+#[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+pub type Hkdf<D> = hkdf::SimpleHkdf<D>;
+
+#[cfg(all(feature = "with-aws-lc", not(feature = "with-openssl")))]
+pub use aws_lc_compat::{Sha1, Sha256, Sha512};
 #[cfg(feature = "with-openssl")]
-pub use openssl_compat::Sha1;
-#[cfg(not(feature = "with-openssl"))]
+pub use openssl_compat::{Sha1, Sha256, Sha512};
+#[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
 pub use sha1::Sha1;
-
+#[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
 pub use sha2::{Sha256, Sha512};
+
 pub use sha3::{Sha3_256, Shake128, Shake256, Shake256Reader};
 
 /// Compatibility layer between OpenSSL and `digest`
 #[cfg(feature = "with-openssl")]
 mod openssl_compat {
-    use openssl::sha::Sha1 as OpenSslSha1;
-
+    use digest::core_api::BlockSizeUser;
     use digest::{FixedOutput, HashMarker, Output, OutputSizeUser, Update};
 
-    /// Wrapper around OpenSSL Sha1 to make it compatible with `digest`
-    #[derive(Default, Clone)]
-    pub struct Sha1(OpenSslSha1);
+    /// Define a wrapper around an OpenSSL digest, to make it compatible with `digest`.
+    macro_rules! openssl_digest {
+        ($name:ident, $openssl:ty, $size:ty, $block_size:ty, $doc:literal) => {
+            #[doc = $doc]
+            #[derive(Default, Clone)]
+            pub struct $name($openssl);
 
-    impl Update for Sha1 {
-        fn update(&mut self, data: &[u8]) {
-            self.0.update(data);
-        }
-    }
+            impl Update for $name {
+                fn update(&mut self, data: &[u8]) {
+                    self.0.update(data);
+                }
+            }
+
+            impl OutputSizeUser for $name {
+                type OutputSize = $size;
+            }
+
+            impl BlockSizeUser for $name {
+                type BlockSize = $block_size;
+            }
+
+            impl FixedOutput for $name {
+                fn finalize_into(self, out: &mut Output<Self>) {
+                    *out = self.0.finish().into();
+                }
+            }
 
-    impl OutputSizeUser for Sha1 {
-        type OutputSize = typenum::consts::U20;
+            impl HashMarker for $name {}
+        };
     }
 
-    impl FixedOutput for Sha1 {
-        fn finalize_into(self, out: &mut Output<Self>) {
-            *out = self.0.finish().into();
-        }
+    openssl_digest!(
+        Sha1,
+        openssl::sha::Sha1,
+        typenum::consts::U20,
+        typenum::consts::U64,
+        "Wrapper around OpenSSL Sha1 to make it compatible with `digest`"
+    );
+    openssl_digest!(
+        Sha256,
+        openssl::sha::Sha256,
+        typenum::consts::U32,
+        typenum::consts::U64,
+        "Wrapper around OpenSSL Sha256 to make it compatible with `digest`"
+    );
+    openssl_digest!(
+        Sha512,
+        openssl::sha::Sha512,
+        typenum::consts::U64,
+        typenum::consts::U128,
+        "Wrapper around OpenSSL Sha512 to make it compatible with `digest`"
+    );
+}
+
+/// Compatibility layer between aws-lc-rs and `digest`
+#[cfg(all(feature = "with-aws-lc", not(feature = "with-openssl")))]
+mod aws_lc_compat {
+    use aws_lc_rs::digest::{Algorithm, Context};
+    use digest::core_api::BlockSizeUser;
+    use digest::{FixedOutput, HashMarker, Output, OutputSizeUser, Update};
+
+    /// Define a wrapper around an aws-lc-rs digest, to make it compatible with `digest`.
+    macro_rules! aws_lc_digest {
+        ($name:ident, $alg:expr, $size:ty, $block_size:ty, $doc:literal) => {
+            #[doc = $doc]
+            #[derive(Clone)]
+            pub struct $name(Context);
+
+            impl $name {
+                /// The aws-lc-rs algorithm that we wrap.
+                const ALGORITHM: &'static Algorithm = $alg;
+            }
+
+            impl Default for $name {
+                fn default() -> Self {
+                    Self(Context::new(Self::ALGORITHM))
+                }
+            }
+
+            impl Update for $name {
+                fn update(&mut self, data: &[u8]) {
+                    self.0.update(data);
+                }
+            }
+
+            impl OutputSizeUser for $name {
+                type OutputSize = $size;
+            }
+
+            impl BlockSizeUser for $name {
+                type BlockSize = $block_size;
+            }
+
+            impl FixedOutput for $name {
+                fn finalize_into(self, out: &mut Output<Self>) {
+                    out.copy_from_slice(self.0.finish().as_ref());
+                }
+            }
+
+            impl HashMarker for $name {}
+        };
     }
 
-    impl HashMarker for Sha1 {}
+    aws_lc_digest!(
+        Sha1,
+        &aws_lc_rs::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        typenum::consts::U20,
+        typenum::consts::U64,
+        "Wrapper around aws-lc-rs SHA1 to make it compatible with `digest`"
+    );
+    aws_lc_digest!(
+        Sha256,
+        &aws_lc_rs::digest::SHA256,
+        typenum::consts::U32,
+        typenum::consts::U64,
+        "Wrapper around aws-lc-rs SHA256 to make it compatible with `digest`"
+    );
+    aws_lc_digest!(
+        Sha512,
+        &aws_lc_rs::digest::SHA512,
+        typenum::consts::U64,
+        typenum::consts::U128,
+        "Wrapper around aws-lc-rs SHA512 to make it compatible with `digest`"
+    );
 }
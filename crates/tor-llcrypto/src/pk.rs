@@ -5,6 +5,7 @@
 
 pub mod ed25519;
 pub mod keymanip;
+pub mod keypool;
 pub mod rsa;
 
 /// Re-exporting Curve25519 implementations.
@@ -12,6 +13,10 @@ pub mod rsa;
 /// *TODO*: Eventually we should probably recommend using this code via some
 /// key-agreement trait, but for now we are just re-using the APIs from
 /// [`x25519_dalek`].
+///
+/// With the `with-openssl` or `with-aws-lc` feature, public keys and shared
+/// secrets are computed by OpenSSL or aws-lc-rs instead. (Keys are still
+/// generated, and stored, by `x25519_dalek`.)
 pub mod curve25519 {
     use educe::Educe;
 
@@ -33,7 +38,17 @@ pub mod curve25519 {
     /// and that can never be inspected.
     ///
     /// See [`x25519_dalek::EphemeralSecret`] for more information.
-    pub struct EphemeralSecret(x25519_dalek::EphemeralSecret);
+    pub struct EphemeralSecret(EphemeralInner);
+
+    /// The key inside an [`EphemeralSecret`].
+    #[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
+    type EphemeralInner = x25519_dalek::EphemeralSecret;
+    /// The key inside an [`EphemeralSecret`].
+    ///
+    /// Our backends need the bytes of the key, which an
+    /// `x25519_dalek::EphemeralSecret` won't give them.
+    #[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+    type EphemeralInner = x25519_dalek::StaticSecret;
 
     /// A curve25519 secret key that can be used more than once,
     /// and whose value can be inspected.
@@ -60,18 +75,35 @@ pub mod curve25519 {
     /// A shared secret negotiated using curve25519.
     ///
     /// See [`x25519_dalek::SharedSecret`] for more information
-    pub struct SharedSecret(x25519_dalek::SharedSecret);
+    pub struct SharedSecret(SharedInner);
+
+    /// The value inside a [`SharedSecret`].
+    #[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
+    type SharedInner = x25519_dalek::SharedSecret;
+    /// The value inside a [`SharedSecret`].
+    #[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+    type SharedInner = backend::SharedBytes;
 
     impl<'a> From<&'a EphemeralSecret> for PublicKey {
+        #[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
         fn from(secret: &'a EphemeralSecret) -> Self {
             Self((&secret.0).into())
         }
+        #[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+        fn from(secret: &'a EphemeralSecret) -> Self {
+            backend::public_key(secret.0.as_bytes()).into()
+        }
     }
 
     impl<'a> From<&'a StaticSecret> for PublicKey {
+        #[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
         fn from(secret: &'a StaticSecret) -> Self {
             Self((&secret.0).into())
         }
+        #[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+        fn from(secret: &'a StaticSecret) -> Self {
+            backend::public_key(secret.0.as_bytes()).into()
+        }
     }
 
     impl From<[u8; 32]> for StaticSecret {
@@ -88,14 +120,21 @@ pub mod curve25519 {
     impl EphemeralSecret {
         /// Return a new random ephemeral secret key.
         pub fn random_from_rng<R: rand_core::RngCore + rand_core::CryptoRng>(csprng: R) -> Self {
-            Self(x25519_dalek::EphemeralSecret::random_from_rng(
-                RngCompat::new(csprng),
-            ))
+            Self(EphemeralInner::random_from_rng(RngCompat::new(csprng)))
         }
         /// Negotiate a shared secret using this secret key and a public key.
+        #[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
         pub fn diffie_hellman(self, their_public: &PublicKey) -> SharedSecret {
             SharedSecret(self.0.diffie_hellman(&their_public.0))
         }
+        /// Negotiate a shared secret using this secret key and a public key.
+        #[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+        pub fn diffie_hellman(self, their_public: &PublicKey) -> SharedSecret {
+            SharedSecret(backend::diffie_hellman(
+                self.0.as_bytes(),
+                their_public.as_bytes(),
+            ))
+        }
     }
     impl StaticSecret {
         /// Return a new random static secret key.
@@ -105,9 +144,18 @@ pub mod curve25519 {
             )))
         }
         /// Negotiate a shared secret using this secret key and a public key.
+        #[cfg(not(any(feature = "with-openssl", feature = "with-aws-lc")))]
         pub fn diffie_hellman(&self, their_public: &PublicKey) -> SharedSecret {
             SharedSecret(self.0.diffie_hellman(&their_public.0))
         }
+        /// Negotiate a shared secret using this secret key and a public key.
+        #[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+        pub fn diffie_hellman(&self, their_public: &PublicKey) -> SharedSecret {
+            SharedSecret(backend::diffie_hellman(
+                self.0.as_bytes(),
+                their_public.as_bytes(),
+            ))
+        }
         /// Return the bytes that represent this key.
         pub fn to_bytes(&self) -> [u8; 32] {
             self.0.to_bytes()
@@ -135,6 +183,99 @@ pub mod curve25519 {
             self.0.to_bytes()
         }
     }
+
+    /// Curve25519 computations done by OpenSSL or aws-lc-rs.
+    #[cfg(any(feature = "with-openssl", feature = "with-aws-lc"))]
+    mod backend {
+        use zeroize::{Zeroize, ZeroizeOnDrop};
+
+        /// A shared secret, as computed by our backend.
+        #[derive(Zeroize, ZeroizeOnDrop)]
+        pub(super) struct SharedBytes([u8; 32]);
+
+        impl SharedBytes {
+            /// Return the shared secret as an array of bytes.
+            pub(super) fn as_bytes(&self) -> &[u8; 32] {
+                &self.0
+            }
+            /// Return true if both keys contributed to this shared secret,
+            /// that is, if it is not all zeros.
+            pub(super) fn was_contributory(&self) -> bool {
+                !crate::util::ct::is_zero(&self.0)
+            }
+        }
+
+        /// Return the public key for the secret key `secret`.
+        #[cfg(feature = "with-openssl")]
+        pub(super) fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+            use openssl::pkey::{Id, PKey};
+
+            let key = PKey::private_key_from_raw_bytes(secret, Id::X25519)
+                .expect("openssl error while loading X25519 key");
+            key.raw_public_key()
+                .expect("openssl error while computing X25519 public key")
+                .try_into()
+                .expect("X25519 public key of the wrong length")
+        }
+
+        /// Return the shared secret for `secret` and `their_public`.
+        ///
+        /// OpenSSL refuses to compute a shared secret that is all zeros (as
+        /// happens for a low-order public key), so we return all zeros if it
+        /// fails, to make [`SharedBytes::was_contributory`] return false.
+        #[cfg(feature = "with-openssl")]
+        pub(super) fn diffie_hellman(secret: &[u8; 32], their_public: &[u8; 32]) -> SharedBytes {
+            use openssl::derive::Deriver;
+            use openssl::pkey::{Id, PKey};
+
+            let derive = || -> Result<SharedBytes, openssl::error::ErrorStack> {
+                let key = PKey::private_key_from_raw_bytes(secret, Id::X25519)?;
+                let peer = PKey::public_key_from_raw_bytes(their_public, Id::X25519)?;
+                let mut deriver = Deriver::new(&key)?;
+                deriver.set_peer(&peer)?;
+                let mut shared = SharedBytes([0; 32]);
+                deriver.derive(&mut shared.0)?;
+                Ok(shared)
+            };
+            derive().unwrap_or(SharedBytes([0; 32]))
+        }
+
+        /// Return the public key for the secret key `secret`.
+        #[cfg(all(feature = "with-aws-lc", not(feature = "with-openssl")))]
+        pub(super) fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+            use aws_lc_rs::agreement::{PrivateKey, X25519};
+
+            let key = PrivateKey::from_private_key(&X25519, secret)
+                .expect("aws-lc error while loading X25519 key");
+            key.compute_public_key()
+                .expect("aws-lc error while computing X25519 public key")
+                .as_ref()
+                .try_into()
+                .expect("X25519 public key of the wrong length")
+        }
+
+        /// Return the shared secret for `secret` and `their_public`.
+        ///
+        /// aws-lc-rs refuses to compute a shared secret that is all zeros (as
+        /// happens for a low-order public key), so we return all zeros if it
+        /// fails, to make [`SharedBytes::was_contributory`] return false.
+        #[cfg(all(feature = "with-aws-lc", not(feature = "with-openssl")))]
+        pub(super) fn diffie_hellman(secret: &[u8; 32], their_public: &[u8; 32]) -> SharedBytes {
+            use aws_lc_rs::agreement::{agree, PrivateKey, UnparsedPublicKey, X25519};
+            use aws_lc_rs::error::Unspecified;
+
+            let derive = || -> Result<SharedBytes, Unspecified> {
+                let key = PrivateKey::from_private_key(&X25519, secret)?;
+                let peer = UnparsedPublicKey::new(&X25519, their_public);
+                agree(&key, &peer, Unspecified, |shared| {
+                    let mut out = SharedBytes([0; 32]);
+                    out.0.copy_from_slice(shared);
+                    Ok(out)
+                })
+            };
+            derive().unwrap_or(SharedBytes([0; 32]))
+        }
+    }
 }
 
 /// A type for a validatable signature.
@@ -186,6 +327,38 @@ pub fn validate_all_sigs(v: &[Box<dyn ValidatableSignature>]) -> bool {
     ed_batch_is_valid && non_ed_sigs.iter().all(|b| b.is_valid())
 }
 
+/// Find out which of the signatures in this slice are invalid.
+///
+/// Return the indices (within `v`) of the invalid signatures, in order.
+/// If every signature is valid, the result is empty.
+///
+/// Like [`validate_all_sigs`], this batch-verifies the Ed25519 signatures;
+/// if the batch fails, it checks them one at a time to find the bad ones.
+/// (See [`ed25519::find_invalid`] for details.)
+pub fn find_invalid_sigs(v: &[Box<dyn ValidatableSignature>]) -> Vec<usize> {
+    let mut ed_sigs = Vec::new();
+    let mut ed_idxs = Vec::new();
+    let mut invalid = Vec::new();
+    for (idx, sig) in v.iter().enumerate() {
+        match sig.as_ed25519() {
+            Some(ed_sig) => {
+                ed_sigs.push(ed_sig);
+                ed_idxs.push(idx);
+            }
+            None if !sig.is_valid() => invalid.push(idx),
+            None => {}
+        }
+    }
+
+    invalid.extend(
+        crate::pk::ed25519::find_invalid(&ed_sigs[..])
+            .into_iter()
+            .map(|i| ed_idxs[i]),
+    );
+    invalid.sort_unstable();
+    invalid
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -509,6 +509,29 @@ pub fn validate_batch(sigs: &[&ValidatableEd25519Signature]) -> bool {
     }
 }
 
+/// Verify the provided signatures as a batch, and if any of them are invalid,
+/// find out which.
+///
+/// Return the indices (within `sigs`) of the invalid signatures, in order.
+/// If every signature is valid, the result is empty.
+///
+/// This first tries [`validate_batch`], which is fast when all the signatures
+/// are valid, as they usually are. If the batch fails, it falls back to
+/// checking each signature on its own. In that case, the individual checks
+/// decide: a signature that passes on its own is not reported as invalid,
+/// even if it made the batch fail.
+pub fn find_invalid(sigs: &[&ValidatableEd25519Signature]) -> Vec<usize> {
+    use crate::pk::ValidatableSignature;
+    if validate_batch(sigs) {
+        return Vec::new();
+    }
+    sigs.iter()
+        .enumerate()
+        .filter(|(_, sig)| !sig.is_valid())
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
 /// An object that has an Ed25519 [`PublicKey`].
 pub trait Ed25519PublicKey {
     /// Get the Ed25519 [`PublicKey`].
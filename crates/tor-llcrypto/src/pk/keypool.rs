@@ -0,0 +1,243 @@
+//! A pool of pregenerated ephemeral curve25519 keypairs.
+//!
+//! Every circuit extension needs a fresh curve25519 keypair for its ntor
+//! handshake. Generating one takes a scalar multiplication, which is part of
+//! the latency of building a circuit. An [`X25519KeyPool`] moves that work
+//! off the critical path: it keeps a supply of keypairs that were generated
+//! ahead of time, optionally by a background thread, so that a handshake can
+//! just take one.
+//!
+//! Pooled secret keys are zeroized when they are dropped, whether they are
+//! used or discarded. Keys that have waited in the pool for longer than its
+//! `max_age` are discarded rather than used, so that a key that has been
+//! sitting in memory for a long time is never used.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+// `std::time::Instant::now` panics on `wasm32-unknown-unknown`.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::Instant;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+use web_time::Instant;
+
+use super::curve25519::{PublicKey, StaticKeypair, StaticSecret};
+
+/// A keypair in the pool, along with when it was generated.
+struct PooledKey {
+    /// The keypair.
+    keypair: StaticKeypair,
+    /// When we generated it.
+    created: Instant,
+}
+
+/// The mutable state of an [`X25519KeyPool`].
+struct PoolState {
+    /// The keys that are ready for use, oldest first.
+    keys: VecDeque<PooledKey>,
+}
+
+/// A pool of pregenerated ephemeral curve25519 keypairs.
+///
+/// See the [module documentation](self) for details.
+pub struct X25519KeyPool {
+    /// The keys in the pool.
+    state: Mutex<PoolState>,
+    /// Signalled when a key is taken, to wake up the refill thread.
+    taken: Condvar,
+    /// The number of keys that we try to keep in the pool.
+    capacity: usize,
+    /// The longest that a key may wait in the pool before we discard it.
+    max_age: Duration,
+}
+
+impl X25519KeyPool {
+    /// Create a new, empty pool that holds up to `capacity` keys, each for at
+    /// most `max_age`.
+    ///
+    /// The pool is not refilled unless you call [`refill`](Self::refill).
+    /// Use [`launch`](Self::launch) for a pool that refills itself.
+    pub fn new(capacity: usize, max_age: Duration) -> Arc<Self> {
+        Arc::new(X25519KeyPool {
+            state: Mutex::new(PoolState {
+                keys: VecDeque::with_capacity(capacity),
+            }),
+            taken: Condvar::new(),
+            capacity,
+            max_age,
+        })
+    }
+
+    /// Create a new pool, as with [`new`](Self::new), and launch a
+    /// background thread to keep it full.
+    ///
+    /// The thread exits soon after the pool is dropped.
+    pub fn launch(capacity: usize, max_age: Duration) -> std::io::Result<Arc<Self>> {
+        let pool = Self::new(capacity, max_age);
+        let weak = Arc::downgrade(&pool);
+        std::thread::Builder::new()
+            .name("arti-x25519-pool".into())
+            .spawn(move || refill_loop(&weak))?;
+        Ok(pool)
+    }
+
+    /// Return the number of keys that this pool tries to hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return the number of keys currently in the pool, including any that
+    /// have gone stale but haven't been discarded yet.
+    pub fn len(&self) -> usize {
+        self.lock().keys.len()
+    }
+
+    /// Return true if the pool has no keys in it.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take a fresh keypair from the pool, if there is one.
+    ///
+    /// Stale keys are discarded along the way. Returns `None` if the pool has
+    /// no fresh keys: the caller should generate a keypair itself.
+    pub fn take(&self) -> Option<StaticKeypair> {
+        let now = Instant::now();
+        let key = {
+            let mut state = self.lock();
+            self.discard_stale(&mut state, now);
+            state.keys.pop_front()
+        };
+        self.taken.notify_one();
+        key.map(|k| k.keypair)
+    }
+
+    /// Generate keys with `rng` until the pool is full, discarding any stale
+    /// keys first.
+    ///
+    /// Returns the number of keys generated.
+    pub fn refill<R: rand_core::RngCore + rand_core::CryptoRng>(&self, mut rng: R) -> usize {
+        let now = Instant::now();
+        let missing = {
+            let mut state = self.lock();
+            self.discard_stale(&mut state, now);
+            self.capacity.saturating_sub(state.keys.len())
+        };
+        // Generate the keys without holding the lock, so that `take` doesn't
+        // have to wait for us.
+        let new_keys: Vec<_> = (0..missing)
+            .map(|_| {
+                let secret = StaticSecret::random_from_rng(&mut rng);
+                let public = PublicKey::from(&secret);
+                PooledKey {
+                    keypair: StaticKeypair { secret, public },
+                    created: now,
+                }
+            })
+            .collect();
+        let mut state = self.lock();
+        let room = self.capacity.saturating_sub(state.keys.len());
+        let n = new_keys.len().min(room);
+        state.keys.extend(new_keys.into_iter().take(n));
+        n
+    }
+
+    /// Drop every key in `state` that is older than our `max_age`.
+    fn discard_stale(&self, state: &mut PoolState, now: Instant) {
+        // Keys are added in order, so the oldest ones are at the front.
+        while let Some(k) = state.keys.front() {
+            if now.saturating_duration_since(k.created) <= self.max_age {
+                break;
+            }
+            state.keys.pop_front();
+        }
+    }
+
+    /// Lock our state.
+    ///
+    /// We never panic while holding the lock, so if it is poisoned the state
+    /// is still consistent, and we can use it.
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The longest that the refill thread waits before checking whether the
+/// pool still exists, and whether its keys have gone stale.
+const MAX_REFILL_WAIT: Duration = Duration::from_secs(10);
+
+/// Keep the pool `weak` full, until it is dropped.
+fn refill_loop(weak: &Weak<X25519KeyPool>) {
+    while let Some(pool) = weak.upgrade() {
+        pool.refill(rand::rng());
+        // Wait until someone takes a key, or until the oldest key might have
+        // gone stale.
+        let state = pool.lock();
+        let _ = pool
+            .taken
+            .wait_timeout(state, pool.max_age.min(MAX_REFILL_WAIT))
+            .unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_basic_utils::test_rng::testing_rng;
+
+    #[test]
+    fn take_and_refill() {
+        let pool = X25519KeyPool::new(3, Duration::from_secs(3600));
+        assert!(pool.is_empty());
+        assert!(pool.take().is_none());
+
+        assert_eq!(pool.refill(testing_rng()), 3);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.refill(testing_rng()), 0);
+
+        let kp = pool.take().unwrap();
+        assert_eq!(kp.public, PublicKey::from(&kp.secret));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.refill(testing_rng()), 1);
+        assert_eq!(pool.len(), 3);
+
+        // Every key is different.
+        let a = pool.take().unwrap().public;
+        let b = pool.take().unwrap().public;
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn stale_keys_discarded() {
+        let pool = X25519KeyPool::new(2, Duration::ZERO);
+        pool.refill(testing_rng());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(pool.take().is_none());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn background_refill() {
+        let pool = X25519KeyPool::launch(2, Duration::from_secs(3600)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while pool.len() < 2 {
+            assert!(Instant::now() < deadline, "pool was never refilled");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(pool.take().is_some());
+    }
+}
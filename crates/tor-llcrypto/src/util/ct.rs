@@ -1,4 +1,8 @@
 //! Define helpers for working with types in constant time.
+//!
+//! Code that compares secrets, or chooses between values based on a secret,
+//! should use the helpers here (or those of [`subtle`]) rather than `==` and
+//! `if`, which can leak information through timing.
 
 use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
 use zeroize::Zeroize;
@@ -94,6 +98,40 @@ impl<const N: usize> AsMut<[u8; N]> for CtByteArray<N> {
     }
 }
 
+/// Convert a boolean into a [`Choice`].
+///
+/// This isn't necessarily a good idea or constant-time: the conversion itself
+/// may branch on `v`.  Use it for booleans that come from functions which
+/// don't provide a `Choice`, so that they can be combined with other
+/// `Choice`s without short-circuiting.
+pub fn bool_to_choice(v: bool) -> Choice {
+    Choice::from(u8::from(v))
+}
+
+/// Return true if two slices are equal.  Performs its operation in constant
+/// time, but returns a bool instead of a [`Choice`].
+///
+/// The time taken depends on the lengths of the slices, but not on their
+/// contents.  Slices of different lengths are never equal.
+pub fn bytes_eq(a: &[u8], b: &[u8]) -> bool {
+    let choice = a.ct_eq(b);
+    choice.unwrap_u8() == 1
+}
+
+/// Returns true if all bytes of the input are zero (including if the slice is
+/// empty). Executes in constant time for a given length of input.
+pub fn is_zero(x: &[u8]) -> bool {
+    // It's tempting to lift the Choice out of the fold loop, s.t. the loop does
+    // a simple bit-or of each byte, but then the compiler could theoretically
+    // exit the loop early if all bits become one (i.e. 0xff). (Granted this
+    // seems unlikely in practice)
+    x.iter()
+        .map(|b| bool_to_choice(*b == 0))
+        .fold(bool_to_choice(true), std::ops::BitAnd::bitand)
+        .unwrap_u8()
+        == 1
+}
+
 /// Try to find an item in a slice without leaking where and whether the
 /// item was found.
 ///
@@ -199,4 +237,29 @@ mod test {
         assert_eq!(every_word.unwrap(), "every");
         assert_eq!(no_word, None);
     }
+
+    #[test]
+    fn test_bytes_eq() {
+        assert!(bytes_eq(&b"123"[..], &b"1234"[..3]));
+        assert!(!bytes_eq(&b"123"[..], &b"1234"[..]));
+        assert!(bytes_eq(&b"45"[..], &b"45"[..]));
+        assert!(!bytes_eq(&b"hi"[..], &b"45"[..]));
+        assert!(bytes_eq(&b""[..], &b""[..]));
+    }
+
+    #[test]
+    fn test_is_zero() {
+        assert!(is_zero(&[]));
+        assert!(is_zero(&[0]));
+        assert!(is_zero(&[0, 0]));
+        assert!(!is_zero(&[1, 0]));
+        assert!(!is_zero(&[0, 1]));
+        assert!(!is_zero(&[0, 1, 0]));
+    }
+
+    #[test]
+    fn test_bool_to_choice() {
+        assert_eq!(bool_to_choice(true).unwrap_u8(), 1);
+        assert_eq!(bool_to_choice(false).unwrap_u8(), 0);
+    }
 }
@@ -0,0 +1,158 @@
+//! Check that whichever cryptography backend is enabled agrees with
+//! RustCrypto and `x25519_dalek`.
+//!
+//! With no backend feature enabled, these tests compare RustCrypto with
+//! itself; run them with `--features with-openssl` or
+//! `--features with-aws-lc` to check the other backends.
+
+#![allow(clippy::uninlined_format_args)]
+
+use cipher::{KeyIvInit, StreamCipher};
+use digest::Digest;
+use hex_literal::hex;
+use rand::RngCore as _;
+use tor_basic_utils::test_rng::testing_rng;
+use tor_llcrypto as ll;
+
+/// Return `len` random bytes.
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut v = vec![0; len];
+    testing_rng().fill_bytes(&mut v);
+    v
+}
+
+/// Lengths to split our input into, to exercise partial blocks.
+const CHUNKS: &[usize] = &[0, 1, 15, 16, 17, 63, 64, 65, 127, 509, 1000];
+
+/// Check that digest `D` agrees with `R` on random input fed to it in
+/// uneven chunks.
+fn check_digest<D: Digest + Clone, R: Digest + Clone>() {
+    let data = random_bytes(CHUNKS.iter().sum());
+    let mut d = D::new();
+    let mut r = R::new();
+    let mut pos = 0;
+    for &len in CHUNKS {
+        d.update(&data[pos..pos + len]);
+        r.update(&data[pos..pos + len]);
+        pos += len;
+        // A clone has the same state.
+        assert_eq!(d.clone().finalize()[..], r.clone().finalize()[..]);
+    }
+    assert_eq!(d.finalize()[..], r.finalize()[..]);
+}
+
+#[test]
+fn digests() {
+    check_digest::<ll::d::Sha1, sha1::Sha1>();
+    check_digest::<ll::d::Sha256, sha2::Sha256>();
+    check_digest::<ll::d::Sha512, sha2::Sha512>();
+}
+
+/// Check that cipher `C` agrees with `R` on random input fed to it in
+/// uneven chunks.
+fn check_cipher<C: KeyIvInit + StreamCipher, R: KeyIvInit + StreamCipher>() {
+    let key = random_bytes(C::key_size());
+    // Start near the end of the counter space, to check that it wraps
+    // around the same way.
+    let mut iv = [0xff_u8; 16];
+    iv[0] = 0x42;
+    let mut c = C::new_from_slices(&key, &iv).unwrap();
+    let mut r = R::new_from_slices(&key, &iv).unwrap();
+
+    let data = random_bytes(CHUNKS.iter().sum());
+    let mut got = data.clone();
+    let mut expected = data;
+    let mut pos = 0;
+    for &len in CHUNKS {
+        c.apply_keystream(&mut got[pos..pos + len]);
+        r.apply_keystream(&mut expected[pos..pos + len]);
+        pos += len;
+    }
+    assert_eq!(got, expected);
+}
+
+#[test]
+fn aes_ctr() {
+    check_cipher::<ll::cipher::aes::Aes128Ctr, ctr::Ctr128BE<aes::Aes128>>();
+    check_cipher::<ll::cipher::aes::Aes256Ctr, ctr::Ctr128BE<aes::Aes256>>();
+}
+
+#[test]
+fn x25519() {
+    use ll::pk::curve25519::*;
+
+    let mut rng = testing_rng();
+    for _ in 0..16 {
+        let a = StaticSecret::random_from_rng(&mut rng);
+        let b = StaticSecret::random_from_rng(&mut rng);
+        let a_dalek = x25519_dalek::StaticSecret::from(a.to_bytes());
+        let b_dalek = x25519_dalek::StaticSecret::from(b.to_bytes());
+
+        let a_pub = PublicKey::from(&a);
+        let b_pub = PublicKey::from(&b);
+        assert_eq!(
+            a_pub.as_bytes(),
+            x25519_dalek::PublicKey::from(&a_dalek).as_bytes()
+        );
+
+        let shared = a.diffie_hellman(&b_pub);
+        assert!(shared.was_contributory());
+        assert_eq!(shared.as_bytes(), b.diffie_hellman(&a_pub).as_bytes());
+        assert_eq!(
+            shared.as_bytes(),
+            a_dalek
+                .diffie_hellman(&x25519_dalek::PublicKey::from(&b_dalek))
+                .as_bytes()
+        );
+    }
+
+    // Ephemeral secrets agree with static ones.
+    let e = EphemeralSecret::random_from_rng(&mut rng);
+    let s = StaticSecret::random_from_rng(&mut rng);
+    let e_pub = PublicKey::from(&e);
+    let shared = e.diffie_hellman(&PublicKey::from(&s));
+    assert_eq!(shared.as_bytes(), s.diffie_hellman(&e_pub).as_bytes());
+}
+
+#[test]
+fn tv_x25519_rfc7748() {
+    use ll::pk::curve25519::*;
+
+    // From RFC 7748, section 6.1.
+    let a = StaticSecret::from(hex!(
+        "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"
+    ));
+    let b = StaticSecret::from(hex!(
+        "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb"
+    ));
+    let a_pub = PublicKey::from(&a);
+    let b_pub = PublicKey::from(&b);
+    assert_eq!(
+        a_pub.as_bytes(),
+        &hex!("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+    );
+    assert_eq!(
+        b_pub.as_bytes(),
+        &hex!("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+    );
+    let shared = hex!("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+    assert_eq!(a.diffie_hellman(&b_pub).as_bytes(), &shared);
+    assert_eq!(b.diffie_hellman(&a_pub).as_bytes(), &shared);
+}
+
+#[test]
+fn x25519_low_order() {
+    use ll::pk::curve25519::*;
+
+    // A public key of small order gives an all-zero shared secret, which
+    // every backend reports as non-contributory.
+    let s = StaticSecret::random_from_rng(testing_rng());
+    for low_order in [
+        hex!("0000000000000000000000000000000000000000000000000000000000000000"),
+        hex!("0100000000000000000000000000000000000000000000000000000000000000"),
+    ] {
+        let shared = s.diffie_hellman(&PublicKey::from(low_order));
+        assert!(!shared.was_contributory());
+        assert_eq!(shared.as_bytes(), &[0; 32]);
+    }
+}
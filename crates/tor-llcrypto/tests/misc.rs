@@ -124,6 +124,18 @@ fn batch_verify() {
     ));
     let sigrefs: Vec<_> = sigs.iter().collect();
     assert!(!validate_batch(&sigrefs[..]));
+    assert_eq!(find_invalid(&sigrefs[..]), vec![3]);
+    assert_eq!(find_invalid(&sigrefs[..3]), Vec::<usize>::new());
+
+    // Put the junk signature in the middle of another valid one, and check
+    // the whole list through the generic API.
+    sigs.swap(1, 3);
+    let boxed: Vec<Box<dyn ll::pk::ValidatableSignature>> = sigs
+        .into_iter()
+        .map(|s| Box::new(s) as Box<dyn ll::pk::ValidatableSignature>)
+        .collect();
+    assert_eq!(ll::pk::find_invalid_sigs(&boxed[..]), vec![1]);
+    assert!(!ll::pk::validate_all_sigs(&boxed[..]));
 }
 
 #[test]
@@ -0,0 +1,777 @@
+//! Caches that participate in the memory quota system
+//!
+//! A [`CachePool`] is a single Participant in the memory quota system,
+//! shared by any number of [`MemCache`]s.
+//! Each cache is a bounded map with least-recently-used eviction,
+//! whose entries' memory cost is claimed from the pool.
+//!
+//! When the tracker selects the pool for reclamation,
+//! *every* cache in the pool is flushed.
+//! The pool then re-registers with the tracker (in a new Account)
+//! the next time any of its caches wants to store something.
+//! So, unlike a [`mq_queue`](crate::mq_queue), a cache recovers from reclamation:
+//! all that is lost is the cached data, which can be re-fetched or recomputed.
+//!
+//! The age reported to the tracker is the time the least-recently-used entry
+//! (in any of the caches) was last used.
+//! So caches holding stale data are reclaimed in preference to busy queues.
+//!
+//! Each cache keeps [`CacheStats`], so that cache pressure is observable.
+//!
+//! # Example
+//!
+//! ```
+//! use tor_memquota::{MemoryQuotaTracker, HasMemoryCost, EnabledToken};
+//! use tor_memquota::cache::{CachePool, MemCache};
+//! use tor_rtcompat::{DynTimeProvider, PreferredRuntime};
+//!
+//! #[derive(Clone, Debug)]
+//! struct Doc(String);
+//! impl HasMemoryCost for Doc {
+//!     fn memory_cost(&self, _: EnabledToken) -> usize { self.0.len() }
+//! }
+//!
+//! let runtime = PreferredRuntime::create().unwrap();
+//! let pool = CachePool::new(MemoryQuotaTracker::new_noop(), DynTimeProvider::new(runtime));
+//! let cache: MemCache<u32, Doc> = MemCache::new(&pool, "docs", 100);
+//!
+//! cache.insert(7, Doc("hello".into()));
+//! assert_eq!(cache.get(&7).unwrap().0, "hello");
+//! assert_eq!(cache.stats().hits, 1);
+//! ```
+
+#![forbid(unsafe_code)] // if you remove this, enable (or write) miri tests (git grep miri)
+
+use crate::internal_prelude::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+//---------- CachePool ----------
+
+/// A Participant in the memory quota system, shared by a number of [`MemCache`]s
+///
+/// This is a handle, which is cheap to clone; clones share state.
+#[derive(Clone, Debug)]
+pub struct CachePool(Arc<PoolShared>);
+
+/// Shared state of a [`CachePool`]
+#[derive(Debug)]
+struct PoolShared {
+    /// The tracker we register with
+    tracker: Arc<MemoryQuotaTracker>,
+    /// Time provider, for recording when entries were last used
+    runtime: DynTimeProvider,
+    /// Our current registration with the tracker, if any
+    ///
+    /// Lock ordering: we may call into the tracker while holding this lock.
+    /// So it must never be taken by [`IsParticipant::get_oldest`].
+    registration: Mutex<PoolRegistration>,
+    /// Generation number of the current registration
+    ///
+    /// Incremented each time we lose a registration.
+    /// Entries remember the generation under which their memory was claimed,
+    /// so that we never release memory into a Participation that didn't claim it,
+    /// and so that a flush only discards entries claimed by the lost registration.
+    ///
+    /// Only changed while holding `registration`,
+    /// but read by caches (while holding their own lock) without taking it.
+    generation: AtomicU64,
+    /// The caches that are using this pool
+    ///
+    /// Lock ordering: this lock (and then the locks of the members)
+    /// is taken by [`IsParticipant::get_oldest`],
+    /// so we must not call into the tracker while holding it.
+    members: Mutex<Vec<Weak<dyn PoolMember>>>,
+    /// Number of times the tracker has asked us to reclaim memory
+    reclaims: AtomicU64,
+}
+
+/// Registration of a [`CachePool`] with the tracker
+#[derive(Debug, Default)]
+struct PoolRegistration {
+    /// Our Account and Participation, if we are currently registered
+    current: Option<(Account, Participation)>,
+}
+
+/// A cache, as seen by the [`CachePool`]
+///
+/// Implementations must not call into the memory quota tracker.
+trait PoolMember: Debug + Send + Sync + 'static {
+    /// Return the time the least-recently-used entry was last used
+    fn oldest(&self) -> Option<CoarseInstant>;
+    /// Discard the entries whose memory was not claimed under `generation`,
+    /// because of memory reclamation
+    ///
+    /// Entries claimed under `generation` arrived after the registration was lost,
+    /// and their memory is accounted to the new registration, so they are kept.
+    fn flush(&self, generation: u64);
+}
+
+impl CachePool {
+    /// Create a new, empty, pool, which will claim memory from `tracker`
+    ///
+    /// The pool doesn't register with the tracker until something is cached.
+    pub fn new(tracker: Arc<MemoryQuotaTracker>, runtime: DynTimeProvider) -> Self {
+        CachePool(Arc::new(PoolShared {
+            tracker,
+            runtime,
+            registration: Mutex::new(PoolRegistration::default()),
+            generation: AtomicU64::new(0),
+            members: Mutex::new(Vec::new()),
+            reclaims: AtomicU64::new(0),
+        }))
+    }
+
+    /// Return the number of times this pool has been flushed by memory reclamation
+    pub fn reclaim_count(&self) -> u64 {
+        self.0.reclaims.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Add `member` to the set of caches flushed by reclamation
+    fn add_member(&self, member: Weak<dyn PoolMember>) {
+        let mut members = self.0.members.lock().expect("cache pool lock poisoned");
+        members.retain(|m| m.strong_count() > 0);
+        members.push(member);
+    }
+
+    /// Return whether memory claimed under `generation` is still accounted to us
+    ///
+    /// Doesn't take the registration lock, so it may be called with a cache locked.
+    fn is_current(&self, generation: u64) -> bool {
+        self.0.generation.load(AtomicOrdering::Relaxed) == generation
+    }
+
+    /// Return the current time
+    fn now(&self) -> CoarseInstant {
+        self.0.runtime.now_coarse()
+    }
+
+    /// Claim `qty` bytes, registering with the tracker if necessary
+    ///
+    /// Returns the generation under which the memory was claimed,
+    /// or `None` if the memory could not be claimed
+    /// (in which case the caller should not store anything).
+    fn claim(&self, qty: usize) -> Option<u64> {
+        let mut reg = self
+            .0
+            .registration
+            .lock()
+            .expect("cache pool lock poisoned");
+        if reg.current.is_none() {
+            let particip = Arc::downgrade(&self.0) as Weak<dyn IsParticipant>;
+            let registered = self.0.tracker.new_account(None).and_then(|account| {
+                let partn = account.register_participant(particip)?;
+                Ok((account, partn))
+            });
+            match registered {
+                Ok(registered) => reg.current = Some(registered),
+                Err(e) => {
+                    trace_report!(&e, "cache pool: unable to register with memory tracker");
+                    return None;
+                }
+            }
+        }
+        let (_, partn) = reg.current.as_mut()?;
+        match partn.claim(qty) {
+            Ok(()) => Some(self.0.generation.load(AtomicOrdering::Relaxed)),
+            Err(e) => {
+                trace_report!(&e, "cache pool: unable to claim memory");
+                let (lost, _) = self.0.lose_registration(&mut reg);
+                drop::<MutexGuard<_>>(reg);
+                drop(lost);
+                None
+            }
+        }
+    }
+
+    /// Release `qty` bytes, which were claimed under `generation`
+    fn release(&self, generation: u64, qty: usize) {
+        if qty == 0 {
+            return;
+        }
+        let mut reg = self
+            .0
+            .registration
+            .lock()
+            .expect("cache pool lock poisoned");
+        if self.0.generation.load(AtomicOrdering::Relaxed) != generation {
+            // Claimed by a registration that has since been torn down,
+            // so the tracker has already forgotten about it.
+            return;
+        }
+        if let Some((_, partn)) = reg.current.as_mut() {
+            partn.release(qty);
+        }
+    }
+}
+
+impl PoolShared {
+    /// Forget our current registration (if any) and move on to a new generation
+    ///
+    /// `reg` must be the guard of `self.registration`.
+    ///
+    /// Returns the old registration, which the caller should drop
+    /// after releasing the lock, and the new generation.
+    #[must_use]
+    fn lose_registration(
+        &self,
+        reg: &mut PoolRegistration,
+    ) -> (Option<(Account, Participation)>, u64) {
+        let generation = self
+            .generation
+            .load(AtomicOrdering::Relaxed)
+            .wrapping_add(1);
+        self.generation.store(generation, AtomicOrdering::Relaxed);
+        (reg.current.take(), generation)
+    }
+}
+
+impl IsParticipant for PoolShared {
+    fn get_oldest(&self, _: EnabledToken) -> Option<CoarseInstant> {
+        let members = self.members.lock().expect("cache pool lock poisoned");
+        members.iter().filter_map(|m| m.upgrade()?.oldest()).min()
+    }
+
+    fn reclaim(self: Arc<Self>, _: EnabledToken) -> mtracker::ReclaimFuture {
+        Box::pin(async move {
+            let (lost, generation) = {
+                let mut reg = self.registration.lock().expect("cache pool lock poisoned");
+                self.lose_registration(&mut reg)
+            };
+            drop(lost);
+
+            // Caches may claim memory (under the new generation) while we flush them.
+            // Those entries are accounted to the new registration, so each member
+            // keeps them; and entries claimed under the old generation but not yet
+            // stored are rejected by the cache, which re-checks the generation.
+            let members: Vec<Arc<dyn PoolMember>> = self
+                .members
+                .lock()
+                .expect("cache pool lock poisoned")
+                .iter()
+                .filter_map(Weak::upgrade)
+                .collect();
+            for member in members {
+                member.flush(generation);
+            }
+
+            let n = self
+                .reclaims
+                .fetch_add(1, AtomicOrdering::Relaxed)
+                .saturating_add(1);
+            debug!("memory quota: flushed caches (reclaim #{})", n);
+            mtracker::Reclaimed::Collapsing
+        })
+    }
+}
+
+//---------- MemCache ----------
+
+/// A bounded least-recently-used cache, whose memory use is tracked by a [`CachePool`]
+///
+/// Values are returned by cloning, so `V` should usually be cheap to clone
+/// (for example, an `Arc`).
+///
+/// This is a handle, which is cheap to clone; clones share state.
+pub struct MemCache<K, V> {
+    /// The shared state
+    shared: Arc<CacheShared<K, V>>,
+}
+
+/// Shared state of a [`MemCache`]
+#[derive(Debug)]
+struct CacheShared<K, V> {
+    /// Name of this cache, for logging
+    name: &'static str,
+    /// The pool we claim memory from
+    pool: CachePool,
+    /// Maximum number of entries to keep
+    max_entries: usize,
+    /// The entries
+    ///
+    /// The pool holds a `Weak` reference to this.
+    /// We never call into the tracker while holding this lock.
+    state: Arc<Mutex<CacheState<K, V>>>,
+}
+
+/// Contents of a [`MemCache`]
+#[derive(Debug)]
+struct CacheState<K, V> {
+    /// The entries, by key
+    entries: HashMap<K, Entry<V>>,
+    /// The keys, and when they were last used, in order of last use
+    ///
+    /// Indexed by a sequence number, so that the least recently used entry is first.
+    lru: BTreeMap<u64, (K, CoarseInstant)>,
+    /// Sequence number to assign to the next use
+    next_seq: u64,
+    /// Statistics
+    stats: CacheStats,
+}
+
+/// An entry in a [`MemCache`]
+#[derive(Debug)]
+struct Entry<V> {
+    /// The value
+    value: V,
+    /// The memory we claimed for this entry
+    cost: usize,
+    /// The pool generation under which we claimed the memory
+    generation: u64,
+    /// Key of this entry in `CacheState.lru`
+    seq: u64,
+}
+
+/// Statistics about a [`MemCache`]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// Number of entries currently in the cache
+    pub entries: usize,
+    /// Memory currently claimed for the entries, in bytes
+    pub bytes: usize,
+    /// Number of lookups that found an entry
+    pub hits: u64,
+    /// Number of lookups that found nothing
+    pub misses: u64,
+    /// Number of entries discarded to stay within the entry limit
+    pub evictions: u64,
+    /// Number of insertions discarded because memory could not be claimed
+    pub rejected: u64,
+    /// Number of times the cache was flushed by memory reclamation
+    pub flushes: u64,
+}
+
+impl<K, V> Clone for MemCache<K, V> {
+    fn clone(&self) -> Self {
+        MemCache {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<K: Debug, V: Debug> Debug for MemCache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemCache")
+            .field("name", &self.shared.name)
+            .field("max_entries", &self.shared.max_entries)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, V> MemCache<K, V>
+where
+    K: Hash + Eq + Clone + Debug + Send + 'static,
+    V: HasMemoryCost + Clone + Debug + Send + 'static,
+{
+    /// Create a new cache, in `pool`, holding at most `max_entries` entries
+    ///
+    /// `name` is used in log messages.
+    pub fn new(pool: &CachePool, name: &'static str, max_entries: usize) -> Self {
+        let state = Arc::new(Mutex::new(CacheState {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_seq: 0,
+            stats: CacheStats::default(),
+        }));
+        pool.add_member(Arc::downgrade(&state) as Weak<dyn PoolMember>);
+        MemCache {
+            shared: Arc::new(CacheShared {
+                name,
+                pool: pool.clone(),
+                max_entries,
+                state,
+            }),
+        }
+    }
+
+    /// Look up `key`, marking the entry as recently used
+    pub fn get(&self, key: &K) -> Option<V> {
+        let now = self.shared.pool.now();
+        let mut state = self.shared.lock();
+        let state = &mut *state;
+        let Some(entry) = state.entries.get_mut(key) else {
+            state.stats.misses = state.stats.misses.saturating_add(1);
+            return None;
+        };
+        state.stats.hits = state.stats.hits.saturating_add(1);
+        let _: Option<_> = state.lru.remove(&entry.seq);
+        entry.seq = state.next_seq;
+        state.next_seq = state.next_seq.wrapping_add(1);
+        state.lru.insert(entry.seq, (key.clone(), now));
+        Some(entry.value.clone())
+    }
+
+    /// Store `value` under `key`, replacing any previous value
+    ///
+    /// If the cache is full, the least recently used entries are discarded.
+    /// If the memory can't be claimed, the value is not stored.
+    pub fn insert(&self, key: K, value: V) {
+        let cost = Self::entry_cost(&value);
+        let Some(generation) = self.shared.pool.claim(cost) else {
+            let mut state = self.shared.lock();
+            state.stats.rejected = state.stats.rejected.saturating_add(1);
+            return;
+        };
+        let now = self.shared.pool.now();
+
+        let mut discarded = Vec::new();
+        {
+            let mut state = self.shared.lock();
+            if !self.shared.pool.is_current(generation) {
+                // The pool was reclaimed since we claimed the memory.
+                // If we stored the entry now, it might miss the flush,
+                // and stay in the cache unaccounted for.
+                state.stats.rejected = state.stats.rejected.saturating_add(1);
+                return;
+            }
+            let seq = state.next_seq;
+            state.next_seq = seq.wrapping_add(1);
+            let entry = Entry {
+                value,
+                cost,
+                generation,
+                seq,
+            };
+            state.lru.insert(seq, (key.clone(), now));
+            if let Some(old) = state.entries.insert(key, entry) {
+                let _: Option<_> = state.lru.remove(&old.seq);
+                discarded.push(old);
+            }
+            while state.entries.len() > self.shared.max_entries {
+                let Some((_, (victim, _))) = state.lru.pop_first() else {
+                    break;
+                };
+                if let Some(old) = state.entries.remove(&victim) {
+                    state.stats.evictions = state.stats.evictions.saturating_add(1);
+                    discarded.push(old);
+                }
+            }
+            state.recount(&discarded, cost);
+        }
+        self.release(discarded);
+    }
+
+    /// Remove the entry for `key`, if there is one, returning its value
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let old = {
+            let mut state = self.shared.lock();
+            let old = state.entries.remove(key)?;
+            let _: Option<_> = state.lru.remove(&old.seq);
+            state.recount(std::slice::from_ref(&old), 0);
+            old
+        };
+        let value = old.value.clone();
+        self.release(vec![old]);
+        Some(value)
+    }
+
+    /// Remove every entry for which `keep` returns false
+    pub fn retain(&self, mut keep: impl FnMut(&K, &V) -> bool) {
+        let mut discarded = Vec::new();
+        {
+            let mut state = self.shared.lock();
+            let state = &mut *state;
+            let doomed: Vec<K> = state
+                .entries
+                .iter()
+                .filter(|(k, e)| !keep(k, &e.value))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for k in doomed {
+                if let Some(old) = state.entries.remove(&k) {
+                    let _: Option<_> = state.lru.remove(&old.seq);
+                    discarded.push(old);
+                }
+            }
+            state.recount(&discarded, 0);
+        }
+        self.release(discarded);
+    }
+
+    /// Remove all the entries
+    pub fn clear(&self) {
+        self.retain(|_, _| false);
+    }
+
+    /// Return statistics about this cache
+    pub fn stats(&self) -> CacheStats {
+        self.shared.lock().stats.clone()
+    }
+
+    /// Return the memory cost we claim for an entry containing `value`
+    fn entry_cost(value: &V) -> usize {
+        let value_cost = EnabledToken::new_if_compiled_in()
+            .map(|enabled| value.memory_cost(enabled))
+            .unwrap_or(0);
+        // The key is stored twice: in `entries` and in `lru`.
+        value_cost
+            .saturating_add(size_of::<Entry<V>>())
+            .saturating_add(size_of::<K>().saturating_mul(2))
+    }
+
+    /// Release the memory claimed for `discarded`, after the lock has been released
+    fn release(&self, discarded: Vec<Entry<V>>) {
+        for old in discarded {
+            self.shared.pool.release(old.generation, old.cost);
+        }
+    }
+}
+
+impl<K, V> CacheShared<K, V> {
+    /// Lock the cache state
+    fn lock(&self) -> MutexGuard<CacheState<K, V>> {
+        self.state.lock().expect("cache lock poisoned")
+    }
+}
+
+impl<K, V> CacheState<K, V> {
+    /// Update the entry and byte counts, after inserting `added` bytes
+    /// and removing `discarded`
+    fn recount(&mut self, discarded: &[Entry<V>], added: usize) {
+        let removed = discarded
+            .iter()
+            .fold(0_usize, |acc, e| acc.saturating_add(e.cost));
+        self.stats.bytes = self
+            .stats
+            .bytes
+            .saturating_add(added)
+            .saturating_sub(removed);
+        self.stats.entries = self.entries.len();
+    }
+}
+
+impl<K, V> Drop for CacheShared<K, V> {
+    fn drop(&mut self) {
+        let discarded: Vec<(u64, usize)> = {
+            let mut state = self.lock();
+            let discarded = state
+                .entries
+                .drain()
+                .map(|(_, e)| (e.generation, e.cost))
+                .collect();
+            state.lru.clear();
+            discarded
+        };
+        for (generation, cost) in discarded {
+            self.pool.release(generation, cost);
+        }
+    }
+}
+
+impl<K, V> PoolMember for Mutex<CacheState<K, V>>
+where
+    K: Hash + Eq + Debug + Send + 'static,
+    V: Debug + Send + 'static,
+{
+    fn oldest(&self) -> Option<CoarseInstant> {
+        let state = self.lock().expect("cache lock poisoned");
+        let (_, (_, last_used)) = state.lru.first_key_value()?;
+        Some(*last_used)
+    }
+
+    fn flush(&self, generation: u64) {
+        let flushed = {
+            let mut state = self.lock().expect("cache lock poisoned");
+            let state = &mut *state;
+            let (kept, flushed): (HashMap<_, _>, Vec<_>) = mem::take(&mut state.entries)
+                .into_iter()
+                .partition(|(_, e)| e.generation == generation);
+            state.entries = kept;
+            let flushed: Vec<Entry<V>> = flushed
+                .into_iter()
+                .map(|(_, e)| {
+                    let _: Option<_> = state.lru.remove(&e.seq);
+                    e
+                })
+                .collect();
+            // The memory of the flushed entries was claimed by the lost registration,
+            // so there is nothing to release.
+            state.recount(&flushed, 0);
+            state.stats.flushes = state.stats.flushes.saturating_add(1);
+            flushed
+        };
+        // Drop the values outside the lock.
+        drop(flushed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    #![allow(clippy::arithmetic_side_effects)] // don't mind potential panicking ops in tests
+
+    use super::*;
+    use tor_rtmock::MockRuntime;
+
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    struct Doc(usize);
+
+    impl HasMemoryCost for Doc {
+        fn memory_cost(&self, _: EnabledToken) -> usize {
+            self.0
+        }
+    }
+
+    fn mk_pool(rt: &MockRuntime) -> CachePool {
+        CachePool::new(
+            MemoryQuotaTracker::new_noop(),
+            DynTimeProvider::new(rt.clone()),
+        )
+    }
+
+    #[test]
+    fn lru_eviction() {
+        MockRuntime::test_with_various(|rt| async move {
+            let pool = mk_pool(&rt);
+            let cache: MemCache<u32, Doc> = MemCache::new(&pool, "test", 2);
+
+            cache.insert(1, Doc(10));
+            cache.insert(2, Doc(20));
+            // Touch 1, so that 2 is now the least recently used.
+            assert_eq!(cache.get(&1), Some(Doc(10)));
+            cache.insert(3, Doc(30));
+
+            assert_eq!(cache.get(&2), None);
+            assert_eq!(cache.get(&1), Some(Doc(10)));
+            assert_eq!(cache.get(&3), Some(Doc(30)));
+
+            let stats = cache.stats();
+            assert_eq!(stats.entries, 2);
+            assert_eq!(stats.evictions, 1);
+            assert_eq!(stats.hits, 3);
+            assert_eq!(stats.misses, 1);
+        });
+    }
+
+    #[test]
+    fn replace_remove_retain() {
+        MockRuntime::test_with_various(|rt| async move {
+            let pool = mk_pool(&rt);
+            let cache: MemCache<u32, Doc> = MemCache::new(&pool, "test", 10);
+
+            for i in 0..5 {
+                cache.insert(i, Doc(i as usize));
+            }
+            cache.insert(0, Doc(100));
+            assert_eq!(cache.stats().entries, 5);
+            assert_eq!(cache.get(&0), Some(Doc(100)));
+
+            assert_eq!(cache.remove(&1), Some(Doc(1)));
+            assert_eq!(cache.remove(&1), None);
+
+            cache.retain(|k, _| k % 2 == 0);
+            assert_eq!(cache.stats().entries, 2);
+            assert_eq!(cache.get(&3), None);
+            assert_eq!(cache.get(&4), Some(Doc(4)));
+
+            cache.clear();
+            let stats = cache.stats();
+            assert_eq!(stats.entries, 0);
+            assert_eq!(stats.bytes, 0);
+        });
+    }
+
+    #[cfg(all(feature = "memquota", not(miri) /* coarsetime */))]
+    #[test]
+    fn reclaim_flushes_all_members() {
+        use crate::mtracker::test::{mbytes, mk_tracker, TEST_DEFAULT_LIMIT};
+
+        MockRuntime::test_with_various(|rt| async move {
+            let tracker = mk_tracker(&rt);
+            let pool = CachePool::new(tracker.clone(), DynTimeProvider::new(rt.clone()));
+            let c1: MemCache<u32, Doc> = MemCache::new(&pool, "one", 100);
+            let c2: MemCache<String, Doc> = MemCache::new(&pool, "two", 100);
+            c2.insert("a".into(), Doc(1));
+
+            // Go over quota.
+            let n = TEST_DEFAULT_LIMIT / mbytes(1) + 1;
+            for i in 0..n {
+                c1.insert(i as u32, Doc(mbytes(1)));
+            }
+            assert_eq!(c1.stats().entries, n);
+
+            rt.advance_until_stalled().await;
+
+            // The tracker has asked the pool to reclaim, which flushed both caches.
+            assert_eq!(pool.reclaim_count(), 1);
+            assert_eq!(c1.get(&0), None);
+            assert_eq!(c2.get(&"a".into()), None);
+            for stats in [c1.stats(), c2.stats()] {
+                assert_eq!(stats.flushes, 1);
+                assert_eq!(stats.entries, 0);
+                assert_eq!(stats.bytes, 0);
+            }
+            assert!(tracker.used_current_approx().unwrap() < mbytes(1));
+
+            // The caches are still usable after being flushed,
+            // and claim their memory under a new registration.
+            c1.insert(1, Doc(1));
+            assert_eq!(c1.get(&1), Some(Doc(1)));
+            rt.advance_until_stalled().await;
+            assert_eq!(pool.reclaim_count(), 1);
+            assert_eq!(c1.get(&1), Some(Doc(1)));
+        });
+    }
+
+    #[cfg(all(feature = "memquota", not(miri) /* coarsetime */))]
+    #[test]
+    fn reclaim_concurrent_with_insert() {
+        use crate::mtracker::test::mk_tracker;
+
+        MockRuntime::test_with_various(|rt| async move {
+            let tracker = mk_tracker(&rt);
+            let pool = CachePool::new(tracker.clone(), DynTimeProvider::new(rt.clone()));
+            let cache: MemCache<u32, Doc> = MemCache::new(&pool, "test", 1000);
+
+            let inserter = {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..20_000 {
+                        cache.insert(i % 1000, Doc(1));
+                    }
+                })
+            };
+            // Reclaim repeatedly, while the other thread is claiming and storing.
+            while !inserter.is_finished() {
+                pool.0.clone().reclaim(EnabledToken::new()).await;
+                std::thread::yield_now();
+            }
+            inserter.join().unwrap();
+
+            // Everything that survived is accounted to the current registration,
+            // and the statistics agree with the contents.
+            let generation = pool.0.generation.load(AtomicOrdering::Relaxed);
+            {
+                let state = cache.shared.lock();
+                assert!(state.entries.values().all(|e| e.generation == generation));
+                let bytes: usize = state.entries.values().map(|e| e.cost).sum();
+                assert_eq!(state.stats.bytes, bytes);
+                assert_eq!(state.stats.entries, state.entries.len());
+                assert_eq!(state.lru.len(), state.entries.len());
+            }
+
+            // A final reclaim leaves nothing behind, in the cache or the tracker.
+            pool.0.clone().reclaim(EnabledToken::new()).await;
+            let stats = cache.stats();
+            assert_eq!(stats.entries, 0);
+            assert_eq!(stats.bytes, 0);
+            drop(cache);
+            assert_eq!(tracker.used_current_approx().unwrap(), 0);
+        });
+    }
+}
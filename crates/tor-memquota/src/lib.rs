@@ -244,6 +244,7 @@ mod internal_prelude;
 mod utils;
 
 // Modules with public items
+pub mod cache;
 mod config;
 mod error;
 pub mod memory_cost;
@@ -270,7 +271,7 @@ pub use error::{Error, MemoryReclaimedError, StartupError};
 pub use if_enabled::EnabledToken;
 pub use memory_cost::HasMemoryCost;
 pub use memory_cost_derive::{assert_copy_static, HasMemoryCostStructural};
-pub use mtracker::{Account, MemoryQuotaTracker};
+pub use mtracker::{Account, MemoryQuotaTracker, ReclaimEvent};
 pub use utils::ArcMemoryQuotaTrackerExt;
 
 #[doc(hidden)]
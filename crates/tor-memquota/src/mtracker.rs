@@ -109,6 +109,26 @@ pub struct MemoryQuotaTracker {
     state: IfEnabled<Mutex<State>>,
 }
 
+/// Report that a [`MemoryQuotaTracker`] has started to reclaim memory
+///
+/// Delivered by the stream from [`MemoryQuotaTracker::reclaim_events`].
+/// All quantities are in bytes, and [approximate](../index.html#is-approximate).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ReclaimEvent {
+    /// The total memory use when reclamation started
+    pub used: usize,
+    /// The configured maximum, which `used` exceeded
+    pub max: usize,
+    /// The configured low water mark, down to which we are reclaiming
+    pub low_water: usize,
+}
+
+/// How many [`ReclaimEvent`]s we queue for each listener
+///
+/// If a listener doesn't keep up, it misses events.
+const RECLAIM_EVENTS_QUEUE_LEN: usize = 4;
+
 /// Handle onto an Account
 ///
 /// An `Account` is a handle.  All clones refer to the same underlying conceptual Account.
@@ -395,6 +415,12 @@ struct Global {
     /// Configuration
     config: ConfigInner,
 
+    /// Senders for the streams returned by [`MemoryQuotaTracker::reclaim_events`]
+    ///
+    /// Senders whose receivers have gone away are removed when we next
+    /// start reclaiming.
+    reclaim_listeners: Vec<mpsc::Sender<ReclaimEvent>>,
+
     /// Make this type uninhabited if memory tracking is compiled out
     #[allow(dead_code)]
     enabled: EnabledToken,
@@ -556,6 +582,7 @@ impl MemoryQuotaTracker {
         let global = Global {
             total_used,
             config,
+            reclaim_listeners: Vec::new(),
             enabled,
         };
         let accounts = SlotMap::default();
@@ -642,6 +669,23 @@ impl MemoryQuotaTracker {
         Ok(*state.total_used.as_raw())
     }
 
+    /// Return a stream of [`ReclaimEvent`]s
+    ///
+    /// An event is yielded each time the tracker starts reclaiming memory,
+    /// because usage has gone over the configured maximum.
+    /// This is a hook for embedders that want to release memory of their own,
+    /// or to report memory pressure to the user.
+    ///
+    /// The stream is lossy: if the receiver doesn't keep up, some events are dropped.
+    /// For a no-op tracker, the stream ends immediately.
+    pub fn reclaim_events(&self) -> Result<mpsc::Receiver<ReclaimEvent>, TrackerCorrupted> {
+        let (tx, rx) = mpsc_channel_no_memquota(RECLAIM_EVENTS_QUEUE_LEN);
+        if let Enabled(mut state, _enabled) = self.lock()? {
+            state.global.reclaim_listeners.push(tx);
+        }
+        Ok(rx)
+    }
+
     /// Make a new `Account`
     ///
     /// To actually record memory usage, a Participant must be added.
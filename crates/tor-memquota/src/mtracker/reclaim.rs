@@ -154,6 +154,19 @@ impl Reclaiming {
             *state.total_used, state.config.max, state.config.low_water,
         );
 
+        let event = ReclaimEvent {
+            used: *state.total_used.as_raw(),
+            max: state.config.max.as_usize(),
+            low_water: state.config.low_water.as_usize(),
+        };
+        state
+            .global
+            .reclaim_listeners
+            .retain_mut(|tx| match tx.try_send(event) {
+                Ok(()) => true,
+                Err(e) => !e.is_disconnected(),
+            });
+
         // `BinaryHeap` is a max heap, so use Rev
         let mut heap = BinaryHeap::new();
 
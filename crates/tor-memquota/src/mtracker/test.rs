@@ -405,6 +405,38 @@ fn parent() {
     });
 }
 
+#[traced_test]
+#[test]
+fn reclaim_events() {
+    test_with_various_mocks(|rt| async move {
+        let trk = mk_tracker(&rt);
+        let mut events = trk.reclaim_events().unwrap();
+        drop(trk.reclaim_events().unwrap());
+
+        let ps: Vec<Arc<UnifiedP>> = (0..3)
+            .map(|i| UnifiedP::new(&rt, &trk, None, secs(i), i))
+            .collect();
+        for p in &ps[0..2] {
+            p.lock().claim(mbytes(7)).unwrap();
+        }
+        rt.advance_until_stalled().await;
+        assert!(events.try_next().is_err());
+
+        ps[2].lock().claim(mbytes(7)).unwrap();
+        rt.advance_until_stalled().await;
+
+        let event = events.try_next().unwrap().unwrap();
+        assert!(event.used > TEST_DEFAULT_LIMIT);
+        assert_eq!(event.max, TEST_DEFAULT_LIMIT);
+        assert_eq!(event.low_water, TEST_DEFAULT_LOWWATER);
+        assert!(events.try_next().is_err());
+
+        // The listener whose receiver we dropped has been forgotten.
+        let state = trk.lock().unwrap().into_enabled().unwrap();
+        assert_eq!(state.global.reclaim_listeners.len(), 1);
+    });
+}
+
 #[traced_test]
 #[test]
 fn cache() {
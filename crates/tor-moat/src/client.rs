@@ -0,0 +1,389 @@
+//! The moat client itself.
+
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use serde::{Deserialize, Serialize};
+use tor_guardmgr::bridge::BridgeConfigBuilder;
+use tor_persist::{DynStorageHandle, StateMgr};
+use tor_rtcompat::{SleepProvider, SleepProviderExt as _};
+use tracing::{debug, info, warn};
+
+use crate::config::MoatConfig;
+use crate::msg::{self, BuiltinRequest, MoatBridge, SettingsRequest};
+use crate::{http, Error, Result};
+
+/// Key under which we store the bridges that we obtained.
+const STATE_KEY: &str = "moat_bridges";
+
+/// A way to open connections to a moat server.
+///
+/// Implementations must open a TLS connection, **and validate the server's
+/// certificate**: the bridges we receive are only as trustworthy as the
+/// connection they arrive on.
+#[async_trait]
+pub trait MoatConnector: Send + Sync {
+    /// The type of stream that this connector returns.
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin;
+
+    /// Open a TLS connection to port 443 on `host`, using `host` as the SNI
+    /// hostname.
+    ///
+    /// When domain fronting, `host` is the front domain; otherwise it is the
+    /// moat server itself.
+    async fn connect(&self, host: &str) -> io::Result<Self::Stream>;
+}
+
+/// A hook to decide whether to use the bridges that we received.
+///
+/// Typically this asks the user.
+#[async_trait]
+pub trait ConfirmBridges: Send + Sync {
+    /// Return true if we should use `bridges`.
+    async fn confirm(&self, bridges: &[MoatBridge]) -> bool;
+}
+
+/// A [`ConfirmBridges`] that accepts every set of bridges without asking.
+#[derive(Clone, Copy, Debug, Default)]
+#[allow(clippy::exhaustive_structs)]
+pub struct AlwaysConfirm;
+
+#[async_trait]
+impl ConfirmBridges for AlwaysConfirm {
+    async fn confirm(&self, _bridges: &[MoatBridge]) -> bool {
+        true
+    }
+}
+
+/// Somewhere to obtain fresh bridges from, when our configured ones have failed.
+///
+/// This is implemented by [`MoatClient`], and lets a Tor client hold one
+/// without knowing how it connects to the moat server.
+#[async_trait]
+pub trait BridgeSource: Send + Sync {
+    /// Obtain a fresh set of bridges that use one of the pluggable
+    /// `transports`, or (if `transports` is empty) whatever transports the
+    /// source recommends.
+    async fn fetch_bridges(&self, transports: &[String]) -> Result<Vec<BridgeConfigBuilder>>;
+}
+
+/// A set of bridges that we obtained from a moat server, as persisted on disk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PersistedBridges {
+    /// When we obtained these bridges.
+    obtained: SystemTime,
+    /// The bridges themselves.
+    bridges: Vec<MoatBridge>,
+}
+
+impl PersistedBridges {
+    /// Return the time when these bridges were obtained.
+    pub fn obtained(&self) -> SystemTime {
+        self.obtained
+    }
+
+    /// Return the bridges themselves.
+    pub fn bridges(&self) -> &[MoatBridge] {
+        &self.bridges
+    }
+
+    /// Return a configuration builder for each bridge that we can parse.
+    ///
+    /// Bridges that we can't parse are skipped, with a warning.
+    pub fn bridge_configs(&self) -> Vec<BridgeConfigBuilder> {
+        parse_bridges(&self.bridges)
+    }
+}
+
+/// A client for the moat bridge distribution API.
+pub struct MoatClient<C, SP> {
+    /// Our configuration.
+    config: MoatConfig,
+    /// How we reach the server.
+    connector: C,
+    /// Used to enforce our timeout.
+    runtime: SP,
+    /// Asked before we use any bridges that we receive.
+    confirm: Box<dyn ConfirmBridges>,
+    /// Where we store the bridges that we obtain, if anywhere.
+    storage: Option<DynStorageHandle<PersistedBridges>>,
+}
+
+impl<C, SP> MoatClient<C, SP>
+where
+    C: MoatConnector,
+    SP: SleepProvider,
+{
+    /// Construct a new `MoatClient`.
+    ///
+    /// By default, every set of bridges is accepted, and nothing is persisted.
+    pub fn new(config: MoatConfig, connector: C, runtime: SP) -> Self {
+        MoatClient {
+            config,
+            connector,
+            runtime,
+            confirm: Box::new(AlwaysConfirm),
+            storage: None,
+        }
+    }
+
+    /// Ask `hook` before using any bridges that we receive.
+    pub fn with_confirmation<H: ConfirmBridges + 'static>(mut self, hook: H) -> Self {
+        self.confirm = Box::new(hook);
+        self
+    }
+
+    /// Store the bridges that we obtain in `statemgr`.
+    pub fn with_state_mgr<M>(mut self, statemgr: M) -> Self
+    where
+        M: StateMgr + Send + Sync + 'static,
+    {
+        self.storage = Some(statemgr.create_handle(STATE_KEY));
+        self
+    }
+
+    /// Ask the server which bridges to use from `country` (a two-letter
+    /// country code, if known) with any of the pluggable `transports`.
+    pub async fn circumvention_settings(
+        &self,
+        country: Option<&str>,
+        transports: &[String],
+    ) -> Result<Vec<MoatBridge>> {
+        let body = serde_json::to_vec(&SettingsRequest {
+            country,
+            transports,
+        })?;
+        let reply = self.request("settings", &body).await?;
+        msg::parse_settings(&reply)
+    }
+
+    /// Ask the server for its current built-in bridges for `transports`.
+    pub async fn builtin_bridges(&self, transports: &[String]) -> Result<Vec<MoatBridge>> {
+        let body = serde_json::to_vec(&BuiltinRequest { transports })?;
+        let reply = self.request("builtin", &body).await?;
+        msg::parse_builtin(&reply)
+    }
+
+    /// Obtain a fresh set of bridges, to use when our configured ones fail.
+    ///
+    /// Tries the circumvention settings for `country` first, then falls back
+    /// to the built-in bridges.  The bridges are passed to our confirmation
+    /// hook; if it accepts them, they are stored (if we have somewhere to
+    /// store them) and returned as configuration builders.
+    pub async fn obtain_bridges(
+        &self,
+        country: Option<&str>,
+        transports: &[String],
+    ) -> Result<Vec<BridgeConfigBuilder>> {
+        let mut bridges = match self.circumvention_settings(country, transports).await {
+            Ok(bridges) => bridges,
+            Err(e) => {
+                debug!("Moat settings request failed: {}", e);
+                vec![]
+            }
+        };
+        if bridges.is_empty() {
+            bridges = self.builtin_bridges(transports).await?;
+        }
+        let configs = parse_bridges(&bridges);
+        if configs.is_empty() {
+            return Err(Error::NoBridges);
+        }
+
+        if !self.confirm.confirm(&bridges).await {
+            return Err(Error::Declined);
+        }
+        info!("Obtained {} bridges from moat server.", configs.len());
+
+        if let Some(storage) = &self.storage {
+            let persisted = PersistedBridges {
+                obtained: self.runtime.wallclock(),
+                bridges,
+            };
+            storage.store(&persisted).map_err(Error::Persist)?;
+        }
+        Ok(configs)
+    }
+
+    /// Return the bridges that we most recently obtained and stored, if any.
+    pub fn persisted_bridges(&self) -> Result<Option<PersistedBridges>> {
+        match &self.storage {
+            Some(storage) => storage.load().map_err(Error::Persist),
+            None => Ok(None),
+        }
+    }
+
+    /// Send `body` to the moat `endpoint`, and return the reply.
+    async fn request(&self, endpoint: &str, body: &[u8]) -> Result<Vec<u8>> {
+        let exchange = async {
+            let mut stream = self
+                .connector
+                .connect(self.config.connect_host())
+                .await
+                .map_err(|e| Error::Connect(Arc::new(e)))?;
+            http::post(
+                &mut stream,
+                self.config.host(),
+                &self.config.path(endpoint),
+                body,
+                self.config.max_response_len(),
+            )
+            .await
+        };
+        self.runtime
+            .timeout(self.config.timeout(), exchange)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+}
+
+#[async_trait]
+impl<C, SP> BridgeSource for MoatClient<C, SP>
+where
+    C: MoatConnector,
+    SP: SleepProvider,
+{
+    async fn fetch_bridges(&self, transports: &[String]) -> Result<Vec<BridgeConfigBuilder>> {
+        // We don't know which country we are in.
+        self.obtain_bridges(None, transports).await
+    }
+}
+
+/// Parse every bridge in `bridges` that we understand.
+fn parse_bridges(bridges: &[MoatBridge]) -> Vec<BridgeConfigBuilder> {
+    bridges
+        .iter()
+        .filter_map(|b| match b.line().parse() {
+            Ok(cfg) => Some(cfg),
+            Err(e) => {
+                warn!("Ignoring unparseable bridge from moat server: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use futures::task::SpawnExt as _;
+    use std::sync::Mutex;
+    use tor_persist::TestingStateMgr;
+    use tor_rtmock::io::{stream_pair, LocalStream};
+    use tor_rtmock::MockRuntime;
+
+    /// A connector that answers every request with a canned response.
+    struct Canned {
+        /// Used to spawn our fake servers.
+        runtime: MockRuntime,
+        /// Responses to give, in order.
+        responses: Mutex<Vec<&'static str>>,
+        /// Hosts that we were asked to connect to.
+        hosts: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl MoatConnector for Canned {
+        type Stream = LocalStream;
+
+        async fn connect(&self, host: &str) -> io::Result<LocalStream> {
+            self.hosts.lock().unwrap().push(host.into());
+            let body = self.responses.lock().unwrap().remove(0);
+            let (client, mut server) = stream_pair();
+            self.runtime
+                .spawn(async move {
+                    let mut req = Vec::new();
+                    let mut buf = [0; 256];
+                    while !req.ends_with(b"}") {
+                        let n = server.read(&mut buf).await.unwrap();
+                        req.extend_from_slice(&buf[..n]);
+                    }
+                    let resp = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    server.write_all(resp.as_bytes()).await.unwrap();
+                    server.close().await.unwrap();
+                })
+                .unwrap();
+            Ok(client)
+        }
+    }
+
+    /// A confirmation hook that always says no.
+    struct Decline;
+    #[async_trait]
+    impl ConfirmBridges for Decline {
+        async fn confirm(&self, _bridges: &[MoatBridge]) -> bool {
+            false
+        }
+    }
+
+    const BRIDGE: &str = "38.229.33.83:80 0BAC39417268B96B9F514E7F63FA6FBA1A788955";
+
+    #[test]
+    fn obtain_and_persist() {
+        MockRuntime::test_with_various(|rt| async move {
+            let settings = r#"{"settings": []}"#;
+            let builtin =
+                r#"{"vanilla": ["38.229.33.83:80 0BAC39417268B96B9F514E7F63FA6FBA1A788955"]}"#;
+            let connector = Canned {
+                runtime: rt.clone(),
+                responses: Mutex::new(vec![settings, builtin]),
+                hosts: Mutex::new(vec![]),
+            };
+            let statemgr = TestingStateMgr::new();
+            statemgr.try_lock().unwrap();
+            let client = MoatClient::new(MoatConfig::default(), connector, rt.clone())
+                .with_state_mgr(statemgr);
+
+            let configs = client.obtain_bridges(Some("zz"), &[]).await.unwrap();
+            assert_eq!(configs.len(), 1);
+            assert_eq!(
+                client.connector.hosts.lock().unwrap().as_slice(),
+                ["cdn.sstatic.net", "cdn.sstatic.net"]
+            );
+
+            let persisted = client.persisted_bridges().unwrap().unwrap();
+            assert_eq!(persisted.bridges().len(), 1);
+            assert_eq!(persisted.bridges()[0].line(), BRIDGE);
+            assert_eq!(persisted.bridge_configs().len(), 1);
+        });
+    }
+
+    #[test]
+    fn declined() {
+        MockRuntime::test_with_various(|rt| async move {
+            let settings = r#"{"settings": [{"bridges": {"type": "vanilla", "source": "bridgedb", "bridge_strings": ["38.229.33.83:80 0BAC39417268B96B9F514E7F63FA6FBA1A788955"]}}]}"#;
+            let connector = Canned {
+                runtime: rt.clone(),
+                responses: Mutex::new(vec![settings]),
+                hosts: Mutex::new(vec![]),
+            };
+            let client = MoatClient::new(MoatConfig::default(), connector, rt.clone())
+                .with_confirmation(Decline);
+
+            let err = client.obtain_bridges(None, &[]).await.unwrap_err();
+            assert!(matches!(err, Error::Declined));
+            assert!(client.persisted_bridges().unwrap().is_none());
+        });
+    }
+}
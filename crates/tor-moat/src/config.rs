@@ -0,0 +1,159 @@
+//! Configuration for a moat client.
+
+use std::time::Duration;
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tor_config::{impl_standard_builder, ConfigBuildError};
+
+/// Default URL for the moat API.
+///
+/// This is the same reflector that Tor Browser uses.
+const DEFAULT_URL: &str = "https://moat.torproject.org.global.prod.fastly.net/";
+
+/// Default front domain to use when reaching [`DEFAULT_URL`].
+const DEFAULT_FRONT: &str = "cdn.sstatic.net";
+
+/// Configuration for a [`MoatClient`](crate::MoatClient).
+///
+/// This type is immutable once constructed.  To build one, use
+/// [`MoatConfigBuilder`], or deserialize it from a string.
+#[derive(Debug, Clone, Builder, Eq, PartialEq)]
+#[builder(build_fn(error = "ConfigBuildError", validate = "Self::validate"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+pub struct MoatConfig {
+    /// The base URL of the moat API.
+    ///
+    /// Must be an `https` URL.
+    #[builder(default = "DEFAULT_URL.into()", setter(into))]
+    url: String,
+
+    /// A domain to use for domain fronting.
+    ///
+    /// If this is set, we connect to this domain, and present it as our TLS
+    /// SNI hostname, but ask for the host in `url` in our HTTP request.
+    #[builder(default = "Some(DEFAULT_FRONT.into())")]
+    front: Option<String>,
+
+    /// How long to wait for a reply from the moat server.
+    #[builder(default = "Duration::from_secs(60)")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    timeout: Duration,
+
+    /// The longest response we're willing to accept from the moat server.
+    #[builder(default = "64 * 1024")]
+    max_response_len: usize,
+}
+impl_standard_builder! { MoatConfig }
+
+impl MoatConfigBuilder {
+    /// Check that the configured URL is one we can use.
+    fn validate(&self) -> Result<(), ConfigBuildError> {
+        if let Some(url) = &self.url {
+            split_url(url).ok_or_else(|| ConfigBuildError::Invalid {
+                field: "url".into(),
+                problem: "Not an https URL with a hostname".into(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl MoatConfig {
+    /// Return the hostname that we should ask for in our HTTP requests.
+    pub fn host(&self) -> &str {
+        self.split_url().0
+    }
+
+    /// Return the hostname that we should open a TLS connection to.
+    ///
+    /// This is the front domain if we have one, and [`host`](Self::host)
+    /// otherwise.
+    pub fn connect_host(&self) -> &str {
+        self.front.as_deref().unwrap_or_else(|| self.host())
+    }
+
+    /// Return the HTTP path to use for the moat endpoint `endpoint`.
+    pub(crate) fn path(&self, endpoint: &str) -> String {
+        let prefix = self.split_url().1.trim_end_matches('/');
+        format!("{}/moat/circumvention/{}", prefix, endpoint)
+    }
+
+    /// Return how long to wait for a reply.
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Return the longest response we'll accept.
+    pub(crate) fn max_response_len(&self) -> usize {
+        self.max_response_len
+    }
+
+    /// Split our URL into a hostname and a path.
+    fn split_url(&self) -> (&str, &str) {
+        // We validated the URL when we were built.
+        split_url(&self.url).unwrap_or((&self.url, ""))
+    }
+}
+
+/// Split an `https` URL into its hostname and its path (which may be empty).
+///
+/// Return None if the URL is not an `https` URL with a hostname.
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("https://")?;
+    let (host, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, ""),
+    };
+    if host.is_empty() || host.contains(['@', ':', '?', '#']) {
+        return None;
+    }
+    Some((host, path))
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let cfg = MoatConfig::default();
+        assert_eq!(cfg.host(), "moat.torproject.org.global.prod.fastly.net");
+        assert_eq!(cfg.connect_host(), "cdn.sstatic.net");
+        assert_eq!(cfg.path("settings"), "/moat/circumvention/settings");
+    }
+
+    #[test]
+    fn custom_url() {
+        let cfg = MoatConfig::builder()
+            .url("https://bridges.example.com/api/")
+            .front(None)
+            .build()
+            .unwrap();
+        assert_eq!(cfg.host(), "bridges.example.com");
+        assert_eq!(cfg.connect_host(), "bridges.example.com");
+        assert_eq!(cfg.path("builtin"), "/api/moat/circumvention/builtin");
+
+        for bad in [
+            "http://example.com/",
+            "https:///x",
+            "https://a@b/",
+            "ftp://x",
+        ] {
+            assert!(MoatConfig::builder().url(bad).build().is_err(), "{}", bad);
+        }
+    }
+}
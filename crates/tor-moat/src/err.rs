@@ -0,0 +1,95 @@
+//! Declare an error type for the tor-moat crate.
+
+use std::sync::Arc;
+
+use thiserror::Error;
+use tor_error::{ErrorKind, HasKind};
+
+/// An error that occurred while obtaining bridges from a moat server.
+#[derive(Error, Debug, Clone)]
+#[non_exhaustive]
+pub enum Error {
+    /// We couldn't open a connection to the moat server.
+    #[error("Unable to connect to moat server")]
+    Connect(#[source] Arc<std::io::Error>),
+
+    /// We got an IO error while talking to the moat server.
+    #[error("IO error while talking to moat server")]
+    Io(#[source] Arc<std::io::Error>),
+
+    /// The moat server took too long to answer.
+    #[error("Timed out waiting for moat server")]
+    Timeout,
+
+    /// The moat server's HTTP response could not be parsed.
+    #[error("Unable to parse HTTP response from moat server")]
+    HttpParse(#[from] httparse::Error),
+
+    /// The moat server's HTTP response was incomplete.
+    #[error("Truncated HTTP response from moat server")]
+    Truncated,
+
+    /// The moat server's HTTP response used an invalid chunked encoding.
+    #[error("Invalid chunked encoding from moat server")]
+    BadChunk,
+
+    /// The moat server's HTTP response was longer than we allow.
+    #[error("HTTP response from moat server was too long")]
+    ResponseTooLong,
+
+    /// The moat server answered with an HTTP status other than 200.
+    #[error("Moat server answered with HTTP status {0}")]
+    HttpStatus(u16),
+
+    /// The moat server's reply was not the JSON we expected.
+    #[error("Unable to parse reply from moat server")]
+    Json(#[source] Arc<serde_json::Error>),
+
+    /// The moat server told us that our request failed.
+    #[error("Moat server reported an error ({code}): {detail}")]
+    Api {
+        /// The error code that the server gave us.
+        code: u16,
+        /// The server's description of the error.
+        detail: String,
+    },
+
+    /// The moat server didn't give us any bridges that we could use.
+    #[error("Moat server gave us no usable bridges")]
+    NoBridges,
+
+    /// The confirmation hook declined the bridges that we received.
+    #[error("Bridges from moat server were declined")]
+    Declined,
+
+    /// We couldn't store the bridges that we received.
+    #[error("Unable to store bridges from moat server")]
+    Persist(#[source] tor_persist::Error),
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(Arc::new(e))
+    }
+}
+
+impl HasKind for Error {
+    fn kind(&self) -> ErrorKind {
+        use Error as E;
+        use ErrorKind as EK;
+        match self {
+            E::Connect(_) | E::Io(_) => EK::LocalNetworkError,
+            E::Timeout => EK::RemoteNetworkTimeout,
+            E::HttpParse(_)
+            | E::Truncated
+            | E::BadChunk
+            | E::ResponseTooLong
+            | E::HttpStatus(_)
+            | E::Json(_)
+            | E::Api { .. }
+            | E::NoBridges => EK::RemoteProtocolViolation,
+            E::Declined => EK::Other,
+            E::Persist(e) => e.kind(),
+        }
+    }
+}
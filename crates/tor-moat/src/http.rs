@@ -0,0 +1,199 @@
+//! Just enough HTTP/1.1 to talk to a moat server.
+//!
+//! We send a single POST request per connection, with `Connection: close`,
+//! and read the response until the server closes the stream.
+
+use std::sync::Arc;
+
+use futures::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use crate::{Error, Result};
+
+/// Maximum number of headers we accept in a response.
+const MAX_HEADERS: usize = 32;
+
+/// Send a POST request with a JSON `body` to `path` on `host` over `stream`,
+/// and return the body of the response.
+///
+/// Gives an error if the response is longer than `max_len` bytes in total,
+/// or if its status is anything other than 200.
+pub(crate) async fn post<S>(
+    stream: &mut S,
+    host: &str,
+    path: &str,
+    body: &[u8],
+    max_len: usize,
+) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/vnd.api+json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    stream.write_all(&request).await.map_err(io_err)?;
+    stream.flush().await.map_err(io_err)?;
+
+    let mut response = Vec::new();
+    let limit = u64::try_from(max_len).unwrap_or(u64::MAX).saturating_add(1);
+    (&mut *stream)
+        .take(limit)
+        .read_to_end(&mut response)
+        .await
+        .map_err(io_err)?;
+    if response.len() > max_len {
+        return Err(Error::ResponseTooLong);
+    }
+
+    parse_response(&response)
+}
+
+/// Parse a complete HTTP response, and return its body.
+fn parse_response(response: &[u8]) -> Result<Vec<u8>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let n_parsed = match parsed.parse(response)? {
+        httparse::Status::Complete(n) => n,
+        httparse::Status::Partial => return Err(Error::Truncated),
+    };
+    match parsed.code {
+        Some(200) => {}
+        Some(code) => return Err(Error::HttpStatus(code)),
+        None => return Err(Error::Truncated),
+    }
+
+    let header = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    };
+    let body = &response[n_parsed..];
+
+    if header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case(b"chunked")) {
+        return decode_chunked(body);
+    }
+    if let Some(len) = header("Content-Length") {
+        let len: usize = std::str::from_utf8(len)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or(httparse::Error::HeaderValue)?;
+        return body.get(..len).map(<[u8]>::to_vec).ok_or(Error::Truncated);
+    }
+    Ok(body.to_vec())
+}
+
+/// Decode a body that was sent with `Transfer-Encoding: chunked`.
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let (idx, size) = match httparse::parse_chunk_size(body) {
+            Ok(httparse::Status::Complete(v)) => v,
+            Ok(httparse::Status::Partial) => return Err(Error::Truncated),
+            Err(httparse::InvalidChunkSize) => return Err(Error::BadChunk),
+        };
+        let size = usize::try_from(size).map_err(|_| Error::ResponseTooLong)?;
+        body = &body[idx..];
+        if size == 0 {
+            return Ok(out);
+        }
+        let chunk = body.get(..size).ok_or(Error::Truncated)?;
+        out.extend_from_slice(chunk);
+        body = body[size..].strip_prefix(b"\r\n").ok_or(Error::BadChunk)?;
+    }
+}
+
+/// Wrap an IO error that happened while talking to the server.
+fn io_err(e: std::io::Error) -> Error {
+    Error::Io(Arc::new(e))
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn content_length() {
+        let resp = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, and more";
+        assert_eq!(parse_response(resp).unwrap(), b"hello");
+
+        let resp = b"HTTP/1.1 200 OK\r\nContent-Length: 50\r\n\r\nhello";
+        assert!(matches!(parse_response(resp), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn chunked() {
+        let resp = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        assert_eq!(parse_response(resp).unwrap(), b"hello, world");
+
+        let resp = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloX";
+        assert!(matches!(parse_response(resp), Err(Error::BadChunk)));
+    }
+
+    #[test]
+    fn bad_status() {
+        let resp = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        assert!(matches!(parse_response(resp), Err(Error::HttpStatus(404))));
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 200"),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (mut client, mut server) = tor_rtmock::io::stream_pair();
+        futures::executor::block_on(async {
+            let server = async {
+                let mut req = Vec::new();
+                let mut buf = [0; 256];
+                while !req.ends_with(b"{}") {
+                    let n = server.read(&mut buf).await.unwrap();
+                    assert_ne!(n, 0);
+                    req.extend_from_slice(&buf[..n]);
+                }
+                let req = std::str::from_utf8(&req).unwrap();
+                assert!(req.starts_with("POST /moat/circumvention/builtin HTTP/1.1\r\n"));
+                assert!(req.contains("\r\nHost: moat.example.com\r\n"));
+                assert!(req.ends_with("\r\n\r\n{}"));
+                server
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n[]")
+                    .await
+                    .unwrap();
+                server.close().await.unwrap();
+            };
+            let client = post(
+                &mut client,
+                "moat.example.com",
+                "/moat/circumvention/builtin",
+                b"{}",
+                1000,
+            );
+            let (_, body) = futures::join!(server, client);
+            assert_eq!(body.unwrap(), b"[]");
+        });
+    }
+}
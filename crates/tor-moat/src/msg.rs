@@ -0,0 +1,218 @@
+//! Requests to, and replies from, the moat circumvention API.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// A bridge that we received from a moat server.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MoatBridge {
+    /// The pluggable transport that this bridge uses (for example `obfs4`).
+    transport: String,
+    /// Where the server got this bridge (for example `bridgedb` or `builtin`).
+    source: String,
+    /// The bridge line, in the format used in our configuration.
+    line: String,
+}
+
+impl MoatBridge {
+    /// Return the pluggable transport that this bridge uses.
+    pub fn transport(&self) -> &str {
+        &self.transport
+    }
+
+    /// Return the source that the server said this bridge came from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Return the bridge line for this bridge.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+}
+
+/// Body of a request to the `settings` endpoint.
+#[derive(Debug, Serialize)]
+pub(crate) struct SettingsRequest<'a> {
+    /// The two-letter country code that we're in, if we know it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) country: Option<&'a str>,
+    /// The transports that we support.
+    pub(crate) transports: &'a [String],
+}
+
+/// Body of a request to the `builtin` endpoint.
+#[derive(Debug, Serialize)]
+pub(crate) struct BuiltinRequest<'a> {
+    /// The transports that we support.
+    pub(crate) transports: &'a [String],
+}
+
+/// An error, as reported by the moat API.
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    /// An HTTP-like error code.
+    code: u16,
+    /// A description of what went wrong.
+    #[serde(default)]
+    detail: String,
+}
+
+/// Reply from the `settings` endpoint.
+#[derive(Debug, Deserialize)]
+struct SettingsResponse {
+    /// The settings that the server recommends, in order of preference.
+    #[serde(default)]
+    settings: Vec<Setting>,
+    /// Any errors that the server reported.
+    #[serde(default)]
+    errors: Vec<ApiError>,
+}
+
+/// A single recommended setting.
+#[derive(Debug, Deserialize)]
+struct Setting {
+    /// The bridges to use with this setting.
+    bridges: BridgeSet,
+}
+
+/// A set of bridges for a single transport.
+#[derive(Debug, Deserialize)]
+struct BridgeSet {
+    /// The transport that these bridges use.
+    #[serde(rename = "type")]
+    transport: String,
+    /// Where the server got these bridges.
+    #[serde(default)]
+    source: String,
+    /// The bridge lines.
+    #[serde(default)]
+    bridge_strings: Vec<String>,
+}
+
+/// Return an error if `errors` is nonempty.
+fn check_errors(errors: Vec<ApiError>) -> Result<()> {
+    match errors.into_iter().next() {
+        Some(ApiError { code, detail }) => Err(Error::Api { code, detail }),
+        None => Ok(()),
+    }
+}
+
+/// Parse the reply from the `settings` endpoint.
+pub(crate) fn parse_settings(body: &[u8]) -> Result<Vec<MoatBridge>> {
+    let resp: SettingsResponse = serde_json::from_slice(body)?;
+    check_errors(resp.errors)?;
+    Ok(resp
+        .settings
+        .into_iter()
+        .flat_map(|Setting { bridges }| {
+            let BridgeSet {
+                transport,
+                source,
+                bridge_strings,
+            } = bridges;
+            bridge_strings.into_iter().map(move |line| MoatBridge {
+                transport: transport.clone(),
+                source: source.clone(),
+                line,
+            })
+        })
+        .collect())
+}
+
+/// Parse the reply from the `builtin` endpoint.
+pub(crate) fn parse_builtin(body: &[u8]) -> Result<Vec<MoatBridge>> {
+    // The builtin endpoint reports errors in the same way as the
+    // settings endpoint, but otherwise returns a map from transport to lines.
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Reply {
+        /// An error.
+        Errors {
+            /// The errors that the server reported.
+            errors: Vec<ApiError>,
+        },
+        /// A set of bridges.
+        Bridges(BTreeMap<String, Vec<String>>),
+    }
+
+    match serde_json::from_slice(body)? {
+        Reply::Errors { errors } => {
+            check_errors(errors)?;
+            Ok(vec![])
+        }
+        Reply::Bridges(map) => Ok(map
+            .into_iter()
+            .flat_map(|(transport, lines)| {
+                lines.into_iter().map(move |line| MoatBridge {
+                    transport: transport.clone(),
+                    source: "builtin".into(),
+                    line,
+                })
+            })
+            .collect()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn settings() {
+        let body = br#"{
+            "settings": [
+              {"bridges": {"type": "obfs4", "source": "bridgedb",
+                           "bridge_strings": ["obfs4 192.0.2.1:443 0123 cert=x iat-mode=0",
+                                              "obfs4 192.0.2.2:443 4567 cert=y iat-mode=0"]}},
+              {"bridges": {"type": "snowflake", "source": "builtin",
+                           "bridge_strings": ["snowflake 192.0.2.3:80 89ab"]}}
+            ],
+            "country": "zz"
+        }"#;
+        let bridges = parse_settings(body).unwrap();
+        assert_eq!(bridges.len(), 3);
+        assert_eq!(bridges[0].transport(), "obfs4");
+        assert_eq!(bridges[0].source(), "bridgedb");
+        assert_eq!(bridges[2].line(), "snowflake 192.0.2.3:80 89ab");
+
+        let body = br#"{"errors": [{"code": 406, "detail": "Unknown country"}]}"#;
+        assert!(matches!(
+            parse_settings(body),
+            Err(Error::Api { code: 406, .. })
+        ));
+
+        assert!(matches!(parse_settings(b"[1,2"), Err(Error::Json(_))));
+    }
+
+    #[test]
+    fn builtin() {
+        let body = br#"{"obfs4": ["obfs4 192.0.2.1:443 0123"], "meek": []}"#;
+        let bridges = parse_builtin(body).unwrap();
+        assert_eq!(bridges.len(), 1);
+        assert_eq!(bridges[0].transport(), "obfs4");
+        assert_eq!(bridges[0].source(), "builtin");
+
+        let body = br#"{"errors": [{"code": 404, "detail": "Nope"}]}"#;
+        assert!(matches!(
+            parse_builtin(body),
+            Err(Error::Api { code: 404, .. })
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+//! A [`MoatConnector`] that opens validated TLS connections with `native-tls`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs as _};
+
+use async_native_tls::{Certificate, TlsConnector, TlsStream};
+use async_trait::async_trait;
+use tor_rtcompat::{Blocking, NetStreamProvider};
+use tracing::debug;
+
+use crate::MoatConnector;
+
+/// The port that we connect to.
+const HTTPS_PORT: u16 = 443;
+
+/// A [`MoatConnector`] that uses the platform's TLS library.
+///
+/// It looks up the host it is asked for in DNS, connects to port 443, and
+/// negotiates TLS with that host as the SNI hostname, **validating the
+/// server's certificate** against the platform's root certificates.
+///
+/// When the [`MoatConfig`](crate::MoatConfig) has a front domain, that is
+/// the host that we connect to, and whose certificate we validate: this is
+/// what makes the connection domain-fronted.  The moat server's real
+/// hostname only appears inside the encrypted HTTP request.
+pub struct NativeTlsMoatConnector<R> {
+    /// Used to make TCP connections, and to look up hostnames.
+    runtime: R,
+    /// Used to negotiate TLS.
+    tls: TlsConnector,
+    /// Addresses to use for particular hosts, instead of looking them up.
+    addrs: HashMap<String, SocketAddr>,
+}
+
+impl<R> NativeTlsMoatConnector<R>
+where
+    R: NetStreamProvider + Blocking,
+{
+    /// Construct a new connector that trusts the platform's root certificates.
+    pub fn new(runtime: R) -> Self {
+        NativeTlsMoatConnector {
+            runtime,
+            tls: TlsConnector::new(),
+            addrs: HashMap::new(),
+        }
+    }
+
+    /// Also trust the DER-encoded certificate `der` as a root certificate.
+    pub fn with_root_certificate(mut self, der: &[u8]) -> io::Result<Self> {
+        let cert = Certificate::from_der(der).map_err(io::Error::other)?;
+        self.tls = self.tls.add_root_certificate(cert);
+        Ok(self)
+    }
+
+    /// Connect to `addr` whenever we are asked for `host`, instead of looking
+    /// `host` up in DNS.
+    ///
+    /// We still validate the server's certificate for `host`.
+    pub fn with_address(mut self, host: impl Into<String>, addr: SocketAddr) -> Self {
+        self.addrs.insert(host.into(), addr);
+        self
+    }
+
+    /// Return the addresses to try for `host`.
+    async fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addr) = self.addrs.get(host) {
+            return Ok(vec![*addr]);
+        }
+        let host = host.to_owned();
+        self.runtime
+            .spawn_blocking(move || {
+                (host.as_str(), HTTPS_PORT)
+                    .to_socket_addrs()
+                    .map(Iterator::collect)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl<R> MoatConnector for NativeTlsMoatConnector<R>
+where
+    R: NetStreamProvider + Blocking,
+{
+    type Stream = TlsStream<R::Stream>;
+
+    async fn connect(&self, host: &str) -> io::Result<Self::Stream> {
+        let mut last_err = None;
+        for addr in self.resolve(host).await? {
+            match self.runtime.connect(&addr).await {
+                Ok(stream) => {
+                    return self
+                        .tls
+                        .connect(host, stream)
+                        .await
+                        .map_err(io::Error::other);
+                }
+                Err(e) => {
+                    debug!("Couldn't connect to {} for moat: {}", host, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no addresses for moat host")
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{Error, MoatClient, MoatConfig};
+    use native_tls_crate as native_tls;
+    use std::io::{Read as _, Write as _};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread::JoinHandle;
+
+    /// A self-signed certificate for `front.example`; see `testdata/README.md`.
+    const FRONT_DER: &[u8] = include_bytes!("../testdata/front.der");
+    /// The same certificate, with its private key.
+    const FRONT_PFX: &[u8] = include_bytes!("../testdata/front.pfx");
+    /// The password for [`FRONT_PFX`].
+    const FRONT_PFX_PASSWORD: &str = "abc";
+
+    /// Run a fake front server that answers a single moat request for
+    /// `moat.example.com` with one built-in bridge.
+    ///
+    /// Returns the address it listens on, and a handle that returns the
+    /// request it received, or `None` if the TLS handshake failed.
+    fn fake_front() -> (SocketAddr, JoinHandle<Option<String>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let identity = native_tls::Identity::from_pkcs12(FRONT_PFX, FRONT_PFX_PASSWORD).unwrap();
+        let handle = std::thread::spawn(move || {
+            let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+            let (conn, _) = listener.accept().unwrap();
+            let mut conn = acceptor.accept(conn).ok()?;
+            let mut req = Vec::new();
+            let mut buf = [0; 256];
+            while !req.ends_with(b"}") {
+                let n = conn.read(&mut buf).unwrap();
+                assert_ne!(n, 0);
+                req.extend_from_slice(&buf[..n]);
+            }
+            let body =
+                r#"{"vanilla": ["38.229.33.83:80 0BAC39417268B96B9F514E7F63FA6FBA1A788955"]}"#;
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            conn.write_all(resp.as_bytes()).unwrap();
+            conn.shutdown().unwrap();
+            Some(String::from_utf8(req).unwrap())
+        });
+        (addr, handle)
+    }
+
+    /// Return a configuration for `moat.example.com`, fronted by `front`.
+    fn config(front: &str) -> MoatConfig {
+        MoatConfig::builder()
+            .url("https://moat.example.com/")
+            .front(Some(front.into()))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn fronted() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let (addr, server) = fake_front();
+            let connector = NativeTlsMoatConnector::new(rt.clone())
+                .with_root_certificate(FRONT_DER)
+                .unwrap()
+                .with_address("front.example", addr);
+            let client = MoatClient::new(config("front.example"), connector, rt.clone());
+
+            let bridges = client.builtin_bridges(&[]).await.unwrap();
+            assert_eq!(bridges.len(), 1);
+
+            // We asked the front for the real moat server.
+            let req = server.join().unwrap().unwrap();
+            assert!(req.starts_with("POST /moat/circumvention/builtin HTTP/1.1\r\n"));
+            assert!(req.contains("\r\nHost: moat.example.com\r\n"));
+        });
+    }
+
+    #[test]
+    fn wrong_certificate() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            // The server's certificate is for front.example, not other.example.
+            let (addr, server) = fake_front();
+            let connector = NativeTlsMoatConnector::new(rt.clone())
+                .with_root_certificate(FRONT_DER)
+                .unwrap()
+                .with_address("other.example", addr);
+            let client = MoatClient::new(config("other.example"), connector, rt.clone());
+
+            let err = client.builtin_bridges(&[]).await.unwrap_err();
+            assert!(matches!(err, Error::Connect(_)));
+            assert!(server.join().unwrap().is_none());
+        });
+    }
+}
@@ -35,6 +35,7 @@ use std::sync::Arc;
 
 use tor_linkspec::HasRelayIds;
 use tor_netdoc::{doc::netstatus, types::policy::PortPolicy};
+use tor_protover::Protocols;
 
 use crate::{FamilyRules, Relay, SubnetConfig};
 
@@ -164,6 +165,42 @@ impl<'a> RelayDetails<'a> {
     pub fn ipv6_declared_policy(&self) -> &Arc<PortPolicy> {
         self.0.md.ipv6_policy()
     }
+    /// Return the nickname of this relay, as listed in the consensus.
+    ///
+    /// Nicknames are neither unique nor authenticated:
+    /// never use them to identify a relay.
+    pub fn nickname(&self) -> &str {
+        self.0.rs.nickname()
+    }
+    /// Return the set of flags that the directory authorities assigned to
+    /// this relay.
+    pub fn flags(&self) -> netstatus::RelayFlags {
+        *self.0.rs.flags()
+    }
+    /// Return the bandwidth weight that the consensus lists for this relay,
+    /// and whether that weight was measured by the bandwidth authorities.
+    ///
+    /// This is the raw value from the consensus.  To learn how likely this
+    /// relay is to be chosen for a given position, use
+    /// [`NetDir::relay_weight`](crate::NetDir::relay_weight) instead.
+    ///
+    /// An [`Unmeasured`](netstatus::RelayWeight::Unmeasured) weight is
+    /// derived from the bandwidth that the relay advertises.  We can't
+    /// report the advertised bandwidth itself, or the relay's uptime:
+    /// those are only listed in full server descriptors, which we don't
+    /// download.
+    pub fn consensus_weight(&self) -> netstatus::RelayWeight {
+        *self.0.rs.weight()
+    }
+    /// Return the software version that this relay claims to be running,
+    /// if the consensus lists one.
+    pub fn version(&self) -> Option<&netstatus::RelayVersion> {
+        self.0.rs.version()
+    }
+    /// Return the subprotocol versions that this relay claims to support.
+    pub fn protovers(&self) -> &Protocols {
+        self.0.rs.protovers()
+    }
 }
 
 /// A view for lower-level details about a [`UncheckedRelay`](crate::UncheckedRelay).
@@ -184,6 +221,32 @@ impl<'a> UncheckedRelayDetails<'a> {
     pub fn is_dir_cache(&self) -> bool {
         rs_is_dir_cache(self.0.rs)
     }
+    /// Return the nickname of this relay, as listed in the consensus.
+    ///
+    /// See [`RelayDetails::nickname`].
+    pub fn nickname(&self) -> &str {
+        self.0.rs.nickname()
+    }
+    /// Return the set of flags that the directory authorities assigned to
+    /// this relay.
+    pub fn flags(&self) -> netstatus::RelayFlags {
+        *self.0.rs.flags()
+    }
+    /// Return the bandwidth weight that the consensus lists for this relay.
+    ///
+    /// See [`RelayDetails::consensus_weight`].
+    pub fn consensus_weight(&self) -> netstatus::RelayWeight {
+        *self.0.rs.weight()
+    }
+    /// Return the software version that this relay claims to be running,
+    /// if the consensus lists one.
+    pub fn version(&self) -> Option<&netstatus::RelayVersion> {
+        self.0.rs.version()
+    }
+    /// Return the subprotocol versions that this relay claims to support.
+    pub fn protovers(&self) -> &Protocols {
+        self.0.rs.protovers()
+    }
 }
 
 /// Return true if `rs` is usable as a directory cache.
@@ -2618,6 +2618,52 @@ mod test {
             .allows_some_port());
     }
 
+    #[test]
+    fn test_consensus_details() {
+        use tor_netdoc::doc::netstatus::{RelayFlags, RelayWeight as RsWeight};
+        let netdir = construct_custom_netdir(|pos, nb, _| {
+            if pos == 17 {
+                nb.rs
+                    .nickname("Seventeen".into())
+                    .version("Tor 0.4.8.9".into());
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let r4 = netdir.by_id(&Ed25519Identity::from([4; 32])).unwrap();
+        let r17 = netdir.by_id(&Ed25519Identity::from([17; 32])).unwrap();
+
+        let d4 = r4.low_level_details();
+        let d17 = r17.low_level_details();
+        assert_eq!(d4.nickname(), "Unnamed");
+        assert_eq!(d17.nickname(), "Seventeen");
+        assert!(d4.version().is_none());
+        assert_eq!(d17.version().unwrap().to_string(), "0.4.8.9");
+        assert!(d4.flags().contains(RelayFlags::HSDIR));
+        assert!(!d4.flags().contains(RelayFlags::EXIT));
+        assert!(d17.flags().contains(RelayFlags::EXIT));
+        assert!(matches!(d4.consensus_weight(), RsWeight::Measured(5000)));
+        assert!(matches!(d17.consensus_weight(), RsWeight::Measured(8000)));
+        assert!(d4
+            .protovers()
+            .supports_named_subver(tor_protover::named::DIRCACHE_CONSDIFF));
+        assert!(!d17
+            .protovers()
+            .supports_named_subver(tor_protover::named::DIRCACHE_CONSDIFF));
+
+        let u17 = netdir
+            .all_relays()
+            .find(|r| r.rsa_identity() == Some(&RsaIdentity::from([17; 20])))
+            .unwrap();
+        assert_eq!(u17.low_level_details().nickname(), "Seventeen");
+        assert!(matches!(
+            u17.low_level_details().consensus_weight(),
+            RsWeight::Measured(8000)
+        ));
+    }
+
     #[cfg(feature = "experimental-api")]
     #[test]
     fn test_accessors() {
@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tor_netdoc::types::policy::{AddrPortPattern, PortPolicy};
+
+fuzz_target!(|data: &str| {
+    // Anything we can parse, we should be able to format and re-parse
+    // to the same value.
+    if let Ok(p) = data.parse::<PortPolicy>() {
+        let again: PortPolicy = p.to_string().parse().expect("couldn't reparse PortPolicy");
+        assert_eq!(p, again);
+    }
+    if let Ok(p) = data.parse::<AddrPortPattern>() {
+        let again: AddrPortPattern = p
+            .to_string()
+            .parse()
+            .expect("couldn't reparse AddrPortPattern");
+        assert_eq!(p, again);
+    }
+});
@@ -80,6 +80,7 @@ pub use rs::build::RouterStatusBuilder;
 pub use rs::MdConsensusRouterStatus;
 #[cfg(feature = "ns_consensus")]
 pub use rs::NsConsensusRouterStatus;
+pub use rs::Version as RelayVersion;
 use void::ResultVoidExt as _;
 
 /// The lifetime of a networkstatus document.
@@ -149,6 +149,14 @@ impl AddrPortPattern {
         }
     }
 
+    /// Return an AddrPortPattern matching all IPv6 targets.
+    pub fn new_all_ipv6() -> Self {
+        Self {
+            pattern: IpPattern::V6Star,
+            ports: PortRange::new_all(),
+        }
+    }
+
     /// Return true iff this pattern matches a given address and port.
     pub fn matches(&self, addr: &IpAddr, port: u16) -> bool {
         self.pattern.matches(addr) && self.ports.contains(port)
@@ -370,6 +378,11 @@ mod test {
 
         check("0.0.0.0/0:*", &["127.0.0.1:80"], &["[f00b::]:80"]);
         check("[::]/0:*", &["[f00b::]:80"], &["127.0.0.1:80"]);
+
+        assert_eq!(
+            AddrPortPattern::new_all_ipv6(),
+            "[::]/0:*".parse::<AddrPortPattern>().unwrap()
+        );
     }
 
     #[test]
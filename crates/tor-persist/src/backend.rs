@@ -0,0 +1,329 @@
+//! A [`StateMgr`] implementation on top of a pluggable storage backend.
+//!
+//! Applications that can't (or don't want to) keep their state in files
+//! can implement [`StateBackend`] for their own storage: app-private storage
+//! on a mobile platform, a remote key-value store, a vault, and so on.
+//! [`BackendStateMgr`] then takes care of serialization and of enforcing the
+//! lock, so that the backend only needs to move bytes around.
+
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, LockStatus, RecordFormat, Result, StateMgr, Transaction};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+/// An error reported by a [`StateBackend`].
+pub type BackendError = Arc<dyn std::error::Error + Send + Sync + 'static>;
+
+/// A storage backend that can hold Arti's persistent state.
+///
+/// Values are opaque byte strings.  (Usually, [`BackendStateMgr`] stores
+/// UTF-8 JSON documents, but it stores other [`RecordFormat`]s on request,
+/// so backends should not rely on this.)
+///
+/// # Locking
+///
+/// As with the other state managers, at most one user of a given store
+/// should be able to write to it at a time.  The backend is responsible for
+/// deciding who holds the lock: [`try_lock`](StateBackend::try_lock) should
+/// succeed for at most one process (or other user) at a time, and should
+/// report [`LockStatus::AlreadyHeld`] if this backend already holds the lock.
+///
+/// [`BackendStateMgr`] never calls [`put`](StateBackend::put) or
+/// [`put_all`](StateBackend::put_all) unless
+/// [`is_locked`](StateBackend::is_locked) returns true.
+///
+/// # Keys
+///
+/// Keys are short ASCII strings chosen by Arti, such as `"guards"` or
+/// `"circuit_timeouts"`; backends may store them however they like.
+pub trait StateBackend: Send + Sync + 'static {
+    /// Return the value stored with `key`, or `None` if there is none.
+    fn get(&self, key: &str) -> std::result::Result<Option<Vec<u8>>, BackendError>;
+
+    /// Store `value` with `key`, replacing any previous value.
+    ///
+    /// This should be atomic: after a crash, a later `get` should return
+    /// either the old value or the new one.
+    fn put(&self, key: &str, value: &[u8]) -> std::result::Result<(), BackendError>;
+
+    /// Store every value in `values`, replacing any previous values with the
+    /// same keys.
+    ///
+    /// This must be atomic: after an error or a crash, a later `get` should
+    /// return either all of the old values or all of the new ones.
+    fn put_all(&self, values: &[(String, Vec<u8>)]) -> std::result::Result<(), BackendError>;
+
+    /// Return the keys of every value in the store, in any order.
+    fn list(&self) -> std::result::Result<Vec<String>, BackendError>;
+
+    /// Try to acquire the write lock for the store, without blocking.
+    fn try_lock(&self) -> std::result::Result<LockStatus, BackendError>;
+
+    /// Release the write lock, if we hold it.  If we don't, do nothing.
+    fn unlock(&self) -> std::result::Result<(), BackendError>;
+
+    /// Return true if we currently hold the write lock.
+    fn is_locked(&self) -> bool;
+}
+
+/// A [`StateMgr`] that stores its state in a [`StateBackend`].
+///
+/// See the [module documentation](self) for details.
+#[derive(Clone)]
+pub struct BackendStateMgr {
+    /// The backend that holds our state.
+    backend: Arc<dyn StateBackend>,
+}
+
+impl fmt::Debug for BackendStateMgr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackendStateMgr").finish_non_exhaustive()
+    }
+}
+
+impl BackendStateMgr {
+    /// Create a new `BackendStateMgr` that stores state in `backend`.
+    ///
+    /// The new manager is read-only until you call
+    /// [`try_lock`](StateMgr::try_lock), unless `backend` already holds its
+    /// lock.
+    pub fn new<B: StateBackend>(backend: B) -> Self {
+        BackendStateMgr {
+            backend: Arc::new(backend),
+        }
+    }
+
+    /// Return the keys of every value in the store, in any order.
+    pub fn list_keys(&self) -> Result<Vec<String>> {
+        self.backend
+            .list()
+            .map_err(|e| Error::new(ErrorSource::Backend(e), Action::Listing, Resource::Manager))
+    }
+
+    /// Return an error Resource corresponding to a given `key`.
+    fn err_resource(&self, key: &str) -> Resource {
+        Resource::Key {
+            key: key.to_string(),
+        }
+    }
+}
+
+impl StateMgr for BackendStateMgr {
+    fn load<D>(&self, key: &str) -> Result<Option<D>>
+    where
+        D: DeserializeOwned,
+    {
+        let Some(value) = self.backend.get(key).map_err(|e| {
+            Error::new(
+                ErrorSource::Backend(e),
+                Action::Loading,
+                self.err_resource(key),
+            )
+        })?
+        else {
+            return Ok(None);
+        };
+        let value = crate::format::decode(&value)
+            .map_err(|e| Error::new(e, Action::Loading, self.err_resource(key)))?;
+        Ok(Some(value))
+    }
+
+    fn store<S>(&self, key: &str, val: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        self.store_with_format(key, val, RecordFormat::Json)
+    }
+
+    fn store_with_format<S>(&self, key: &str, val: &S, format: RecordFormat) -> Result<()>
+    where
+        S: Serialize,
+    {
+        if !self.can_store() {
+            return Err(Error::new(
+                ErrorSource::NoLock,
+                Action::Storing,
+                Resource::Manager,
+            ));
+        }
+        let value = crate::format::encode(val, format)
+            .map_err(|e| Error::new(e, Action::Storing, self.err_resource(key)))?;
+        self.backend.put(key, &value).map_err(|e| {
+            Error::new(
+                ErrorSource::Backend(e),
+                Action::Storing,
+                self.err_resource(key),
+            )
+        })
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        if !self.can_store() {
+            return Err(Error::new(
+                ErrorSource::NoLock,
+                Action::Storing,
+                Resource::Manager,
+            ));
+        }
+        let values = txn
+            .into_values()
+            .map(|(key, val)| {
+                let val = serde_json::to_vec_pretty(&val)
+                    .map_err(|e| Error::new(e, Action::Storing, self.err_resource(&key)))?;
+                Ok((key, val))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.backend
+            .put_all(&values)
+            .map_err(|e| Error::new(ErrorSource::Backend(e), Action::Storing, Resource::Manager))
+    }
+
+    fn can_store(&self) -> bool {
+        self.backend.is_locked()
+    }
+
+    fn try_lock(&self) -> Result<LockStatus> {
+        self.backend
+            .try_lock()
+            .map_err(|e| Error::new(ErrorSource::Backend(e), Action::Locking, Resource::Manager))
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.backend.unlock().map_err(|e| {
+            Error::new(
+                ErrorSource::Backend(e),
+                Action::Unlocking,
+                Resource::Manager,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use tor_error::{ErrorKind, HasKind};
+
+    /// A backend that keeps values in a map, and can be told to fail.
+    #[derive(Default)]
+    struct MapBackend {
+        entries: Mutex<HashMap<String, Vec<u8>>>,
+        locked: AtomicBool,
+        broken: Arc<AtomicBool>,
+    }
+
+    /// The error from a broken [`MapBackend`].
+    #[derive(Debug, thiserror::Error)]
+    #[error("backend is broken")]
+    struct Broken;
+
+    impl MapBackend {
+        fn check(&self) -> std::result::Result<(), BackendError> {
+            if self.broken.load(Ordering::SeqCst) {
+                Err(Arc::new(Broken))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl StateBackend for MapBackend {
+        fn get(&self, key: &str) -> std::result::Result<Option<Vec<u8>>, BackendError> {
+            self.check()?;
+            Ok(self.entries.lock().unwrap().get(key).cloned())
+        }
+        fn put(&self, key: &str, value: &[u8]) -> std::result::Result<(), BackendError> {
+            self.check()?;
+            assert!(self.is_locked());
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+        fn put_all(&self, values: &[(String, Vec<u8>)]) -> std::result::Result<(), BackendError> {
+            self.check()?;
+            assert!(self.is_locked());
+            self.entries.lock().unwrap().extend(values.iter().cloned());
+            Ok(())
+        }
+        fn list(&self) -> std::result::Result<Vec<String>, BackendError> {
+            self.check()?;
+            Ok(self.entries.lock().unwrap().keys().cloned().collect())
+        }
+        fn try_lock(&self) -> std::result::Result<LockStatus, BackendError> {
+            self.check()?;
+            if self.locked.swap(true, Ordering::SeqCst) {
+                Ok(LockStatus::AlreadyHeld)
+            } else {
+                Ok(LockStatus::NewlyAcquired)
+            }
+        }
+        fn unlock(&self) -> std::result::Result<(), BackendError> {
+            self.locked.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+        fn is_locked(&self) -> bool {
+            self.locked.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn load_store() {
+        let mgr = BackendStateMgr::new(MapBackend::default());
+        assert_eq!(mgr.load::<String>("x").unwrap(), None);
+
+        let err = mgr.store("x", &"hello").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BadApiUsage);
+
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::AlreadyHeld);
+        assert!(mgr.can_store());
+        mgr.store("x", &"hello").unwrap();
+        mgr.store("y", &[1_u8, 2, 3]).unwrap();
+        assert_eq!(mgr.load::<String>("x").unwrap().unwrap(), "hello");
+        assert_eq!(mgr.load::<Vec<u8>>("y").unwrap().unwrap(), vec![1, 2, 3]);
+
+        let mut keys = mgr.list_keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["x", "y"]);
+
+        mgr.unlock().unwrap();
+        assert!(!mgr.can_store());
+        assert!(mgr.store("x", &"bye").is_err());
+        assert_eq!(mgr.load::<String>("x").unwrap().unwrap(), "hello");
+    }
+
+    #[test]
+    fn backend_errors() {
+        let backend = MapBackend::default();
+        let broken = Arc::clone(&backend.broken);
+        let mgr = BackendStateMgr::new(backend);
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        mgr.store("x", &"hello").unwrap();
+
+        broken.store(true, Ordering::SeqCst);
+        let err = mgr.load::<String>("x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PersistentStateAccessFailed);
+        assert!(matches!(err.source(), ErrorSource::Backend(_)));
+        assert!(mgr.store("x", &"bye").is_err());
+        assert!(mgr.list_keys().is_err());
+    }
+}
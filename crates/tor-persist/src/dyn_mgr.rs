@@ -0,0 +1,229 @@
+//! A [`StateMgr`] that can hold any other `StateMgr`.
+
+use crate::err::{Action, Resource};
+use crate::{Error, JsonValue, LockStatus, RecordFormat, Result, StateMgr, Transaction};
+use futures::FutureExt as _;
+use oneshot_fused_workaround as oneshot;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+/// An object-safe version of [`StateMgr`], so that [`DynStateMgr`] can hold
+/// any `StateMgr`.
+///
+/// Values pass through it as [`JsonValue`]s.
+trait ErasedStateMgr: Send + Sync {
+    /// As [`StateMgr::load`].
+    fn load_value(&self, key: &str) -> Result<Option<JsonValue>>;
+    /// As [`StateMgr::store_with_format`].
+    fn store_value(&self, key: &str, val: &JsonValue, format: RecordFormat) -> Result<()>;
+    /// As [`StateMgr::commit`].
+    fn commit_txn(&self, txn: Transaction) -> Result<()>;
+    /// As [`StateMgr::can_store`].
+    fn can_store_values(&self) -> bool;
+    /// As [`StateMgr::try_lock`].
+    fn try_lock_store(&self) -> Result<LockStatus>;
+    /// As [`StateMgr::unlock`].
+    fn unlock_store(&self) -> Result<()>;
+}
+
+impl<M: StateMgr + Send + Sync> ErasedStateMgr for M {
+    fn load_value(&self, key: &str) -> Result<Option<JsonValue>> {
+        self.load(key)
+    }
+    fn store_value(&self, key: &str, val: &JsonValue, format: RecordFormat) -> Result<()> {
+        self.store_with_format(key, val, format)
+    }
+    fn commit_txn(&self, txn: Transaction) -> Result<()> {
+        self.commit(txn)
+    }
+    fn can_store_values(&self) -> bool {
+        self.can_store()
+    }
+    fn try_lock_store(&self) -> Result<LockStatus> {
+        self.try_lock()
+    }
+    fn unlock_store(&self) -> Result<()> {
+        self.unlock()
+    }
+}
+
+/// A [`StateMgr`] that wraps any other `StateMgr`, so that code can hold
+/// one without being generic over its type.
+///
+/// Every value passes through a [`JsonValue`] on its way to and from the
+/// wrapped manager, which is a little slower, but otherwise this behaves just
+/// like the manager that it wraps.
+#[derive(Clone)]
+pub struct DynStateMgr {
+    /// Inner reference-counted state.
+    inner: Arc<DynStateMgrInner>,
+}
+
+/// The inner state of a [`DynStateMgr`].
+struct DynStateMgrInner {
+    /// The manager that we wrap.
+    mgr: Box<dyn ErasedStateMgr>,
+    /// A oneshot sender that is used to alert other tasks when this manager
+    /// is finally dropped.
+    ///
+    /// It is a sender for Void because we never actually want to send anything here;
+    /// we only want to generate canceled events.
+    #[allow(dead_code)] // the only purpose of this field is to be dropped.
+    dropped_tx: oneshot::Sender<void::Void>,
+    /// Cloneable handle which resolves when this manager is dropped.
+    dropped_rx: futures::future::Shared<oneshot::Receiver<void::Void>>,
+}
+
+impl fmt::Debug for DynStateMgr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynStateMgr").finish_non_exhaustive()
+    }
+}
+
+impl DynStateMgr {
+    /// Wrap `mgr`.
+    pub fn new<M: StateMgr + Send + Sync + 'static>(mgr: M) -> Self {
+        let (dropped_tx, dropped_rx) = oneshot::channel();
+        DynStateMgr {
+            inner: Arc::new(DynStateMgrInner {
+                mgr: Box::new(mgr),
+                dropped_tx,
+                dropped_rx: dropped_rx.shared(),
+            }),
+        }
+    }
+
+    /// Return a future that resolves once this manager, and every clone of
+    /// it, has been dropped.
+    ///
+    /// (This is like [`FsStateMgr::wait_for_unlock`](crate::FsStateMgr::wait_for_unlock).)
+    pub fn wait_for_drop(&self) -> impl futures::Future<Output = ()> + Send + Sync + 'static {
+        self.inner.dropped_rx.clone().map(|_| ())
+    }
+}
+
+impl StateMgr for DynStateMgr {
+    fn load<D>(&self, key: &str) -> Result<Option<D>>
+    where
+        D: DeserializeOwned,
+    {
+        let Some(value) = self.inner.mgr.load_value(key)? else {
+            return Ok(None);
+        };
+        serde_json::from_value(value).map(Some).map_err(|e| {
+            Error::new(
+                e,
+                Action::Loading,
+                Resource::Key {
+                    key: key.to_string(),
+                },
+            )
+        })
+    }
+
+    fn store<S>(&self, key: &str, val: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        self.store_with_format(key, val, RecordFormat::Json)
+    }
+
+    fn store_with_format<S>(&self, key: &str, val: &S, format: RecordFormat) -> Result<()>
+    where
+        S: Serialize,
+    {
+        let value = serde_json::to_value(val).map_err(|e| {
+            Error::new(
+                e,
+                Action::Storing,
+                Resource::Key {
+                    key: key.to_string(),
+                },
+            )
+        })?;
+        self.inner.mgr.store_value(key, &value, format)
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        self.inner.mgr.commit_txn(txn)
+    }
+
+    fn can_store(&self) -> bool {
+        self.inner.mgr.can_store_values()
+    }
+
+    fn try_lock(&self) -> Result<LockStatus> {
+        self.inner.mgr.try_lock_store()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.inner.mgr.unlock_store()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::MemoryStateMgr;
+    use std::collections::HashMap;
+
+    #[test]
+    fn wraps_manager() {
+        let memory = MemoryStateMgr::new();
+        let mgr = DynStateMgr::new(memory.clone());
+
+        assert!(!mgr.can_store());
+        assert!(mgr.store("guards", &"hello").is_err());
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert!(memory.can_store());
+
+        let mut guards = HashMap::new();
+        guards.insert("a".to_string(), vec![1_u32, 2, 3]);
+        mgr.store("guards", &guards).unwrap();
+        assert_eq!(
+            mgr.load::<HashMap<String, Vec<u32>>>("guards").unwrap(),
+            Some(guards.clone())
+        );
+        // The value went to the wrapped manager.
+        assert_eq!(
+            memory.load::<HashMap<String, Vec<u32>>>("guards").unwrap(),
+            Some(guards)
+        );
+        assert_eq!(mgr.load::<String>("nothing").unwrap(), None);
+
+        let mut txn = Transaction::new();
+        txn.store("x", &1_u8).unwrap();
+        txn.store("y", &2_u8).unwrap();
+        mgr.commit(txn).unwrap();
+        assert_eq!(memory.load::<u8>("y").unwrap(), Some(2));
+
+        mgr.unlock().unwrap();
+        assert!(!memory.can_store());
+    }
+
+    #[test]
+    fn wait_for_drop() {
+        let mgr = DynStateMgr::new(MemoryStateMgr::new());
+        let clone = mgr.clone();
+        let mut dropped = mgr.wait_for_drop();
+
+        drop(mgr);
+        assert!((&mut dropped).now_or_never().is_none());
+        drop(clone);
+        assert!(dropped.now_or_never().is_some());
+    }
+}
@@ -0,0 +1,548 @@
+//! Encrypting wrapper around a StateMgr.
+//!
+//! Our persistent state can say a lot about a client: its guards, for
+//! example, stay the same for months, and can be used to recognize it.  An
+//! [`EncryptedStateMgr`] encrypts and authenticates every value before passing
+//! it to some other [`StateMgr`], so that somebody who can read the stored
+//! state (but doesn't know the key) learns nothing from it except which keys
+//! are present, and roughly how large their values are.
+//!
+//! # Construction
+//!
+//! Each value is serialized as JSON, and then encrypted with AES-256-CTR and
+//! authenticated with a SHA3-256 MAC, much as in the onion service descriptor
+//! encryption of `rend-spec-v3`.  The cipher key, IV, and MAC key are derived
+//! with SHAKE-256 from the [`StateEncryptionKey`], a fresh random salt, and the
+//! key under which the value is stored, so that a value can't be moved from
+//! one key to another without detection.
+//!
+//! # Passphrases
+//!
+//! [`EncryptedStateMgr::with_passphrase`] derives the [`StateEncryptionKey`]
+//! from a passphrase with Argon2id.  The salt and the Argon2id parameters
+//! are kept, unencrypted, under the reserved key `state_encryption` in the
+//! underlying manager, so that the same passphrase gives the same key the
+//! next time.
+
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, LockStatus, Result, StateMgr, Transaction};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64ct::{Base64, Encoding as _};
+use cipher::{KeyIvInit as _, StreamCipher as _};
+use digest::{ExtendableOutput as _, FixedOutput as _, Update as _, XofReader as _};
+use rand::{CryptoRng, Rng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tor_error::{bad_api_usage, into_bad_api_usage};
+use tor_llcrypto::cipher::aes::Aes256Ctr as Cipher;
+use tor_llcrypto::d::{Sha3_256 as Hash, Shake256 as Kdf};
+use tor_llcrypto::util::ct::CtByteArray;
+use zeroize::Zeroizing;
+
+/// Length of a [`StateEncryptionKey`].
+const KEY_LEN: usize = 32;
+/// Length of the random salt that we generate for each value.
+const SALT_LEN: usize = 16;
+/// Length of our MAC.
+const MAC_LEN: usize = 32;
+/// Length of the cipher key that we derive.
+const CIPHER_KEY_LEN: usize = 32;
+/// Length of the cipher IV that we derive.
+const IV_LEN: usize = 16;
+/// Length of the MAC key that we derive.
+const MAC_KEY_LEN: usize = 32;
+
+/// A personalization string for our key derivation.
+const KDF_PERSONALIZATION: &[u8] = b"tor-persist encrypted state v1";
+
+/// The key under which [`EncryptedStateMgr::with_passphrase`] keeps its
+/// [`PassphraseRecord`].
+const PASSPHRASE_RECORD_KEY: &str = "state_encryption";
+
+/// A secret key used to encrypt the values in an [`EncryptedStateMgr`].
+///
+/// The key is supplied by the embedder, who is responsible for keeping it
+/// somewhere other than the state that it protects.  If the key needs to come
+/// from a passphrase, use [`StateEncryptionKey::from_passphrase`], or let
+/// [`EncryptedStateMgr::with_passphrase`] take care of the salt as well.
+#[derive(Clone)]
+pub struct StateEncryptionKey(Zeroizing<[u8; KEY_LEN]>);
+
+impl StateEncryptionKey {
+    /// Generate a new random key.
+    pub fn generate<R: Rng + CryptoRng>(rng: &mut R) -> Self {
+        let mut key = Zeroizing::new([0_u8; KEY_LEN]);
+        rng.fill(&mut key[..]);
+        StateEncryptionKey(key)
+    }
+
+    /// Derive a key from `passphrase` and `salt` with Argon2id.
+    ///
+    /// The salt must be at least 8 bytes long; it should be random, and
+    /// stored alongside the state.
+    pub fn from_passphrase(
+        passphrase: &[u8],
+        salt: &[u8],
+        params: &PassphraseKdfParams,
+    ) -> Result<Self> {
+        let params = Params::new(
+            params.memory_kib,
+            params.iterations,
+            params.parallelism,
+            Some(KEY_LEN),
+        )
+        .map_err(|e| {
+            Error::new(
+                into_bad_api_usage!("Invalid passphrase KDF parameters")(e),
+                Action::Initializing,
+                Resource::Manager,
+            )
+        })?;
+        let mut key = Zeroizing::new([0_u8; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, salt, &mut key[..])
+            .map_err(|e| {
+                Error::new(
+                    into_bad_api_usage!("Unable to derive key from passphrase")(e),
+                    Action::Initializing,
+                    Resource::Manager,
+                )
+            })?;
+        Ok(StateEncryptionKey(key))
+    }
+
+    /// Return the bytes of this key.
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+impl From<[u8; KEY_LEN]> for StateEncryptionKey {
+    fn from(bytes: [u8; KEY_LEN]) -> Self {
+        StateEncryptionKey(Zeroizing::new(bytes))
+    }
+}
+
+impl std::fmt::Debug for StateEncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StateEncryptionKey(..)")
+    }
+}
+
+/// The Argon2id parameters with which to derive a [`StateEncryptionKey`]
+/// from a passphrase.
+///
+/// The default is 19 MiB of memory, 2 iterations, and no parallelism, as
+/// recommended by OWASP.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PassphraseKdfParams {
+    /// The amount of memory to use, in KiB.
+    memory_kib: u32,
+    /// The number of iterations.
+    iterations: u32,
+    /// The degree of parallelism.
+    parallelism: u32,
+}
+
+impl PassphraseKdfParams {
+    /// Return a new set of parameters, using `memory_kib` KiB of memory,
+    /// `iterations` iterations, and `parallelism` lanes.
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Self {
+        PassphraseKdfParams {
+            memory_kib,
+            iterations,
+            parallelism,
+        }
+    }
+}
+
+impl Default for PassphraseKdfParams {
+    fn default() -> Self {
+        PassphraseKdfParams::new(19 * 1024, 2, 1)
+    }
+}
+
+/// What [`EncryptedStateMgr::with_passphrase`] stores, so that it can derive
+/// the same key again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PassphraseRecord {
+    /// The base64 encoding of the salt.
+    salt: String,
+    /// The parameters that we used.
+    params: PassphraseKdfParams,
+}
+
+/// The form in which an encrypted value is passed to the underlying manager.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    /// The base64 encoding of the salt, the ciphertext, and the MAC.
+    #[serde(rename = "encrypted_v1")]
+    data: String,
+}
+
+/// A [`StateMgr`] that encrypts every value before storing it in another
+/// `StateMgr`.
+///
+/// Locking is handled entirely by the underlying manager.
+///
+/// See the [module documentation](self) for details.
+///
+/// # Limitations
+///
+/// Keys are not encrypted, and neither are the lengths of values.
+///
+/// Values that were stored without encryption can't be loaded through this
+/// manager: trying to do so gives an error.
+///
+/// The key `state_encryption` is reserved for
+/// [`with_passphrase`](EncryptedStateMgr::with_passphrase).
+#[derive(Clone, Debug)]
+pub struct EncryptedStateMgr<M> {
+    /// The manager that holds the encrypted values.
+    inner: M,
+    /// The key that we use to encrypt them.
+    key: Arc<StateEncryptionKey>,
+    /// A passphrase record that we generated, and have yet to store.
+    ///
+    /// We can only store it once we hold the lock, so we write it along with
+    /// the first value that we store.
+    pending_record: Arc<Mutex<Option<PassphraseRecord>>>,
+}
+
+impl<M: StateMgr> EncryptedStateMgr<M> {
+    /// Wrap `inner`, so that every value is encrypted with `key`.
+    pub fn new(inner: M, key: StateEncryptionKey) -> Self {
+        EncryptedStateMgr {
+            inner,
+            key: Arc::new(key),
+            pending_record: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Wrap `inner`, so that every value is encrypted with a key derived from
+    /// `passphrase`.
+    ///
+    /// If `inner` already has a salt, we use it, along with the parameters
+    /// that were stored with it.  Otherwise, we generate a new salt, and
+    /// store it (with `params`) once we first store a value.
+    ///
+    /// A wrong passphrase isn't detected here: loading values with it gives
+    /// errors of kind [`PersistentStateCorrupted`](tor_error::ErrorKind::PersistentStateCorrupted).
+    pub fn with_passphrase(
+        inner: M,
+        passphrase: &[u8],
+        params: PassphraseKdfParams,
+    ) -> Result<Self> {
+        let (record, pending) = match inner.load::<PassphraseRecord>(PASSPHRASE_RECORD_KEY)? {
+            Some(record) => (record, false),
+            None => {
+                let salt: [u8; SALT_LEN] = rand::rng().random();
+                let record = PassphraseRecord {
+                    salt: Base64::encode_string(&salt),
+                    params,
+                };
+                (record, true)
+            }
+        };
+        let salt = Base64::decode_vec(&record.salt).map_err(|_| {
+            Error::new(
+                ErrorSource::Undecryptable,
+                Action::Initializing,
+                Resource::Key {
+                    key: PASSPHRASE_RECORD_KEY.to_string(),
+                },
+            )
+        })?;
+        let key = StateEncryptionKey::from_passphrase(passphrase, &salt, &record.params)?;
+
+        let mgr = Self::new(inner, key);
+        if pending {
+            *mgr.pending_record.lock().expect("Lock poisoned") = Some(record);
+        }
+        Ok(mgr)
+    }
+
+    /// Return a reference to the underlying manager.
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Store our passphrase record, if we have one that isn't stored yet.
+    fn store_pending_record(&self) -> Result<()> {
+        let mut pending = self.pending_record.lock().expect("Lock poisoned");
+        if let Some(record) = pending.as_ref() {
+            self.inner.store(PASSPHRASE_RECORD_KEY, record)?;
+            *pending = None;
+        }
+        Ok(())
+    }
+
+    /// Return an error if `key` is the key that we reserve for our passphrase
+    /// record.
+    fn check_not_reserved(key: &str) -> Result<()> {
+        if key == PASSPHRASE_RECORD_KEY {
+            return Err(Error::new(
+                bad_api_usage!("{:?} is reserved by EncryptedStateMgr", key),
+                Action::Storing,
+                Resource::Key {
+                    key: key.to_string(),
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return the cipher and MAC with which to en/decrypt the value for
+    /// `storage_key`, given its `salt`.
+    ///
+    /// The MAC has already absorbed its key and the salt: the ciphertext
+    /// should be added, and then it should be finalized.
+    fn init(&self, storage_key: &str, salt: &[u8; SALT_LEN]) -> (Cipher, Hash) {
+        let mut kdf = Kdf::default();
+        kdf.update(self.key.as_bytes());
+        kdf.update(salt);
+        kdf.update(KDF_PERSONALIZATION);
+        kdf.update(&(storage_key.len() as u64).to_be_bytes());
+        kdf.update(storage_key.as_bytes());
+        let mut key_stream = kdf.finalize_xof();
+
+        let mut key = Zeroizing::new([0_u8; CIPHER_KEY_LEN]);
+        let mut iv = Zeroizing::new([0_u8; IV_LEN]);
+        let mut mac_key = Zeroizing::new([0_u8; MAC_KEY_LEN]);
+        key_stream.read(&mut key[..]);
+        key_stream.read(&mut iv[..]);
+        key_stream.read(&mut mac_key[..]);
+
+        let cipher = Cipher::new(key.as_ref().into(), iv.as_ref().into());
+
+        let mut mac = Hash::default();
+        mac.update(&(MAC_KEY_LEN as u64).to_be_bytes());
+        mac.update(&mac_key[..]);
+        mac.update(&(SALT_LEN as u64).to_be_bytes());
+        mac.update(&salt[..]);
+
+        (cipher, mac)
+    }
+
+    /// Encrypt `plaintext`, to be stored with `storage_key`.
+    fn seal(&self, storage_key: &str, plaintext: &[u8]) -> Sealed {
+        let salt: [u8; SALT_LEN] = rand::rng().random();
+        let (mut cipher, mut mac) = self.init(storage_key, &salt);
+
+        let mut output = Vec::with_capacity(SALT_LEN + plaintext.len() + MAC_LEN);
+        output.extend_from_slice(&salt[..]);
+        output.extend_from_slice(plaintext);
+        cipher.apply_keystream(&mut output[SALT_LEN..]);
+        mac.update(&output[SALT_LEN..]);
+        output.extend_from_slice(&mac.finalize_fixed());
+
+        Sealed {
+            data: Base64::encode_string(&output),
+        }
+    }
+
+    /// Decrypt and authenticate `sealed`, which was stored with `storage_key`.
+    fn open(
+        &self,
+        storage_key: &str,
+        sealed: &Sealed,
+    ) -> std::result::Result<Zeroizing<Vec<u8>>, ErrorSource> {
+        let data = Base64::decode_vec(&sealed.data).map_err(|_| ErrorSource::Undecryptable)?;
+        if data.len() < SALT_LEN + MAC_LEN {
+            return Err(ErrorSource::Undecryptable);
+        }
+        let (salt, rest) = data.split_at(SALT_LEN);
+        let (ciphertext, expected_mac) = rest.split_at(rest.len() - MAC_LEN);
+        let salt: &[u8; SALT_LEN] = salt.try_into().expect("Wrong salt length?");
+        let expected_mac: [u8; MAC_LEN] = expected_mac.try_into().expect("Wrong MAC length?");
+
+        let (mut cipher, mut mac) = self.init(storage_key, salt);
+        mac.update(ciphertext);
+        let mut received_mac = CtByteArray::from([0_u8; MAC_LEN]);
+        mac.finalize_into(received_mac.as_mut().into());
+        if received_mac != CtByteArray::from(expected_mac) {
+            return Err(ErrorSource::Undecryptable);
+        }
+
+        let mut plaintext = Zeroizing::new(ciphertext.to_vec());
+        cipher.apply_keystream(&mut plaintext[..]);
+        Ok(plaintext)
+    }
+}
+
+impl<M: StateMgr> StateMgr for EncryptedStateMgr<M> {
+    fn load<D>(&self, key: &str) -> Result<Option<D>>
+    where
+        D: DeserializeOwned,
+    {
+        let Some(sealed) = self.inner.load::<Sealed>(key)? else {
+            return Ok(None);
+        };
+        let plaintext = self
+            .open(key, &sealed)
+            .map_err(|e| Error::new(e, Action::Loading, Resource::Manager))?;
+        serde_json::from_slice(&plaintext)
+            .map(Some)
+            .map_err(|e| Error::new(e, Action::Loading, Resource::Manager))
+    }
+
+    fn store<S>(&self, key: &str, val: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        Self::check_not_reserved(key)?;
+        let plaintext = Zeroizing::new(
+            serde_json::to_vec(val)
+                .map_err(|e| Error::new(e, Action::Storing, Resource::Manager))?,
+        );
+        self.store_pending_record()?;
+        self.inner.store(key, &self.seal(key, &plaintext))
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        let mut sealed = Transaction::new();
+        for (key, val) in txn.into_values() {
+            Self::check_not_reserved(&key)?;
+            let plaintext = Zeroizing::new(
+                serde_json::to_vec(&val)
+                    .map_err(|e| Error::new(e, Action::Storing, Resource::Manager))?,
+            );
+            sealed.store(&key, &self.seal(&key, &plaintext))?;
+        }
+        self.store_pending_record()?;
+        self.inner.commit(sealed)
+    }
+
+    fn can_store(&self) -> bool {
+        self.inner.can_store()
+    }
+
+    fn try_lock(&self) -> Result<LockStatus> {
+        self.inner.try_lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.inner.unlock()
+    }
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::FsStateMgr;
+    use std::collections::HashMap;
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let fs = FsStateMgr::from_path(dir.path()).unwrap();
+        let key = StateEncryptionKey::generate(&mut rand::rng());
+        let store = EncryptedStateMgr::new(fs.clone(), key.clone());
+
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert!(fs.can_store());
+
+        let guards: HashMap<_, _> = [("guard".to_string(), "hunter2".to_string())]
+            .into_iter()
+            .collect();
+        store.store("guards", &guards).unwrap();
+        let loaded: Option<HashMap<String, String>> = store.load("guards").unwrap();
+        assert_eq!(loaded, Some(guards));
+        assert_eq!(store.load::<String>("nothing").unwrap(), None);
+
+        // The file on disk doesn't reveal anything.
+        let raw = std::fs::read_to_string(dir.path().join("state/guards.json")).unwrap();
+        assert!(!raw.contains("hunter2"));
+        assert!(!raw.contains("guard\""));
+
+        // A second manager with the same key can read it.
+        let store2 = EncryptedStateMgr::new(fs, key);
+        assert!(store2
+            .load::<HashMap<String, String>>("guards")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn tampering() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let fs = FsStateMgr::from_path(dir.path()).unwrap();
+        let store = EncryptedStateMgr::new(fs.clone(), [7_u8; 32].into());
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        store.store("a", &"apple").unwrap();
+        store.store("b", &"banana").unwrap();
+
+        // The wrong key can't read anything.
+        let wrong = EncryptedStateMgr::new(fs.clone(), [8_u8; 32].into());
+        let err = wrong.load::<String>("a").unwrap_err();
+        assert!(matches!(err.source(), ErrorSource::Undecryptable));
+
+        // A value moved to a different key can't be read.
+        let a: Sealed = fs.load("a").unwrap().unwrap();
+        fs.store("b", &a).unwrap();
+        let err = store.load::<String>("b").unwrap_err();
+        assert!(matches!(err.source(), ErrorSource::Undecryptable));
+
+        // Nor can an unencrypted value.
+        fs.store("a", &"apple").unwrap();
+        assert!(store.load::<String>("a").is_err());
+    }
+
+    #[test]
+    fn passphrase() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let fs = FsStateMgr::from_path(dir.path()).unwrap();
+        // Cheap parameters, so that the test is fast.
+        let params = PassphraseKdfParams::new(64, 1, 1);
+        let store = EncryptedStateMgr::with_passphrase(fs.clone(), b"hunter2", params).unwrap();
+
+        // Nothing is stored until we hold the lock and store something.
+        assert!(fs
+            .load::<PassphraseRecord>("state_encryption")
+            .unwrap()
+            .is_none());
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        store.store("guards", &"abc").unwrap();
+        let record: PassphraseRecord = fs.load("state_encryption").unwrap().unwrap();
+        assert_eq!(record.params, params);
+        assert_eq!(Base64::decode_vec(&record.salt).unwrap().len(), SALT_LEN);
+
+        // The reserved key can't be overwritten.
+        assert!(store.store("state_encryption", &"x").is_err());
+
+        // The same passphrase finds the salt and parameters, and gets the
+        // same key, even if it asks for different parameters.
+        let store2 = EncryptedStateMgr::with_passphrase(
+            fs.clone(),
+            b"hunter2",
+            PassphraseKdfParams::default(),
+        )
+        .unwrap();
+        assert_eq!(store2.load::<String>("guards").unwrap().unwrap(), "abc");
+        assert_eq!(store2.key.as_bytes(), store.key.as_bytes());
+
+        // A different passphrase can't read anything.
+        let wrong = EncryptedStateMgr::with_passphrase(fs.clone(), b"hunter3", params).unwrap();
+        let err = wrong.load::<String>("guards").unwrap_err();
+        assert!(matches!(err.source(), ErrorSource::Undecryptable));
+
+        // Another directory gets another salt.
+        let dir2 = tempfile::TempDir::new().unwrap();
+        let fs2 = FsStateMgr::from_path(dir2.path()).unwrap();
+        let other = EncryptedStateMgr::with_passphrase(fs2, b"hunter2", params).unwrap();
+        assert_ne!(other.key.as_bytes(), store.key.as_bytes());
+    }
+}
@@ -30,11 +30,16 @@ pub(crate) enum Resource {
         /// The path within the checked directory to the file.
         file: std::path::PathBuf,
     },
-    /// Testing-only: a scratch-item in a memory-backed store.
-    #[cfg(feature = "testing")]
+    /// The item with a given key, in whatever store we are using.
+    #[display("{:?} in persistent storage", key)]
+    Key {
+        /// The key for the item
+        key: String,
+    },
+    /// An item in a memory-backed store.
     #[display("{} in memory-backed store", key)]
-    Temporary {
-        /// The key for the scratch item
+    Memory {
+        /// The key for the item
         key: String,
     },
     /// An instance state directory
@@ -75,6 +80,12 @@ pub(crate) enum Action {
     /// We were trying to validate the storage and initialize the manager.
     #[display("constructing storage manager")]
     Initializing,
+    /// We were trying to watch the store for changes.
+    #[display("watching for changes")]
+    Watching,
+    /// We were trying to list the keys in the store.
+    #[display("listing stored keys")]
+    Listing,
     /// We were trying to enumerate state objects
     #[display("enumerating instances")]
     Enumerating,
@@ -115,6 +126,76 @@ pub enum ErrorSource {
     #[error("JSON error")]
     Serde(#[from] Arc<serde_json::Error>),
 
+    /// A stored record is in a format that we don't know how to read.
+    ///
+    /// This usually means that it was written by a newer version of Arti,
+    /// or by one built with more features.
+    #[error("Stored data is in an unknown format {header:?}")]
+    UnknownFormat {
+        /// The name of the format, from the record's header.
+        header: String,
+    },
+
+    /// Problem when serializing or deserializing CBOR data.
+    #[cfg(feature = "cbor")]
+    #[error("CBOR error")]
+    Cbor(#[source] Arc<dyn std::error::Error + Send + Sync>),
+
+    /// A stored value has a newer schema version than we know how to read.
+    ///
+    /// This usually means that the state was written by a newer version of
+    /// Arti.
+    #[error("Stored data has schema version {found}, but we only support up to {supported}")]
+    SchemaTooNew {
+        /// The schema version of the stored value.
+        found: u32,
+        /// The newest schema version that we support.
+        supported: u32,
+    },
+
+    /// We couldn't upgrade a stored value from an older schema version.
+    #[error("Unable to upgrade stored data from schema version {from}")]
+    Migration {
+        /// The schema version that we were trying to upgrade from.
+        from: u32,
+        /// The error from the migration function.
+        #[source]
+        error: crate::versioned::MigrationError,
+    },
+
+    /// A stored file did not match its checksum.
+    #[error("Stored data does not match its checksum")]
+    ChecksumMismatch,
+
+    /// An encrypted value could not be decrypted, or failed authentication.
+    ///
+    /// This can happen if the value was encrypted with a different key,
+    /// or if it has been corrupted or tampered with.
+    #[error("Unable to decrypt stored data")]
+    Undecryptable,
+
+    /// An error from a [`StateBackend`](crate::StateBackend).
+    #[error("Error from storage backend")]
+    Backend(#[source] crate::BackendError),
+
+    /// An error from the filesystem watcher.
+    #[cfg(all(feature = "watch", not(target_arch = "wasm32")))]
+    #[error("Problem watching for changes")]
+    Watch(#[source] Arc<notify::Error>),
+
+    /// An error from the SQLite database.
+    #[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+    #[error("SQLite error")]
+    Sqlite(#[source] Arc<rusqlite::Error>),
+
+    /// A directory contains entries whose names differ only in case or Unicode normalization.
+    ///
+    /// These would be the same file on some filesystems,
+    /// so we can't tell which one is meant.
+    /// See [`slug::collision_key`](crate::slug::collision_key).
+    #[error("Ambiguous names in directory: {0:?}")]
+    NameCollision(Vec<std::ffi::OsString>),
+
     /// Another task or process holds this persistent state lock, but we need exclusive access
     #[error("State already lockedr")]
     AlreadyLocked,
@@ -190,6 +271,21 @@ impl tor_error::HasKind for Error {
             E::Bug(e)          => e.kind(),
             E::Serde(..) if self.action == Action::Storing  => K::Internal,
             E::Serde(..) => K::PersistentStateCorrupted,
+            #[cfg(feature = "cbor")]
+            E::Cbor(..) if self.action == Action::Storing  => K::Internal,
+            #[cfg(feature = "cbor")]
+            E::Cbor(..)        => K::PersistentStateCorrupted,
+            E::UnknownFormat{..} => K::PersistentStateCorrupted,
+            E::Undecryptable   => K::PersistentStateCorrupted,
+            E::ChecksumMismatch => K::PersistentStateCorrupted,
+            E::SchemaTooNew{..} => K::PersistentStateCorrupted,
+            E::Migration{..}   => K::PersistentStateCorrupted,
+            E::NameCollision(..) => K::PersistentStateCorrupted,
+            E::Backend(..)     => K::PersistentStateAccessFailed,
+            #[cfg(all(feature = "watch", not(target_arch = "wasm32")))]
+            E::Watch(..)       => K::PersistentStateAccessFailed,
+            #[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+            E::Sqlite(..)      => K::PersistentStateAccessFailed,
         }
     }
 }
@@ -206,6 +302,13 @@ impl From<serde_json::Error> for ErrorSource {
     }
 }
 
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+impl From<rusqlite::Error> for ErrorSource {
+    fn from(e: rusqlite::Error) -> ErrorSource {
+        ErrorSource::Sqlite(Arc::new(e))
+    }
+}
+
 impl From<fs_mistrust::Error> for ErrorSource {
     fn from(e: fs_mistrust::Error) -> ErrorSource {
         match e {
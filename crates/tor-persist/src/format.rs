@@ -0,0 +1,146 @@
+//! Serialization formats for stored records.
+//!
+//! By default, records are stored as JSON, with no header, just as they
+//! always have been.  A record in any other format starts with a header
+//! naming its format:
+//!
+//! ```text
+//! \0arti-record:FORMAT\n
+//! ```
+//!
+//! Since no JSON document can start with a NUL byte, every record says how
+//! to read it.  So a store can hold records in several formats at once, and
+//! a record can be moved to a new format simply by storing it again.
+
+use crate::err::ErrorSource;
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "cbor")]
+use std::sync::Arc;
+
+/// The start of the header of a record that is not in JSON.
+const HEADER_PREFIX: &[u8] = b"\0arti-record:";
+
+/// The name of [`RecordFormat::Cbor`], in headers.
+#[cfg(feature = "cbor")]
+const CBOR: &[u8] = b"cbor";
+
+/// A format in which to serialize a stored record.
+///
+/// Pass this to [`StateMgr::store_with_format`](crate::StateMgr::store_with_format).
+/// When loading, the format is detected automatically.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum RecordFormat {
+    /// Pretty-printed JSON, with no header.
+    ///
+    /// This is the default, and is what older versions of Arti understand.
+    #[default]
+    Json,
+    /// [CBOR](https://www.rfc-editor.org/rfc/rfc8949.html), a compact binary
+    /// format.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// Serialize `val` in `format`, with a header if it needs one.
+pub(crate) fn encode<S: Serialize + ?Sized>(
+    val: &S,
+    format: RecordFormat,
+) -> Result<Vec<u8>, ErrorSource> {
+    let mut out = vec![];
+    match format {
+        RecordFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, val)?;
+        }
+        #[cfg(feature = "cbor")]
+        RecordFormat::Cbor => {
+            out.extend_from_slice(HEADER_PREFIX);
+            out.extend_from_slice(CBOR);
+            out.push(b'\n');
+            ciborium::into_writer(val, &mut out).map_err(|e| ErrorSource::Cbor(Arc::new(e)))?;
+        }
+    }
+    Ok(out)
+}
+
+/// Deserialize a record in whatever format its header says.
+pub(crate) fn decode<D: DeserializeOwned>(record: &[u8]) -> Result<D, ErrorSource> {
+    let Some(rest) = record.strip_prefix(HEADER_PREFIX) else {
+        return Ok(serde_json::from_slice(record)?);
+    };
+    let bad_header = || ErrorSource::UnknownFormat {
+        header: String::from_utf8_lossy(&rest[..rest.len().min(32)]).into_owned(),
+    };
+    let newline = rest
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(bad_header)?;
+    #[allow(unused_variables)] // (`body` is unused if we support no formats with headers)
+    let (name, body) = (&rest[..newline], &rest[newline + 1..]);
+    match name {
+        #[cfg(feature = "cbor")]
+        CBOR => ciborium::from_reader(body).map_err(|e| ErrorSource::Cbor(Arc::new(e))),
+        _ => Err(ErrorSource::UnknownFormat {
+            header: String::from_utf8_lossy(name).into_owned(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn sample() -> BTreeMap<String, Vec<u32>> {
+        [("a".into(), vec![1, 2, 3]), ("b".into(), vec![])].into()
+    }
+
+    #[test]
+    fn json() {
+        let record = encode(&sample(), RecordFormat::Json).unwrap();
+        assert_eq!(record[0], b'{');
+        assert_eq!(decode::<BTreeMap<_, _>>(&record).unwrap(), sample());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        let record = encode(&sample(), RecordFormat::Cbor).unwrap();
+        assert!(record.starts_with(b"\0arti-record:cbor\n"));
+        assert!(record.len() < encode(&sample(), RecordFormat::Json).unwrap().len());
+        assert_eq!(decode::<BTreeMap<_, _>>(&record).unwrap(), sample());
+
+        let mut truncated = record.clone();
+        truncated.pop();
+        assert!(matches!(
+            decode::<BTreeMap<String, Vec<u32>>>(&truncated),
+            Err(ErrorSource::Cbor(_))
+        ));
+    }
+
+    #[test]
+    fn unknown() {
+        for (record, header) in [
+            (&b"\0arti-record:zstd-json\n..."[..], "zstd-json"),
+            (&b"\0arti-record:no newline"[..], "no newline"),
+        ] {
+            match decode::<u32>(record) {
+                Err(ErrorSource::UnknownFormat { header: h }) => assert_eq!(h, header),
+                other => panic!("{other:?}"),
+            }
+        }
+    }
+}
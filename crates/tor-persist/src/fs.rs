@@ -2,11 +2,28 @@
 
 #![forbid(unsafe_code)] // if you remove this, enable (or write) miri tests (git grep miri)
 
+#[cfg(feature = "backups")]
+mod backup;
 mod clean;
+mod durability;
+#[cfg(feature = "gc")]
+mod gc;
+mod namespace;
+mod read_only;
+mod transaction;
+#[cfg(feature = "watch")]
+mod watch;
+
+pub use durability::Durability;
+#[cfg(feature = "gc")]
+pub use gc::GcPolicy;
+pub use read_only::StateSnapshot;
+#[cfg(feature = "watch")]
+pub use watch::{StateChange, StateWatcher};
 
 use crate::err::{Action, ErrorSource, Resource};
 use crate::load_store;
-use crate::{Error, LockStatus, Result, StateMgr};
+use crate::{Error, LockStatus, RecordFormat, Result, StateMgr, Transaction};
 use fs_mistrust::anon_home::PathExt as _;
 use fs_mistrust::CheckedDir;
 use futures::FutureExt;
@@ -29,9 +46,22 @@ use tracing::info;
 /// By default, every `FsStateMgr` starts out unlocked, and only able
 /// to read.  Use [`FsStateMgr::try_lock()`] to lock it.
 ///
+/// Processes that only ever want to read, such as tools that inspect a
+/// running Arti's state, should use [`FsStateMgr::open_read_only`] instead.
+/// That never creates or writes any files, and
+/// [`snapshot`](FsStateMgr::snapshot) gives them a consistent view of every
+/// record.
+///
+/// # Formats
+///
+/// Records stored with [`store_with_format`](StateMgr::store_with_format)
+/// in a format other than JSON are still kept in `KEY.json`, but start
+/// with a header naming their format.  Transactions are always stored as
+/// JSON.
+///
 /// # Limitations
 ///
-/// 1. This manager only accepts objects that can be serialized as
+/// 1. By default, this manager only accepts objects that can be serialized as
 ///    JSON documents.  Some types (like maps with non-string keys) can't
 ///    be serialized as JSON.
 ///
@@ -48,6 +78,12 @@ use tracing::info;
 pub struct FsStateMgr {
     /// Inner reference-counted object.
     inner: Arc<FsStateMgrInner>,
+    /// The number of backups to keep of each file, if we are using
+    /// checksums and backups.
+    #[cfg(feature = "backups")]
+    backups: Option<usize>,
+    /// How carefully to write stored values to disk.
+    durability: Durability,
 }
 
 /// Inner reference-counted object, used by `FsStateMgr`.
@@ -56,7 +92,10 @@ struct FsStateMgrInner {
     /// Directory in which we store state files.
     statepath: CheckedDir,
     /// Lockfile to achieve exclusive access to state files.
-    lockfile: Mutex<fslock::LockFile>,
+    ///
+    /// `None` if we were opened with [`FsStateMgr::open_read_only`], and
+    /// can never take the lock.
+    lockfile: Option<Mutex<fslock::LockFile>>,
     /// A oneshot sender that is used to alert other tasks when this lock is
     /// finally dropped.
     ///
@@ -66,6 +105,19 @@ struct FsStateMgrInner {
     lock_dropped_tx: oneshot::Sender<void::Void>,
     /// Cloneable handle which resolves when this lock is dropped.
     lock_dropped_rx: futures::future::Shared<oneshot::Receiver<void::Void>>,
+    /// The contents that we last wrote to each file, so that our watchers
+    /// can ignore our own changes.
+    #[cfg(feature = "watch")]
+    own_writes: watch::OwnWrites,
+    /// Functions that can stop us from collecting records.
+    #[cfg(feature = "gc")]
+    gc_vetoes: gc::GcVetoes,
+    /// Values that we have stored, but not yet written, because of
+    /// [`Durability::Batched`].
+    pending: Mutex<durability::PendingWrites>,
+    /// The rules for checking our files' permissions, for use by our
+    /// namespaces.
+    mistrust: fs_mistrust::Mistrust,
 }
 
 impl FsStateMgr {
@@ -101,7 +153,7 @@ impl FsStateMgr {
             )
         })?;
 
-        let lockfile = Mutex::new(fslock::LockFile::open(&lockpath).map_err(|e| {
+        let lockfile = fslock::LockFile::open(&lockpath).map_err(|e| {
             Error::new(
                 e,
                 Action::Initializing,
@@ -110,18 +162,37 @@ impl FsStateMgr {
                     file: "state.lock".into(),
                 },
             )
-        })?);
+        })?;
 
+        Ok(Self::from_checked_dir(statepath, Some(lockfile), mistrust))
+    }
+
+    /// Construct a new `FsStateMgr` for the state files in `statepath`,
+    /// using `lockfile` (if any) to take the lock.
+    fn from_checked_dir(
+        statepath: CheckedDir,
+        lockfile: Option<fslock::LockFile>,
+        mistrust: &fs_mistrust::Mistrust,
+    ) -> Self {
         let (lock_dropped_tx, lock_dropped_rx) = oneshot::channel();
         let lock_dropped_rx = lock_dropped_rx.shared();
-        Ok(FsStateMgr {
+        FsStateMgr {
             inner: Arc::new(FsStateMgrInner {
                 statepath,
-                lockfile,
+                lockfile: lockfile.map(Mutex::new),
                 lock_dropped_tx,
                 lock_dropped_rx,
+                #[cfg(feature = "watch")]
+                own_writes: Default::default(),
+                #[cfg(feature = "gc")]
+                gc_vetoes: Default::default(),
+                pending: Default::default(),
+                mistrust: mistrust.clone(),
             }),
-        })
+            #[cfg(feature = "backups")]
+            backups: None,
+            durability: Durability::default(),
+        }
     }
     /// Like from_path_and_mistrust, but do not verify permissions.
     ///
@@ -194,6 +265,34 @@ impl FsStateMgr {
         }
     }
 
+    /// Remove the record with `key`, along with its checksum, if it has
+    /// one.
+    ///
+    /// Does nothing if there is no such record.  Requires that we hold the
+    /// lock.
+    pub fn remove(&self, key: &str) -> Result<()> {
+        if !self.can_store() {
+            return Err(Error::new(
+                ErrorSource::NoLock,
+                Action::Deleting,
+                Resource::Manager,
+            ));
+        }
+
+        self.discard_pending(key);
+        let dir = &self.inner.statepath;
+        let rel_fname = self.rel_filename(key);
+        let mut sum_fname = rel_fname.clone().into_os_string();
+        sum_fname.push(".sum");
+        for fname in [rel_fname, sum_fname.into()] {
+            match dir.remove_file(&fname) {
+                Ok(()) | Err(fs_mistrust::Error::NotFound(_)) => {}
+                Err(e) => return Err(Error::new(e, Action::Deleting, self.err_resource(key))),
+            }
+        }
+        Ok(())
+    }
+
     /// Return a handle which resolves when the file is unlocked
     pub fn wait_for_unlock(&self) -> impl futures::Future<Output = ()> + Send + Sync + 'static {
         self.inner.lock_dropped_rx.clone().map(|_| ())
@@ -202,19 +301,17 @@ impl FsStateMgr {
 
 impl StateMgr for FsStateMgr {
     fn can_store(&self) -> bool {
-        let lockfile = self
-            .inner
-            .lockfile
-            .lock()
-            .expect("Poisoned lock on state lockfile");
+        let Some(lockfile) = &self.inner.lockfile else {
+            return false;
+        };
+        let lockfile = lockfile.lock().expect("Poisoned lock on state lockfile");
         lockfile.owns_lock()
     }
     fn try_lock(&self) -> Result<LockStatus> {
-        let mut lockfile = self
-            .inner
-            .lockfile
-            .lock()
-            .expect("Poisoned lock on state lockfile");
+        let Some(lockfile) = &self.inner.lockfile else {
+            return Ok(LockStatus::NoLock);
+        };
+        let mut lockfile = lockfile.lock().expect("Poisoned lock on state lockfile");
         if lockfile.owns_lock() {
             Ok(LockStatus::AlreadyHeld)
         } else if lockfile
@@ -222,17 +319,18 @@ impl StateMgr for FsStateMgr {
             .map_err(|e| Error::new(e, Action::Locking, self.err_resource_lock()))?
         {
             self.clean(SystemTime::now());
+            self.recover_transaction()?;
             Ok(LockStatus::NewlyAcquired)
         } else {
             Ok(LockStatus::NoLock)
         }
     }
     fn unlock(&self) -> Result<()> {
-        let mut lockfile = self
-            .inner
-            .lockfile
-            .lock()
-            .expect("Poisoned lock on state lockfile");
+        let Some(lockfile) = &self.inner.lockfile else {
+            return Ok(());
+        };
+        self.flush()?;
+        let mut lockfile = lockfile.lock().expect("Poisoned lock on state lockfile");
         if lockfile.owns_lock() {
             lockfile
                 .unlock()
@@ -244,10 +342,27 @@ impl StateMgr for FsStateMgr {
     where
         D: DeserializeOwned,
     {
+        if let Some(v) = self.load_pending(key)? {
+            return Ok(Some(v));
+        }
+        if let Some(v) = self.load_committed(key)? {
+            return Ok(Some(v));
+        }
+        #[cfg(feature = "backups")]
+        if let Some(n) = self.backups {
+            return self.load_with_backups(key, n);
+        }
         self.with_load_store_target(key, Action::Loading, |t| t.load())
     }
 
     fn store<S>(&self, key: &str, val: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        self.store_with_format(key, val, RecordFormat::Json)
+    }
+
+    fn store_with_format<S>(&self, key: &str, val: &S, format: RecordFormat) -> Result<()>
     where
         S: Serialize,
     {
@@ -259,7 +374,23 @@ impl StateMgr for FsStateMgr {
             ));
         }
 
-        self.with_load_store_target(key, Action::Storing, |t| t.store(val))
+        let output = crate::format::encode(val, format)
+            .map_err(|e| Error::new(e, Action::Storing, self.err_resource(key)))?;
+        #[cfg(feature = "watch")]
+        self.note_own_write(&self.rel_filename(key), &output);
+        self.write_durably(key, output)
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        if !self.can_store() {
+            return Err(Error::new(
+                ErrorSource::NoLock,
+                Action::Storing,
+                Resource::Manager,
+            ));
+        }
+
+        self.commit_transaction(txn)
     }
 }
 
@@ -323,6 +454,56 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn remove() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStateMgr::from_path(dir.path())?;
+        assert!(store.remove("a").is_err());
+
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+        store.store("a", &1_u32)?;
+        store.store("b", &2_u32)?;
+        store.remove("a")?;
+        assert_eq!(store.load::<u32>("a")?, None);
+        assert_eq!(store.load::<u32>("b")?, Some(2));
+        // Removing a record that doesn't exist is fine.
+        store.remove("a")?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn mixed_formats() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let store = FsStateMgr::from_path(dir.path())?;
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+
+        store.store("a", &[1_u32, 2, 3])?;
+        store.store_with_format("b", &[4_u32, 5, 6], RecordFormat::Cbor)?;
+        assert!(std::fs::read(statedir.join("b.json"))
+            .unwrap()
+            .starts_with(b"\0arti-record:cbor\n"));
+        assert_eq!(store.load::<Vec<u32>>("a")?, Some(vec![1, 2, 3]));
+        assert_eq!(store.load::<Vec<u32>>("b")?, Some(vec![4, 5, 6]));
+
+        // Migrate a record by storing it again.
+        store.store_with_format("a", &[1_u32, 2, 3], RecordFormat::Cbor)?;
+        assert_eq!(store.load::<Vec<u32>>("a")?, Some(vec![1, 2, 3]));
+        store.store("b", &[4_u32, 5, 6])?;
+        assert_eq!(
+            std::fs::read_to_string(statedir.join("b.json")).unwrap(),
+            "[\n  4,\n  5,\n  6\n]"
+        );
+
+        std::fs::write(statedir.join("c.json"), b"\0arti-record:lzma\n...").unwrap();
+        let err = store.load::<Vec<u32>>("c").unwrap_err();
+        assert!(matches!(err.source(), ErrorSource::UnknownFormat { .. }));
+
+        Ok(())
+    }
+
     #[test]
     fn clean_successful() -> Result<()> {
         let dir = tempfile::TempDir::new().unwrap();
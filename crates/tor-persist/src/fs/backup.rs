@@ -0,0 +1,281 @@
+//! Checksums and rotated backups for the files in an [`FsStateMgr`]'s
+//! directory.
+//!
+//! When backups are enabled with [`FsStateMgr::with_backups`], every value
+//! that we store gets a checksum, in `KEY.json.sum` beside `KEY.json`.
+//! Before we replace a file, we copy its old contents (if they were intact)
+//! to `backups/KEY.json.1`, moving any older copies to `KEY.json.2` and so
+//! on, up to the configured number.
+//!
+//! When we load a value whose file fails its checksum or can't be parsed,
+//! we log a warning and use the newest intact backup instead.  We write the
+//! checksum before the file, so that a crash between the two writes makes
+//! the file look corrupt, and we fall back to the copy we made of it.
+//!
+//! Files with no checksum (such as ones written by older versions of Arti)
+//! are accepted as long as they parse.
+
+use super::FsStateMgr;
+use crate::err::{Action, ErrorSource};
+use crate::{Error, Result};
+use digest::Digest as _;
+use fs_mistrust::CheckedDir;
+use serde::de::DeserializeOwned;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tor_error::warn_report;
+use tor_llcrypto::d::Sha256;
+use tracing::warn;
+
+/// The subdirectory of the state directory in which we keep backups.
+const BACKUP_DIR: &str = "backups";
+
+/// Return the checksum that we store for a file containing `contents`.
+fn checksum(contents: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(contents))
+}
+
+/// Return the name of the file holding the checksum for `rel_fname`.
+fn sum_fname(rel_fname: &Path) -> PathBuf {
+    let mut fname = OsString::from(rel_fname);
+    fname.push(".sum");
+    fname.into()
+}
+
+/// Return the name of the `n`th most recent backup of `rel_fname`.
+fn backup_fname(rel_fname: &Path, n: usize) -> PathBuf {
+    let mut fname = OsString::from(rel_fname);
+    fname.push(format!(".{n}"));
+    Path::new(BACKUP_DIR).join(fname)
+}
+
+/// Read the file `rel_fname` from `dir`, and check it against its checksum.
+///
+/// Returns `Ok(None)` if the file does not exist, and
+/// [`ErrorSource::ChecksumMismatch`] if it does not match its checksum.
+fn read_checked(
+    dir: &CheckedDir,
+    rel_fname: &Path,
+) -> std::result::Result<Option<Vec<u8>>, ErrorSource> {
+    let contents = match dir.read(rel_fname) {
+        Ok(contents) => contents,
+        Err(fs_mistrust::Error::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match dir.read_to_string(sum_fname(rel_fname)) {
+        Ok(sum) if sum.trim() != checksum(&contents) => Err(ErrorSource::ChecksumMismatch),
+        Ok(_) | Err(fs_mistrust::Error::NotFound(_)) => Ok(Some(contents)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read and deserialize the file `rel_fname` from `dir`, checking it
+/// against its checksum.
+fn load_checked<D: DeserializeOwned>(
+    dir: &CheckedDir,
+    rel_fname: &Path,
+) -> std::result::Result<Option<D>, ErrorSource> {
+    read_checked(dir, rel_fname)?
+        .map(|contents| crate::format::decode(&contents))
+        .transpose()
+}
+
+impl FsStateMgr {
+    /// Enable checksums for every value that this manager stores, and keep
+    /// up to `n` older copies of each one to recover from if it is
+    /// corrupted.
+    ///
+    /// With `n == 0`, corruption is detected but can't be recovered from.
+    ///
+    /// Checksums are stored beside each file, as `KEY.json.sum`, and
+    /// backups in the `backups` subdirectory.  If a file fails its checksum
+    /// or can't be parsed, we log a warning and load the newest intact
+    /// backup instead.
+    ///
+    /// This only affects this manager, and clones made from it afterwards.
+    #[must_use]
+    pub fn with_backups(mut self, n: usize) -> Self {
+        self.backups = Some(n);
+        self
+    }
+
+    /// Load the value with `key`, falling back to the newest intact backup
+    /// if it is corrupt.
+    pub(super) fn load_with_backups<D: DeserializeOwned>(
+        &self,
+        key: &str,
+        n: usize,
+    ) -> Result<Option<D>> {
+        let dir = &self.inner.statepath;
+        let rel_fname = self.rel_filename(key);
+        let error = match load_checked(dir, &rel_fname) {
+            Ok(v) => return Ok(v),
+            Err(e) if is_corrupt(&e) => e,
+            Err(e) => return Err(Error::new(e, Action::Loading, self.err_resource(key))),
+        };
+
+        for i in 1..=n {
+            match load_checked(dir, &backup_fname(&rel_fname, i)) {
+                Ok(Some(v)) => {
+                    warn_report!(
+                        error,
+                        "Stored state for {:?} is corrupt; recovered it from backup {}",
+                        key,
+                        i
+                    );
+                    return Ok(Some(v));
+                }
+                // There are no older backups.
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+        warn!(
+            "Stored state for {:?} is corrupt, and has no intact backup",
+            key
+        );
+        Err(Error::new(error, Action::Loading, self.err_resource(key)))
+    }
+
+    /// Prepare to store `output` with `key`: back up the current file, if it
+    /// is intact, and write the checksum for `output`.
+    ///
+    /// The caller must then store `output` itself.
+    pub(super) fn prepare_store_with_backups(
+        &self,
+        key: &str,
+        output: &[u8],
+        n: usize,
+    ) -> Result<()> {
+        let dir = &self.inner.statepath;
+        let rel_fname = self.rel_filename(key);
+        if n > 0 {
+            // If we can't make a backup, we still want to store the value.
+            if let Err(e) = rotate_backups(dir, &rel_fname, n) {
+                warn_report!(e, "Unable to back up stored state for {:?}", key);
+            }
+        }
+        dir.write_and_replace(sum_fname(&rel_fname), checksum(output))
+            .map_err(|e| Error::new(e, Action::Storing, self.err_resource(key)))
+    }
+}
+
+/// Return true if `e`, from loading a file, means that the file is corrupt.
+fn is_corrupt(e: &ErrorSource) -> bool {
+    match e {
+        ErrorSource::ChecksumMismatch | ErrorSource::Serde(_) => true,
+        #[cfg(feature = "cbor")]
+        ErrorSource::Cbor(_) => true,
+        _ => false,
+    }
+}
+
+/// Copy the file `rel_fname` in `dir`, if it is intact, to its first backup,
+/// moving older backups along to make room and keeping at most `n`.
+fn rotate_backups(
+    dir: &CheckedDir,
+    rel_fname: &Path,
+    n: usize,
+) -> std::result::Result<(), ErrorSource> {
+    let Ok(Some(current)) = read_checked(dir, rel_fname) else {
+        // Missing, or corrupt: keep the backups we have.
+        return Ok(());
+    };
+    if crate::format::decode::<serde::de::IgnoredAny>(&current).is_err() {
+        return Ok(());
+    }
+
+    dir.make_directory(BACKUP_DIR)?;
+    for i in (1..n).rev() {
+        let from = backup_fname(rel_fname, i);
+        let to = backup_fname(rel_fname, i + 1);
+        for (from, to) in [(sum_fname(&from), sum_fname(&to)), (from, to)] {
+            match std::fs::rename(dir.join(from)?, dir.join(to)?) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(ErrorSource::IoError(Arc::new(e))),
+            }
+        }
+    }
+    let first = backup_fname(rel_fname, 1);
+    dir.write_and_replace(sum_fname(&first), checksum(&current))?;
+    dir.write_and_replace(&first, &current)?;
+    Ok(())
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{LockStatus, StateMgr};
+    use tor_error::{ErrorKind, HasKind};
+
+    #[test]
+    fn recover_from_backups() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let store = FsStateMgr::from_path(dir.path()).unwrap().with_backups(2);
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        for v in ["one", "two", "three"] {
+            store.store("thing", &v).unwrap();
+        }
+        assert_eq!(store.load::<String>("thing").unwrap().unwrap(), "three");
+        assert!(statedir.join("backups/thing.json.2").exists());
+        assert!(!statedir.join("backups/thing.json.3").exists());
+
+        // Truncated: use the newest backup.
+        std::fs::write(statedir.join("thing.json"), "\"thr").unwrap();
+        assert_eq!(store.load::<String>("thing").unwrap().unwrap(), "two");
+
+        // Valid JSON, but wrong checksum: still corrupt.  And if the newest
+        // backup is corrupt too, use the one before it.
+        std::fs::write(statedir.join("thing.json"), "\"other\"").unwrap();
+        std::fs::write(statedir.join("backups/thing.json.1"), "\"twX\"").unwrap();
+        assert_eq!(store.load::<String>("thing").unwrap().unwrap(), "one");
+
+        std::fs::write(statedir.join("backups/thing.json.2"), "").unwrap();
+        let err = store.load::<String>("thing").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PersistentStateCorrupted);
+
+        // Storing over a corrupt file doesn't back it up.
+        store.store("thing", &"four").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(statedir.join("backups/thing.json.1")).unwrap(),
+            "\"twX\""
+        );
+        assert_eq!(store.load::<String>("thing").unwrap().unwrap(), "four");
+    }
+
+    #[test]
+    fn unchecksummed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let store = FsStateMgr::from_path(dir.path()).unwrap();
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        store.store("thing", &"old").unwrap();
+        assert!(!statedir.join("thing.json.sum").exists());
+        drop(store);
+
+        // A file written without a checksum is still accepted.
+        let store = FsStateMgr::from_path(dir.path()).unwrap().with_backups(1);
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert_eq!(store.load::<String>("thing").unwrap().unwrap(), "old");
+        store.store("thing", &"new").unwrap();
+        assert_eq!(store.load::<String>("thing").unwrap().unwrap(), "new");
+        assert!(statedir.join("backups/thing.json.1").exists());
+    }
+}
@@ -0,0 +1,341 @@
+//! Control over how carefully an [`FsStateMgr`] gets its files onto the disk.
+//!
+//! By default, we write each value as soon as it is stored, but leave it to
+//! the operating system to decide when the data actually reaches the disk.
+//! That is a poor fit at both ends: a server that loses its guard state in a
+//! power failure would rather pay for an `fsync`, and a device whose state
+//! lives on flash would rather not rewrite a file every time a value changes.
+//! [`FsStateMgr::with_durability`] chooses between these with a
+//! [`Durability`].
+//!
+//! With [`Durability::Batched`], stored values are kept in memory, and loaded
+//! from there, until they are flushed.  We flush whenever a value is stored
+//! and the interval has passed since the last flush, before committing a
+//! transaction, when unlocking, when the last clone of the manager is
+//! dropped, and when [`FsStateMgr::flush`] is called.
+
+use super::FsStateMgr;
+use crate::err::{Action, ErrorSource};
+use crate::{Error, Result};
+use fs_mistrust::CheckedDir;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How carefully an [`FsStateMgr`] makes sure that stored values reach the
+/// disk.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Durability {
+    /// Write each value as soon as it is stored, and `fsync` it and its
+    /// directory before returning.
+    ///
+    /// Values survive a crash or power failure as soon as they are stored,
+    /// at the cost of slower stores.
+    Always,
+    /// Keep stored values in memory, and write them all at once, with
+    /// `fsync`, at most once every `interval`.
+    ///
+    /// This writes much less often when values change frequently, but
+    /// values stored since the last flush are lost if we crash.
+    Batched {
+        /// The shortest time to wait between flushes.
+        interval: Duration,
+    },
+    /// Write each value as soon as it is stored, but don't wait for it to
+    /// reach the disk.
+    ///
+    /// This is the default, and is how Arti has always behaved.
+    #[default]
+    Relaxed,
+}
+
+/// A serialized value, waiting to be written.
+#[derive(Debug)]
+pub(super) struct Record {
+    /// The value, as it will appear on disk.
+    output: Vec<u8>,
+    /// The number of backups to keep, as configured on the manager that
+    /// stored it.
+    #[cfg(feature = "backups")]
+    backups: Option<usize>,
+}
+
+/// The values stored with [`Durability::Batched`] that we have not yet
+/// written.
+#[derive(Debug)]
+pub(super) struct PendingWrites {
+    /// The values, by key.
+    records: BTreeMap<String, Record>,
+    /// When we last flushed.
+    last_flush: Instant,
+}
+
+impl Default for PendingWrites {
+    fn default() -> Self {
+        PendingWrites {
+            records: BTreeMap::new(),
+            last_flush: Instant::now(),
+        }
+    }
+}
+
+impl FsStateMgr {
+    /// Use `durability` to decide how carefully to write stored values.
+    ///
+    /// This only affects this manager, and clones made from it afterwards.
+    /// See [`Durability`] for the options.
+    #[must_use]
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Write any values that are still waiting to be written because of
+    /// [`Durability::Batched`].
+    ///
+    /// Does nothing if there are none.
+    pub fn flush(&self) -> Result<()> {
+        let records = {
+            let mut pending = self.inner.pending.lock().expect("Lock poisoned");
+            pending.last_flush = Instant::now();
+            std::mem::take(&mut pending.records)
+        };
+        for (key, record) in &records {
+            self.write_record(key, record, true)?;
+        }
+        Ok(())
+    }
+
+    /// Return true if our files should be `fsync`ed when we write them.
+    pub(super) fn syncs(&self) -> bool {
+        self.durability != Durability::Relaxed
+    }
+
+    /// Store `output` with `key`, according to our [`Durability`].
+    ///
+    /// The caller must hold the lock.
+    pub(super) fn write_durably(&self, key: &str, output: Vec<u8>) -> Result<()> {
+        let record = Record {
+            output,
+            #[cfg(feature = "backups")]
+            backups: self.backups,
+        };
+        match self.durability {
+            Durability::Batched { interval } => {
+                let due = {
+                    let mut pending = self.inner.pending.lock().expect("Lock poisoned");
+                    pending.records.insert(key.to_owned(), record);
+                    pending.last_flush.elapsed() >= interval
+                };
+                if due {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+            Durability::Always | Durability::Relaxed => {
+                // Don't let an older value, from a clone with different
+                // settings, replace this one later.
+                self.discard_pending(key);
+                self.write_record(key, &record, self.syncs())
+            }
+        }
+    }
+
+    /// If the value with `key` is waiting to be written, load it.
+    pub(super) fn load_pending<D: DeserializeOwned>(&self, key: &str) -> Result<Option<D>> {
+        let pending = self.inner.pending.lock().expect("Lock poisoned");
+        pending
+            .records
+            .get(key)
+            .map(|record| crate::format::decode(&record.output))
+            .transpose()
+            .map_err(|e| Error::new(e, Action::Loading, self.err_resource(key)))
+    }
+
+    /// Forget the value with `key`, if it is waiting to be written.
+    pub(super) fn discard_pending(&self, key: &str) {
+        self.inner
+            .pending
+            .lock()
+            .expect("Lock poisoned")
+            .records
+            .remove(key);
+    }
+
+    /// Return every value that is waiting to be written, with its key.
+    pub(super) fn pending_outputs(&self) -> Vec<(String, Vec<u8>)> {
+        let pending = self.inner.pending.lock().expect("Lock poisoned");
+        pending
+            .records
+            .iter()
+            .map(|(key, record)| (key.clone(), record.output.clone()))
+            .collect()
+    }
+
+    /// Write `record` to the file for `key`, and `fsync` it if `sync` is
+    /// true.
+    fn write_record(&self, key: &str, record: &Record, sync: bool) -> Result<()> {
+        #[cfg(feature = "backups")]
+        if let Some(n) = record.backups {
+            self.prepare_store_with_backups(key, &record.output, n)?;
+        }
+        self.with_load_store_target(key, Action::Storing, |t| {
+            if sync {
+                write_synced(t.dir, t.rel_fname, &record.output)
+            } else {
+                t.store_encoded(&record.output)
+            }
+        })
+    }
+}
+
+impl Drop for FsStateMgr {
+    fn drop(&mut self) {
+        // Only the last clone has to flush: until then, someone can still
+        // load the pending values.
+        if std::sync::Arc::strong_count(&self.inner) == 1 {
+            if let Err(e) = self.flush() {
+                tor_error::warn_report!(e, "Unable to write stored state to disk");
+            }
+        }
+    }
+}
+
+/// Like [`CheckedDir::write_and_replace`], but `fsync` the new file before
+/// it replaces the old one, and then its directory.
+pub(super) fn write_synced(
+    dir: &CheckedDir,
+    rel_fname: &Path,
+    contents: &[u8],
+) -> std::result::Result<(), ErrorSource> {
+    let tmp_fname = rel_fname.with_extension("tmp");
+    // As in write_and_replace, remove any old temporary file first, in case
+    // it's a symlink to somewhere silly.
+    let _ignore = dir.remove_file(&tmp_fname);
+    let mut tmp = dir.open(
+        &tmp_fname,
+        OpenOptions::new().create(true).truncate(true).write(true),
+    )?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    drop(tmp);
+    let fname = dir.join(rel_fname)?;
+    std::fs::rename(dir.join(&tmp_fname)?, &fname)?;
+    sync_dir(fname.parent().expect("joined path has no parent"))
+}
+
+/// `fsync` the directory `dir`, so that renames within it are on disk.
+///
+/// Directories can't be opened for syncing on Windows, where renames are
+/// durable by the time they return.
+pub(super) fn sync_dir(dir: &Path) -> std::result::Result<(), ErrorSource> {
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{LockStatus, StateMgr, Transaction};
+
+    #[test]
+    fn always() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let store = FsStateMgr::from_path(dir.path())
+            .unwrap()
+            .with_durability(Durability::Always);
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        store.store("a", &1_u32).unwrap();
+        store.store("a", &2_u32).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(statedir.join("a.json")).unwrap(),
+            "2"
+        );
+        assert!(!statedir.join("a.tmp").exists());
+    }
+
+    #[test]
+    fn batched() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let store =
+            FsStateMgr::from_path(dir.path())
+                .unwrap()
+                .with_durability(Durability::Batched {
+                    interval: Duration::from_secs(3600),
+                });
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        // Stored values are visible to us, but not on disk yet.
+        store.store("a", &1_u32).unwrap();
+        store.store("a", &2_u32).unwrap();
+        assert_eq!(store.load::<u32>("a").unwrap(), Some(2));
+        assert!(!statedir.join("a.json").exists());
+
+        store.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(statedir.join("a.json")).unwrap(),
+            "2"
+        );
+
+        // Committing a transaction flushes first, so that the older value
+        // can't replace the newer one later.
+        store.store("b", &1_u32).unwrap();
+        let mut txn = Transaction::new();
+        txn.store("b", &2_u32).unwrap();
+        store.commit(txn).unwrap();
+        assert_eq!(store.load::<u32>("b").unwrap(), Some(2));
+
+        // Dropping the last clone flushes.
+        store.store("c", &3_u32).unwrap();
+        let clone = store.clone();
+        drop(store);
+        assert!(!statedir.join("c.json").exists());
+        drop(clone);
+        assert_eq!(
+            std::fs::read_to_string(statedir.join("c.json")).unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn batched_due() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let store =
+            FsStateMgr::from_path(dir.path())
+                .unwrap()
+                .with_durability(Durability::Batched {
+                    interval: Duration::ZERO,
+                });
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        store.store("a", &1_u32).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(statedir.join("a.json")).unwrap(),
+            "1"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Garbage collection of stale records in an [`FsStateMgr`]'s directory.
+//!
+//! Some state records stop being useful without anybody deleting them: for
+//! example, data about a configuration that is no longer in use.  A garbage
+//! collection pass, run with [`FsStateMgr::collect_garbage`], removes records
+//! that haven't been stored for longer than a [`GcPolicy`]'s `max_age`, and
+//! then the least recently stored records until the directory is within its
+//! `max_total_size`.
+//!
+//! Subsystems that own records they still need, but don't store often, can
+//! protect them with [`FsStateMgr::add_gc_veto`].
+
+use super::FsStateMgr;
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, Result};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+/// A function that returns true if the record with a given key must be kept.
+type GcVeto = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// The vetoes registered with an [`FsStateMgr`].
+#[derive(Default)]
+pub(super) struct GcVetoes(Mutex<Vec<GcVeto>>);
+
+impl fmt::Debug for GcVetoes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcVetoes").finish_non_exhaustive()
+    }
+}
+
+impl GcVetoes {
+    /// Return true if any veto says that the record with `key` must be kept.
+    fn vetoed(&self, key: &str) -> bool {
+        // Clone the list, so that vetoes can't deadlock by adding more.
+        let vetoes = self.0.lock().expect("Lock poisoned").clone();
+        vetoes.iter().any(|veto| veto(key))
+    }
+}
+
+/// Limits to enforce when collecting garbage.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct GcPolicy {
+    /// Remove records that haven't been stored for longer than this.
+    ///
+    /// If `None`, records are never too old.
+    pub max_age: Option<Duration>,
+    /// Remove the least recently stored records until the total size of
+    /// all records is at most this many bytes.
+    ///
+    /// If `None`, there is no limit.
+    pub max_total_size: Option<u64>,
+}
+
+/// A record that we might collect.
+struct Candidate {
+    /// The record's key.
+    key: String,
+    /// The size of its file.
+    size: u64,
+    /// When it was last stored.
+    modified: SystemTime,
+}
+
+impl FsStateMgr {
+    /// Register `veto`, which is called with the key of each record that
+    /// we are about to collect, and returns true if it must be kept.
+    ///
+    /// Vetoes apply to this manager and all of its clones.
+    pub fn add_gc_veto<F>(&self, veto: F)
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.inner
+            .gc_vetoes
+            .0
+            .lock()
+            .expect("Lock poisoned")
+            .push(Arc::new(veto));
+    }
+
+    /// Remove stale records from our directory according to `policy`,
+    /// as of `now`.
+    ///
+    /// A record's age is the time since it was last stored.  Records that a
+    /// veto wants to keep are never removed, even if that leaves the
+    /// directory over its size limit.
+    ///
+    /// Requires that we hold the lock.  Returns the keys of the records that
+    /// we removed.
+    pub fn collect_garbage(&self, policy: &GcPolicy, now: SystemTime) -> Result<Vec<String>> {
+        let err = |e: ErrorSource| Error::new(e, Action::Deleting, Resource::Manager);
+        if !self.can_store() {
+            return Err(err(ErrorSource::NoLock));
+        }
+
+        let mut candidates = Vec::new();
+        let entries = std::fs::read_dir(self.inner.statepath.as_path())
+            .map_err(|e| err(ErrorSource::IoError(Arc::new(e))))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| err(ErrorSource::IoError(Arc::new(e))))?;
+            let fname = entry.file_name();
+            let Some(key) = fname.to_str().and_then(|f| f.strip_suffix(".json")) else {
+                continue;
+            };
+            let meta = entry
+                .metadata()
+                .map_err(|e| err(ErrorSource::IoError(Arc::new(e))))?;
+            if !meta.is_file() {
+                continue;
+            }
+            candidates.push(Candidate {
+                key: key.to_string(),
+                size: meta.len(),
+                modified: meta.modified().unwrap_or(now),
+            });
+        }
+        // Oldest first.
+        candidates.sort_by_key(|c| c.modified);
+
+        let mut total_size: u64 = candidates.iter().map(|c| c.size).sum();
+        let mut removed = Vec::new();
+        for c in candidates {
+            let too_old = policy.max_age.is_some_and(|max_age| {
+                now.duration_since(c.modified)
+                    .is_ok_and(|age| age > max_age)
+            });
+            let too_big = policy.max_total_size.is_some_and(|max| total_size > max);
+            if !(too_old || too_big) || self.inner.gc_vetoes.vetoed(&c.key) {
+                continue;
+            }
+
+            info!("Removing stale state record {:?}", c.key);
+            self.remove(&c.key)?;
+            total_size -= c.size;
+            removed.push(c.key);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{LockStatus, StateMgr};
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    /// Store `key` in `store`, and make it look as if it was stored
+    /// `days_ago` days before `now`.
+    fn store_aged(store: &FsStateMgr, key: &str, days_ago: u32, now: SystemTime) {
+        store.store(key, &"0123456789").unwrap();
+        let path = store.path().join("state").join(format!("{key}.json"));
+        let mtime = filetime::FileTime::from_system_time(now - DAY * days_ago);
+        filetime::set_file_mtime(path, mtime).unwrap();
+    }
+
+    #[test]
+    fn expire_and_budget() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStateMgr::from_path(dir.path()).unwrap();
+        let now = SystemTime::now();
+
+        assert!(store.collect_garbage(&GcPolicy::default(), now).is_err());
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        store_aged(&store, "ancient", 100, now);
+        store_aged(&store, "needed", 90, now);
+        store_aged(&store, "old", 10, now);
+        store_aged(&store, "new", 1, now);
+        store_aged(&store, "newest", 0, now);
+        store.add_gc_veto(|key| key == "needed");
+
+        let mut policy = GcPolicy::default();
+        assert!(store.collect_garbage(&policy, now).unwrap().is_empty());
+
+        policy.max_age = Some(DAY * 30);
+        assert_eq!(
+            store.collect_garbage(&policy, now).unwrap(),
+            vec!["ancient"]
+        );
+        assert!(store.load::<String>("needed").unwrap().is_some());
+
+        // Each record is 12 bytes: room for "needed" and two more.
+        policy.max_total_size = Some(36);
+        assert_eq!(store.collect_garbage(&policy, now).unwrap(), vec!["old"]);
+        assert!(store.load::<String>("new").unwrap().is_some());
+        assert!(store.load::<String>("newest").unwrap().is_some());
+    }
+}
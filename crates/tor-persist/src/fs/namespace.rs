@@ -0,0 +1,97 @@
+//! Namespaces: separate state managers for applications that embed Arti.
+//!
+//! An application that embeds Arti may want to keep some state of its own
+//! beside Arti's.  Rather than writing files into Arti's state directory,
+//! it can ask for a namespace with [`FsStateMgr::namespace`], and get a
+//! state manager of its own.
+
+use super::FsStateMgr;
+use crate::err::{Action, Resource};
+use crate::slug::SlugRef;
+use crate::{Error, Result};
+
+/// The subdirectory of an [`FsStateMgr`]'s directory that holds its
+/// namespaces.
+const NAMESPACES_DIR: &str = "namespaces";
+
+impl FsStateMgr {
+    /// Return a new state manager for the namespace called `name`.
+    ///
+    /// The namespace is kept in the `namespaces/NAME` subdirectory of our
+    /// [`path`](FsStateMgr::path), with the same permission checks as this
+    /// manager.  Its keys are separate from ours, and from those of every other
+    /// namespace, and it has its own lock: taking or releasing it has no
+    /// effect on this manager.
+    ///
+    /// Every call with the same `name` returns a manager for the same
+    /// namespace.  As with any `FsStateMgr`, clone the result rather than
+    /// calling this again, so that the clones share a lock.
+    ///
+    /// The namespaces of a read-only manager are read-only too.
+    pub fn namespace(&self, name: &SlugRef) -> Result<FsStateMgr> {
+        let dir = self.path().join(NAMESPACES_DIR);
+        if self.inner.lockfile.is_none() {
+            return FsStateMgr::open_read_only(dir.join(name.as_str()), &self.inner.mistrust);
+        }
+        self.inner
+            .mistrust
+            .verifier()
+            .make_secure_dir(&dir)
+            .map_err(|e| {
+                Error::new(
+                    e,
+                    Action::Initializing,
+                    Resource::Directory { dir: dir.clone() },
+                )
+            })?;
+        FsStateMgr::from_path_and_mistrust(dir.join(name.as_str()), &self.inner.mistrust)
+    }
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{LockStatus, StateMgr};
+
+    #[test]
+    fn isolated() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStateMgr::from_path(dir.path()).unwrap();
+        let app = store.namespace(crate::slug!("my-app")).unwrap();
+        let other = store.namespace(crate::slug!("other")).unwrap();
+        assert_eq!(app.path(), dir.path().join("namespaces/my-app"));
+
+        // Separate locks.
+        assert_eq!(app.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert!(!store.can_store());
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        app.unlock().unwrap();
+        assert!(store.can_store());
+        assert_eq!(app.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        // Separate keys.
+        store.store("thing", &1_u32).unwrap();
+        app.store("thing", &2_u32).unwrap();
+        assert_eq!(store.load::<u32>("thing").unwrap(), Some(1));
+        assert_eq!(app.load::<u32>("thing").unwrap(), Some(2));
+        assert_eq!(other.load::<u32>("thing").unwrap(), None);
+
+        // The same name gives the same namespace.
+        drop(app);
+        let app = store.namespace(crate::slug!("my-app")).unwrap();
+        assert_eq!(app.load::<u32>("thing").unwrap(), Some(2));
+    }
+}
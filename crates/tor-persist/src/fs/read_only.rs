@@ -0,0 +1,228 @@
+//! Read-only access to an [`FsStateMgr`]'s directory, for processes other
+//! than the one that holds its lock.
+//!
+//! Tools that inspect the state of a running Arti can't take the lock, and
+//! shouldn't create or change any files.  [`FsStateMgr::open_read_only`]
+//! gives them a manager that never does, and [`FsStateMgr::snapshot`] reads
+//! every record at once.
+//!
+//! Each file is replaced atomically, so any one record is always intact.
+//! But the writer may change several records while we read them, as part of
+//! a [`Transaction`](crate::Transaction); so a snapshot reads every record,
+//! and then reads them again, until two passes in a row agree.
+
+use super::transaction::TXN_DIR;
+use super::FsStateMgr;
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, Result};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// The records in an [`FsStateMgr`]'s directory, as of a single moment.
+///
+/// Returned by [`FsStateMgr::snapshot`].
+#[derive(Clone, Debug)]
+pub struct StateSnapshot {
+    /// The serialized records, by the names of their files, without the
+    /// `.json` extension.
+    records: BTreeMap<String, Vec<u8>>,
+}
+
+impl FsStateMgr {
+    /// Open the state in `path` to read, without ever writing to it.
+    ///
+    /// Unlike [`from_path_and_mistrust`](FsStateMgr::from_path_and_mistrust),
+    /// this doesn't create the directory or its lock file, and fails if the
+    /// directory does not exist.  The result can load values, but
+    /// [`try_lock`](crate::StateMgr::try_lock) always returns
+    /// [`LockStatus::NoLock`](crate::LockStatus::NoLock), so it can never
+    /// store them.  This is safe to use while another process holds the
+    /// lock.
+    pub fn open_read_only<P: AsRef<Path>>(
+        path: P,
+        mistrust: &fs_mistrust::Mistrust,
+    ) -> Result<Self> {
+        let dir = path.as_ref().join("state");
+        let statepath = mistrust
+            .verifier()
+            .check_content()
+            .secure_dir(&dir)
+            .map_err(|e| Error::new(e, Action::Initializing, Resource::Directory { dir }))?;
+        Ok(Self::from_checked_dir(statepath, None, mistrust))
+    }
+
+    /// Read every record in this manager's directory, consistently.
+    ///
+    /// If another process commits a transaction while we read, the snapshot
+    /// has either all of its changes, or none.  See the
+    /// [module documentation](self) for how.
+    ///
+    /// This includes values stored by this manager that are still waiting to
+    /// be written because of [`Durability::Batched`](super::Durability::Batched).
+    pub fn snapshot(&self) -> Result<StateSnapshot> {
+        let err = |e: ErrorSource| Error::new(e, Action::Loading, Resource::Manager);
+        let mut records = self.read_records().map_err(err)?;
+        loop {
+            let again = self.read_records().map_err(err)?;
+            if again == records {
+                break;
+            }
+            records = again;
+        }
+        for (key, output) in self.pending_outputs() {
+            records.insert(sanitize_filename::sanitize(key), output);
+        }
+        Ok(StateSnapshot { records })
+    }
+
+    /// Read every record in this manager's directory, once, along with any
+    /// changes from a committed transaction that hasn't been finished.
+    fn read_records(&self) -> std::result::Result<BTreeMap<String, Vec<u8>>, ErrorSource> {
+        let dir = &self.inner.statepath;
+        let committed = self.commit_marker()?.unwrap_or_default();
+
+        let mut fnames = BTreeSet::new();
+        for entry in std::fs::read_dir(dir.as_path())? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fnames.insert(PathBuf::from(entry.file_name()));
+            }
+        }
+        fnames.extend(committed.iter().cloned());
+
+        let mut records = BTreeMap::new();
+        for fname in fnames {
+            if fname.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            let Some(name) = fname.file_stem().and_then(OsStr::to_str) else {
+                continue;
+            };
+            let name = name.to_owned();
+            let staged = committed
+                .contains(&fname)
+                .then(|| Path::new(TXN_DIR).join(&fname));
+            // If the staged file has been renamed into place since we read
+            // the marker, we find nothing, and read the renamed file instead.
+            for fname in staged.iter().chain([&fname]) {
+                match dir.read(fname) {
+                    Ok(record) => {
+                        records.insert(name, record);
+                        break;
+                    }
+                    Err(fs_mistrust::Error::NotFound(_)) => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+impl StateSnapshot {
+    /// Return the keys of every record in this snapshot.
+    ///
+    /// Each key is recovered from the name of its file, so it is only
+    /// accurate for keys that are already fs-safe.  (See the "Limitations"
+    /// section on [`FsStateMgr`].)
+    pub fn keys(&self) -> impl Iterator<Item = &str> + '_ {
+        self.records.keys().map(String::as_str)
+    }
+
+    /// Deserialize the record with `key`, if there is one.
+    ///
+    /// Use [`JsonValue`](crate::JsonValue) to inspect records of any type.
+    pub fn load<D: DeserializeOwned>(&self, key: &str) -> Result<Option<D>> {
+        self.records
+            .get(&sanitize_filename::sanitize(key))
+            .map(|record| crate::format::decode(record))
+            .transpose()
+            .map_err(|e| Error::new(e, Action::Loading, Resource::Manager))
+    }
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{LockStatus, StateMgr};
+
+    fn trust() -> fs_mistrust::Mistrust {
+        fs_mistrust::Mistrust::new_dangerously_trust_everyone()
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert!(FsStateMgr::open_read_only(dir.path(), &trust()).is_err());
+        assert!(!dir.path().join("state").exists());
+
+        let primary = FsStateMgr::from_path(dir.path()).unwrap();
+        assert_eq!(primary.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        primary.store("a", &1_u32).unwrap();
+
+        let reader = FsStateMgr::open_read_only(dir.path(), &trust()).unwrap();
+        assert!(!reader.can_store());
+        assert_eq!(reader.try_lock().unwrap(), LockStatus::NoLock);
+        assert!(reader.store("a", &2_u32).is_err());
+        reader.unlock().unwrap();
+        assert_eq!(reader.load::<u32>("a").unwrap(), Some(1));
+        primary.store("a", &3_u32).unwrap();
+        assert_eq!(reader.load::<u32>("a").unwrap(), Some(3));
+
+        // We don't need, or create, the lock file.
+        drop(primary);
+        std::fs::remove_file(dir.path().join("state/state.lock")).unwrap();
+        let reader = FsStateMgr::open_read_only(dir.path(), &trust()).unwrap();
+        assert_eq!(reader.load::<u32>("a").unwrap(), Some(3));
+        let ns = reader.namespace(crate::slug!("app"));
+        assert!(ns.is_err());
+        assert!(!dir.path().join("state/state.lock").exists());
+        assert!(!dir.path().join("namespaces").exists());
+    }
+
+    #[test]
+    fn snapshot() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let primary = FsStateMgr::from_path(dir.path()).unwrap();
+        assert_eq!(primary.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        primary.store("a", &1_u32).unwrap();
+        primary.store("b", &2_u32).unwrap();
+
+        // A transaction that was committed, but not finished: it changes
+        // `b`, and adds `c`.
+        std::fs::create_dir(statedir.join(TXN_DIR)).unwrap();
+        std::fs::write(statedir.join(TXN_DIR).join("b.json"), "20").unwrap();
+        std::fs::write(statedir.join(TXN_DIR).join("c.json"), "30").unwrap();
+        std::fs::write(
+            statedir.join(TXN_DIR).join("commit-marker"),
+            r#"["b.json","c.json"]"#,
+        )
+        .unwrap();
+        std::fs::write(statedir.join("notes.txt"), "not a record").unwrap();
+
+        let reader = FsStateMgr::open_read_only(dir.path(), &trust()).unwrap();
+        let snapshot = reader.snapshot().unwrap();
+        assert_eq!(snapshot.keys().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(snapshot.load::<u32>("a").unwrap(), Some(1));
+        assert_eq!(snapshot.load::<u32>("b").unwrap(), Some(20));
+        assert_eq!(snapshot.load::<u32>("c").unwrap(), Some(30));
+        assert_eq!(snapshot.load::<u32>("d").unwrap(), None);
+        assert!(snapshot.load::<String>("a").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Atomic transactions for [`FsStateMgr`].
+//!
+//! To commit a [`Transaction`], we write each new file into the
+//! `transaction` subdirectory, and then write a commit marker listing them.
+//! Writing the marker is the commit point.  Then we rename each staged file
+//! into place, and remove the marker.
+//!
+//! If we crash before writing the marker, the staged files are discarded
+//! the next time we take the lock.  If we crash after writing it, the
+//! renames are finished the next time we take the lock; until then, readers
+//! that see the marker load the staged files instead.
+
+use super::FsStateMgr;
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, Result, Transaction};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The subdirectory of the state directory in which we stage transactions.
+pub(super) const TXN_DIR: &str = "transaction";
+
+/// The commit marker for a transaction, listing the files that it changes.
+///
+/// (This has no `.json` extension, so it can't be the file for any key.)
+const COMMIT_MARKER: &str = "transaction/commit-marker";
+
+impl FsStateMgr {
+    /// Store every value in `txn` atomically.
+    ///
+    /// The caller must hold the lock.
+    pub(super) fn commit_transaction(&self, txn: Transaction) -> Result<()> {
+        let dir = &self.inner.statepath;
+        let err = |e: ErrorSource| Error::new(e, Action::Storing, Resource::Manager);
+
+        // Finish any earlier transaction first, in case it was for some of
+        // the same files, and write any older values for them that are still
+        // waiting.
+        self.recover_transaction()?;
+        self.flush()?;
+        let write = |rel_fname: &Path, contents: &[u8]| {
+            if self.syncs() {
+                super::durability::write_synced(dir, rel_fname, contents)
+            } else {
+                dir.write_and_replace(rel_fname, contents)
+                    .map_err(ErrorSource::from)
+            }
+        };
+
+        dir.make_directory(TXN_DIR).map_err(|e| err(e.into()))?;
+        let mut fnames = Vec::new();
+        for (key, value) in txn.into_values() {
+            let output = serde_json::to_string_pretty(&value)
+                .map_err(|e| Error::new(e, Action::Storing, self.err_resource(&key)))?;
+            let rel_fname = self.rel_filename(&key);
+            #[cfg(feature = "watch")]
+            self.note_own_write(&rel_fname, output.as_bytes());
+            #[cfg(feature = "backups")]
+            if let Some(n) = self.backups {
+                self.prepare_store_with_backups(&key, output.as_bytes(), n)?;
+            }
+            write(&Path::new(TXN_DIR).join(&rel_fname), output.as_bytes())
+                .map_err(|e| Error::new(e, Action::Storing, self.err_resource(&key)))?;
+            fnames.push(rel_fname);
+        }
+
+        let marker = serde_json::to_string(&fnames).map_err(|e| err(Arc::new(e).into()))?;
+        write(Path::new(COMMIT_MARKER), marker.as_bytes()).map_err(err)?;
+
+        self.finish_transaction(&fnames).map_err(err)?;
+        if self.syncs() {
+            super::durability::sync_dir(dir.as_path()).map_err(err)?;
+        }
+        Ok(())
+    }
+
+    /// Complete a transaction that was interrupted after it was committed,
+    /// and discard any that was interrupted before then.
+    ///
+    /// The caller must hold the lock.
+    pub(super) fn recover_transaction(&self) -> Result<()> {
+        let dir = &self.inner.statepath;
+        let err = |e: ErrorSource| Error::new(e, Action::Storing, Resource::Manager);
+
+        if let Some(fnames) = self.commit_marker().map_err(err)? {
+            self.finish_transaction(&fnames).map_err(err)?;
+        }
+
+        let staged = match dir.read_directory(TXN_DIR) {
+            Ok(staged) => staged,
+            Err(fs_mistrust::Error::NotFound(_)) => return Ok(()),
+            Err(e) => return Err(err(e.into())),
+        };
+        for entry in staged {
+            let entry = entry.map_err(|e| err(ErrorSource::IoError(Arc::new(e))))?;
+            dir.remove_file(Path::new(TXN_DIR).join(entry.file_name()))
+                .map_err(|e| err(e.into()))?;
+        }
+        Ok(())
+    }
+
+    /// If there is a committed transaction that hasn't been finished, and it
+    /// changes the value with `key`, load that value.
+    pub(super) fn load_committed<D: DeserializeOwned>(&self, key: &str) -> Result<Option<D>> {
+        let err = |e: ErrorSource| Error::new(e, Action::Loading, self.err_resource(key));
+        let rel_fname = self.rel_filename(key);
+        match self.commit_marker().map_err(err)? {
+            Some(fnames) if fnames.contains(&rel_fname) => {}
+            _ => return Ok(None),
+        }
+        // If the file has been renamed into place since we read the marker,
+        // this finds nothing, and the caller loads the renamed file.
+        crate::load_store::Target {
+            dir: &self.inner.statepath,
+            rel_fname: &Path::new(TXN_DIR).join(rel_fname),
+        }
+        .load()
+        .map_err(err)
+    }
+
+    /// Read the list of files in the committed transaction, if there is one.
+    pub(super) fn commit_marker(&self) -> std::result::Result<Option<Vec<PathBuf>>, ErrorSource> {
+        match self.inner.statepath.read_to_string(COMMIT_MARKER) {
+            Ok(marker) => Ok(Some(
+                serde_json::from_str(&marker).map_err(|e| ErrorSource::from(Arc::new(e)))?,
+            )),
+            Err(fs_mistrust::Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Move the staged files `fnames` into place, and remove the commit
+    /// marker.
+    fn finish_transaction(&self, fnames: &[PathBuf]) -> std::result::Result<(), ErrorSource> {
+        let dir = &self.inner.statepath;
+        for fname in fnames {
+            let staged = dir.join(Path::new(TXN_DIR).join(fname))?;
+            match std::fs::rename(staged, dir.join(fname)?) {
+                Ok(()) => {}
+                // We already moved it, before we were interrupted.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(ErrorSource::IoError(Arc::new(e))),
+            }
+        }
+        dir.remove_file(COMMIT_MARKER)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{LockStatus, StateMgr};
+
+    #[test]
+    fn commit() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStateMgr::from_path(dir.path()).unwrap();
+
+        let mut txn = Transaction::new();
+        txn.store("a", &1_u32).unwrap();
+        txn.store("b", &2_u32).unwrap();
+        assert!(store.commit(txn.clone()).is_err());
+
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        store.commit(txn).unwrap();
+        assert_eq!(store.load::<u32>("a").unwrap(), Some(1));
+        assert_eq!(store.load::<u32>("b").unwrap(), Some(2));
+        assert!(!dir.path().join("state").join(COMMIT_MARKER).exists());
+    }
+
+    #[test]
+    fn interrupted() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let statedir = dir.path().join("state");
+        let txndir = statedir.join(TXN_DIR);
+        let store = FsStateMgr::from_path(dir.path()).unwrap();
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        store.store("a", &1_u32).unwrap();
+        store.store("b", &2_u32).unwrap();
+        drop(store);
+
+        // Crashed before committing: the staged file is discarded.
+        std::fs::create_dir(&txndir).unwrap();
+        std::fs::write(txndir.join("a.json"), "10").unwrap();
+        let store = FsStateMgr::from_path(dir.path()).unwrap();
+        assert_eq!(store.load::<u32>("a").unwrap(), Some(1));
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert!(!txndir.join("a.json").exists());
+        drop(store);
+
+        // Crashed after committing, and after moving one file into place.
+        std::fs::write(txndir.join("b.json"), "20").unwrap();
+        std::fs::write(statedir.join("a.json"), "10").unwrap();
+        std::fs::write(statedir.join(COMMIT_MARKER), r#"["a.json","b.json"]"#).unwrap();
+        let store = FsStateMgr::from_path(dir.path()).unwrap();
+        // Readers see the whole transaction...
+        assert_eq!(store.load::<u32>("a").unwrap(), Some(10));
+        assert_eq!(store.load::<u32>("b").unwrap(), Some(20));
+        // ...and it is finished when we take the lock.
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert!(!statedir.join(COMMIT_MARKER).exists());
+        assert_eq!(
+            std::fs::read_to_string(statedir.join("b.json")).unwrap(),
+            "20"
+        );
+    }
+}
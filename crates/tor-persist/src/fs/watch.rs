@@ -0,0 +1,275 @@
+//! Watching an [`FsStateMgr`]'s directory for changes made by other processes.
+//!
+//! Only one process can hold the lock on a state directory and write to it,
+//! but any number can read from it.  A reader that wants to notice when the
+//! writer changes something can call [`FsStateMgr::watch`], and reload the
+//! values whose keys it is told about.
+
+use super::FsStateMgr;
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, Result};
+use futures::channel::mpsc;
+use futures::Stream;
+use notify::{EventKind, Watcher as _};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// The concrete type of the underlying watcher.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "windows"))]
+type NotifyWatcher = notify::RecommendedWatcher;
+/// The concrete type of the underlying watcher.
+///
+/// (On backends using kqueue, we use a polling watcher to work around
+/// <https://github.com/notify-rs/notify/issues/644>, as `tor-config` does.)
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "windows")))]
+type NotifyWatcher = notify::PollWatcher;
+
+/// How often a polling watcher checks for changes.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "windows")))]
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The contents that this process last wrote for each file in a state
+/// directory, by filename.
+///
+/// We use this to avoid telling a watcher about our own writes.
+pub(super) type OwnWrites = Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>;
+
+/// A change to the state in a directory, reported by a [`StateWatcher`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum StateChange {
+    /// The value with the given key was created, replaced, or deleted by
+    /// another process.
+    ///
+    /// The key is recovered from the name of the file, so it is only
+    /// accurate for keys that are already fs-safe.  (See the "Limitations"
+    /// section on [`FsStateMgr`].)
+    Changed(String),
+    /// We may have missed some changes: reload every value you care about.
+    Rescan,
+}
+
+/// A stream of [`StateChange`]s for a state directory.
+///
+/// Returned by [`FsStateMgr::watch`].  Changes stop being reported when
+/// this is dropped.
+///
+/// Several changes may be reported for a single write, and changes made
+/// in quick succession may be reported together.  Consumers should treat
+/// each change as a hint to reload the value, not as a count of updates.
+pub struct StateWatcher {
+    /// The underlying watcher.
+    ///
+    /// We keep this only so that it is not dropped.
+    _watcher: NotifyWatcher,
+    /// The changes that the watcher has reported.
+    rx: mpsc::UnboundedReceiver<StateChange>,
+}
+
+impl std::fmt::Debug for StateWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateWatcher").finish_non_exhaustive()
+    }
+}
+
+impl Stream for StateWatcher {
+    type Item = StateChange;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StateChange>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+impl FsStateMgr {
+    /// Start watching this manager's directory for changes made by other
+    /// processes.
+    ///
+    /// Uses inotify, kqueue, or the platform's equivalent where it is
+    /// reliable, and polls otherwise.  Values stored through this manager
+    /// (or its clones) are not reported.
+    pub fn watch(&self) -> Result<StateWatcher> {
+        let dir = self.inner.statepath.as_path().to_path_buf();
+        let own_writes = Arc::clone(&self.inner.own_writes);
+        let (tx, rx) = mpsc::unbounded();
+
+        let handler = {
+            let dir = dir.clone();
+            move |event: notify::Result<notify::Event>| {
+                for change in handle_event(event, &dir, &own_writes) {
+                    // If this fails, the StateWatcher has been dropped,
+                    // and nobody is interested any more.
+                    let _ = tx.unbounded_send(change);
+                }
+            }
+        };
+
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "windows"))]
+        let config = notify::Config::default();
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "windows")))]
+        let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+
+        let err = |e: notify::Error| {
+            Error::new(
+                ErrorSource::Watch(Arc::new(e)),
+                Action::Watching,
+                Resource::Directory { dir: dir.clone() },
+            )
+        };
+        let mut watcher = NotifyWatcher::new(handler, config).map_err(err)?;
+        watcher
+            .watch(&dir, notify::RecursiveMode::NonRecursive)
+            .map_err(err)?;
+
+        Ok(StateWatcher {
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    /// Remember that we are about to write `contents` to the file
+    /// `rel_fname`, so that watchers don't report it as a change.
+    pub(super) fn note_own_write(&self, rel_fname: &Path, contents: &[u8]) {
+        self.inner
+            .own_writes
+            .lock()
+            .expect("Lock poisoned")
+            .insert(rel_fname.to_path_buf(), contents.to_vec());
+    }
+}
+
+/// Map a `notify` event for the state directory `dir` to the changes that
+/// we should report.
+fn handle_event(
+    event: notify::Result<notify::Event>,
+    dir: &Path,
+    own_writes: &OwnWrites,
+) -> Vec<StateChange> {
+    let event = match event {
+        Ok(event) => event,
+        // We don't know what we missed.
+        Err(_) => return vec![StateChange::Rescan],
+    };
+    if event.need_rescan() {
+        return vec![StateChange::Rescan];
+    }
+    if matches!(
+        event.kind,
+        EventKind::Access(_) | EventKind::Any | EventKind::Other
+    ) {
+        return vec![];
+    }
+
+    let mut changes = vec![];
+    for path in &event.paths {
+        let Some(fname) = path.file_name() else {
+            continue;
+        };
+        let Some(key) = fname.to_str().and_then(|f| f.strip_suffix(".json")) else {
+            // Not a state file: probably a temporary file or the lock.
+            continue;
+        };
+        let own = own_writes
+            .lock()
+            .expect("Lock poisoned")
+            .get(Path::new(fname))
+            .is_some_and(|ours| std::fs::read(dir.join(fname)).ok().as_ref() == Some(ours));
+        if own {
+            continue;
+        }
+        let change = StateChange::Changed(key.to_string());
+        if !changes.contains(&change) {
+            changes.push(change);
+        }
+    }
+    changes
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{LockStatus, StateMgr};
+    use futures::{FutureExt as _, StreamExt as _};
+    use std::time::{Duration, Instant};
+
+    /// Wait up to `timeout` for `watcher` to report that `key` changed.
+    fn wait_for_change(watcher: &mut StateWatcher, key: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            while let Some(Some(change)) = watcher.next().now_or_never() {
+                if change == StateChange::Changed(key.into()) {
+                    return true;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "windows"))]
+    fn other_writer() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let writer = FsStateMgr::from_path(dir.path()).unwrap();
+        let reader = FsStateMgr::from_path(dir.path()).unwrap();
+        assert_eq!(writer.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        let mut reader_watch = reader.watch().unwrap();
+        let mut writer_watch = writer.watch().unwrap();
+
+        writer.store("guards", &"some guards").unwrap();
+        assert!(wait_for_change(
+            &mut reader_watch,
+            "guards",
+            Duration::from_secs(10)
+        ));
+        assert_eq!(
+            reader.load::<String>("guards").unwrap().unwrap(),
+            "some guards"
+        );
+
+        // The writer isn't told about its own changes.
+        assert!(!wait_for_change(
+            &mut writer_watch,
+            "guards",
+            Duration::from_millis(500)
+        ));
+    }
+
+    #[test]
+    fn ignored_files() {
+        let dir = Path::new("/nonexistent");
+        let own_writes = OwnWrites::default();
+        let event = |name: &str| {
+            Ok(
+                notify::Event::new(EventKind::Create(notify::event::CreateKind::File))
+                    .add_path(dir.join(name)),
+            )
+        };
+        assert_eq!(
+            handle_event(event("guards.json"), dir, &own_writes),
+            vec![StateChange::Changed("guards".into())]
+        );
+        assert!(handle_event(event("guards.tmp"), dir, &own_writes).is_empty());
+        assert!(handle_event(event("state.lock"), dir, &own_writes).is_empty());
+        assert_eq!(
+            handle_event(Err(notify::Error::generic("oops")), dir, &own_writes),
+            vec![StateChange::Rescan]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Support for several Arti instances sharing the same base directories.
+//!
+//! Each instance is identified by a [`Slug`].
+//! An instance that has one keeps its state, keys and cache in its own
+//! subdirectory of each base directory, and so gets its own locks as well.
+//! An instance without one uses the base directories directly,
+//! as it always has.
+//!
+//! ### Implied filesystem structure
+//!
+//! ```text
+//! BASE_DIR/                       (state of the unnamed instance)
+//! BASE_DIR/instances/
+//! BASE_DIR/instances/INSTANCE/    (state of the instance named INSTANCE)
+//!
+//! eg
+//!
+//! STATE_DIR/instances/system-proxy/state/state.lock
+//! STATE_DIR/instances/system-proxy/state/guards.json
+//! STATE_DIR/instances/system-proxy/keystore/
+//! ```
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::err::{Action, Resource};
+use crate::slug::{Slug, SlugRef};
+use crate::{Error, Result};
+
+/// Name of the subdirectory of a base directory that holds named instances.
+pub const INSTANCES_DIR: &str = "instances";
+
+/// Return the directory to use for `instance` within the base directory `base`.
+///
+/// If `instance` is `None`, this is `base` itself.
+pub fn instance_dir(base: impl AsRef<Path>, instance: Option<&SlugRef>) -> PathBuf {
+    let base = base.as_ref();
+    match instance {
+        Some(instance) => base.join(INSTANCES_DIR).join(instance.as_str()),
+        None => base.to_path_buf(),
+    }
+}
+
+/// Return the names of all the instances in the base directory `base`.
+///
+/// The unnamed instance, if any, is not included.
+/// Entries in the instances directory that are not directories,
+/// or whose names are not valid slugs, are ignored.
+///
+/// The names are returned in sorted order.
+pub fn list_instances(base: impl AsRef<Path>) -> Result<Vec<Slug>> {
+    let dir = base.as_ref().join(INSTANCES_DIR);
+    let err = |e: io::Error| {
+        Error::new(
+            e,
+            Action::Enumerating,
+            Resource::Directory { dir: dir.clone() },
+        )
+    };
+
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(err(e)),
+    };
+
+    let mut instances = vec![];
+    for entry in entries {
+        let entry = entry.map_err(err)?;
+        if !entry.file_type().map_err(err)?.is_dir() {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        if let Ok(slug) = Slug::new(name) {
+            instances.push(slug);
+        }
+    }
+    instances.sort();
+    Ok(instances)
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn dirs() {
+        let base = Path::new("/var/lib/arti");
+        assert_eq!(instance_dir(base, None), base);
+        let proxy = SlugRef::new("system-proxy").unwrap();
+        assert_eq!(
+            instance_dir(base, Some(proxy)),
+            Path::new("/var/lib/arti/instances/system-proxy")
+        );
+    }
+
+    #[test]
+    fn list() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(list_instances(tmp.path()).unwrap().is_empty());
+
+        let instances = tmp.path().join(INSTANCES_DIR);
+        for name in ["zebra", "alpha", "Not A Slug"] {
+            std::fs::create_dir_all(instances.join(name)).unwrap();
+        }
+        std::fs::write(instances.join("file"), b"").unwrap();
+
+        let found = list_instances(tmp.path()).unwrap();
+        let found: Vec<&str> = found.iter().map(|s| s.as_str()).collect();
+        assert_eq!(found, ["alpha", "zebra"]);
+    }
+}
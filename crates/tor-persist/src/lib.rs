@@ -46,16 +46,27 @@
 // TODO #1645 (either remove this, or decide to have it everywhere)
 #![cfg_attr(not(all(feature = "experimental", feature = "full")), allow(unused))]
 
+mod backend;
+mod dyn_mgr;
+#[cfg(feature = "encryption")]
+mod encrypted;
 mod err;
+mod format;
 #[cfg(not(target_arch = "wasm32"))]
 mod fs;
 mod fs_mistrust_error_ext;
 mod handle;
 pub mod hsnickname;
+pub mod instance;
 mod load_store;
+mod memory;
 pub mod slug;
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+mod sqlite;
 #[cfg(feature = "testing")]
 mod testing;
+mod transaction;
+mod versioned;
 
 #[cfg(feature = "state-dir")]
 pub mod state_dir;
@@ -66,14 +77,28 @@ use std::sync::Arc;
 /// Wrapper type for Results returned from this crate.
 type Result<T> = std::result::Result<T, crate::Error>;
 
+pub use backend::{BackendError, BackendStateMgr, StateBackend};
+pub use dyn_mgr::DynStateMgr;
+#[cfg(feature = "encryption")]
+pub use encrypted::{EncryptedStateMgr, PassphraseKdfParams, StateEncryptionKey};
 pub use err::{Error, ErrorSource};
+pub use format::RecordFormat;
+#[cfg(all(feature = "gc", not(target_arch = "wasm32")))]
+pub use fs::GcPolicy;
 #[cfg(not(target_arch = "wasm32"))]
-pub use fs::FsStateMgr;
+pub use fs::{Durability, FsStateMgr, StateSnapshot};
+#[cfg(all(feature = "watch", not(target_arch = "wasm32")))]
+pub use fs::{StateChange, StateWatcher};
 pub use fs_mistrust_error_ext::FsMistrustErrorExt;
 pub use handle::{DynStorageHandle, StorageHandle};
+pub use memory::MemoryStateMgr;
 pub use serde_json::Value as JsonValue;
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+pub use sqlite::{SqliteBatch, SqliteStateMgr};
 #[cfg(feature = "testing")]
 pub use testing::TestingStateMgr;
+pub use transaction::Transaction;
+pub use versioned::{MigrationError, Schema};
 
 /// An object that can manage persistent state.
 ///
@@ -98,6 +123,30 @@ pub trait StateMgr: Clone {
     fn store<S>(&self, key: &str, val: &S) -> Result<()>
     where
         S: Serialize;
+    /// Try to save `val` with key `key` in the store, serialized in `format`.
+    ///
+    /// Replaces any previous value associated with `key`.
+    /// [`load`](StateMgr::load) detects the format of each record,
+    /// so records in different formats can be mixed freely,
+    /// and a record can be moved to a new format by storing it again.
+    ///
+    /// State managers that can only store JSON ignore `format`:
+    /// the default implementation just calls [`store`](StateMgr::store).
+    fn store_with_format<S>(&self, key: &str, val: &S, _format: RecordFormat) -> Result<()>
+    where
+        S: Serialize,
+    {
+        self.store(key, val)
+    }
+    /// Store every value in `txn`, replacing any previous values with the
+    /// same keys.
+    ///
+    /// This is atomic: either every value is stored, or (if there is an
+    /// error, or we crash) none of them are.
+    ///
+    /// As with [`store`](StateMgr::store), this requires that we hold the
+    /// lock.
+    fn commit(&self, txn: Transaction) -> Result<()>;
     /// Return true if this is a read-write state manager.
     ///
     /// If it returns false, then attempts to `store` will fail with
@@ -126,6 +175,33 @@ pub trait StateMgr: Clone {
     {
         Arc::new(handle::StorageHandleImpl::new(self, key.into()))
     }
+
+    /// Make a new [`StorageHandle`] to store values of particular type
+    /// at a particular key, tagged with the version of their `schema`.
+    ///
+    /// When loading, values stored with an older version of the schema are
+    /// upgraded with its migrations (and stored back, if we hold the lock).
+    /// Values stored with a newer version than `schema` knows about give an
+    /// error of kind [`PersistentStateCorrupted`](tor_error::ErrorKind::PersistentStateCorrupted),
+    /// rather than being misread.
+    ///
+    /// Values that were stored at `key` without a version, such as with
+    /// [`create_handle`](StateMgr::create_handle), are treated as version 0.
+    fn create_versioned_handle<T>(
+        self,
+        key: impl Into<String>,
+        schema: Schema,
+    ) -> DynStorageHandle<T>
+    where
+        Self: Send + Sync + Sized + 'static,
+        T: Serialize + DeserializeOwned + 'static,
+    {
+        Arc::new(versioned::VersionedStorageHandle::new(
+            self,
+            key.into(),
+            schema,
+        ))
+    }
 }
 
 /// A possible outcome from calling [`StateMgr::try_lock()`]
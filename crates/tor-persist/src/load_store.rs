@@ -1,7 +1,7 @@
 //! Helper module for loading and storing via serde
 //!
 //! Utilities to load or store a serde-able object,
-//! in JSON format,
+//! in JSON format (or, when loading, any [`RecordFormat`](crate::RecordFormat)),
 //! to/from a disk file at a caller-specified filename.
 //!
 //! The caller is supposed to do any necessary locking.
@@ -37,8 +37,8 @@ impl Target<'_> {
     ///
     /// Returns `None` if the file doesn't exist.
     pub(crate) fn load<D: DeserializeOwned>(&self) -> Result<Option<D>, ErrorSource> {
-        let string = match self.dir.read_to_string(self.rel_fname) {
-            Ok(string) => string,
+        let record = match self.dir.read(self.rel_fname) {
+            Ok(record) => record,
             Err(fs_mistrust::Error::NotFound(_)) => {
                 trace!("loading {self} (not found)");
                 return Ok(None);
@@ -49,7 +49,7 @@ impl Target<'_> {
             }
         };
 
-        let r = serde_json::from_str(&string)?;
+        let r = crate::format::decode(&record)?;
         trace!("loaded {self}");
 
         Ok(Some(r))
@@ -71,9 +71,16 @@ impl Target<'_> {
     /// See [`fs_mistrust::CheckedDir::write_and_replace`]
     /// for more details about the semantics.
     pub(crate) fn store<S: Serialize>(&self, val: &S) -> Result<(), ErrorSource> {
-        trace!("storing {self}");
         let output = serde_json::to_string_pretty(val)?;
+        self.store_encoded(output.as_bytes())
+    }
 
+    /// Store `output`, which is already serialized, to the file
+    /// specified by `self`
+    ///
+    /// The same caveats apply as for [`store`](Target::store).
+    pub(crate) fn store_encoded(&self, output: &[u8]) -> Result<(), ErrorSource> {
+        trace!("storing {self}");
         self.dir.write_and_replace(self.rel_fname, output)?;
 
         Ok(())
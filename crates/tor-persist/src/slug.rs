@@ -18,6 +18,14 @@
 //! they should be separated using `/`, `+`, or `.`
 //! ([`SLUG_SEPARATOR_CHARS`]).
 //! Slugs should not be concatenated without separators (for security reasons).
+//! [`SlugPath`] does this for you.
+//!
+//! Arbitrary strings, such as names chosen by a user,
+//! can be encoded as slugs (and decoded again)
+//! with [`Slug::encode_lossless`] and [`SlugRef::decode_lossless`].
+//!
+//! Names that aren't slugs can collide on case-insensitive filesystems;
+//! see [`collision_key`] and [`find_collisions`].
 //!
 //! On Windows only, the following slugs are forbidden,
 //! because of [absurd Windows filename behaviours](https://learn.microsoft.com/en-us/windows/win32/fileio/naming-a-file):
@@ -27,6 +35,9 @@
 //!
 //! [^1]: <https://learn.microsoft.com/en-us/windows/win32/fileio/naming-a-file#naming-conventions>
 
+mod collision;
+mod encode;
+mod path;
 pub mod timestamp;
 
 use std::borrow::Borrow;
@@ -44,6 +55,12 @@ use thiserror::Error;
 #[cfg_attr(docsrs, doc(cfg(target_family = "windows")))]
 pub use os::ForbiddenOnWindows;
 
+pub use collision::{collision_key, find_collisions};
+pub use encode::BadSlugEncoding;
+pub use path::{
+    SlugPath, SlugPathTooLong, SlugSeparator, MAX_SLUG_PATH_COMPONENT_LEN, MAX_SLUG_PATH_LEN,
+};
+
 /// An owned slug, checked for syntax
 ///
 /// The syntax check can be relied on for safety/soundness.
@@ -140,12 +157,32 @@ impl SlugRef {
         })
     }
 
+    /// Make a SlugRef out of a `str`, panicking if it has the wrong syntax
+    ///
+    /// This is a `const fn`, so in a const context, bad syntax is a compile-time error.
+    /// Usually, use the [`slug!`](crate::slug!) macro, which forces that.
+    ///
+    /// This is stricter than [`SlugRef::new`]:
+    /// the slugs which are forbidden on Windows are rejected on every platform,
+    /// so that a slug that compiles anywhere is valid everywhere.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `s` is not a valid slug.
+    pub const fn new_const(s: &str) -> &SlugRef {
+        assert!(check_syntax_const(s), "invalid slug");
+        unsafe {
+            // SAFETY: we have checked the syntax, at least as strictly as check_syntax
+            SlugRef::new_unchecked(s)
+        }
+    }
+
     /// Make a SlugRef out of a `str`, without checking the syntax
     ///
     /// # Safety
     ///
     /// It's the caller's responsibility to check the syntax of the input string.
-    pub unsafe fn new_unchecked<'s>(s: &'s str) -> &'s SlugRef {
+    pub const unsafe fn new_unchecked<'s>(s: &'s str) -> &'s SlugRef {
         unsafe {
             // SAFETY
             // SlugRef is repr(transparent).  So the alignment and memory layout
@@ -280,6 +317,75 @@ pub fn check_syntax(s: &str) -> Result<(), BadSlug> {
     Ok(())
 }
 
+/// Check the string `s` to see if it would be valid as a slug on every platform
+///
+/// This is the `const` version of [`check_syntax`], used by [`SlugRef::new_const`].
+//
+// SAFETY
+// This function checks the syntax, and is relied on by unsafe code.
+// It must be at least as strict as check_syntax.
+const fn check_syntax_const(s: &str) -> bool {
+    let b = s.as_bytes();
+    if b.is_empty() || b[0] == b'-' {
+        return false;
+    }
+    let mut i = 0;
+    while i < b.len() {
+        let c = b[i];
+        if !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_' || c == b'-') {
+            return false;
+        }
+        i += 1;
+    }
+    let mut i = 0;
+    while i < WINDOWS_FORBIDDEN.len() {
+        if const_str_eq(s, WINDOWS_FORBIDDEN[i]) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Return true if `a` and `b` are equal (for use in const contexts)
+const fn const_str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Make a `&'static` [`SlugRef`] from a string literal, checking its syntax at compile time
+///
+/// The result can be used in a `const`.
+/// See [`SlugRef::new_const`] for details.
+///
+/// ```
+/// use tor_persist::slug::SlugRef;
+///
+/// const GUARDS: &SlugRef = tor_persist::slug!("guards");
+/// assert_eq!(GUARDS.as_str(), "guards");
+/// ```
+///
+/// Invalid slugs don't compile:
+///
+/// ```compile_fail
+/// let _ = tor_persist::slug!("Guards");
+/// ```
+#[macro_export]
+macro_rules! slug { { $s:literal } => { {
+    const SLUG: &$crate::slug::SlugRef = $crate::slug::SlugRef::new_const($s);
+    SLUG
+} } }
+
 impl Display for BadSlug {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -303,6 +409,15 @@ impl Display for BadSlug {
     }
 }
 
+/// The slugs that are forbidden on Windows
+///
+/// Windows thinks "C:\\Program Files\lpt0.json" is a printer.
+const WINDOWS_FORBIDDEN: &[&str] = &[
+    "con", "prn", "aux", "nul", //
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "com0", //
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9", "lpt0",
+];
+
 /// Forbidden slug support for Windows
 #[cfg(target_family = "windows")]
 mod os {
@@ -315,16 +430,9 @@ mod os {
     // Double reference so that BadSlug has to contain only one word, not two
     pub type ForbiddenOnWindows = &'static &'static str;
 
-    /// The forbidden slugs - windows thinks "C:\\Program Files\lpt0.json" is a printer.
-    const FORBIDDEN: &[&str] = &[
-        "con", "prn", "aux", "nul", //
-        "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "com0", //
-        "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9", "lpt0",
-    ];
-
     /// Check whether this slug is forbidden here
     pub(super) fn check_forbidden(s: &str) -> Result<(), BadSlug> {
-        for bad in FORBIDDEN {
+        for bad in WINDOWS_FORBIDDEN {
             if s == *bad {
                 return Err(BadSlug::ForbiddenOnWindows(bad));
             }
@@ -453,4 +561,22 @@ mod test {
             "empty identifier (empty slug) not allowed"
         );
     }
+    #[test]
+    fn const_slugs() {
+        const GUARDS: &SlugRef = crate::slug!("guards");
+        assert_eq!(GUARDS.as_str(), "guards");
+        assert_eq!(crate::slug!("a-b_0").as_str(), "a-b_0");
+
+        // check_syntax_const must be at least as strict as check_syntax.
+        for s in [
+            "", "-", "-a", "A", "a.b", "a/b", "a+b", "a b", "\u{e9}", "con", "lpt0", "nul",
+        ] {
+            assert!(!check_syntax_const(s), "{s:?}");
+            assert!(std::panic::catch_unwind(|| SlugRef::new_const(s)).is_err());
+        }
+        for s in ["a", "a-", "_", "0", "conn", "co"] {
+            assert!(check_syntax_const(s), "{s:?}");
+            assert_eq!(SlugRef::new_const(s), SlugRef::new(s).unwrap());
+        }
+    }
 }
@@ -0,0 +1,108 @@
+//! Detecting names that collide on case- or normalization-insensitive filesystems
+//!
+//! Distinct slugs never collide, since they are all lowercase ASCII.
+//! But a directory can still contain entries whose names aren't slugs:
+//! for example, a directory copied from a case-sensitive filesystem
+//! might contain both `foo` and `Foo`,
+//! which are the same file on macOS and Windows,
+//! or `é` in both its composed and decomposed forms,
+//! which are the same file on macOS.
+//!
+//! [`collision_key`] returns the same key for any two names that might collide,
+//! and [`find_collisions`] uses it to look for colliding entries in a directory.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use unicode_normalization::UnicodeNormalization as _;
+
+/// Return a key such that two names that might refer to the same file have the same key
+///
+/// We lowercase `name`, and then put it in Unicode Normalization Form C.
+/// So names which differ only in case, or in Unicode normalization, have the same key.
+///
+/// For a valid slug, the key is the slug itself.
+pub fn collision_key(name: &str) -> String {
+    name.to_lowercase().nfc().collect()
+}
+
+/// Return every set of entries in `dir` whose names have the same [`collision_key`]
+///
+/// Each set is sorted, and has at least two entries.
+/// Names that aren't valid UTF-8 are compared lossily.
+pub fn find_collisions(dir: impl AsRef<Path>) -> io::Result<Vec<Vec<OsString>>> {
+    let mut by_key = BTreeMap::<String, Vec<OsString>>::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        by_key
+            .entry(collision_key(&name.to_string_lossy()))
+            .or_default()
+            .push(name);
+    }
+    Ok(by_key
+        .into_values()
+        .filter(|names| names.len() > 1)
+        .map(|mut names| {
+            names.sort();
+            names
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(collision_key("foo-bar_1"), "foo-bar_1");
+        assert_eq!(collision_key("Foo"), collision_key("fOO"));
+        assert_eq!(collision_key("caf\u{e9}"), collision_key("CAFE\u{301}"));
+        assert_ne!(collision_key("cafe"), collision_key("caf\u{e9}"));
+    }
+
+    #[test]
+    #[cfg(not(miri))] // filesystem access
+    fn scan() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let touch = |name: &str| fs::write(dir.path().join(name), "").unwrap();
+        touch("a");
+        touch("b");
+        assert!(find_collisions(dir.path()).unwrap().is_empty());
+
+        touch("c\u{e9}.json");
+        // On a case-insensitive filesystem, this overwrites `b`,
+        // and there is nothing to find.
+        if fs::write(dir.path().join("B"), "x").is_ok()
+            && fs::read_to_string(dir.path().join("b")).unwrap().is_empty()
+        {
+            touch("CE\u{301}.json");
+            assert_eq!(
+                find_collisions(dir.path()).unwrap(),
+                vec![
+                    vec![OsString::from("B"), OsString::from("b")],
+                    vec![
+                        OsString::from("CE\u{301}.json"),
+                        OsString::from("c\u{e9}.json")
+                    ],
+                ]
+            );
+        }
+    }
+}
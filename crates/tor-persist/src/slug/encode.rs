@@ -0,0 +1,166 @@
+//! Reversibly encoding arbitrary strings as slugs
+//!
+//! [`Slug::encode_lossless`] can turn any string into a slug,
+//! and [`SlugRef::decode_lossless`] turns it back.
+//!
+//! ### Encoding
+//!
+//! `_` is the escape character:
+//!
+//!  * Lowercase ASCII alphanumerics, and `-` other than at the start, are unchanged.
+//!  * `_` becomes `__`.
+//!  * Every other character becomes `_`, its Unicode scalar value in lowercase hex, and `_`.
+//!    For example, `A` becomes `_41_`, and `é` becomes `_e9_`.
+//!  * If the result would be empty, or would be one of the slugs forbidden on Windows,
+//!    it is prefixed with `_-`.
+//!    (We do this on every platform, so that the encoding is portable.)
+//!
+//! So `Alice's service` becomes `_41_lice_27_s_20_service`.
+
+use super::*;
+
+/// Prefix for encoded slugs which would otherwise be empty, or forbidden on Windows
+const MARKER: &str = "_-";
+
+/// Error for a slug which is not the output of [`Slug::encode_lossless`]
+#[derive(Error, Debug, Clone, Eq, PartialEq, Hash)]
+#[error("slug is not a valid encoding of a string")]
+#[non_exhaustive]
+pub struct BadSlugEncoding;
+
+impl Slug {
+    /// Encode an arbitrary string `s` as a slug, reversibly
+    ///
+    /// Different strings always produce different slugs,
+    /// and the original can be recovered with [`decode_lossless`](SlugRef::decode_lossless).
+    /// Strings which are already valid slugs, and don't contain `_`, are mostly unchanged.
+    ///
+    /// See the [module documentation](self) for the encoding.
+    ///
+    /// ```
+    /// use tor_persist::slug::Slug;
+    ///
+    /// let slug = Slug::encode_lossless("My Onion Service!");
+    /// assert_eq!(slug.as_str(), "_4d_y_20_onion_20_service_21_");
+    /// assert_eq!(slug.decode_lossless().unwrap(), "My Onion Service!");
+    /// ```
+    pub fn encode_lossless(s: &str) -> Slug {
+        let mut out = String::with_capacity(s.len());
+        for (i, c) in s.chars().enumerate() {
+            if c.is_ascii_lowercase() || c.is_ascii_digit() || (c == '-' && i != 0) {
+                out.push(c);
+            } else if c == '_' {
+                out.push_str("__");
+            } else {
+                out.push_str(&format!("_{:x}_", u32::from(c)));
+            }
+        }
+        if out.is_empty() || WINDOWS_FORBIDDEN.contains(&out.as_str()) {
+            out.insert_str(0, MARKER);
+        }
+        Slug::new(out).expect("encoded slug has bad syntax")
+    }
+}
+
+impl SlugRef {
+    /// Decode a slug made by [`Slug::encode_lossless`], returning the original string
+    ///
+    /// Returns an error if this slug could not have been made by `encode_lossless`.
+    pub fn decode_lossless(&self) -> Result<String, BadSlugEncoding> {
+        let s = self.as_str();
+        let (marked, s) = match s.strip_prefix(MARKER) {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        if marked != (s.is_empty() || WINDOWS_FORBIDDEN.contains(&s)) {
+            return Err(BadSlugEncoding);
+        }
+
+        let mut out = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '-' && out.is_empty() {
+                return Err(BadSlugEncoding);
+            } else if c != '_' {
+                out.push(c);
+                continue;
+            }
+            let escaped = chars.as_str();
+            if let Some(rest) = escaped.strip_prefix('_') {
+                out.push('_');
+                chars = rest.chars();
+                continue;
+            }
+            let (hex, rest) = escaped.split_once('_').ok_or(BadSlugEncoding)?;
+            let c = u32::from_str_radix(hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or(BadSlugEncoding)?;
+            // Insist on the canonical form, so that each string has only one encoding.
+            if (hex.len() > 1 && hex.starts_with('0'))
+                || c.is_ascii_lowercase()
+                || c.is_ascii_digit()
+                || c == '_'
+                || (c == '-' && !out.is_empty())
+            {
+                return Err(BadSlugEncoding);
+            }
+            out.push(c);
+            chars = rest.chars();
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for (s, encoded) in [
+            ("", "_-"),
+            ("simple", "simple"),
+            ("a-b", "a-b"),
+            ("-a", "_2d_a"),
+            ("a_b", "a__b"),
+            ("_", "__"),
+            ("Alice's service", "_41_lice_27_s_20_service"),
+            ("caf\u{e9}", "caf_e9_"),
+            ("\u{1f9c5}", "_1f9c5_"),
+            ("\0", "_0_"),
+            ("a/../b", "a_2f__2e__2e__2f_b"),
+            ("con", "_-con"),
+            ("lpt1", "_-lpt1"),
+            ("Con", "_43_on"),
+        ] {
+            let slug = Slug::encode_lossless(s);
+            assert_eq!(slug.as_str(), encoded, "{s:?}");
+            assert_eq!(slug.decode_lossless().unwrap(), s, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn bad() {
+        for s in [
+            "_", "_x", "_41", "_041_", "_61_", "_5f_", "a_2d_", "_-a", "con", "_110000_", "_d800_",
+        ] {
+            let Ok(slug) = SlugRef::new(s) else { continue };
+            assert_eq!(slug.decode_lossless(), Err(BadSlugEncoding), "{s:?}");
+        }
+    }
+}
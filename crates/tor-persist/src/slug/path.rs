@@ -0,0 +1,256 @@
+//! Composing slugs into multi-component names, such as relative paths
+//!
+//! A [`SlugPath`] is one or more slugs, joined by [`SlugSeparator`]s.
+//! Since the separators are never valid slug characters,
+//! the components of a `SlugPath` can always be recovered,
+//! and a `SlugPath` can never escape the directory it is used in.
+//!
+//! ```
+//! use tor_persist::slug::{SlugPath, SlugRef, SlugSeparator};
+//!
+//! let id = SlugRef::new("allium-cepa").unwrap();
+//! let lock = SlugPath::new(tor_persist::slug!("hss"))
+//!     .join(SlugSeparator::Slash, id)
+//!     .join(SlugSeparator::Dot, tor_persist::slug!("lock"));
+//! assert_eq!(lock.as_str(), "hss/allium-cepa.lock");
+//! ```
+
+use super::*;
+
+use std::io;
+use std::path::PathBuf;
+
+/// The maximum length, in bytes, of one `/`-separated component of a [`SlugPath`]
+///
+/// This is `NAME_MAX` on most Unix filesystems, and the limit on NTFS.
+pub const MAX_SLUG_PATH_COMPONENT_LEN: usize = 255;
+
+/// The maximum total length, in bytes, of a [`SlugPath`]
+///
+/// This is `PATH_MAX` on macOS, which is the smallest of the platforms we care about
+/// (other than Windows without long path support, where even this may be too long,
+/// since the directory the `SlugPath` is relative to also counts).
+pub const MAX_SLUG_PATH_LEN: usize = 1024;
+
+/// A separator between the slugs in a [`SlugPath`]
+///
+/// These are the characters in [`SLUG_SEPARATOR_CHARS`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum SlugSeparator {
+    /// `/`, a directory separator
+    Slash,
+    /// `+`
+    Plus,
+    /// `.`, typically before an extension
+    Dot,
+}
+
+/// One or more slugs, joined by separators
+///
+/// Build one with [`SlugPath::new`] and [`join`](SlugPath::join),
+/// or check an existing string with [`SlugPath::parse`].
+/// See the [module documentation](self).
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)] //
+#[derive(derive_more::Display)]
+pub struct SlugPath(String);
+
+/// Error for a [`SlugPath`] that is too long to use as a path
+#[derive(Error, Debug, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum SlugPathTooLong {
+    /// One of the `/`-separated components is too long
+    #[error("path component is {length} bytes long, but the limit is {max}")]
+    Component {
+        /// The length of the component
+        length: usize,
+        /// The limit
+        max: usize,
+    },
+    /// The whole path is too long
+    #[error("path is {length} bytes long, but the limit is {max}")]
+    Total {
+        /// The length of the path
+        length: usize,
+        /// The limit
+        max: usize,
+    },
+}
+
+impl SlugSeparator {
+    /// Return this separator as a character
+    pub fn as_char(self) -> char {
+        match self {
+            SlugSeparator::Slash => '/',
+            SlugSeparator::Plus => '+',
+            SlugSeparator::Dot => '.',
+        }
+    }
+}
+
+impl SlugPath {
+    /// Make a `SlugPath` containing just `first`
+    pub fn new(first: &SlugRef) -> Self {
+        SlugPath(first.as_str().to_owned())
+    }
+
+    /// Check that `s` consists of slugs separated by [`SLUG_SEPARATOR_CHARS`]
+    ///
+    /// Every component must be a valid slug;
+    /// in particular, `s` may not start or end with a separator, or contain two in a row.
+    pub fn parse(s: &str) -> Result<Self, BadSlug> {
+        for component in s.split(|c| SLUG_SEPARATOR_CHARS.contains(c)) {
+            check_syntax(component)?;
+        }
+        Ok(SlugPath(s.to_owned()))
+    }
+
+    /// Append `sep` and then `slug`
+    pub fn push(&mut self, sep: SlugSeparator, slug: &SlugRef) {
+        self.0.push(sep.as_char());
+        self.0.push_str(slug.as_str());
+    }
+
+    /// Append `sep` and then `slug`, returning the extended `SlugPath`
+    #[must_use]
+    pub fn join(mut self, sep: SlugSeparator, slug: &SlugRef) -> Self {
+        self.push(sep, slug);
+        self
+    }
+
+    /// Return this `SlugPath` as a string
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Check that this `SlugPath` is short enough to use as a relative path
+    ///
+    /// See [`MAX_SLUG_PATH_COMPONENT_LEN`] and [`MAX_SLUG_PATH_LEN`].
+    pub fn check_length(&self) -> Result<(), SlugPathTooLong> {
+        for component in self.0.split('/') {
+            if component.len() > MAX_SLUG_PATH_COMPONENT_LEN {
+                return Err(SlugPathTooLong::Component {
+                    length: component.len(),
+                    max: MAX_SLUG_PATH_COMPONENT_LEN,
+                });
+            }
+        }
+        if self.0.len() > MAX_SLUG_PATH_LEN {
+            return Err(SlugPathTooLong::Total {
+                length: self.0.len(),
+                max: MAX_SLUG_PATH_LEN,
+            });
+        }
+        Ok(())
+    }
+
+    /// Convert this `SlugPath` to a relative path, if it is not too long
+    ///
+    /// Each `/` becomes a path separator.
+    pub fn to_path_buf(&self) -> Result<PathBuf, SlugPathTooLong> {
+        self.check_length()?;
+        Ok(self.0.split('/').collect())
+    }
+}
+
+impl From<&SlugRef> for SlugPath {
+    fn from(slug: &SlugRef) -> Self {
+        SlugPath::new(slug)
+    }
+}
+
+impl From<SlugPath> for String {
+    fn from(path: SlugPath) -> String {
+        path.0
+    }
+}
+
+impl AsRef<str> for SlugPath {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<SlugPathTooLong> for io::Error {
+    fn from(e: SlugPathTooLong) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    fn slug(s: &str) -> &SlugRef {
+        SlugRef::new(s).unwrap()
+    }
+
+    #[test]
+    fn build() {
+        let mut p = SlugPath::new(slug("a"));
+        p.push(SlugSeparator::Slash, slug("b_c"));
+        let p = p
+            .join(SlugSeparator::Plus, slug("d"))
+            .join(SlugSeparator::Dot, slug("json"));
+        assert_eq!(p.as_str(), "a/b_c+d.json");
+        assert_eq!(p.to_string(), "a/b_c+d.json");
+        assert_eq!(p.to_path_buf().unwrap(), Path::new("a").join("b_c+d.json"));
+        assert_eq!(SlugPath::parse("a/b_c+d.json").unwrap(), p);
+    }
+
+    #[test]
+    fn parse_bad() {
+        for (s, e) in [
+            ("", BadSlug::EmptySlugNotAllowed),
+            ("/a", BadSlug::EmptySlugNotAllowed),
+            ("a/", BadSlug::EmptySlugNotAllowed),
+            ("a//b", BadSlug::EmptySlugNotAllowed),
+            ("a/-b", BadSlug::BadFirstCharacter('-')),
+            ("a/B", BadSlug::BadCharacter('B')),
+            ("a\\b", BadSlug::BadCharacter('\\')),
+        ] {
+            assert_eq!(SlugPath::parse(s).unwrap_err(), e, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        let long = "x".repeat(MAX_SLUG_PATH_COMPONENT_LEN);
+        let p = SlugPath::new(slug(&long));
+        p.check_length().unwrap();
+        let p = p.join(SlugSeparator::Dot, slug("lock"));
+        assert_eq!(
+            p.to_path_buf().unwrap_err(),
+            SlugPathTooLong::Component {
+                length: MAX_SLUG_PATH_COMPONENT_LEN + 5,
+                max: MAX_SLUG_PATH_COMPONENT_LEN,
+            }
+        );
+
+        let mut p = SlugPath::new(slug(&long));
+        for _ in 0..3 {
+            p.push(SlugSeparator::Slash, slug(&long));
+        }
+        p.check_length().unwrap();
+        p.push(SlugSeparator::Slash, slug("more"));
+        assert!(matches!(
+            p.check_length().unwrap_err(),
+            SlugPathTooLong::Total { .. }
+        ));
+        let e: io::Error = p.to_path_buf().unwrap_err().into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
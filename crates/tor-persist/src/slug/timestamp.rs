@@ -1,10 +1,16 @@
 //! A module exporting timestamps types that can be encoded as [`Slug`]s.
+//!
+//! [`Iso8601TimeSlug`] has a resolution of one second.
+//! For names that must sort in the order they were made,
+//! such as the names of successive files in a directory,
+//! use [`PreciseTimeSlug`], generated by a [`PreciseTimeSlugGenerator`].
 
 use crate::slug::{BadSlug, Slug};
 
 use std::fmt;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use derive_more::{From, Into};
 use thiserror::Error;
@@ -69,6 +75,10 @@ pub enum BadIso8601TimeSlug {
     /// The timestamp is not a valid slug.
     #[error("Invalid slug")]
     Slug(#[from] BadSlug),
+
+    /// The sequence number of a [`PreciseTimeSlug`] is missing or invalid.
+    #[error("Invalid sequence number")]
+    Sequence,
 }
 
 impl fmt::Display for Iso8601TimeSlug {
@@ -81,6 +91,158 @@ impl fmt::Display for Iso8601TimeSlug {
     }
 }
 
+/// A high-resolution UTC timestamp, with a sequence number, that can be used as a `Slug`.
+///
+/// The encoding is fixed-width:
+/// `[year][month][day][hour][minute][second]_[nanosecond]_[sequence]`,
+/// where the nanosecond field has 9 digits, and the sequence number has 4.
+/// For example, `20241023130545_012345678_0000`.
+///
+/// Since the encoding is fixed-width,
+/// the encoded slugs sort in the same order as the `PreciseTimeSlug`s themselves:
+/// by time, and then by sequence number.
+/// (This holds for years 0 to 9999.)
+///
+/// Use a [`PreciseTimeSlugGenerator`] to make `PreciseTimeSlug`s that
+/// sort in the order they were generated,
+/// even if the clock is coarse or steps backwards.
+///
+/// # Example
+///
+/// ```
+/// # use tor_persist::slug::timestamp::{PreciseTimeSlug, BadIso8601TimeSlug};
+/// # fn demo() -> Result<(), BadIso8601TimeSlug> {
+///
+/// let slug = "20241023130545_012345678_0001".parse::<PreciseTimeSlug>()?;
+/// assert_eq!(slug.sequence(), 1);
+/// assert_eq!("20241023130545_012345678_0001", slug.to_string());
+///
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)] //
+pub struct PreciseTimeSlug {
+    /// The time.
+    time: SystemTime,
+    /// The sequence number, for ordering slugs with the same `time`.
+    ///
+    /// At most [`MAX_SEQUENCE`].
+    sequence: u16,
+}
+
+/// The format of the time in a [`PreciseTimeSlug`].
+const PRECISE_FMT: &[FormatItem] =
+    format_description!("[year][month][day][hour][minute][second]_[subsecond digits:9]");
+
+/// The largest sequence number of a [`PreciseTimeSlug`]: the largest with 4 digits.
+const MAX_SEQUENCE: u16 = 9999;
+
+impl PreciseTimeSlug {
+    /// Make a `PreciseTimeSlug` for `time`, with sequence number 0
+    ///
+    /// Usually, use a [`PreciseTimeSlugGenerator`] instead.
+    pub fn new(time: SystemTime) -> Self {
+        PreciseTimeSlug { time, sequence: 0 }
+    }
+
+    /// Return the time of this `PreciseTimeSlug`
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Return the sequence number of this `PreciseTimeSlug`
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Return the next `PreciseTimeSlug` after this one
+    ///
+    /// This has the same time, and the next sequence number;
+    /// or, if the sequence number is at its maximum,
+    /// the next nanosecond, and sequence number 0.
+    fn successor(&self) -> Self {
+        if self.sequence < MAX_SEQUENCE {
+            PreciseTimeSlug {
+                time: self.time,
+                sequence: self.sequence + 1,
+            }
+        } else {
+            PreciseTimeSlug::new(self.time + Duration::from_nanos(1))
+        }
+    }
+}
+
+impl From<PreciseTimeSlug> for SystemTime {
+    fn from(slug: PreciseTimeSlug) -> SystemTime {
+        slug.time
+    }
+}
+
+impl FromStr for PreciseTimeSlug {
+    type Err = BadIso8601TimeSlug;
+
+    fn from_str(s: &str) -> Result<PreciseTimeSlug, Self::Err> {
+        let (time, sequence) = s.rsplit_once('_').ok_or(BadIso8601TimeSlug::Sequence)?;
+        let sequence = (sequence.len() == 4 && sequence.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| sequence.parse().ok())
+            .flatten()
+            .ok_or(BadIso8601TimeSlug::Sequence)?;
+        let time = PrimitiveDateTime::parse(time, &PRECISE_FMT)?;
+
+        Ok(PreciseTimeSlug {
+            time: time.assume_utc().into(),
+            sequence,
+        })
+    }
+}
+
+impl TryInto<Slug> for PreciseTimeSlug {
+    type Error = Bug;
+
+    fn try_into(self) -> Result<Slug, Self::Error> {
+        Slug::new(self.to_string()).map_err(into_internal!("PreciseTimeSlug is not a valid slug?!"))
+    }
+}
+
+impl fmt::Display for PreciseTimeSlug {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ts = OffsetDateTime::from(self.time)
+            .format(PRECISE_FMT)
+            .map_err(|_| fmt::Error)?;
+
+        write!(f, "{ts}_{:04}", self.sequence)
+    }
+}
+
+/// A source of [`PreciseTimeSlug`]s that sort in the order they were generated
+///
+/// Each slug is later than the previous one:
+/// if the clock hasn't moved on since the last slug (or has gone backwards),
+/// we reuse the last slug's time, with the next sequence number.
+#[derive(Debug, Default)]
+pub struct PreciseTimeSlugGenerator {
+    /// The last slug that we generated, if any.
+    last: Mutex<Option<PreciseTimeSlug>>,
+}
+
+impl PreciseTimeSlugGenerator {
+    /// Make a new `PreciseTimeSlugGenerator`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate a `PreciseTimeSlug` for `now`, later than any generated before
+    pub fn generate(&self, now: SystemTime) -> PreciseTimeSlug {
+        let mut last = self.last.lock().expect("lock poisoned");
+        let slug = match *last {
+            Some(prev) if prev.time >= now => prev.successor(),
+            _ => PreciseTimeSlug::new(now),
+        };
+        *last = Some(slug);
+        slug
+    }
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -129,4 +291,64 @@ mod test {
             parsed_timestamp.try_into_slug().unwrap().to_string(),
         );
     }
+    #[test]
+    fn precise_timestamp() {
+        const VALID: &str = "20241023130545_012345678_0042";
+
+        let t = VALID.parse::<PreciseTimeSlug>().unwrap();
+        assert_eq!(t.sequence(), 42);
+        assert_eq!(
+            t.time(),
+            parse_rfc3339("2024-10-23T13:05:45.012345678Z").unwrap()
+        );
+        assert_eq!(t.to_string(), VALID);
+        assert_eq!(t.try_into_slug().unwrap().to_string(), VALID);
+
+        for bad in [
+            "20241023130545_012345678",
+            "20241023130545_012345678_42",
+            "20241023130545_012345678_+042",
+            "20241023130545_01234567_0042",
+            "20241023130545_0042",
+            "20241023130545",
+            "",
+        ] {
+            assert!(bad.parse::<PreciseTimeSlug>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn precise_timestamp_generator() {
+        let gen = PreciseTimeSlugGenerator::new();
+        let t0 = parse_rfc3339("2024-10-23T13:05:45Z").unwrap();
+        let ns = Duration::from_nanos(1);
+
+        let mut slugs = vec![
+            gen.generate(t0),
+            // The clock didn't move...
+            gen.generate(t0),
+            // ...moved on...
+            gen.generate(t0 + ns * 10),
+            // ...and stepped backwards.
+            gen.generate(t0),
+            gen.generate(t0 + ns * 11),
+        ];
+        // Sequence numbers run out.
+        slugs.extend((0..=MAX_SEQUENCE).map(|_| gen.generate(t0)));
+
+        let strings = slugs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(strings[0], "20241023130545_000000000_0000");
+        assert_eq!(strings[1], "20241023130545_000000000_0001");
+        assert_eq!(strings[2], "20241023130545_000000010_0000");
+        assert_eq!(strings[3], "20241023130545_000000010_0001");
+        assert_eq!(strings[4], "20241023130545_000000011_0000");
+        assert_eq!(strings.last().unwrap(), "20241023130545_000000012_0000");
+
+        // The slugs, and their encodings, are in the order they were generated.
+        assert!(slugs.windows(2).all(|w| w[0] < w[1]));
+        assert!(strings.windows(2).all(|w| w[0] < w[1]));
+        for (slug, s) in slugs.iter().zip(&strings) {
+            assert_eq!(&s.parse::<PreciseTimeSlug>().unwrap(), slug);
+        }
+    }
 }
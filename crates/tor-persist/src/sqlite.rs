@@ -0,0 +1,453 @@
+//! SQLite implementation of StateMgr.
+
+#![forbid(unsafe_code)] // if you remove this, enable (or write) miri tests (git grep miri)
+
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, LockStatus, Result, StateMgr, Transaction};
+use fs_mistrust::CheckedDir;
+use rusqlite::OptionalExtension as _;
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The name of the database file, within the `state` directory.
+const DB_FNAME: &str = "state.sqlite3";
+
+/// The name of the lock file, within the `state` directory.
+///
+/// This is the same lock file that [`FsStateMgr`](crate::FsStateMgr) uses,
+/// so that the two kinds of manager exclude one another.
+const LOCK_FNAME: &str = "state.lock";
+
+/// The schema for our database.
+///
+/// Every value is stored as a JSON document, keyed by its (unmodified) key.
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS State (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+  );
+";
+
+/// Implementation of StateMgr that stores all state in a single SQLite
+/// database.
+///
+/// Unlike [`FsStateMgr`](crate::FsStateMgr), which keeps one JSON file per
+/// key, this manager keeps every value in one file, and can update several
+/// keys at once atomically with [`SqliteStateMgr::store_batch`].
+///
+/// # Locking
+///
+/// This manager uses the same lock file as `FsStateMgr` to determine whether
+/// it's allowed to write to the database.  Only one process should write at a
+/// time, though any number may read.
+///
+/// By default, every `SqliteStateMgr` starts out unlocked, and only able to
+/// read.  Use [`StateMgr::try_lock()`] to lock it.
+///
+/// # Limitations
+///
+/// This manager only accepts objects that can be serialized as JSON
+/// documents.
+///
+/// NEVER use user-controlled or remote-controlled data for your keys.
+#[cfg_attr(docsrs, doc(cfg(all(feature = "sqlite", not(target_arch = "wasm32")))))]
+#[derive(Clone, Debug)]
+pub struct SqliteStateMgr {
+    /// Inner reference-counted object.
+    inner: Arc<SqliteStateMgrInner>,
+}
+
+/// Inner reference-counted object, used by `SqliteStateMgr`.
+#[derive(Debug)]
+struct SqliteStateMgrInner {
+    /// Directory in which we store the database and the lock file.
+    statepath: CheckedDir,
+    /// Connection to the database.
+    conn: Mutex<rusqlite::Connection>,
+    /// Lockfile to achieve exclusive write access to the database.
+    lockfile: Mutex<fslock::LockFile>,
+}
+
+/// A set of changes to make to a [`SqliteStateMgr`] all at once.
+///
+/// See [`SqliteStateMgr::store_batch`].
+pub struct SqliteBatch<'a> {
+    /// The manager whose state we are changing.
+    mgr: &'a SqliteStateMgr,
+    /// The transaction in which we are making the changes.
+    tx: rusqlite::Transaction<'a>,
+}
+
+impl SqliteBatch<'_> {
+    /// Save `val` with key `key`, replacing any previous value.
+    pub fn store<S>(&mut self, key: &str, val: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        let json = serde_json::to_string_pretty(val)
+            .map_err(|e| Error::new(e, Action::Storing, self.mgr.err_resource_db()))?;
+        self.tx
+            .execute(
+                "INSERT OR REPLACE INTO State (key, value) VALUES (?, ?)",
+                (key, &json),
+            )
+            .map_err(|e| Error::new(e, Action::Storing, self.mgr.err_resource_db()))?;
+        Ok(())
+    }
+
+    /// Remove the value with key `key`, if there is one.
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        self.tx
+            .execute("DELETE FROM State WHERE key = ?", (key,))
+            .map_err(|e| Error::new(e, Action::Deleting, self.mgr.err_resource_db()))?;
+        Ok(())
+    }
+}
+
+impl SqliteStateMgr {
+    /// Construct a new `SqliteStateMgr` to store data in `path`.
+    ///
+    /// The database is kept in a `state` subdirectory of `path`, which this
+    /// function will try to create if it does not already exist.
+    ///
+    /// All files must be "private" according to the rules specified in `mistrust`.
+    pub fn from_path_and_mistrust<P: AsRef<Path>>(
+        path: P,
+        mistrust: &fs_mistrust::Mistrust,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let dir = path.join("state");
+
+        let statepath = mistrust
+            .verifier()
+            .check_content()
+            .make_secure_dir(&dir)
+            .map_err(|e| {
+                Error::new(
+                    e,
+                    Action::Initializing,
+                    Resource::Directory { dir: dir.clone() },
+                )
+            })?;
+        let file_resource = |file: &str| Resource::File {
+            container: dir.clone(),
+            file: file.into(),
+        };
+
+        let lockpath = statepath
+            .join(LOCK_FNAME)
+            .map_err(|e| Error::new(e, Action::Initializing, file_resource(LOCK_FNAME)))?;
+        let lockfile = fslock::LockFile::open(&lockpath)
+            .map_err(|e| Error::new(e, Action::Initializing, file_resource(LOCK_FNAME)))?;
+
+        // Check permissions on the database; don't require it to exist.
+        let dbpath = statepath
+            .join(DB_FNAME)
+            .map_err(|e| Error::new(e, Action::Initializing, file_resource(DB_FNAME)))?;
+        match mistrust.verifier().require_file().check(&dbpath) {
+            Ok(()) | Err(fs_mistrust::Error::NotFound(_)) => {}
+            Err(e) => return Err(Error::new(e, Action::Initializing, file_resource(DB_FNAME))),
+        }
+
+        let conn = rusqlite::Connection::open(&dbpath)
+            .and_then(|conn| conn.execute_batch(SCHEMA).map(|()| conn))
+            .map_err(|e| Error::new(e, Action::Initializing, file_resource(DB_FNAME)))?;
+
+        Ok(SqliteStateMgr {
+            inner: Arc::new(SqliteStateMgrInner {
+                statepath,
+                conn: Mutex::new(conn),
+                lockfile: Mutex::new(lockfile),
+            }),
+        })
+    }
+
+    /// Like from_path_and_mistrust, but do not verify permissions.
+    ///
+    /// Testing only.
+    #[cfg(test)]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_path_and_mistrust(
+            path,
+            &fs_mistrust::Mistrust::new_dangerously_trust_everyone(),
+        )
+    }
+
+    /// Return the top-level directory for this storage manager.
+    ///
+    /// (This is the same directory passed to
+    /// [`SqliteStateMgr::from_path_and_mistrust`].)
+    pub fn path(&self) -> &Path {
+        self.inner
+            .statepath
+            .as_path()
+            .parent()
+            .expect("No parent directory even after path.join?")
+    }
+
+    /// Make several changes to the stored state, atomically.
+    ///
+    /// Calls `f` with a [`SqliteBatch`], on which it can store and delete
+    /// values.  If `f` returns `Ok`, all of its changes are saved together;
+    /// otherwise, none of them are.
+    ///
+    /// Like [`StateMgr::store`], this requires that we hold the lock.
+    pub fn store_batch<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut SqliteBatch<'_>) -> Result<()>,
+    {
+        if !self.can_store() {
+            return Err(Error::new(
+                ErrorSource::NoLock,
+                Action::Storing,
+                Resource::Manager,
+            ));
+        }
+
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction()
+            .map_err(|e| Error::new(e, Action::Storing, self.err_resource_db()))?;
+        let mut batch = SqliteBatch { mgr: self, tx };
+        f(&mut batch)?;
+        batch
+            .tx
+            .commit()
+            .map_err(|e| Error::new(e, Action::Storing, self.err_resource_db()))
+    }
+
+    /// Lock and return our database connection.
+    fn conn(&self) -> MutexGuard<'_, rusqlite::Connection> {
+        self.inner
+            .conn
+            .lock()
+            .expect("Poisoned lock on state database")
+    }
+
+    /// Return a `Resource` object representing our database.
+    fn err_resource_db(&self) -> Resource {
+        Resource::File {
+            container: self.path().to_path_buf(),
+            file: PathBuf::from("state").join(DB_FNAME),
+        }
+    }
+
+    /// Return a `Resource` object representing our lock file.
+    fn err_resource_lock(&self) -> Resource {
+        Resource::File {
+            container: self.path().to_path_buf(),
+            file: PathBuf::from("state").join(LOCK_FNAME),
+        }
+    }
+}
+
+impl StateMgr for SqliteStateMgr {
+    fn can_store(&self) -> bool {
+        let lockfile = self
+            .inner
+            .lockfile
+            .lock()
+            .expect("Poisoned lock on state lockfile");
+        lockfile.owns_lock()
+    }
+    fn try_lock(&self) -> Result<LockStatus> {
+        let mut lockfile = self
+            .inner
+            .lockfile
+            .lock()
+            .expect("Poisoned lock on state lockfile");
+        if lockfile.owns_lock() {
+            Ok(LockStatus::AlreadyHeld)
+        } else if lockfile
+            .try_lock()
+            .map_err(|e| Error::new(e, Action::Locking, self.err_resource_lock()))?
+        {
+            Ok(LockStatus::NewlyAcquired)
+        } else {
+            Ok(LockStatus::NoLock)
+        }
+    }
+    fn unlock(&self) -> Result<()> {
+        let mut lockfile = self
+            .inner
+            .lockfile
+            .lock()
+            .expect("Poisoned lock on state lockfile");
+        if lockfile.owns_lock() {
+            lockfile
+                .unlock()
+                .map_err(|e| Error::new(e, Action::Unlocking, self.err_resource_lock()))?;
+        }
+        Ok(())
+    }
+    fn load<D>(&self, key: &str) -> Result<Option<D>>
+    where
+        D: DeserializeOwned,
+    {
+        let json: Option<String> = self
+            .conn()
+            .query_row("SELECT value FROM State WHERE key = ?", (key,), |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(|e| Error::new(e, Action::Loading, self.err_resource_db()))?;
+        json.map(|json| serde_json::from_str(&json))
+            .transpose()
+            .map_err(|e| Error::new(e, Action::Loading, self.err_resource_db()))
+    }
+
+    fn store<S>(&self, key: &str, val: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        self.store_batch(|batch| batch.store(key, val))
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        self.store_batch(|batch| {
+            txn.into_values()
+                .try_for_each(|(key, val)| batch.store(&key, &val))
+        })
+    }
+}
+
+#[cfg(all(test, not(miri) /* filesystem access */))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn simple() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = SqliteStateMgr::from_path(dir.path())?;
+
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+        let stuff: HashMap<_, _> = vec![("hello".to_string(), "world".to_string())]
+            .into_iter()
+            .collect();
+        store.store("xyz", &stuff)?;
+
+        let stuff2: Option<HashMap<String, String>> = store.load("xyz")?;
+        let nothing: Option<HashMap<String, String>> = store.load("abc")?;
+
+        assert_eq!(Some(stuff), stuff2);
+        assert!(nothing.is_none());
+
+        assert_eq!(dir.path(), store.path());
+
+        drop(store); // Do this to release the fs lock.
+        let store = SqliteStateMgr::from_path(dir.path())?;
+        let stuff3: Option<HashMap<String, String>> = store.load("xyz")?;
+        assert_eq!(stuff2, stuff3);
+
+        let stuff4: HashMap<_, _> = vec![("greetings".to_string(), "humans".to_string())]
+            .into_iter()
+            .collect();
+
+        assert!(matches!(
+            store.store("xyz", &stuff4).unwrap_err().source(),
+            ErrorSource::NoLock
+        ));
+
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+        store.store("xyz", &stuff4)?;
+
+        let stuff5: Option<HashMap<String, String>> = store.load("xyz")?;
+        assert_eq!(Some(stuff4), stuff5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = SqliteStateMgr::from_path(dir.path())?;
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+
+        store.store_batch(|b| {
+            b.store("a", &1_u32)?;
+            b.store("b", &"two")?;
+            b.store("c", &[3_u8])
+        })?;
+        assert_eq!(store.load::<u32>("a")?, Some(1));
+        assert_eq!(store.load::<String>("b")?, Some("two".into()));
+
+        // A failed batch changes nothing.
+        let failed = store.store_batch(|b| {
+            b.store("a", &100_u32)?;
+            b.delete("b")?;
+            Err(Error::new(
+                ErrorSource::NoLock,
+                Action::Storing,
+                Resource::Manager,
+            ))
+        });
+        assert!(failed.is_err());
+        assert_eq!(store.load::<u32>("a")?, Some(1));
+        assert_eq!(store.load::<String>("b")?, Some("two".into()));
+
+        store.store_batch(|b| b.delete("b"))?;
+        assert_eq!(store.load::<String>("b")?, None);
+        assert_eq!(store.load::<Vec<u8>>("c")?, Some(vec![3]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn locking() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store1 = SqliteStateMgr::from_path(dir.path()).unwrap();
+        let store2 = SqliteStateMgr::from_path(dir.path()).unwrap();
+        let fs_store = crate::FsStateMgr::from_path_and_mistrust(
+            dir.path(),
+            &fs_mistrust::Mistrust::new_dangerously_trust_everyone(),
+        )
+        .unwrap();
+
+        // Nobody has the lock; store1 will take it.
+        assert_eq!(store1.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert_eq!(store1.try_lock().unwrap(), LockStatus::AlreadyHeld);
+        assert!(store1.can_store());
+
+        // store1 has the lock; the others will try to get it and fail.
+        assert_eq!(store2.try_lock().unwrap(), LockStatus::NoLock);
+        assert!(!store2.can_store());
+        assert_eq!(fs_store.try_lock().unwrap(), LockStatus::NoLock);
+
+        // But they can still read.
+        store1.store("k", &7_u8).unwrap();
+        assert_eq!(store2.load::<u8>("k").unwrap(), Some(7));
+
+        // Store 1 will drop the lock, and store2 can get it.
+        store1.unlock().unwrap();
+        assert!(!store1.can_store());
+        assert_eq!(store2.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        assert!(store2.can_store());
+        assert!(!store1.can_store());
+    }
+
+    #[test]
+    fn errors() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = SqliteStateMgr::from_path(dir.path()).unwrap();
+        assert_eq!(store.try_lock().unwrap(), LockStatus::NewlyAcquired);
+
+        store.store("n", &"not a number").unwrap();
+        let bad: Result<Option<u32>> = store.load("n");
+        assert!(matches!(bad.unwrap_err().source(), ErrorSource::Serde(_)));
+    }
+}
@@ -188,7 +188,7 @@ use tracing::trace;
 
 use crate::err::{Action, ErrorSource, Resource};
 use crate::load_store;
-use crate::slug::{BadSlug, Slug, SlugRef, TryIntoSlug};
+use crate::slug::{BadSlug, Slug, SlugPath, SlugRef, SlugSeparator, TryIntoSlug};
 pub use crate::Error;
 
 #[allow(unused_imports)] // Simplifies a lot of references in our docs
@@ -215,7 +215,7 @@ use std::path::MAIN_SEPARATOR as PATH_SEPARATOR;
 pub type Result<T> = StdResult<T, Error>;
 
 /// Extension for lockfiles
-const LOCK_EXTN: &str = "lock";
+const LOCK_EXTN: &SlugRef = crate::slug!("lock");
 /// Suffix for lockfiles, precisely `"." + LOCK_EXTN`
 // There's no way to concatenate constant strings with names!
 // We could use the const_format crate maybe?
@@ -433,8 +433,15 @@ impl StateDirectory {
 
                 let kind_dir = make_secure_directory(&sd.dir, kind)?;
 
+                let lock_slug_path = SlugPath::new(id).join(SlugSeparator::Dot, LOCK_EXTN);
+                check_no_collisions(&kind_dir, &[id.as_str(), lock_slug_path.as_str()])
+                    .map_err(|source| handle_err(Action::Initializing, source))?;
+
+                let lock_fname = lock_slug_path.to_path_buf().map_err(|source| {
+                    handle_err(Action::Initializing, io::Error::from(source).into())
+                })?;
                 let lock_path = kind_dir
-                    .join(format!("{id}.{LOCK_EXTN}"))
+                    .join(lock_fname)
                     .map_err(|source| handle_err(Action::Initializing, source.into()))?;
 
                 let flock_guard = match LockFileGuard::try_lock(&lock_path) {
@@ -1012,6 +1019,34 @@ fn touch_instance_dir(dir: &CheckedDir) -> Result<()> {
         .map_err(|source| Error::new(source, Action::Initializing, resource()))
 }
 
+/// Check that no entry in `dir` could be confused with one of `names`
+///
+/// On a case-insensitive filesystem, an entry whose name differs from one of `names`
+/// only in case (or Unicode normalization) would be the same file;
+/// on a case-sensitive one, it would be a different file that a copy to
+/// a case-insensitive filesystem would conflate with ours.
+/// Either way, the layout is ambiguous, so we refuse to use it.
+fn check_no_collisions(dir: &CheckedDir, names: &[&str]) -> StdResult<(), ErrorSource> {
+    let keys = names
+        .iter()
+        .map(|name| slug::collision_key(name))
+        .collect::<Vec<_>>();
+    let mut collisions = vec![];
+    for entry in fs::read_dir(dir.as_path())? {
+        let entry_name = entry?.file_name();
+        let lossy = entry_name.to_string_lossy();
+        if !names.contains(&&*lossy) && keys.contains(&slug::collision_key(&lossy)) {
+            collisions.push(entry_name);
+        }
+    }
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        collisions.sort();
+        Err(ErrorSource::NameCollision(collisions))
+    }
+}
+
 /// A place in the state or cache directory, where we can load/store a serialisable type
 ///
 /// Implies exclusive access.
@@ -1229,6 +1264,26 @@ mod test {
         });
     }
 
+    #[test]
+    #[traced_test]
+    fn test_name_collision() {
+        test_temp_dir!().used_by(|dir| {
+            let sd = mk_state_dir(dir);
+            fs::create_dir_all(dir.join("garlic/Wild")).unwrap();
+
+            let garlic = Garlic("wild".try_into_slug().unwrap());
+            let err = sd.acquire_instance(&garlic).unwrap_err();
+            assert_eq!(err.kind(), TEK::PersistentStateCorrupted);
+            assert!(matches!(
+                err.source(),
+                ErrorSource::NameCollision(names) if names == &[std::ffi::OsString::from("Wild")]
+            ));
+
+            let garlic = Garlic("tame".try_into_slug().unwrap());
+            sd.acquire_instance(&garlic).unwrap();
+        });
+    }
+
     #[test]
     #[traced_test]
     #[allow(clippy::comparison_chain)]
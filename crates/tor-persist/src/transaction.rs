@@ -0,0 +1,71 @@
+//! Transactions: several values, stored together atomically.
+
+use crate::err::{Action, Resource};
+use crate::{Error, JsonValue, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A set of values to store together, atomically.
+///
+/// Stage values with [`Transaction::store`], then pass the transaction to
+/// [`StateMgr::commit`](crate::StateMgr::commit): either every value is
+/// stored, or none of them are, even if we crash partway through.
+///
+/// # Example
+///
+/// ```
+/// use tor_persist::{MemoryStateMgr, StateMgr, Transaction};
+///
+/// let mgr = MemoryStateMgr::new();
+/// assert!(mgr.try_lock()?.held());
+///
+/// let mut txn = Transaction::new();
+/// txn.store("guards", &["a", "b"])?;
+/// txn.store("bridges", &["c"])?;
+/// mgr.commit(txn)?;
+///
+/// assert_eq!(mgr.load::<Vec<String>>("bridges")?, Some(vec!["c".into()]));
+/// # Ok::<(), tor_persist::Error>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Transaction {
+    /// The values to store, by key.
+    values: BTreeMap<String, JsonValue>,
+}
+
+impl Transaction {
+    /// Create a new, empty `Transaction`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stage `val` to be stored with `key`.
+    ///
+    /// Replaces any value already staged with `key`.
+    pub fn store<S>(&mut self, key: &str, val: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        let val = serde_json::to_value(val).map_err(|e| {
+            Error::new(
+                e,
+                Action::Storing,
+                Resource::Key {
+                    key: key.to_string(),
+                },
+            )
+        })?;
+        self.values.insert(key.to_string(), val);
+        Ok(())
+    }
+
+    /// Return true if no values have been staged.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Return the staged values, in order of their keys.
+    pub(crate) fn into_values(self) -> impl Iterator<Item = (String, JsonValue)> {
+        self.values.into_iter()
+    }
+}
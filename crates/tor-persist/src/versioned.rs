@@ -0,0 +1,319 @@
+//! Versioned state records, with migrations between versions.
+//!
+//! A value stored through a [`VersionedStorageHandle`] is tagged with the
+//! version of its schema.  When the schema for a key changes, the code that
+//! owns it adds a migration to its [`Schema`], which upgrades values stored
+//! with the previous version.  On load, old values are upgraded through every
+//! migration in turn (and stored back, if we hold the lock); values from a
+//! version newer than we know about are rejected, rather than misread.
+//!
+//! Values that were stored before their key used versioning are treated as
+//! version 0.
+
+use crate::err::{Action, ErrorSource, Resource};
+use crate::{Error, JsonValue, Result, StateMgr, StorageHandle};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// A function that upgrades a value from one schema version to the next.
+type MigrationFn =
+    dyn Fn(JsonValue) -> std::result::Result<JsonValue, MigrationError> + Send + Sync;
+
+/// An error returned by a migration function.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct MigrationError(String);
+
+impl MigrationError {
+    /// Create a new `MigrationError` with a given message.
+    pub fn new(msg: impl Into<String>) -> Self {
+        MigrationError(msg.into())
+    }
+}
+
+/// The history of the schema for one kind of stored value.
+///
+/// A `Schema` with no migrations is at version 0.  Each call to
+/// [`Schema::migration`] adds one to the current version.
+#[derive(Clone, Default)]
+pub struct Schema {
+    /// The migrations, in order: `migrations[n]` upgrades a value from
+    /// version `n` to version `n + 1`.
+    migrations: Vec<Arc<MigrationFn>>,
+}
+
+impl fmt::Debug for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Schema")
+            .field("current_version", &self.current_version())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Schema {
+    /// Create a new `Schema`, at version 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a migration that upgrades values from the current version to the
+    /// next one.
+    pub fn migration<F>(mut self, f: F) -> Self
+    where
+        F: Fn(JsonValue) -> std::result::Result<JsonValue, MigrationError> + Send + Sync + 'static,
+    {
+        self.migrations.push(Arc::new(f));
+        self
+    }
+
+    /// Return the version of values that we store with this schema.
+    pub fn current_version(&self) -> u32 {
+        self.migrations
+            .len()
+            .try_into()
+            .expect("More than u32::MAX migrations?")
+    }
+
+    /// Upgrade `value`, which was stored with schema version `version`, to
+    /// the current version.
+    fn upgrade(
+        &self,
+        version: u32,
+        mut value: JsonValue,
+    ) -> std::result::Result<JsonValue, ErrorSource> {
+        let current = self.current_version();
+        if version > current {
+            return Err(ErrorSource::SchemaTooNew {
+                found: version,
+                supported: current,
+            });
+        }
+        for (from, migration) in self.migrations.iter().enumerate().skip(version as usize) {
+            value = migration(value).map_err(|error| ErrorSource::Migration {
+                from: from as u32,
+                error,
+            })?;
+        }
+        Ok(value)
+    }
+}
+
+/// The form in which we store a versioned value.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VersionedRecord {
+    /// The schema version of `value`.
+    schema_version: u32,
+    /// The value itself.
+    value: JsonValue,
+}
+
+/// A [`StorageHandle`] that tags the values it stores with a schema version,
+/// and upgrades old values when it loads them.
+///
+/// To get an object of this type, call [`StateMgr::create_versioned_handle`].
+///
+/// See the [module documentation](self) for details.
+pub(crate) struct VersionedStorageHandle<M, T> {
+    /// An underlying [`StateMgr`] to use.
+    mgr: M,
+    /// The key to use when loading and storing from the [`StateMgr`].
+    key: String,
+    /// The schema of the values stored at `key`.
+    schema: Schema,
+    /// A zero-sized type to please the type checker.
+    ///
+    /// (See `StorageHandleImpl` for an explanation of the type.)
+    phantom: PhantomData<fn(T) -> T>,
+}
+
+impl<M, T> VersionedStorageHandle<M, T>
+where
+    M: StateMgr,
+    T: Serialize + DeserializeOwned + 'static,
+{
+    /// Construct a new VersionedStorageHandle.
+    pub(crate) fn new(mgr: M, key: String, schema: Schema) -> Self {
+        VersionedStorageHandle {
+            mgr,
+            key,
+            schema,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Return a `Resource` object representing our key.
+    fn err_resource(&self) -> Resource {
+        Resource::Key {
+            key: self.key.clone(),
+        }
+    }
+
+    /// Store `value`, which is already at the current schema version.
+    fn store_value(&self, value: JsonValue) -> Result<()> {
+        let record = VersionedRecord {
+            schema_version: self.schema.current_version(),
+            value,
+        };
+        self.mgr.store(&self.key, &record)
+    }
+}
+
+impl<M, T> StorageHandle<T> for VersionedStorageHandle<M, T>
+where
+    M: StateMgr,
+    T: Serialize + DeserializeOwned + 'static,
+{
+    fn load(&self) -> Result<Option<T>> {
+        let Some(stored) = self.mgr.load::<JsonValue>(&self.key)? else {
+            return Ok(None);
+        };
+        let (version, value) = match serde_json::from_value::<VersionedRecord>(stored.clone()) {
+            Ok(record) => (record.schema_version, record.value),
+            // Stored before this key was versioned.
+            Err(_) => (0, stored),
+        };
+        let value = self
+            .schema
+            .upgrade(version, value)
+            .map_err(|e| Error::new(e, Action::Loading, self.err_resource()))?;
+        let result = serde_json::from_value(value.clone())
+            .map_err(|e| Error::new(e, Action::Loading, self.err_resource()))?;
+
+        if version < self.schema.current_version() && self.mgr.can_store() {
+            self.store_value(value)?;
+        }
+        Ok(Some(result))
+    }
+
+    fn store(&self, val: &T) -> Result<()> {
+        let value = serde_json::to_value(val)
+            .map_err(|e| Error::new(e, Action::Storing, self.err_resource()))?;
+        self.store_value(value)
+    }
+
+    fn can_store(&self) -> bool {
+        self.mgr.can_store()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{DynStorageHandle, LockStatus, MemoryStateMgr};
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct V2 {
+        name: String,
+        count: u32,
+    }
+
+    /// A schema whose version 0 was a bare string, version 1 was
+    /// `{"name": ...}`, and version 2 added `count`.
+    fn schema() -> Schema {
+        Schema::new()
+            .migration(|v| match v {
+                JsonValue::String(name) => Ok(json!({ "name": name })),
+                _ => Err(MigrationError::new("expected a string")),
+            })
+            .migration(|mut v| {
+                v.as_object_mut()
+                    .ok_or_else(|| MigrationError::new("expected an object"))?
+                    .insert("count".into(), json!(0));
+                Ok(v)
+            })
+    }
+
+    #[test]
+    fn upgrade_legacy() {
+        let mgr = MemoryStateMgr::new();
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        mgr.store("thing", &"hello").unwrap();
+
+        let h: DynStorageHandle<V2> = mgr.clone().create_versioned_handle("thing", schema());
+        let expected = V2 {
+            name: "hello".into(),
+            count: 0,
+        };
+        assert_eq!(h.load().unwrap(), Some(expected));
+
+        // It was stored back, upgraded.
+        let raw: JsonValue = mgr.load("thing").unwrap().unwrap();
+        assert_eq!(
+            raw,
+            json!({ "schema_version": 2, "value": { "name": "hello", "count": 0 } })
+        );
+    }
+
+    #[test]
+    fn upgrade_from_middle() {
+        let mgr = MemoryStateMgr::new();
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        mgr.store(
+            "thing",
+            &json!({ "schema_version": 1, "value": { "name": "x" } }),
+        )
+        .unwrap();
+
+        let h: DynStorageHandle<V2> = mgr.clone().create_versioned_handle("thing", schema());
+        assert_eq!(h.load().unwrap().unwrap().name, "x");
+
+        let v = V2 {
+            name: "y".into(),
+            count: 7,
+        };
+        h.store(&v).unwrap();
+        assert_eq!(h.load().unwrap(), Some(v));
+    }
+
+    #[test]
+    fn too_new() {
+        let mgr = MemoryStateMgr::new();
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        mgr.store("thing", &json!({ "schema_version": 3, "value": {} }))
+            .unwrap();
+
+        let h: DynStorageHandle<V2> = mgr.clone().create_versioned_handle("thing", schema());
+        let err = h.load().unwrap_err();
+        assert!(matches!(
+            err.source(),
+            ErrorSource::SchemaTooNew {
+                found: 3,
+                supported: 2
+            }
+        ));
+        // We didn't touch it.
+        let raw: JsonValue = mgr.load("thing").unwrap().unwrap();
+        assert_eq!(raw["schema_version"], 3);
+    }
+
+    #[test]
+    fn failed_migration() {
+        let mgr = MemoryStateMgr::new();
+        assert_eq!(mgr.try_lock().unwrap(), LockStatus::NewlyAcquired);
+        mgr.store("thing", &17_u32).unwrap();
+
+        let h: DynStorageHandle<V2> = mgr.clone().create_versioned_handle("thing", schema());
+        let err = h.load().unwrap_err();
+        assert!(matches!(
+            err.source(),
+            ErrorSource::Migration { from: 0, .. }
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use tor_cell::chancell::codec::ChannelCodec;
+use tor_cell::chancell::msg::AnyChanMsg;
+use tor_cell::chancell::{BoxedCellBody, CELL_DATA_LEN};
+use tor_cell::relaycell::msg::AnyRelayMsg;
+use tor_cell::relaycell::{RelayCellFormat, UnparsedRelayMsg};
+
+mod cpu_time;
+use cpu_time::*;
+
+/// The number of cells in each batch that we decode.
+const N_CELLS: usize = 64;
+
+/// Return a buffer holding `N_CELLS` encoded RELAY channel cells.
+fn relay_cells() -> BytesMut {
+    let mut buf = BytesMut::new();
+    for i in 0..N_CELLS {
+        buf.extend_from_slice(&(i as u32 + 1).to_be_bytes());
+        buf.extend_from_slice(&[3]); // RELAY
+        buf.extend_from_slice(&[0x5a; CELL_DATA_LEN]);
+    }
+    buf
+}
+
+/// Return a V0 relay cell body holding a DATA message with a full payload.
+fn data_cell_body() -> BoxedCellBody {
+    let mut body = [0_u8; CELL_DATA_LEN];
+    body[0] = 2; // DATA
+    body[3..5].copy_from_slice(&7_u16.to_be_bytes());
+    body[9..11].copy_from_slice(&498_u16.to_be_bytes());
+    body[11..].fill(0x5a);
+    Box::new(body)
+}
+
+/// Benchmark decoding channel cells and relay messages, with and without
+/// copying their bodies.
+pub fn cell_decode_benchmark(c: &mut Criterion<CpuTime>) {
+    let mut group = c.benchmark_group("cell_decode");
+    group.throughput(Throughput::Bytes((N_CELLS * (CELL_DATA_LEN + 5)) as u64));
+
+    group.bench_function("chan_decode_cell", |b| {
+        b.iter_batched_ref(
+            relay_cells,
+            |buf| {
+                let mut codec = ChannelCodec::new(4);
+                while let Some(cell) = codec.decode_cell::<AnyChanMsg>(buf).unwrap() {
+                    criterion::black_box(cell);
+                }
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("chan_decode_raw_cell", |b| {
+        b.iter_batched_ref(
+            relay_cells,
+            |buf| {
+                let mut codec = ChannelCodec::new(4);
+                while let Some(cell) = codec.decode_raw_cell(buf).unwrap() {
+                    criterion::black_box(cell);
+                }
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    group.throughput(Throughput::Bytes(CELL_DATA_LEN as u64));
+
+    group.bench_function("relay_decode_data", |b| {
+        b.iter_batched(
+            || {
+                UnparsedRelayMsg::from_singleton_body(RelayCellFormat::V0, data_cell_body())
+                    .unwrap()
+            },
+            |msg| {
+                criterion::black_box(msg.decode::<AnyRelayMsg>().unwrap());
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("relay_body", |b| {
+        b.iter_batched_ref(
+            || {
+                UnparsedRelayMsg::from_singleton_body(RelayCellFormat::V0, data_cell_body())
+                    .unwrap()
+            },
+            |msg| {
+                criterion::black_box(msg.body().unwrap());
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+   name = cell_decode;
+   config = Criterion::default()
+      .with_measurement(CpuTime)
+      .sample_size(5000);
+   targets = cell_decode_benchmark);
+criterion_main!(cell_decode);
@@ -6,7 +6,7 @@ use tor_bytes::SecretBuf;
 use tor_cell::relaycell::{RelayCellFormatTrait, RelayCellFormatV0};
 use tor_llcrypto::{
     cipher::aes::{Aes128Ctr, Aes256Ctr},
-    d::{Sha1, Sha256},
+    d::{Sha1, Sha3_256},
 };
 use tor_proto::bench_utils::{
     client_decrypt, encrypt_inbound, HopCryptState, InboundCryptWrapper, RelayBody,
@@ -76,7 +76,7 @@ pub fn cell_decrypt_benchmark(c: &mut Criterion<CpuTime>) {
 
     group.bench_function("cell_decrypt_Tor1Hsv3RelayCrypto", |b| {
         b.iter_batched_ref(
-            || full_circuit_inbound_setup!(Aes256Ctr, Sha256, RelayCellFormatV0),
+            || full_circuit_inbound_setup!(Aes256Ctr, Sha3_256, RelayCellFormatV0),
             |(cell, cc_in)| {
                 client_decrypt(cell, cc_in).unwrap();
             },
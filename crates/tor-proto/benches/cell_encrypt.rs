@@ -5,15 +5,20 @@ use tor_bytes::SecretBuf;
 use tor_cell::relaycell::RelayCellFormatV0;
 use tor_llcrypto::{
     cipher::aes::{Aes128Ctr, Aes256Ctr},
-    d::{Sha1, Sha256},
+    d::{Sha1, Sha3_256},
+};
+use tor_proto::bench_utils::{
+    client_encrypt, client_encrypt_batch, OutboundCryptWrapper, RelayBody, RelayBodyBatch,
 };
-use tor_proto::bench_utils::{client_encrypt, OutboundCryptWrapper, RelayBody};
 
 mod cpu_time;
 use cpu_time::*;
 
 const HOP_NUM: u8 = 2;
 
+/// Number of cells to encrypt at once in the batched benchmarks.
+const BATCH_LEN: usize = 16;
+
 /// Helper macro to setup a full circuit encryption benchmark.
 macro_rules! full_circuit_outbound_setup {
     ($sc:ty, $d:ty, $f:ty) => {{
@@ -39,7 +44,22 @@ fn create_outbound_cell(rng: &mut ThreadRng) -> RelayBody {
     cell.into()
 }
 
-/// Benchmark the `client_encrypt` function.
+/// Create a batch of `BATCH_LEN` random outbound cells.
+fn create_outbound_batch(rng: &mut ThreadRng) -> RelayBodyBatch {
+    (0..BATCH_LEN)
+        .map(|_| {
+            let mut cell = [0u8; 509];
+            rng.fill(&mut cell[..]);
+            cell
+        })
+        .collect()
+}
+
+/// Benchmark the `client_encrypt` and `client_encrypt_batch` functions.
+///
+/// In the `cell_encrypt_batch` group, `per_cell_Tor1RelayCrypto` is the
+/// baseline: it encrypts the same number of cells as
+/// `batch_Tor1RelayCrypto`, one call at a time.
 pub fn cell_encrypt_benchmark(c: &mut Criterion<CpuTime>) {
     let mut group = c.benchmark_group("cell_encrypt");
     group.throughput(Throughput::Bytes(509));
@@ -56,7 +76,7 @@ pub fn cell_encrypt_benchmark(c: &mut Criterion<CpuTime>) {
 
     group.bench_function("cell_encrypt_Tor1Hsv3RelayCrypto", |b| {
         b.iter_batched_ref(
-            || full_circuit_outbound_setup!(Aes256Ctr, Sha256, RelayCellFormatV0),
+            || full_circuit_outbound_setup!(Aes256Ctr, Sha3_256, RelayCellFormatV0),
             |(cell, cc_out)| {
                 client_encrypt(cell, cc_out, HOP_NUM).unwrap();
             },
@@ -65,6 +85,43 @@ pub fn cell_encrypt_benchmark(c: &mut Criterion<CpuTime>) {
     });
 
     group.finish();
+
+    let mut group = c.benchmark_group("cell_encrypt_batch");
+    group.throughput(Throughput::Bytes((509 * BATCH_LEN) as u64));
+
+    group.bench_function("per_cell_Tor1RelayCrypto", |b| {
+        b.iter_batched_ref(
+            || {
+                let (cell, cc_out) =
+                    full_circuit_outbound_setup!(Aes128Ctr, Sha1, RelayCellFormatV0);
+                let cells: Vec<RelayBody> = std::iter::once(cell)
+                    .chain((1..BATCH_LEN).map(|_| create_outbound_cell(&mut rand::rng())))
+                    .collect();
+                (cells, cc_out)
+            },
+            |(cells, cc_out)| {
+                for cell in cells.iter_mut() {
+                    client_encrypt(cell, cc_out, HOP_NUM).unwrap();
+                }
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("batch_Tor1RelayCrypto", |b| {
+        b.iter_batched_ref(
+            || {
+                let (_, cc_out) = full_circuit_outbound_setup!(Aes128Ctr, Sha1, RelayCellFormatV0);
+                (create_outbound_batch(&mut rand::rng()), cc_out)
+            },
+            |(cells, cc_out)| {
+                client_encrypt_batch(cells, cc_out, HOP_NUM).unwrap();
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
 }
 
 criterion_group!(
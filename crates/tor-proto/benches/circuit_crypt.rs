@@ -0,0 +1,72 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rand::prelude::*;
+
+use tor_bytes::SecretBuf;
+use tor_proto::bench_utils::{BenchCircuit, BenchProtocol, RelayBody};
+
+mod cpu_time;
+use cpu_time::*;
+
+/// The hop at the end of the circuit.
+const HOP_NUM: u8 = 2;
+
+/// The protocols to benchmark, along with their names.
+const PROTOCOLS: &[(&str, BenchProtocol)] = &[
+    ("Tor1RelayCrypto", BenchProtocol::Tor1),
+    ("Tor1Hsv3RelayCrypto", BenchProtocol::Tor1Hsv3),
+    #[cfg(feature = "counter-galois-onion")]
+    ("CgoRelayCrypto", BenchProtocol::Cgo),
+];
+
+/// Build a three-hop circuit using `protocol`, and a random cell to send on it.
+fn full_circuit_setup(protocol: BenchProtocol) -> (RelayBody, BenchCircuit) {
+    let seeds: [SecretBuf; 3] = [
+        b"hidden we are free".to_vec().into(),
+        b"free to speak, to free ourselves".to_vec().into(),
+        b"free to hide no more".to_vec().into(),
+    ];
+    let circ = BenchCircuit::new(protocol, &seeds).unwrap();
+
+    let mut rng = rand::rng();
+    let mut cell = [0u8; 509];
+    rng.fill(&mut cell[..]);
+    (cell.into(), circ)
+}
+
+/// Benchmark sending a cell through a whole circuit, in each direction.
+pub fn circuit_crypt_benchmark(c: &mut Criterion<CpuTime>) {
+    let mut group = c.benchmark_group("circuit_crypt");
+    group.throughput(Throughput::Bytes(509));
+
+    for (name, protocol) in PROTOCOLS {
+        group.bench_function(format!("circuit_outbound_{name}"), |b| {
+            b.iter_batched_ref(
+                || full_circuit_setup(*protocol),
+                |(cell, circ)| {
+                    circ.send_outbound(cell, HOP_NUM).unwrap();
+                },
+                criterion::BatchSize::SmallInput,
+            );
+        });
+
+        group.bench_function(format!("circuit_inbound_{name}"), |b| {
+            b.iter_batched_ref(
+                || full_circuit_setup(*protocol),
+                |(cell, circ)| {
+                    circ.send_inbound(cell, HOP_NUM).unwrap();
+                },
+                criterion::BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+   name = circuit_crypt;
+   config = Criterion::default()
+      .with_measurement(CpuTime)
+      .sample_size(5000);
+   targets = circuit_crypt_benchmark);
+criterion_main!(circuit_crypt);
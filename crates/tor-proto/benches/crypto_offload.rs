@@ -0,0 +1,102 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::future::join_all;
+use rand::prelude::*;
+
+use tor_bytes::SecretBuf;
+use tor_cell::relaycell::RelayCellFormatV0;
+use tor_llcrypto::{cipher::aes::Aes128Ctr, d::Sha1};
+use tor_proto::bench_utils::{
+    client_encrypt_batch, OffloadedOutboundCrypt, OutboundCryptWrapper, RelayBodyBatch,
+};
+use tor_proto::{CryptoOffload, CryptoOffloadConfig};
+
+const HOP_NUM: u8 = 2;
+
+/// Number of circuits sending cells at the same time.
+const N_CIRCUITS: usize = 8;
+
+/// Number of cells that each circuit encrypts at once.
+const BATCH_LEN: usize = 16;
+
+/// Build a three-hop Tor1 circuit's outbound crypto state.
+fn outbound_setup() -> OutboundCryptWrapper {
+    let seed1: SecretBuf = b"hidden we are free".to_vec().into();
+    let seed2: SecretBuf = b"free to speak, to free ourselves".to_vec().into();
+    let seed3: SecretBuf = b"free to hide no more".to_vec().into();
+
+    let mut cc_out = OutboundCryptWrapper::new();
+    for seed in [seed1, seed2, seed3] {
+        cc_out
+            .add_layer_from_seed::<Aes128Ctr, Sha1, RelayCellFormatV0>(seed)
+            .unwrap();
+    }
+    cc_out
+}
+
+/// Create a batch of `BATCH_LEN` random outbound cells.
+fn create_outbound_batch(rng: &mut ThreadRng) -> RelayBodyBatch {
+    (0..BATCH_LEN)
+        .map(|_| {
+            let mut cell = [0u8; 509];
+            rng.fill(&mut cell[..]);
+            cell
+        })
+        .collect()
+}
+
+/// Benchmark encrypting a batch of cells on each of several circuits, first
+/// inline on one thread, and then on a crypto offload pool.
+///
+/// This measures wall-clock time, since the point of the pool is to spend CPU
+/// time on other threads.
+pub fn crypto_offload_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("crypto_offload");
+    group.throughput(Throughput::Bytes((509 * BATCH_LEN * N_CIRCUITS) as u64));
+
+    group.bench_function("inline_Tor1RelayCrypto", |b| {
+        b.iter_batched_ref(
+            || {
+                (0..N_CIRCUITS)
+                    .map(|_| (create_outbound_batch(&mut rand::rng()), outbound_setup()))
+                    .collect::<Vec<_>>()
+            },
+            |circuits| {
+                for (cells, cc_out) in circuits.iter_mut() {
+                    client_encrypt_batch(cells, cc_out, HOP_NUM).unwrap();
+                }
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    let pool = CryptoOffload::launch(&CryptoOffloadConfig::default()).unwrap();
+    group.bench_function("offloaded_Tor1RelayCrypto", |b| {
+        b.iter_batched_ref(
+            || {
+                (0..N_CIRCUITS)
+                    .map(|_| {
+                        let cc_out = OffloadedOutboundCrypt::new(&pool, outbound_setup());
+                        (create_outbound_batch(&mut rand::rng()), cc_out)
+                    })
+                    .collect::<Vec<_>>()
+            },
+            |circuits| {
+                let jobs = circuits
+                    .iter_mut()
+                    .map(|(cells, cc_out)| cc_out.encrypt_batch(cells, HOP_NUM));
+                for res in futures::executor::block_on(join_all(jobs)) {
+                    res.unwrap();
+                }
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+   name = crypto_offload;
+   config = Criterion::default();
+   targets = crypto_offload_benchmark);
+criterion_main!(crypto_offload);
@@ -49,8 +49,9 @@
 //!
 //! TODO: There is no channel padding.
 //!
-//! TODO: There is no flow control, rate limiting, queueing, or
-//! fairness.
+//! TODO: There is no flow control or rate limiting.  Cells from different
+//! circuits are scheduled fairly by the reactor (see `scheduler`), but there is
+//! no further queueing.
 
 /// The size of the channel buffer for communication between `Channel` and its reactor.
 pub const CHANNEL_BUFFER_SIZE: usize = 128;
@@ -59,9 +60,13 @@ mod circmap;
 mod codec;
 mod handshake;
 pub mod kist;
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod mock_relay;
 pub mod padding;
 pub mod params;
 mod reactor;
+mod scheduler;
 mod unique_id;
 
 pub use crate::channel::params::*;
@@ -490,7 +495,7 @@ impl Channel {
         });
 
         // We start disabled; the channel manager will `reconfigure` us soon after creation.
-        let padding_timer = Box::pin(padding::Timer::new_disabled(sleep_prov, None)?);
+        let padding_timer = Box::pin(padding::Timer::new_disabled(sleep_prov.clone(), None)?);
 
         let reactor = Reactor {
             control: control_rx,
@@ -506,6 +511,8 @@ impl Channel {
             details,
             padding_timer,
             special_outgoing: Default::default(),
+            scheduler: Default::default(),
+            sleep_prov,
         };
 
         Ok((channel, reactor))
@@ -1,5 +1,13 @@
 //! KIST-related parameters.
 
+use std::time::Duration;
+
+/// The default half-life of a circuit's recent cell count.
+///
+/// This is the default for the `CircuitPriorityHalflifeMsec` consensus
+/// parameter.
+pub const DEFAULT_CIRCUIT_PRIORITY_HALFLIFE: Duration = Duration::from_secs(30);
+
 /// A set of parameters derived from the consensus document,
 /// controlling the KIST behavior of channels.
 #[derive(Debug, Clone, Copy, PartialEq, amplify::Getters)]
@@ -14,14 +22,25 @@ pub struct KistParams {
     /// [`TCP_NOTSENT_LOWAT`]: https://lwn.net/Articles/560082/
     #[getter(as_copy)]
     tcp_notsent_lowat: u32,
+    /// The half-life of each circuit's recent cell count,
+    /// used to decide which circuit on a channel gets to send next.
+    ///
+    /// Circuits that have sent fewer cells recently go first.
+    #[getter(as_copy)]
+    circuit_priority_halflife: Duration,
 }
 
 impl KistParams {
     /// Create a new `KistParams` from the given `KistMode` and options.
-    pub fn new(kist_enabled: KistMode, tcp_notsent_lowat: u32) -> Self {
+    pub fn new(
+        kist_enabled: KistMode,
+        tcp_notsent_lowat: u32,
+        circuit_priority_halflife: Duration,
+    ) -> Self {
         Self {
             kist_enabled,
             tcp_notsent_lowat,
+            circuit_priority_halflife,
         }
     }
 }
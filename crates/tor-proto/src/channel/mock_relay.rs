@@ -0,0 +1,507 @@
+//! An in-process mock relay, for end-to-end testing.
+//!
+//! A [`MockRelay`] plays the part of the relay at the far end of a
+//! [`Channel`].  It answers CREATE_FAST and CREATE2 cells, keeps real
+//! relay-side cell cryptography for every hop of every circuit, handles
+//! EXTEND2 messages by pretending to be the next hop, and runs an echo
+//! server on every stream that a client opens.
+//!
+//! No network is involved, so tests that use a mock relay can build full
+//! multi-hop circuits and exchange stream data deterministically.
+//!
+//! ## Limitations
+//!
+//! Every hop of every circuit uses the same keys: extend to
+//! [`MockRelay::circ_target`] to reach another "hop".  Only the fixed-window
+//! SENDME algorithm is supported: congestion control is never negotiated.
+//! Only the original relay cell format (`RelayCellFormat::V0`) is used.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use futures::channel::mpsc;
+use futures::task::SpawnExt as _;
+use futures::{SinkExt as _, StreamExt as _};
+use tor_basic_utils::test_rng::{testing_rng, TestingRng};
+use tor_cell::chancell::msg::{self as chanmsg, AnyChanMsg, HandshakeType};
+use tor_cell::chancell::{AnyChanCell, BoxedCellBody, ChanCmd, ChanMsg as _, CircId};
+use tor_cell::relaycell::extend::NtorV3Extension;
+use tor_cell::relaycell::msg::{self as relaymsg, AnyRelayMsg};
+use tor_cell::relaycell::{
+    AnyRelayMsgOuter, RelayCellFormat, RelayCellFormatV0, RelayMsg as _, StreamId,
+};
+use tor_error::{internal, into_internal};
+use tor_linkspec::{OwnedChanTarget, OwnedCircTarget};
+use tor_llcrypto::pk::curve25519;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_rtcompat::{NoOpStreamOpsHandle, Runtime};
+use tracing::{debug, trace};
+
+use super::codec::CodecError;
+use super::{Channel, OpenChanCellS2C, UniqId};
+use crate::crypto::cell::{
+    CryptInit as _, InboundRelayLayer, OutboundRelayLayer, RelayCellBody, RelayLayer as _,
+    Tor1RelayCrypto,
+};
+use crate::crypto::handshake::fast::CreateFastServer;
+use crate::crypto::handshake::ntor::{NtorSecretKey, NtorServer};
+use crate::crypto::handshake::ntor_v3::{NtorV3SecretKey, NtorV3Server};
+use crate::crypto::handshake::{KeyGenerator, RelayHandshakeError, ServerHandshake as _};
+use crate::util::err::ChannelClosed;
+use crate::util::fake_mq;
+use crate::{ClockSkew, Error, Result};
+
+/// Link protocol version that our mock channels pretend to have negotiated.
+const LINK_PROTOCOL: u16 = 5;
+
+/// Number of cells that can be queued in each direction between a mock relay
+/// and its channel.
+const QUEUE_LEN: usize = 128;
+
+/// Number of DATA cells we receive on a hop before sending a circuit SENDME.
+const CIRC_SENDME_INC: u32 = 100;
+
+/// Number of DATA cells we receive on a stream before sending a stream SENDME.
+const STREAM_SENDME_INC: u32 = 50;
+
+/// A relay that runs in-process, for use in tests.
+///
+/// Create one with [`MockRelay::new`], then call [`MockRelay::launch`] to get
+/// an open [`Channel`] to it.
+pub struct MockRelay {
+    /// Our ntor onion key.
+    ntor_key: NtorSecretKey,
+    /// Our ntor-v3 onion key.
+    ntor_v3_key: NtorV3SecretKey,
+    /// A description of ourself, as clients should see us.
+    target: OwnedCircTarget,
+}
+
+impl MockRelay {
+    /// Construct a new `MockRelay` with fixed keys and identities.
+    pub fn new() -> Self {
+        let ed_id = Ed25519Identity::from([6; 32]);
+        let rsa_id = RsaIdentity::from([10; 20]);
+        let sk = curve25519::StaticSecret::from([0x42; 32]);
+        let pk = curve25519::PublicKey::from(&sk);
+
+        let mut builder = OwnedCircTarget::builder();
+        builder
+            .chan_target()
+            .ed_identity(ed_id)
+            .rsa_identity(rsa_id);
+        let target = builder
+            .ntor_onion_key(pk)
+            .protocols(
+                "Relay=1-4 FlowCtrl=1"
+                    .parse()
+                    .expect("Couldn't parse protocols"),
+            )
+            .build()
+            .expect("Couldn't construct mock relay target");
+
+        MockRelay {
+            ntor_key: NtorSecretKey::new(sk.clone(), pk, rsa_id),
+            ntor_v3_key: NtorV3SecretKey::new(sk, pk, ed_id),
+            target,
+        }
+    }
+
+    /// Return a [`CircTarget`](tor_linkspec::CircTarget) describing this relay.
+    ///
+    /// Use this for `create_firsthop_*` and for every `extend_*` call on
+    /// circuits through this relay.
+    pub fn circ_target(&self) -> OwnedCircTarget {
+        self.target.clone()
+    }
+
+    /// Launch this relay on `runtime`, and return an open channel to it.
+    ///
+    /// The channel's reactor and the relay itself run as tasks on `runtime`;
+    /// they stop once the channel is closed.
+    pub fn launch<R: Runtime>(self, runtime: &R) -> Result<Arc<Channel>> {
+        let (to_relay, from_client) = mpsc::channel::<AnyChanCell>(QUEUE_LEN);
+        let (to_client, from_relay) = mpsc::channel(QUEUE_LEN);
+
+        let sink =
+            to_relay.sink_map_err(|_| CodecError::Io(io::Error::from(io::ErrorKind::BrokenPipe)));
+        let peer_id = OwnedChanTarget::from_chan_target(&self.target);
+        let (channel, reactor) = Channel::new(
+            LINK_PROTOCOL,
+            Box::new(sink),
+            Box::new(from_relay),
+            Box::new(NoOpStreamOpsHandle::default()),
+            UniqId::new(),
+            peer_id,
+            ClockSkew::None,
+            runtime.clone(),
+            fake_mq(),
+        )?;
+
+        runtime
+            .spawn(async move {
+                let _ignore = reactor.run().await;
+            })
+            .map_err(into_internal!("Couldn't spawn mock channel reactor"))?;
+
+        let state = RelayState {
+            keys: self,
+            circs: HashMap::new(),
+            to_client,
+            rng: testing_rng(),
+        };
+        runtime
+            .spawn(state.run(from_client))
+            .map_err(into_internal!("Couldn't spawn mock relay"))?;
+
+        Ok(channel)
+    }
+}
+
+impl Default for MockRelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The relay's side of one hop on a circuit.
+struct Hop {
+    /// Crypto for cells moving away from the client.
+    fwd: Box<dyn OutboundRelayLayer + Send>,
+    /// Crypto for cells moving towards the client.
+    back: Box<dyn InboundRelayLayer + Send>,
+    /// Number of DATA cells received at this hop.
+    data_received: u32,
+    /// Open streams at this hop, with the number of DATA cells received on each.
+    streams: HashMap<StreamId, u32>,
+}
+
+impl Hop {
+    /// Create a new hop from the output of a circuit handshake.
+    fn new(keygen: impl KeyGenerator) -> Result<Self> {
+        let (fwd, back, _binding) =
+            Tor1RelayCrypto::<RelayCellFormatV0>::construct(keygen)?.split_relay_layer();
+        Ok(Hop {
+            fwd: Box::new(fwd),
+            back: Box::new(back),
+            data_received: 0,
+            streams: HashMap::new(),
+        })
+    }
+}
+
+/// A circuit, as the mock relay sees it.
+#[derive(Default)]
+struct Circ {
+    /// Every hop that the client has built, starting with the first.
+    hops: Vec<Hop>,
+}
+
+/// The running state of a [`MockRelay`].
+struct RelayState {
+    /// Our keys and identity.
+    keys: MockRelay,
+    /// Every circuit that is currently open.
+    circs: HashMap<CircId, Circ>,
+    /// Queue of cells for the client.
+    to_client: mpsc::Sender<std::result::Result<OpenChanCellS2C, CodecError>>,
+    /// Randomness for handshakes and cell padding.
+    rng: TestingRng,
+}
+
+impl RelayState {
+    /// Handle cells from the client until the channel closes.
+    async fn run(mut self, mut from_client: mpsc::Receiver<AnyChanCell>) {
+        while let Some(cell) = from_client.next().await {
+            match self.handle_cell(cell).await {
+                Ok(()) => {}
+                Err(Error::ChannelClosed(_)) => break,
+                Err(e) => debug!("Mock relay: {}", e),
+            }
+        }
+        trace!("Mock relay: channel closed; shutting down.");
+    }
+
+    /// Handle a single cell from the client.
+    async fn handle_cell(&mut self, cell: AnyChanCell) -> Result<()> {
+        let (circid, msg) = cell.into_circid_and_msg();
+        let Some(circid) = circid else {
+            // Padding and other channel-level messages: nothing to do.
+            return Ok(());
+        };
+
+        match msg {
+            AnyChanMsg::CreateFast(m) => {
+                let (keygen, reply) = CreateFastServer::server(
+                    &mut self.rng,
+                    &mut |_: &()| Some(()),
+                    &[()],
+                    m.handshake(),
+                )
+                .map_err(|e| Error::CircProto(format!("CREATE_FAST failed: {}", e)))?;
+                self.new_circ(circid, Hop::new(keygen)?)?;
+                self.send(circid, chanmsg::CreatedFast::new(reply).into())
+                    .await
+            }
+            AnyChanMsg::Create2(m) => {
+                let (hop, reply) =
+                    server_handshake(&self.keys, &mut self.rng, m.handshake_type(), m.body())?;
+                self.new_circ(circid, hop)?;
+                self.send(circid, chanmsg::Created2::new(reply).into())
+                    .await
+            }
+            AnyChanMsg::Relay(m) => {
+                self.handle_relay(circid, ChanCmd::RELAY, m.into_relay_body())
+                    .await
+            }
+            AnyChanMsg::RelayEarly(m) => {
+                self.handle_relay(circid, ChanCmd::RELAY_EARLY, m.into_relay_body())
+                    .await
+            }
+            AnyChanMsg::Destroy(_) => {
+                self.circs.remove(&circid);
+                Ok(())
+            }
+            other => {
+                trace!("Mock relay: ignoring {} cell", other.cmd());
+                Ok(())
+            }
+        }
+    }
+
+    /// Record a new circuit whose first hop is `hop`.
+    fn new_circ(&mut self, circid: CircId, hop: Hop) -> Result<()> {
+        if self.circs.contains_key(&circid) {
+            return Err(Error::ChanProto(format!(
+                "CREATE on existing circuit {}",
+                circid
+            )));
+        }
+        self.circs.insert(circid, Circ { hops: vec![hop] });
+        Ok(())
+    }
+
+    /// Handle a RELAY or RELAY_EARLY cell from the client.
+    async fn handle_relay(
+        &mut self,
+        circid: CircId,
+        cmd: ChanCmd,
+        body: BoxedCellBody,
+    ) -> Result<()> {
+        let circ = self
+            .circs
+            .get_mut(&circid)
+            .ok_or_else(|| Error::ChanProto(format!("Relay cell on unknown circuit {}", circid)))?;
+
+        // Peel off layers until some hop recognizes the cell.
+        let mut body = RelayCellBody::from(body);
+        let mut recognized = None;
+        for (idx, hop) in circ.hops.iter_mut().enumerate() {
+            if let Some(tag) = hop.fwd.decrypt_outbound(cmd, &mut body) {
+                recognized = Some((idx, tag.to_vec()));
+                break;
+            }
+        }
+        let (hopnum, tag) =
+            recognized.ok_or_else(|| Error::CircProto("Relay cell not recognized".into()))?;
+        let (streamid, msg) = AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, body.into())
+            .map_err(|e| Error::from_cell_dec(e, "relay cell"))?
+            .into_streamid_and_msg();
+
+        let mut replies: Vec<(Option<StreamId>, AnyRelayMsg)> = Vec::new();
+        match msg {
+            AnyRelayMsg::Extend2(m) => {
+                if hopnum + 1 != circ.hops.len() {
+                    return Err(Error::CircProto("EXTEND2 to a non-final hop".into()));
+                }
+                let (hop, reply) =
+                    server_handshake(&self.keys, &mut self.rng, m.handshake_type(), m.handshake())?;
+                circ.hops.push(hop);
+                replies.push((None, relaymsg::Extended2::new(reply).into()));
+            }
+            AnyRelayMsg::Begin(_) | AnyRelayMsg::BeginDir(_) => {
+                let streamid = streamid.ok_or(Error::StreamIdZero)?;
+                circ.hops[hopnum].streams.insert(streamid, 0);
+                replies.push((Some(streamid), relaymsg::Connected::new_empty().into()));
+            }
+            AnyRelayMsg::Data(m) => {
+                let hop = &mut circ.hops[hopnum];
+                hop.data_received = hop.data_received.wrapping_add(1);
+                if let Some(received) = streamid.and_then(|id| hop.streams.get_mut(&id)) {
+                    *received = received.wrapping_add(1);
+                    let stream_sendme = *received % STREAM_SENDME_INC == 0;
+                    replies.push((streamid, m.into()));
+                    if stream_sendme {
+                        replies.push((streamid, relaymsg::Sendme::new_empty().into()));
+                    }
+                }
+                if hop.data_received % CIRC_SENDME_INC == 0 {
+                    let sendme = match <[u8; 20]>::try_from(&tag[..]) {
+                        Ok(tag) => relaymsg::Sendme::new_tag(tag),
+                        Err(_) => relaymsg::Sendme::new_empty(),
+                    };
+                    replies.push((None, sendme.into()));
+                }
+            }
+            AnyRelayMsg::End(_) => {
+                if let Some(streamid) = streamid {
+                    circ.hops[hopnum].streams.remove(&streamid);
+                }
+            }
+            other => {
+                trace!("Mock relay: ignoring {} message", other.cmd());
+            }
+        }
+
+        for (streamid, msg) in replies {
+            self.send_relay(circid, hopnum, streamid, msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a relay message to the client from hop `hopnum` of a circuit.
+    async fn send_relay(
+        &mut self,
+        circid: CircId,
+        hopnum: usize,
+        streamid: Option<StreamId>,
+        msg: AnyRelayMsg,
+    ) -> Result<()> {
+        let circ = self
+            .circs
+            .get_mut(&circid)
+            .ok_or_else(|| internal!("Circuit {} vanished", circid))?;
+        let body = AnyRelayMsgOuter::new(streamid, msg)
+            .encode(RelayCellFormat::V0, &mut self.rng)
+            .map_err(|e| Error::from_cell_enc(e, "relay cell"))?;
+
+        let mut body = RelayCellBody::from(body);
+        let (earlier, rest) = circ.hops.split_at_mut(hopnum);
+        let origin = rest
+            .first_mut()
+            .ok_or_else(|| internal!("No hop {} on circuit {}", hopnum, circid))?;
+        origin.back.originate(ChanCmd::RELAY, &mut body);
+        for hop in earlier.iter_mut().rev() {
+            hop.back.encrypt_inbound(ChanCmd::RELAY, &mut body);
+        }
+
+        let body: BoxedCellBody = body.into();
+        self.send(circid, chanmsg::Relay::from(body).into()).await
+    }
+
+    /// Send a channel message to the client on `circid`.
+    async fn send(&mut self, circid: CircId, msg: super::OpenChanMsgS2C) -> Result<()> {
+        self.to_client
+            .send(Ok(OpenChanCellS2C::new(Some(circid), msg)))
+            .await
+            .map_err(|_| ChannelClosed.into())
+    }
+}
+
+/// Answer a CREATE2 or EXTEND2 handshake of type `handshake_type`.
+///
+/// On success, return the new hop and the handshake reply.
+fn server_handshake(
+    keys: &MockRelay,
+    rng: &mut TestingRng,
+    handshake_type: HandshakeType,
+    body: &[u8],
+) -> Result<(Hop, Vec<u8>)> {
+    let failed = |e: RelayHandshakeError| {
+        Error::CircProto(format!("{} handshake failed: {}", handshake_type, e))
+    };
+    match handshake_type {
+        HandshakeType::NTOR => {
+            let (keygen, reply) = NtorServer::server(
+                rng,
+                &mut |_: &()| Some(()),
+                std::slice::from_ref(&keys.ntor_key),
+                body,
+            )
+            .map_err(failed)?;
+            Ok((Hop::new(keygen)?, reply))
+        }
+        HandshakeType::NTOR_V3 => {
+            let (keygen, reply) = NtorV3Server::server(
+                rng,
+                &mut |_: &[NtorV3Extension]| Some(vec![]),
+                std::slice::from_ref(&keys.ntor_v3_key),
+                body,
+            )
+            .map_err(failed)?;
+            Ok((Hop::new(keygen)?, reply))
+        }
+        other => Err(Error::CircProto(format!(
+            "Unsupported handshake type {}",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use crate::tunnel::circuit::CircParameters;
+    use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    #[test]
+    fn three_hop_echo() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let relay = MockRelay::new();
+            let target = relay.circ_target();
+            let chan = relay.launch(&rt).unwrap();
+
+            let (pending, reactor) = chan.new_circ().await.unwrap();
+            rt.spawn(async {
+                let _ignore = reactor.run().await;
+            })
+            .unwrap();
+
+            let circ = pending
+                .create_firsthop_fast(CircParameters::default())
+                .await
+                .unwrap();
+            circ.extend_ntor(&target, CircParameters::default())
+                .await
+                .unwrap();
+            circ.extend_ntor_v3(&target, CircParameters::default())
+                .await
+                .unwrap();
+            assert_eq!(circ.n_hops(), 3);
+
+            // Send enough data to need both stream and circuit SENDMEs.
+            let stream = circ
+                .begin_stream("www.example.com", 80, None)
+                .await
+                .unwrap();
+            let (mut r, mut w) = stream.split();
+            let msg: Vec<u8> = (0..300_000_u32).map(|n| (n % 251) as u8).collect();
+            let mut echoed = vec![0_u8; msg.len()];
+            let write = async {
+                w.write_all(&msg).await.unwrap();
+                w.flush().await.unwrap();
+            };
+            let read = async {
+                r.read_exact(&mut echoed).await.unwrap();
+            };
+            futures::join!(write, read);
+            assert_eq!(msg, echoed);
+        });
+    }
+}
@@ -42,6 +42,7 @@ use super::padding;
 /// pub struct ChannelPaddingInstructions { ... } // containing the fields as specified
 /// pub struct ChannelPaddingInstructionsUpdates { ... } // containing `Option` of each field
 /// pub fn ChannelPaddingInstructions::initial_update(&self) -> ChannelPaddingInstructionsUpdates;
+/// pub fn ChannelPaddingInstructions::update_from(&self, &Self) -> ChannelPaddingInstructionsUpdates;
 /// pub fn ChannelPaddingInstructionsUpdatesBuilder::$field(self, new_value: _) -> Self;
 /// ```
 ///
@@ -104,7 +105,20 @@ macro_rules! define_channels_insns_and_automatic_impls { { $(
         /// Used during channel startup.
         #[must_use = "initial_update makes an updates message that must be sent to have effect"]
         pub fn initial_update(&self) -> Option<ChannelPaddingInstructionsUpdates> {
-            let mut supposed = ChannelPaddingInstructions::default();
+            self.update_from(&ChannelPaddingInstructions::default())
+        }
+
+        /// Create an update message which changes a channel that is following
+        /// `current` to follow `self` instead.
+        ///
+        /// Used when the channel manager moves a channel from one set of
+        /// instructions to another.
+        #[must_use = "update_from makes an updates message that must be sent to have effect"]
+        pub fn update_from(
+            &self,
+            current: &ChannelPaddingInstructions,
+        ) -> Option<ChannelPaddingInstructionsUpdates> {
+            let mut supposed = current.clone();
 
             supposed.start_update()
               $(
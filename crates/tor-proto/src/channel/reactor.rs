@@ -7,6 +7,7 @@
 //! or in the error handling behavior.
 
 use super::circmap::{CircEnt, CircMap};
+use super::scheduler::CircScheduler;
 use super::OpenChanCellS2C;
 use crate::channel::OpenChanMsgS2C;
 use crate::tunnel::circuit::halfcirc::HalfCirc;
@@ -30,6 +31,7 @@ use oneshot_fused_workaround as oneshot;
 
 use futures::sink::SinkExt;
 use futures::stream::Stream;
+use futures::FutureExt as _;
 use futures::Sink;
 use futures::StreamExt as _;
 use futures::{select, select_biased};
@@ -45,6 +47,13 @@ use crate::channel::{
 use crate::tunnel::circuit::{celltypes::CreateResponse, CircuitRxSender};
 use tracing::{debug, trace};
 
+/// The largest number of outgoing cells that we collect in the [`CircScheduler`]
+/// before choosing which one to send.
+///
+/// Cells beyond this stay in the queue from the `Channel`, which applies
+/// backpressure to the circuits that send them.
+const MAX_SCHEDULED_CELLS: usize = 64;
+
 /// A boxed trait object that can provide `ChanCell`s.
 pub(super) type BoxedChannelStream = Box<
     dyn Stream<Item = std::result::Result<OpenChanCellS2C, CodecError>> + Send + Unpin + 'static,
@@ -134,6 +143,10 @@ pub struct Reactor<S: SleepProvider> {
     pub(super) padding_timer: Pin<Box<padding::Timer<S>>>,
     /// Outgoing cells introduced at the channel reactor
     pub(super) special_outgoing: SpecialOutgoing,
+    /// Outgoing cells from our circuits, waiting for their turn to be sent.
+    pub(super) scheduler: CircScheduler,
+    /// A time provider, used to decay the circuits' priorities in `scheduler`.
+    pub(super) sleep_prov: S,
     /// A map from circuit ID to Sinks on which we can deliver cells.
     pub(super) circs: CircMap,
     /// A unique identifier for this channel.
@@ -218,6 +231,24 @@ impl<S: SleepProvider> Reactor<S> {
                     return Some(l)
                 }
 
+                // Collect the cells that are ready right now, so that the
+                // scheduler can choose fairly between the circuits that sent them.
+                let now = self.sleep_prov.now();
+                while self.scheduler.len() < MAX_SCHEDULED_CELLS {
+                    match self.cells.next().now_or_never() {
+                        Some(Some(cell)) => self.scheduler.push(cell, now),
+                        // Nothing is ready, or the queue is closed. (In the
+                        // latter case, we'll notice below, once the scheduler
+                        // is empty.)
+                        Some(None) | None => break,
+                    }
+                }
+                if let Some(cell) = self.scheduler.pop(now) {
+                    // See reasoning below.
+                    self.padding_timer.as_mut().note_cell_sent();
+                    return Some(cell);
+                }
+
                 select_biased! {
                     n = self.cells.next() => {
                         // Note transmission on *input* to the reactor, not ultimate
@@ -323,7 +354,11 @@ impl<S: SleepProvider> Reactor<S> {
                     self.special_outgoing.padding_negotiate = Some(padding_negotiate.clone());
                 }
             }
-            CtrlMsg::KistConfigUpdate(kist) => self.apply_kist_params(&kist),
+            CtrlMsg::KistConfigUpdate(kist) => {
+                self.scheduler
+                    .set_halflife(kist.circuit_priority_halflife());
+                self.apply_kist_params(&kist);
+            }
         }
         Ok(())
     }
@@ -412,6 +447,7 @@ impl<S: SleepProvider> Reactor<S> {
 
         // Remove the circuit from the map: nothing more can be done with it.
         let entry = self.circs.remove(circid);
+        self.scheduler.forget(circid);
         self.update_disused_since();
         match entry {
             // If the circuit is waiting for CREATED, tell it that it
@@ -462,6 +498,8 @@ impl<S: SleepProvider> Reactor<S> {
         // TODO: It would be great to have a tighter upper bound for
         // the number of relay cells we'll receive.
         self.circs.destroy_sent(id, HalfCirc::new(3000));
+        // Any cells that the circuit still had waiting are no use now.
+        self.scheduler.forget(id);
         self.update_disused_since();
         let destroy = Destroy::new(DestroyReason::NONE).into();
         let cell = AnyChanCell::new(Some(id), destroy);
@@ -0,0 +1,285 @@
+//! Choosing which circuit gets to send the next cell on a channel.
+//!
+//! When several circuits share a channel, the channel reactor doesn't just
+//! send their cells in the order that they arrive. Instead, it collects the
+//! cells that are ready into a [`CircScheduler`], which keeps a queue for each
+//! circuit and picks the circuit that has sent the fewest cells recently, as
+//! measured by an exponentially weighted moving average (EWMA).
+//!
+//! This favours quiet, interactive circuits over bulk transfers, as in the
+//! "EWMA" circuit scheduling of C Tor. It works together with KIST (see
+//! [`kist`](super::kist)), which limits how much data we hand to the kernel
+//! at once, so that the choice of circuit is made as late as possible.
+//!
+//! Cells that don't belong to a circuit (such as channel padding) are always
+//! sent first. Cells for a single circuit are always sent in order.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use tor_cell::chancell::{AnyChanCell, CircId};
+
+use super::kist::DEFAULT_CIRCUIT_PRIORITY_HALFLIFE;
+
+/// An exponentially decaying count of the cells sent on a circuit.
+#[derive(Debug, Clone)]
+struct Ewma {
+    /// The count, as of `last_update`.
+    count: f64,
+    /// The time at which `count` was last updated.
+    last_update: Instant,
+}
+
+impl Ewma {
+    /// Return the count as of `now`, decayed with the given `halflife`.
+    fn value_at(&self, now: Instant, halflife: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        self.count * 0.5_f64.powf(elapsed.as_secs_f64() / halflife.as_secs_f64())
+    }
+
+    /// Note that a cell was sent at `now`.
+    fn note_cell_sent(&mut self, now: Instant, halflife: Duration) {
+        self.count = self.value_at(now, halflife) + 1.0;
+        self.last_update = now;
+    }
+}
+
+/// The scheduling state of a single circuit.
+#[derive(Debug)]
+struct CircState {
+    /// The cells waiting to be sent on this circuit, in order.
+    cells: VecDeque<AnyChanCell>,
+    /// How many cells this circuit has sent recently.
+    ewma: Ewma,
+}
+
+/// A queue of outgoing cells, which decides which circuit sends next.
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub(super) struct CircScheduler {
+    /// How quickly a circuit's cell count decays.
+    halflife: Duration,
+    /// Cells that don't belong to any circuit.
+    chan_cells: VecDeque<AnyChanCell>,
+    /// The state of every circuit that we have scheduled.
+    circs: HashMap<CircId, CircState>,
+    /// The circuits that have cells waiting.
+    ///
+    /// When two circuits have the same priority, the one nearer the front
+    /// goes first; a circuit that has just sent moves to the back.
+    active: VecDeque<CircId>,
+    /// The total number of cells waiting.
+    n_queued: usize,
+}
+
+impl Default for CircScheduler {
+    fn default() -> Self {
+        Self::new(DEFAULT_CIRCUIT_PRIORITY_HALFLIFE)
+    }
+}
+
+impl CircScheduler {
+    /// Create a new, empty scheduler, with the given EWMA `halflife`.
+    pub(super) fn new(halflife: Duration) -> Self {
+        Self {
+            halflife,
+            chan_cells: VecDeque::new(),
+            circs: HashMap::new(),
+            active: VecDeque::new(),
+            n_queued: 0,
+        }
+    }
+
+    /// Change the half-life of the circuits' cell counts.
+    pub(super) fn set_halflife(&mut self, halflife: Duration) {
+        self.halflife = halflife;
+    }
+
+    /// Return the number of cells waiting to be sent.
+    pub(super) fn len(&self) -> usize {
+        self.n_queued
+    }
+
+    /// Add `cell` to the queue for its circuit.
+    pub(super) fn push(&mut self, cell: AnyChanCell, now: Instant) {
+        self.n_queued += 1;
+        let Some(circid) = cell.circid() else {
+            self.chan_cells.push_back(cell);
+            return;
+        };
+        let state = self.circs.entry(circid).or_insert_with(|| CircState {
+            cells: VecDeque::new(),
+            ewma: Ewma {
+                count: 0.0,
+                last_update: now,
+            },
+        });
+        if state.cells.is_empty() {
+            self.active.push_back(circid);
+        }
+        state.cells.push_back(cell);
+    }
+
+    /// Remove and return the next cell that we should send, if there is one.
+    pub(super) fn pop(&mut self, now: Instant) -> Option<AnyChanCell> {
+        if let Some(cell) = self.chan_cells.pop_front() {
+            self.n_queued -= 1;
+            return Some(cell);
+        }
+
+        // Find the active circuit with the lowest count. There are rarely many
+        // circuits with cells waiting at once, so a linear search is fine.
+        let (idx, _) = self
+            .active
+            .iter()
+            .enumerate()
+            .map(|(idx, circid)| {
+                let value = self
+                    .circs
+                    .get(circid)
+                    .map_or(0.0, |state| state.ewma.value_at(now, self.halflife));
+                (idx, value)
+            })
+            .fold(
+                None,
+                |best: Option<(usize, f64)>, (idx, value)| match best {
+                    Some((_, best_value)) if best_value <= value => best,
+                    _ => Some((idx, value)),
+                },
+            )?;
+        let circid = self.active.remove(idx)?;
+        let state = self.circs.get_mut(&circid)?;
+        let cell = state.cells.pop_front()?;
+        state.ewma.note_cell_sent(now, self.halflife);
+        if !state.cells.is_empty() {
+            self.active.push_back(circid);
+        }
+        self.n_queued -= 1;
+        Some(cell)
+    }
+
+    /// Forget about the circuit `circid`, discarding any cells still waiting
+    /// to be sent on it.
+    ///
+    /// Call this when the circuit is closed.
+    pub(super) fn forget(&mut self, circid: CircId) {
+        if let Some(state) = self.circs.remove(&circid) {
+            self.n_queued -= state.cells.len();
+            self.active.retain(|id| *id != circid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_cell::chancell::msg::{self, AnyChanMsg};
+
+    fn relay_cell(circid: u32, tag: u8) -> AnyChanCell {
+        let body = [tag; 509];
+        AnyChanCell::new(
+            CircId::new(circid),
+            AnyChanMsg::Relay(msg::Relay::new(&body[..])),
+        )
+    }
+
+    fn circ_and_tag(cell: &AnyChanCell) -> (u32, u8) {
+        let circid = cell.circid().unwrap().into();
+        let AnyChanMsg::Relay(relay) = cell.msg() else {
+            panic!("not a relay cell");
+        };
+        (circid, relay.clone().into_relay_body()[0])
+    }
+
+    #[test]
+    fn padding_first() {
+        let now = Instant::now();
+        let mut sched = CircScheduler::default();
+        sched.push(relay_cell(1, 0), now);
+        sched.push(AnyChanCell::new(None, msg::Padding::new().into()), now);
+        assert_eq!(sched.len(), 2);
+        assert!(sched.pop(now).unwrap().circid().is_none());
+        assert_eq!(circ_and_tag(&sched.pop(now).unwrap()), (1, 0));
+        assert!(sched.pop(now).is_none());
+        assert_eq!(sched.len(), 0);
+    }
+
+    #[test]
+    fn round_robin_and_order() {
+        let now = Instant::now();
+        let mut sched = CircScheduler::default();
+        for tag in 0..3 {
+            sched.push(relay_cell(1, tag), now);
+        }
+        for tag in 0..3 {
+            sched.push(relay_cell(2, tag), now);
+        }
+        let sent: Vec<_> = std::iter::from_fn(|| sched.pop(now))
+            .map(|c| circ_and_tag(&c))
+            .collect();
+        assert_eq!(sent, vec![(1, 0), (2, 0), (1, 1), (2, 1), (1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn quiet_circuit_first() {
+        let start = Instant::now();
+        let halflife = Duration::from_secs(10);
+        let mut sched = CircScheduler::new(halflife);
+
+        // Circuit 1 sends a lot.
+        for tag in 0..20 {
+            sched.push(relay_cell(1, tag), start);
+            sched.pop(start).unwrap();
+        }
+
+        // Now both circuits have cells waiting: the quiet one goes first,
+        // until it has caught up.
+        for tag in 0..3 {
+            sched.push(relay_cell(1, tag), start);
+            sched.push(relay_cell(2, tag), start);
+        }
+        let sent: Vec<_> = std::iter::from_fn(|| sched.pop(start))
+            .map(|c| circ_and_tag(&c).0)
+            .collect();
+        assert_eq!(sent, vec![2, 2, 2, 1, 1, 1]);
+
+        // After many half-lives, circuit 1's history no longer matters much:
+        // it goes ahead of circuit 2, which has just sent a few cells.
+        let later = start + halflife * 20;
+        for tag in 0..5 {
+            sched.push(relay_cell(2, tag), later);
+            sched.pop(later).unwrap();
+        }
+        sched.push(relay_cell(2, 0), later);
+        sched.push(relay_cell(1, 0), later);
+        sched.push(relay_cell(2, 0), later);
+        assert_eq!(circ_and_tag(&sched.pop(later).unwrap()).0, 1);
+    }
+
+    #[test]
+    fn forget() {
+        let now = Instant::now();
+        let mut sched = CircScheduler::default();
+        sched.push(relay_cell(1, 0), now);
+        sched.push(relay_cell(1, 1), now);
+        sched.push(relay_cell(2, 0), now);
+        sched.forget(CircId::new(1).unwrap());
+        assert_eq!(sched.len(), 1);
+        assert_eq!(circ_and_tag(&sched.pop(now).unwrap()), (2, 0));
+        assert!(sched.pop(now).is_none());
+    }
+}
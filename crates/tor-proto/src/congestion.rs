@@ -27,12 +27,12 @@ mod rtt;
 pub(crate) mod sendme;
 mod vegas;
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::{Error, Result};
 
 use self::{
-    params::{Algorithm, CongestionControlParams, CongestionWindowParams},
+    params::{Algorithm, AlgorithmType, CongestionControlParams, CongestionWindowParams},
     rtt::RoundtripTimeEstimator,
     sendme::{CircTag, SendmeValidator},
 };
@@ -54,6 +54,10 @@ pub(crate) trait CongestionControlAlgorithm: Send + std::fmt::Debug {
     /// Return the congestion window object. The reason is returns an Option is because not all
     /// algorithm uses one and so we avoid acting on it if so.
     fn cwnd(&self) -> Option<&CongestionWindow>;
+    /// Return the type of this algorithm, as negotiated with the hop.
+    fn algorithm_type(&self) -> AlgorithmType;
+    /// Return the number of cells in flight, if this algorithm keeps track of it.
+    fn inflight(&self) -> Option<u32>;
 
     /// Inform the algorithm that we just got a DATA cell.
     ///
@@ -245,6 +249,68 @@ impl CongestionWindow {
     }
 }
 
+/// A snapshot of the congestion control state of a single circuit hop.
+///
+/// This is meant for diagnostics only: the values can change as soon as the next cell is sent or
+/// received on the circuit.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CongestionControlStats {
+    /// The congestion control algorithm in use on this hop.
+    algorithm: AlgorithmType,
+    /// The current congestion window, in cells, if the algorithm uses one.
+    cwnd: Option<u32>,
+    /// The number of cells sent but not yet acknowledged by a SENDME, if known.
+    inflight: Option<u32>,
+    /// Whether we are still in slow start.
+    in_slow_start: bool,
+    /// The smoothed RTT estimate, if we have one.
+    ewma_rtt: Option<Duration>,
+    /// The minimum observed RTT, if we have one.
+    min_rtt: Option<Duration>,
+}
+
+impl CongestionControlStats {
+    /// Return the congestion control algorithm in use.
+    pub fn algorithm(&self) -> AlgorithmType {
+        self.algorithm
+    }
+
+    /// Return the congestion window in cells.
+    ///
+    /// This is `None` for algorithms that don't use a congestion window, such as the fixed
+    /// window algorithm.
+    pub fn cwnd(&self) -> Option<u32> {
+        self.cwnd
+    }
+
+    /// Return the number of cells in flight, that is sent but not yet acknowledged.
+    pub fn inflight(&self) -> Option<u32> {
+        self.inflight
+    }
+
+    /// Return true if the algorithm is still in slow start.
+    pub fn in_slow_start(&self) -> bool {
+        self.in_slow_start
+    }
+
+    /// Return the smoothed (EWMA) round trip time estimate.
+    ///
+    /// This is `None` if no estimate is available yet, or if the algorithm doesn't measure
+    /// the RTT.
+    pub fn ewma_rtt(&self) -> Option<Duration> {
+        self.ewma_rtt
+    }
+
+    /// Return the minimum round trip time observed so far.
+    ///
+    /// This is `None` if no estimate is available yet, or if the algorithm doesn't measure
+    /// the RTT.
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+}
+
 /// Congestion control state of a hop on a circuit.
 ///
 /// This controls the entire logic of congestion control and circuit level SENDMEs.
@@ -292,6 +358,19 @@ impl CongestionControl {
         self.algorithm.can_send()
     }
 
+    /// Return a snapshot of the congestion control state, for diagnostics.
+    pub(crate) fn stats(&self) -> CongestionControlStats {
+        let rtt_ready = self.algorithm.cwnd().is_some() && self.rtt.is_ready();
+        CongestionControlStats {
+            algorithm: self.algorithm.algorithm_type(),
+            cwnd: self.algorithm.cwnd().map(CongestionWindow::get),
+            inflight: self.algorithm.inflight(),
+            in_slow_start: self.state.in_slow_start(),
+            ewma_rtt: rtt_ready.then(|| self.rtt.ewma_rtt()),
+            min_rtt: rtt_ready.then(|| self.rtt.min_rtt()),
+        }
+    }
+
     /// Called when a SENDME cell is received.
     ///
     /// An error is returned if there is a protocol violation with regards to congestion control.
@@ -374,8 +453,12 @@ mod test {
     #![allow(clippy::needless_pass_by_value)]
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
 
-    use crate::congestion::test_utils::new_cwnd;
+    use crate::congestion::test_utils::{
+        new_cwnd,
+        params::{build_cc_fixed_params, build_cc_vegas_params},
+    };
 
+    use super::params::AlgorithmType;
     use super::sendme::CircTag;
     use super::CongestionControl;
 
@@ -411,4 +494,25 @@ mod test {
         cwnd.dec();
         assert_eq!(cwnd.get(), cwnd.params().cwnd_init());
     }
+
+    #[test]
+    fn stats() {
+        let params = build_cc_vegas_params();
+        let cc = CongestionControl::new(&params);
+        let stats = cc.stats();
+        assert_eq!(stats.algorithm(), AlgorithmType::VEGAS);
+        assert_eq!(stats.cwnd(), Some(params.cwnd_params().cwnd_init()));
+        assert_eq!(stats.inflight(), Some(0));
+        assert!(stats.in_slow_start());
+        // No SENDME received yet, so no RTT estimate.
+        assert_eq!(stats.ewma_rtt(), None);
+        assert_eq!(stats.min_rtt(), None);
+
+        let cc = CongestionControl::new(&build_cc_fixed_params());
+        let stats = cc.stats();
+        assert_eq!(stats.algorithm(), AlgorithmType::FIXED_WINDOW);
+        assert_eq!(stats.cwnd(), None);
+        assert_eq!(stats.inflight(), None);
+        assert_eq!(stats.ewma_rtt(), None);
+    }
 }
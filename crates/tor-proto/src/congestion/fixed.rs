@@ -7,6 +7,7 @@
 use crate::Result;
 
 use super::{
+    params::AlgorithmType,
     rtt::RoundtripTimeEstimator,
     sendme::{self, WindowParams},
     CongestionControlAlgorithm, CongestionSignals, CongestionWindow, State,
@@ -51,6 +52,14 @@ impl CongestionControlAlgorithm for FixedWindow {
         None
     }
 
+    fn algorithm_type(&self) -> AlgorithmType {
+        AlgorithmType::FIXED_WINDOW
+    }
+
+    fn inflight(&self) -> Option<u32> {
+        None
+    }
+
     fn sendme_received(
         &mut self,
         _state: &mut State,
@@ -95,6 +95,16 @@ impl RoundtripTimeEstimator {
         u32::try_from(self.min_rtt.as_micros()).unwrap_or(u32::MAX)
     }
 
+    /// Return the current smoothed RTT estimate.
+    pub(crate) fn ewma_rtt(&self) -> Duration {
+        self.ewma_rtt
+    }
+
+    /// Return the minimum observed RTT.
+    pub(crate) fn min_rtt(&self) -> Duration {
+        self.min_rtt
+    }
+
     /// Inform the estimator that we did (at time `now`) something that we'll expect a SENDME to
     /// be received for.
     pub(crate) fn expect_sendme(&mut self, now: Instant) {
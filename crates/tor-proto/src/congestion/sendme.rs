@@ -39,13 +39,13 @@ impl From<[u8; SENDME_TAG_LEN]> for CircTag {
 }
 impl PartialEq for CircTag {
     fn eq(&self, other: &Self) -> bool {
-        crate::util::ct::bytes_eq(&self.0, &other.0)
+        tor_llcrypto::util::ct::bytes_eq(&self.0, &other.0)
     }
 }
 impl Eq for CircTag {}
 impl PartialEq<[u8; SENDME_TAG_LEN]> for CircTag {
     fn eq(&self, other: &[u8; SENDME_TAG_LEN]) -> bool {
-        crate::util::ct::bytes_eq(&self.0, &other[..])
+        tor_llcrypto::util::ct::bytes_eq(&self.0, &other[..])
     }
 }
 
@@ -5,8 +5,9 @@
 //! Spec: prop324 section 3.3 (TOR_VEGAS)
 
 use super::{
-    params::VegasParams, rtt::RoundtripTimeEstimator, CongestionControlAlgorithm,
-    CongestionSignals, CongestionWindow, State,
+    params::{AlgorithmType, VegasParams},
+    rtt::RoundtripTimeEstimator,
+    CongestionControlAlgorithm, CongestionSignals, CongestionWindow, State,
 };
 use crate::Result;
 
@@ -133,6 +134,14 @@ impl CongestionControlAlgorithm for Vegas {
         Some(&self.cwnd)
     }
 
+    fn algorithm_type(&self) -> AlgorithmType {
+        AlgorithmType::VEGAS
+    }
+
+    fn inflight(&self) -> Option<u32> {
+        Some(self.num_inflight)
+    }
+
     /// Called when a SENDME cell is received.
     ///
     /// This is where the Vegas algorithm magic happens entirely. For every SENDME we get, the
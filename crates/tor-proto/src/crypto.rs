@@ -5,6 +5,12 @@
 //!   * `cell` implements relay crypto as used on circuits.
 //!   * `handshake` implements the ntor handshake.
 //!   * `ll` provides building blocks for other parts of the protocol.
+//!
+//! With the `relay` feature, `relay` exposes the relay side of the
+//! relay crypto, for use by relays.
+//!
+//! With the `crypto-offload` feature, `offload` provides a pool of worker
+//! threads that circuit reactors can run their relay crypto on.
 
 #[cfg(feature = "bench")]
 pub(crate) mod bench_utils;
@@ -12,5 +18,9 @@ pub(crate) mod binding;
 pub(crate) mod cell;
 pub(crate) mod handshake;
 pub(crate) mod ll;
+#[cfg(feature = "crypto-offload")]
+pub(crate) mod offload;
+#[cfg(feature = "relay")]
+pub(crate) mod relay;
 #[cfg(test)]
 mod testing;
@@ -5,13 +5,23 @@ use crate::Result;
 use cipher::{KeyIvInit, StreamCipher};
 use digest::Digest;
 use tor_bytes::SecretBuf;
-use tor_cell::{chancell::ChanCmd, relaycell::RelayCellFormatTrait};
+use tor_cell::{
+    chancell::ChanCmd,
+    relaycell::{RelayCellFormat, RelayCellFormatTrait, RelayCellFormatV0},
+};
+use tor_error::internal;
+use tor_llcrypto::{cipher::aes::Aes256Ctr, d::Sha3_256};
 
+#[cfg(feature = "counter-galois-onion")]
+use super::cell::cgo;
 pub use super::cell::tor1::bench_utils::*;
 use super::cell::{
-    tor1::CryptStatePair, ClientLayer, CryptInit, InboundClientCrypt, InboundRelayLayer as _,
-    OutboundClientCrypt,
+    tor1::CryptStatePair, ClientLayer, CryptInit, InboundClientCrypt, InboundClientLayer,
+    InboundRelayLayer, OutboundClientCrypt, OutboundClientLayer, OutboundRelayLayer, RelayLayer,
+    Tor1RelayCrypto,
 };
+#[cfg(feature = "crypto-offload")]
+use super::offload::{CryptoOffload, OffloadLane};
 
 /// Public wrapper around the `CryptStatePair` struct.
 #[repr(transparent)]
@@ -132,3 +142,192 @@ pub fn client_encrypt(
 
     Ok(())
 }
+
+/// Public wrapper around the `OutboundClientCrypt::encrypt_batch` method
+/// for benchmarking purposes.
+pub fn client_encrypt_batch(
+    cells: &mut RelayBodyBatch,
+    cc_out: &mut OutboundCryptWrapper,
+    hop_num: u8,
+) -> Result<()> {
+    let cells = &mut cells.0;
+    cc_out
+        .0
+        .encrypt_batch(ChanCmd::RELAY, cells, hop_num.into())?;
+
+    Ok(())
+}
+
+/// A client's outbound crypto state for a circuit, assigned to one worker of a
+/// [`CryptoOffload`] pool, as a circuit reactor's would be.
+#[cfg(feature = "crypto-offload")]
+pub struct OffloadedOutboundCrypt {
+    /// The worker that runs our jobs.
+    lane: OffloadLane,
+    /// Our crypto state, when no job has it.
+    cc_out: OutboundClientCrypt,
+}
+
+#[cfg(feature = "crypto-offload")]
+impl OffloadedOutboundCrypt {
+    /// Assign `cc_out` to the next worker of `pool`.
+    pub fn new(pool: &CryptoOffload, cc_out: OutboundCryptWrapper) -> Self {
+        Self {
+            lane: pool.lane(),
+            cc_out: cc_out.0,
+        }
+    }
+
+    /// Like [`client_encrypt_batch`], but run the encryption on our worker.
+    pub async fn encrypt_batch(&mut self, cells: &mut RelayBodyBatch, hop_num: u8) -> Result<()> {
+        let mut cc_out = std::mem::replace(&mut self.cc_out, OutboundClientCrypt::new());
+        let mut batch = std::mem::take(&mut cells.0);
+        let (cc_out, batch, res) = self
+            .lane
+            .run(move || {
+                let res = cc_out
+                    .encrypt_batch(ChanCmd::RELAY, &mut batch, hop_num.into())
+                    .map(|_| ());
+                (cc_out, batch, res)
+            })
+            .await?;
+        self.cc_out = cc_out;
+        cells.0 = batch;
+        res
+    }
+}
+
+/// A relay cell encryption protocol, along with the relay cell format that
+/// it uses, for use with [`BenchCircuit`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum BenchProtocol {
+    /// The original Tor relay crypto (AES-128-CTR and SHA-1), with
+    /// `RelayCellFormat::V0`.
+    Tor1,
+    /// The onion service variant of the original relay crypto (AES-256-CTR
+    /// and SHA3-256), with `RelayCellFormat::V0`.
+    Tor1Hsv3,
+    /// Counter Galois Onion with AES-128, with `RelayCellFormat::V1`.
+    #[cfg(feature = "counter-galois-onion")]
+    Cgo,
+}
+
+impl BenchProtocol {
+    /// Return the relay cell format that this protocol uses.
+    pub fn relay_cell_format(&self) -> RelayCellFormat {
+        match self {
+            BenchProtocol::Tor1 | BenchProtocol::Tor1Hsv3 => RelayCellFormat::V0,
+            #[cfg(feature = "counter-galois-onion")]
+            BenchProtocol::Cgo => RelayCellFormat::V1,
+        }
+    }
+}
+
+/// The cryptographic state of a whole circuit: both the client's view of it,
+/// and that of every relay on it.
+///
+/// This lets a benchmark drive a cell all the way through the circuit, in
+/// either direction, including the work that the client and the relays do to
+/// originate and recognize it.
+pub struct BenchCircuit {
+    /// The client's outbound crypto state.
+    cc_out: OutboundClientCrypt,
+    /// The client's inbound crypto state.
+    cc_in: InboundClientCrypt,
+    /// Each relay's outbound crypto state, in order.
+    relay_out: Vec<Box<dyn OutboundRelayLayer + Send>>,
+    /// Each relay's inbound crypto state, in order.
+    relay_in: Vec<Box<dyn InboundRelayLayer + Send>>,
+}
+
+impl BenchCircuit {
+    /// Build a circuit that uses `protocol`, with one hop for each seed in
+    /// `seeds`.
+    pub fn new(protocol: BenchProtocol, seeds: &[SecretBuf]) -> Result<Self> {
+        let mut circ = BenchCircuit {
+            cc_out: OutboundClientCrypt::new(),
+            cc_in: InboundClientCrypt::new(),
+            relay_out: Vec::new(),
+            relay_in: Vec::new(),
+        };
+        for seed in seeds {
+            let seed = seed.clone();
+            match protocol {
+                BenchProtocol::Tor1 => {
+                    circ.add_hop::<Tor1RelayCrypto<RelayCellFormatV0>, _, _, _, _>(seed)?;
+                }
+                BenchProtocol::Tor1Hsv3 => {
+                    // (This is `Tor1Hsv3RelayCrypto`, which only exists with
+                    // the `hs-common` feature.)
+                    circ.add_hop::<CryptStatePair<Aes256Ctr, Sha3_256, RelayCellFormatV0>, _, _, _, _>(
+                        seed,
+                    )?;
+                }
+                #[cfg(feature = "counter-galois-onion")]
+                BenchProtocol::Cgo => {
+                    circ.add_hop::<cgo::CryptStatePair<aes::Aes128>, _, _, _, _>(seed)?;
+                }
+            }
+        }
+        Ok(circ)
+    }
+
+    /// Add a hop of type `L` to the end of this circuit, for both the client
+    /// and the relay.
+    fn add_hop<L, CF, CB, RF, RB>(&mut self, seed: SecretBuf) -> Result<()>
+    where
+        L: CryptInit + ClientLayer<CF, CB> + RelayLayer<RF, RB>,
+        CF: OutboundClientLayer + Send + 'static,
+        CB: InboundClientLayer + Send + 'static,
+        RF: OutboundRelayLayer + Send + 'static,
+        RB: InboundRelayLayer + Send + 'static,
+    {
+        let (fwd, back, _binding) = L::construct(KGen::new(seed.clone()))?.split_client_layer();
+        self.cc_out.add_layer(Box::new(fwd));
+        self.cc_in.add_layer(Box::new(back));
+
+        let (fwd, back, _binding) = L::construct(KGen::new(seed))?.split_relay_layer();
+        self.relay_out.push(Box::new(fwd));
+        self.relay_in.push(Box::new(back));
+        Ok(())
+    }
+
+    /// Send `cell` from the client to the relay at `hop`.
+    ///
+    /// The client encrypts the cell, and then each relay up to `hop` decrypts
+    /// it.  Returns an error if `hop` does not recognize the cell.
+    pub fn send_outbound(&mut self, cell: &mut RelayBody, hop: u8) -> Result<()> {
+        let cell = &mut cell.0;
+        self.cc_out.encrypt(ChanCmd::RELAY, cell, hop.into())?;
+        for layer in self.relay_out.iter_mut().take(usize::from(hop) + 1) {
+            if layer.decrypt_outbound(ChanCmd::RELAY, cell).is_some() {
+                return Ok(());
+            }
+        }
+        Err(internal!("Outbound cell was not recognized").into())
+    }
+
+    /// Send `cell` from the relay at `hop` to the client.
+    ///
+    /// The relay originates the cell, each earlier relay encrypts it, and
+    /// then the client decrypts it.  Returns an error if the client does not
+    /// recognize the cell as coming from `hop`.
+    pub fn send_inbound(&mut self, cell: &mut RelayBody, hop: u8) -> Result<()> {
+        let cell = &mut cell.0;
+        let hop_idx = usize::from(hop);
+        let originator = self
+            .relay_in
+            .get_mut(hop_idx)
+            .ok_or_else(|| internal!("No such hop"))?;
+        originator.originate(ChanCmd::RELAY, cell);
+        for layer in self.relay_in[..hop_idx].iter_mut().rev() {
+            layer.encrypt_inbound(ChanCmd::RELAY, cell);
+        }
+        let (from_hop, _tag) = self.cc_in.decrypt(ChanCmd::RELAY, cell)?;
+        if from_hop != hop.into() {
+            return Err(internal!("Inbound cell recognized at the wrong hop").into());
+        }
+        Ok(())
+    }
+}
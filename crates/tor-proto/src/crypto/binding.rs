@@ -1,12 +1,21 @@
 //! Types related to binding messages to specific circuits
 
+use crate::{Error, Result};
+use tor_bytes::SecretBuf;
 #[cfg(feature = "hs-service")]
 use tor_hscrypto::ops::HsMacKey;
+use tor_llcrypto::d::{Hkdf, Sha256};
 use zeroize::Zeroizing;
 
 /// Number of bytes of circuit binding material negotiated per circuit hop.
 pub(crate) const CIRC_BINDING_LEN: usize = 20;
 
+/// The HKDF salt used by [`CircuitBinding::export_key`].
+///
+/// This keeps exported keys separate from anything else that might someday be
+/// derived from a circuit binding key.
+const EXPORTER_SALT: &[u8] = b"tor-circuit-binding-exporter-v1";
+
 /// Cryptographic information used to bind a message to a specific circuit.
 ///
 /// This information is used in some of our protocols (currently only the onion
@@ -33,11 +42,15 @@ impl From<[u8; CIRC_BINDING_LEN]> for CircuitBinding {
 impl TryFrom<&[u8]> for CircuitBinding {
     type Error = crate::Error;
 
-    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let value: &[u8; CIRC_BINDING_LEN] = &value
-            .try_into()
-            .or(Err(Self::Error::InvalidKDFOutputLength))?;
-        Ok(Self::from(*value))
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        if value.len() != CIRC_BINDING_LEN {
+            return Err(Self::Error::InvalidKDFOutputLength);
+        }
+        // Copy straight into the box, so that no copy of the key is left
+        // behind on the stack.
+        let mut binding = Box::new(Zeroizing::new([0_u8; CIRC_BINDING_LEN]));
+        binding.copy_from_slice(value);
+        Ok(Self(binding))
     }
 }
 
@@ -53,6 +66,37 @@ impl CircuitBinding {
         HsMacKey::from(self.dangerously_into_bytes())
     }
 
+    /// Derive `len` bytes of key material from this circuit binding key,
+    /// for use in an application protocol.
+    ///
+    /// The output is bound to this hop of this circuit: the other side of the
+    /// hop can compute the same key, and nobody else can.
+    ///
+    /// `label` should be a fixed string that identifies the application
+    /// protocol and the purpose of the key; `context` may hold any further
+    /// per-use information. Different labels or contexts give unrelated keys.
+    /// (The output for a shorter `len` is a prefix of the output for a longer
+    /// one, so don't rely on `len` alone to separate keys.)
+    ///
+    /// This is HKDF-SHA256, with the circuit binding key as the input keying
+    /// material, and the length-prefixed `label` and `context` as the info
+    /// string. It can produce at most 8160 bytes.
+    pub fn export_key(&self, label: &[u8], context: &[u8], len: usize) -> Result<SecretBuf> {
+        let hkdf = Hkdf::<Sha256>::new(Some(EXPORTER_SALT), &(**self.0)[..]);
+
+        let mut info = Vec::with_capacity(label.len() + context.len() + 8);
+        for part in [label, context] {
+            let part_len = u32::try_from(part.len()).map_err(|_| Error::InvalidKDFOutputLength)?;
+            info.extend_from_slice(&part_len.to_be_bytes());
+            info.extend_from_slice(part);
+        }
+
+        let mut result: SecretBuf = vec![0; len].into();
+        hkdf.expand(&info, result.as_mut())
+            .map_err(|_| Error::InvalidKDFOutputLength)?;
+        Ok(result)
+    }
+
     /// Return a view of this key as a byte-slice.
     ///
     /// This is potentially dangerous, since we don't want to expose this
@@ -62,3 +106,44 @@ impl CircuitBinding {
         &(**self.0)[..]
     }
 }
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn export_key() {
+        let b1 = CircuitBinding::from([1_u8; CIRC_BINDING_LEN]);
+        let b2 = CircuitBinding::from([2_u8; CIRC_BINDING_LEN]);
+
+        let k = b1.export_key(b"example", b"ctx", 32).unwrap();
+        assert_eq!(k.len(), 32);
+        // Deterministic.
+        assert_eq!(k, b1.export_key(b"example", b"ctx", 32).unwrap());
+        // Different keys, labels, and contexts give different outputs.
+        assert_ne!(k, b2.export_key(b"example", b"ctx", 32).unwrap());
+        assert_ne!(k, b1.export_key(b"example2", b"ctx", 32).unwrap());
+        assert_ne!(k, b1.export_key(b"example", b"ctx2", 32).unwrap());
+        // The length prefix keeps the label and context apart.
+        assert_ne!(k, b1.export_key(b"examplec", b"tx", 32).unwrap());
+
+        assert!(b1.export_key(b"example", b"", 255 * 32).is_ok());
+        assert!(matches!(
+            b1.export_key(b"example", b"", 255 * 32 + 1),
+            Err(Error::InvalidKDFOutputLength)
+        ));
+    }
+}
@@ -36,14 +36,18 @@ pub(crate) mod tor1;
 
 use crate::{Error, Result};
 use derive_deftly::Deftly;
+use tor_bytes::SecretBuf;
 use tor_cell::{chancell::BoxedCellBody, chancell::ChanCmd};
+use tor_error::internal;
 use tor_memquota::derive_deftly_template_HasMemoryCost;
 
 use super::binding::CircuitBinding;
 
 /// Type for the body of a relay cell.
+///
+/// This can be converted to and from a [`BoxedCellBody`].
 #[derive(Clone, derive_more::From, derive_more::Into)]
-pub(crate) struct RelayCellBody(BoxedCellBody);
+pub struct RelayCellBody(BoxedCellBody);
 
 impl AsRef<[u8]> for RelayCellBody {
     fn as_ref(&self) -> &[u8] {
@@ -63,10 +67,120 @@ pub(crate) trait CryptInit: Sized {
     fn seed_len() -> usize;
     /// Construct this state from a seed of the appropriate length.
     fn initialize(seed: &[u8]) -> Result<Self>;
+    /// Construct this state from the key material in `keys`.
+    ///
+    /// The default implementation takes a single [`KeyComponent::Seed`] of
+    /// length [`seed_len`](Self::seed_len), and passes it to
+    /// [`initialize`](Self::initialize).  States that are made of several
+    /// independent keys should override this, and take each key separately.
+    fn initialize_from<M: KeyMaterial + ?Sized>(keys: &mut M) -> Result<Self> {
+        let seed = keys.take(KeyComponent::Seed, Self::seed_len())?;
+        Self::initialize(&seed[..])
+    }
     /// Initialize this object from a key generator.
+    ///
+    /// The key generator's output is treated as a flat seed, as described
+    /// in tor-spec 5.2.2.
     fn construct<K: super::handshake::KeyGenerator>(keygen: K) -> Result<Self> {
         let seed = keygen.expand(Self::seed_len())?;
-        Self::initialize(&seed[..])
+        Self::construct_from(FlatSeed::new(seed))
+    }
+    /// Initialize this object from the key material in `keys`, all of which
+    /// must be used.
+    fn construct_from<M: KeyMaterial>(mut keys: M) -> Result<Self> {
+        let state = Self::initialize_from(&mut keys)?;
+        keys.finish()?;
+        Ok(state)
+    }
+}
+
+/// One of the keys (or other secret values) that make up the cryptographic
+/// state for one hop of a circuit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub(crate) enum KeyComponent {
+    /// The entire seed for a state that doesn't distinguish its keys.
+    Seed,
+    /// The initial value of the digest for cells sent away from the client
+    /// (`Df` in the spec).
+    ForwardDigest,
+    /// The initial value of the digest for cells sent towards the client
+    /// (`Db` in the spec).
+    BackwardDigest,
+    /// The cipher key for cells sent away from the client (`Kf` in the spec).
+    ForwardKey,
+    /// The cipher key for cells sent towards the client (`Kb` in the spec).
+    BackwardKey,
+    /// The entire seed for the state used for cells sent away from the client.
+    ForwardSeed,
+    /// The entire seed for the state used for cells sent towards the client.
+    BackwardSeed,
+    /// The circuit binding key (`KH` in the spec).
+    Binding,
+}
+
+/// A source of key material, from which the cryptographic state for one hop of
+/// a circuit can be initialized.
+///
+/// A `CryptInit` implementation asks for each [`KeyComponent`] that it needs,
+/// in order.  This lets a handshake provide its keys in whatever form it
+/// likes: as a single flat seed (see [`FlatSeed`]), as separately derived
+/// keys, or by expanding a different key for each component.
+pub(crate) trait KeyMaterial {
+    /// Return the next `len` bytes of key material, to be used as `component`.
+    ///
+    /// Implementations should return exactly `len` bytes, or an error.
+    fn take(&mut self, component: KeyComponent, len: usize) -> Result<SecretBuf>;
+    /// Check that all of the key material has been used.
+    ///
+    /// The default implementation does nothing.
+    fn finish(self) -> Result<()>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
+}
+
+/// Key material in the form of a single flat seed.
+///
+/// Components are taken from the start of the seed, in the order that they are
+/// asked for, whatever they are.  For the `tor1` protocol, this gives the layout
+/// `Df | Db | Kf | Kb | KH` described in tor-spec 5.2.2.
+///
+/// The seed, and every component taken from it, is held in a [`SecretBuf`],
+/// so that none of it outlives its use.
+pub(crate) struct FlatSeed {
+    /// The seed.
+    seed: SecretBuf,
+    /// The number of bytes of the seed that we've used so far.
+    used: usize,
+}
+
+impl FlatSeed {
+    /// Create a new `FlatSeed` from `seed`.
+    pub(crate) fn new(seed: SecretBuf) -> Self {
+        FlatSeed { seed, used: 0 }
+    }
+}
+
+impl KeyMaterial for FlatSeed {
+    fn take(&mut self, component: KeyComponent, len: usize) -> Result<SecretBuf> {
+        let remaining = &self.seed[self.used..];
+        if len > remaining.len() {
+            return Err(internal!("seed too short for {:?}", component).into());
+        }
+        let mut taken = SecretBuf::with_capacity(len);
+        taken.extend_from_slice(&remaining[..len]);
+        self.used += len;
+        Ok(taken)
+    }
+
+    fn finish(self) -> Result<()> {
+        let left_over = self.seed.len() - self.used;
+        if left_over != 0 {
+            return Err(internal!("{} bytes of seed were left over", left_over).into());
+        }
+        Ok(())
     }
 }
 
@@ -108,6 +222,16 @@ pub(crate) trait InboundRelayLayer {
     fn originate(&mut self, cmd: ChanCmd, cell: &mut RelayCellBody) -> &[u8];
     /// Encrypt a RelayCellBody that is moving towards the client.
     fn encrypt_inbound(&mut self, cmd: ChanCmd, cell: &mut RelayCellBody);
+    /// Encrypt a batch of RelayCellBody that are moving towards the client,
+    /// in order.
+    ///
+    /// This has the same effect as calling `encrypt_inbound` on each cell
+    /// in turn, but implementations may be able to do it faster.
+    fn encrypt_inbound_batch(&mut self, cmd: ChanCmd, cells: &mut [RelayCellBody]) {
+        for cell in cells {
+            self.encrypt_inbound(cmd, cell);
+        }
+    }
 }
 
 /// Represent a relay's view of the outbound crypto state on a given circuit.
@@ -129,6 +253,15 @@ pub(crate) trait OutboundClientLayer {
     fn originate_for(&mut self, cmd: ChanCmd, cell: &mut RelayCellBody) -> &[u8];
     /// Encrypt a RelayCellBody to be decrypted by this layer.
     fn encrypt_outbound(&mut self, cmd: ChanCmd, cell: &mut RelayCellBody);
+    /// Encrypt a batch of RelayCellBody to be decrypted by this layer, in order.
+    ///
+    /// This has the same effect as calling `encrypt_outbound` on each cell
+    /// in turn, but implementations may be able to do it faster.
+    fn encrypt_outbound_batch(&mut self, cmd: ChanCmd, cells: &mut [RelayCellBody]) {
+        for cell in cells {
+            self.encrypt_outbound(cmd, cell);
+        }
+    }
 }
 
 /// A client's view of the crypto state shared with a single relay on a circuit,
@@ -246,6 +379,42 @@ impl OutboundClientCrypt {
         Ok(tag.try_into().expect("wrong SENDME digest size"))
     }
 
+    /// Prepare a batch of cell bodies to be sent away from the client.
+    ///
+    /// This has the same effect as calling [`encrypt`](Self::encrypt)
+    /// on each cell in turn, but the intermediate layers encrypt the whole
+    /// batch at once.
+    ///
+    /// On success, returns the tags that should be expected for authenticated
+    /// SENDMEs sent in response to these cells, in the same order as the cells.
+    pub(crate) fn encrypt_batch(
+        &mut self,
+        cmd: ChanCmd,
+        cells: &mut [RelayCellBody],
+        hop: HopNum,
+    ) -> Result<Vec<[u8; SENDME_TAG_LEN]>> {
+        let hop: usize = hop.into();
+        if hop >= self.layers.len() {
+            return Err(Error::NoSuchHop);
+        }
+
+        let mut layers = self.layers.iter_mut().take(hop + 1).rev();
+        let first_layer = layers.next().ok_or(Error::NoSuchHop)?;
+        let tags = cells
+            .iter_mut()
+            .map(|cell| {
+                first_layer
+                    .originate_for(cmd, cell)
+                    .try_into()
+                    .expect("wrong SENDME digest size")
+            })
+            .collect();
+        for layer in layers {
+            layer.encrypt_outbound_batch(cmd, cells);
+        }
+        Ok(tags)
+    }
+
     /// Add a new layer to this OutboundClientCrypt
     pub(crate) fn add_layer(&mut self, layer: Box<dyn OutboundClientLayer + Send>) {
         assert!(self.layers.len() < u8::MAX as usize);
@@ -16,7 +16,9 @@
 // Implementation note: For naming, I'm trying to use the symbols from the paper
 // and the spec (which should be the same) wherever possible.
 
-#![allow(dead_code)] // TODO CGO: Remove this once we actually use CGO encryption.
+// Not everything here is used in every configuration: AES-256 support, and
+// the relay side of the construction without the `relay` feature.
+#![allow(dead_code)]
 
 use aes::{Aes128, Aes256};
 use cipher::{BlockCipher, BlockDecrypt, BlockEncrypt, BlockSizeUser, StreamCipher as _};
@@ -25,10 +27,11 @@ use polyval::{universal_hash::UniversalHash, Polyval};
 use static_assertions::const_assert;
 use tor_cell::{chancell::ChanCmd, chancell::CELL_DATA_LEN};
 use tor_error::internal;
-use zeroize::Zeroizing;
+use tor_llcrypto::util::ct;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
 
-use super::{CryptInit, RelayCellBody, SENDME_TAG_LEN};
-use crate::{circuit::CircuitBinding, util::ct};
+use super::{CryptInit, FlatSeed, KeyComponent, KeyMaterial, RelayCellBody, SENDME_TAG_LEN};
+use crate::circuit::CircuitBinding;
 
 /// Size of CGO tag, in bytes.
 const CGO_TAG_LEN: usize = 16;
@@ -55,9 +58,18 @@ type Block = [u8; BLK_LEN];
 /// Helper trait to define the features we need from a block cipher,
 /// and make our "where" declarations smaller.
 ///
+/// We require `ZeroizeOnDrop` so that the key schedules in our states (and in
+/// the copies of them that we make for each keystream) are scrubbed.
+///
 /// Not sealed because it is never used outside of this crate.
 pub(crate) trait BlkCipher:
-    BlockCipher + KeyInit + BlockSizeUser<BlockSize = BlockLen> + BlockEncrypt + BlockDecrypt + Clone
+    BlockCipher
+    + KeyInit
+    + BlockSizeUser<BlockSize = BlockLen>
+    + BlockEncrypt
+    + BlockDecrypt
+    + Clone
+    + ZeroizeOnDrop
 {
     /// Length of the key used by this block cipher.
     const KEY_LEN: usize;
@@ -532,12 +544,20 @@ impl<BC: BlkCipher> CryptInit for CryptStatePair<BC> {
         if seed.len() != Self::seed_len() {
             return Err(internal!("Invalid seed length").into());
         }
+        Self::construct_from(FlatSeed::new(seed.to_vec().into()))
+    }
+    fn initialize_from<M: KeyMaterial + ?Sized>(keys: &mut M) -> crate::Result<Self> {
         let slen = CryptState::<BC>::seed_len();
-        let (outb, inb, binding) = (&seed[0..slen], &seed[slen..slen * 2], &seed[slen * 2..]);
+        let outb = keys.take(KeyComponent::ForwardSeed, slen)?;
+        let inb = keys.take(KeyComponent::BackwardSeed, slen)?;
+        let binding = keys.take(
+            KeyComponent::Binding,
+            crate::crypto::binding::CIRC_BINDING_LEN,
+        )?;
         Ok(Self {
-            outbound: CryptState::initialize(outb)?,
-            inbound: CryptState::initialize(inb)?,
-            binding: binding.try_into().expect("Invalid slice length"),
+            outbound: CryptState::initialize(&outb[..])?,
+            inbound: CryptState::initialize(&inb[..])?,
+            binding: binding[..].try_into()?,
         })
     }
 }
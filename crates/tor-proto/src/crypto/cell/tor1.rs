@@ -14,16 +14,19 @@ use crate::{circuit::CircuitBinding, crypto::binding::CIRC_BINDING_LEN, Error, R
 
 use cipher::{KeyIvInit, StreamCipher};
 use digest::{generic_array::GenericArray, Digest};
+use tor_bytes::SecretBuf;
 use tor_cell::{
     chancell::ChanCmd,
     relaycell::{RelayCellFields, RelayCellFormatTrait},
 };
 use tor_error::internal;
 use typenum::Unsigned;
+use zeroize::Zeroize as _;
 
 use super::{
-    ClientLayer, CryptInit, InboundClientLayer, InboundRelayLayer, OutboundClientLayer,
-    OutboundRelayLayer, RelayCellBody, RelayLayer, SENDME_TAG_LEN,
+    ClientLayer, CryptInit, FlatSeed, InboundClientLayer, InboundRelayLayer, KeyComponent,
+    KeyMaterial, OutboundClientLayer, OutboundRelayLayer, RelayCellBody, RelayLayer,
+    SENDME_TAG_LEN,
 };
 
 /// A CryptState represents one layer of shared cryptographic state between
@@ -39,6 +42,9 @@ use super::{
 /// It is parameterized on a stream cipher and a digest type: most circuits
 /// will use AES-128-CTR and SHA1, but v3 onion services use AES-256-CTR and
 /// SHA-3.
+///
+/// The key material in a `CryptState` is scrubbed when it is dropped:
+/// see the `Drop` implementation for details.
 pub(crate) struct CryptState<SC: StreamCipher, D: Digest + Clone, RCF: RelayCellFormatTrait> {
     /// Stream cipher for en/decrypting cell bodies.
     ///
@@ -54,6 +60,23 @@ pub(crate) struct CryptState<SC: StreamCipher, D: Digest + Clone, RCF: RelayCell
     relay_cell_format: PhantomData<RCF>,
 }
 
+impl<SC: StreamCipher, D: Digest + Clone, RCF: RelayCellFormatTrait> Drop
+    for CryptState<SC, D, RCF>
+{
+    fn drop(&mut self) {
+        // Our stream ciphers zeroize their own keys and counters on drop
+        // (the RustCrypto ones via `ZeroizeOnDrop`, and the OpenSSL ones by
+        // freeing their contexts with `EVP_CIPHER_CTX_free`).
+        //
+        // Our digests don't support `zeroize`, so as a best effort we overwrite
+        // the running digest, which was seeded with Df or Db, with a fresh one.
+        // `black_box` keeps the compiler from eliding this as a dead store.
+        self.digest = D::new();
+        std::hint::black_box(&mut self.digest);
+        self.last_digest_val.as_mut_slice().zeroize();
+    }
+}
+
 /// A pair of CryptStates shared between a client and a relay, one for the
 /// outbound (away from the client) direction, and one for the inbound
 /// (towards the client) direction.
@@ -72,7 +95,7 @@ impl<SC: StreamCipher + KeyIvInit, D: Digest + Clone, RCF: RelayCellFormatTrait>
     fn seed_len() -> usize {
         SC::KeySize::to_usize() * 2 + D::OutputSize::to_usize() * 2 + CIRC_BINDING_LEN
     }
-    fn initialize(mut seed: &[u8]) -> Result<Self> {
+    fn initialize(seed: &[u8]) -> Result<Self> {
         // This corresponds to the use of the KDF algorithm as described in
         // tor-spec 5.2.2
         if seed.len() != Self::seed_len() {
@@ -81,36 +104,45 @@ impl<SC: StreamCipher + KeyIvInit, D: Digest + Clone, RCF: RelayCellFormatTrait>
                 seed.len()
             )));
         }
-
-        // Advances `seed` by `n` bytes, returning the advanced bytes
-        let mut take_seed = |n: usize| -> &[u8] {
-            let res = &seed[..n];
-            seed = &seed[n..];
-            res
-        };
-
+        Self::construct_from(FlatSeed::new(seed.to_vec().into()))
+    }
+    fn initialize_from<M: KeyMaterial + ?Sized>(keys: &mut M) -> Result<Self> {
         let dlen = D::OutputSize::to_usize();
         let keylen = SC::KeySize::to_usize();
 
-        let df = take_seed(dlen);
-        let db = take_seed(dlen);
-        let kf = take_seed(keylen);
-        let kb = take_seed(keylen);
-        let binding_key = take_seed(CIRC_BINDING_LEN);
+        // Takes `n` bytes of key material for `component`, making sure that we
+        // got as many as we asked for.
+        let mut take_key = |component: KeyComponent, n: usize| -> Result<SecretBuf> {
+            let key = keys.take(component, n)?;
+            if key.len() != n {
+                return Err(Error::from(internal!(
+                    "{:?} had invalid length {}",
+                    component,
+                    key.len()
+                )));
+            }
+            Ok(key)
+        };
+
+        let df = take_key(KeyComponent::ForwardDigest, dlen)?;
+        let db = take_key(KeyComponent::BackwardDigest, dlen)?;
+        let kf = take_key(KeyComponent::ForwardKey, keylen)?;
+        let kb = take_key(KeyComponent::BackwardKey, keylen)?;
+        let binding_key = take_key(KeyComponent::Binding, CIRC_BINDING_LEN)?;
 
         let fwd = CryptState {
-            cipher: SC::new(kf.into(), &Default::default()),
-            digest: D::new().chain_update(df),
+            cipher: SC::new(kf[..].into(), &Default::default()),
+            digest: D::new().chain_update(&df[..]),
             last_digest_val: GenericArray::default(),
             relay_cell_format: PhantomData,
         };
         let back = CryptState {
-            cipher: SC::new(kb.into(), &Default::default()),
-            digest: D::new().chain_update(db),
+            cipher: SC::new(kb[..].into(), &Default::default()),
+            digest: D::new().chain_update(&db[..]),
             last_digest_val: GenericArray::default(),
             relay_cell_format: PhantomData,
         };
-        let binding = CircuitBinding::try_from(binding_key)?;
+        let binding = CircuitBinding::try_from(&binding_key[..])?;
 
         Ok(CryptStatePair { fwd, back, binding })
     }
@@ -289,7 +321,7 @@ impl RelayCellBody {
         d: &mut D,
         rcvd: &mut GenericArray<u8, D::OutputSize>,
     ) -> bool {
-        use crate::util::ct;
+        use tor_llcrypto::util::ct;
 
         // Validate 'Recognized' field
         if !ct::is_zero(self.recognized::<RCF>()) {
@@ -359,6 +391,15 @@ pub(crate) mod bench_utils {
             self.0.is_recognized::<D, RCF>(d, rcvd)
         }
     }
+
+    /// Public wrapper around a batch of `RelayCellBody`s.
+    pub struct RelayBodyBatch(pub(in crate::crypto) Vec<RelayCellBody>);
+
+    impl FromIterator<[u8; 509]> for RelayBodyBatch {
+        fn from_iter<I: IntoIterator<Item = [u8; 509]>>(iter: I) -> Self {
+            Self(iter.into_iter().map(|body| Box::new(body).into()).collect())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -438,4 +479,130 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn batch() {
+        use crate::crypto::handshake::ShakeKeyGenerator as KGen;
+        use rand::RngCore as _;
+        use tor_basic_utils::test_rng::testing_rng;
+
+        let seeds: [&[u8]; 3] = [b"one", b"two", b"three"];
+        let mut rng = testing_rng();
+        let cmd = ChanCmd::RELAY;
+
+        let make_crypt = || {
+            let mut cc_out = OutboundClientCrypt::new();
+            let mut cc_in = InboundClientCrypt::new();
+            for seed in seeds {
+                let pair = Tor1RelayCrypto::construct(KGen::new(seed.to_vec().into())).unwrap();
+                add_layers(&mut cc_out, &mut cc_in, pair);
+            }
+            cc_out
+        };
+        let mut cc_one = make_crypt();
+        let mut cc_batch = make_crypt();
+
+        // Use uneven batch sizes, including an empty one.
+        for n in [1, 3, 7, 0, 2] {
+            let cells: Vec<RelayCellBody> = (0..n)
+                .map(|_| {
+                    let mut body = Box::new([0_u8; 509]);
+                    rng.fill_bytes(&mut body[..]);
+                    body.into()
+                })
+                .collect();
+
+            let mut expected = cells.clone();
+            let expected_tags: Vec<_> = expected
+                .iter_mut()
+                .map(|cell| *cc_one.encrypt(cmd, cell, 2.into()).unwrap())
+                .collect();
+
+            let mut batch = cells;
+            let tags = cc_batch.encrypt_batch(cmd, &mut batch, 2.into()).unwrap();
+            assert_eq!(tags, expected_tags);
+            for (a, b) in batch.iter().zip(expected.iter()) {
+                assert_eq!(a.as_ref(), b.as_ref());
+            }
+        }
+
+        // Relay side, inbound direction.
+        let mut r_one =
+            Tor1RelayCrypto::<RelayCellFormatV0>::construct(KGen::new(b"four".to_vec().into()))
+                .unwrap();
+        let mut r_batch =
+            Tor1RelayCrypto::<RelayCellFormatV0>::construct(KGen::new(b"four".to_vec().into()))
+                .unwrap();
+        let mut cells: Vec<RelayCellBody> = (0..5)
+            .map(|_| {
+                let mut body = Box::new([0_u8; 509]);
+                rng.fill_bytes(&mut body[..]);
+                body.into()
+            })
+            .collect();
+        let mut expected = cells.clone();
+        for cell in expected.iter_mut() {
+            r_one.encrypt_inbound(cmd, cell);
+        }
+        r_batch.encrypt_inbound_batch(cmd, &mut cells);
+        for (a, b) in cells.iter().zip(expected.iter()) {
+            assert_eq!(a.as_ref(), b.as_ref());
+        }
+    }
+
+    #[test]
+    fn structured_keys() {
+        use crate::crypto::cell::{FlatSeed, KeyComponent, KeyMaterial};
+        use std::collections::HashMap;
+
+        /// Key material that hands out separately provided keys, in any order.
+        struct Structured(HashMap<KeyComponent, SecretBuf>);
+        impl KeyMaterial for Structured {
+            fn take(&mut self, component: KeyComponent, _len: usize) -> Result<SecretBuf> {
+                self.0
+                    .remove(&component)
+                    .ok_or_else(|| internal!("missing {:?}", component).into())
+            }
+        }
+
+        const K: &[u8; 92] =
+            b"    'My public key is in this signed x509 object', said Tom assertively.      (N-PREG-VIRYL)";
+        let structured = |kf_len: usize| {
+            let part = |r: std::ops::Range<usize>| SecretBuf::from(K[r].to_vec());
+            Structured(HashMap::from([
+                (KeyComponent::ForwardDigest, part(0..20)),
+                (KeyComponent::BackwardDigest, part(20..40)),
+                (KeyComponent::ForwardKey, part(40..40 + kf_len)),
+                (KeyComponent::BackwardKey, part(56..72)),
+                (KeyComponent::Binding, part(72..92)),
+            ]))
+        };
+
+        let cmd = ChanCmd::RELAY;
+        let mut flat =
+            Tor1RelayCrypto::<RelayCellFormatV0>::construct_from(FlatSeed::new(K.to_vec().into()))
+                .unwrap();
+        let mut split =
+            Tor1RelayCrypto::<RelayCellFormatV0>::construct_from(structured(16)).unwrap();
+        let mut cell1: RelayCellBody = Box::new([7_u8; 509]).into();
+        let mut cell2 = cell1.clone();
+        flat.originate(cmd, &mut cell1);
+        split.originate(cmd, &mut cell2);
+        assert_eq!(cell1.as_ref(), cell2.as_ref());
+
+        // Keys of the wrong length are rejected.
+        assert!(Tor1RelayCrypto::<RelayCellFormatV0>::construct_from(structured(15)).is_err());
+        // So are seeds that are too short or too long.
+        assert!(
+            Tor1RelayCrypto::<RelayCellFormatV0>::construct_from(FlatSeed::new(
+                K[..91].to_vec().into()
+            ))
+            .is_err()
+        );
+        let long = [&K[..], b"!"].concat();
+        assert!(
+            Tor1RelayCrypto::<RelayCellFormatV0>::construct_from(FlatSeed::new(long.into()))
+                .is_err()
+        );
+    }
 }
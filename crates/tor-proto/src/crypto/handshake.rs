@@ -17,11 +17,43 @@ pub(crate) mod ntor;
 pub(crate) mod ntor_v3;
 
 use std::borrow::Borrow;
+use std::sync::{Arc, OnceLock};
 
 use crate::Result;
 //use zeroize::Zeroizing;
 use rand_core::{CryptoRng, RngCore};
 use tor_bytes::SecretBuf;
+use tor_llcrypto::pk::curve25519;
+use tor_llcrypto::pk::keypool::X25519KeyPool;
+
+/// The pool of pregenerated ephemeral keys that client handshakes use, if any.
+///
+/// Set with [`set_x25519_key_pool`].
+static X25519_KEY_POOL: OnceLock<Arc<X25519KeyPool>> = OnceLock::new();
+
+/// Make the client ntor handshakes take their ephemeral keys from `pool`.
+///
+/// When the pool is empty, or only has stale keys, the handshakes generate
+/// their keys on demand, as they do when no pool is set.
+///
+/// This is a process-wide setting, and can only be set once. Returns `false`
+/// (and does nothing) if a pool was already set.
+pub fn set_x25519_key_pool(pool: Arc<X25519KeyPool>) -> bool {
+    X25519_KEY_POOL.set(pool).is_ok()
+}
+
+/// Return a fresh ephemeral keypair for a client handshake.
+///
+/// Takes the keypair from the pool set with [`set_x25519_key_pool`] if we can,
+/// and otherwise generates it with `rng`.
+fn ephemeral_x25519_keypair<R: RngCore + CryptoRng>(rng: &mut R) -> curve25519::StaticKeypair {
+    if let Some(keypair) = X25519_KEY_POOL.get().and_then(|pool| pool.take()) {
+        return keypair;
+    }
+    let secret = curve25519::StaticSecret::random_from_rng(rng);
+    let public = curve25519::PublicKey::from(&secret);
+    curve25519::StaticKeypair { secret, public }
+}
 
 /// A ClientHandshake is used to generate a client onionskin and
 /// handle a relay onionskin.
@@ -5,8 +5,8 @@ use std::borrow::Borrow;
 
 use super::{RelayHandshakeError, RelayHandshakeResult};
 use crate::crypto::ll::kdf::{Kdf, LegacyKdf};
-use crate::util::ct::bytes_eq;
 use crate::{Error, Result};
+use tor_llcrypto::util::ct::bytes_eq;
 
 use rand::{CryptoRng, RngCore};
 use tor_bytes::SecretBuf;
@@ -3,14 +3,13 @@
 use std::borrow::Borrow;
 
 use super::{AuxDataReply, KeyGenerator, RelayHandshakeError, RelayHandshakeResult};
-use crate::util::ct;
 use crate::{Error, Result};
 use tor_bytes::{EncodeResult, Reader, SecretBuf, Writer};
 use tor_error::into_internal;
 use tor_llcrypto::d;
 use tor_llcrypto::pk::curve25519::*;
 use tor_llcrypto::pk::rsa::RsaIdentity;
-use tor_llcrypto::util::ct::ct_lookup;
+use tor_llcrypto::util::ct::{self, ct_lookup};
 
 use digest::Mac;
 use rand_core::{CryptoRng, RngCore};
@@ -140,7 +139,7 @@ impl KeyGenerator for NtorHkdfKeyGenerator {
 }
 
 /// Alias for an HMAC output, used to validate correctness of a handshake.
-type Authcode = digest::CtOutput<hmac::Hmac<d::Sha256>>;
+type Authcode = digest::CtOutput<d::Hmac<d::Sha256>>;
 
 /// Perform a client handshake, generating an onionskin and a state object
 fn client_handshake_ntor_v1<R>(
@@ -150,8 +149,10 @@ fn client_handshake_ntor_v1<R>(
 where
     R: RngCore + CryptoRng,
 {
-    let my_sk = StaticSecret::random_from_rng(rng);
-    let my_public = PublicKey::from(&my_sk);
+    let StaticKeypair {
+        secret: my_sk,
+        public: my_public,
+    } = super::ephemeral_x25519_keypair(rng);
 
     client_handshake_ntor_v1_no_keygen(my_public, my_sk, relay_public)
 }
@@ -236,8 +237,7 @@ fn ntor_derive(
     secret_input.write(y)?; // Y
     secret_input.write(ntor1_protoid)?; // PROTOID
 
-    use hmac::Hmac;
-    use tor_llcrypto::d::Sha256;
+    use tor_llcrypto::d::{Hmac, Sha256};
     let verify = {
         let mut m =
             Hmac::<Sha256>::new_from_slice(ntor1_verify).expect("Hmac allows keys of any size");
@@ -15,13 +15,12 @@
 use std::borrow::Borrow;
 
 use super::{RelayHandshakeError, RelayHandshakeResult};
-use crate::util::ct;
 use crate::{Error, Result};
 use tor_bytes::{EncodeResult, Reader, SecretBuf, Writeable, Writer};
 use tor_error::into_internal;
 use tor_llcrypto::d::{Sha3_256, Shake256, Shake256Reader};
 use tor_llcrypto::pk::{curve25519, ed25519::Ed25519Identity};
-use tor_llcrypto::util::ct::ct_lookup;
+use tor_llcrypto::util::ct::{self, ct_lookup};
 
 use cipher::{KeyIvInit, StreamCipher};
 
@@ -32,6 +31,9 @@ use tor_cell::relaycell::extend::NtorV3Extension;
 use tor_llcrypto::cipher::aes::Aes256Ctr;
 use zeroize::Zeroizing;
 
+#[cfg(feature = "ntor-v3-pq")]
+pub(crate) mod pq;
+
 /// The verification string to be used for circuit extension.
 const NTOR3_CIRC_VERIFICATION: &[u8] = b"circuit extend";
 
@@ -410,19 +412,22 @@ fn client_handshake_ntor_v3<R: RngCore + CryptoRng>(
     client_msg: &[u8],
     verification: &[u8],
 ) -> EncodeResult<(NtorV3HandshakeState, Vec<u8>)> {
-    let my_sk = curve25519::StaticSecret::random_from_rng(rng);
-    client_handshake_ntor_v3_no_keygen(relay_public, client_msg, verification, my_sk)
+    let my_keypair = super::ephemeral_x25519_keypair(rng);
+    client_handshake_ntor_v3_no_keygen(relay_public, client_msg, verification, my_keypair)
 }
 
 /// As `client_handshake_ntor_v3`, but don't generate an ephemeral DH
-/// key: instead take that key an arguments `my_sk`.
+/// key: instead take that key an arguments `my_keypair`.
 fn client_handshake_ntor_v3_no_keygen(
     relay_public: &NtorV3PublicKey,
     client_msg: &[u8],
     verification: &[u8],
-    my_sk: curve25519::StaticSecret,
+    my_keypair: curve25519::StaticKeypair,
 ) -> EncodeResult<(NtorV3HandshakeState, Vec<u8>)> {
-    let my_public = curve25519::PublicKey::from(&my_sk);
+    let curve25519::StaticKeypair {
+        secret: my_sk,
+        public: my_public,
+    } = my_keypair;
     let bx = my_sk.diffie_hellman(&relay_public.pk);
 
     let (enc_key, mut mac) = kdf_msgkdf(&bx, relay_public, &my_public, verification)?;
@@ -838,6 +843,10 @@ mod test {
             pk: relay_public.clone(),
         };
 
+        let x = curve25519::StaticKeypair {
+            public: (&x).into(),
+            secret: x,
+        };
         let (state, client_handshake) =
             client_handshake_ntor_v3_no_keygen(&relay_public, &client_message, &verification, x)
                 .unwrap();
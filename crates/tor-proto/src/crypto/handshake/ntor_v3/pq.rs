@@ -0,0 +1,307 @@
+//! A hybrid post-quantum variant of the ntor v3 handshake, using X25519 and
+//! ML-KEM-768.
+//!
+//! The relay's onion key and the relay's authentication are exactly as in
+//! ntor v3.  In addition, the client generates an ephemeral ML-KEM-768 key
+//! pair for each handshake:
+//!
+//!   * The client sends its encapsulation key at the start of its (encrypted)
+//!     ntor v3 message, before its extensions.
+//!   * The relay encapsulates a fresh shared secret to that key, and sends
+//!     the ciphertext at the start of its (encrypted) ntor v3 reply, before
+//!     its extensions.
+//!
+//! Both messages are covered by the ntor v3 MACs, so the ML-KEM exchange is
+//! authenticated like the rest of the handshake.  The circuit keys are then
+//! derived from both the output of ntor v3 and the ML-KEM shared secret, so
+//! that they stay secret unless _both_ X25519 and ML-KEM are broken.  Since
+//! the ML-KEM keys are ephemeral, this gives forward secrecy against an
+//! adversary who records traffic today and gets a quantum computer later.
+//!
+//! We use a different verification string from ntor v3, so that a hybrid
+//! handshake can never be confused with a plain ntor v3 handshake.
+//!
+//! The encapsulation key and the ciphertext are too long to fit in a single
+//! cell, so this handshake can only be used in EXTEND2 messages that are sent
+//! in fragments, and not in CREATE2 cells.
+
+use std::borrow::Borrow;
+
+use super::{
+    client_handshake_ntor_v3, client_handshake_ntor_v3_part2, server_handshake_ntor_v3_no_keygen,
+    DigestWriter, Encap, NtorV3HandshakeState, NtorV3KeyGenerator, NtorV3PublicKey,
+    NtorV3SecretKey, NtorV3XofReader,
+};
+use crate::crypto::handshake::{RelayHandshakeError, RelayHandshakeResult};
+use crate::{Error, Result};
+
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, EncodedSizeUser, KemCore, MlKem768};
+use rand_core::{CryptoRng, RngCore};
+use tor_bytes::{EncodeResult, SecretBuf, Writer};
+use tor_cell::relaycell::extend::NtorV3Extension;
+use tor_error::{internal, into_internal};
+use tor_llcrypto::d::Shake256;
+use tor_llcrypto::pk::curve25519;
+use tor_llcrypto::util::rng::RngCompat;
+use zeroize::Zeroizing;
+
+/// The verification string to be used for circuit extension.
+const NTOR3_PQ_CIRC_VERIFICATION: &[u8] = b"circuit extend x25519mlkem768";
+
+/// Tweak used to derive the final circuit keys from the ntor v3 keys and the
+/// ML-KEM shared secret.
+const T_HYBRID_KDF: Encap<'static> = Encap(b"ntor3-x25519mlkem768-sha3_256-1:kdf_hybrid");
+
+/// The length of the key that we take from the ntor v3 handshake, to combine
+/// with the ML-KEM shared secret.
+const NTOR_KEY_LEN: usize = 32;
+
+/// The type of our ML-KEM encapsulation key.
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+/// The type of our ML-KEM decapsulation key.
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+/// The length of an encoded ML-KEM-768 encapsulation key.
+const ENCAPSULATION_KEY_LEN: usize = 1184;
+/// The length of an ML-KEM-768 ciphertext.
+const CIPHERTEXT_LEN: usize = 1088;
+
+/// Derive the final key stream for the circuit, from the key stream
+/// produced by ntor v3, the ML-KEM `shared_secret`, and the ML-KEM
+/// `ciphertext`.
+fn kdf_hybrid(
+    mut ntor_keys: NtorV3XofReader,
+    shared_secret: &[u8],
+    ciphertext: &[u8],
+) -> EncodeResult<NtorV3XofReader> {
+    use digest::{ExtendableOutput, XofReader};
+    let mut ntor_key = Zeroizing::new([0_u8; NTOR_KEY_LEN]);
+    ntor_keys.read(&mut ntor_key[..]);
+
+    // KEYS = KDF_hybrid(ntor_key | ENCAP(ss) | ENCAP(ct))
+    let mut xof = DigestWriter(Shake256::default());
+    xof.write(&T_HYBRID_KDF)?;
+    xof.write(&ntor_key[..])?;
+    xof.write(&Encap(shared_secret))?;
+    xof.write(&Encap(ciphertext))?;
+    Ok(NtorV3XofReader(xof.take().finalize_xof()))
+}
+
+/// Client side of the hybrid ntor v3 handshake.
+pub(crate) struct NtorV3PqClient;
+
+/// Client state for the hybrid ntor v3 handshake.
+pub(crate) struct NtorV3PqHandshakeState {
+    /// The state of the underlying ntor v3 handshake.
+    ntor: NtorV3HandshakeState,
+    /// Our ephemeral ML-KEM decapsulation key for this handshake.
+    decapsulation_key: DecapsulationKey,
+}
+
+impl crate::crypto::handshake::ClientHandshake for NtorV3PqClient {
+    type KeyType = NtorV3PublicKey;
+    type StateType = NtorV3PqHandshakeState;
+    type KeyGen = NtorV3KeyGenerator;
+    type ClientAuxData = [NtorV3Extension];
+    type ServerAuxData = Vec<NtorV3Extension>;
+
+    fn client1<R: RngCore + CryptoRng, M: Borrow<[NtorV3Extension]>>(
+        rng: &mut R,
+        key: &NtorV3PublicKey,
+        extensions: &M,
+    ) -> Result<(Self::StateType, Vec<u8>)> {
+        let (decapsulation_key, encapsulation_key) =
+            MlKem768::generate(&mut RngCompat::new(&mut *rng));
+
+        let mut message = Vec::new();
+        message.write_all(&encapsulation_key.as_bytes()[..]);
+        NtorV3Extension::write_many_onto(extensions.borrow(), &mut message)
+            .map_err(|e| Error::from_bytes_enc(e, "ntor3 handshake extensions"))?;
+
+        let (ntor, message) =
+            client_handshake_ntor_v3(rng, key, &message, NTOR3_PQ_CIRC_VERIFICATION).map_err(
+                into_internal!("Can't encode hybrid ntor3 client handshake."),
+            )?;
+        let state = NtorV3PqHandshakeState {
+            ntor,
+            decapsulation_key,
+        };
+        Ok((state, message))
+    }
+
+    fn client2<T: AsRef<[u8]>>(
+        state: Self::StateType,
+        msg: T,
+    ) -> Result<(Vec<NtorV3Extension>, Self::KeyGen)> {
+        let (message, reader) =
+            client_handshake_ntor_v3_part2(&state.ntor, msg.as_ref(), NTOR3_PQ_CIRC_VERIFICATION)?;
+        if message.len() < CIPHERTEXT_LEN {
+            return Err(Error::HandshakeProto(
+                "Hybrid ntor3 reply too short for an ML-KEM ciphertext".into(),
+            ));
+        }
+        let (ciphertext, extensions) = message.split_at(CIPHERTEXT_LEN);
+        let ciphertext: Ciphertext<MlKem768> = ciphertext
+            .try_into()
+            .map_err(|_| Error::from(internal!("Wrong ML-KEM ciphertext length")))?;
+        let shared_secret = state
+            .decapsulation_key
+            .decapsulate(&ciphertext)
+            .map_err(|_| Error::BadCircHandshakeAuth)?;
+        let shared_secret = Zeroizing::new(shared_secret.to_vec());
+
+        let extensions =
+            NtorV3Extension::decode(extensions).map_err(|err| Error::CellDecodeErr {
+                object: "ntor v3 extensions",
+                err,
+            })?;
+        let reader = kdf_hybrid(reader, &shared_secret, &ciphertext)
+            .map_err(into_internal!("Can't derive hybrid ntor3 keys."))?;
+
+        Ok((extensions, NtorV3KeyGenerator { reader }))
+    }
+}
+
+/// Server side of the hybrid ntor v3 handshake.
+pub(crate) struct NtorV3PqServer;
+
+impl crate::crypto::handshake::ServerHandshake for NtorV3PqServer {
+    type KeyType = NtorV3SecretKey;
+    type KeyGen = NtorV3KeyGenerator;
+    type ClientAuxData = [NtorV3Extension];
+    type ServerAuxData = Vec<NtorV3Extension>;
+
+    fn server<
+        R: RngCore + CryptoRng,
+        REPLY: crate::crypto::handshake::AuxDataReply<Self>,
+        T: AsRef<[u8]>,
+    >(
+        rng: &mut R,
+        reply_fn: &mut REPLY,
+        key: &[Self::KeyType],
+        msg: T,
+    ) -> RelayHandshakeResult<(Self::KeyGen, Vec<u8>)> {
+        let secret_key_y = curve25519::StaticSecret::random_from_rng(&mut *rng);
+
+        // The ML-KEM ciphertext that we sent, and the secret that it encapsulates.
+        let mut encapsulated: Option<(Ciphertext<MlKem768>, SecretBuf)> = None;
+        let mut bytes_reply_fn = |bytes: &[u8]| -> Option<Vec<u8>> {
+            if bytes.len() < ENCAPSULATION_KEY_LEN {
+                return None;
+            }
+            let (encapsulation_key, client_exts) = bytes.split_at(ENCAPSULATION_KEY_LEN);
+            let encapsulation_key =
+                EncapsulationKey::from_bytes(&encapsulation_key.try_into().ok()?);
+            let client_exts = NtorV3Extension::decode(client_exts).ok()?;
+            let reply_exts = reply_fn.reply(&client_exts)?;
+
+            let (ciphertext, shared_secret) = encapsulation_key
+                .encapsulate(&mut RngCompat::new(&mut *rng))
+                .ok()?;
+            let mut out = ciphertext.to_vec();
+            NtorV3Extension::write_many_onto(&reply_exts, &mut out).ok()?;
+            encapsulated = Some((ciphertext, shared_secret.to_vec().into()));
+            Some(out)
+        };
+
+        let (reply, reader) = server_handshake_ntor_v3_no_keygen(
+            &mut bytes_reply_fn,
+            &secret_key_y,
+            msg.as_ref(),
+            key,
+            NTOR3_PQ_CIRC_VERIFICATION,
+        )?;
+        let (ciphertext, shared_secret) =
+            encapsulated.ok_or(RelayHandshakeError::BadClientHandshake)?;
+        let reader = kdf_hybrid(reader, &shared_secret, &ciphertext)
+            .map_err(into_internal!("Can't derive hybrid ntor3 keys."))?;
+
+        Ok((NtorV3KeyGenerator { reader }, reply))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::crypto::handshake::ntor_v3::{NtorV3Client, NtorV3Server};
+    use crate::crypto::handshake::{ClientHandshake, KeyGenerator, ServerHandshake};
+    use tor_basic_utils::test_rng::testing_rng;
+
+    #[test]
+    fn roundtrip() {
+        let mut rng = testing_rng();
+        let relay_private = NtorV3SecretKey::generate_for_test(&mut rng);
+
+        let client_exts = vec![NtorV3Extension::RequestCongestionControl];
+        let reply_exts = vec![NtorV3Extension::AckCongestionControl { sendme_inc: 31 }];
+
+        let (c_state, c_handshake) =
+            NtorV3PqClient::client1(&mut rng, &relay_private.pk, &client_exts[..]).unwrap();
+        assert!(c_handshake.len() > ENCAPSULATION_KEY_LEN);
+
+        let mut rep = |msg: &[NtorV3Extension]| -> Option<Vec<NtorV3Extension>> {
+            assert_eq!(msg, client_exts);
+            Some(reply_exts.clone())
+        };
+        let (s_keygen, s_handshake) =
+            NtorV3PqServer::server(&mut rng, &mut rep, &[relay_private], &c_handshake).unwrap();
+        assert!(s_handshake.len() > CIPHERTEXT_LEN);
+
+        let (extensions, c_keygen) = NtorV3PqClient::client2(c_state, s_handshake).unwrap();
+        assert_eq!(extensions, reply_exts);
+
+        let c_keys = c_keygen.expand(1000).unwrap();
+        let s_keys = s_keygen.expand(100).unwrap();
+        assert_eq!(s_keys[..], c_keys[..100]);
+    }
+
+    #[test]
+    fn not_confused_with_ntor3() {
+        let mut rng = testing_rng();
+        let relay_private = NtorV3SecretKey::generate_for_test(&mut rng);
+        let mut rep = |_: &[NtorV3Extension]| Some(vec![]);
+
+        // A hybrid client message isn't accepted by a plain ntor3 server...
+        let (_, c_handshake) = NtorV3PqClient::client1(&mut rng, &relay_private.pk, &[]).unwrap();
+        let relay_keys = [NtorV3SecretKey::new(
+            relay_private.sk.clone(),
+            relay_private.pk.pk,
+            relay_private.pk.id,
+        )];
+        assert!(NtorV3Server::server(&mut rng, &mut rep, &relay_keys, &c_handshake).is_err());
+
+        // ... and a plain ntor3 client message isn't accepted by a hybrid server.
+        let (_, c_handshake) = NtorV3Client::client1(&mut rng, &relay_private.pk, &[]).unwrap();
+        assert!(NtorV3PqServer::server(&mut rng, &mut rep, &relay_keys, &c_handshake).is_err());
+    }
+
+    #[test]
+    fn bad_reply() {
+        let mut rng = testing_rng();
+        let relay_private = NtorV3SecretKey::generate_for_test(&mut rng);
+        let mut rep = |_: &[NtorV3Extension]| Some(vec![]);
+
+        let (c_state, c_handshake) =
+            NtorV3PqClient::client1(&mut rng, &relay_private.pk, &[]).unwrap();
+        let (_, mut s_handshake) =
+            NtorV3PqServer::server(&mut rng, &mut rep, &[relay_private], &c_handshake).unwrap();
+        // Tamper with the ciphertext.
+        let last = s_handshake.len() - 1;
+        s_handshake[last] ^= 1;
+        assert!(NtorV3PqClient::client2(c_state, s_handshake).is_err());
+    }
+}
@@ -17,7 +17,7 @@
 use crate::{Error, Result};
 use digest::{ExtendableOutput, Update, XofReader};
 use tor_bytes::SecretBuf;
-use tor_llcrypto::d::{Sha1, Sha256, Shake256};
+use tor_llcrypto::d::{Hkdf, Sha1, Sha256, Shake256};
 use zeroize::Zeroize;
 
 /// A trait for a key derivation function.
@@ -92,7 +92,7 @@ impl<'a, 'b> Ntor1Kdf<'a, 'b> {
 
 impl Kdf for Ntor1Kdf<'_, '_> {
     fn derive(&self, seed: &[u8], n_bytes: usize) -> Result<SecretBuf> {
-        let hkdf = hkdf::Hkdf::<Sha256>::new(Some(self.t_key), seed);
+        let hkdf = Hkdf::<Sha256>::new(Some(self.t_key), seed);
 
         let mut result: SecretBuf = vec![0; n_bytes].into();
         hkdf.expand(self.m_expand, result.as_mut())
@@ -204,7 +204,7 @@ mod test {
             .derive(input, 99)
             .unwrap();
 
-        let kdf = hkdf::Hkdf::<Sha256>::new(Some(&b"key"[..]), &input[..]);
+        let kdf = Hkdf::<Sha256>::new(Some(&b"key"[..]), &input[..]);
         let mut expect_result = vec![0_u8; 99];
         kdf.expand(&b"expand"[..], &mut expect_result[..]).unwrap();
 
@@ -0,0 +1,283 @@
+//! Offloading of relay cell cryptography onto a pool of worker threads.
+//!
+//! Normally, all of the layered encryption and decryption for a circuit
+//! happens on that circuit's reactor task.  On a busy multi-core machine,
+//! that can tie up the executor threads that every other reactor needs.
+//!
+//! A [`CryptoOffload`] is a pool of worker threads.  Once one has been set
+//! with [`set_crypto_offload`], each new circuit is assigned to a single
+//! worker, and its reactor hands its crypto state to that worker for each
+//! cell (or batch of cells) it encrypts or decrypts, awaiting the result.
+//! Since a reactor never has more than one job in flight, and each worker
+//! runs its jobs in order, every circuit's cells are still processed in
+//! order, while different circuits are handled in parallel.
+
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc, OnceLock};
+
+use derive_builder::Builder;
+use futures::channel::oneshot;
+use tor_config::{impl_standard_builder, ConfigBuildError};
+use tor_error::internal;
+
+use crate::{Error, Result};
+
+/// Configuration for a [`CryptoOffload`] pool.
+#[derive(Builder, Clone, Debug, amplify::Getters)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[non_exhaustive]
+pub struct CryptoOffloadConfig {
+    /// The number of worker threads to run.
+    ///
+    /// Defaults to the number of CPUs available to us.
+    #[builder(default = "default_n_threads()")]
+    #[getter(as_copy)]
+    n_threads: NonZeroUsize,
+}
+impl_standard_builder! { CryptoOffloadConfig: !Deserialize }
+
+/// Return the default number of worker threads.
+fn default_n_threads() -> NonZeroUsize {
+    std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+}
+
+/// The pool that new circuits offload their relay crypto to, if any.
+///
+/// Set with [`set_crypto_offload`].
+static CRYPTO_OFFLOAD: OnceLock<Arc<CryptoOffload>> = OnceLock::new();
+
+/// Make every circuit created from now on run its relay crypto on `pool`.
+///
+/// Circuits that already exist keep running their crypto on their reactors.
+///
+/// This is a process-wide setting, and can only be set once. Returns `false`
+/// (and does nothing) if a pool was already set.
+pub fn set_crypto_offload(pool: Arc<CryptoOffload>) -> bool {
+    CRYPTO_OFFLOAD.set(pool).is_ok()
+}
+
+/// Return a lane on the pool set with [`set_crypto_offload`], for a new
+/// circuit to use.
+pub(crate) fn new_circuit_lane() -> Option<OffloadLane> {
+    CRYPTO_OFFLOAD.get().map(|pool| pool.lane())
+}
+
+/// A job to run on a worker thread.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of worker threads for relay cell cryptography.
+///
+/// The threads exit once the pool, and every circuit using it, are dropped.
+pub struct CryptoOffload {
+    /// One queue for each worker thread.
+    workers: Vec<std_mpsc::Sender<Job>>,
+    /// The index of the worker to assign to the next circuit.
+    next_worker: AtomicUsize,
+}
+
+impl CryptoOffload {
+    /// Launch a new pool of worker threads, as described by `config`.
+    pub fn launch(config: &CryptoOffloadConfig) -> std::io::Result<Arc<Self>> {
+        let workers = (0..config.n_threads.get())
+            .map(|n| {
+                let (send, recv) = std_mpsc::channel::<Job>();
+                std::thread::Builder::new()
+                    .name(format!("arti-crypto-{}", n))
+                    .spawn(move || {
+                        for job in recv {
+                            // A job that panics takes down its own circuit
+                            // (which never gets its result), but not the
+                            // other circuits on this worker.
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                    })?;
+                Ok(send)
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Arc::new(CryptoOffload {
+            workers,
+            next_worker: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Return the number of worker threads in this pool.
+    pub fn n_threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Assign a worker to a new circuit, and return a lane for submitting
+    /// jobs to it.
+    ///
+    /// Workers are assigned round-robin.
+    pub(crate) fn lane(&self) -> OffloadLane {
+        let idx = self.next_worker.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        OffloadLane {
+            worker: self.workers[idx].clone(),
+        }
+    }
+}
+
+/// A handle for submitting jobs to a single worker thread.
+///
+/// Jobs submitted through the same lane are run in order.
+#[derive(Clone, Debug)]
+pub(crate) struct OffloadLane {
+    /// The queue for our worker.
+    worker: std_mpsc::Sender<Job>,
+}
+
+impl OffloadLane {
+    /// Run `f` on our worker, after every job previously submitted, and
+    /// return a future for its output.
+    ///
+    /// The job is submitted right away, not when the future is first polled.
+    /// If the job panics, the future resolves to an error.
+    pub(crate) fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> impl Future<Output = Result<T>> {
+        let (send, recv) = oneshot::channel();
+        let submitted = self.worker.send(Box::new(move || {
+            // If nobody is listening, the circuit is gone, and so are its cells.
+            let _ = send.send(f());
+        }));
+        async move {
+            submitted.map_err(|_| Error::from(internal!("crypto offload worker has exited")))?;
+            recv.await
+                .map_err(|_| Error::from(internal!("crypto offload job panicked")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use crate::crypto::cell::{
+        CryptInit as _, HopNum, OutboundClientCrypt, RelayCellBody, RelayLayer as _,
+        Tor1RelayCrypto,
+    };
+    use crate::crypto::handshake::ShakeKeyGenerator as KGen;
+    use futures::future::join_all;
+    use rand::RngCore as _;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_cell::chancell::ChanCmd;
+    use tor_cell::relaycell::RelayCellFormatV0;
+
+    /// Return a client's outbound crypto state for a three-hop circuit.
+    fn make_crypt(seed: u8) -> OutboundClientCrypt {
+        let mut cc_out = OutboundClientCrypt::new();
+        for hop in 0..3 {
+            let seed = vec![seed, hop];
+            let pair =
+                Tor1RelayCrypto::<RelayCellFormatV0>::construct(KGen::new(seed.into())).unwrap();
+            let (outbound, _, _) = pair.split_relay_layer();
+            cc_out.add_layer(Box::new(outbound));
+        }
+        cc_out
+    }
+
+    /// Return `n` random cell bodies.
+    fn random_cells(n: usize) -> Vec<RelayCellBody> {
+        let mut rng = testing_rng();
+        (0..n)
+            .map(|_| {
+                let mut body = Box::new([0_u8; 509]);
+                rng.fill_bytes(&mut body[..]);
+                body.into()
+            })
+            .collect()
+    }
+
+    /// Return a pool with `n` threads.
+    fn pool(n: usize) -> Arc<CryptoOffload> {
+        let config = CryptoOffloadConfig::builder()
+            .n_threads(NonZeroUsize::new(n).unwrap())
+            .build()
+            .unwrap();
+        CryptoOffload::launch(&config).unwrap()
+    }
+
+    #[test]
+    fn encrypt_in_order() {
+        let pool = pool(2);
+        assert_eq!(pool.n_threads(), 2);
+        let cmd = ChanCmd::RELAY;
+
+        // Use more circuits than threads, so that some of them share a worker.
+        let circuits = (0..3).map(|seed| {
+            let lane = pool.lane();
+            let mut direct = make_crypt(seed);
+            let mut offloaded = make_crypt(seed);
+            async move {
+                // Each "reactor" waits for one job before submitting the next,
+                // as the real ones do.
+                for (i, cell) in random_cells(50).into_iter().enumerate() {
+                    let hop = HopNum::from((i % 3) as u8);
+                    let mut expected = cell.clone();
+                    let tag = *direct.encrypt(cmd, &mut expected, hop).unwrap();
+                    let (crypt, got) = lane
+                        .run(move || {
+                            let mut cell = cell;
+                            let tag = *offloaded.encrypt(cmd, &mut cell, hop).unwrap();
+                            (offloaded, (cell, tag))
+                        })
+                        .await
+                        .unwrap();
+                    offloaded = crypt;
+                    assert_eq!(got.0.as_ref(), expected.as_ref());
+                    assert_eq!(got.1, tag);
+                }
+            }
+        });
+        futures::executor::block_on(join_all(circuits));
+    }
+
+    #[test]
+    fn jobs_run_in_order() {
+        let pool = pool(1);
+        let lane = pool.lane();
+
+        // Submit everything before waiting for anything.
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let jobs: Vec<_> = (0..20)
+            .map(|i| {
+                let log = Arc::clone(&log);
+                lane.run(move || log.lock().unwrap().push(i))
+            })
+            .collect();
+        for res in futures::executor::block_on(join_all(jobs)) {
+            res.unwrap();
+        }
+        assert_eq!(*log.lock().unwrap(), (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn survives_panic() {
+        let pool = pool(1);
+        let lane = pool.lane();
+
+        let failed = lane.run(|| -> u8 { panic!("oops") });
+        let ok = lane.run(|| 7_u8);
+        assert!(futures::executor::block_on(failed).is_err());
+        // The worker is still there for the next job.
+        assert_eq!(futures::executor::block_on(ok).unwrap(), 7);
+    }
+}
@@ -0,0 +1,327 @@
+//! Relay-side cryptographic state for a circuit.
+//!
+//! A relay shares a single layer of cell encryption with the client on each
+//! circuit that passes through it. In the outbound direction (away from the
+//! client), the relay decrypts each cell, and checks whether the cell is
+//! addressed to it: if it is, the relay handles the cell itself; otherwise, it
+//! passes the cell on to the next hop. In the inbound direction (towards the
+//! client), the relay either originates a cell of its own, or adds a layer of
+//! encryption to a cell that it received from the next hop.
+
+use tor_cell::chancell::ChanCmd;
+use tor_cell::relaycell::extend::NtorV3Extension;
+use tor_cell::relaycell::{RelayCellFormat, RelayCellFormatV0};
+
+use crate::crypto::binding::CircuitBinding;
+#[cfg(feature = "counter-galois-onion")]
+use crate::crypto::cell::cgo;
+use crate::crypto::cell::{
+    CryptInit, InboundRelayLayer, OutboundRelayLayer, RelayCellBody, RelayLayer, Tor1RelayCrypto,
+};
+use crate::crypto::handshake::KeyGenerator;
+#[cfg(feature = "counter-galois-onion")]
+use crate::tunnel::handshake::cgo_subproto;
+use crate::{Error, Result};
+
+/// A relay cell encryption protocol that a relay can use on a circuit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum RelayCryptProtocol {
+    /// The original Tor cell encryption protocol, using AES-128 and SHA-1.
+    ///
+    /// Reference:
+    /// - <https://spec.torproject.org/tor-spec/routing-relay-cells.html>
+    Tor1,
+    /// The Counter Galois Onion cell encryption protocol, using AES-128.
+    ///
+    /// Reference:
+    /// - <https://spec.torproject.org/proposals/359-cgo-redux.html>
+    #[cfg(feature = "counter-galois-onion")]
+    Cgo,
+}
+
+impl RelayCryptProtocol {
+    /// Return the relay cell format used with this protocol.
+    pub fn relay_cell_format(&self) -> RelayCellFormat {
+        match self {
+            RelayCryptProtocol::Tor1 => RelayCellFormat::V0,
+            #[cfg(feature = "counter-galois-onion")]
+            RelayCryptProtocol::Cgo => RelayCellFormat::V1,
+        }
+    }
+
+    /// Return the protocol that a client asked for in the extensions of its
+    /// ntor v3 handshake.
+    ///
+    /// Returns an error if the client requested a subprotocol that we don't
+    /// support: in that case, we must refuse the handshake.
+    pub fn from_ntor_v3_extensions(extensions: &[NtorV3Extension]) -> Result<Self> {
+        let mut protocol = RelayCryptProtocol::Tor1;
+        for ext in extensions {
+            let NtorV3Extension::SubprotocolRequest { protocols } = ext else {
+                continue;
+            };
+            for &subproto in protocols {
+                protocol = requested_protocol(subproto).ok_or_else(|| {
+                    Error::HandshakeProto("Client requested an unsupported subprotocol".into())
+                })?;
+            }
+        }
+        Ok(protocol)
+    }
+}
+
+/// Return the protocol that a client asks for with `subproto`, in a
+/// [`SubprotocolRequest`](NtorV3Extension::SubprotocolRequest), if we support it.
+#[cfg_attr(not(feature = "counter-galois-onion"), allow(unused_variables))]
+fn requested_protocol(subproto: (u8, u8)) -> Option<RelayCryptProtocol> {
+    #[cfg(feature = "counter-galois-onion")]
+    if subproto == cgo_subproto() {
+        return Some(RelayCryptProtocol::Cgo);
+    }
+    None
+}
+
+/// The cryptographic state that a relay shares with the client on a single
+/// circuit.
+///
+/// Use [`split`](RelayCrypt::split) to get the state for each direction.
+pub struct RelayCrypt {
+    /// The state for cells moving away from the client.
+    fwd: OutboundRelayCrypt,
+    /// The state for cells moving towards the client.
+    back: InboundRelayCrypt,
+    /// A circuit binding key for this hop.
+    binding: CircuitBinding,
+}
+
+impl RelayCrypt {
+    /// Construct the cryptographic state for a circuit using `protocol`,
+    /// from the key material produced by the circuit handshake.
+    pub fn construct(protocol: RelayCryptProtocol, keygen: impl KeyGenerator) -> Result<Self> {
+        match protocol {
+            RelayCryptProtocol::Tor1 => {
+                construct::<Tor1RelayCrypto<RelayCellFormatV0>, _, _>(keygen)
+            }
+            #[cfg(feature = "counter-galois-onion")]
+            RelayCryptProtocol::Cgo => construct::<cgo::CryptStatePair<aes::Aes128>, _, _>(keygen),
+        }
+    }
+
+    /// Consume this object, and return its outbound and inbound states,
+    /// and its circuit binding key.
+    pub fn split(self) -> (OutboundRelayCrypt, InboundRelayCrypt, CircuitBinding) {
+        (self.fwd, self.back, self.binding)
+    }
+}
+
+/// Helper: Construct a `RelayCrypt` for a layer type `L`.
+fn construct<L, F, B>(keygen: impl KeyGenerator) -> Result<RelayCrypt>
+where
+    L: CryptInit + RelayLayer<F, B>,
+    F: OutboundRelayLayer + Send + 'static,
+    B: InboundRelayLayer + Send + 'static,
+{
+    let (fwd, back, binding) = L::construct(keygen)?.split_relay_layer();
+    Ok(RelayCrypt {
+        fwd: OutboundRelayCrypt(Box::new(fwd)),
+        back: InboundRelayCrypt(Box::new(back)),
+        binding,
+    })
+}
+
+/// A relay's cryptographic state for cells moving away from the client
+/// on a single circuit.
+pub struct OutboundRelayCrypt(Box<dyn OutboundRelayLayer + Send>);
+
+impl OutboundRelayCrypt {
+    /// Decrypt a cell body that is moving away from the client.
+    ///
+    /// If the cell is addressed to us, return the authentication tag that the
+    /// client expects in an authenticated SENDME for it. Otherwise, return
+    /// `None`: the cell should be relayed to the next hop.
+    pub fn decrypt(&mut self, cmd: ChanCmd, cell: &mut RelayCellBody) -> Option<&[u8]> {
+        self.0.decrypt_outbound(cmd, cell)
+    }
+}
+
+/// A relay's cryptographic state for cells moving towards the client
+/// on a single circuit.
+pub struct InboundRelayCrypt(Box<dyn InboundRelayLayer + Send>);
+
+impl InboundRelayCrypt {
+    /// Prepare a cell body that we originated to be sent towards the client,
+    /// and encrypt it.
+    ///
+    /// Return the authentication tag that we should expect in an
+    /// authenticated SENDME from the client in response to this cell.
+    pub fn originate(&mut self, cmd: ChanCmd, cell: &mut RelayCellBody) -> &[u8] {
+        self.0.originate(cmd, cell)
+    }
+
+    /// Encrypt a cell body that we received from the next hop, and that is
+    /// moving towards the client.
+    pub fn encrypt(&mut self, cmd: ChanCmd, cell: &mut RelayCellBody) {
+        self.0.encrypt_inbound(cmd, cell);
+    }
+
+    /// Encrypt a batch of cell bodies that are moving towards the client,
+    /// in order.
+    ///
+    /// This has the same effect as calling [`encrypt`](Self::encrypt) on each
+    /// cell in turn.
+    pub fn encrypt_batch(&mut self, cmd: ChanCmd, cells: &mut [RelayCellBody]) {
+        self.0.encrypt_inbound_batch(cmd, cells);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use crate::crypto::cell::{ClientLayer, InboundClientCrypt, OutboundClientCrypt};
+    use crate::crypto::handshake::ShakeKeyGenerator as KGen;
+    use rand::RngCore;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_bytes::SecretBuf;
+
+    fn s(seed: &[u8]) -> SecretBuf {
+        seed.to_vec().into()
+    }
+
+    /// Check that a two-hop circuit built by a client from `seeds` works with
+    /// relays that use `protocol` with the same seeds.
+    fn check_circuit(
+        protocol: RelayCryptProtocol,
+        mut cc_out: OutboundClientCrypt,
+        mut cc_in: InboundClientCrypt,
+        seeds: [SecretBuf; 2],
+    ) {
+        let [seed1, seed2] = seeds;
+        let (mut r1_out, mut r1_in, _) = RelayCrypt::construct(protocol, KGen::new(seed1))
+            .unwrap()
+            .split();
+        let (mut r2_out, mut r2_in, _) = RelayCrypt::construct(protocol, KGen::new(seed2))
+            .unwrap()
+            .split();
+        let cmd = ChanCmd::RELAY;
+        let mut rng = testing_rng();
+
+        for _ in 0..50 {
+            // A cell for the second hop is relayed by the first hop.
+            let mut orig = Box::new([0_u8; 509]);
+            rng.fill_bytes(&mut orig[..]);
+            let mut cell: RelayCellBody = orig.clone().into();
+            let client_tag = cc_out.encrypt(cmd, &mut cell, 1.into()).unwrap().to_vec();
+            assert!(r1_out.decrypt(cmd, &mut cell).is_none());
+            let relay_tag = r2_out.decrypt(cmd, &mut cell).unwrap();
+            assert_eq!(relay_tag, &client_tag[..]);
+            assert_eq!(&cell.as_ref()[32..], &orig[32..]);
+
+            // A cell from the second hop is relayed towards the client.
+            let mut cell: RelayCellBody = orig.clone().into();
+            let relay_tag = r2_in.originate(cmd, &mut cell).to_vec();
+            r1_in.encrypt(cmd, &mut cell);
+            let (hop, client_tag) = cc_in.decrypt(cmd, &mut cell).unwrap();
+            assert_eq!(hop, 1.into());
+            assert_eq!(&client_tag[..], &relay_tag[..]);
+            assert_eq!(&cell.as_ref()[32..], &orig[32..]);
+        }
+    }
+
+    #[test]
+    fn tor1() {
+        let seeds = [s(b"the first hop"), s(b"the second hop")];
+        let mut cc_out = OutboundClientCrypt::new();
+        let mut cc_in = InboundClientCrypt::new();
+        for seed in &seeds {
+            let pair =
+                Tor1RelayCrypto::<RelayCellFormatV0>::construct(KGen::new(seed.clone())).unwrap();
+            let (fwd, back, _) = pair.split_client_layer();
+            cc_out.add_layer(Box::new(fwd));
+            cc_in.add_layer(Box::new(back));
+        }
+        check_circuit(RelayCryptProtocol::Tor1, cc_out, cc_in, seeds);
+    }
+
+    #[test]
+    #[cfg(feature = "counter-galois-onion")]
+    fn cgo() {
+        let seeds = [s(b"the first hop"), s(b"the second hop")];
+        let mut cc_out = OutboundClientCrypt::new();
+        let mut cc_in = InboundClientCrypt::new();
+        for seed in &seeds {
+            let pair =
+                cgo::CryptStatePair::<aes::Aes128>::construct(KGen::new(seed.clone())).unwrap();
+            let (fwd, back, _) = pair.split_client_layer();
+            cc_out.add_layer(Box::new(fwd));
+            cc_in.add_layer(Box::new(back));
+        }
+        check_circuit(RelayCryptProtocol::Cgo, cc_out, cc_in, seeds);
+    }
+
+    #[test]
+    fn negotiate() {
+        let negotiate = RelayCryptProtocol::from_ntor_v3_extensions;
+        assert_eq!(negotiate(&[]).unwrap(), RelayCryptProtocol::Tor1);
+        assert_eq!(
+            negotiate(&[NtorV3Extension::RequestCongestionControl]).unwrap(),
+            RelayCryptProtocol::Tor1
+        );
+        let unsupported = NtorV3Extension::SubprotocolRequest {
+            protocols: vec![(2, 63)],
+        };
+        assert!(negotiate(&[unsupported]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "counter-galois-onion")]
+    fn cgo_negotiated() {
+        use crate::tunnel::handshake::{cgo_subproto, HandshakeRole, RelayCryptLayerProtocol};
+        use tor_cell::relaycell::RelayCellFormat;
+
+        // The relay picks CGO when the client asks for it...
+        let request = NtorV3Extension::SubprotocolRequest {
+            protocols: vec![cgo_subproto()],
+        };
+        let protocol = RelayCryptProtocol::from_ntor_v3_extensions(&[request]).unwrap();
+        assert_eq!(protocol, RelayCryptProtocol::Cgo);
+
+        // ... and the client's layers for CGO work with the relay's.
+        let client_protocol = RelayCryptLayerProtocol::Cgo(RelayCellFormat::V1);
+        assert!(matches!(
+            client_protocol.relay_cell_format(),
+            RelayCellFormat::V1
+        ));
+        assert!(matches!(protocol.relay_cell_format(), RelayCellFormat::V1));
+        let seeds = [s(b"the first hop"), s(b"the second hop")];
+        let mut cc_out = OutboundClientCrypt::new();
+        let mut cc_in = InboundClientCrypt::new();
+        for seed in &seeds {
+            let layer = client_protocol
+                .construct_layers(HandshakeRole::Initiator, KGen::new(seed.clone()))
+                .unwrap();
+            cc_out.add_layer(layer.fwd);
+            cc_in.add_layer(layer.back);
+        }
+        check_circuit(protocol, cc_out, cc_in, seeds);
+
+        // A relay can't use the client's side of CGO.
+        assert!(client_protocol
+            .construct_layers(HandshakeRole::Responder, KGen::new(s(b"a hop")))
+            .is_err());
+    }
+}
@@ -64,7 +64,20 @@ pub use util::skew::ClockSkew;
 
 pub use channel::params::ChannelPaddingInstructions;
 pub use congestion::params as ccparams;
+pub use congestion::CongestionControlStats;
 pub use crypto::cell::{HopNum, HopNumDisplay};
+pub use crypto::handshake::set_x25519_key_pool;
+#[cfg(feature = "crypto-offload")]
+#[cfg_attr(docsrs, doc(cfg(feature = "crypto-offload")))]
+pub use crypto::offload::{
+    set_crypto_offload, CryptoOffload, CryptoOffloadConfig, CryptoOffloadConfigBuilder,
+};
+#[cfg(feature = "relay")]
+#[cfg_attr(docsrs, doc(cfg(feature = "relay")))]
+pub use crypto::{
+    cell::RelayCellBody,
+    relay::{InboundRelayCrypt, OutboundRelayCrypt, RelayCrypt, RelayCryptProtocol},
+};
 pub use tunnel::circuit;
 
 /// A Result type for this crate.
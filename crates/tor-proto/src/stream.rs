@@ -7,7 +7,7 @@
 //!
 //! # Limitations
 //!
-//! There is no fairness, rate-limiting, or flow control.
+//! There is no fairness or rate-limiting.
 
 mod cmdcheck;
 #[cfg(feature = "stream-ctrl")]
@@ -19,6 +19,8 @@ mod incoming;
 mod params;
 mod raw;
 mod resolve;
+#[cfg(feature = "experimental-udp")]
+mod udp;
 
 pub(crate) use cmdcheck::{AnyCmdChecker, CmdChecker, StreamStatus};
 pub use data::{DataReader, DataStream, DataWriter};
@@ -34,6 +36,11 @@ pub use incoming::{
 pub use params::StreamParameters;
 pub use raw::StreamReader;
 pub use resolve::ResolveStream;
+#[cfg(feature = "experimental-udp")]
+pub(crate) use udp::UdpCmdChecker;
+#[cfg(feature = "experimental-udp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "experimental-udp")))]
+pub use udp::{UdpStream, UdpStreamSender};
 pub(crate) use {data::DataCmdChecker, resolve::ResolveCmdChecker};
 
 pub use tor_cell::relaycell::msg::IpVersionPreference;
@@ -42,4 +49,4 @@ pub use tor_cell::relaycell::msg::IpVersionPreference;
 #[cfg_attr(docsrs, doc(cfg(feature = "stream-ctrl")))]
 pub use {ctrl::ClientStreamCtrl, data::ClientDataStreamCtrl};
 
-pub(crate) use flow_control::StreamSendFlowControl;
+pub(crate) use flow_control::{StreamRecvQueue, StreamSendFlowControl};
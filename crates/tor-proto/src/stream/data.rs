@@ -21,7 +21,6 @@ use tor_cell::restricted_msg;
 use std::fmt::Debug;
 use std::io::Result as IoResult;
 use std::pin::Pin;
-#[cfg(any(feature = "stream-ctrl", feature = "experimental-api"))]
 use std::sync::Arc;
 #[cfg(feature = "stream-ctrl")]
 use std::sync::{Mutex, Weak};
@@ -33,6 +32,8 @@ use crate::tunnel::circuit::ClientCirc;
 
 use crate::memquota::StreamAccount;
 use crate::stream::StreamReader;
+use crate::tunnel::circuit::traffic::TrafficCounters;
+use crate::tunnel::circuit::{StreamStats, StreamStatsHandle, UniqId};
 use crate::tunnel::StreamTarget;
 use tor_basic_utils::skip_fmt;
 use tor_cell::relaycell::msg::Data;
@@ -122,6 +123,10 @@ pub struct DataStream {
     w: DataWriter,
     /// Underlying reader for this stream
     r: DataReader,
+    /// The traffic counters for this stream, shared with the reactor.
+    traffic: Arc<TrafficCounters>,
+    /// The circuit that this stream is on.
+    circ_id: UniqId,
     /// A control object that can be used to monitor and control this stream
     /// without needing to own it.
     #[cfg(feature = "stream-ctrl")]
@@ -185,12 +190,21 @@ pub struct ClientDataStreamCtrl {
 /// If you want the stream to close earlier, use [`close`](futures::io::AsyncWriteExt::close)
 /// (or [`shutdown`](tokio_crate::io::AsyncWriteExt::shutdown) with `tokio`).
 ///
-/// Remember that Tor does not support half-open streams:
-/// If you `close` or `shutdown` a stream,
+/// Closing the writer sends an END message to the other side, but it does not
+/// close the [`DataReader`]: you can keep reading any data that the other side
+/// sent before it saw the END, until you see the end of the stream.
+///
+/// Remember, though, that the Tor protocol does not support half-open streams:
 /// the other side will not see the stream as half-open,
 /// and so will (probably) not finish sending you any in-progress data.
 /// Do not use `close`/`shutdown` to communicate anything besides
-/// "I am done using this stream."
+/// "I am done sending on this stream."
+/// (Many implementations never answer an END with an END of their own,
+/// so don't wait on the reader after closing unless you know that the
+/// other side will close the stream too.)
+///
+/// Similarly, once the other side has closed the stream, the [`DataReader`]
+/// reports the end of the stream, and any further writes will fail.
 ///
 // # Semver note
 //
@@ -376,6 +390,8 @@ impl DataStream {
     ) -> Self {
         let relay_cell_format = target.relay_cell_format();
         let out_buf_len = Data::max_body_len(relay_cell_format);
+        let traffic = Arc::clone(target.traffic());
+        let circ_id = target.circ_id();
 
         #[cfg(feature = "stream-ctrl")]
         let status = {
@@ -421,6 +437,8 @@ impl DataStream {
         DataStream {
             w,
             r,
+            traffic,
+            circ_id,
             #[cfg(feature = "stream-ctrl")]
             ctrl,
         }
@@ -465,6 +483,25 @@ impl DataStream {
     pub fn client_stream_ctrl(&self) -> Option<&Arc<ClientDataStreamCtrl>> {
         Some(&self.ctrl)
     }
+
+    /// Return a snapshot of the traffic that we have sent and received on
+    /// this stream, and the circuit that it is on.
+    ///
+    /// This counts the DATA messages that the circuit reactor has handled:
+    /// data that is still buffered in this `DataStream` counts as sent only
+    /// once it has been flushed.
+    pub fn stats(&self) -> StreamStats {
+        self.traffic.stream_stats(self.circ_id)
+    }
+
+    /// Return a handle for watching the traffic on this stream without
+    /// holding the stream itself.
+    ///
+    /// The handle's [`stats`](StreamStatsHandle::stats) returns `None` once
+    /// this stream has been dropped and closed.
+    pub fn stats_handle(&self) -> StreamStatsHandle {
+        self.traffic.stream_stats_handle(self.circ_id)
+    }
 }
 
 impl AsyncRead for DataStream {
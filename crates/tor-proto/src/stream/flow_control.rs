@@ -1,17 +1,45 @@
 //! Code for implementing flow control (stream-level).
+//!
+//! Streams on circuits without congestion control use "legacy"
+//! SENDME-window-based flow control.
+//! Streams on circuits with congestion control instead use XON/XOFF
+//! flow control, as described in proposal 324:
+//! a receiver whose reader isn't keeping up sends an XOFF
+//! to ask the sender to stop sending data,
+//! and an XON once its reader has caught up.
 
-use tor_cell::relaycell::RelayMsg;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use tor_cell::relaycell::msg::{Xoff, Xon};
+use tor_cell::relaycell::{RelayCmd, RelayMsg};
+use tor_error::internal;
+use tracing::trace;
 
 use crate::congestion::sendme;
 use crate::{Error, Result};
 
+/// The number of bytes of stream data that we let queue up for a stream's reader
+/// before we send an XOFF.
+///
+/// This is the `cc_xoff_client` default from proposal 324:
+/// 500 cells with 498 bytes of data each.
+const XOFF_THRESHOLD: usize = 500 * 498;
+
+/// Once we have sent an XOFF, the number of queued bytes at or below which
+/// we send an XON.
+const XON_THRESHOLD: usize = XOFF_THRESHOLD / 2;
+
 /// Private internals of [`StreamSendFlowControl`].
 #[derive(Debug)]
 enum StreamSendFlowControlEnum {
     /// "legacy" sendme-window-based flow control.
     WindowBased(sendme::StreamSendWindow),
     /// XON/XOFF flow control.
-    XonXoffBased,
+    XonXoffBased {
+        /// True if the other side has sent us an XOFF,
+        /// and has not sent an XON since.
+        paused: bool,
+    },
 }
 
 /// Manages outgoing flow control for a stream.
@@ -33,25 +61,25 @@ impl StreamSendFlowControl {
     }
 
     /// Returns a new xon/xoff-based [`StreamSendFlowControl`].
-    ///
-    /// **NOTE:** This isn't actually implemented yet,
-    /// and is currently a no-op congestion control.
-    // TODO(#534): remove the note above
     pub(crate) fn new_xon_xoff_based() -> Self {
         Self {
-            e: StreamSendFlowControlEnum::XonXoffBased,
+            e: StreamSendFlowControlEnum::XonXoffBased { paused: false },
         }
     }
 
+    /// Return true if this stream uses XON/XOFF flow control.
+    pub(crate) fn is_xon_xoff_based(&self) -> bool {
+        matches!(self.e, StreamSendFlowControlEnum::XonXoffBased { .. })
+    }
+
     /// Whether this stream is ready to send `msg`.
     pub(crate) fn can_send<M: RelayMsg>(&self, msg: &M) -> bool {
         match &self.e {
             StreamSendFlowControlEnum::WindowBased(w) => {
                 !sendme::cmd_counts_towards_windows(msg.cmd()) || w.window() > 0
             }
-            StreamSendFlowControlEnum::XonXoffBased => {
-                // TODO(#534): xon-based will depend on number of bytes in the body of DATA messages
-                true
+            StreamSendFlowControlEnum::XonXoffBased { paused } => {
+                !paused || msg.cmd() != RelayCmd::DATA
             }
         }
     }
@@ -74,10 +102,12 @@ impl StreamSendFlowControl {
                     Ok(())
                 }
             }
-            StreamSendFlowControlEnum::XonXoffBased => {
-                // TODO(#534): xon-based will update state based on number of bytes in the body of
-                // DATA messages
-                Ok(())
+            StreamSendFlowControlEnum::XonXoffBased { paused } => {
+                if *paused && msg.cmd() == RelayCmd::DATA {
+                    Err(internal!("Tried to send DATA on a stream after an XOFF").into())
+                } else {
+                    Ok(())
+                }
             }
         }
     }
@@ -91,11 +121,154 @@ impl StreamSendFlowControl {
     pub(crate) fn put_for_incoming_sendme(&mut self) -> Result<()> {
         match &mut self.e {
             StreamSendFlowControlEnum::WindowBased(w) => w.put(),
-            StreamSendFlowControlEnum::XonXoffBased => Err(Error::CircProto(
+            StreamSendFlowControlEnum::XonXoffBased { .. } => Err(Error::CircProto(
                 "Stream level SENDME not allowed due to congestion control".into(),
             )),
         }
     }
 
-    // TODO(#534): Add methods for handling incoming xon, xoff.
+    /// Handle an incoming XOFF.
+    ///
+    /// On failure, return an error: the caller should close the stream or
+    /// circuit with a protocol error.
+    pub(crate) fn handle_incoming_xoff(&mut self, _xoff: Xoff) -> Result<()> {
+        match &mut self.e {
+            StreamSendFlowControlEnum::WindowBased(_) => Err(Error::CircProto(
+                "XOFF not allowed without congestion control".into(),
+            )),
+            StreamSendFlowControlEnum::XonXoffBased { paused: true } => Err(Error::CircProto(
+                "Received XOFF on a stream that was already stopped".into(),
+            )),
+            StreamSendFlowControlEnum::XonXoffBased { paused } => {
+                *paused = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle an incoming XON.
+    ///
+    /// On failure, return an error: the caller should close the stream or
+    /// circuit with a protocol error.
+    pub(crate) fn handle_incoming_xon(&mut self, xon: Xon) -> Result<()> {
+        match &mut self.e {
+            StreamSendFlowControlEnum::WindowBased(_) => Err(Error::CircProto(
+                "XON not allowed without congestion control".into(),
+            )),
+            StreamSendFlowControlEnum::XonXoffBased { paused } => {
+                // TODO: Limit the rate at which we send on this stream
+                // to the advised rate.
+                trace!("Received XON with rate advice {:?}", xon.kbps_ewma());
+                *paused = false;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The amount of stream data waiting for a stream's reader.
+///
+/// This is shared between the circuit reactor, which queues messages for the
+/// reader, and the reader itself. The reactor uses it to decide when to send
+/// an XOFF, and the reader uses it to decide when to send an XON.
+///
+/// Only streams that use XON/XOFF flow control send XOFFs, and so only those
+/// streams ever send XONs.
+#[derive(Debug, Default)]
+pub(crate) struct StreamRecvQueue {
+    /// The number of bytes of DATA that have been queued for the reader,
+    /// but not yet read.
+    queued: AtomicUsize,
+    /// True if we have sent an XOFF, and not yet sent an XON.
+    xoff_sent: AtomicBool,
+}
+
+impl StreamRecvQueue {
+    /// Note that the reactor has queued `len` bytes of data for the reader.
+    ///
+    /// Return true if the caller should now send an XOFF.
+    /// This is never the case unless `xon_xoff_based` is true.
+    pub(crate) fn note_queued(&self, len: usize, xon_xoff_based: bool) -> bool {
+        let queued = self.queued.fetch_add(len, Ordering::AcqRel) + len;
+        xon_xoff_based && queued > XOFF_THRESHOLD && !self.xoff_sent.swap(true, Ordering::AcqRel)
+    }
+
+    /// Note that the reader has read `len` bytes of data.
+    ///
+    /// Return true if the caller should now send an XON.
+    pub(crate) fn note_read(&self, len: usize) -> bool {
+        let queued = self
+            .queued
+            .fetch_sub(len, Ordering::AcqRel)
+            .saturating_sub(len);
+        queued <= XON_THRESHOLD && self.xoff_sent.swap(false, Ordering::AcqRel)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_cell::relaycell::flow_ctrl::XonKbpsEwma;
+    use tor_cell::relaycell::msg::{AnyRelayMsg, Data, End};
+
+    #[test]
+    fn xon_xoff() {
+        let data = AnyRelayMsg::from(Data::new(b"hello").unwrap());
+        let end = AnyRelayMsg::from(End::new_misc());
+        let xon = || Xon::new(XonKbpsEwma::Unlimited);
+
+        let mut fc = StreamSendFlowControl::new_xon_xoff_based();
+        assert!(fc.is_xon_xoff_based());
+        assert!(fc.can_send(&data));
+        fc.take_capacity_to_send(&data).unwrap();
+        assert!(fc.put_for_incoming_sendme().is_err());
+
+        // After an XOFF, we can't send DATA, but can still send other messages.
+        fc.handle_incoming_xoff(Xoff::new()).unwrap();
+        assert!(!fc.can_send(&data));
+        assert!(fc.take_capacity_to_send(&data).is_err());
+        assert!(fc.can_send(&end));
+        fc.take_capacity_to_send(&end).unwrap();
+        assert!(fc.handle_incoming_xoff(Xoff::new()).is_err());
+
+        fc.handle_incoming_xon(xon()).unwrap();
+        assert!(fc.can_send(&data));
+
+        let mut fc = StreamSendFlowControl::new_window_based(sendme::StreamSendWindow::new(500));
+        assert!(!fc.is_xon_xoff_based());
+        assert!(fc.handle_incoming_xoff(Xoff::new()).is_err());
+        assert!(fc.handle_incoming_xon(xon()).is_err());
+    }
+
+    #[test]
+    fn recv_queue() {
+        let q = StreamRecvQueue::default();
+
+        // Streams without XON/XOFF flow control never send an XOFF.
+        assert!(!q.note_queued(XOFF_THRESHOLD + 1, false));
+        assert!(!q.note_read(XOFF_THRESHOLD + 1));
+
+        assert!(!q.note_queued(XOFF_THRESHOLD, true));
+        assert!(q.note_queued(1, true));
+        // Only one XOFF until we've sent an XON.
+        assert!(!q.note_queued(1, true));
+
+        // We don't send an XON until the queue has drained enough.
+        assert!(!q.note_read(XOFF_THRESHOLD - XON_THRESHOLD));
+        assert!(q.note_read(1));
+        assert!(!q.note_read(1));
+    }
 }
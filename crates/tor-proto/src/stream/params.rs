@@ -1,7 +1,11 @@
 //! Declares a type to configure new streams.
 
+use std::sync::Arc;
+
 use tor_cell::relaycell::msg::{BeginFlags, IpVersionPreference};
 
+use crate::tunnel::circuit::{StreamCloseHook, TrafficTotals};
+
 /// A set of preferences used to declare how a new stream should be opened.
 #[derive(Clone, Debug, Default)]
 pub struct StreamParameters {
@@ -13,6 +17,13 @@ pub struct StreamParameters {
     suppress_hostname: bool,
     /// True if we are suppressing flags.
     suppress_begin_flags: bool,
+    /// Totals that the stream's traffic should count towards, if any.
+    traffic_totals: Option<Arc<TrafficTotals>>,
+    /// A hook to call with the stream's final traffic, once it has closed.
+    close_hook: Option<StreamCloseHook>,
+    /// True if the circuit padder should not see the cells that this
+    /// stream sends.
+    hide_from_padding: bool,
 }
 
 impl StreamParameters {
@@ -70,6 +81,33 @@ impl StreamParameters {
         self
     }
 
+    /// Configure the stream's traffic to count towards `totals`, as well as
+    /// towards the stream's own [`StreamStats`](crate::circuit::StreamStats).
+    pub fn count_traffic_in(&mut self, totals: Arc<TrafficTotals>) -> &mut Self {
+        self.traffic_totals = Some(totals);
+        self
+    }
+
+    /// Configure the stream to call `hook` with its final
+    /// [`StreamStats`](crate::circuit::StreamStats), once it has closed.
+    ///
+    /// Replaces any hook that was configured before.
+    pub fn on_close(&mut self, hook: StreamCloseHook) -> &mut Self {
+        self.close_hook = Some(hook);
+        self
+    }
+
+    /// Configure whether the cells that this stream sends count as traffic
+    /// for the circuit's padding machines.
+    ///
+    /// By default they do.  A stream that sends traffic which isn't worth
+    /// hiding, such as periodic keepalive probes, can turn this off so that
+    /// its traffic doesn't make the circuit send padding of its own.
+    pub fn circuit_padding(&mut self, enabled: bool) -> &mut Self {
+        self.hide_from_padding = !enabled;
+        self
+    }
+
     /// Crate-internal: Return true if the stream is optimistic.
     pub(crate) fn is_optimistic(&self) -> bool {
         self.optimistic
@@ -88,4 +126,21 @@ impl StreamParameters {
     pub(crate) fn suppressing_hostname(&self) -> bool {
         self.suppress_hostname
     }
+
+    /// Crate-internal: Return the totals that the stream's traffic should
+    /// count towards, if any.
+    pub(crate) fn traffic_totals(&self) -> Option<Arc<TrafficTotals>> {
+        self.traffic_totals.clone()
+    }
+
+    /// Crate-internal: Return the hook to call when the stream closes, if any.
+    pub(crate) fn close_hook(&self) -> Option<StreamCloseHook> {
+        self.close_hook.clone()
+    }
+
+    /// Crate-internal: Return true if the circuit padder should not see the
+    /// cells that this stream sends.
+    pub(crate) fn hidden_from_padding(&self) -> bool {
+        self.hide_from_padding
+    }
 }
@@ -2,12 +2,14 @@
 //! cells.
 
 use crate::congestion::sendme;
+use crate::stream::StreamRecvQueue;
 use crate::tunnel::StreamTarget;
 use crate::{Error, Result};
 use tor_cell::relaycell::{RelayCmd, UnparsedRelayMsg};
 
 use crate::tunnel::circuit::StreamMpscReceiver;
 use futures::stream::StreamExt;
+use std::sync::Arc;
 
 /// The read part of a stream on a particular circuit.
 #[derive(Debug)]
@@ -15,7 +17,7 @@ pub struct StreamReader {
     /// The underlying `StreamTarget` for this stream.
     ///
     /// A reader has this target in order to:
-    ///   * Make the reactor send SENDME messages.
+    ///   * Make the reactor send SENDME and XON messages.
     ///   * Tell the reactor when there is a protocol error.
     ///   * Keep the stream alive at least until the StreamReader
     ///     is dropped.
@@ -29,6 +31,12 @@ pub struct StreamReader {
     /// opposed to having the reactor assume we're always reading, and potentially overwhelm itself
     /// with having to buffer data).
     pub(crate) recv_window: sendme::StreamRecvWindow,
+    /// The amount of data that the reactor has queued for us, but that we
+    /// haven't read yet.
+    ///
+    /// We use this to tell the reactor to send an XON once we've drained
+    /// the queue after it sent an XOFF.
+    pub(crate) recv_queue: Arc<StreamRecvQueue>,
     /// Whether or not this stream has ended.
     pub(crate) ended: bool,
 }
@@ -55,6 +63,10 @@ impl StreamReader {
             self.recv_window.put();
         }
 
+        if msg.cmd() == RelayCmd::DATA && self.recv_queue.note_read(msg.data_len().into()) {
+            self.target.send_xon().await?;
+        }
+
         Ok(msg)
     }
 
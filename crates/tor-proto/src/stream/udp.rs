@@ -0,0 +1,220 @@
+//! Declare a type for UDP streams, as described in proposal 339.
+
+use crate::memquota::StreamAccount;
+use crate::stream::StreamReader;
+use crate::tunnel::StreamTarget;
+use crate::{Error, Result};
+use tor_cell::relaycell::msg::{ConnectedUdp, Datagram};
+use tor_cell::relaycell::udp::AddressPort;
+use tor_cell::relaycell::RelayCmd;
+use tor_cell::restricted_msg;
+
+use super::AnyCmdChecker;
+
+/// A UdpStream represents a UDP "connection" made with a CONNECT_UDP cell.
+///
+/// Unlike a [`DataStream`](super::DataStream), it is datagram-oriented:
+/// each call to [`send`](UdpStream::send) sends a single DATAGRAM message,
+/// and each call to [`recv`](UdpStream::recv) returns a single one.
+/// Datagrams are delivered in order, but the exit may drop them.
+///
+/// To send datagrams while another task waits in [`recv`](UdpStream::recv),
+/// use a [`UdpStreamSender`] from [`sender`](UdpStream::sender).
+#[derive(Debug)]
+pub struct UdpStream {
+    /// The underlying stream reader.
+    reader: StreamReader,
+    /// The stream target that we use to send datagrams.
+    target: StreamTarget,
+    /// The CONNECTED_UDP message from the exit, once we have received it.
+    connected: Option<ConnectedUdp>,
+    /// The memory quota account that should be used for this stream's data
+    ///
+    /// Exists to keep the account alive
+    _memquota: StreamAccount,
+}
+
+/// A handle for sending datagrams on a [`UdpStream`].
+///
+/// Unlike the `UdpStream` itself, a `UdpStreamSender` can be cloned, and
+/// used while another task is receiving datagrams on the stream.
+/// The stream stays open until the `UdpStream` and all of its senders
+/// have been dropped.
+#[derive(Clone, Debug)]
+pub struct UdpStreamSender {
+    /// The stream target that we use to send datagrams.
+    target: StreamTarget,
+}
+
+restricted_msg! {
+    /// An allowable incoming message on a UDP stream.
+    enum UdpStreamMsg : RelayMsg {
+        End,
+        ConnectedUdp,
+        Datagram,
+    }
+}
+
+impl UdpStream {
+    /// Wrap a StreamReader and a StreamTarget into a UdpStream.
+    ///
+    /// Call only after sending a CONNECT_UDP cell.
+    pub(crate) fn new(reader: StreamReader, target: StreamTarget, memquota: StreamAccount) -> Self {
+        UdpStream {
+            reader,
+            target,
+            connected: None,
+            _memquota: memquota,
+        }
+    }
+
+    /// Read and decode the next message on this stream.
+    async fn read_msg(&mut self) -> Result<UdpStreamMsg> {
+        let cell = self.reader.recv().await?;
+        match cell.decode::<UdpStreamMsg>() {
+            Ok(cell) => Ok(cell.into_msg()),
+            Err(e) => {
+                self.reader.protocol_error();
+                Err(Error::from_bytes_err(e, "message on a UDP stream"))
+            }
+        }
+    }
+
+    /// Wait until a CONNECTED_UDP message is received, or some other
+    /// message is received to indicate an error.
+    ///
+    /// Does nothing if this stream is already connected.
+    pub async fn wait_for_connection(&mut self) -> Result<()> {
+        if self.connected.is_some() {
+            return Ok(());
+        }
+        match self.read_msg().await? {
+            UdpStreamMsg::ConnectedUdp(c) => {
+                self.connected = Some(c);
+                Ok(())
+            }
+            UdpStreamMsg::End(e) => Err(Error::EndReceived(e.reason())),
+            UdpStreamMsg::Datagram(_) => Err(Error::StreamProto(
+                "Received DATAGRAM before CONNECTED_UDP on a stream".into(),
+            )),
+        }
+    }
+
+    /// Send a single datagram on this stream.
+    ///
+    /// Returns an error if `datagram` is longer than [`Datagram::MAXLEN`] bytes.
+    pub async fn send(&mut self, datagram: &[u8]) -> Result<()> {
+        let msg = Datagram::new(datagram).map_err(|e| Error::from_cell_enc(e, "datagram"))?;
+        self.target.send(msg.into()).await
+    }
+
+    /// Return a new [`UdpStreamSender`] for this stream.
+    pub fn sender(&self) -> UdpStreamSender {
+        UdpStreamSender {
+            target: self.target.clone(),
+        }
+    }
+
+    /// Receive the next datagram on this stream.
+    ///
+    /// If the stream was opened optimistically, this waits for the exit's
+    /// CONNECTED_UDP message first.
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        self.wait_for_connection().await?;
+        match self.read_msg().await? {
+            UdpStreamMsg::Datagram(d) => Ok(d.into()),
+            UdpStreamMsg::End(e) => Err(Error::EndReceived(e.reason())),
+            UdpStreamMsg::ConnectedUdp(_) => Err(Error::StreamProto(
+                "Received CONNECTED_UDP twice on a stream".into(),
+            )),
+        }
+    }
+
+    /// Return the address that the exit has bound locally for this stream,
+    /// if we have received its CONNECTED_UDP message.
+    pub fn local_addr(&self) -> Option<&AddressPort> {
+        self.connected.as_ref().map(ConnectedUdp::our_address)
+    }
+
+    /// Return the address that the exit has connected this stream to,
+    /// if we have received its CONNECTED_UDP message.
+    pub fn peer_addr(&self) -> Option<&AddressPort> {
+        self.connected.as_ref().map(ConnectedUdp::their_address)
+    }
+}
+
+impl UdpStreamSender {
+    /// Send a single datagram on the stream.
+    ///
+    /// Returns an error if `datagram` is longer than [`Datagram::MAXLEN`] bytes.
+    pub async fn send(&mut self, datagram: &[u8]) -> Result<()> {
+        let msg = Datagram::new(datagram).map_err(|e| Error::from_cell_enc(e, "datagram"))?;
+        self.target.send(msg.into()).await
+    }
+}
+
+/// A `CmdChecker` that enforces correctness for incoming commands on an
+/// outbound UDP stream.
+#[derive(Debug)]
+pub(crate) struct UdpCmdChecker {
+    /// True if we are expecting to receive a CONNECTED_UDP message on this stream.
+    expecting_connected: bool,
+}
+
+impl Default for UdpCmdChecker {
+    fn default() -> Self {
+        Self {
+            expecting_connected: true,
+        }
+    }
+}
+
+impl super::CmdChecker for UdpCmdChecker {
+    fn check_msg(
+        &mut self,
+        msg: &tor_cell::relaycell::UnparsedRelayMsg,
+    ) -> Result<super::StreamStatus> {
+        use super::StreamStatus::*;
+        match msg.cmd() {
+            RelayCmd::CONNECTED_UDP => {
+                if !self.expecting_connected {
+                    Err(Error::StreamProto(
+                        "Received CONNECTED_UDP twice on a stream.".into(),
+                    ))
+                } else {
+                    self.expecting_connected = false;
+                    Ok(Open)
+                }
+            }
+            RelayCmd::DATAGRAM => {
+                if !self.expecting_connected {
+                    Ok(Open)
+                } else {
+                    Err(Error::StreamProto(
+                        "Received DATAGRAM before CONNECTED_UDP on a stream".into(),
+                    ))
+                }
+            }
+            RelayCmd::END => Ok(Closed),
+            _ => Err(Error::StreamProto(format!(
+                "Unexpected {} on a UDP stream!",
+                msg.cmd()
+            ))),
+        }
+    }
+
+    fn consume_checked_msg(&mut self, msg: tor_cell::relaycell::UnparsedRelayMsg) -> Result<()> {
+        let _ = msg
+            .decode::<UdpStreamMsg>()
+            .map_err(|err| Error::from_bytes_err(err, "cell on half-closed UDP stream"))?;
+        Ok(())
+    }
+}
+
+impl UdpCmdChecker {
+    /// Return a new boxed `UdpCmdChecker` in a state suitable for a newly
+    /// constructed stream.
+    pub(crate) fn new_any() -> AnyCmdChecker {
+        Box::<Self>::default()
+    }
+}
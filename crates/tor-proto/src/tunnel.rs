@@ -15,8 +15,9 @@ use std::sync::Arc;
 
 use crate::crypto::cell::HopNum;
 use crate::{Error, Result};
-use circuit::ClientCirc;
+use circuit::traffic::TrafficCounters;
 use circuit::{handshake, StreamMpscSender};
+use circuit::{ClientCirc, UniqId};
 use reactor::{CtrlMsg, LegId};
 
 use tor_async_utils::SinkCloseChannel as _;
@@ -75,6 +76,8 @@ pub(crate) struct StreamTarget {
     /// Reference to the circuit that this stream is on.
     // TODO(conflux): this should be a ClientTunnel
     circ: Arc<ClientCirc>,
+    /// The traffic counters for this stream, shared with the reactor.
+    traffic: Arc<TrafficCounters>,
 }
 
 impl StreamTarget {
@@ -171,12 +174,38 @@ impl StreamTarget {
         rx.await.map_err(|_| Error::CircuitClosed)?
     }
 
+    /// Send an XON cell for this stream.
+    pub(crate) async fn send_xon(&mut self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+
+        self.circ
+            .control
+            .unbounded_send(CtrlMsg::SendXon {
+                stream_id: self.stream_id,
+                hop: self.hop,
+                sender: tx,
+            })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
     /// Return a reference to the circuit that this `StreamTarget` is using.
     #[cfg(any(feature = "experimental-api", feature = "stream-ctrl"))]
     pub(crate) fn circuit(&self) -> &Arc<ClientCirc> {
         &self.circ
     }
 
+    /// Return the traffic counters for this stream.
+    pub(crate) fn traffic(&self) -> &Arc<TrafficCounters> {
+        &self.traffic
+    }
+
+    /// Return the unique identifier of the circuit that this stream is on.
+    pub(crate) fn circ_id(&self) -> UniqId {
+        self.circ.unique_id()
+    }
+
     /// Return the kind of relay cell in use on this `StreamTarget`.
     pub(crate) fn relay_cell_format(&self) -> RelayCellFormat {
         self.relay_cell_format
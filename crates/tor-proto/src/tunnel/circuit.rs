@@ -41,19 +41,32 @@ pub mod handshake;
 #[cfg(not(feature = "hs-common"))]
 pub(crate) mod handshake;
 
+#[cfg(feature = "circ-padding")]
+#[cfg_attr(docsrs, doc(cfg(feature = "circ-padding")))]
+pub mod padding;
+#[cfg(not(feature = "circ-padding"))]
+pub(crate) mod padding;
+
+pub(crate) mod hop_crypto;
+pub(crate) mod liveness;
 pub(super) mod path;
+pub(crate) mod traffic;
 pub(crate) mod unique_id;
 
 use crate::channel::Channel;
 use crate::congestion::params::CongestionControlParams;
+use crate::congestion::CongestionControlStats;
 use crate::crypto::cell::HopNum;
 use crate::crypto::handshake::ntor_v3::NtorV3PublicKey;
 use crate::memquota::{CircuitAccount, SpecificAccount as _};
 use crate::stream::{
     AnyCmdChecker, DataCmdChecker, DataStream, ResolveCmdChecker, ResolveStream, StreamParameters,
-    StreamReader,
+    StreamReader, StreamRecvQueue,
 };
+#[cfg(feature = "experimental-udp")]
+use crate::stream::{UdpCmdChecker, UdpStream};
 use crate::tunnel::circuit::celltypes::*;
+use crate::tunnel::circuit::traffic::TrafficCounters;
 use crate::tunnel::reactor::CtrlCmd;
 use crate::tunnel::reactor::{
     CircuitHandshake, CtrlMsg, Reactor, RECV_WINDOW_INIT, STREAM_READER_BUFFER,
@@ -62,12 +75,14 @@ use crate::tunnel::{HopLocation, LegId, StreamTarget, TargetHop};
 use crate::util::skew::ClockSkew;
 use crate::{Error, ResolveError, Result};
 use educe::Educe;
+use tor_bytes::SecretBuf;
 use tor_cell::{
     chancell::CircId,
     relaycell::msg::{AnyRelayMsg, Begin, Resolve, Resolved, ResolvedVal},
+    relaycell::RelayCellFormat,
 };
 
-use tor_error::{internal, into_internal};
+use tor_error::{bad_api_usage, internal, into_internal};
 use tor_linkspec::{CircTarget, LinkSpecType, OwnedChanTarget, RelayIdType};
 
 pub use crate::crypto::binding::CircuitBinding;
@@ -88,11 +103,19 @@ use crate::DynTimeProvider;
 use futures::FutureExt as _;
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tor_memquota::mq_queue::{self, ChannelSpec as _, MpscSpec};
+use tor_rtcompat::{SleepProvider as _, SleepProviderExt as _};
 
 use crate::crypto::handshake::ntor::NtorPublicKey;
 
+pub use hop_crypto::{HopCryptoInfo, HopCryptoProtocol, HopHandshake};
+pub use liveness::LivenessProbe;
 pub use path::{Path, PathEntry};
+pub use traffic::{
+    AggregateTrafficStats, CircTrafficSnapshot, StreamCloseHook, StreamStats, StreamStatsHandle,
+    TrafficCounts, TrafficStats, TrafficTotals,
+};
 
 /// The size of the buffer for communication between `ClientCirc` and its reactor.
 pub const CIRCUIT_BUFFER_SIZE: usize = 128;
@@ -200,6 +223,12 @@ pub(super) struct MutableState {
     /// an `Option`.
     #[educe(Debug(ignore))]
     pub(super) binding: Vec<Option<CircuitBinding>>,
+
+    /// Traffic counters for each hop and each open stream.
+    pub(super) traffic: traffic::CircTraffic,
+
+    /// A description of the cryptography used by each hop in the circuit's path.
+    pub(super) crypto: Vec<HopCryptoInfo>,
 }
 
 /// A ClientCirc that needs to send a create cell and receive a created* cell.
@@ -330,6 +359,105 @@ impl ClientCirc {
         rx.await.map_err(|_| Error::CircuitClosed)?
     }
 
+    /// Attach a traffic-shaping defense to this circuit.
+    ///
+    /// This replaces any defense that was previously attached.
+    /// See the [`padding`] module for details.
+    #[cfg(feature = "circ-padding")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "circ-padding")))]
+    pub async fn set_padder<P>(&self, padder: P) -> Result<()>
+    where
+        P: padding::CircuitPadder + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.command
+            .unbounded_send(CtrlCmd::SetPadder {
+                padder: Box::new(padder),
+                done: tx,
+            })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Ask the relay at `hop` to start or stop one of its padding machines,
+    /// and wait for its reply.
+    ///
+    /// Returns [`Error::PaddingRefused`] if the relay declines the request.
+    ///
+    /// Before asking a relay to send us padding, make sure that our padder
+    /// [accepts padding](padding::CircuitPadder::accepts_padding_from) from that hop:
+    /// otherwise, its padding cells will close the circuit.
+    /// [`padding::MachinePadder`] accepts padding from every hop it has a machine on.
+    ///
+    /// # Limitations
+    ///
+    /// This installs a handler for the reply, so it must not be called while
+    /// an ad-hoc conversation is in progress on this circuit.
+    #[cfg(feature = "circ-padding")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "circ-padding")))]
+    pub async fn negotiate_padding(
+        &self,
+        hop: HopNum,
+        msg: tor_cell::relaycell::msg::PaddingNegotiate,
+    ) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let handler = padding::PaddingNegotiationHandler::new(hop, msg.clone(), reply_tx);
+        let (sender, receiver) = oneshot::channel();
+
+        self.control
+            .unbounded_send(CtrlMsg::SendMsgAndInstallHandler {
+                msg: Some(tor_cell::relaycell::AnyRelayMsgOuter::new(None, msg.into())),
+                handler: Some(Box::new(handler)),
+                sender,
+            })
+            .map_err(|_| Error::CircuitClosed)?;
+        receiver.await.map_err(|_| Error::CircuitClosed)??;
+
+        reply_rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Link this circuit with `others` into a single conflux (multipath) tunnel,
+    /// as described in [proposal 329].
+    ///
+    /// All the circuits must end at the same exit relay, which must support conflux,
+    /// and must have the same number of hops.
+    /// Only this circuit may have open streams.
+    ///
+    /// The circuits in `others` are taken over by this circuit:
+    /// whether or not linking succeeds, they can no longer be used on their own.
+    ///
+    /// Returns once the exit has confirmed that all the circuits are linked.
+    /// After that, new streams are opened at the exit of the tunnel,
+    /// and the data of every stream at the exit is spread over all the circuits.
+    ///
+    /// [proposal 329]: https://spec.torproject.org/proposals/329-traffic-splitting.html
+    //
+    // TODO(conflux): this should return a ClientTunnel.
+    #[cfg(feature = "conflux")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "conflux")))]
+    pub async fn link_circuits(&self, others: Vec<Arc<ClientCirc>>) -> Result<()> {
+        let mut circuits = Vec::with_capacity(others.len());
+        for circ in others {
+            let (tx, rx) = oneshot::channel();
+            circ.command
+                .unbounded_send(CtrlCmd::ShutdownAndReturnCircuit { answer: tx })
+                .map_err(|_| Error::CircuitClosed)?;
+            circuits.push(rx.await.map_err(|_| Error::CircuitClosed)??);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.control
+            .unbounded_send(CtrlMsg::LinkCircuits {
+                circuits,
+                answer: tx,
+            })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
     /// Get the clock skew claimed by the first hop of the circuit.
     ///
     /// See [`Channel::clock_skew()`].
@@ -343,6 +471,128 @@ impl ClientCirc {
         Ok(rx.await.map_err(|_| Error::CircuitClosed)??)
     }
 
+    /// Return a snapshot of the congestion control state for `hop`.
+    ///
+    /// This includes the congestion window and RTT estimates, if the negotiated
+    /// algorithm uses them. It is intended for diagnostics only.
+    ///
+    /// Returns an error if `hop` does not exist, or if this is a multi-path tunnel.
+    pub async fn congestion_control_stats(&self, hop: HopNum) -> Result<CongestionControlStats> {
+        let (tx, rx) = oneshot::channel();
+
+        self.command
+            .unbounded_send(CtrlCmd::QueryCcStats { hop, done: tx })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Return the smoothed round-trip time of each leg of this tunnel,
+    /// as measured from the SENDMEs sent by the last hop of the leg.
+    ///
+    /// For a conflux set, the last hop of every leg is the exit at which they join.
+    /// An entry is `None` if we have no estimate for that leg yet,
+    /// because its last hop hasn't sent us a SENDME,
+    /// or because the congestion control algorithm in use doesn't measure RTT.
+    pub async fn leg_rtts(&self) -> Result<Vec<Option<Duration>>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.command
+            .unbounded_send(CtrlCmd::QueryLegRtts { done: tx })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Send a single DROP cell to `hop`, which the hop will discard.
+    ///
+    /// This counts as traffic on the circuit (and on its channel),
+    /// without sending anything to the other end of any stream.
+    ///
+    /// Returns an error if `hop` does not exist, or if the circuit is closed.
+    pub async fn send_drop(&self, hop: HopNum) -> Result<()> {
+        // The reactor treats a cell for a hop that doesn't exist as fatal,
+        // so check first.
+        if usize::from(hop) >= self.n_hops() {
+            return Err(Error::NoSuchHop);
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.control
+            .unbounded_send(CtrlMsg::SendDrop { hop, sender })
+            .map_err(|_| Error::CircuitClosed)?;
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Check whether `hop` of this circuit is still alive.
+    ///
+    /// This opens a BEGIN_DIR stream to `hop`, and waits up to `timeout` for
+    /// the hop to answer it, then closes the stream. Every relay answers a
+    /// BEGIN_DIR, either with a CONNECTED cell or (if it isn't willing to
+    /// act as a directory cache from this position) with an END cell, so
+    /// either answer shows that the hop is alive.
+    ///
+    /// Returns a [`LivenessProbe`] with the time the hop took to answer, if
+    /// it answered in time, and when we last heard from the hop.
+    ///
+    /// This lets callers detect circuits that have stopped working
+    /// before they try to use them for a real request.
+    ///
+    /// Returns an error if `hop` does not exist, if this is a multi-path tunnel,
+    /// or if the circuit is closed.
+    pub async fn probe_liveness(
+        self: &Arc<ClientCirc>,
+        hop: HopNum,
+        timeout: Duration,
+    ) -> Result<LivenessProbe> {
+        // TODO(conflux): Support tunnels with more than one leg.
+        let legs = self.legs().await?;
+        let [(leg_id, path)] = &legs[..] else {
+            return Err(bad_api_usage!(
+                "cannot probe the liveness of a tunnel with {} legs",
+                legs.len()
+            )
+            .into());
+        };
+        if usize::from(hop) >= path.n_hops() {
+            return Err(Error::NoSuchHop);
+        }
+        let target = TargetHop::Hop(HopLocation::Hop((*leg_id, hop)));
+
+        let started = self.time_provider.now();
+        let answer = async {
+            let (reader, target, memquota) = self
+                .begin_stream_impl(
+                    target,
+                    AnyRelayMsg::BeginDir(Default::default()),
+                    DataCmdChecker::new_any(),
+                    None,
+                    None,
+                    false,
+                )
+                .await?;
+            // Dropping the stream once we have an answer closes it.
+            let mut stream = DataStream::new(reader, target, memquota);
+            match stream.wait_for_connection().await {
+                // The hop told us to go away, but it's still there.
+                Ok(()) | Err(Error::EndReceived(_)) => Ok(()),
+                Err(e) => Err(e),
+            }
+        };
+        let reply_rtt = match self.time_provider.timeout(timeout, answer).await {
+            Ok(Ok(())) => Some(self.time_provider.now().saturating_duration_since(started)),
+            Ok(Err(e)) => return Err(e),
+            Err(_timeout) => None,
+        };
+
+        let (tx, rx) = oneshot::channel();
+        self.command
+            .unbounded_send(CtrlCmd::QueryLiveness { hop, done: tx })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        let probe: LivenessProbe = rx.await.map_err(|_| Error::CircuitClosed)??;
+        Ok(probe.with_reply_rtt(reply_rtt))
+    }
+
     /// Return a reference to this circuit's memory quota account
     pub fn mq_account(&self) -> &CircuitAccount {
         &self.memquota
@@ -367,6 +617,51 @@ impl ClientCirc {
         // it very rarely, so it's not _that_ bad IMO.
     }
 
+    /// Derive `len` bytes of key material bound to `hop` of this circuit,
+    /// for use in an application protocol.
+    ///
+    /// The relay at `hop` can derive the same key from its side of the
+    /// circuit, so this lets an application authenticate its own data to a
+    /// specific hop of a specific circuit.
+    ///
+    /// See [`CircuitBinding::export_key`] for the meaning of `label` and
+    /// `context`, and for how the key is derived.
+    ///
+    /// Return an error if we have no circuit binding information for the hop,
+    /// or if the hop does not exist.
+    pub fn export_key(
+        &self,
+        hop: HopNum,
+        label: &[u8],
+        context: &[u8],
+        len: usize,
+    ) -> Result<SecretBuf> {
+        self.binding_key(hop)
+            .ok_or(Error::NoSuchHop)?
+            .export_key(label, context, len)
+    }
+
+    /// Return a description of the cryptography that each hop of this
+    /// circuit uses, in order.
+    ///
+    /// This includes the handshake that negotiated each hop's keys, its relay
+    /// cell format, and its cell encryption protocol.
+    pub fn crypto_info(&self) -> Vec<HopCryptoInfo> {
+        self.mutable.lock().expect("poisoned lock").crypto.clone()
+    }
+
+    /// Return a snapshot of the traffic that we have sent and received on
+    /// this circuit, for each hop and for each open stream.
+    ///
+    /// This is cheap: it doesn't need to wait for the circuit reactor.
+    pub fn traffic_stats(&self) -> CircTrafficSnapshot {
+        self.mutable
+            .lock()
+            .expect("poisoned lock")
+            .traffic
+            .snapshot()
+    }
+
     /// Start an ad-hoc protocol exchange to the specified hop on this circuit
     ///
     /// To use this:
@@ -574,9 +869,11 @@ impl ClientCirc {
                 stream_id,
                 hop_num,
                 receiver,
+                recv_queue,
                 msg_tx,
                 memquota,
                 relay_cell_format,
+                traffic,
             } = req_ctx;
 
             // We already enforce this in handle_incoming_stream_request; this
@@ -591,12 +888,14 @@ impl ClientCirc {
                 hop: HopLocation::Hop((leg_id, hop_num)),
                 stream_id,
                 relay_cell_format,
+                traffic,
             };
 
             let reader = StreamReader {
                 target: target.clone(),
                 receiver,
                 recv_window: StreamRecvWindow::new(RECV_WINDOW_INIT),
+                recv_queue,
                 ended: false,
             };
 
@@ -644,6 +943,60 @@ impl ClientCirc {
     /// Extend the circuit via the ntor handshake to a new target last
     /// hop.
     pub async fn extend_ntor_v3<Tg>(&self, target: &Tg, params: CircParameters) -> Result<()>
+    where
+        Tg: CircTarget,
+    {
+        let protocol = handshake::RelayCryptLayerProtocol::Tor1(RelayCellFormat::V0);
+        self.extend_ntor_v3_inner(target, params, false, protocol)
+            .await
+    }
+
+    /// Extend the circuit to a new target last hop, via the ntor v3
+    /// handshake, and use Counter Galois Onion (CGO) relay cell encryption
+    /// with the new hop.
+    ///
+    /// The target must support CGO (see
+    /// [`RELAY_CRYPT_CGO`](tor_protover::named::RELAY_CRYPT_CGO)): otherwise,
+    /// it will refuse the handshake.
+    #[cfg(feature = "counter-galois-onion")]
+    pub async fn extend_ntor_v3_cgo<Tg>(&self, target: &Tg, params: CircParameters) -> Result<()>
+    where
+        Tg: CircTarget,
+    {
+        let protocol = handshake::RelayCryptLayerProtocol::Cgo(RelayCellFormat::V1);
+        self.extend_ntor_v3_inner(target, params, false, protocol)
+            .await
+    }
+
+    /// Extend the circuit to a new target last hop, via the hybrid
+    /// post-quantum variant of the ntor v3 handshake, using X25519 and ML-KEM.
+    ///
+    /// This protects the keys of the new hop against an adversary who records
+    /// the handshake now, and who is later able to break X25519.
+    ///
+    /// The handshake messages are too long to fit in a single cell, so they
+    /// are sent in fragments: the last hop of this circuit and the new target
+    /// must both support fragmented relay messages and this handshake.
+    #[cfg(feature = "ntor-v3-pq")]
+    pub async fn extend_ntor_v3_pq<Tg>(&self, target: &Tg, params: CircParameters) -> Result<()>
+    where
+        Tg: CircTarget,
+    {
+        let protocol = handshake::RelayCryptLayerProtocol::Tor1(RelayCellFormat::V0);
+        self.extend_ntor_v3_inner(target, params, true, protocol)
+            .await
+    }
+
+    /// Extend the circuit via the ntor v3 handshake, or its hybrid
+    /// post-quantum variant if `post_quantum` is true, and use `protocol`
+    /// for relay cell encryption with the new hop.
+    async fn extend_ntor_v3_inner<Tg>(
+        &self,
+        target: &Tg,
+        params: CircParameters,
+        post_quantum: bool,
+        protocol: handshake::RelayCryptLayerProtocol,
+    ) -> Result<()>
     where
         Tg: CircTarget,
     {
@@ -669,6 +1022,8 @@ impl ClientCirc {
                 public_key: key,
                 linkspecs,
                 params,
+                post_quantum,
+                protocol,
                 done: tx,
             })
             .map_err(|_| Error::CircuitClosed)?;
@@ -714,13 +1069,12 @@ impl ClientCirc {
         use self::handshake::BoxedClientLayer;
 
         let protocol = handshake::RelayCryptLayerProtocol::from(protocol);
-        let relay_cell_format = protocol.relay_cell_format();
 
         let BoxedClientLayer { fwd, back, binding } = protocol.construct_layers(role, seed)?;
 
         let (tx, rx) = oneshot::channel();
         let message = CtrlCmd::ExtendVirtual {
-            relay_cell_format,
+            protocol,
             cell_crypto: (fwd, back, binding),
             params,
             done: tx,
@@ -740,20 +1094,30 @@ impl ClientCirc {
     ///
     /// The caller will typically want to see the first cell in response,
     /// to see whether it is e.g. an END or a CONNECTED.
+    ///
+    /// If `totals` is provided, the stream's traffic counts towards it.
+    /// If `on_close` is provided, it is called when the stream closes.
+    /// If `hide_from_padding` is true, the circuit padder doesn't see the
+    /// cells that the stream sends.
     async fn begin_stream_impl(
         self: &Arc<ClientCirc>,
+        hop: TargetHop,
         begin_msg: AnyRelayMsg,
         cmd_checker: AnyCmdChecker,
+        totals: Option<Arc<TrafficTotals>>,
+        on_close: Option<StreamCloseHook>,
+        hide_from_padding: bool,
     ) -> Result<(StreamReader, StreamTarget, StreamAccount)> {
-        // TODO: Possibly this should take a hop, rather than just
-        // assuming it's the last hop.
-        let hop = TargetHop::LastHop;
-
         let time_prov = self.time_provider.clone();
 
         let memquota = StreamAccount::new(self.mq_account())?;
         let (sender, receiver) = MpscSpec::new(STREAM_READER_BUFFER)
             .new_mq(time_prov.clone(), memquota.as_raw_account())?;
+        let recv_queue = Arc::new(StreamRecvQueue::default());
+        let traffic = Arc::new(
+            TrafficCounters::for_stream(totals, on_close, self.unique_id())
+                .hide_from_padding(hide_from_padding),
+        );
         let (tx, rx) = oneshot::channel();
         let (msg_tx, msg_rx) =
             MpscSpec::new(CIRCUIT_BUFFER_SIZE).new_mq(time_prov, memquota.as_raw_account())?;
@@ -763,6 +1127,8 @@ impl ClientCirc {
                 hop,
                 message: begin_msg,
                 sender,
+                recv_queue: Arc::clone(&recv_queue),
+                traffic: Arc::clone(&traffic),
                 rx: msg_rx,
                 done: tx,
                 cmd_checker,
@@ -777,12 +1143,14 @@ impl ClientCirc {
             hop,
             stream_id,
             relay_cell_format,
+            traffic,
         };
 
         let reader = StreamReader {
             target: target.clone(),
             receiver,
             recv_window: StreamRecvWindow::new(RECV_WINDOW_INIT),
+            recv_queue,
             ended: false,
         };
 
@@ -791,13 +1159,28 @@ impl ClientCirc {
 
     /// Start a DataStream (anonymized connection) to the given
     /// address and port, using a BEGIN cell.
+    ///
+    /// If `totals` is provided, the stream's traffic counts towards it.
+    /// If `on_close` is provided, it is called when the stream closes.
+    /// If `hide_from_padding` is true, the circuit padder doesn't see the
+    /// cells that the stream sends.
     async fn begin_data_stream(
         self: &Arc<ClientCirc>,
         msg: AnyRelayMsg,
         optimistic: bool,
+        totals: Option<Arc<TrafficTotals>>,
+        on_close: Option<StreamCloseHook>,
+        hide_from_padding: bool,
     ) -> Result<DataStream> {
         let (reader, target, memquota) = self
-            .begin_stream_impl(msg, DataCmdChecker::new_any())
+            .begin_stream_impl(
+                TargetHop::LastHop,
+                msg,
+                DataCmdChecker::new_any(),
+                totals,
+                on_close,
+                hide_from_padding,
+            )
             .await?;
         let mut stream = DataStream::new(reader, target, memquota);
         if !optimistic {
@@ -827,7 +1210,47 @@ impl ClientCirc {
         };
         let beginmsg = Begin::new(target, port, begin_flags)
             .map_err(|e| Error::from_cell_enc(e, "begin message"))?;
-        self.begin_data_stream(beginmsg.into(), optimistic).await
+        self.begin_data_stream(
+            beginmsg.into(),
+            optimistic,
+            parameters.traffic_totals(),
+            parameters.close_hook(),
+            parameters.hidden_from_padding(),
+        )
+        .await
+    }
+
+    /// Start a UDP stream to the given address and port, using a
+    /// CONNECT_UDP cell, as described in proposal 339.
+    ///
+    /// Unless `parameters` ask for an optimistic stream, this waits for
+    /// the exit's CONNECTED_UDP reply before returning.
+    #[cfg(feature = "experimental-udp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "experimental-udp")))]
+    pub async fn begin_udp_stream(
+        self: &Arc<ClientCirc>,
+        target: &str,
+        port: u16,
+        parameters: Option<StreamParameters>,
+    ) -> Result<UdpStream> {
+        let parameters = parameters.unwrap_or_default();
+        let msg = tor_cell::relaycell::msg::ConnectUdp::new(target, port, parameters.begin_flags())
+            .map_err(|e| Error::from_cell_enc(e, "connect_udp message"))?;
+        let (reader, target, memquota) = self
+            .begin_stream_impl(
+                TargetHop::LastHop,
+                msg.into(),
+                UdpCmdChecker::new_any(),
+                parameters.traffic_totals(),
+                parameters.close_hook(),
+                parameters.hidden_from_padding(),
+            )
+            .await?;
+        let mut stream = UdpStream::new(reader, target, memquota);
+        if !parameters.is_optimistic() {
+            stream.wait_for_connection().await?;
+        }
+        Ok(stream)
     }
 
     /// Start a new stream to the last relay in the circuit, using
@@ -837,8 +1260,14 @@ impl ClientCirc {
         // Since they are local to a relay that we've already authenticated
         // with and built a circuit to, there should be no additional checks
         // we need to perform to see whether the BEGINDIR will succeed.
-        self.begin_data_stream(AnyRelayMsg::BeginDir(Default::default()), true)
-            .await
+        self.begin_data_stream(
+            AnyRelayMsg::BeginDir(Default::default()),
+            true,
+            None,
+            None,
+            false,
+        )
+        .await
     }
 
     /// Perform a DNS lookup, using a RESOLVE cell with the last relay
@@ -886,11 +1315,53 @@ impl ClientCirc {
             .collect()
     }
 
+    /// Perform a DNS lookup, like [`resolve`](ClientCirc::resolve), but return
+    /// every answer that the last relay sent, along with its TTL in seconds.
+    ///
+    /// Unlike `resolve`, error answers are returned along with the others,
+    /// rather than as an `Err`.
+    ///
+    /// Note that this function does not check for timeouts; that's
+    /// the caller's responsibility.
+    pub async fn resolve_answers(
+        self: &Arc<ClientCirc>,
+        hostname: &str,
+    ) -> Result<Vec<(ResolvedVal, u32)>> {
+        let resolve_msg = Resolve::new(hostname);
+
+        Ok(self.try_resolve(resolve_msg).await?.into_answers())
+    }
+
+    /// Perform a reverse DNS lookup, like [`resolve_ptr`](ClientCirc::resolve_ptr),
+    /// but return every answer that the last relay sent, along with its TTL
+    /// in seconds.
+    ///
+    /// Unlike `resolve_ptr`, error answers are returned along with the
+    /// others, rather than as an `Err`.
+    ///
+    /// Note that this function does not check for timeouts; that's
+    /// the caller's responsibility.
+    pub async fn resolve_ptr_answers(
+        self: &Arc<ClientCirc>,
+        addr: IpAddr,
+    ) -> Result<Vec<(ResolvedVal, u32)>> {
+        let resolve_ptr_msg = Resolve::new_reverse(&addr);
+
+        Ok(self.try_resolve(resolve_ptr_msg).await?.into_answers())
+    }
+
     /// Helper: Send the resolve message, and read resolved message from
     /// resolve stream.
     async fn try_resolve(self: &Arc<ClientCirc>, msg: Resolve) -> Result<Resolved> {
         let (reader, _target, memquota) = self
-            .begin_stream_impl(msg.into(), ResolveCmdChecker::new_any())
+            .begin_stream_impl(
+                TargetHop::LastHop,
+                msg.into(),
+                ResolveCmdChecker::new_any(),
+                None,
+                None,
+                false,
+            )
             .await?;
         let mut resolve_stream = ResolveStream::new(reader, memquota);
         resolve_stream.read_msg().await
@@ -1175,6 +1646,7 @@ pub(crate) mod test {
     use super::*;
     use crate::channel::OpenChanCellS2C;
     use crate::channel::{test::new_reactor, CodecError};
+    use crate::congestion::params::AlgorithmType;
     use crate::congestion::sendme;
     use crate::congestion::test_utils::params::build_cc_vegas_params;
     use crate::crypto::cell::RelayCellBody;
@@ -1503,7 +1975,16 @@ pub(crate) mod test {
         chan: Arc<Channel>,
         next_msg_from: HopNum,
     ) -> (Arc<ClientCirc>, CircuitRxSender) {
-        let circid = CircId::new(128).unwrap();
+        newcirc_with_id(rt, chan, next_msg_from, CircId::new(128).unwrap()).await
+    }
+
+    // Helper: like `newcirc_ext`, but with the given circuit ID.
+    async fn newcirc_with_id<R: Runtime>(
+        rt: &R,
+        chan: Arc<Channel>,
+        next_msg_from: HopNum,
+        circid: CircId,
+    ) -> (Arc<ClientCirc>, CircuitRxSender) {
         let (_created_send, created_recv) = oneshot::channel();
         let (circmsg_send, circmsg_recv) = fake_mpsc(64);
         let unique_id = UniqId::new(23, 17);
@@ -1643,6 +2124,21 @@ pub(crate) mod test {
                 example_target().ed_identity()
             );
         }
+
+        // Does the new hop report the handshake we used?
+        let crypto = circ.crypto_info();
+        assert_eq!(crypto.len(), 4);
+        let expected_handshake = match handshake_type {
+            HandshakeType::Fast => unreachable!(),
+            HandshakeType::Ntor => chanmsg::HandshakeType::NTOR,
+            HandshakeType::NtorV3 => chanmsg::HandshakeType::NTOR_V3,
+        };
+        assert_eq!(
+            crypto[3].handshake(),
+            HopHandshake::Create2(expected_handshake)
+        );
+        assert_eq!(crypto[3].protocol(), HopCryptoProtocol::Tor1);
+        assert!(matches!(crypto[3].relay_cell_format(), RelayCellFormat::V0));
     }
 
     #[traced_test]
@@ -1756,6 +2252,7 @@ pub(crate) mod test {
             let (chan, mut rx, _sink) = working_fake_channel(&rt);
             let (circ, mut sink) = newcirc(&rt, chan).await;
             let circid = circ.peek_circid();
+            let unique_id = circ.unique_id();
 
             let begin_and_send_fut = async move {
                 // Here we'll say we've got a circuit, and we want to
@@ -1768,6 +2265,13 @@ pub(crate) mod test {
                 assert_eq!(&buf[..n], b"HTTP/1.0 404 Not found\r\n");
                 let n = stream.read(&mut buf).await.unwrap();
                 assert_eq!(n, 0);
+
+                let stats = stream.stats();
+                assert_eq!(stats.circ_id(), unique_id);
+                assert_eq!(stats.traffic().sent().cells(), 1);
+                assert_eq!(stats.traffic().sent().data_bytes(), 16);
+                assert_eq!(stats.traffic().received().cells(), 1);
+                assert_eq!(stats.traffic().received().data_bytes(), 24);
                 stream
             };
             let reply_fut = async move {
@@ -1824,6 +2328,251 @@ pub(crate) mod test {
         });
     }
 
+    #[cfg(feature = "crypto-offload")]
+    #[traced_test]
+    #[test]
+    fn begindir_offloaded() {
+        use crate::crypto::offload::{CryptoOffload, CryptoOffloadConfig};
+        use std::num::NonZeroUsize;
+
+        // Enough data for several cells in each direction.
+        const N_BYTES: usize = 498 * 12 + 7;
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            let config = CryptoOffloadConfig::builder()
+                .n_threads(NonZeroUsize::new(2).unwrap())
+                .build()
+                .unwrap();
+            let pool = CryptoOffload::launch(&config).unwrap();
+            let (tx, done) = oneshot::channel();
+            circ.command
+                .unbounded_send(CtrlCmd::SetCryptoOffload {
+                    lane: Some(pool.lane()),
+                    done: tx,
+                })
+                .unwrap();
+            done.await.unwrap().unwrap();
+
+            let request: Vec<u8> = (0..N_BYTES).map(|i| (i % 251) as u8).collect();
+            let response: Vec<u8> = (0..N_BYTES).map(|i| (i % 241) as u8).collect();
+
+            let client_fut = {
+                let request = request.clone();
+                let response = response.clone();
+                async move {
+                    let mut stream = circ.begin_dir_stream().await.unwrap();
+                    stream.write_all(&request).await.unwrap();
+                    stream.flush().await.unwrap();
+                    let mut got = Vec::new();
+                    stream.read_to_end(&mut got).await.unwrap();
+                    assert_eq!(got, response);
+                    stream
+                }
+            };
+            let relay_fut = async move {
+                // Decode the next relay message that the client sends.
+                async fn next_msg(
+                    rx: &mut Receiver<AnyChanCell>,
+                ) -> (Option<StreamId>, AnyRelayMsg) {
+                    let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                    match chmsg {
+                        AnyChanMsg::Relay(r) => AnyRelayMsgOuter::decode_singleton(
+                            RelayCellFormat::V0,
+                            r.into_relay_body(),
+                        )
+                        .unwrap()
+                        .into_streamid_and_msg(),
+                        other => panic!("{:?}", other),
+                    }
+                }
+
+                let (streamid, rmsg) = next_msg(&mut rx).await;
+                assert!(matches!(rmsg, AnyRelayMsg::BeginDir(_)));
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+                // The request has to arrive in order, across all its cells.
+                let mut got = Vec::new();
+                while got.len() < request.len() {
+                    let (streamid_2, rmsg) = next_msg(&mut rx).await;
+                    assert_eq!(streamid_2, streamid);
+                    match rmsg {
+                        AnyRelayMsg::Data(d) => got.extend_from_slice(d.as_ref()),
+                        other => panic!("{:?}", other),
+                    }
+                }
+                assert_eq!(got, request);
+
+                for chunk in response.chunks(relaymsg::Data::MAXLEN) {
+                    let data = relaymsg::Data::new(chunk).unwrap().into();
+                    sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+                }
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
+                sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+
+                (rx, sink) // gotta keep these alive, or the reactor will exit.
+            };
+
+            let (_stream, (_rx, _sink)) = futures::join!(client_fut, relay_fut);
+        });
+    }
+
+    #[cfg(feature = "experimental-udp")]
+    #[traced_test]
+    #[test]
+    fn udp_stream() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            let client_fut = async move {
+                let mut stream = circ
+                    .begin_udp_stream("www.example.com", 53, None)
+                    .await
+                    .unwrap();
+                assert_eq!(stream.peer_addr().unwrap().port(), 53);
+                stream.send(b"query").await.unwrap();
+                assert_eq!(stream.recv().await.unwrap(), b"answer");
+                let err = stream.recv().await.unwrap_err();
+                assert!(matches!(err, Error::EndReceived(_)));
+                stream
+            };
+            let relay_fut = async move {
+                let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert!(matches!(rmsg, AnyRelayMsg::ConnectUdp(_)));
+
+                // Tell the client that the "connection" succeeded.
+                let connected = relaymsg::ConnectedUdp::new(
+                    ("10.0.0.1", 4321).try_into().unwrap(),
+                    ("192.0.2.7", 53).try_into().unwrap(),
+                )
+                .unwrap()
+                .into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+                // Read the client's datagram, and answer it.
+                let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (streamid_2, rmsg) = rmsg.into_streamid_and_msg();
+                assert_eq!(streamid_2, streamid);
+                let AnyRelayMsg::Datagram(d) = rmsg else {
+                    panic!("expected DATAGRAM");
+                };
+                assert_eq!(d.as_ref(), b"query");
+
+                let answer = relaymsg::Datagram::new(b"answer").unwrap().into();
+                sink.send(rmsg_to_ccmsg(streamid, answer)).await.unwrap();
+
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
+                sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+
+                (rx, sink)
+            };
+
+            let (_stream, (_rx, _sink)) = futures::join!(client_fut, relay_fut);
+        });
+    }
+
+    #[cfg(feature = "relay-fragment")]
+    #[traced_test]
+    #[test]
+    fn fragmented_msgs() {
+        use tor_cell::relaycell::RelayCellDecoder;
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+            // Too long to fit in a single BEGIN cell.
+            let hostname = vec!["a".repeat(60); 10].join(".");
+
+            let client_fut = async {
+                let mut stream = circ.begin_stream(&hostname, 80, None).await.unwrap();
+                let mut buf = [0_u8; 11];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello world");
+                stream
+            };
+            let relay_fut = async {
+                // The BEGIN message arrives in fragments.
+                let mut decoder = RelayCellDecoder::new(RelayCellFormat::V0);
+                let mut n_cells = 0;
+                let msg = loop {
+                    let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                    let AnyChanMsg::Relay(r) = chmsg else {
+                        panic!("{:?}", chmsg);
+                    };
+                    n_cells += 1;
+                    let (mut msgs, _) = decoder.decode(r.into_relay_body()).unwrap().into_parts();
+                    if let Some(msg) = msgs.next() {
+                        break msg;
+                    }
+                };
+                assert_eq!(n_cells, 2);
+                let streamid = msg.stream_id();
+                let AnyRelayMsg::Begin(begin) = msg.decode::<AnyRelayMsg>().unwrap().into_msg()
+                else {
+                    panic!("expected BEGIN");
+                };
+                assert_eq!(begin.addr(), hostname.as_bytes());
+
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+                // Send a DATA message in two fragments.
+                for fragment in [&b"\x02\x00\x0bhello"[..], &b" world"[..]] {
+                    let msg = AnyRelayMsg::Unrecognized(relaymsg::Unrecognized::new(
+                        RelayCmd::FRAGMENT,
+                        fragment,
+                    ));
+                    sink.send(rmsg_to_ccmsg(streamid, msg)).await.unwrap();
+                }
+
+                (rx, sink)
+            };
+
+            let (_stream, (_rx, _sink)) = futures::join!(client_fut, relay_fut);
+            assert!(!circ.is_closing());
+        });
+    }
+
+    #[cfg(feature = "relay-fragment")]
+    #[traced_test]
+    #[test]
+    fn fragment_on_nonexistent_stream() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            // The first fragment of a DATA message, on a stream that doesn't exist.
+            let msg = AnyRelayMsg::Unrecognized(relaymsg::Unrecognized::new(
+                RelayCmd::FRAGMENT,
+                &b"\x02\x00\x0bhello"[..],
+            ));
+            sink.send(rmsg_to_ccmsg(StreamId::new(77), msg))
+                .await
+                .unwrap();
+            rt.advance_until_stalled().await;
+            assert!(circ.is_closing());
+        });
+    }
+
     // Test: close a stream, either by dropping it or by calling AsyncWriteExt::close.
     fn close_stream_helper(by_drop: bool) {
         tor_rtcompat::test_with_all_runtimes!(|rt| async move {
@@ -2052,6 +2801,13 @@ pub(crate) mod test {
                 let (window, _tags) = rx.await.unwrap().unwrap();
                 assert_eq!(window, 1000 - 201);
             }
+
+            // The fixed window algorithm has no congestion window or RTT estimate.
+            let stats = circ.congestion_control_stats(2.into()).await.unwrap();
+            assert_eq!(stats.algorithm(), AlgorithmType::FIXED_WINDOW);
+            assert!(stats.cwnd().is_none());
+            assert!(stats.ewma_rtt().is_none());
+            assert!(circ.congestion_control_stats(7.into()).await.is_err());
         });
     }
 
@@ -2485,4 +3241,398 @@ pub(crate) mod test {
             let (_circ, _send) = futures::join!(simulate_service, simulate_client);
         });
     }
+
+    // Helper: read the next relay message that the circuit sends.
+    async fn next_relay_msg(rx: &mut Receiver<AnyChanCell>) -> (Option<StreamId>, AnyRelayMsg) {
+        let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+        match chmsg {
+            AnyChanMsg::Relay(r) => {
+                AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                    .unwrap()
+                    .into_streamid_and_msg()
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn probe_liveness_alive() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            // Probe the middle hop, and answer from it.
+            let (circ, mut sink) = newcirc_ext(&rt, chan, 1.into()).await;
+            let timeout = Duration::from_secs(10);
+
+            let relay_fut = async move {
+                let (streamid, msg) = next_relay_msg(&mut rx).await;
+                assert!(matches!(msg, AnyRelayMsg::BeginDir(_)));
+                rt.advance_by(Duration::from_millis(300)).await;
+                // A relay that won't serve directory requests here still answers.
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::NOTDIRECTORY).into();
+                sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+                (rx, sink)
+            };
+            let (probe, (_rx, _sink)) =
+                futures::join!(circ.probe_liveness(1.into(), timeout), relay_fut);
+            let probe = probe.unwrap();
+
+            assert!(probe.is_alive());
+            assert_eq!(probe.reply_rtt(), Some(Duration::from_millis(300)));
+            assert!(probe.heard_within(Duration::from_secs(1)));
+            assert!(!circ.is_closing());
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn probe_liveness_dead() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, _sink) = newcirc(&rt, chan).await;
+            let timeout = Duration::from_secs(10);
+
+            // Nothing ever answers the probe.
+            let (probe, ()) = futures::join!(
+                circ.probe_liveness(1.into(), timeout),
+                rt.advance_until_stalled()
+            );
+            let probe = probe.unwrap();
+
+            assert!(!probe.is_alive());
+            assert!(probe.reply_rtt().is_none());
+            // We haven't heard anything from the hop, or measured its RTT.
+            assert!(probe.since_last_received().is_none());
+            assert!(probe.sendme_rtt().is_none());
+            assert!(!circ.is_closing());
+
+            // The probe went out as a BEGIN_DIR, and was torn down when we gave up.
+            let (streamid, msg) = next_relay_msg(&mut rx).await;
+            assert!(matches!(msg, AnyRelayMsg::BeginDir(_)));
+            let (end_streamid, msg) = next_relay_msg(&mut rx).await;
+            assert_eq!(end_streamid, streamid);
+            assert!(matches!(msg, AnyRelayMsg::End(_)));
+
+            assert!(matches!(
+                circ.probe_liveness(7.into(), timeout).await,
+                Err(Error::NoSuchHop)
+            ));
+            assert!(!circ.is_closing());
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn send_drop() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, _sink) = newcirc(&rt, chan).await;
+
+            circ.send_drop(2.into()).await.unwrap();
+            let (_, msg) = next_relay_msg(&mut rx).await;
+            assert!(matches!(msg, AnyRelayMsg::Drop(_)));
+
+            assert!(matches!(
+                circ.send_drop(7.into()).await,
+                Err(Error::NoSuchHop)
+            ));
+            assert!(!circ.is_closing());
+        });
+    }
+
+    /// A padder that sends a fixed list of padding cells, and records events.
+    #[cfg(feature = "circ-padding")]
+    struct TestPadder {
+        /// Hops to send padding to, in reverse order.
+        pending: Vec<HopNum>,
+        /// The events we've been told about.
+        events: Arc<Mutex<Vec<(HopNum, padding::PaddingEvent)>>>,
+    }
+
+    #[cfg(feature = "circ-padding")]
+    impl padding::CircuitPadder for TestPadder {
+        fn note_event(&mut self, hop: HopNum, event: padding::PaddingEvent) {
+            self.events.lock().unwrap().push((hop, event));
+        }
+        fn poll_padding(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<HopNum> {
+            match self.pending.pop() {
+                Some(hop) => std::task::Poll::Ready(hop),
+                None => std::task::Poll::Pending,
+            }
+        }
+        fn accepts_padding_from(&self, _hop: HopNum) -> bool {
+            true
+        }
+    }
+
+    #[cfg(feature = "circ-padding")]
+    #[traced_test]
+    #[test]
+    fn circ_padding() {
+        use padding::PaddingEvent;
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+            let events = Arc::new(Mutex::new(vec![]));
+
+            // The padding to hop 7 is discarded, since there is no such hop.
+            let padder = TestPadder {
+                pending: vec![7.into(), 1.into()],
+                events: Arc::clone(&events),
+            };
+            circ.set_padder(padder).await.unwrap();
+
+            let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+            let rmsg = match chmsg {
+                AnyChanMsg::Relay(r) => {
+                    AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                        .unwrap()
+                }
+                other => panic!("{:?}", other),
+            };
+            assert_eq!(rmsg.cmd(), RelayCmd::DROP);
+
+            // Padding from the circuit's hops is accepted, since our padder asked for it.
+            let drop = relaymsg::Drop::default().into();
+            sink.send(rmsg_to_ccmsg(None, drop)).await.unwrap();
+            rt.advance_until_stalled().await;
+            assert!(!circ.is_closing());
+
+            assert_eq!(
+                events.lock().unwrap().as_slice(),
+                [
+                    (1.into(), PaddingEvent::PaddingSent),
+                    (2.into(), PaddingEvent::PaddingReceived),
+                ]
+            );
+        });
+    }
+
+    #[cfg(feature = "circ-padding")]
+    #[traced_test]
+    #[test]
+    fn stream_hidden_from_padding() {
+        use padding::PaddingEvent;
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, _circ_sink) = newcirc(&rt, chan).await;
+            let events = Arc::new(Mutex::new(vec![]));
+            let padder = TestPadder {
+                pending: vec![],
+                events: Arc::clone(&events),
+            };
+            circ.set_padder(padder).await.unwrap();
+
+            let mut params = StreamParameters::new();
+            params.optimistic(true);
+            let mut shown = circ
+                .begin_stream("www.example.com", 80, Some(params.clone()))
+                .await
+                .unwrap();
+            params.circuit_padding(false);
+            let mut hidden = circ
+                .begin_stream("www.example.com", 80, Some(params))
+                .await
+                .unwrap();
+            for _ in 0..2 {
+                let (_, msg) = next_relay_msg(&mut rx).await;
+                assert_eq!(msg.cmd(), RelayCmd::BEGIN);
+            }
+            events.lock().unwrap().clear();
+
+            // The hidden stream's data isn't reported to the padder...
+            hidden.write_all(b"keepalive").await.unwrap();
+            hidden.flush().await.unwrap();
+            let (_, msg) = next_relay_msg(&mut rx).await;
+            assert_eq!(msg.cmd(), RelayCmd::DATA);
+            rt.advance_until_stalled().await;
+            assert!(events.lock().unwrap().is_empty());
+
+            // ...but the other stream's data is.
+            shown.write_all(b"hello").await.unwrap();
+            shown.flush().await.unwrap();
+            let (_, msg) = next_relay_msg(&mut rx).await;
+            assert_eq!(msg.cmd(), RelayCmd::DATA);
+            rt.advance_until_stalled().await;
+            assert_eq!(
+                events.lock().unwrap().as_slice(),
+                [(2.into(), PaddingEvent::NonPaddingSent)]
+            );
+        });
+    }
+
+    #[cfg(feature = "circ-padding")]
+    #[traced_test]
+    #[test]
+    fn negotiate_padding() {
+        use tor_cell::relaycell::padding::PaddingResponse;
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            // Accept the first request, and refuse the second.
+            let relay = async move {
+                for response in [PaddingResponse::OK, PaddingResponse::ERR] {
+                    let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                    let rmsg = match chmsg {
+                        AnyChanMsg::Relay(r) => AnyRelayMsgOuter::decode_singleton(
+                            RelayCellFormat::V0,
+                            r.into_relay_body(),
+                        )
+                        .unwrap(),
+                        other => panic!("{:?}", other),
+                    };
+                    let AnyRelayMsg::PaddingNegotiate(req) = rmsg.into_streamid_and_msg().1 else {
+                        panic!("expected PADDING_NEGOTIATE");
+                    };
+                    let reply = relaymsg::PaddingNegotiated::new(
+                        req.command(),
+                        response,
+                        req.machine_type(),
+                        req.machine_ctr(),
+                    );
+                    sink.send(rmsg_to_ccmsg(None, reply.into())).await.unwrap();
+                }
+                (rx, sink)
+            };
+            let client = async {
+                circ.negotiate_padding(2.into(), relaymsg::PaddingNegotiate::start(3, 1))
+                    .await
+                    .unwrap();
+                let err = circ
+                    .negotiate_padding(2.into(), relaymsg::PaddingNegotiate::stop(3, 1))
+                    .await
+                    .unwrap_err();
+                assert!(matches!(err, Error::PaddingRefused(3)));
+            };
+
+            let ((_rx, _sink), ()) = futures::join!(relay, client);
+            rt.advance_until_stalled().await;
+            assert!(!circ.is_closing());
+        });
+    }
+
+    /// Read the next cell sent on the channel, and decode it as a relay message.
+    #[cfg(feature = "conflux")]
+    async fn next_conflux_msg(rx: &mut Receiver<AnyChanCell>) -> (CircId, AnyRelayMsg) {
+        let (id, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+        let rmsg = match chmsg {
+            AnyChanMsg::Relay(r) => {
+                AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                    .unwrap()
+            }
+            other => panic!("{:?}", other),
+        };
+        (id.unwrap(), rmsg.into_streamid_and_msg().1)
+    }
+
+    /// Link two new circuits into a conflux set, answering for the relay.
+    ///
+    /// Returns the first circuit, the channel's receiver and sender,
+    /// and the senders for each circuit.
+    /// These must be kept alive, or the reactor will exit.
+    #[cfg(feature = "conflux")]
+    async fn setup_linked_circuits<R: Runtime>(
+        rt: &R,
+    ) -> (
+        Arc<ClientCirc>,
+        Receiver<AnyChanCell>,
+        Sender<std::result::Result<OpenChanCellS2C, CodecError>>,
+        CircuitRxSender,
+        CircuitRxSender,
+    ) {
+        use tor_cell::relaycell::conflux::{V1DesiredUx, V1LinkPayload};
+
+        let (chan, mut rx, chan_sink) = working_fake_channel(rt);
+        let (circ1, mut sink1) = newcirc(rt, Arc::clone(&chan)).await;
+        let (circ2, mut sink2) =
+            newcirc_with_id(rt, chan, 2.into(), CircId::new(129).unwrap()).await;
+        let circid1 = circ1.peek_circid();
+        let circid2 = circ2.peek_circid();
+
+        let link_fut = circ1.link_circuits(vec![circ2]);
+        let reply_fut = async move {
+            // We get a LINK on each circuit, with the same nonce.
+            let mut nonces = HashMap::new();
+            for _ in 0..2 {
+                let (id, rmsg) = next_conflux_msg(&mut rx).await;
+                let AnyRelayMsg::ConfluxLink(link) = rmsg else {
+                    panic!("{:?}", rmsg);
+                };
+                nonces.insert(id, *link.payload().nonce());
+            }
+            let nonce = nonces[&circid1];
+            assert_eq!(nonce, nonces[&circid2]);
+
+            for sink in [&mut sink1, &mut sink2] {
+                let payload = V1LinkPayload::new(nonce, V1DesiredUx::NO_OPINION);
+                let linked = relaymsg::ConfluxLinked::new(payload).into();
+                sink.send(rmsg_to_ccmsg(None, linked)).await.unwrap();
+            }
+
+            // Each LINKED is acknowledged on the circuit it arrived on.
+            let mut acked = vec![];
+            for _ in 0..2 {
+                let (id, rmsg) = next_conflux_msg(&mut rx).await;
+                assert_eq!(rmsg.cmd(), RelayCmd::CONFLUX_LINKED_ACK);
+                acked.push(id);
+            }
+            assert!(acked.contains(&circid1));
+            assert!(acked.contains(&circid2));
+
+            (rx, sink1, sink2)
+        };
+
+        let (res, (rx, sink1, sink2)) = futures::join!(link_fut, reply_fut);
+        res.unwrap();
+        (circ1, rx, chan_sink, sink1, sink2)
+    }
+
+    #[test]
+    #[cfg(feature = "conflux")]
+    fn link_circuits() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (circ1, _rx, _chan_sink, _sink1, _sink2) = setup_linked_circuits(&rt).await;
+            assert!(!circ1.is_closing());
+            // Neither exit has sent a SENDME, so we have no RTT for either leg.
+            assert_eq!(circ1.leg_rtts().await.unwrap(), vec![None, None]);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "conflux")]
+    fn conflux_ooo_limit() {
+        use crate::tunnel::reactor::MAX_OOO_MSGS;
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (circ1, mut rx, _chan_sink, _sink1, mut sink2) = setup_linked_circuits(&rt).await;
+            // Throw away the SENDMEs that we send as the data arrives.
+            rt.spawn(async move { while rx.next().await.is_some() {} })
+                .unwrap();
+
+            // Skip a sequence number, so that none of the data can be delivered.
+            let switch = relaymsg::ConfluxSwitch::new(1).into();
+            sink2.send(rmsg_to_ccmsg(None, switch)).await.unwrap();
+            let data: AnyRelayMsg = relaymsg::Data::new(b"x").unwrap().into();
+            for _ in 0..MAX_OOO_MSGS {
+                sink2
+                    .send(rmsg_to_ccmsg(StreamId::new(7), data.clone()))
+                    .await
+                    .unwrap();
+            }
+            rt.advance_until_stalled().await;
+            assert!(!circ1.is_closing());
+
+            // One more message is too many: the whole set is closed.
+            sink2
+                .send(rmsg_to_ccmsg(StreamId::new(7), data))
+                .await
+                .unwrap();
+            rt.advance_until_stalled().await;
+            assert!(circ1.is_closing());
+        });
+    }
 }
@@ -15,6 +15,8 @@ use tor_cell::relaycell::{RelayCellFormat, RelayCellFormatV0};
 use tor_error::internal;
 
 use crate::crypto::binding::CircuitBinding;
+#[cfg(feature = "counter-galois-onion")]
+use crate::crypto::cell::cgo;
 #[cfg(feature = "hs-common")]
 use crate::crypto::cell::Tor1Hsv3RelayCrypto;
 use crate::crypto::cell::{
@@ -59,6 +61,26 @@ pub(crate) enum RelayCryptLayerProtocol {
     /// - <https://spec.torproject.org/rend-spec/introduction-protocol.html#INTRO-HANDSHAKE-REQS>
     #[cfg(feature = "hs-common")]
     HsV3(RelayCellFormat),
+    /// The Counter Galois Onion cell encryption protocol, using AES-128.
+    ///
+    /// Only usable by the initiator of a handshake.  The client asks for it
+    /// with a [`SubprotocolRequest`](tor_cell::relaycell::extend::NtorV3Extension::SubprotocolRequest)
+    /// for [`cgo_subproto`] in its ntor v3 handshake.
+    ///
+    /// Reference:
+    /// - <https://spec.torproject.org/proposals/359-cgo-redux.html>
+    #[cfg(feature = "counter-galois-onion")]
+    Cgo(RelayCellFormat),
+}
+
+/// Return the `(protocol, version)` pair with which a client asks a relay to
+/// use CGO relay cell encryption, in a
+/// [`SubprotocolRequest`](tor_cell::relaycell::extend::NtorV3Extension::SubprotocolRequest).
+#[cfg(feature = "counter-galois-onion")]
+pub(crate) fn cgo_subproto() -> (u8, u8) {
+    let cgo = tor_protover::named::RELAY_CRYPT_CGO;
+    let protocol = u8::try_from(cgo.kind().get()).expect("Relay protocol number out of range");
+    (protocol, cgo.version())
 }
 
 #[cfg(feature = "hs-common")]
@@ -110,6 +132,10 @@ impl RelayCryptLayerProtocol {
             HsV3(V0) => construct::<Tor1Hsv3RelayCrypto<RelayCellFormatV0>, _>(keygen, role),
             #[cfg(feature = "hs-common")]
             HsV3(_) => Err(internal!("protocol not implemented").into()),
+            #[cfg(feature = "counter-galois-onion")]
+            Cgo(V1) => construct_initiator::<cgo::CryptStatePair<aes::Aes128>, _, _>(keygen, role),
+            #[cfg(feature = "counter-galois-onion")]
+            Cgo(_) => Err(internal!("protocol not implemented").into()),
         }
     }
 
@@ -119,6 +145,8 @@ impl RelayCryptLayerProtocol {
             RelayCryptLayerProtocol::Tor1(v) => *v,
             #[cfg(feature = "hs-common")]
             RelayCryptLayerProtocol::HsV3(v) => *v,
+            #[cfg(feature = "counter-galois-onion")]
+            RelayCryptLayerProtocol::Cgo(v) => *v,
         }
     }
 }
@@ -141,3 +169,30 @@ where
         binding: Some(binding),
     })
 }
+
+/// Helper: Construct a BoxedClientLayer for a layer type L whose outbound and
+/// inbound cryptographic states are different types.
+///
+/// Such layers can't be used with their directions reversed, so this gives an
+/// error if we are the responder.
+#[cfg(feature = "counter-galois-onion")]
+fn construct_initiator<L, F, B>(
+    keygen: impl KeyGenerator,
+    role: HandshakeRole,
+) -> Result<BoxedClientLayer>
+where
+    L: CryptInit + ClientLayer<F, B>,
+    F: OutboundClientLayer + Send + 'static,
+    B: InboundClientLayer + Send + 'static,
+{
+    if role != HandshakeRole::Initiator {
+        return Err(internal!("tried to use a client-only protocol as responder").into());
+    }
+    let layer = L::construct(keygen)?;
+    let (fwd, back, binding) = layer.split_client_layer();
+    Ok(BoxedClientLayer {
+        fwd: Box::new(fwd),
+        back: Box::new(back),
+        binding: Some(binding),
+    })
+}
@@ -0,0 +1,108 @@
+//! Describing the cryptography that each hop of a circuit uses.
+//!
+//! See [`ClientCirc::crypto_info`](super::ClientCirc::crypto_info).
+
+use tor_cell::chancell::msg::HandshakeType;
+use tor_cell::relaycell::RelayCellFormat;
+
+use super::handshake::RelayCryptLayerProtocol;
+
+/// The handshake that we used to negotiate the keys for a hop.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum HopHandshake {
+    /// A CREATE_FAST handshake, which has no public-key authentication.
+    ///
+    /// This is only used for the first hop.
+    CreateFast,
+    /// A CREATE2 or EXTEND2 handshake of the given type.
+    Create2(HandshakeType),
+    /// The keys were negotiated outside the circuit, and added with
+    /// [`ClientCirc::extend_virtual`](super::ClientCirc::extend_virtual).
+    ///
+    /// This is how onion service circuits get their final, virtual, hop.
+    Virtual,
+}
+
+/// The relay cell encryption protocol that a hop uses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum HopCryptoProtocol {
+    /// The original Tor cell encryption protocol, using AES-128 and SHA-1.
+    Tor1,
+    /// The onion service variant of the original protocol, using AES-256 and
+    /// SHA3-256.
+    HsV3,
+    /// The Counter Galois Onion cell encryption protocol, using AES-128.
+    Cgo,
+}
+
+impl HopCryptoProtocol {
+    /// Return the name of the block cipher that this protocol uses.
+    pub fn cipher(&self) -> &'static str {
+        match self {
+            HopCryptoProtocol::Tor1 => "AES-128-CTR",
+            HopCryptoProtocol::HsV3 => "AES-256-CTR",
+            HopCryptoProtocol::Cgo => "AES-128",
+        }
+    }
+
+    /// Return the name of the digest that this protocol uses to recognize
+    /// and authenticate cells.
+    ///
+    /// This is `None` for CGO, which authenticates cells with tags instead
+    /// of a running digest.
+    pub fn digest(&self) -> Option<&'static str> {
+        match self {
+            HopCryptoProtocol::Tor1 => Some("SHA-1"),
+            HopCryptoProtocol::HsV3 => Some("SHA3-256"),
+            HopCryptoProtocol::Cgo => None,
+        }
+    }
+}
+
+/// A description of the cryptography that a single hop of a circuit uses.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct HopCryptoInfo {
+    /// The handshake that negotiated this hop's keys.
+    handshake: HopHandshake,
+    /// The relay cell encryption protocol.
+    protocol: HopCryptoProtocol,
+    /// The relay cell format.
+    relay_cell_format: RelayCellFormat,
+}
+
+impl HopCryptoInfo {
+    /// Describe a hop that was negotiated with `handshake`, and uses `protocol`.
+    pub(crate) fn new(handshake: HopHandshake, protocol: RelayCryptLayerProtocol) -> Self {
+        let relay_cell_format = protocol.relay_cell_format();
+        let protocol = match protocol {
+            RelayCryptLayerProtocol::Tor1(_) => HopCryptoProtocol::Tor1,
+            #[cfg(feature = "hs-common")]
+            RelayCryptLayerProtocol::HsV3(_) => HopCryptoProtocol::HsV3,
+            #[cfg(feature = "counter-galois-onion")]
+            RelayCryptLayerProtocol::Cgo(_) => HopCryptoProtocol::Cgo,
+        };
+        Self {
+            handshake,
+            protocol,
+            relay_cell_format,
+        }
+    }
+
+    /// Return the handshake that negotiated this hop's keys.
+    pub fn handshake(&self) -> HopHandshake {
+        self.handshake
+    }
+
+    /// Return the relay cell encryption protocol that this hop uses.
+    pub fn protocol(&self) -> HopCryptoProtocol {
+        self.protocol
+    }
+
+    /// Return the relay cell format that this hop uses.
+    pub fn relay_cell_format(&self) -> RelayCellFormat {
+        self.relay_cell_format
+    }
+}
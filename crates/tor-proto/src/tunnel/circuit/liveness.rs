@@ -0,0 +1,76 @@
+//! Checking whether a circuit is still alive.
+//!
+//! See [`ClientCirc::probe_liveness`](super::ClientCirc::probe_liveness).
+
+use std::time::Duration;
+
+/// The result of probing a hop of a circuit, to see whether it is still alive.
+///
+/// The probe sends the hop a request that it has to answer, and waits a limited
+/// time for the answer. If the answer arrived in time, the hop is alive, and
+/// [`reply_rtt`](LivenessProbe::reply_rtt) says how long it took. If it didn't,
+/// the hop (or some hop before it) is not passing cells along any more, and the
+/// circuit should not be used.
+///
+/// The probe also reports when the hop was last heard from, and the
+/// round-trip time most recently measured from the hop's SENDMEs.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct LivenessProbe {
+    /// How long the hop took to answer the probe, if it answered in time.
+    reply_rtt: Option<Duration>,
+    /// How long ago we last received a cell from the hop.
+    since_last_received: Option<Duration>,
+    /// The smoothed round-trip time to the hop, measured from its SENDMEs.
+    sendme_rtt: Option<Duration>,
+}
+
+impl LivenessProbe {
+    /// Create a new `LivenessProbe`, with no reply to the probe.
+    pub(crate) fn new(since_last_received: Option<Duration>, sendme_rtt: Option<Duration>) -> Self {
+        Self {
+            reply_rtt: None,
+            since_last_received,
+            sendme_rtt,
+        }
+    }
+
+    /// Return this `LivenessProbe`, recording that the hop answered the probe
+    /// after `reply_rtt`, or (if it is `None`) that it didn't answer in time.
+    pub(crate) fn with_reply_rtt(self, reply_rtt: Option<Duration>) -> Self {
+        Self { reply_rtt, ..self }
+    }
+
+    /// Return true if the hop answered the probe in time.
+    pub fn is_alive(&self) -> bool {
+        self.reply_rtt.is_some()
+    }
+
+    /// Return how long the hop took to answer the probe,
+    /// or `None` if it didn't answer in time.
+    pub fn reply_rtt(&self) -> Option<Duration> {
+        self.reply_rtt
+    }
+
+    /// Return how long ago we last received a cell from the hop,
+    /// or `None` if we never have.
+    pub fn since_last_received(&self) -> Option<Duration> {
+        self.since_last_received
+    }
+
+    /// Return the smoothed round-trip time to the hop, as measured from the
+    /// time between sending cells and getting the SENDMEs that acknowledge them.
+    ///
+    /// This is `None` if we have no RTT estimate, either because the hop hasn't
+    /// sent us a SENDME yet, or because the congestion control algorithm in
+    /// use doesn't measure RTT.
+    pub fn sendme_rtt(&self) -> Option<Duration> {
+        self.sendme_rtt
+    }
+
+    /// Return true if we have heard from the hop within `limit`.
+    pub fn heard_within(&self, limit: Duration) -> bool {
+        self.since_last_received
+            .is_some_and(|elapsed| elapsed <= limit)
+    }
+}
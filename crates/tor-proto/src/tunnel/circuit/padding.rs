@@ -0,0 +1,178 @@
+//! Hooks for circuit-level traffic shaping.
+//!
+//! A [`CircuitPadder`] can be attached to a circuit in order to experiment
+//! with defenses against website fingerprinting, such as constant-rate
+//! padding, or machines in the style of FRONT or Tamaraw.
+//!
+//! The padder is told about every relay cell that the circuit sends or
+//! receives, and may ask the circuit to send `DROP` cells to any hop.
+//! It runs inside the circuit reactor, so its methods must never block.
+//!
+//! By default, circuits use [`NoPadding`], which does nothing.
+//! [`MachinePadder`] runs padding machines in the style of the C Tor
+//! circuit padding framework.
+//!
+//! # Limitations
+//!
+//! A padder can only add cells: it cannot yet delay or reorder the circuit's
+//! real traffic.
+
+use std::task::{Context, Poll};
+
+use crate::crypto::cell::HopNum;
+
+#[cfg(feature = "circ-padding")]
+mod machine;
+
+#[cfg(feature = "circ-padding")]
+pub use machine::{
+    Histogram, MachineError, MachineEvent, MachinePadder, MachineState, PaddingMachine, Transition,
+};
+
+#[cfg(feature = "circ-padding")]
+use {
+    crate::tunnel::reactor::{circuit::Circuit, MetaCellDisposition, MetaCellHandler},
+    crate::{Error, Result},
+    oneshot_fused_workaround as oneshot,
+    tor_cell::relaycell::msg::{PaddingNegotiate, PaddingNegotiated},
+    tor_cell::relaycell::padding::PaddingResponse,
+    tor_cell::relaycell::{RelayCmd, UnparsedRelayMsg},
+};
+
+/// An event on a circuit, as reported to a [`CircuitPadder`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PaddingEvent {
+    /// We sent a relay cell that was not padding.
+    NonPaddingSent,
+    /// We sent a `DROP` cell.
+    ///
+    /// This is reported for every `DROP` cell we send, including those
+    /// that our padder did not ask for.
+    PaddingSent,
+    /// We received a relay cell that was not padding.
+    NonPaddingReceived,
+    /// We received a `DROP` cell.
+    PaddingReceived,
+}
+
+/// A traffic-shaping defense attached to a single circuit.
+///
+/// Implementations usually hold a
+/// [`SleepProvider`](tor_rtcompat::SleepProvider) of their own,
+/// so that they can schedule padding in [`poll_padding`](Self::poll_padding).
+pub trait CircuitPadder: Send {
+    /// Note that `event` has happened on the hop `hop` of this circuit.
+    ///
+    /// This is called for every relay cell that we send or receive,
+    /// including cells on streams, and before we send anything in response.
+    fn note_event(&mut self, hop: HopNum, event: PaddingEvent);
+
+    /// Check whether we should send a padding cell.
+    ///
+    /// Return `Poll::Ready(hop)` to send a single `DROP` cell to `hop`.
+    /// Otherwise, return `Poll::Pending`, and arrange for `cx` to be woken
+    /// when padding should next be sent.
+    ///
+    /// Padding to a hop that doesn't exist (yet) is discarded.
+    fn poll_padding(&mut self, cx: &mut Context<'_>) -> Poll<HopNum>;
+
+    /// Return true if we should accept `DROP` cells from `hop`.
+    ///
+    /// Unexpected `DROP` cells are a protocol violation, and close the
+    /// circuit; a padder should only return true here if it has arranged for
+    /// the hop to send us padding.
+    fn accepts_padding_from(&self, hop: HopNum) -> bool {
+        let _ = hop;
+        false
+    }
+}
+
+/// A [`CircuitPadder`] that never sends padding.
+///
+/// This is the default for every circuit.
+#[derive(Clone, Copy, Debug, Default)]
+#[allow(clippy::exhaustive_structs)]
+pub struct NoPadding;
+
+impl CircuitPadder for NoPadding {
+    fn note_event(&mut self, _hop: HopNum, _event: PaddingEvent) {}
+
+    fn poll_padding(&mut self, _cx: &mut Context<'_>) -> Poll<HopNum> {
+        Poll::Pending
+    }
+}
+
+/// A [`MetaCellHandler`] that waits for the `PADDING_NEGOTIATED` reply
+/// to a `PADDING_NEGOTIATE` message that we sent.
+#[cfg(feature = "circ-padding")]
+pub(crate) struct PaddingNegotiationHandler {
+    /// The hop we sent the request to.
+    hop: HopNum,
+    /// The request that we sent.
+    request: PaddingNegotiate,
+    /// A channel to tell the caller whether the relay accepted the request.
+    done: Option<oneshot::Sender<Result<()>>>,
+}
+
+#[cfg(feature = "circ-padding")]
+impl PaddingNegotiationHandler {
+    /// Create a new handler for the reply to `request`, which we sent to `hop`.
+    pub(crate) fn new(
+        hop: HopNum,
+        request: PaddingNegotiate,
+        done: oneshot::Sender<Result<()>>,
+    ) -> Self {
+        Self {
+            hop,
+            request,
+            done: Some(done),
+        }
+    }
+}
+
+#[cfg(feature = "circ-padding")]
+impl MetaCellHandler for PaddingNegotiationHandler {
+    fn expected_hop(&self) -> HopNum {
+        self.hop
+    }
+
+    fn handle_msg(
+        &mut self,
+        msg: UnparsedRelayMsg,
+        _reactor: &mut Circuit,
+    ) -> Result<MetaCellDisposition> {
+        if msg.cmd() != RelayCmd::PADDING_NEGOTIATED {
+            return Err(Error::CircProto(format!(
+                "Unexpected {} cell while negotiating padding",
+                msg.cmd()
+            )));
+        }
+        let reply = msg
+            .decode::<PaddingNegotiated>()
+            .map_err(|e| Error::from_bytes_err(e, "padding negotiated message"))?
+            .into_msg();
+
+        let request = &self.request;
+        if reply.command() != request.command()
+            || reply.machine_type() != request.machine_type()
+            || reply.machine_ctr() != request.machine_ctr()
+        {
+            return Err(Error::CircProto(
+                "PADDING_NEGOTIATED does not match our request".into(),
+            ));
+        }
+
+        let outcome = if reply.response() == PaddingResponse::OK {
+            Ok(())
+        } else {
+            Err(Error::PaddingRefused(reply.machine_type()))
+        };
+        if let Some(done) = self.done.take() {
+            // Don't care if the receiver goes away.
+            let _ = done.send(outcome);
+        }
+
+        Ok(MetaCellDisposition::ConversationFinished)
+    }
+}
@@ -0,0 +1,632 @@
+//! Padding machines, in the style of the circuit padding framework of C Tor.
+//!
+//! A [`PaddingMachine`] is a list of [`MachineState`]s. A state may have a
+//! [`Histogram`] of delays: whenever the machine enters the state, it samples a
+//! delay from the histogram, and sends a `DROP` cell once that delay has passed.
+//! Events on the circuit, such as sending or receiving a cell, move the machine
+//! between states according to each state's [`Transition`]s.
+//!
+//! A [`MachinePadder`] runs one or more machines, each attached to a hop of the
+//! circuit; it can be installed with
+//! [`ClientCirc::set_padder`](crate::circuit::ClientCirc::set_padder).
+//! If the relay at that hop should send padding too, ask it to start one of its
+//! own machines with
+//! [`ClientCirc::negotiate_padding`](crate::circuit::ClientCirc::negotiate_padding).
+//!
+//! See the "Circuit-level padding" section of `padding-spec.txt`.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::FutureExt as _;
+use rand::Rng;
+use tor_rtcompat::SleepProvider;
+use tracing::debug;
+
+use super::{CircuitPadder, PaddingEvent};
+use crate::crypto::cell::HopNum;
+
+/// The maximum number of events that a single circuit event may cause a
+/// machine to process.
+///
+/// This stops a badly designed machine from looping forever, for instance by
+/// re-entering a state with an empty histogram on every `BinsEmpty` event.
+const MAX_EVENTS_PER_EVENT: usize = 16;
+
+/// An error while building a padding machine.
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum MachineError {
+    /// A histogram had the wrong number of bin edges.
+    #[error("Histogram with {n_bins} bins has {n_edges} edges")]
+    BadEdgeCount {
+        /// The number of bins.
+        n_bins: usize,
+        /// The number of edges.
+        n_edges: usize,
+    },
+    /// The edges of a histogram were not strictly increasing.
+    #[error("Histogram edges are not strictly increasing")]
+    UnsortedEdges,
+    /// A transition pointed to a state that does not exist.
+    #[error("Transition to nonexistent state {0}")]
+    NoSuchState(usize),
+    /// The machine had no states.
+    #[error("Padding machine has no states")]
+    NoStates,
+}
+
+/// An event that may cause a [`PaddingMachine`] to change state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum MachineEvent {
+    /// We sent a relay cell that was not padding.
+    NonPaddingSent,
+    /// We sent a padding cell.
+    PaddingSent,
+    /// We received a relay cell that was not padding.
+    NonPaddingReceived,
+    /// We received a padding cell.
+    PaddingReceived,
+    /// We sampled the "infinity" bin of the histogram, so no padding is scheduled.
+    Infinity,
+    /// The histogram of the current state has run out of tokens.
+    BinsEmpty,
+    /// We have sent as much padding as the current state allows.
+    LengthCount,
+}
+
+impl From<PaddingEvent> for MachineEvent {
+    fn from(event: PaddingEvent) -> Self {
+        match event {
+            PaddingEvent::NonPaddingSent => MachineEvent::NonPaddingSent,
+            PaddingEvent::PaddingSent => MachineEvent::PaddingSent,
+            PaddingEvent::NonPaddingReceived => MachineEvent::NonPaddingReceived,
+            PaddingEvent::PaddingReceived => MachineEvent::PaddingReceived,
+        }
+    }
+}
+
+/// What a [`PaddingMachine`] should do in response to a [`MachineEvent`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Transition {
+    /// Move to the state with this index, and schedule padding.
+    ///
+    /// Moving to a different state refills its histogram and resets its
+    /// length count; moving to the current state only reschedules padding.
+    Goto(usize),
+    /// Cancel any scheduled padding, and stay in the current state.
+    Cancel,
+    /// Stop the machine.
+    End,
+}
+
+/// A histogram of delays between padding cells.
+///
+/// Each bin holds a number of tokens: the chance of picking a bin is
+/// proportional to its tokens, and the delay is then picked uniformly within
+/// the bin. An extra "infinity" bin stands for not sending padding at all.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    /// The edges of the bins: bin `i` covers delays in `edges[i]..edges[i + 1]`.
+    edges: Vec<Duration>,
+    /// The number of tokens in each bin.
+    tokens: Vec<u32>,
+    /// The number of tokens in the infinity bin.
+    infinity_tokens: u32,
+}
+
+/// The outcome of sampling a [`Histogram`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Sample {
+    /// Send padding after `delay`, which was taken from bin `bin`.
+    Delay {
+        /// The index of the bin.
+        bin: usize,
+        /// The delay.
+        delay: Duration,
+    },
+    /// Don't send padding.
+    Infinity,
+    /// There are no tokens left in any bin.
+    Empty,
+}
+
+impl Histogram {
+    /// Create a new histogram.
+    ///
+    /// `edges` must be strictly increasing, and have one more element than
+    /// `tokens`.
+    pub fn new(
+        edges: Vec<Duration>,
+        tokens: Vec<u32>,
+        infinity_tokens: u32,
+    ) -> Result<Self, MachineError> {
+        if edges.len() != tokens.len() + 1 {
+            return Err(MachineError::BadEdgeCount {
+                n_bins: tokens.len(),
+                n_edges: edges.len(),
+            });
+        }
+        if edges.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(MachineError::UnsortedEdges);
+        }
+        Ok(Self {
+            edges,
+            tokens,
+            infinity_tokens,
+        })
+    }
+
+    /// Return true if there are no tokens left, other than in the infinity bin.
+    fn bins_empty(&self) -> bool {
+        self.tokens.iter().all(|t| *t == 0)
+    }
+
+    /// Remove a token from bin `bin`, if it has any.
+    fn remove_token(&mut self, bin: usize) {
+        if let Some(t) = self.tokens.get_mut(bin) {
+            *t = t.saturating_sub(1);
+        }
+    }
+
+    /// Pick a delay from this histogram.
+    fn sample<R: Rng>(&self, rng: &mut R) -> Sample {
+        let total: u64 = self.tokens.iter().map(|t| u64::from(*t)).sum::<u64>()
+            + u64::from(self.infinity_tokens);
+        if total == 0 {
+            return Sample::Empty;
+        }
+
+        let mut pick = rng.random_range(0..total);
+        for (bin, tokens) in self.tokens.iter().enumerate() {
+            let tokens = u64::from(*tokens);
+            if pick < tokens {
+                let delay = rng.random_range(self.edges[bin]..self.edges[bin + 1]);
+                return Sample::Delay { bin, delay };
+            }
+            pick -= tokens;
+        }
+        Sample::Infinity
+    }
+}
+
+/// A single state of a [`PaddingMachine`].
+#[derive(Clone, Debug, Default)]
+pub struct MachineState {
+    /// The delays between padding cells, or `None` if this state never pads.
+    histogram: Option<Histogram>,
+    /// The maximum number of padding cells to send in this state.
+    length: Option<u32>,
+    /// If true, remove a token from the histogram whenever we send padding.
+    remove_tokens: bool,
+    /// What to do on each event. Events that aren't listed are ignored.
+    transitions: Vec<(MachineEvent, Transition)>,
+}
+
+impl MachineState {
+    /// Create a new state that never sends padding, and ignores every event.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `histogram` to schedule padding in this state.
+    pub fn with_histogram(mut self, histogram: Histogram) -> Self {
+        self.histogram = Some(histogram);
+        self
+    }
+
+    /// Send at most `length` padding cells in this state.
+    ///
+    /// After that, the machine gets a [`MachineEvent::LengthCount`] event.
+    pub fn with_length(mut self, length: u32) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// Remove a token from the histogram every time we send padding.
+    ///
+    /// When the histogram runs out, the machine gets a
+    /// [`MachineEvent::BinsEmpty`] event.
+    pub fn with_token_removal(mut self) -> Self {
+        self.remove_tokens = true;
+        self
+    }
+
+    /// Take `transition` whenever `event` happens in this state.
+    ///
+    /// This replaces any earlier transition for `event`.
+    pub fn on(mut self, event: MachineEvent, transition: Transition) -> Self {
+        self.transitions.retain(|(e, _)| *e != event);
+        self.transitions.push((event, transition));
+        self
+    }
+
+    /// Return the transition to take on `event`, if any.
+    fn transition(&self, event: MachineEvent) -> Option<Transition> {
+        self.transitions
+            .iter()
+            .find(|(e, _)| *e == event)
+            .map(|(_, t)| *t)
+    }
+}
+
+/// A circuit padding machine.
+///
+/// The machine starts in its first state.
+#[derive(Clone, Debug)]
+pub struct PaddingMachine {
+    /// The states of this machine.
+    states: Vec<MachineState>,
+}
+
+impl PaddingMachine {
+    /// Create a new padding machine from its states.
+    ///
+    /// Return an error if there are no states, or if a transition refers to a
+    /// state that doesn't exist.
+    pub fn new(states: Vec<MachineState>) -> Result<Self, MachineError> {
+        if states.is_empty() {
+            return Err(MachineError::NoStates);
+        }
+        for state in &states {
+            for (_, transition) in &state.transitions {
+                if let Transition::Goto(idx) = transition {
+                    if *idx >= states.len() {
+                        return Err(MachineError::NoSuchState(*idx));
+                    }
+                }
+            }
+        }
+        Ok(Self { states })
+    }
+}
+
+/// The state of a running [`PaddingMachine`].
+struct ActiveState<F> {
+    /// The index of the current state.
+    index: usize,
+    /// The histogram of the current state, with the tokens we have left.
+    histogram: Option<Histogram>,
+    /// The number of padding cells sent in this state.
+    n_sent: u32,
+    /// The padding we have scheduled, if any, and the bin its delay came from.
+    timer: Option<(Pin<Box<F>>, usize)>,
+    /// If we asked for a padding cell that hasn't been sent yet,
+    /// the bin its delay came from.
+    sent_bin: Option<usize>,
+}
+
+/// A [`PaddingMachine`] running on a single hop of a circuit.
+struct MachineRunner<R: SleepProvider> {
+    /// The hop that this machine pads to.
+    hop: HopNum,
+    /// The machine itself.
+    machine: PaddingMachine,
+    /// The current state, or `None` if the machine has ended.
+    active: Option<ActiveState<R::SleepFuture>>,
+}
+
+impl<R: SleepProvider> MachineRunner<R> {
+    /// Start running `machine` on `hop`.
+    fn new(hop: HopNum, machine: PaddingMachine, runtime: &R) -> Self {
+        let mut runner = Self {
+            hop,
+            machine,
+            active: None,
+        };
+        let mut events = VecDeque::new();
+        runner.enter(0, runtime, &mut events);
+        runner.process(runtime, events);
+        runner
+    }
+
+    /// Move to state `index`, and schedule padding.
+    ///
+    /// Any events this causes are added to `events`.
+    fn enter(&mut self, index: usize, runtime: &R, events: &mut VecDeque<MachineEvent>) {
+        let state = &self.machine.states[index];
+        if self.active.as_ref().is_some_and(|a| a.index != index) {
+            self.active = None;
+        }
+        let active = self.active.get_or_insert_with(|| ActiveState {
+            index,
+            histogram: state.histogram.clone(),
+            n_sent: 0,
+            timer: None,
+            sent_bin: None,
+        });
+
+        active.timer = None;
+        if state.length.is_some_and(|len| active.n_sent >= len) {
+            return;
+        }
+        let Some(histogram) = &active.histogram else {
+            return;
+        };
+        match histogram.sample(&mut rand::rng()) {
+            Sample::Delay { bin, delay } => {
+                active.timer = Some((Box::pin(runtime.sleep(delay)), bin));
+            }
+            Sample::Infinity => events.push_back(MachineEvent::Infinity),
+            Sample::Empty => events.push_back(MachineEvent::BinsEmpty),
+        }
+    }
+
+    /// Process `events` in order, along with any events they cause.
+    fn process(&mut self, runtime: &R, mut events: VecDeque<MachineEvent>) {
+        let mut n_processed = 0;
+        while let Some(event) = events.pop_front() {
+            let Some(active) = self.active.as_mut() else {
+                return;
+            };
+            n_processed += 1;
+            if n_processed > MAX_EVENTS_PER_EVENT {
+                debug!("Padding machine on hop {} is looping", self.hop.display());
+                return;
+            }
+
+            let state = &self.machine.states[active.index];
+            if event == MachineEvent::PaddingSent {
+                if let Some(bin) = active.sent_bin.take() {
+                    active.n_sent = active.n_sent.saturating_add(1);
+                    if let Some(histogram) = active.histogram.as_mut() {
+                        if state.remove_tokens {
+                            histogram.remove_token(bin);
+                            if histogram.bins_empty() {
+                                events.push_back(MachineEvent::BinsEmpty);
+                            }
+                        }
+                    }
+                    if state.length.is_some_and(|len| active.n_sent >= len) {
+                        events.push_back(MachineEvent::LengthCount);
+                    }
+                }
+            }
+
+            match state.transition(event) {
+                None => {}
+                Some(Transition::Goto(index)) => {
+                    if index != active.index {
+                        // The remaining events belong to the state we are leaving.
+                        events.clear();
+                    }
+                    self.enter(index, runtime, &mut events);
+                }
+                Some(Transition::Cancel) => active.timer = None,
+                Some(Transition::End) => self.active = None,
+            }
+        }
+    }
+
+    /// Check whether our scheduled padding is due.
+    fn poll_timer(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(active) = self.active.as_mut() else {
+            return Poll::Pending;
+        };
+        let Some((timer, bin)) = active.timer.as_mut() else {
+            return Poll::Pending;
+        };
+        match timer.poll_unpin(cx) {
+            Poll::Ready(()) => {
+                active.sent_bin = Some(*bin);
+                active.timer = None;
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A [`CircuitPadder`] that runs [`PaddingMachine`]s.
+///
+/// It accepts padding from every hop that has a machine attached,
+/// even after that machine has ended.
+pub struct MachinePadder<R: SleepProvider> {
+    /// The runtime used to schedule padding.
+    runtime: R,
+    /// The machines that we are running.
+    machines: Vec<MachineRunner<R>>,
+}
+
+impl<R: SleepProvider> MachinePadder<R> {
+    /// Create a new `MachinePadder` with no machines.
+    pub fn new(runtime: R) -> Self {
+        Self {
+            runtime,
+            machines: Vec::new(),
+        }
+    }
+
+    /// Start running `machine` on the hop `hop`.
+    ///
+    /// Several machines may run on the same hop.
+    pub fn add_machine(&mut self, hop: HopNum, machine: PaddingMachine) {
+        let runner = MachineRunner::new(hop, machine, &self.runtime);
+        self.machines.push(runner);
+    }
+}
+
+impl<R: SleepProvider> CircuitPadder for MachinePadder<R> {
+    fn note_event(&mut self, hop: HopNum, event: PaddingEvent) {
+        for runner in self.machines.iter_mut().filter(|r| r.hop == hop) {
+            runner.process(&self.runtime, VecDeque::from([event.into()]));
+        }
+    }
+
+    fn poll_padding(&mut self, cx: &mut Context<'_>) -> Poll<HopNum> {
+        for runner in &mut self.machines {
+            if runner.poll_timer(cx).is_ready() {
+                return Poll::Ready(runner.hop);
+            }
+        }
+        Poll::Pending
+    }
+
+    fn accepts_padding_from(&self, hop: HopNum) -> bool {
+        self.machines.iter().any(|r| r.hop == hop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_rtmock::MockSleepProvider;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn histogram() {
+        assert!(matches!(
+            Histogram::new(vec![ms(0), ms(10)], vec![1, 1], 0),
+            Err(MachineError::BadEdgeCount { .. })
+        ));
+        assert!(matches!(
+            Histogram::new(vec![ms(10), ms(10)], vec![1], 0),
+            Err(MachineError::UnsortedEdges)
+        ));
+
+        let mut rng = testing_rng();
+        let h = Histogram::new(vec![ms(10), ms(20), ms(30)], vec![0, 3], 0).unwrap();
+        for _ in 0..100 {
+            match h.sample(&mut rng) {
+                Sample::Delay { bin, delay } => {
+                    assert_eq!(bin, 1);
+                    assert!(delay >= ms(20) && delay < ms(30));
+                }
+                other => panic!("unexpected sample {other:?}"),
+            }
+        }
+
+        let h = Histogram::new(vec![ms(10), ms(20)], vec![0], 5).unwrap();
+        assert_eq!(h.sample(&mut rng), Sample::Infinity);
+        let h = Histogram::new(vec![ms(10), ms(20)], vec![0], 0).unwrap();
+        assert_eq!(h.sample(&mut rng), Sample::Empty);
+    }
+
+    #[test]
+    fn bad_machine() {
+        assert!(matches!(
+            PaddingMachine::new(vec![]),
+            Err(MachineError::NoStates)
+        ));
+        let state = MachineState::new().on(MachineEvent::NonPaddingSent, Transition::Goto(1));
+        assert!(matches!(
+            PaddingMachine::new(vec![state]),
+            Err(MachineError::NoSuchState(1))
+        ));
+    }
+
+    #[test]
+    fn run_machine() {
+        futures::executor::block_on(async {
+            let sleep = MockSleepProvider::default();
+            let mut cx = Context::from_waker(noop_waker_ref());
+            let hop: HopNum = 1.into();
+
+            // Wait for some non-padding traffic, then send two padding
+            // cells, 10ms apart, and stop.
+            let machine = PaddingMachine::new(vec![
+                MachineState::new().on(MachineEvent::NonPaddingSent, Transition::Goto(1)),
+                MachineState::new()
+                    .with_histogram(Histogram::new(vec![ms(10), ms(11)], vec![2], 0).unwrap())
+                    .with_token_removal()
+                    .on(MachineEvent::PaddingSent, Transition::Goto(1))
+                    .on(MachineEvent::BinsEmpty, Transition::End),
+            ])
+            .unwrap();
+
+            let mut padder = MachinePadder::new(sleep.clone());
+            padder.add_machine(hop, machine);
+            assert!(padder.accepts_padding_from(hop));
+            assert!(!padder.accepts_padding_from(2.into()));
+
+            // Nothing is scheduled in the start state.
+            sleep.advance(ms(100)).await;
+            assert!(padder.poll_padding(&mut cx).is_pending());
+
+            // Events on other hops are ignored.
+            padder.note_event(2.into(), PaddingEvent::NonPaddingSent);
+            sleep.advance(ms(100)).await;
+            assert!(padder.poll_padding(&mut cx).is_pending());
+
+            padder.note_event(hop, PaddingEvent::NonPaddingSent);
+            assert!(padder.poll_padding(&mut cx).is_pending());
+            for _ in 0..2 {
+                sleep.advance(ms(11)).await;
+                assert_eq!(padder.poll_padding(&mut cx), Poll::Ready(hop));
+                assert!(padder.poll_padding(&mut cx).is_pending());
+                padder.note_event(hop, PaddingEvent::PaddingSent);
+            }
+
+            // The histogram is empty, so the machine has ended.
+            sleep.advance(ms(100)).await;
+            assert!(padder.poll_padding(&mut cx).is_pending());
+            padder.note_event(hop, PaddingEvent::NonPaddingSent);
+            sleep.advance(ms(100)).await;
+            assert!(padder.poll_padding(&mut cx).is_pending());
+        });
+    }
+
+    #[test]
+    fn length_and_cancel() {
+        futures::executor::block_on(async {
+            let sleep = MockSleepProvider::default();
+            let mut cx = Context::from_waker(noop_waker_ref());
+            let hop: HopNum = 0.into();
+
+            // Pad every 5ms, at most 3 times; cancel on non-padding traffic.
+            let machine = PaddingMachine::new(vec![MachineState::new()
+                .with_histogram(Histogram::new(vec![ms(5), ms(6)], vec![1], 0).unwrap())
+                .with_length(3)
+                .on(MachineEvent::PaddingSent, Transition::Goto(0))
+                .on(MachineEvent::NonPaddingReceived, Transition::Cancel)
+                .on(MachineEvent::PaddingReceived, Transition::Goto(0))])
+            .unwrap();
+
+            let mut padder = MachinePadder::new(sleep.clone());
+            padder.add_machine(hop, machine);
+
+            sleep.advance(ms(6)).await;
+            assert_eq!(padder.poll_padding(&mut cx), Poll::Ready(hop));
+            padder.note_event(hop, PaddingEvent::PaddingSent);
+
+            // Cancelling means we don't pad, until something reschedules us.
+            padder.note_event(hop, PaddingEvent::NonPaddingReceived);
+            sleep.advance(ms(6)).await;
+            assert!(padder.poll_padding(&mut cx).is_pending());
+            padder.note_event(hop, PaddingEvent::PaddingReceived);
+
+            for _ in 0..2 {
+                sleep.advance(ms(6)).await;
+                assert_eq!(padder.poll_padding(&mut cx), Poll::Ready(hop));
+                padder.note_event(hop, PaddingEvent::PaddingSent);
+            }
+
+            // We have reached our length limit.
+            sleep.advance(ms(6)).await;
+            assert!(padder.poll_padding(&mut cx).is_pending());
+        });
+    }
+}
@@ -0,0 +1,624 @@
+//! Counting the traffic on a circuit, for each hop and for each stream.
+//!
+//! The reactor updates a set of [`TrafficCounters`] for each hop and each open
+//! stream as it sends and receives cells. Applications can take a cheap
+//! [`CircTrafficSnapshot`] of them at any time, via
+//! [`ClientCirc::traffic_stats`](super::ClientCirc::traffic_stats), without
+//! waiting for the reactor.
+//!
+//! A stream's counters can also add to a [`TrafficTotals`], which keeps
+//! running totals for many streams, on many circuits, and can report their
+//! final values to a [`StreamCloseHook`] when the stream closes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use tor_cell::relaycell::StreamId;
+
+use super::UniqId;
+use crate::crypto::cell::HopNum;
+
+/// Counts of the traffic in one direction, on a hop or a stream.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct TrafficCounts {
+    /// The number of relay cells.
+    ///
+    /// For a stream, this is the number of DATA messages.
+    cells: u64,
+    /// The number of bytes of stream data, in DATA messages.
+    data_bytes: u64,
+}
+
+impl TrafficCounts {
+    /// Return the number of relay cells.
+    ///
+    /// For a stream, this is the number of DATA messages.
+    /// (Several small messages may share a cell.)
+    pub fn cells(&self) -> u64 {
+        self.cells
+    }
+
+    /// Return the number of bytes of stream data, in DATA messages.
+    ///
+    /// This doesn't include the overhead of relay cell headers or padding.
+    pub fn data_bytes(&self) -> u64 {
+        self.data_bytes
+    }
+}
+
+/// A snapshot of the traffic sent and received on a hop or a stream.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct TrafficStats {
+    /// The traffic that we sent.
+    sent: TrafficCounts,
+    /// The traffic that we received.
+    received: TrafficCounts,
+}
+
+impl TrafficStats {
+    /// Return the counts of the traffic that we sent.
+    pub fn sent(&self) -> TrafficCounts {
+        self.sent
+    }
+
+    /// Return the counts of the traffic that we received.
+    pub fn received(&self) -> TrafficCounts {
+        self.received
+    }
+}
+
+/// A snapshot of the traffic on a circuit, for each hop and for each open stream.
+///
+/// The counters for different hops and streams are read one after another,
+/// so they may not all reflect exactly the same moment.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CircTrafficSnapshot {
+    /// The traffic for each hop, in order.
+    hops: Vec<TrafficStats>,
+    /// The traffic for each open stream.
+    streams: Vec<(HopNum, StreamId, TrafficStats)>,
+}
+
+impl CircTrafficSnapshot {
+    /// Return the traffic for each hop of the circuit, in order.
+    pub fn hops(&self) -> &[TrafficStats] {
+        &self.hops
+    }
+
+    /// Return the traffic for `hop`, if it exists.
+    pub fn hop(&self, hop: HopNum) -> Option<&TrafficStats> {
+        self.hops.get(usize::from(hop))
+    }
+
+    /// Return the traffic for each open stream, along with the hop that the
+    /// stream is attached to.
+    ///
+    /// Streams are listed in no particular order.
+    pub fn streams(&self) -> impl Iterator<Item = (HopNum, StreamId, &TrafficStats)> + '_ {
+        self.streams
+            .iter()
+            .map(|(hop, stream_id, stats)| (*hop, *stream_id, stats))
+    }
+
+    /// Return the traffic for the open stream `stream_id` on `hop`, if there is one.
+    pub fn stream(&self, hop: HopNum, stream_id: StreamId) -> Option<&TrafficStats> {
+        self.streams
+            .iter()
+            .find(|(h, s, _)| *h == hop && *s == stream_id)
+            .map(|(_, _, stats)| stats)
+    }
+}
+
+/// A snapshot of the traffic on a single stream.
+///
+/// Returned by [`DataStream::stats`](crate::stream::DataStream::stats).
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct StreamStats {
+    /// The circuit that the stream is on.
+    circ_id: UniqId,
+    /// The traffic on the stream.
+    traffic: TrafficStats,
+}
+
+impl StreamStats {
+    /// Return the unique identifier of the circuit that the stream is on.
+    pub fn circ_id(&self) -> UniqId {
+        self.circ_id
+    }
+
+    /// Return the traffic that we have sent and received on the stream.
+    pub fn traffic(&self) -> TrafficStats {
+        self.traffic
+    }
+}
+
+/// A handle for watching the traffic on a stream, without holding the stream.
+///
+/// Returned by [`DataStream::stats_handle`](crate::stream::DataStream::stats_handle).
+/// This lets a background task notice when a stream goes idle, or goes away.
+#[derive(Clone, Debug)]
+pub struct StreamStatsHandle {
+    /// The circuit that the stream is on.
+    circ_id: UniqId,
+    /// The stream's counters, owned by the stream and the reactor.
+    counters: Weak<TrafficCounters>,
+}
+
+impl StreamStatsHandle {
+    /// Return a snapshot of the traffic on the stream, or `None` if the
+    /// stream has been dropped, and the reactor has finished with it.
+    pub fn stats(&self) -> Option<StreamStats> {
+        self.counters
+            .upgrade()
+            .map(|counters| counters.stream_stats(self.circ_id))
+    }
+}
+
+/// A snapshot of a [`TrafficTotals`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AggregateTrafficStats {
+    /// The traffic on every stream that has counted towards the totals.
+    traffic: TrafficStats,
+    /// The circuits with open streams that count towards the totals.
+    circuits: Vec<UniqId>,
+}
+
+impl AggregateTrafficStats {
+    /// Return the sum of the traffic on every stream that has counted
+    /// towards the totals, including streams that have since closed.
+    pub fn traffic(&self) -> TrafficStats {
+        self.traffic
+    }
+
+    /// Return the unique identifiers of the circuits that carry at least one
+    /// open stream that counts towards the totals.
+    ///
+    /// Circuits are listed in no particular order.
+    pub fn circuits(&self) -> &[UniqId] {
+        &self.circuits
+    }
+}
+
+/// Running totals of the traffic on a set of streams.
+///
+/// To make a stream count towards a `TrafficTotals`, pass it to
+/// [`StreamParameters::count_traffic_in`](crate::stream::StreamParameters::count_traffic_in)
+/// when opening the stream.
+#[derive(Debug, Default)]
+pub struct TrafficTotals {
+    /// The sum of the traffic on every stream that counts towards these totals.
+    counters: TrafficCounters,
+    /// The number of open streams on each circuit that count towards these
+    /// totals.
+    open_streams: Mutex<HashMap<UniqId, usize>>,
+}
+
+impl TrafficTotals {
+    /// Return a new, empty, `TrafficTotals`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a snapshot of these totals.
+    pub fn snapshot(&self) -> AggregateTrafficStats {
+        let circuits = self
+            .open_streams
+            .lock()
+            .expect("poisoned lock")
+            .keys()
+            .copied()
+            .collect();
+        AggregateTrafficStats {
+            traffic: self.counters.snapshot(),
+            circuits,
+        }
+    }
+}
+
+/// A function to call with the final traffic on a stream, once it has closed.
+///
+/// To give a stream a `StreamCloseHook`, pass it to
+/// [`StreamParameters::on_close`](crate::stream::StreamParameters::on_close)
+/// when opening the stream.
+///
+/// The stream counts as closed once it has been dropped, and the circuit
+/// reactor has finished with it.  The hook is called from whichever of those
+/// happens last, possibly on the reactor's task: it should return quickly,
+/// and must not block.
+#[derive(Clone)]
+pub struct StreamCloseHook(Arc<dyn Fn(StreamStats) + Send + Sync>);
+
+impl StreamCloseHook {
+    /// Return a new `StreamCloseHook` that calls `f`.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(StreamStats) + Send + Sync + 'static,
+    {
+        StreamCloseHook(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for StreamCloseHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamCloseHook").finish_non_exhaustive()
+    }
+}
+
+/// A registration of an open stream with a [`TrafficTotals`].
+///
+/// When this is dropped, the stream no longer counts as open.
+#[derive(Debug)]
+struct TotalsEntry {
+    /// The totals that the stream counts towards.
+    totals: Arc<TrafficTotals>,
+    /// The circuit that the stream is on.
+    circ_id: UniqId,
+}
+
+impl TotalsEntry {
+    /// Note that a stream on `circ_id` is now open, and counts towards `totals`.
+    fn new(totals: Arc<TrafficTotals>, circ_id: UniqId) -> Self {
+        *totals
+            .open_streams
+            .lock()
+            .expect("poisoned lock")
+            .entry(circ_id)
+            .or_default() += 1;
+        TotalsEntry { totals, circ_id }
+    }
+}
+
+impl Drop for TotalsEntry {
+    fn drop(&mut self) {
+        let mut open = self.totals.open_streams.lock().expect("poisoned lock");
+        if let Some(n) = open.get_mut(&self.circ_id) {
+            *n -= 1;
+            if *n == 0 {
+                open.remove(&self.circ_id);
+            }
+        }
+    }
+}
+
+/// Live counters of the traffic in one direction.
+#[derive(Debug, Default)]
+struct DirectionCounters {
+    /// See [`TrafficCounts::cells`].
+    cells: AtomicU64,
+    /// See [`TrafficCounts::data_bytes`].
+    data_bytes: AtomicU64,
+}
+
+impl DirectionCounters {
+    /// Return a snapshot of these counters.
+    fn snapshot(&self) -> TrafficCounts {
+        TrafficCounts {
+            cells: self.cells.load(Ordering::Relaxed),
+            data_bytes: self.data_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Live traffic counters for a hop or a stream.
+///
+/// These are updated by the reactor, and read by [`CircTraffic::snapshot`].
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounters {
+    /// Counters for the traffic that we sent.
+    sent: DirectionCounters,
+    /// Counters for the traffic that we received.
+    received: DirectionCounters,
+    /// The totals that this stream's traffic also counts towards, if any.
+    totals: Option<TotalsEntry>,
+    /// The hook to call when this stream closes, and the circuit that the
+    /// stream is on, if any.
+    on_close: Option<(StreamCloseHook, UniqId)>,
+    /// True if the cells that this stream sends are not reported to the
+    /// circuit padder.
+    hidden_from_padding: bool,
+}
+
+impl TrafficCounters {
+    /// Return new counters for a stream on `circ_id`, whose traffic also
+    /// counts towards `totals`, if provided.
+    ///
+    /// If `on_close` is provided, it is called with the stream's final
+    /// traffic when these counters are dropped.
+    pub(crate) fn for_stream(
+        totals: Option<Arc<TrafficTotals>>,
+        on_close: Option<StreamCloseHook>,
+        circ_id: UniqId,
+    ) -> Self {
+        TrafficCounters {
+            sent: DirectionCounters::default(),
+            received: DirectionCounters::default(),
+            totals: totals.map(|totals| TotalsEntry::new(totals, circ_id)),
+            on_close: on_close.map(|hook| (hook, circ_id)),
+            hidden_from_padding: false,
+        }
+    }
+
+    /// Return these counters, configured so that the cells that the stream
+    /// sends are reported to the circuit padder unless `hidden` is true.
+    pub(crate) fn hide_from_padding(mut self, hidden: bool) -> Self {
+        self.hidden_from_padding = hidden;
+        self
+    }
+
+    /// Return true if the cells that this stream sends must not be reported
+    /// to the circuit padder.
+    pub(crate) fn hidden_from_padding(&self) -> bool {
+        self.hidden_from_padding
+    }
+
+    /// Note that we sent a cell (or, for a stream, a DATA message).
+    pub(crate) fn note_cell_sent(&self) {
+        self.sent.cells.fetch_add(1, Ordering::Relaxed);
+        if let Some(entry) = &self.totals {
+            entry.totals.counters.note_cell_sent();
+        }
+    }
+
+    /// Note that we sent `len` bytes of stream data.
+    pub(crate) fn note_data_sent(&self, len: usize) {
+        self.sent
+            .data_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(entry) = &self.totals {
+            entry.totals.counters.note_data_sent(len);
+        }
+    }
+
+    /// Note that we received a cell (or, for a stream, a DATA message).
+    pub(crate) fn note_cell_received(&self) {
+        self.received.cells.fetch_add(1, Ordering::Relaxed);
+        if let Some(entry) = &self.totals {
+            entry.totals.counters.note_cell_received();
+        }
+    }
+
+    /// Note that we received `len` bytes of stream data.
+    pub(crate) fn note_data_received(&self, len: usize) {
+        self.received
+            .data_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(entry) = &self.totals {
+            entry.totals.counters.note_data_received(len);
+        }
+    }
+
+    /// Return a snapshot of these counters, as the traffic on a stream on
+    /// `circ_id`.
+    pub(crate) fn stream_stats(&self, circ_id: UniqId) -> StreamStats {
+        StreamStats {
+            circ_id,
+            traffic: self.snapshot(),
+        }
+    }
+
+    /// Return a handle for watching these counters, as the traffic on a
+    /// stream on `circ_id`.
+    pub(crate) fn stream_stats_handle(self: &Arc<Self>, circ_id: UniqId) -> StreamStatsHandle {
+        StreamStatsHandle {
+            circ_id,
+            counters: Arc::downgrade(self),
+        }
+    }
+
+    /// Return a snapshot of these counters.
+    fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            sent: self.sent.snapshot(),
+            received: self.received.snapshot(),
+        }
+    }
+}
+
+impl Drop for TrafficCounters {
+    fn drop(&mut self) {
+        if let Some((hook, circ_id)) = self.on_close.take() {
+            (hook.0)(self.stream_stats(circ_id));
+        }
+    }
+}
+
+/// The traffic counters for every hop and open stream of a circuit.
+#[derive(Debug, Default)]
+pub(crate) struct CircTraffic {
+    /// The counters for each hop, in order.
+    hops: Vec<Arc<TrafficCounters>>,
+    /// The counters for each stream.
+    ///
+    /// The reactor and the stream itself own the counters for each open
+    /// stream; once both are done with the stream, they are dropped, and we
+    /// forget about them.
+    streams: HashMap<(HopNum, StreamId), Weak<TrafficCounters>>,
+}
+
+impl CircTraffic {
+    /// Return the counters for a newly added hop.
+    pub(crate) fn add_hop(&mut self) -> Arc<TrafficCounters> {
+        let counters = Arc::new(TrafficCounters::default());
+        self.hops.push(Arc::clone(&counters));
+        counters
+    }
+
+    /// Record the counters for the stream `stream_id` on `hop`.
+    pub(crate) fn add_stream(
+        &mut self,
+        hop: HopNum,
+        stream_id: StreamId,
+        counters: &Arc<TrafficCounters>,
+    ) {
+        // Forget about any streams that have been closed,
+        // so that this map doesn't keep growing.
+        self.streams.retain(|_, c| c.strong_count() > 0);
+        self.streams
+            .insert((hop, stream_id), Arc::downgrade(counters));
+    }
+
+    /// Return a snapshot of the traffic counters.
+    pub(crate) fn snapshot(&self) -> CircTrafficSnapshot {
+        let hops = self.hops.iter().map(|c| c.snapshot()).collect();
+        let streams = self
+            .streams
+            .iter()
+            .filter_map(|((hop, stream_id), c)| Some((*hop, *stream_id, c.upgrade()?.snapshot())))
+            .collect();
+        CircTrafficSnapshot { hops, streams }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn snapshot() {
+        let mut traffic = CircTraffic::default();
+        let hop0 = traffic.add_hop();
+        let hop1 = traffic.add_hop();
+        let hopnum1 = HopNum::from(1);
+        let sid = StreamId::new(7).unwrap();
+        let stream = Arc::new(TrafficCounters::default());
+        traffic.add_stream(hopnum1, sid, &stream);
+
+        hop0.note_cell_sent();
+        hop1.note_cell_sent();
+        hop1.note_data_sent(100);
+        hop1.note_cell_received();
+        hop1.note_data_received(400);
+        stream.note_cell_sent();
+        stream.note_data_sent(100);
+
+        let snap = traffic.snapshot();
+        assert_eq!(snap.hops().len(), 2);
+        assert_eq!(snap.hop(0.into()).unwrap().sent().cells(), 1);
+        assert_eq!(snap.hop(0.into()).unwrap().sent().data_bytes(), 0);
+        let h1 = snap.hop(hopnum1).unwrap();
+        assert_eq!(h1.sent().data_bytes(), 100);
+        assert_eq!(h1.received().cells(), 1);
+        assert_eq!(h1.received().data_bytes(), 400);
+        assert!(snap.hop(2.into()).is_none());
+
+        let s = snap.stream(hopnum1, sid).unwrap();
+        assert_eq!(s.sent().cells(), 1);
+        assert_eq!(s.sent().data_bytes(), 100);
+        assert_eq!(s.received(), TrafficCounts::default());
+        assert_eq!(snap.streams().count(), 1);
+
+        // Once the stream is closed, it disappears from the snapshot.
+        drop(stream);
+        let snap = traffic.snapshot();
+        assert!(snap.stream(hopnum1, sid).is_none());
+        assert_eq!(snap.streams().count(), 0);
+    }
+
+    #[test]
+    fn totals() {
+        let totals = Arc::new(TrafficTotals::new());
+        let circ_a = UniqId::new(1, 1);
+        let circ_b = UniqId::new(1, 2);
+        let s1 = TrafficCounters::for_stream(Some(Arc::clone(&totals)), None, circ_a);
+        let s2 = TrafficCounters::for_stream(Some(Arc::clone(&totals)), None, circ_a);
+        let s3 = TrafficCounters::for_stream(Some(Arc::clone(&totals)), None, circ_b);
+        let uncounted = TrafficCounters::for_stream(None, None, circ_b);
+
+        s1.note_cell_sent();
+        s1.note_data_sent(10);
+        s2.note_cell_received();
+        s2.note_data_received(20);
+        s3.note_cell_received();
+        s3.note_data_received(30);
+        uncounted.note_cell_sent();
+        uncounted.note_data_sent(1000);
+
+        let s1_stats = s1.stream_stats(circ_a);
+        assert_eq!(s1_stats.circ_id(), circ_a);
+        assert_eq!(s1_stats.traffic().sent().data_bytes(), 10);
+        assert_eq!(s1_stats.traffic().received(), TrafficCounts::default());
+
+        let snap = totals.snapshot();
+        assert_eq!(snap.traffic().sent().cells(), 1);
+        assert_eq!(snap.traffic().sent().data_bytes(), 10);
+        assert_eq!(snap.traffic().received().cells(), 2);
+        assert_eq!(snap.traffic().received().data_bytes(), 50);
+        let mut circuits = snap.circuits().to_vec();
+        circuits.sort_by_key(|id| id.to_string());
+        assert_eq!(circuits, vec![circ_a, circ_b]);
+
+        // Closed streams still count, but their circuits are forgotten once
+        // they have no more open streams.
+        drop(s1);
+        drop(s3);
+        let snap = totals.snapshot();
+        assert_eq!(snap.traffic().received().data_bytes(), 50);
+        assert_eq!(snap.circuits(), &[circ_a]);
+        drop(s2);
+        assert!(totals.snapshot().circuits().is_empty());
+    }
+
+    #[test]
+    fn stats_handle() {
+        let circ_id = UniqId::new(1, 1);
+        let stream = Arc::new(TrafficCounters::for_stream(None, None, circ_id));
+        let handle = stream.stream_stats_handle(circ_id);
+        assert_eq!(handle.stats().unwrap().traffic(), TrafficStats::default());
+
+        stream.note_cell_sent();
+        stream.note_data_sent(10);
+        let stats = handle.stats().unwrap();
+        assert_eq!(stats.circ_id(), circ_id);
+        assert_eq!(stats.traffic().sent().data_bytes(), 10);
+
+        // Once the stream is closed, there is nothing left to watch.
+        drop(stream);
+        assert!(handle.stats().is_none());
+    }
+
+    #[test]
+    fn close_hook() {
+        let circ_id = UniqId::new(1, 1);
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let hook = {
+            let closed = Arc::clone(&closed);
+            StreamCloseHook::new(move |stats| closed.lock().unwrap().push(stats))
+        };
+        let stream = Arc::new(TrafficCounters::for_stream(None, Some(hook), circ_id));
+        let reactor_side = Arc::clone(&stream);
+        stream.note_cell_sent();
+        stream.note_data_sent(10);
+        reactor_side.note_cell_received();
+        reactor_side.note_data_received(20);
+
+        // The hook is only called once both owners are done with the stream.
+        drop(stream);
+        assert!(closed.lock().unwrap().is_empty());
+        drop(reactor_side);
+        let closed = closed.lock().unwrap();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].circ_id(), circ_id);
+        assert_eq!(closed[0].traffic().sent().data_bytes(), 10);
+        assert_eq!(closed[0].traffic().received().data_bytes(), 20);
+    }
+}
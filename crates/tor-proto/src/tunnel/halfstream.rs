@@ -5,9 +5,13 @@
 
 use crate::congestion::sendme::{cmd_counts_towards_windows, StreamRecvWindow};
 use crate::stream::{AnyCmdChecker, StreamSendFlowControl, StreamStatus};
+use crate::tunnel::circuit::StreamMpscSender;
 use crate::{Error, Result};
+use tor_async_utils::SinkTrySend as _;
 use tor_cell::relaycell::{RelayCmd, UnparsedRelayMsg};
 
+use std::pin::Pin;
+
 /// Type to track state of half-closed streams.
 ///
 /// A half-closed stream is one where we've sent an END cell, but where
@@ -26,6 +30,13 @@ pub(super) struct HalfStream {
     recvw: StreamRecvWindow,
     /// Object to tell us which cells to accept on this stream.
     cmd_checker: AnyCmdChecker,
+    /// A sink for delivering the messages that we accept on this stream
+    /// to the stream's reader, if it is still reading.
+    ///
+    /// This lets a stream be half-closed: after we send an END, the reader
+    /// still gets any data that the other side sent before it saw our END,
+    /// followed by the other side's END.
+    sink: Option<StreamMpscSender<UnparsedRelayMsg>>,
 }
 
 impl HalfStream {
@@ -34,11 +45,13 @@ impl HalfStream {
         send_flow_control: StreamSendFlowControl,
         recvw: StreamRecvWindow,
         cmd_checker: AnyCmdChecker,
+        sink: Option<StreamMpscSender<UnparsedRelayMsg>>,
     ) -> Self {
         HalfStream {
             send_flow_control,
             recvw,
             cmd_checker,
+            sink,
         }
     }
 
@@ -49,15 +62,34 @@ impl HalfStream {
     /// END cells to this method.
     /// no ends here.
     pub(super) fn handle_msg(&mut self, msg: UnparsedRelayMsg) -> Result<StreamStatus> {
-        use tor_cell::relaycell::msg::Sendme;
+        use tor_cell::relaycell::msg::{Sendme, Xoff, Xon};
         use StreamStatus::*;
-        if msg.cmd() == RelayCmd::SENDME {
-            // We handle SENDME separately, and don't give it to the checker.
-            let _ = msg
-                .decode::<Sendme>()
-                .map_err(|e| Error::from_bytes_err(e, "SENDME on half-closed stream"))?;
-            self.send_flow_control.put_for_incoming_sendme()?;
-            return Ok(Open);
+        // We handle flow control messages separately, and don't give them to the checker.
+        match msg.cmd() {
+            RelayCmd::SENDME => {
+                let _ = msg
+                    .decode::<Sendme>()
+                    .map_err(|e| Error::from_bytes_err(e, "SENDME on half-closed stream"))?;
+                self.send_flow_control.put_for_incoming_sendme()?;
+                return Ok(Open);
+            }
+            RelayCmd::XOFF => {
+                let xoff = msg
+                    .decode::<Xoff>()
+                    .map_err(|e| Error::from_bytes_err(e, "XOFF on half-closed stream"))?
+                    .into_msg();
+                self.send_flow_control.handle_incoming_xoff(xoff)?;
+                return Ok(Open);
+            }
+            RelayCmd::XON => {
+                let xon = msg
+                    .decode::<Xon>()
+                    .map_err(|e| Error::from_bytes_err(e, "XON on half-closed stream"))?
+                    .into_msg();
+                self.send_flow_control.handle_incoming_xon(xon)?;
+                return Ok(Open);
+            }
+            _ => {}
         }
 
         if cmd_counts_towards_windows(msg.cmd()) {
@@ -65,7 +97,17 @@ impl HalfStream {
         }
 
         let status = self.cmd_checker.check_msg(&msg)?;
-        self.cmd_checker.consume_checked_msg(msg)?;
+        match &mut self.sink {
+            Some(sink) => {
+                // The reader will decode the message itself.
+                if Pin::new(sink).try_send(msg).is_err() {
+                    // The reader has gone away (or isn't keeping up, which it
+                    // can't expect us to fix now that we've sent an END).
+                    self.sink = None;
+                }
+            }
+            None => self.cmd_checker.consume_checked_msg(msg)?,
+        }
         Ok(status)
     }
 }
@@ -86,10 +128,12 @@ mod test {
     #![allow(clippy::needless_pass_by_value)]
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
+    use crate::tunnel::circuit::test::fake_mpsc;
     use crate::{
         congestion::sendme::{StreamRecvWindow, StreamSendWindow},
         stream::DataCmdChecker,
     };
+    use futures::{FutureExt as _, StreamExt as _};
     use rand::{CryptoRng, Rng};
     use tor_basic_utils::test_rng::testing_rng;
     use tor_cell::relaycell::{
@@ -122,6 +166,7 @@ mod test {
             StreamSendFlowControl::new_window_based(sendw),
             StreamRecvWindow::new(20),
             DataCmdChecker::new_any(),
+            None,
         );
 
         // one sendme is fine
@@ -145,6 +190,7 @@ mod test {
             StreamSendFlowControl::new_window_based(StreamSendWindow::new(20)),
             StreamRecvWindow::new(20),
             DataCmdChecker::new_any(),
+            None,
         )
     }
 
@@ -176,6 +222,58 @@ mod test {
         );
     }
 
+    /// Return a `DataCmdChecker` that has already seen a CONNECTED message.
+    fn connected_checker<R: Rng + CryptoRng>(rng: &mut R) -> AnyCmdChecker {
+        let mut cmd_checker = DataCmdChecker::new_any();
+        cmd_checker
+            .check_msg(&to_unparsed(rng, msg::Connected::new_empty().into()))
+            .unwrap();
+        cmd_checker
+    }
+
+    #[test]
+    fn halfstream_forwards_to_reader() {
+        let mut rng = testing_rng();
+        let (sink, mut reader) = fake_mpsc(8);
+        let mut hs = HalfStream::new(
+            StreamSendFlowControl::new_window_based(StreamSendWindow::new(20)),
+            StreamRecvWindow::new(20),
+            connected_checker(&mut rng),
+            Some(sink),
+        );
+
+        let m = msg::Data::new(&b"still in flight"[..]).unwrap();
+        assert_eq!(
+            hs.handle_msg(to_unparsed(&mut rng, m.into())).unwrap(),
+            StreamStatus::Open
+        );
+        let m = msg::End::new_misc();
+        assert_eq!(
+            hs.handle_msg(to_unparsed(&mut rng, m.into())).unwrap(),
+            StreamStatus::Closed
+        );
+
+        // The reader still gets the messages.
+        let got = reader.next().now_or_never().unwrap().unwrap();
+        assert_eq!(got.cmd(), RelayCmd::DATA);
+        let got = reader.next().now_or_never().unwrap().unwrap();
+        assert_eq!(got.cmd(), RelayCmd::END);
+
+        // Once the reader is gone, we still check messages, but drop them.
+        drop(reader);
+        let mut hs = HalfStream::new(
+            StreamSendFlowControl::new_window_based(StreamSendWindow::new(20)),
+            StreamRecvWindow::new(1),
+            connected_checker(&mut rng),
+            hs.sink.take(),
+        );
+        let m = msg::Data::new(&b"nobody is listening"[..]).unwrap();
+        hs.handle_msg(to_unparsed(&mut rng, m.clone().into()))
+            .unwrap();
+        assert!(hs.sink.is_none());
+        assert!(hs.handle_msg(to_unparsed(&mut rng, m.into())).is_err());
+    }
+
     #[test]
     fn halfstream_connected() {
         let mut hs = hs_new();
@@ -202,6 +300,7 @@ mod test {
             StreamSendFlowControl::new_window_based(StreamSendWindow::new(20)),
             StreamRecvWindow::new(20),
             cmd_checker,
+            None,
         );
         let e = hs
             .handle_msg(to_unparsed(&mut rng, m.into()))
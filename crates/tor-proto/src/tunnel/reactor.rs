@@ -24,10 +24,12 @@ pub(super) mod syncview;
 use crate::crypto::cell::HopNum;
 use crate::crypto::handshake::ntor_v3::NtorV3PublicKey;
 use crate::memquota::{CircuitAccount, StreamAccount};
-use crate::stream::AnyCmdChecker;
+use crate::stream::{AnyCmdChecker, StreamRecvQueue};
 #[cfg(feature = "hs-service")]
 use crate::stream::{IncomingStreamRequest, IncomingStreamRequestFilter};
 use crate::tunnel::circuit::celltypes::ClientCircChanMsg;
+#[cfg(feature = "hs-service")]
+use crate::tunnel::circuit::traffic::TrafficCounters;
 use crate::tunnel::circuit::unique_id::UniqId;
 use crate::tunnel::circuit::CircuitRxReceiver;
 use crate::tunnel::circuit::MutableState;
@@ -36,7 +38,11 @@ use crate::util::err::ReactorError;
 use crate::util::skew::ClockSkew;
 use crate::{Error, Result};
 use circuit::{Circuit, CircuitCmd};
+#[cfg(feature = "conflux")]
+use conflux::msghandler::OooRelayMsg;
 use conflux::ConfluxSet;
+#[cfg(all(test, feature = "conflux"))]
+pub(crate) use conflux::MAX_OOO_MSGS;
 use control::ControlHandler;
 use std::mem::size_of;
 use tor_cell::relaycell::msg::{AnyRelayMsg, End, Sendme};
@@ -146,6 +152,11 @@ enum RunOnceCmd {
 enum RunOnceCmdInner {
     /// Send a RELAY cell.
     Send {
+        /// The leg the cell should be sent on.
+        ///
+        /// If this is `None`, the cell is sent on the primary leg.
+        /// Multiplexed cells on a conflux tunnel are always sent on the primary leg.
+        leg: Option<LegId>,
         /// The cell to send.
         cell: SendRelayCell,
         /// A channel for sending completion notifications.
@@ -153,7 +164,7 @@ enum RunOnceCmdInner {
     },
     /// Send a given control message on this circuit, and install a control-message handler to
     /// receive responses.
-    #[cfg(feature = "send-control-msg")]
+    #[cfg(any(feature = "send-control-msg", feature = "circ-padding"))]
     SendMsgAndInstallHandler {
         /// The message to send, if any
         msg: Option<AnyRelayMsgOuter>,
@@ -168,6 +179,8 @@ enum RunOnceCmdInner {
     },
     /// Handle a SENDME message.
     HandleSendMe {
+        /// The leg the SENDME was received on.
+        leg: LegId,
         /// The hop number.
         hop: HopNum,
         /// The SENDME message to handle.
@@ -206,20 +219,35 @@ enum RunOnceCmdInner {
     },
     /// Perform a clean shutdown on this circuit.
     CleanShutdown,
+    /// Queue a multiplexed message received on a conflux leg,
+    /// and deliver the messages that are now in order.
+    #[cfg(feature = "conflux")]
+    Enqueue(OooRelayMsg),
+    /// Add the given circuits to this tunnel, and link them into a conflux set.
+    #[cfg(feature = "conflux")]
+    LinkCircuits {
+        /// The circuits to add.
+        #[educe(Debug(ignore))]
+        circuits: Vec<Circuit>,
+        /// Oneshot channel to notify once all the circuits are linked.
+        answer: ReactorResultChannel<()>,
+    },
 }
 
 impl RunOnceCmdInner {
     /// Create a [`RunOnceCmdInner`] out of a [`CircuitCmd`] and [`LegIdKey`].
     fn from_circuit_cmd(leg: LegIdKey, cmd: CircuitCmd) -> Self {
         match cmd {
-            CircuitCmd::Send(cell) => {
-                // TODO(conflux): add leg ID to Send
-                Self::Send { cell, done: None }
-            }
-            CircuitCmd::HandleSendMe { hop, sendme } => {
-                // TODO(conflux): add leg to HandleSendMe
-                Self::HandleSendMe { hop, sendme }
-            }
+            CircuitCmd::Send(cell) => Self::Send {
+                leg: Some(LegId(leg)),
+                cell,
+                done: None,
+            },
+            CircuitCmd::HandleSendMe { hop, sendme } => Self::HandleSendMe {
+                leg: LegId(leg),
+                hop,
+                sendme,
+            },
             CircuitCmd::CloseStream {
                 hop,
                 sid,
@@ -233,6 +261,8 @@ impl RunOnceCmdInner {
                 done: None,
             },
             CircuitCmd::CleanShutdown => Self::CleanShutdown,
+            #[cfg(feature = "conflux")]
+            CircuitCmd::Enqueue(msg) => Self::Enqueue(msg),
         }
     }
 }
@@ -382,10 +412,8 @@ pub struct Reactor {
     ///
     /// Contains 1 or more circuits.
     ///
-    /// Circuits may be added to this set throughout the lifetime of the reactor.
-    //
-    // TODO(conflux): add a control command for adding a circuit leg,
-    // and update these docs to explain how legs are added
+    /// Circuits may be added to this set throughout the lifetime of the reactor,
+    /// with [`CtrlMsg::LinkCircuits`].
     ///
     /// Sometimes, the reactor will remove circuits from this set,
     /// for example if the `LINKED` message takes too long to arrive,
@@ -434,12 +462,18 @@ pub(crate) struct StreamReqInfo {
     /// A channel for receiving messages from this stream.
     #[deftly(has_memory_cost(indirect_size = "0"))] // estimate
     pub(crate) receiver: StreamMpscReceiver<UnparsedRelayMsg>,
+    /// The number of bytes queued on `receiver`, shared with the reactor.
+    #[deftly(has_memory_cost(indirect_size = "0"))] // estimate
+    pub(crate) recv_queue: Arc<StreamRecvQueue>,
     /// A channel for sending messages to be sent on this stream.
     #[deftly(has_memory_cost(indirect_size = "size_of::<AnyRelayMsg>()"))] // estimate
     pub(crate) msg_tx: StreamMpscSender<AnyRelayMsg>,
     /// The memory quota account to be used for this stream
     #[deftly(has_memory_cost(indirect_size = "0"))] // estimate (it contains an Arc)
     pub(crate) memquota: StreamAccount,
+    /// The traffic counters for this stream, shared with the reactor.
+    #[deftly(has_memory_cost(indirect_size = "0"))] // estimate (it's an Arc)
+    pub(crate) traffic: Arc<TrafficCounters>,
 }
 
 /// Data required for handling an incoming stream request.
@@ -559,9 +593,6 @@ impl Reactor {
             return Ok(());
         }
 
-        // TODO(conflux): support adding and linking circuits
-        // TODO(conflux): support switching the primary leg
-
         let action = select_biased! {
             res = self.command.next() => {
                 let cmd = unwrap_or_shutdown!(self, res, "command channel drop")?;
@@ -595,7 +626,16 @@ impl Reactor {
                     .leg_mut(LegId(leg))
                     .ok_or_else(|| internal!("the circuit leg we just had disappeared?!"))?;
 
+                #[cfg(feature = "crypto-offload")]
+                let circ_cmds = circ
+                    .handle_cell_offloaded(&mut self.cell_handlers, cell)
+                    .await?;
+                #[cfg(not(feature = "crypto-offload"))]
                 let circ_cmds = circ.handle_cell(&mut self.cell_handlers, cell)?;
+                // This cell might have been the last CONFLUX_LINKED we were waiting for.
+                #[cfg(feature = "conflux")]
+                self.circuits.maybe_finish_linking();
+
                 if circ_cmds.is_empty() {
                     None
                 } else {
@@ -622,6 +662,9 @@ impl Reactor {
             self.handle_run_once_cmd(cmd).await?;
         }
 
+        // Send any small messages that we held back in order to pack them together.
+        self.circuits.flush_packed_cells().await?;
+
         Ok(())
     }
 
@@ -650,18 +693,17 @@ impl Reactor {
         cmd: RunOnceCmdInner,
     ) -> StdResult<(), ReactorError> {
         match cmd {
-            RunOnceCmdInner::Send { cell, done } => {
+            RunOnceCmdInner::Send { leg, cell, done } => {
                 // TODO: check the cc window
 
-                // TODO(conflux): let the RunOnceCmdInner specify which leg to send the cell on
-                let res = self.circuits.primary_leg_mut()?.send_relay_cell(cell).await;
+                let res = self.circuits.send_relay_cell_on_leg(cell, leg).await;
                 if let Some(done) = done {
                     // Don't care if the receiver goes away
                     let _ = done.send(res.clone());
                 }
                 res?;
             }
-            #[cfg(feature = "send-control-msg")]
+            #[cfg(any(feature = "send-control-msg", feature = "circ-padding"))]
             RunOnceCmdInner::SendMsgAndInstallHandler { msg, handler, done } => {
                 let cell: Result<Option<SendRelayCell>> =
                     self.prepare_msg_and_install_handler(msg, handler);
@@ -669,7 +711,7 @@ impl Reactor {
                 match cell {
                     Ok(Some(cell)) => {
                         // TODO(conflux): let the RunOnceCmdInner specify which leg to send the cell on
-                        let outcome = self.circuits.primary_leg_mut()?.send_relay_cell(cell).await;
+                        let outcome = self.circuits.send_relay_cell_on_leg(cell, None).await;
                         // don't care if receiver goes away.
                         let _ = done.send(outcome.clone());
                         outcome?;
@@ -685,21 +727,25 @@ impl Reactor {
                     }
                 }
             }
-            // TODO(conflux)/TODO(#1857): should this take a leg_id argument?
-            // Currently, we always begin streams on the primary leg
             RunOnceCmdInner::BeginStream { cell, hop, done } => {
                 match cell {
                     Ok((cell, stream_id)) => {
-                        // TODO(conflux): let the RunOnceCmdInner specify which leg to send the cell on
-                        // (currently it is an error to use BeginStream on a multipath tunnel)
-                        let (_id, leg) = self.circuits.single_leg_mut()?;
+                        let (leg_id, _hop_num) = self
+                            .resolve_hop_location(hop)
+                            .map_err(into_bad_api_usage!("Could not resolve {hop:?}"))?;
                         let cell_hop = cell.hop;
-                        let relay_format = leg
+                        let relay_format = self
+                            .circuits
+                            .leg_mut(leg_id)
+                            .ok_or(bad_api_usage!("No leg for id {:?}", leg_id))?
                             .hop_mut(cell_hop)
                             // TODO: Is this the right error type here? Or should there be a "HopDisappeared"?
                             .ok_or(Error::NoSuchHop)?
                             .relay_cell_format();
-                        let outcome = leg.send_relay_cell(cell).await;
+                        let outcome = self
+                            .circuits
+                            .send_relay_cell_on_leg(cell, Some(leg_id))
+                            .await;
                         // don't care if receiver goes away.
                         let _ = done.send(outcome.clone().map(|_| (stream_id, hop, relay_format)));
                         outcome?;
@@ -718,20 +764,17 @@ impl Reactor {
                 reason,
                 done,
             } => {
-                let result = (move || {
-                    // this is needed to force the closure to be FnOnce rather than FnMut :(
-                    let self_ = self;
-                    let (leg_id, hop_num) = self_
+                let result = (|| {
+                    let (leg_id, hop_num) = self
                         .resolve_hop_location(hop)
                         .map_err(into_bad_api_usage!("Could not resolve {hop:?}"))?;
-                    let leg = self_
-                        .circuits
-                        .leg_mut(leg_id)
-                        .ok_or(bad_api_usage!("No leg for id {:?}", leg_id))?;
-                    Ok::<_, Bug>((leg, hop_num))
+                    if self.circuits.leg(leg_id).is_none() {
+                        return Err(bad_api_usage!("No leg for id {:?}", leg_id));
+                    }
+                    Ok::<_, Bug>((leg_id, hop_num))
                 })();
 
-                let (leg, hop_num) = match result {
+                let (leg_id, hop_num) = match result {
                     Ok(x) => x,
                     Err(e) => {
                         if let Some(done) = done {
@@ -743,17 +786,30 @@ impl Reactor {
                     }
                 };
 
-                let res: Result<()> = leg.close_stream(hop_num, sid, behav, reason).await;
+                let leg = self
+                    .circuits
+                    .leg_mut(leg_id)
+                    .ok_or_else(|| internal!("the circuit leg we just had disappeared?!"))?;
+                let res: Result<()> = match leg.close_stream(hop_num, sid, behav, reason) {
+                    Ok(Some(cell)) => {
+                        self.circuits
+                            .send_relay_cell_on_leg(cell, Some(leg_id))
+                            .await
+                    }
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                };
 
                 if let Some(done) = done {
                     // don't care if the sender goes away
                     let _ = done.send(res);
                 }
             }
-            RunOnceCmdInner::HandleSendMe { hop, sendme } => {
-                // TODO(conflux): this should specify which leg of the circuit the SENDME
-                // came on
-                let (_id, leg) = self.circuits.single_leg_mut()?;
+            RunOnceCmdInner::HandleSendMe { leg, hop, sendme } => {
+                let leg = self
+                    .circuits
+                    .leg_mut(leg)
+                    .ok_or_else(|| internal!("the circuit leg we just had disappeared?!"))?;
                 // NOTE: it's okay to await. We are only awaiting on the congestion_signals
                 // future which *should* resolve immediately
                 let signals = leg.congestion_signals().await;
@@ -772,6 +828,18 @@ impl Reactor {
                 trace!("{}: reactor shutdown due to handled cell", self.unique_id);
                 return Err(ReactorError::Shutdown);
             }
+            #[cfg(feature = "conflux")]
+            RunOnceCmdInner::Enqueue(msg) => {
+                let cmds = self.circuits.enqueue_msg(&mut self.cell_handlers, msg)?;
+                for (leg, cmd) in cmds {
+                    let cmd = RunOnceCmdInner::from_circuit_cmd(leg, cmd);
+                    Box::pin(self.handle_single_run_once_cmd(cmd)).await?;
+                }
+            }
+            #[cfg(feature = "conflux")]
+            RunOnceCmdInner::LinkCircuits { circuits, answer } => {
+                self.circuits.add_legs(circuits, answer).await?;
+            }
         }
 
         Ok(())
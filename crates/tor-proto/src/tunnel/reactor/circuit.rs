@@ -2,10 +2,11 @@
 
 pub(super) mod create;
 pub(super) mod extender;
+mod packing;
 
 use crate::channel::{Channel, ChannelSender};
 use crate::congestion::sendme::{self, CircTag};
-use crate::congestion::{CongestionControl, CongestionSignals};
+use crate::congestion::{CongestionControl, CongestionControlStats, CongestionSignals};
 use crate::crypto::binding::CircuitBinding;
 use crate::crypto::cell::{
     HopNum, InboundClientCrypt, InboundClientLayer, OutboundClientCrypt, OutboundClientLayer,
@@ -15,12 +16,17 @@ use crate::crypto::handshake::fast::CreateFastClient;
 use crate::crypto::handshake::ntor::{NtorClient, NtorPublicKey};
 use crate::crypto::handshake::ntor_v3::{NtorV3Client, NtorV3PublicKey};
 use crate::crypto::handshake::{ClientHandshake, KeyGenerator};
+#[cfg(feature = "crypto-offload")]
+use crate::crypto::offload::{self, OffloadLane};
 use crate::memquota::{CircuitAccount, SpecificAccount as _, StreamAccount};
-use crate::stream::{AnyCmdChecker, StreamSendFlowControl, StreamStatus};
+use crate::stream::{AnyCmdChecker, StreamRecvQueue, StreamSendFlowControl, StreamStatus};
 use crate::tunnel::circuit::celltypes::{ClientCircChanMsg, CreateResponse};
 use crate::tunnel::circuit::handshake::{BoxedClientLayer, HandshakeRole};
+use crate::tunnel::circuit::padding::{CircuitPadder, NoPadding, PaddingEvent};
 use crate::tunnel::circuit::path;
+use crate::tunnel::circuit::traffic::TrafficCounters;
 use crate::tunnel::circuit::unique_id::UniqId;
+use crate::tunnel::circuit::HopCryptoInfo;
 use crate::tunnel::circuit::{
     CircParameters, CircuitRxReceiver, MutableState, StreamMpscReceiver, StreamMpscSender,
 };
@@ -39,7 +45,9 @@ use tor_cell::chancell::msg::{AnyChanMsg, HandshakeType, Relay};
 use tor_cell::chancell::{AnyChanCell, ChanCmd, CircId};
 use tor_cell::chancell::{BoxedCellBody, ChanMsg};
 use tor_cell::relaycell::extend::NtorV3Extension;
-use tor_cell::relaycell::msg::{AnyRelayMsg, End, Sendme, Truncated};
+use tor_cell::relaycell::msg::{AnyRelayMsg, End, Sendme, Truncated, Xoff, Xon};
+#[cfg(feature = "relay-fragment")]
+use tor_cell::relaycell::IncompleteRelayMsgInfo;
 use tor_cell::relaycell::{
     AnyRelayMsgOuter, RelayCellDecoder, RelayCellDecoderResult, RelayCellFormat, RelayCmd,
     StreamId, UnparsedRelayMsg,
@@ -48,6 +56,7 @@ use tor_error::{internal, Bug};
 use tor_linkspec::RelayIds;
 use tor_llcrypto::pk;
 use tor_memquota::mq_queue::{ChannelSpec as _, MpscSpec};
+use tor_rtcompat::SleepProvider as _;
 
 use futures::stream::FuturesUnordered;
 use futures::{SinkExt as _, Stream};
@@ -64,6 +73,7 @@ use std::pin::Pin;
 use std::result::Result as StdResult;
 use std::sync::{Arc, Mutex};
 use std::task::Poll;
+use std::time::{Duration, Instant};
 
 use create::{Create2Wrap, CreateFastWrap, CreateHandshakeWrap};
 use extender::HandshakeAuxDataHandler;
@@ -74,6 +84,9 @@ use {
     tor_cell::relaycell::msg::Begin,
 };
 
+#[cfg(feature = "conflux")]
+use super::conflux::msghandler::{ConfluxMsgHandler, OooRelayMsg};
+
 /// Initial value for outbound flow-control window on streams.
 pub(super) const SEND_WINDOW_INIT: u16 = 500;
 /// Initial value for inbound flow-control window on streams.
@@ -106,6 +119,10 @@ pub(super) struct CircHop {
     /// at a time, and we never clone/lock the hop's `StreamMap` outside of
     /// [`Circuit::ready_streams_iterator`].
     ///
+    /// On a conflux leg, the map of the join point is shared with
+    /// the other legs of the set. This doesn't cause any contention either,
+    /// since all the legs are driven by the same reactor.
+    ///
     // TODO: encapsulate the Vec<CircHop> into a separate CircHops structure,
     // and hide its internals from the Reactor. The CircHops implementation
     // should enforce the invariant described in the note above.
@@ -117,9 +134,39 @@ pub(super) struct CircHop {
     /// Decodes relay cells received from this hop.
     inbound: RelayCellDecoder,
     /// Format to use for relay cells.
-    //
-    // When we have packed/fragmented cells, this may be replaced by a RelayCellEncoder.
     relay_format: RelayCellFormat,
+    /// Small messages waiting to be packed into a single cell.
+    ///
+    /// Only used with `RelayCellFormat::V1`.
+    pending_cell: packing::PendingCell,
+    /// Traffic counters for this hop.
+    ///
+    /// These are shared with the circuit's [`MutableState`].
+    traffic: Arc<TrafficCounters>,
+    /// The last time we received a cell from this hop, if ever.
+    last_received: Option<Instant>,
+}
+
+/// An unencrypted relay cell body, waiting to be encrypted and sent to a hop
+/// along with the other cells in its batch.
+struct QueuedCellBody {
+    /// The body of the cell.
+    body: BoxedCellBody,
+    /// True if the cell counts towards the congestion window.
+    counts_towards_windows: bool,
+    /// The event to report to the padder once the cell is sent, if any.
+    padding_event: Option<PaddingEvent>,
+}
+
+impl From<packing::PackedCell> for QueuedCellBody {
+    fn from(cell: packing::PackedCell) -> Self {
+        QueuedCellBody {
+            body: cell.body,
+            counts_towards_windows: cell.counts_towards_windows,
+            // We never pack padding, or messages that the padder mustn't see.
+            padding_event: Some(PaddingEvent::NonPaddingSent),
+        }
+    }
 }
 
 /// A circuit "leg" from a tunnel.
@@ -145,6 +192,12 @@ pub(crate) struct Circuit {
     crypto_in: InboundClientCrypt,
     /// The cryptographic state for this circuit for outbound cells.
     crypto_out: OutboundClientCrypt,
+    /// The crypto offload worker that runs this circuit's relay crypto, if any.
+    ///
+    /// When this is set, `crypto_in` and `crypto_out` are handed to the
+    /// worker for each job, and are left empty until it returns them.
+    #[cfg(feature = "crypto-offload")]
+    offload: Option<OffloadLane>,
     /// List of hops state objects used by the reactor
     hops: Vec<CircHop>,
     /// Mutable information about this circuit, shared with
@@ -159,6 +212,13 @@ pub(crate) struct Circuit {
     /// Memory quota account
     #[allow(dead_code)] // Partly here to keep it alive as long as the circuit
     memquota: CircuitAccount,
+    /// The traffic-shaping defense for this circuit.
+    ///
+    /// This is polled for padding in `ConfluxSet::next_circ_action`.
+    pub(super) padder: Box<dyn CircuitPadder>,
+    /// The conflux state of this leg, if it is part of a conflux set.
+    #[cfg(feature = "conflux")]
+    pub(super) conflux_handler: Option<ConfluxMsgHandler>,
 }
 
 /// A command to run in response to a circuit event.
@@ -192,6 +252,21 @@ pub(super) enum CircuitCmd {
     },
     /// Perform a clean shutdown on this circuit.
     CleanShutdown,
+    /// Deliver a multiplexed message received on the conflux leg this command originates from,
+    /// once all the messages preceding it have been delivered.
+    #[cfg(feature = "conflux")]
+    Enqueue(OooRelayMsg),
+}
+
+/// The outcome of delivering a message to an open stream.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum StreamDelivery {
+    /// The message was handled, and there's nothing more to do.
+    Delivered,
+    /// The message closes the stream.
+    ClosesStream,
+    /// The stream's receive queue is too long: we should send an XOFF.
+    SendXoff,
 }
 
 impl Circuit {
@@ -216,11 +291,28 @@ impl Circuit {
             unique_id,
             channel_id,
             crypto_out,
+            #[cfg(feature = "crypto-offload")]
+            offload: offload::new_circuit_lane(),
             mutable,
             memquota,
+            padder: Box::new(NoPadding),
+            #[cfg(feature = "conflux")]
+            conflux_handler: None,
         }
     }
 
+    /// Replace the crypto offload worker for this circuit with `lane`.
+    #[cfg(all(test, feature = "crypto-offload"))]
+    pub(super) fn set_crypto_offload(&mut self, lane: Option<OffloadLane>) {
+        self.offload = lane;
+    }
+
+    /// Replace the traffic-shaping defense for this circuit with `padder`.
+    #[cfg(feature = "circ-padding")]
+    pub(super) fn set_padder(&mut self, padder: Box<dyn CircuitPadder>) {
+        self.padder = padder;
+    }
+
     /// Handle a [`CtrlMsg::AddFakeHop`](super::CtrlMsg::AddFakeHop) message.
     #[cfg(test)]
     pub(super) fn handle_add_fake_hop(
@@ -242,8 +334,12 @@ impl Circuit {
         let fwd = Box::new(DummyCrypto::new(fwd_lasthop));
         let rev = Box::new(DummyCrypto::new(rev_lasthop));
         let binding = None;
+        let crypto = HopCryptoInfo::new(
+            crate::tunnel::circuit::HopHandshake::Virtual,
+            RelayCryptLayerProtocol::Tor1(format),
+        );
         self.add_hop(
-            format,
+            crypto,
             path::HopDetail::Relay(dummy_peer_id),
             fwd,
             rev,
@@ -254,20 +350,41 @@ impl Circuit {
         let _ = done.send(Ok(()));
     }
 
-    /// Encode `msg` and encrypt it, returning the resulting cell
+    /// Encode `msg` as a single relay cell body.
+    #[cfg(not(feature = "relay-fragment"))]
+    fn encode_relay_msg(
+        relay_format: RelayCellFormat,
+        msg: AnyRelayMsgOuter,
+    ) -> Result<impl IntoIterator<Item = BoxedCellBody>> {
+        let body = msg
+            .encode(relay_format, &mut rand::rng())
+            .map_err(|e| Error::from_cell_enc(e, "relay cell body"))?;
+        Ok(std::iter::once(body))
+    }
+
+    /// Encode `msg` as one or more relay cell bodies.
+    ///
+    /// If `msg` is too long for a single cell, it is split into
+    /// `FRAGMENT` messages, which we must send consecutively.
+    #[cfg(feature = "relay-fragment")]
+    fn encode_relay_msg(
+        relay_format: RelayCellFormat,
+        msg: AnyRelayMsgOuter,
+    ) -> Result<impl IntoIterator<Item = BoxedCellBody>> {
+        msg.encode_fragmented(relay_format, &mut rand::rng())
+            .map_err(|e| Error::from_cell_enc(e, "relay cell body"))
+    }
+
+    /// Encrypt the relay cell `body`, returning the resulting cell
     /// and tag that should be expected for an authenticated SENDME sent
     /// in response to that cell.
-    fn encode_relay_cell(
+    fn encrypt_relay_cell(
         crypto_out: &mut OutboundClientCrypt,
-        relay_format: RelayCellFormat,
         hop: HopNum,
         early: bool,
-        msg: AnyRelayMsgOuter,
+        body: BoxedCellBody,
     ) -> Result<(AnyChanMsg, &[u8; SENDME_TAG_LEN])> {
-        let mut body: RelayCellBody = msg
-            .encode(relay_format, &mut rand::rng())
-            .map_err(|e| Error::from_cell_enc(e, "relay cell body"))?
-            .into();
+        let mut body: RelayCellBody = body.into();
         let cmd = if early {
             ChanCmd::RELAY_EARLY
         } else {
@@ -284,6 +401,65 @@ impl Circuit {
         Ok((msg, tag))
     }
 
+    /// Encrypt the relay cell `bodies` as a single batch, returning the
+    /// resulting cells, along with the tags that should be expected for
+    /// authenticated SENDMEs sent in response to them, in the same order.
+    fn encrypt_relay_cells(
+        crypto_out: &mut OutboundClientCrypt,
+        hop: HopNum,
+        early: bool,
+        bodies: Vec<BoxedCellBody>,
+    ) -> Result<Vec<(AnyChanMsg, [u8; SENDME_TAG_LEN])>> {
+        let mut bodies: Vec<RelayCellBody> = bodies.into_iter().map(Into::into).collect();
+        let cmd = if early {
+            ChanCmd::RELAY_EARLY
+        } else {
+            ChanCmd::RELAY
+        };
+        let tags = crypto_out.encrypt_batch(cmd, &mut bodies, hop)?;
+
+        Ok(bodies
+            .into_iter()
+            .zip(tags)
+            .map(|(body, tag)| {
+                let msg = Relay::from(BoxedCellBody::from(body));
+                let msg = if early {
+                    AnyChanMsg::RelayEarly(msg.into())
+                } else {
+                    AnyChanMsg::Relay(msg)
+                };
+                (msg, tag)
+            })
+            .collect())
+    }
+
+    /// Like [`encrypt_relay_cells`](Self::encrypt_relay_cells), but run the
+    /// encryption on our crypto offload worker, if we have one.
+    async fn encrypt_relay_cells_offloadable(
+        &mut self,
+        hop: HopNum,
+        early: bool,
+        bodies: Vec<BoxedCellBody>,
+    ) -> Result<Vec<(AnyChanMsg, [u8; SENDME_TAG_LEN])>> {
+        #[cfg(feature = "crypto-offload")]
+        if let Some(lane) = &self.offload {
+            // If the job fails, we never get our crypto state back, and the
+            // circuit has to close: that's what returning an error does.
+            let mut crypto_out =
+                std::mem::replace(&mut self.crypto_out, OutboundClientCrypt::new());
+            let (crypto_out, cells) = lane
+                .run(move || {
+                    let cells = Self::encrypt_relay_cells(&mut crypto_out, hop, early, bodies);
+                    (crypto_out, cells)
+                })
+                .await?;
+            self.crypto_out = crypto_out;
+            return cells;
+        }
+
+        Self::encrypt_relay_cells(&mut self.crypto_out, hop, early, bodies)
+    }
+
     /// Encode `msg`, encrypt it, and send it to the 'hop'th hop.
     ///
     /// If there is insufficient outgoing *circuit-level* or *stream-level*
@@ -300,7 +476,16 @@ impl Circuit {
         trace!("{}: sending relay cell: {:?}", self.unique_id, msg);
 
         let c_t_w = sendme::cmd_counts_towards_windows(msg.cmd());
+        let mut padding_event = if msg.cmd() == RelayCmd::DROP {
+            Some(PaddingEvent::PaddingSent)
+        } else {
+            Some(PaddingEvent::NonPaddingSent)
+        };
         let stream_id = msg.stream_id();
+        let data_len = match msg.msg() {
+            AnyRelayMsg::Data(data) => Some(data.as_ref().len()),
+            _ => None,
+        };
         let hop_num = Into::<usize>::into(hop);
         let circhop = &mut self.hops.get_mut(hop_num).ok_or(Error::NoSuchHop)?;
 
@@ -319,21 +504,170 @@ impl Circuit {
                     )));
                 };
                 ent.take_capacity_to_send(msg.msg())?;
+                if let Some(len) = data_len {
+                    ent.traffic.note_cell_sent();
+                    ent.traffic.note_data_sent(len);
+                }
+                if ent.traffic.hidden_from_padding() {
+                    padding_event = None;
+                }
+            }
+        }
+        if let Some(len) = data_len {
+            circhop.traffic.note_data_sent(len);
+        }
+        let relay_format = circhop.relay_format;
+
+        // A packed cell is reported to the padder as a whole, so we don't
+        // pack messages that the padder mustn't see.
+        if matches!(relay_format, RelayCellFormat::V1)
+            && !early
+            && padding_event.is_some()
+            && packing::is_packable(&msg)
+        {
+            // Hold on to this message, in case we send other small messages to
+            // this hop before the end of this reactor iteration.
+            // See `flush_packed_cells`.
+            if let Some(cell) = circhop.pending_cell.push(msg, c_t_w)? {
+                self.send_packed_cell(hop, cell).await?;
+            }
+            return Ok(());
+        }
+        let mut bodies: Vec<_> = Self::encode_relay_msg(relay_format, msg)?
+            .into_iter()
+            .map(|body| QueuedCellBody {
+                body,
+                counts_towards_windows: c_t_w,
+                padding_event,
+            })
+            .collect();
+        // This message mustn't overtake the ones we're holding.
+        if let Some(cell) = circhop.pending_cell.take() {
+            if early {
+                // The cell we're holding goes out as RELAY, not RELAY_EARLY,
+                // so it can't be encrypted in the same batch.
+                self.send_packed_cell(hop, cell).await?;
+            } else {
+                bodies.insert(0, cell.into());
             }
         }
+
+        self.send_cell_bodies(hop, early, bodies).await
+    }
+
+    /// Encrypt `body`, and send it to the 'hop'th hop.
+    async fn send_cell_body(
+        &mut self,
+        hop: HopNum,
+        early: bool,
+        body: BoxedCellBody,
+        counts_towards_windows: bool,
+        padding_event: Option<PaddingEvent>,
+    ) -> Result<()> {
+        #[cfg(feature = "crypto-offload")]
+        if self.offload.is_some() {
+            // A batch of one has the same effect, and knows how to offload.
+            let queued = QueuedCellBody {
+                body,
+                counts_towards_windows,
+                padding_event,
+            };
+            return self.send_cell_bodies(hop, early, vec![queued]).await;
+        }
+
+        let circhop = self
+            .hops
+            .get_mut(usize::from(hop))
+            .ok_or(Error::NoSuchHop)?;
         // NOTE(eta): Now that we've encrypted the cell, we *must* either send it or abort
         //            the whole circuit (e.g. by returning an error).
-        let (msg, tag) =
-            Self::encode_relay_cell(&mut self.crypto_out, circhop.relay_format, hop, early, msg)?;
+        let (msg, tag) = Self::encrypt_relay_cell(&mut self.crypto_out, hop, early, body)?;
         // The cell counted for congestion control, inform our algorithm of such and pass down the
         // tag for authenticated SENDMEs.
-        if c_t_w {
+        if counts_towards_windows {
             circhop.ccontrol.note_data_sent(tag)?;
         }
+        circhop.traffic.note_cell_sent();
 
         let cell = AnyChanCell::new(Some(self.channel_id), msg);
         Pin::new(&mut self.chan_sender).send_unbounded(cell).await?;
+        if let Some(event) = padding_event {
+            self.padder.note_event(hop, event);
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt `bodies` as a single batch, and send them, in order, to the
+    /// 'hop'th hop.
+    async fn send_cell_bodies(
+        &mut self,
+        hop: HopNum,
+        early: bool,
+        bodies: Vec<QueuedCellBody>,
+    ) -> Result<()> {
+        if usize::from(hop) >= self.hops.len() {
+            return Err(Error::NoSuchHop);
+        }
+        let (bodies, info): (Vec<_>, Vec<_>) = bodies
+            .into_iter()
+            .map(|queued| {
+                (
+                    queued.body,
+                    (queued.counts_towards_windows, queued.padding_event),
+                )
+            })
+            .unzip();
+        // NOTE(eta): Now that we've encrypted the cells, we *must* either send them or abort
+        //            the whole circuit (e.g. by returning an error).
+        let cells = self
+            .encrypt_relay_cells_offloadable(hop, early, bodies)
+            .await?;
+        let circhop = self
+            .hops
+            .get_mut(usize::from(hop))
+            .ok_or(Error::NoSuchHop)?;
+        for ((msg, tag), (counts_towards_windows, padding_event)) in cells.into_iter().zip(info) {
+            if counts_towards_windows {
+                circhop.ccontrol.note_data_sent(&tag)?;
+            }
+            circhop.traffic.note_cell_sent();
+
+            let cell = AnyChanCell::new(Some(self.channel_id), msg);
+            Pin::new(&mut self.chan_sender).send_unbounded(cell).await?;
+            if let Some(event) = padding_event {
+                self.padder.note_event(hop, event);
+            }
+        }
+
+        Ok(())
+    }
 
+    /// Encrypt and send a cell of packed messages to the 'hop'th hop.
+    async fn send_packed_cell(&mut self, hop: HopNum, cell: packing::PackedCell) -> Result<()> {
+        // We never pack padding.
+        self.send_cell_body(
+            hop,
+            false,
+            cell.body,
+            cell.counts_towards_windows,
+            Some(PaddingEvent::NonPaddingSent),
+        )
+        .await
+    }
+
+    /// Send every cell of small messages that we've been holding on to,
+    /// hoping to pack more messages into it.
+    ///
+    /// The reactor calls this at the end of each iteration, so that
+    /// no message is delayed for longer than it takes to handle one event.
+    pub(super) async fn flush_packed_cells(&mut self) -> Result<()> {
+        for i in 0..self.hops.len() {
+            let Some(cell) = self.hops[i].pending_cell.take() else {
+                continue;
+            };
+            self.send_packed_cell(HopNum::from(i as u8), cell).await?;
+        }
         Ok(())
     }
 
@@ -372,19 +706,50 @@ impl Circuit {
         }
     }
 
-    /// Decode `cell`, returning its corresponding hop number, tag,
-    /// and decoded body.
-    fn decode_relay_cell(
+    /// Like [`handle_cell`](Self::handle_cell), but decrypt relay cells on
+    /// our crypto offload worker, if we have one.
+    #[cfg(feature = "crypto-offload")]
+    pub(super) async fn handle_cell_offloaded(
         &mut self,
+        handlers: &mut CellHandlers,
+        cell: ClientCircChanMsg,
+    ) -> Result<Vec<CircuitCmd>> {
+        let cell = match cell {
+            ClientCircChanMsg::Relay(r) => r,
+            other => return self.handle_cell(handlers, other),
+        };
+        trace!("{}: handling cell: {:?}", self.unique_id, cell);
+
+        // If the job fails, we never get our crypto state back, and the
+        // circuit has to close: that's what returning an error does.
+        let mut crypto_in = std::mem::replace(&mut self.crypto_in, InboundClientCrypt::new());
+        let job = move || {
+            let decrypted = Self::decrypt_relay_cell(&mut crypto_in, cell);
+            (crypto_in, decrypted)
+        };
+        let (crypto_in, decrypted) = match &self.offload {
+            Some(lane) => lane.run(job).await?,
+            None => job(),
+        };
+        self.crypto_in = crypto_in;
+
+        let (hopnum, tag, body) = decrypted?;
+        self.handle_decrypted_relay_cell(handlers, hopnum, tag, body)
+    }
+
+    /// Decrypt `cell`, returning its corresponding hop number, tag,
+    /// and decrypted body.
+    fn decrypt_relay_cell(
+        crypto_in: &mut InboundClientCrypt,
         cell: Relay,
-    ) -> Result<(HopNum, CircTag, RelayCellDecoderResult)> {
+    ) -> Result<(HopNum, CircTag, RelayCellBody)> {
         // This is always RELAY, not RELAY_EARLY, so long as this code is client-only.
         let cmd = cell.cmd();
         let mut body = cell.into_relay_body().into();
 
         // Decrypt the cell. If it's recognized, then find the
         // corresponding hop.
-        let (hopnum, tag) = self.crypto_in.decrypt(cmd, &mut body)?;
+        let (hopnum, tag) = crypto_in.decrypt(cmd, &mut body)?;
         // Make a copy of the authentication tag. TODO: I'd rather not
         // copy it, but I don't see a way around it right now.
         let tag = {
@@ -395,9 +760,16 @@ impl Circuit {
             tag_copy
         };
 
-        // Decode the cell.
-        let decode_res = self
-            .hop_mut(hopnum)
+        Ok((hopnum, tag.into(), body))
+    }
+
+    /// Decode the decrypted `body` of a cell from `hopnum`.
+    fn decode_relay_cell(
+        &mut self,
+        hopnum: HopNum,
+        body: RelayCellBody,
+    ) -> Result<RelayCellDecoderResult> {
+        self.hop_mut(hopnum)
             .ok_or_else(|| {
                 Error::from(internal!(
                     "Trying to decode cell from nonexistent hop {:?}",
@@ -406,9 +778,7 @@ impl Circuit {
             })?
             .inbound
             .decode(body.into())
-            .map_err(|e| Error::from_bytes_err(e, "relay cell"))?;
-
-        Ok((hopnum, tag.into(), decode_res))
+            .map_err(|e| Error::from_bytes_err(e, "relay cell"))
     }
 
     /// React to a Relay or RelayEarly cell.
@@ -417,7 +787,33 @@ impl Circuit {
         handlers: &mut CellHandlers,
         cell: Relay,
     ) -> Result<Vec<CircuitCmd>> {
-        let (hopnum, tag, decode_res) = self.decode_relay_cell(cell)?;
+        let (hopnum, tag, body) = Self::decrypt_relay_cell(&mut self.crypto_in, cell)?;
+        self.handle_decrypted_relay_cell(handlers, hopnum, tag, body)
+    }
+
+    /// React to a Relay or RelayEarly cell that we have already decrypted.
+    fn handle_decrypted_relay_cell(
+        &mut self,
+        handlers: &mut CellHandlers,
+        hopnum: HopNum,
+        tag: CircTag,
+        body: RelayCellBody,
+    ) -> Result<Vec<CircuitCmd>> {
+        let decode_res = self.decode_relay_cell(hopnum, body)?;
+        let now = self.chan_sender.as_inner().time_provider().now();
+        let hop = self
+            .hop_mut(hopnum)
+            .ok_or_else(|| internal!("Decoded a cell from nonexistent hop {:?}", hopnum))?;
+        hop.last_received = Some(now);
+        let traffic = Arc::clone(&hop.traffic);
+        traffic.note_cell_received();
+
+        let padding_event = if decode_res.cmds().any(|cmd| cmd != RelayCmd::DROP) {
+            PaddingEvent::NonPaddingReceived
+        } else {
+            PaddingEvent::PaddingReceived
+        };
+        self.padder.note_event(hopnum, padding_event);
 
         let c_t_w = decode_res.cmds().any(sendme::cmd_counts_towards_windows);
 
@@ -461,6 +857,9 @@ impl Circuit {
 
         let (mut msgs, incomplete) = decode_res.into_parts();
         while let Some(msg) = msgs.next() {
+            if msg.cmd() == RelayCmd::DATA {
+                traffic.note_data_received(msg.data_len().into());
+            }
             let msg_status = self.handle_relay_msg(handlers, hopnum, c_t_w, msg)?;
 
             match msg_status {
@@ -488,9 +887,45 @@ impl Circuit {
             }
         }
 
+        #[cfg(feature = "relay-fragment")]
+        if let Some(incomplete) = incomplete {
+            self.check_incomplete_msg(hopnum, &incomplete)?;
+        }
+
         Ok(circ_cmds)
     }
 
+    /// Check that the stream of a partially received (fragmented) message exists.
+    ///
+    /// We check this on every fragment, rather than once the message is complete,
+    /// so that we don't buffer a long message for a stream that doesn't exist.
+    #[cfg(feature = "relay-fragment")]
+    fn check_incomplete_msg(&self, hopnum: HopNum, info: &IncompleteRelayMsgInfo) -> Result<()> {
+        let Some(streamid) = info.stream_id() else {
+            // Meta messages are checked once they are complete.
+            return Ok(());
+        };
+        if matches!(
+            info.cmd(),
+            RelayCmd::BEGIN | RelayCmd::BEGIN_DIR | RelayCmd::RESOLVE
+        ) {
+            // These are requests for new streams.
+            return Ok(());
+        }
+        let hop = self
+            .hop(hopnum)
+            .ok_or_else(|| Error::CircProto("Cell from nonexistent hop!".into()))?;
+        let mut hop_map = hop.map.lock().expect("lock poisoned");
+        if hop_map.get_mut(streamid).is_none() {
+            return Err(Error::CircProto(format!(
+                "Fragment of {} received on nonexistent stream {}",
+                info.cmd(),
+                sv(streamid),
+            )));
+        }
+        Ok(())
+    }
+
     /// Handle a single incoming relay message.
     fn handle_relay_msg(
         &mut self,
@@ -509,6 +944,35 @@ impl Circuit {
             return self.handle_meta_cell(handlers, hopnum, msg);
         };
 
+        // On a conflux leg, multiplexed messages must be put back in order
+        // with the ones received on the other legs before they can be delivered.
+        #[cfg(feature = "conflux")]
+        if let Some(handler) = self.conflux_handler.as_mut() {
+            if handler.is_multiplexed(hopnum, msg.cmd()) {
+                let seqno = handler.inc_last_seq_recv()?;
+                return Ok(Some(CircuitCmd::Enqueue(OooRelayMsg {
+                    seqno,
+                    hopnum,
+                    streamid,
+                    cell_counts_toward_windows,
+                    msg,
+                })));
+            }
+        }
+
+        self.handle_in_order_msg(handlers, hopnum, cell_counts_toward_windows, streamid, msg)
+    }
+
+    /// Handle a single incoming stream message,
+    /// which is known to be in order with the other messages of the tunnel.
+    pub(super) fn handle_in_order_msg(
+        &mut self,
+        handlers: &mut CellHandlers,
+        hopnum: HopNum,
+        cell_counts_toward_windows: bool,
+        streamid: StreamId,
+        msg: UnparsedRelayMsg,
+    ) -> Result<Option<CircuitCmd>> {
         let hop = self
             .hop_mut(hopnum)
             .ok_or_else(|| Error::CircProto("Cell from nonexistent hop!".into()))?;
@@ -516,11 +980,22 @@ impl Circuit {
         match hop_map.get_mut(streamid) {
             Some(StreamEntMut::Open(ent)) => {
                 // Can't have a stream level SENDME when congestion control is enabled.
-                let message_closes_stream =
+                let delivery =
                     Self::deliver_msg_to_stream(streamid, ent, cell_counts_toward_windows, msg)?;
 
-                if message_closes_stream {
-                    hop_map.ending_msg_received(streamid)?;
+                match delivery {
+                    StreamDelivery::Delivered => {}
+                    StreamDelivery::ClosesStream => {
+                        hop_map.ending_msg_received(streamid)?;
+                    }
+                    StreamDelivery::SendXoff => {
+                        let xoff = AnyRelayMsgOuter::new(Some(streamid), Xoff::new().into());
+                        return Ok(Some(CircuitCmd::Send(SendRelayCell {
+                            hop: hopnum,
+                            early: false,
+                            cell: xoff,
+                        })));
+                    }
                 }
             }
             #[cfg(feature = "hs-service")]
@@ -573,23 +1048,49 @@ impl Circuit {
         ent: &mut OpenStreamEnt,
         cell_counts_toward_windows: bool,
         msg: UnparsedRelayMsg,
-    ) -> Result<bool> {
+    ) -> Result<StreamDelivery> {
         // The stream for this message exists, and is open.
 
-        if msg.cmd() == RelayCmd::SENDME {
-            let _sendme = msg
-                .decode::<Sendme>()
-                .map_err(|e| Error::from_bytes_err(e, "Sendme message on stream"))?
-                .into_msg();
-
-            // We need to handle sendmes here, not in the stream's
-            // recv() method, or else we'd never notice them if the
-            // stream isn't reading.
-            ent.put_for_incoming_sendme()?;
-            return Ok(false);
+        // We need to handle SENDME, XON, and XOFF messages here, not in the
+        // stream's recv() method, or else we'd never notice them if the
+        // stream isn't reading.
+        match msg.cmd() {
+            RelayCmd::SENDME => {
+                let _sendme = msg
+                    .decode::<Sendme>()
+                    .map_err(|e| Error::from_bytes_err(e, "Sendme message on stream"))?
+                    .into_msg();
+
+                ent.put_for_incoming_sendme()?;
+                return Ok(StreamDelivery::Delivered);
+            }
+            RelayCmd::XOFF => {
+                let xoff = msg
+                    .decode::<Xoff>()
+                    .map_err(|e| Error::from_bytes_err(e, "Xoff message on stream"))?
+                    .into_msg();
+
+                ent.handle_incoming_xoff(xoff)?;
+                return Ok(StreamDelivery::Delivered);
+            }
+            RelayCmd::XON => {
+                let xon = msg
+                    .decode::<Xon>()
+                    .map_err(|e| Error::from_bytes_err(e, "Xon message on stream"))?
+                    .into_msg();
+
+                ent.handle_incoming_xon(xon)?;
+                return Ok(StreamDelivery::Delivered);
+            }
+            _ => {}
         }
 
         let message_closes_stream = ent.cmd_checker.check_msg(&msg)? == StreamStatus::Closed;
+        let send_xoff = ent.note_queued(&msg);
+        if msg.cmd() == RelayCmd::DATA {
+            ent.traffic.note_cell_received();
+            ent.traffic.note_data_received(msg.data_len().into());
+        }
 
         if let Err(e) = Pin::new(&mut ent.sink).try_send(msg) {
             if e.is_full() {
@@ -609,7 +1110,13 @@ impl Circuit {
             }
         }
 
-        Ok(message_closes_stream)
+        Ok(if message_closes_stream {
+            StreamDelivery::ClosesStream
+        } else if send_xoff {
+            StreamDelivery::SendXoff
+        } else {
+            StreamDelivery::Delivered
+        })
     }
 
     /// A helper for handling incoming stream requests.
@@ -720,14 +1227,24 @@ impl Circuit {
             memquota.as_raw_account(),
         )?;
 
+        let recv_queue = Arc::new(StreamRecvQueue::default());
+        let traffic = Arc::new(TrafficCounters::default());
+
         let cmd_checker = DataCmdChecker::new_connected();
         hop.map.lock().expect("lock poisoned").add_ent_with_id(
             sender,
+            Arc::clone(&recv_queue),
+            Arc::clone(&traffic),
             msg_rx,
             hop.build_send_flow_ctrl(),
             stream_id,
             cmd_checker,
         )?;
+        self.mutable
+            .lock()
+            .expect("poisoned lock")
+            .traffic
+            .add_stream(hop_num, stream_id, &traffic);
 
         let outcome = Pin::new(&mut handler.incoming_sender).try_send(StreamReqInfo {
             req,
@@ -735,8 +1252,10 @@ impl Circuit {
             hop_num,
             msg_tx,
             receiver,
+            recv_queue,
             memquota,
             relay_cell_format,
+            traffic,
         });
 
         log_ratelim!("Delivering message to incoming stream handler"; outcome);
@@ -873,7 +1392,7 @@ impl Circuit {
 
         H::handle_server_aux_data(params, &server_msg)?;
 
-        let relay_cell_format = cell_protocol.relay_cell_format();
+        let crypto = HopCryptoInfo::new(wrap.hop_handshake(), cell_protocol);
         let BoxedClientLayer { fwd, back, binding } =
             cell_protocol.construct_layers(HandshakeRole::Initiator, keygen)?;
 
@@ -882,7 +1401,7 @@ impl Circuit {
         let peer_id = self.channel.target().clone();
 
         self.add_hop(
-            relay_cell_format,
+            crypto,
             path::HopDetail::Relay(peer_id),
             fwd,
             back,
@@ -978,13 +1497,6 @@ impl Circuit {
                     // which should prevent the above `is_enabled()` from ever being true,
                     // even with the "flowctl-cc" feature enabled:
                     // https://gitlab.torproject.org/tpo/core/arti/-/merge_requests/2932#note_3191196
-                    // The panic here is so that CI tests will hopefully catch if congestion
-                    // control is unexpectedly enabled.
-                    // We should remove this panic once xon/xoff flow is supported.
-                    #[cfg(not(test))]
-                    panic!("Congestion control is enabled on this circuit, but we don't yet support congestion control");
-
-                    #[allow(unreachable_code)]
                     client_extensions.push(NtorV3Extension::RequestCongestionControl);
                 } else {
                     return Err(
@@ -1017,7 +1529,7 @@ impl Circuit {
     /// Will return an error if the circuit already has [`u8::MAX`] hops.
     pub(super) fn add_hop(
         &mut self,
-        format: RelayCellFormat,
+        crypto: HopCryptoInfo,
         peer_id: path::HopDetail,
         fwd: Box<dyn OutboundClientLayer + 'static + Send>,
         rev: Box<dyn InboundClientLayer + 'static + Send>,
@@ -1038,13 +1550,19 @@ impl Circuit {
 
         let hop_num = (hop_num as u8).into();
 
-        let hop = CircHop::new(self.unique_id, hop_num, format, params);
-        self.hops.push(hop);
-        self.crypto_in.add_layer(rev);
-        self.crypto_out.add_layer(fwd);
         let mut mutable = self.mutable.lock().expect("poisoned lock");
+        let traffic = mutable.traffic.add_hop();
         Arc::make_mut(&mut mutable.path).push_hop(peer_id);
         mutable.binding.push(binding);
+        mutable.crypto.push(crypto);
+        drop(mutable);
+
+        let format = crypto.relay_cell_format();
+
+        let hop = CircHop::new(self.unique_id, hop_num, format, params, traffic);
+        self.hops.push(hop);
+        self.crypto_in.add_layer(rev);
+        self.crypto_out.add_layer(fwd);
 
         Ok(())
     }
@@ -1076,6 +1594,14 @@ impl Circuit {
                 sendme,
             }));
         }
+        if msg.cmd() == RelayCmd::DROP && self.padder.accepts_padding_from(hopnum) {
+            trace!(
+                "{}: Received padding from hop {}",
+                self.unique_id,
+                hopnum.display()
+            );
+            return Ok(None);
+        }
         if msg.cmd() == RelayCmd::TRUNCATED {
             let truncated = msg
                 .decode::<Truncated>()
@@ -1092,6 +1618,20 @@ impl Circuit {
 
             return Ok(Some(CircuitCmd::CleanShutdown));
         }
+        #[cfg(feature = "conflux")]
+        if matches!(
+            msg.cmd(),
+            RelayCmd::CONFLUX_LINKED | RelayCmd::CONFLUX_SWITCH
+        ) {
+            let Some(handler) = self.conflux_handler.as_mut() else {
+                return Err(Error::CircProto(format!(
+                    "Unexpected {} cell on non-conflux client circuit",
+                    msg.cmd()
+                )));
+            };
+
+            return handler.handle_msg(hopnum, msg);
+        }
 
         trace!("{}: Received meta-cell {:?}", self.unique_id, msg);
 
@@ -1192,12 +1732,22 @@ impl Circuit {
     /// To avoid contention, never create more than one [`Circuit::ready_streams_iterator`]
     /// stream at a time!
     ///
+    /// The streams of `exclude_hop`, if any, are not polled.
+    ///
     /// This is cancellation-safe.
-    pub(super) fn ready_streams_iterator(&self) -> impl Stream<Item = Result<CircuitCmd>> {
+    pub(super) fn ready_streams_iterator(
+        &self,
+        exclude_hop: Option<HopNum>,
+    ) -> impl Stream<Item = Result<CircuitCmd>> {
         self.hops
             .iter()
             .enumerate()
-            .filter_map(|(i, hop)| {
+            .filter_map(move |(i, hop)| {
+                let hop_num = HopNum::from(i as u8);
+                if exclude_hop == Some(hop_num) {
+                    return None;
+                }
+
                 if !hop.ccontrol.can_send() {
                     // We can't send anything on this hop that counts towards SENDME windows.
                     //
@@ -1217,7 +1767,6 @@ impl Circuit {
                     return None;
                 }
 
-                let hop_num = HopNum::from(i as u8);
                 let hop_map = Arc::clone(&self.hops[i].map);
                 Some(futures::future::poll_fn(move |cx| {
                     // Process an outbound message from the first ready stream on
@@ -1292,6 +1841,8 @@ impl Circuit {
         hop_num: HopNum,
         message: AnyRelayMsg,
         sender: StreamMpscSender<UnparsedRelayMsg>,
+        recv_queue: Arc<StreamRecvQueue>,
+        traffic: Arc<TrafficCounters>,
         rx: StreamMpscReceiver<AnyRelayMsg>,
         cmd_checker: AnyCmdChecker,
     ) -> StdResult<Result<(SendRelayCell, StreamId)>, Bug> {
@@ -1302,24 +1853,38 @@ impl Circuit {
             ));
         };
 
-        Ok(hop.begin_stream(message, sender, rx, cmd_checker))
+        let r = hop.begin_stream(
+            message,
+            sender,
+            recv_queue,
+            Arc::clone(&traffic),
+            rx,
+            cmd_checker,
+        );
+        if let Ok((_, stream_id)) = &r {
+            self.mutable
+                .lock()
+                .expect("poisoned lock")
+                .traffic
+                .add_stream(hop_num, *stream_id, &traffic);
+        }
+        Ok(r)
     }
 
-    /// Close the specified stream
-    pub(super) async fn close_stream(
+    /// Close the specified stream.
+    ///
+    /// Returns the END cell to send, if any.
+    pub(super) fn close_stream(
         &mut self,
         hop_num: HopNum,
         sid: StreamId,
         behav: CloseStreamBehavior,
         reason: streammap::TerminateReason,
-    ) -> Result<()> {
-        if let Some(hop) = self.hop_mut(hop_num) {
-            let res = hop.close_stream(sid, behav, reason)?;
-            if let Some(cell) = res {
-                self.send_relay_cell(cell).await?;
-            }
+    ) -> Result<Option<SendRelayCell>> {
+        match self.hop_mut(hop_num) {
+            Some(hop) => hop.close_stream(sid, behav, reason),
+            None => Ok(None),
         }
-        Ok(())
     }
 
     /// The number of hops in this circuit.
@@ -1339,6 +1904,43 @@ impl Circuit {
         !self.hops.is_empty()
     }
 
+    /// Return the hop at which this circuit joins the other legs of its conflux set,
+    /// if it is part of one.
+    pub(super) fn join_point(&self) -> Option<HopNum> {
+        #[cfg(feature = "conflux")]
+        if let Some(handler) = &self.conflux_handler {
+            return Some(handler.join_point());
+        }
+
+        None
+    }
+
+    /// Return the stream map of `hop`, if there is such a hop.
+    #[cfg(feature = "conflux")]
+    pub(super) fn stream_map(&self, hop: HopNum) -> Option<Arc<Mutex<streammap::StreamMap>>> {
+        Some(Arc::clone(&self.hop(hop)?.map))
+    }
+
+    /// Replace the stream map of `hop` with `map`,
+    /// so that the streams of `map` can be used from this circuit.
+    ///
+    /// Returns an error if `hop` doesn't exist, or has open streams.
+    #[cfg(feature = "conflux")]
+    pub(super) fn set_stream_map(
+        &mut self,
+        hop: HopNum,
+        map: Arc<Mutex<streammap::StreamMap>>,
+    ) -> StdResult<(), Bug> {
+        let hop = self
+            .hop_mut(hop)
+            .ok_or_else(|| internal!("tried to set the stream map of a nonexistent hop"))?;
+        if hop.n_open_streams() > 0 {
+            return Err(internal!("tried to replace a stream map with open streams"));
+        }
+        hop.map = map;
+        Ok(())
+    }
+
     /// Get the path of the circuit.
     ///
     /// **Warning:** Do not call while already holding the [`Self::mutable`] lock.
@@ -1360,6 +1962,13 @@ impl Circuit {
         let hop = self.hop(hop)?;
         Some(hop.ccontrol.uses_stream_sendme())
     }
+
+    /// Return true if `stream_id` is an open stream on `hop`:
+    /// that is, if we have neither sent nor received an END on it.
+    pub(super) fn stream_is_open(&self, hop: HopNum, stream_id: StreamId) -> bool {
+        self.hop(hop)
+            .is_some_and(|hop| hop.map.lock().expect("lock poisoned").is_open(stream_id))
+    }
 }
 
 /// Return the stream ID of `msg`, if it has one.
@@ -1392,6 +2001,7 @@ impl CircHop {
         hop_num: HopNum,
         relay_format: RelayCellFormat,
         params: &CircParameters,
+        traffic: Arc<TrafficCounters>,
     ) -> Self {
         CircHop {
             unique_id,
@@ -1400,6 +2010,9 @@ impl CircHop {
             ccontrol: CongestionControl::new(&params.ccontrol),
             inbound: RelayCellDecoder::new(relay_format),
             relay_format,
+            pending_cell: Default::default(),
+            traffic,
+            last_received: None,
         }
     }
 
@@ -1409,15 +2022,20 @@ impl CircHop {
         &mut self,
         message: AnyRelayMsg,
         sender: StreamMpscSender<UnparsedRelayMsg>,
+        recv_queue: Arc<StreamRecvQueue>,
+        traffic: Arc<TrafficCounters>,
         rx: StreamMpscReceiver<AnyRelayMsg>,
         cmd_checker: AnyCmdChecker,
     ) -> Result<(SendRelayCell, StreamId)> {
         let flow_ctrl = self.build_send_flow_ctrl();
-        let r =
-            self.map
-                .lock()
-                .expect("lock poisoned")
-                .add_ent(sender, rx, flow_ctrl, cmd_checker)?;
+        let r = self.map.lock().expect("lock poisoned").add_ent(
+            sender,
+            recv_queue,
+            traffic,
+            rx,
+            flow_ctrl,
+            cmd_checker,
+        )?;
         let cell = AnyRelayMsgOuter::new(Some(r), message);
         Ok((
             SendRelayCell {
@@ -1483,6 +2101,18 @@ impl CircHop {
         }
     }
 
+    /// Return a snapshot of the congestion control state of this hop.
+    pub(crate) fn ccontrol_stats(&self) -> CongestionControlStats {
+        self.ccontrol.stats()
+    }
+
+    /// Return how long before `now` we last received a cell from this hop,
+    /// or `None` if we never have.
+    pub(crate) fn since_last_received(&self, now: Instant) -> Option<Duration> {
+        self.last_received
+            .map(|last| now.saturating_duration_since(last))
+    }
+
     /// Delegate to CongestionControl, for testing purposes
     #[cfg(test)]
     pub(crate) fn send_window_and_expected_tags(&self) -> (u32, Vec<CircTag>) {
@@ -1,6 +1,7 @@
 //! Helpers for handling CREATE* cells.
 
 use crate::tunnel::circuit::celltypes::CreateResponse;
+use crate::tunnel::circuit::HopHandshake;
 use crate::{Error, Result};
 use tor_cell::chancell;
 use tor_cell::chancell::msg::{AnyChanMsg, HandshakeType};
@@ -13,12 +14,18 @@ pub(super) trait CreateHandshakeWrap {
     /// Decode a ChanMsg to an appropriate handshake value, checking
     /// its type.
     fn decode_chanmsg(&self, msg: CreateResponse) -> Result<Vec<u8>>;
+    /// Return the kind of handshake that this wraps.
+    fn hop_handshake(&self) -> HopHandshake;
 }
 
 /// A CreateHandshakeWrap that generates CREATE_FAST and handles CREATED_FAST.
 pub(super) struct CreateFastWrap;
 
 impl CreateHandshakeWrap for CreateFastWrap {
+    fn hop_handshake(&self) -> HopHandshake {
+        HopHandshake::CreateFast
+    }
+
     fn to_chanmsg(&self, bytes: Vec<u8>) -> AnyChanMsg {
         chancell::msg::CreateFast::new(bytes).into()
     }
@@ -45,6 +52,10 @@ pub(super) struct Create2Wrap {
 }
 
 impl CreateHandshakeWrap for Create2Wrap {
+    fn hop_handshake(&self) -> HopHandshake {
+        HopHandshake::Create2(self.handshake_type)
+    }
+
     fn to_chanmsg(&self, bytes: Vec<u8>) -> AnyChanMsg {
         chancell::msg::Create2::new(self.handshake_type, bytes).into()
     }
@@ -6,9 +6,12 @@ use crate::crypto::cell::{
     ClientLayer, CryptInit, HopNum, InboundClientLayer, OutboundClientLayer,
 };
 use crate::crypto::handshake::fast::CreateFastClient;
+#[cfg(feature = "ntor-v3-pq")]
+use crate::crypto::handshake::ntor_v3::pq::NtorV3PqClient;
 use crate::crypto::handshake::ntor_v3::NtorV3Client;
 use crate::tunnel::circuit::unique_id::UniqId;
-use crate::tunnel::circuit::CircParameters;
+use crate::tunnel::circuit::{CircParameters, HopCryptoInfo, HopHandshake};
+use crate::tunnel::handshake::RelayCryptLayerProtocol;
 use crate::tunnel::reactor::MetaCellDisposition;
 use crate::{Error, Result};
 use oneshot_fused_workaround as oneshot;
@@ -16,7 +19,7 @@ use std::borrow::Borrow;
 use std::marker::PhantomData;
 use tor_cell::chancell::msg::HandshakeType;
 use tor_cell::relaycell::msg::{Extend2, Extended2};
-use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCellFormat, UnparsedRelayMsg};
+use tor_cell::relaycell::{AnyRelayMsgOuter, UnparsedRelayMsg};
 use tor_error::internal;
 
 use crate::crypto::handshake::ntor::NtorClient;
@@ -47,8 +50,8 @@ where
     unique_id: UniqId,
     /// The hop we're expecting the EXTENDED2 cell to come back from.
     expected_hop: HopNum,
-    /// The relay cell format we intend to use for this hop.
-    relay_cell_format: RelayCellFormat,
+    /// The cryptography we intend to use for this hop.
+    crypto: HopCryptoInfo,
     /// A oneshot channel that we should inform when we are done with this extend operation.
     operation_finished: Option<oneshot::Sender<Result<()>>>,
     /// `PhantomData` used to make the other type parameters required for a circuit extension
@@ -78,7 +81,7 @@ where
     #[allow(clippy::too_many_arguments)]
     #[allow(clippy::blocks_in_conditions)]
     pub(crate) fn begin(
-        relay_cell_format: RelayCellFormat,
+        protocol: RelayCryptLayerProtocol,
         peer_id: OwnedChanTarget,
         handshake_id: HandshakeType,
         key: &H::KeyType,
@@ -120,7 +123,7 @@ where
                 expected_hop: hop,
                 operation_finished: None,
                 phantom: Default::default(),
-                relay_cell_format,
+                crypto: HopCryptoInfo::new(HopHandshake::Create2(handshake_id), protocol),
             };
 
             Ok::<(CircuitExtender<_, _, _, _>, SendRelayCell), Error>((extender, cell))
@@ -176,7 +179,7 @@ where
         // If we get here, it succeeded.  Add a new hop to the circuit.
         let (layer_fwd, layer_back, binding) = layer.split_client_layer();
         circ.add_hop(
-            self.relay_cell_format,
+            self.crypto,
             path::HopDetail::Relay(self.peer_id.clone()),
             Box::new(layer_fwd),
             Box::new(layer_back),
@@ -294,6 +297,17 @@ impl HandshakeAuxDataHandler for NtorV3Client {
     }
 }
 
+#[cfg(feature = "ntor-v3-pq")]
+impl HandshakeAuxDataHandler for NtorV3PqClient {
+    fn handle_server_aux_data(
+        params: &mut CircParameters,
+        data: &Vec<NtorV3Extension>,
+    ) -> Result<()> {
+        // The hybrid handshake uses the same extensions as ntor v3.
+        NtorV3Client::handle_server_aux_data(params, data)
+    }
+}
+
 impl HandshakeAuxDataHandler for NtorClient {
     fn handle_server_aux_data(_params: &mut CircParameters, _data: &()) -> Result<()> {
         // This handshake doesn't have any auxiliary data; nothing to do.
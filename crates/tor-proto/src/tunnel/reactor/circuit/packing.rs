@@ -0,0 +1,163 @@
+//! Packing several small relay messages into a single cell.
+//!
+//! [`RelayCellFormat::V1`](tor_cell::relaycell::RelayCellFormat::V1) cells can
+//! hold more than one relay message. Rather than sending each small message in
+//! a cell of its own, the reactor holds on to them in a [`PendingCell`] until
+//! the end of its current iteration (or until the cell is full, or until it has
+//! to send a message that can't be packed), and sends them all in one cell.
+
+use crate::{Error, Result};
+use tor_cell::chancell::BoxedCellBody;
+use tor_cell::relaycell::msg::{AnyRelayMsg, Data};
+use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCellPacker};
+
+/// The largest DATA message that we try to pack together with others.
+///
+/// Larger DATA messages would rarely leave room for anything else.
+const MAX_PACKABLE_DATA_LEN: usize = Data::MAXLEN_V1 / 2;
+
+/// Return true if `msg` is a small message that we should try to pack into a
+/// cell with others.
+pub(super) fn is_packable(msg: &AnyRelayMsgOuter) -> bool {
+    match msg.msg() {
+        AnyRelayMsg::Sendme(_)
+        | AnyRelayMsg::Begin(_)
+        | AnyRelayMsg::BeginDir(_)
+        | AnyRelayMsg::End(_)
+        | AnyRelayMsg::Xoff(_)
+        | AnyRelayMsg::Xon(_) => true,
+        AnyRelayMsg::Data(data) => data.as_ref().len() <= MAX_PACKABLE_DATA_LEN,
+        _ => false,
+    }
+}
+
+/// A packed cell, ready to encrypt and send.
+#[derive(Debug)]
+pub(super) struct PackedCell {
+    /// The body of the cell.
+    pub(super) body: BoxedCellBody,
+    /// True if any of the messages in the cell counts towards the
+    /// congestion window.
+    pub(super) counts_towards_windows: bool,
+}
+
+/// Small relay messages waiting to be sent to a single hop in one cell.
+#[derive(Debug, Default)]
+pub(super) struct PendingCell {
+    /// The messages that we've packed so far.
+    packer: RelayCellPacker,
+    /// True if any of the messages in `packer` counts towards the
+    /// congestion window.
+    counts_towards_windows: bool,
+}
+
+impl PendingCell {
+    /// Add `msg` to the cell.
+    ///
+    /// If there's no room for `msg`, return the full cell, which must be
+    /// sent before any later cell.
+    pub(super) fn push(
+        &mut self,
+        msg: AnyRelayMsgOuter,
+        counts_towards_windows: bool,
+    ) -> Result<Option<PackedCell>> {
+        let full = self
+            .packer
+            .push(msg, &mut rand::rng())
+            .map_err(|e| Error::from_cell_enc(e, "packed relay cell"))?
+            .map(|body| PackedCell {
+                body,
+                counts_towards_windows: self.counts_towards_windows,
+            });
+        if full.is_some() {
+            self.counts_towards_windows = false;
+        }
+        self.counts_towards_windows |= counts_towards_windows;
+        Ok(full)
+    }
+
+    /// Take the cell that we've packed so far, if it holds any messages.
+    pub(super) fn take(&mut self) -> Option<PackedCell> {
+        let body = self.packer.finish(&mut rand::rng())?;
+        let counts_towards_windows = std::mem::take(&mut self.counts_towards_windows);
+        Some(PackedCell {
+            body,
+            counts_towards_windows,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_cell::relaycell::msg::{End, Sendme};
+    use tor_cell::relaycell::{RelayCellDecoder, RelayCellFormat, RelayCmd, StreamId};
+
+    fn data(len: usize) -> AnyRelayMsgOuter {
+        let data = Data::new(&vec![b'x'; len]).unwrap();
+        AnyRelayMsgOuter::new(StreamId::new(3), data.into())
+    }
+
+    /// Decode `cell`, and return the commands of the messages in it.
+    fn cmds(cell: PackedCell) -> Vec<RelayCmd> {
+        let mut decoder = RelayCellDecoder::new(RelayCellFormat::V1);
+        let (msgs, _) = decoder.decode(cell.body).unwrap().into_parts();
+        msgs.map(|m| m.cmd()).collect()
+    }
+
+    #[test]
+    fn packable() {
+        let sendme = AnyRelayMsgOuter::new(None, Sendme::new_tag([0; 20]).into());
+        assert!(is_packable(&sendme));
+        assert!(is_packable(&data(10)));
+        assert!(!is_packable(&data(Data::MAXLEN_V1)));
+        let drop = AnyRelayMsgOuter::new(None, AnyRelayMsg::Drop(Default::default()));
+        assert!(!is_packable(&drop));
+    }
+
+    #[test]
+    fn pack() {
+        let mut pending = PendingCell::default();
+        assert!(pending.take().is_none());
+
+        let sendme = AnyRelayMsgOuter::new(None, Sendme::new_tag([0; 20]).into());
+        assert!(pending.push(sendme, false).unwrap().is_none());
+        assert!(pending.push(data(100), true).unwrap().is_none());
+        let end = AnyRelayMsgOuter::new(StreamId::new(3), End::new_misc().into());
+        assert!(pending.push(end, false).unwrap().is_none());
+
+        let cell = pending.take().unwrap();
+        assert!(cell.counts_towards_windows);
+        assert_eq!(
+            cmds(cell),
+            vec![RelayCmd::SENDME, RelayCmd::DATA, RelayCmd::END]
+        );
+        assert!(pending.take().is_none());
+
+        // When a message doesn't fit, we get the full cell back.
+        assert!(pending.push(data(240), true).unwrap().is_none());
+        let end = AnyRelayMsgOuter::new(StreamId::new(3), End::new_misc().into());
+        assert!(pending.push(end, false).unwrap().is_none());
+        let full = pending.push(data(240), true).unwrap().unwrap();
+        assert!(full.counts_towards_windows);
+        assert_eq!(cmds(full), vec![RelayCmd::DATA, RelayCmd::END]);
+        let end = AnyRelayMsgOuter::new(StreamId::new(3), End::new_misc().into());
+        assert!(pending.push(end, false).unwrap().is_none());
+        let cell = pending.take().unwrap();
+        assert!(cell.counts_towards_windows);
+        assert_eq!(cmds(cell), vec![RelayCmd::DATA, RelayCmd::END]);
+    }
+}
@@ -1,6 +1,14 @@
 //! Conflux-related functionality
 
+#[cfg(feature = "conflux")]
+pub(super) mod msghandler;
+
+#[cfg(feature = "conflux")]
+use std::cmp::Reverse;
+#[cfg(feature = "conflux")]
+use std::collections::BinaryHeap;
 use std::future::Future;
+use std::task::Poll;
 
 use futures::StreamExt;
 use futures::{select_biased, stream::FuturesUnordered, FutureExt as _};
@@ -8,12 +16,32 @@ use slotmap_careful::SlotMap;
 
 use tor_async_utils::SinkPrepareExt as _;
 use tor_basic_utils::flatten;
+use tor_cell::relaycell::msg;
+use tor_cell::relaycell::AnyRelayMsgOuter;
 use tor_error::{bad_api_usage, internal, into_bad_api_usage, Bug};
 
 use crate::crypto::cell::HopNum;
 use crate::util::err::ReactorError;
 
-use super::{Circuit, CircuitAction, LegId, LegIdKey};
+use super::circuit::CircuitCmd;
+use super::{Circuit, CircuitAction, LegId, LegIdKey, SendRelayCell};
+
+#[cfg(feature = "conflux")]
+use {
+    super::{CellHandlers, ReactorResultChannel},
+    msghandler::{ConfluxMsgHandler, OooRelayMsg},
+    tor_cell::relaycell::conflux::V1Nonce,
+    tor_cell::relaycell::msg::ConfluxSwitch,
+};
+
+/// The largest number of multiplexed messages that a [`ConfluxSet`] holds on to
+/// while waiting for the messages that precede them.
+///
+/// An exit can only get this far ahead on one leg by sending more on it than
+/// the congestion windows allow, or by skipping sequence numbers with SWITCH.
+/// Rather than buffer such messages without bound, we close the set.
+#[cfg(feature = "conflux")]
+pub(crate) const MAX_OOO_MSGS: usize = 8192;
 
 /// A set of linked conflux circuits.
 pub(super) struct ConfluxSet {
@@ -21,6 +49,22 @@ pub(super) struct ConfluxSet {
     legs: SlotMap<LegIdKey, Circuit>,
     /// The unique identifier of the primary leg
     pub(super) primary_id: LegIdKey,
+    /// The absolute sequence number of the last multiplexed message sent on any leg.
+    #[cfg(feature = "conflux")]
+    last_seq_sent: u64,
+    /// The absolute sequence number of the last multiplexed message
+    /// we delivered to a stream.
+    #[cfg(feature = "conflux")]
+    last_seq_delivered: u64,
+    /// Multiplexed messages that arrived before some of the messages preceding them,
+    /// ordered by sequence number.
+    ///
+    /// Never holds more than [`MAX_OOO_MSGS`] messages.
+    #[cfg(feature = "conflux")]
+    ooo_msgs: BinaryHeap<Reverse<OooRelayMsg>>,
+    /// A channel to notify once all the legs of this set are linked.
+    #[cfg(feature = "conflux")]
+    link_done: Option<ReactorResultChannel<()>>,
 }
 
 impl ConfluxSet {
@@ -29,7 +73,18 @@ impl ConfluxSet {
         let mut legs: SlotMap<LegIdKey, Circuit> = SlotMap::with_key();
         let primary_id = legs.insert(circuit_leg);
 
-        Self { legs, primary_id }
+        Self {
+            legs,
+            primary_id,
+            #[cfg(feature = "conflux")]
+            last_seq_sent: 0,
+            #[cfg(feature = "conflux")]
+            last_seq_delivered: 0,
+            #[cfg(feature = "conflux")]
+            ooo_msgs: BinaryHeap::new(),
+            #[cfg(feature = "conflux")]
+            link_done: None,
+        }
     }
 
     /// Remove and return the only leg of this conflux set.
@@ -67,18 +122,11 @@ impl ConfluxSet {
     ///
     /// Returns an error if called before any circuit legs are available.
     pub(super) fn primary_leg_mut(&mut self) -> Result<&mut Circuit, Bug> {
-        // TODO(conflux): support more than one leg,
-        // and remove this check
-        if self.legs.len() > 1 {
-            return Err(internal!("multipath not currently supported"));
-        }
-
         if self.legs.is_empty() {
             Err(bad_api_usage!(
                 "tried to get circuit leg before creating it?!"
             ))
         } else {
-            // TODO(conflux): implement primary leg selection
             let circ = self
                 .legs
                 .get_mut(self.primary_id)
@@ -123,16 +171,48 @@ impl ConfluxSet {
             .remove(leg)
             .ok_or_else(|| bad_api_usage!("leg {leg:?} not found in conflux set"))?;
 
-        // TODO(conflux): if leg == primary_leg, reassign the next best leg to primary_leg
-
         if self.legs.is_empty() {
             // The last circuit in the set has just died, so the reactor should exit.
             return Err(ReactorError::Shutdown);
         }
 
+        if leg == self.primary_id {
+            self.select_primary_leg();
+        }
+
+        #[cfg(feature = "conflux")]
+        if let Some(done) = self.link_done.take() {
+            // Don't care if the receiver goes away
+            let _ = done.send(Err(crate::Error::CircuitClosed));
+        }
+
         Ok(())
     }
 
+    /// Pick a new primary leg.
+    ///
+    /// This is the linked leg with the lowest RTT, if there is one,
+    /// and otherwise an arbitrary leg.
+    fn select_primary_leg(&mut self) {
+        #[cfg(feature = "conflux")]
+        {
+            let best = self
+                .legs
+                .iter()
+                .filter_map(|(id, leg)| Some((id, leg.conflux_handler.as_ref()?.rtt()?)))
+                .min_by_key(|(_id, rtt)| *rtt);
+
+            if let Some((id, _rtt)) = best {
+                self.primary_id = id;
+                return;
+            }
+        }
+
+        if let Some(id) = self.legs.keys().next() {
+            self.primary_id = id;
+        }
+    }
+
     /// Returns the next ready [`CircuitAction`],
     /// obtained from processing the incoming/outgoing messages on all the circuits in this set.
     ///
@@ -143,11 +223,21 @@ impl ConfluxSet {
     pub(super) fn next_circ_action<'a>(
         &'a mut self,
     ) -> impl Future<Output = Result<CircuitAction, crate::Error>> + 'a {
+        let primary_id = self.primary_id;
         self.legs
             .iter_mut()
-            .map(|(leg_id, leg)| {
-                let mut ready_streams = leg.ready_streams_iterator();
+            .map(move |(leg_id, leg)| {
+                // The streams at the join point are shared by all the legs,
+                // so only the primary leg should send their messages.
+                let exclude_hop = if leg_id == primary_id {
+                    None
+                } else {
+                    leg.join_point()
+                };
+                let mut ready_streams = leg.ready_streams_iterator(exclude_hop);
+                let n_hops = usize::from(leg.num_hops());
                 let input = &mut leg.input;
+                let padder = &mut leg.padder;
                 // TODO: we don't really need prepare_send_from here
                 // because the inner select_biased! is cancel-safe.
                 // We should replace this with a simple sink readiness check
@@ -170,6 +260,23 @@ impl ConfluxSet {
                         }
                     };
 
+                    // A future to wait for our padder to ask for a padding cell.
+                    let next_padding = futures::future::poll_fn(|cx| {
+                        match padder.poll_padding(cx) {
+                            Poll::Ready(hop) if usize::from(hop) < n_hops => Poll::Ready(hop),
+                            Poll::Ready(_) => {
+                                // We don't have this hop: discard the padding.
+                                //
+                                // Rather than polling the padder again straight away (which
+                                // could spin forever if it keeps asking for the same hop),
+                                // we ask to be polled again later.
+                                cx.waker().wake_by_ref();
+                                Poll::Pending
+                            }
+                            Poll::Pending => Poll::Pending,
+                        }
+                    });
+
                     // NOTE: the stream returned by this function is polled in the select_biased!
                     // from Reactor::run_once(), so each block from *this* select_biased! must be
                     // cancellation-safe
@@ -185,6 +292,11 @@ impl ConfluxSet {
                         ret = next_ready_stream.fuse() => {
                             ret.map(|cmd| CircuitAction::RunCmd { leg: leg_id, cmd })
                         },
+                        hop = next_padding.fuse() => {
+                            let cell = AnyRelayMsgOuter::new(None, msg::Drop::default().into());
+                            let cmd = CircuitCmd::Send(SendRelayCell { hop, early: false, cell });
+                            Ok(CircuitAction::RunCmd { leg: leg_id, cmd })
+                        },
                     }
                 })
             })
@@ -201,10 +313,274 @@ impl ConfluxSet {
     }
 
     /// The join point on the current primary leg.
+    ///
+    /// Returns `None` if this is not a conflux tunnel.
     pub(super) fn primary_join_point(&self) -> Option<(LegId, HopNum)> {
-        // TODO(conflux): we need a way to get the join point on the primary leg once this tunnel
-        // has been converted from a "non-conflux tunnel" to a "conflux tunnel"
-        None
+        let join_point = self.legs.get(self.primary_id)?.join_point()?;
+        Some((LegId(self.primary_id), join_point))
+    }
+
+    /// Send `cell` on the leg `leg`, or on the primary leg if `leg` is `None`.
+    ///
+    /// Multiplexed messages on a conflux tunnel are always sent on the primary leg,
+    /// regardless of `leg`.
+    pub(super) async fn send_relay_cell_on_leg(
+        &mut self,
+        cell: SendRelayCell,
+        leg: Option<LegId>,
+    ) -> crate::Result<()> {
+        #[cfg(feature = "conflux")]
+        if self.is_multiplexed(&cell) {
+            return self.send_multiplexed(cell).await;
+        }
+
+        let circ = match leg {
+            Some(leg) => self
+                .leg_mut(leg)
+                .ok_or_else(|| bad_api_usage!("No leg for id {:?}", leg))?,
+            None => self.primary_leg_mut()?,
+        };
+
+        circ.send_relay_cell(cell).await
+    }
+
+    /// Send the packed cells that each leg has been holding on to.
+    ///
+    /// See [`Circuit::flush_packed_cells`].
+    pub(super) async fn flush_packed_cells(&mut self) -> crate::Result<()> {
+        for leg in self.legs.values_mut() {
+            leg.flush_packed_cells().await?;
+        }
+        Ok(())
+    }
+
+    /// Return true if `cell` should be sent with a sequence number on the primary leg.
+    #[cfg(feature = "conflux")]
+    fn is_multiplexed(&self, cell: &SendRelayCell) -> bool {
+        self.legs
+            .get(self.primary_id)
+            .and_then(|leg| leg.conflux_handler.as_ref())
+            .is_some_and(|handler| {
+                cell.hop == handler.join_point()
+                    && msghandler::cmd_counts_towards_seqno(cell.cell.cmd())
+            })
+    }
+
+    /// Send a multiplexed message on the primary leg.
+    ///
+    /// If other legs have been used for sending since we last sent on the primary leg,
+    /// we first tell the join point to skip the sequence numbers we used on those legs,
+    /// with a `CONFLUX_SWITCH`.
+    #[cfg(feature = "conflux")]
+    async fn send_multiplexed(&mut self, cell: SendRelayCell) -> crate::Result<()> {
+        let last_seq_sent = self.last_seq_sent;
+        let seqno = last_seq_sent
+            .checked_add(1)
+            .ok_or_else(|| internal!("conflux sequence number overflow"))?;
+        let circ = self.primary_leg_mut()?;
+        let leg_last_seq_sent = circ
+            .conflux_handler
+            .as_ref()
+            .ok_or_else(|| internal!("multiplexing on a non-conflux leg?!"))?
+            .last_seq_sent();
+
+        if leg_last_seq_sent != last_seq_sent {
+            let relative_seqno = u32::try_from(last_seq_sent - leg_last_seq_sent)
+                .map_err(|_| internal!("conflux sequence number gap too large"))?;
+            let switch = SendRelayCell {
+                hop: cell.hop,
+                early: false,
+                cell: AnyRelayMsgOuter::new(None, ConfluxSwitch::new(relative_seqno).into()),
+            };
+            circ.send_relay_cell(switch).await?;
+        }
+
+        circ.send_relay_cell(cell).await?;
+
+        if let Some(handler) = circ.conflux_handler.as_mut() {
+            handler.set_last_seq_sent(seqno);
+        }
+        self.last_seq_sent = seqno;
+
+        Ok(())
+    }
+
+    /// Add `legs` to this set, and start linking them with our existing leg.
+    ///
+    /// This sends a `CONFLUX_LINK` on every leg.
+    /// `done` is notified once all the legs are linked
+    /// (see [`maybe_finish_linking`](Self::maybe_finish_linking)),
+    /// or right away if `legs` can't be added to this set.
+    //
+    // TODO(conflux): time out if the join point takes too long to answer.
+    #[cfg(feature = "conflux")]
+    pub(super) async fn add_legs(
+        &mut self,
+        legs: Vec<Circuit>,
+        done: ReactorResultChannel<()>,
+    ) -> crate::Result<()> {
+        let join_point = match self.check_new_legs(&legs) {
+            Ok(join_point) => join_point,
+            Err(e) => {
+                // Don't care if the receiver goes away
+                let _ = done.send(Err(e.into()));
+                return Ok(());
+            }
+        };
+
+        let nonce = V1Nonce::new(&mut rand::rng());
+        let primary = self.primary_leg_mut()?;
+        let stream_map = primary
+            .stream_map(join_point)
+            .ok_or_else(|| internal!("join point disappeared?!"))?;
+        primary.conflux_handler = Some(ConfluxMsgHandler::new(join_point, nonce));
+
+        for mut leg in legs {
+            leg.set_stream_map(join_point, std::sync::Arc::clone(&stream_map))?;
+            leg.conflux_handler = Some(ConfluxMsgHandler::new(join_point, nonce));
+            let _ = self.legs.insert(leg);
+        }
+        self.link_done = Some(done);
+
+        for leg in self.legs.values_mut() {
+            let link = leg
+                .conflux_handler
+                .as_ref()
+                .ok_or_else(|| internal!("conflux leg without a handler?!"))?
+                .link_msg();
+            let cell = SendRelayCell {
+                hop: join_point,
+                early: false,
+                cell: AnyRelayMsgOuter::new(None, link),
+            };
+            leg.send_relay_cell(cell).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether `legs` can be added to this set,
+    /// returning the join point they would share.
+    #[cfg(feature = "conflux")]
+    fn check_new_legs(&self, legs: &[Circuit]) -> Result<HopNum, Bug> {
+        let (_id, primary) = self
+            .single_leg()
+            .map_err(into_bad_api_usage!("tunnel is already multi-path"))?;
+        if primary.conflux_handler.is_some() {
+            return Err(bad_api_usage!("tunnel is already being linked"));
+        }
+        if legs.is_empty() {
+            return Err(bad_api_usage!("no circuits to link"));
+        }
+
+        let n_hops = primary.num_hops();
+        if n_hops == 0 {
+            return Err(bad_api_usage!("cannot link a circuit with no hops"));
+        }
+        let join_point = HopNum::from(n_hops - 1);
+
+        for leg in legs {
+            if leg.num_hops() != n_hops {
+                return Err(bad_api_usage!(
+                    "cannot link circuits of different lengths ({} and {})",
+                    n_hops,
+                    leg.num_hops()
+                ));
+            }
+            let has_streams = leg
+                .hop(join_point)
+                .is_some_and(|hop| hop.n_open_streams() > 0);
+            if has_streams {
+                return Err(bad_api_usage!(
+                    "cannot link a circuit that has open streams"
+                ));
+            }
+        }
+
+        Ok(join_point)
+    }
+
+    /// If we are linking this set and all its legs are now linked,
+    /// pick the primary leg and notify whoever asked for the linking.
+    #[cfg(feature = "conflux")]
+    pub(super) fn maybe_finish_linking(&mut self) {
+        if self.link_done.is_none() {
+            return;
+        }
+
+        let all_linked = self.legs.values().all(|leg| {
+            leg.conflux_handler
+                .as_ref()
+                .is_some_and(ConfluxMsgHandler::is_linked)
+        });
+        if !all_linked {
+            return;
+        }
+
+        self.select_primary_leg();
+        if let Some(done) = self.link_done.take() {
+            // Don't care if the receiver goes away
+            let _ = done.send(Ok(()));
+        }
+    }
+
+    /// Queue a multiplexed message received on one of our legs,
+    /// and deliver all the messages that are now in order to their streams.
+    ///
+    /// Returns the commands resulting from delivering the messages,
+    /// along with the leg they should run on.
+    ///
+    /// Returns an error, which closes the set, if more than [`MAX_OOO_MSGS`]
+    /// messages are left waiting for the ones that precede them.
+    #[cfg(feature = "conflux")]
+    pub(super) fn enqueue_msg(
+        &mut self,
+        handlers: &mut CellHandlers,
+        msg: OooRelayMsg,
+    ) -> crate::Result<Vec<(LegIdKey, CircuitCmd)>> {
+        if msg.seqno <= self.last_seq_delivered {
+            return Err(crate::Error::CircProto(format!(
+                "Received conflux message with already-used sequence number {}",
+                msg.seqno
+            )));
+        }
+        self.ooo_msgs.push(Reverse(msg));
+
+        let mut cmds = vec![];
+        while self
+            .ooo_msgs
+            .peek()
+            .is_some_and(|Reverse(next)| next.seqno == self.last_seq_delivered + 1)
+        {
+            let Some(Reverse(next)) = self.ooo_msgs.pop() else {
+                break;
+            };
+            self.last_seq_delivered = next.seqno;
+
+            // All the legs share the stream map of the join point,
+            // so it doesn't matter which leg we deliver the message on.
+            let primary_id = self.primary_id;
+            let circ = self.primary_leg_mut()?;
+            let cmd = circ.handle_in_order_msg(
+                handlers,
+                next.hopnum,
+                next.cell_counts_toward_windows,
+                next.streamid,
+                next.msg,
+            )?;
+            if let Some(cmd) = cmd {
+                cmds.push((primary_id, cmd));
+            }
+        }
+
+        if self.ooo_msgs.len() > MAX_OOO_MSGS {
+            return Err(crate::Error::CircProto(format!(
+                "Too many out-of-order conflux messages (waiting for {})",
+                self.last_seq_delivered + 1
+            )));
+        }
+
+        Ok(cmds)
     }
 
     /// Does congestion control use stream SENDMEs for the given hop?
@@ -0,0 +1,336 @@
+//! Handling of conflux messages on a single leg of a conflux set.
+//!
+//! See [proposal 329](https://spec.torproject.org/proposals/329-traffic-splitting.html).
+
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use tor_cell::relaycell::conflux::{V1DesiredUx, V1LinkPayload, V1Nonce};
+use tor_cell::relaycell::msg::{
+    AnyRelayMsg, ConfluxLink, ConfluxLinked, ConfluxLinkedAck, ConfluxSwitch,
+};
+use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCmd, StreamId, UnparsedRelayMsg};
+
+use crate::crypto::cell::HopNum;
+use crate::{Error, Result};
+
+use super::super::circuit::CircuitCmd;
+use super::super::SendRelayCell;
+
+/// Return true if a relay message of type `cmd` is "multiplexed",
+/// and therefore has a sequence number, on a conflux set.
+///
+/// Multiplexed messages can be sent on any leg,
+/// and are reordered by the receiver before being delivered to their stream.
+pub(crate) fn cmd_counts_towards_seqno(cmd: RelayCmd) -> bool {
+    matches!(
+        cmd,
+        RelayCmd::BEGIN
+            | RelayCmd::DATA
+            | RelayCmd::END
+            | RelayCmd::CONNECTED
+            | RelayCmd::RESOLVE
+            | RelayCmd::RESOLVED
+            | RelayCmd::XOFF
+            | RelayCmd::XON
+    )
+}
+
+/// The conflux state of a single circuit leg.
+#[derive(Debug)]
+pub(crate) struct ConfluxMsgHandler {
+    /// The hop at which this leg joins the other legs of the set.
+    join_point: HopNum,
+    /// The nonce identifying the conflux set, sent in our `CONFLUX_LINK`.
+    nonce: V1Nonce,
+    /// Whether this leg has been linked yet.
+    state: LinkState,
+    /// The absolute sequence number of the last multiplexed message sent on this leg.
+    last_seq_sent: u64,
+    /// The absolute sequence number of the last multiplexed message received on this leg.
+    last_seq_recv: u64,
+}
+
+/// The link status of a conflux leg.
+#[derive(Debug, Clone, Copy)]
+enum LinkState {
+    /// We have sent a `CONFLUX_LINK`, and are waiting for a `CONFLUX_LINKED`.
+    Unlinked {
+        /// When we sent the `CONFLUX_LINK`.
+        link_sent_at: coarsetime::Instant,
+    },
+    /// We have received a valid `CONFLUX_LINKED`.
+    Linked {
+        /// The time it took for the `CONFLUX_LINKED` to arrive.
+        rtt: Duration,
+    },
+}
+
+impl ConfluxMsgHandler {
+    /// Create a new handler for a leg joining the set identified by `nonce` at `join_point`.
+    ///
+    /// The `CONFLUX_LINK` returned by [`link_msg`](Self::link_msg)
+    /// should be sent to `join_point` right away.
+    pub(crate) fn new(join_point: HopNum, nonce: V1Nonce) -> Self {
+        Self {
+            join_point,
+            nonce,
+            state: LinkState::Unlinked {
+                link_sent_at: coarsetime::Instant::now(),
+            },
+            last_seq_sent: 0,
+            last_seq_recv: 0,
+        }
+    }
+
+    /// Return the `CONFLUX_LINK` message to send to the join point.
+    pub(crate) fn link_msg(&self) -> AnyRelayMsg {
+        // We pick our primary leg by RTT, which is the MinRTT scheduling algorithm.
+        let payload = V1LinkPayload::new(self.nonce, V1DesiredUx::MIN_LATENCY);
+        ConfluxLink::new(payload).into()
+    }
+
+    /// Return the hop at which this leg joins the other legs of the set.
+    pub(crate) fn join_point(&self) -> HopNum {
+        self.join_point
+    }
+
+    /// Return true if this leg has been linked.
+    pub(crate) fn is_linked(&self) -> bool {
+        matches!(self.state, LinkState::Linked { .. })
+    }
+
+    /// Return the RTT we measured when linking this leg, if it is linked.
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        match self.state {
+            LinkState::Linked { rtt } => Some(rtt),
+            LinkState::Unlinked { .. } => None,
+        }
+    }
+
+    /// Return the absolute sequence number of the last multiplexed message sent on this leg.
+    pub(crate) fn last_seq_sent(&self) -> u64 {
+        self.last_seq_sent
+    }
+
+    /// Record that we have sent the multiplexed message with absolute sequence number `seqno`
+    /// on this leg.
+    pub(crate) fn set_last_seq_sent(&mut self, seqno: u64) {
+        self.last_seq_sent = seqno;
+    }
+
+    /// Note that we have received a multiplexed message on this leg,
+    /// and return its absolute sequence number.
+    pub(crate) fn inc_last_seq_recv(&mut self) -> Result<u64> {
+        self.last_seq_recv = self
+            .last_seq_recv
+            .checked_add(1)
+            .ok_or_else(|| Error::CircProto("Conflux sequence number overflow".into()))?;
+        Ok(self.last_seq_recv)
+    }
+
+    /// Return true if a message of type `cmd` from `hop` on this leg has a sequence number.
+    pub(crate) fn is_multiplexed(&self, hop: HopNum, cmd: RelayCmd) -> bool {
+        self.is_linked() && hop == self.join_point && cmd_counts_towards_seqno(cmd)
+    }
+
+    /// Handle a `CONFLUX_LINKED` or `CONFLUX_SWITCH` message received from `hop`.
+    pub(crate) fn handle_msg(
+        &mut self,
+        hop: HopNum,
+        msg: UnparsedRelayMsg,
+    ) -> Result<Option<CircuitCmd>> {
+        if hop != self.join_point {
+            return Err(Error::CircProto(format!(
+                "Unexpected {} cell from hop {} on conflux leg",
+                msg.cmd(),
+                hop.display(),
+            )));
+        }
+
+        match msg.cmd() {
+            RelayCmd::CONFLUX_LINKED => self.handle_linked(msg),
+            RelayCmd::CONFLUX_SWITCH => self.handle_switch(msg),
+            cmd => Err(Error::from(tor_error::internal!(
+                "Conflux handler received unexpected {cmd} message"
+            ))),
+        }
+    }
+
+    /// Handle a `CONFLUX_LINKED` message, returning the `CONFLUX_LINKED_ACK` to send.
+    fn handle_linked(&mut self, msg: UnparsedRelayMsg) -> Result<Option<CircuitCmd>> {
+        let LinkState::Unlinked { link_sent_at } = self.state else {
+            return Err(Error::CircProto(
+                "Received CONFLUX_LINKED on an already linked leg".into(),
+            ));
+        };
+
+        let linked = msg
+            .decode::<ConfluxLinked>()
+            .map_err(|e| Error::from_bytes_err(e, "linked message"))?
+            .into_msg();
+
+        if linked.payload().nonce() != &self.nonce {
+            return Err(Error::CircProto(
+                "Received CONFLUX_LINKED with mismatched nonce".into(),
+            ));
+        }
+
+        let rtt = coarsetime::Instant::now().duration_since(link_sent_at);
+        self.state = LinkState::Linked { rtt: rtt.into() };
+
+        let cell = AnyRelayMsgOuter::new(None, ConfluxLinkedAck::default().into());
+        Ok(Some(CircuitCmd::Send(SendRelayCell {
+            hop: self.join_point,
+            early: false,
+            cell,
+        })))
+    }
+
+    /// Handle a `CONFLUX_SWITCH` message.
+    fn handle_switch(&mut self, msg: UnparsedRelayMsg) -> Result<Option<CircuitCmd>> {
+        if !self.is_linked() {
+            return Err(Error::CircProto(
+                "Received CONFLUX_SWITCH on an unlinked leg".into(),
+            ));
+        }
+
+        let switch = msg
+            .decode::<ConfluxSwitch>()
+            .map_err(|e| Error::from_bytes_err(e, "switch message"))?
+            .into_msg();
+
+        // The sequence number is relative to the last one we got on this leg.
+        self.last_seq_recv = self
+            .last_seq_recv
+            .checked_add(u64::from(switch.seqno()))
+            .ok_or_else(|| Error::CircProto("Conflux sequence number overflow".into()))?;
+
+        Ok(None)
+    }
+}
+
+/// A multiplexed message, waiting to be delivered to its stream.
+///
+/// Ordered by sequence number.
+#[derive(Debug)]
+pub(crate) struct OooRelayMsg {
+    /// The absolute sequence number of the message.
+    pub(crate) seqno: u64,
+    /// The hop the message came from.
+    pub(crate) hopnum: HopNum,
+    /// The stream the message is for.
+    pub(crate) streamid: StreamId,
+    /// Whether the cell that held the message counted towards the circuit windows.
+    pub(crate) cell_counts_toward_windows: bool,
+    /// The message itself.
+    pub(crate) msg: UnparsedRelayMsg,
+}
+
+impl PartialEq for OooRelayMsg {
+    fn eq(&self, other: &Self) -> bool {
+        self.seqno == other.seqno
+    }
+}
+
+impl Eq for OooRelayMsg {}
+
+impl PartialOrd for OooRelayMsg {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OooRelayMsg {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.seqno.cmp(&other.seqno)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_cell::relaycell::RelayCellFormat;
+
+    /// Encode `msg` and parse it back as an `UnparsedRelayMsg`.
+    fn unparsed(msg: AnyRelayMsg) -> UnparsedRelayMsg {
+        let body = AnyRelayMsgOuter::new(None, msg)
+            .encode(RelayCellFormat::V0, &mut testing_rng())
+            .unwrap();
+        let mut decoder = tor_cell::relaycell::RelayCellDecoder::new(RelayCellFormat::V0);
+        let (mut msgs, _) = decoder.decode(body).unwrap().into_parts();
+        msgs.next().unwrap()
+    }
+
+    fn linked(nonce: V1Nonce) -> UnparsedRelayMsg {
+        let payload = V1LinkPayload::new(nonce, V1DesiredUx::NO_OPINION);
+        unparsed(ConfluxLinked::new(payload).into())
+    }
+
+    #[test]
+    fn link_and_switch() {
+        let mut rng = testing_rng();
+        let nonce = V1Nonce::new(&mut rng);
+        let join_point = HopNum::from(2);
+        let mut handler = ConfluxMsgHandler::new(join_point, nonce);
+        assert!(!handler.is_linked());
+        assert!(!handler.is_multiplexed(join_point, RelayCmd::DATA));
+
+        // LINKED from the wrong hop.
+        let err = handler
+            .handle_msg(HopNum::from(1), linked(nonce))
+            .unwrap_err();
+        assert!(matches!(err, Error::CircProto(_)));
+
+        // SWITCH before LINKED.
+        let switch = unparsed(ConfluxSwitch::new(5).into());
+        let err = handler.handle_msg(join_point, switch).unwrap_err();
+        assert!(matches!(err, Error::CircProto(_)));
+
+        let cmd = handler.handle_msg(join_point, linked(nonce)).unwrap();
+        let Some(CircuitCmd::Send(cell)) = cmd else {
+            panic!("expected LINKED_ACK");
+        };
+        assert_eq!(cell.hop, join_point);
+        assert_eq!(cell.cell.cmd(), RelayCmd::CONFLUX_LINKED_ACK);
+        assert!(handler.is_linked());
+        assert!(handler.rtt().is_some());
+        assert!(handler.is_multiplexed(join_point, RelayCmd::DATA));
+        assert!(!handler.is_multiplexed(join_point, RelayCmd::SENDME));
+
+        // A second LINKED is a protocol violation.
+        let err = handler.handle_msg(join_point, linked(nonce)).unwrap_err();
+        assert!(matches!(err, Error::CircProto(_)));
+
+        assert_eq!(handler.inc_last_seq_recv().unwrap(), 1);
+        let switch = unparsed(ConfluxSwitch::new(5).into());
+        assert!(handler.handle_msg(join_point, switch).unwrap().is_none());
+        assert_eq!(handler.inc_last_seq_recv().unwrap(), 7);
+    }
+
+    #[test]
+    fn bad_nonce() {
+        let mut rng = testing_rng();
+        let join_point = HopNum::from(2);
+        let mut handler = ConfluxMsgHandler::new(join_point, V1Nonce::new(&mut rng));
+        let err = handler
+            .handle_msg(join_point, linked(V1Nonce::new(&mut rng)))
+            .unwrap_err();
+        assert!(matches!(err, Error::CircProto(_)));
+        assert!(!handler.is_linked());
+    }
+}
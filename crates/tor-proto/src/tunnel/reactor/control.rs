@@ -1,28 +1,45 @@
 //! Module providing [`CtrlMsg`].
 
-use super::circuit::extender::CircuitExtender;
+use super::circuit::extender::{CircuitExtender, HandshakeAuxDataHandler};
 use super::{
     CircuitHandshake, CloseStreamBehavior, MetaCellHandler, Reactor, ReactorResultChannel,
     RunOnceCmdInner, SendRelayCell,
 };
+use crate::congestion::CongestionControlStats;
 use crate::crypto::binding::CircuitBinding;
-use crate::crypto::cell::{HopNum, InboundClientLayer, OutboundClientLayer, Tor1RelayCrypto};
+#[cfg(feature = "counter-galois-onion")]
+use crate::crypto::cell::cgo;
+use crate::crypto::cell::{
+    ClientLayer, CryptInit, HopNum, InboundClientLayer, OutboundClientLayer, Tor1RelayCrypto,
+};
+#[cfg(feature = "ntor-v3-pq")]
+use crate::crypto::handshake::ntor_v3::pq::NtorV3PqClient;
 use crate::crypto::handshake::ntor_v3::{NtorV3Client, NtorV3PublicKey};
-use crate::stream::AnyCmdChecker;
+use crate::crypto::handshake::{ClientHandshake, KeyGenerator};
+use crate::stream::{AnyCmdChecker, StreamRecvQueue};
 use crate::tunnel::circuit::celltypes::CreateResponse;
-use crate::tunnel::circuit::{path, CircParameters};
+use crate::tunnel::circuit::traffic::TrafficCounters;
+use crate::tunnel::circuit::{path, CircParameters, LivenessProbe};
+#[cfg(feature = "hs-common")]
+use crate::tunnel::circuit::{HopCryptoInfo, HopHandshake};
+#[cfg(feature = "counter-galois-onion")]
+use crate::tunnel::handshake::cgo_subproto;
+use crate::tunnel::handshake::RelayCryptLayerProtocol;
 use crate::tunnel::reactor::{NtorClient, ReactorError};
 use crate::tunnel::{streammap, HopLocation, LegId, TargetHop};
 use crate::util::skew::ClockSkew;
 use crate::Result;
+use std::time::Duration;
 use tor_cell::chancell::msg::HandshakeType;
 use tor_cell::relaycell::extend::NtorV3Extension;
-use tor_cell::relaycell::msg::{AnyRelayMsg, Sendme};
+use tor_cell::relaycell::flow_ctrl::XonKbpsEwma;
+use tor_cell::relaycell::msg::{AnyRelayMsg, Drop as DropMsg, Sendme, Xon};
 use tor_cell::relaycell::{
     AnyRelayMsgOuter, RelayCellFormat, RelayCellFormatTrait, RelayCellFormatV0, StreamId,
     UnparsedRelayMsg,
 };
 use tor_error::{bad_api_usage, into_bad_api_usage, Bug};
+use tor_rtcompat::SleepProvider as _;
 use tracing::trace;
 #[cfg(feature = "hs-service")]
 use {
@@ -32,16 +49,21 @@ use {
 
 #[cfg(test)]
 use crate::congestion::sendme::CircTag;
+#[cfg(all(test, feature = "crypto-offload"))]
+use crate::crypto::offload::OffloadLane;
 
-#[cfg(feature = "conflux")]
 use super::Circuit;
 
+#[cfg(feature = "circ-padding")]
+use crate::tunnel::circuit::padding::CircuitPadder;
+
 use oneshot_fused_workaround as oneshot;
 
 use crate::crypto::handshake::ntor::NtorPublicKey;
 use crate::tunnel::circuit::{StreamMpscReceiver, StreamMpscSender};
 use tor_linkspec::{EncodedLinkSpec, OwnedChanTarget};
 
+use std::borrow::Borrow;
 use std::result::Result as StdResult;
 use std::sync::Arc;
 
@@ -93,6 +115,12 @@ pub(crate) enum CtrlMsg {
         linkspecs: Vec<EncodedLinkSpec>,
         /// Other parameters relevant for circuit extension.
         params: CircParameters,
+        /// If true, use the hybrid post-quantum variant of the handshake.
+        ///
+        /// (Only supported with the `ntor-v3-pq` feature.)
+        post_quantum: bool,
+        /// Which relay cell encryption protocol (and format) to use for this hop.
+        protocol: RelayCryptLayerProtocol,
         /// Oneshot channel to notify on completion.
         done: ReactorResultChannel<()>,
     },
@@ -111,6 +139,12 @@ pub(crate) enum CtrlMsg {
         /// can assume someone is trying to send us more cells than they should, and abort
         /// the stream.
         sender: StreamMpscSender<UnparsedRelayMsg>,
+        /// The number of bytes queued on `sender`, shared with the stream's reader.
+        ///
+        /// Used to decide when to send XOFF messages.
+        recv_queue: Arc<StreamRecvQueue>,
+        /// The traffic counters for this stream, shared with the stream itself.
+        traffic: Arc<TrafficCounters>,
         /// A channel to receive messages to send on this stream from.
         rx: StreamMpscReceiver<AnyRelayMsg>,
         /// Oneshot channel to notify on completion, with the allocated stream ID.
@@ -149,7 +183,7 @@ pub(crate) enum CtrlMsg {
     },
     /// Send a given control message on this circuit, and install a control-message handler to
     /// receive responses.
-    #[cfg(feature = "send-control-msg")]
+    #[cfg(any(feature = "send-control-msg", feature = "circ-padding"))]
     SendMsgAndInstallHandler {
         /// The message to send, if any
         msg: Option<AnyRelayMsgOuter>,
@@ -171,11 +205,40 @@ pub(crate) enum CtrlMsg {
         /// A sender that we use to tell the caller that the SENDME was sent.
         sender: oneshot::Sender<Result<()>>,
     },
+    /// Send a DROP cell to a hop, which will discard it.
+    SendDrop {
+        /// The hop to send the DROP cell to.
+        hop: HopNum,
+        /// A sender that we use to tell the caller that the cell was sent.
+        sender: oneshot::Sender<Result<()>>,
+    },
+    /// Send an XON cell (used to ask for data to be sent again after an XOFF)
+    /// on the given stream.
+    SendXon {
+        /// The stream ID to send an XON for.
+        stream_id: StreamId,
+        /// The hop number the stream is on.
+        hop: HopLocation,
+        /// A sender that we use to tell the caller that the XON was sent.
+        sender: oneshot::Sender<Result<()>>,
+    },
     /// Get the clock skew claimed by the first hop of the circuit.
     FirstHopClockSkew {
         /// Oneshot channel to return the clock skew.
         answer: oneshot::Sender<StdResult<ClockSkew, Bug>>,
     },
+    /// Add the given circuits to this tunnel, and link them all into a conflux set.
+    ///
+    /// The circuits must have been taken from their own reactors
+    /// with [`CtrlCmd::ShutdownAndReturnCircuit`].
+    #[cfg(feature = "conflux")]
+    LinkCircuits {
+        /// The circuits to add.
+        #[educe(Debug(ignore))]
+        circuits: Vec<Circuit>,
+        /// Oneshot channel to notify once all the circuits are linked.
+        answer: ReactorResultChannel<()>,
+    },
 }
 
 /// A message telling the reactor to do something.
@@ -195,8 +258,8 @@ pub(crate) enum CtrlCmd {
     /// INTRODUCE and RENDEZVOUS messages.)
     #[cfg(feature = "hs-common")]
     ExtendVirtual {
-        /// Which relay cell format to use for this hop.
-        relay_cell_format: RelayCellFormat,
+        /// Which relay cell encryption protocol (and format) to use for this hop.
+        protocol: RelayCryptLayerProtocol,
         /// The cryptographic algorithms and keys to use when communicating with
         /// the newly added hop.
         #[educe(Debug(ignore))]
@@ -226,11 +289,39 @@ pub(crate) enum CtrlCmd {
         #[cfg(feature = "hs-service")]
         filter: Box<dyn IncomingStreamRequestFilter>,
     },
+    /// Attach a traffic-shaping defense to the circuit.
+    #[cfg(feature = "circ-padding")]
+    SetPadder {
+        /// The defense to attach.
+        #[educe(Debug(ignore))]
+        padder: Box<dyn CircuitPadder>,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<()>,
+    },
     /// Get the leg ID and path for each leg of the tunnel.
     QueryLegs {
         /// Oneshot channel to notify on completion.
         done: ReactorResultChannel<Vec<(LegId, Arc<path::Path>)>>,
     },
+    /// Get the information we need to judge whether a given hop is alive.
+    QueryLiveness {
+        /// The hop to query.
+        hop: HopNum,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<LivenessProbe>,
+    },
+    /// Get a snapshot of the congestion control state of a given hop.
+    QueryCcStats {
+        /// The hop to query.
+        hop: HopNum,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<CongestionControlStats>,
+    },
+    /// Get the smoothed RTT of each leg of the tunnel, measured at its last hop.
+    QueryLegRtts {
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<Vec<Option<Duration>>>,
+    },
     /// (tests only) Add a hop to the list of hops on this circuit, with dummy cryptography.
     #[cfg(test)]
     AddFakeHop {
@@ -246,12 +337,17 @@ pub(crate) enum CtrlCmd {
         hop: HopNum,
         done: ReactorResultChannel<(u32, Vec<CircTag>)>,
     },
+    /// (tests only) Replace the crypto offload worker of this circuit.
+    #[cfg(all(test, feature = "crypto-offload"))]
+    SetCryptoOffload {
+        lane: Option<OffloadLane>,
+        done: ReactorResultChannel<()>,
+    },
     /// Shut down the reactor, and return the underlying [`Circuit`],
     /// if the tunnel is not multi-path.
     ///
     /// Returns an error if called on a multi-path reactor.
     #[cfg(feature = "conflux")]
-    #[allow(unused)] // TODO(conflux)
     ShutdownAndReturnCircuit {
         /// Oneshot channel to return the underlying [`Circuit`],
         /// or an error if the reactor's tunnel is multi-path.
@@ -322,7 +418,7 @@ impl<'a> ControlHandler<'a> {
 
                 let (extender, cell) =
                     CircuitExtender::<NtorClient, Tor1RelayCrypto<Rcf>, _, _>::begin(
-                        Rcf::FORMAT,
+                        RelayCryptLayerProtocol::Tor1(Rcf::FORMAT),
                         peer_id,
                         HandshakeType::NTOR,
                         &public_key,
@@ -336,13 +432,19 @@ impl<'a> ControlHandler<'a> {
                     .cell_handlers
                     .set_meta_handler(Box::new(extender))?;
 
-                Ok(Some(RunOnceCmdInner::Send { cell, done: None }))
+                Ok(Some(RunOnceCmdInner::Send {
+                    leg: None,
+                    cell,
+                    done: None,
+                }))
             }
             CtrlMsg::ExtendNtorV3 {
                 peer_id,
                 public_key,
                 linkspecs,
                 params,
+                post_quantum,
+                protocol,
                 done,
             } => {
                 let Ok((_id, circ)) = self.reactor.circuits.single_leg_mut() else {
@@ -355,10 +457,6 @@ impl<'a> ControlHandler<'a> {
                     return Ok(None);
                 };
 
-                // TODO #1067, TODO #1947: support negotiating other formats.
-                /// Local type alias to ensure consistency below.
-                type Rcf = RelayCellFormatV0;
-
                 // Set the client extensions.
                 // allow 'unused_mut' because of the combinations of `cfg` conditions below
                 #[allow(unused_mut)]
@@ -371,13 +469,6 @@ impl<'a> ControlHandler<'a> {
                             // which should prevent the above `is_enabled()` from ever being true,
                             // even with the "flowctl-cc" feature enabled:
                             // https://gitlab.torproject.org/tpo/core/arti/-/merge_requests/2932#note_3191196
-                            // The panic here is so that CI tests will hopefully catch if congestion
-                            // control is unexpectedly enabled.
-                            // We should remove this panic once xon/xoff flow is supported.
-                            #[cfg(not(test))]
-                            panic!("Congestion control is enabled on this circuit, but we don't yet support congestion control");
-
-                            #[allow(unreachable_code)]
                             client_extensions.push(NtorV3Extension::RequestCongestionControl);
                         } else {
                             return Err(
@@ -390,28 +481,90 @@ impl<'a> ControlHandler<'a> {
                     }
                 }
 
-                let (extender, cell) =
-                    CircuitExtender::<NtorV3Client, Tor1RelayCrypto<Rcf>, _, _>::begin(
-                        Rcf::FORMAT,
-                        peer_id,
-                        HandshakeType::NTOR_V3,
-                        &public_key,
-                        linkspecs,
-                        params,
-                        &client_extensions,
-                        circ,
-                        done,
-                    )?;
-                self.reactor
-                    .cell_handlers
-                    .set_meta_handler(Box::new(extender))?;
+                // The relay only learns which cell encryption we want from
+                // our request; if it can't provide it, it refuses the handshake.
+                #[cfg(feature = "counter-galois-onion")]
+                if let RelayCryptLayerProtocol::Cgo(_) = protocol {
+                    client_extensions.push(NtorV3Extension::SubprotocolRequest {
+                        protocols: vec![cgo_subproto()],
+                    });
+                }
 
-                Ok(Some(RunOnceCmdInner::Send { cell, done: None }))
+                use RelayCellFormat::*;
+                use RelayCryptLayerProtocol::*;
+                let (extender, cell) = match (post_quantum, protocol) {
+                    (false, Tor1(V0)) => {
+                        begin_extend::<NtorV3Client, Tor1RelayCrypto<RelayCellFormatV0>, _, _>(
+                            protocol,
+                            peer_id,
+                            HandshakeType::NTOR_V3,
+                            &public_key,
+                            linkspecs,
+                            params,
+                            &client_extensions,
+                            circ,
+                            done,
+                        )?
+                    }
+                    #[cfg(feature = "ntor-v3-pq")]
+                    (true, Tor1(V0)) => {
+                        begin_extend::<NtorV3PqClient, Tor1RelayCrypto<RelayCellFormatV0>, _, _>(
+                            protocol,
+                            peer_id,
+                            HandshakeType::NTOR_V3_MLKEM,
+                            &public_key,
+                            linkspecs,
+                            params,
+                            &client_extensions,
+                            circ,
+                            done,
+                        )?
+                    }
+                    #[cfg(feature = "counter-galois-onion")]
+                    (false, Cgo(V1)) => {
+                        begin_extend::<NtorV3Client, cgo::CryptStatePair<aes::Aes128>, _, _>(
+                            protocol,
+                            peer_id,
+                            HandshakeType::NTOR_V3,
+                            &public_key,
+                            linkspecs,
+                            params,
+                            &client_extensions,
+                            circ,
+                            done,
+                        )?
+                    }
+                    #[cfg(not(feature = "ntor-v3-pq"))]
+                    (true, _) => {
+                        return Err(tor_error::internal!(
+                            "Post-quantum handshake requested, but 'ntor-v3-pq' feature is not enabled"
+                        )
+                        .into());
+                    }
+                    (_, protocol) => {
+                        // Don't care if the receiver goes away
+                        let _ = done.send(Err(tor_error::internal!(
+                            "{protocol:?} is not implemented for ntor v3 handshakes"
+                        )
+                        .into()));
+
+                        return Ok(None);
+                    }
+                };
+                self.reactor.cell_handlers.set_meta_handler(extender)?;
+
+                Ok(Some(RunOnceCmdInner::Send {
+                    leg: None,
+                    cell,
+                    done: None,
+                }))
             }
             CtrlMsg::BeginStream {
                 hop,
                 message,
                 sender,
+                recv_queue,
+                traffic,
                 rx,
                 done,
                 cmd_checker,
@@ -446,7 +599,15 @@ impl<'a> ControlHandler<'a> {
                     }
                 };
 
-                let cell = circ.begin_stream(hop_num, message, sender, rx, cmd_checker)?;
+                let cell = circ.begin_stream(
+                    hop_num,
+                    message,
+                    sender,
+                    recv_queue,
+                    traffic,
+                    rx,
+                    cmd_checker,
+                )?;
                 Ok(Some(RunOnceCmdInner::BeginStream {
                     cell,
                     hop: hop_location,
@@ -503,6 +664,15 @@ impl<'a> ControlHandler<'a> {
                     return Ok(None);
                 }
 
+                // If we've already sent an END on this stream, its reader may still be
+                // reading the data that was sent before the other side saw our END.
+                // But we must not send anything more on the stream.
+                if !self.stream_is_open(leg_id, hop_num, stream_id) {
+                    // don't care if receiver goes away
+                    let _ = sender.send(Ok(()));
+                    return Ok(None);
+                }
+
                 let sendme = Sendme::new_empty();
                 let cell = AnyRelayMsgOuter::new(Some(stream_id), sendme.into());
 
@@ -511,11 +681,63 @@ impl<'a> ControlHandler<'a> {
                     early: false,
                     cell,
                 };
-                // TODO(conflux): We need to pass the leg id in `RunOnceCmdInner::Send`, but we
-                // can't add this to `RunOnceCmdInner` until the stream map knows about legs.
-                // `Reactor::ready_streams_iterator` now knows the leg id, so we should be able to
-                // use that.
                 Ok(Some(RunOnceCmdInner::Send {
+                    leg: Some(leg_id),
+                    cell,
+                    done: Some(sender),
+                }))
+            }
+            // TODO(conflux): this should specify which leg to send the DROP on
+            // (currently we send it down the primary leg)
+            CtrlMsg::SendDrop { hop, sender } => {
+                let cell = AnyRelayMsgOuter::new(None, DropMsg::default().into());
+                let cell = SendRelayCell {
+                    hop,
+                    early: false,
+                    cell,
+                };
+                Ok(Some(RunOnceCmdInner::Send {
+                    leg: None,
+                    cell,
+                    done: Some(sender),
+                }))
+            }
+            CtrlMsg::SendXon {
+                stream_id,
+                hop,
+                sender,
+            } => {
+                // If resolving the hop fails,
+                // we want to report an error back to the initiator and not shut down the reactor.
+                let (leg_id, hop_num) = match self.reactor.resolve_hop_location(hop) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let e = into_bad_api_usage!("Could not resolve hop {hop:?}")(e);
+                        // don't care if receiver goes away
+                        let _ = sender.send(Err(e.into()));
+                        return Ok(None);
+                    }
+                };
+
+                // As with SENDMEs, we must not send an XON after sending an END.
+                if !self.stream_is_open(leg_id, hop_num, stream_id) {
+                    // don't care if receiver goes away
+                    let _ = sender.send(Ok(()));
+                    return Ok(None);
+                }
+
+                // TODO: We don't measure how fast the stream is being drained yet,
+                // so we can't give the other side any useful rate advice.
+                let xon = Xon::new(XonKbpsEwma::Unlimited);
+                let cell = AnyRelayMsgOuter::new(Some(stream_id), xon.into());
+
+                let cell = SendRelayCell {
+                    hop: hop_num,
+                    early: false,
+                    cell,
+                };
+                Ok(Some(RunOnceCmdInner::Send {
+                    leg: Some(leg_id),
                     cell,
                     done: Some(sender),
                 }))
@@ -535,13 +757,14 @@ impl<'a> ControlHandler<'a> {
                     cell,
                 };
                 Ok(Some(RunOnceCmdInner::Send {
+                    leg: None,
                     cell,
                     done: Some(sender),
                 }))
             }
             // TODO(conflux): this should specify which leg to send the msg on
             // (currently we send it down the primary leg)
-            #[cfg(feature = "send-control-msg")]
+            #[cfg(any(feature = "send-control-msg", feature = "circ-padding"))]
             CtrlMsg::SendMsgAndInstallHandler {
                 msg,
                 handler,
@@ -554,9 +777,21 @@ impl<'a> ControlHandler<'a> {
             CtrlMsg::FirstHopClockSkew { answer } => {
                 Ok(Some(RunOnceCmdInner::FirstHopClockSkew { answer }))
             }
+            #[cfg(feature = "conflux")]
+            CtrlMsg::LinkCircuits { circuits, answer } => {
+                Ok(Some(RunOnceCmdInner::LinkCircuits { circuits, answer }))
+            }
         }
     }
 
+    /// Return true if `stream_id` is an open stream on the given hop of the given leg.
+    fn stream_is_open(&self, leg_id: LegId, hop_num: HopNum, stream_id: StreamId) -> bool {
+        self.reactor
+            .circuits
+            .leg(leg_id)
+            .is_some_and(|leg| leg.stream_is_open(hop_num, stream_id))
+    }
+
     /// Handle a control command.
     pub(super) fn handle_cmd(&mut self, msg: CtrlCmd) -> StdResult<(), ReactorError> {
         trace!("{}: reactor received {:?}", self.reactor.unique_id, msg);
@@ -565,7 +800,7 @@ impl<'a> ControlHandler<'a> {
             #[cfg(feature = "hs-common")]
             #[allow(unreachable_code)]
             CtrlCmd::ExtendVirtual {
-                relay_cell_format: format,
+                protocol,
                 cell_crypto,
                 params,
                 done,
@@ -586,7 +821,8 @@ impl<'a> ControlHandler<'a> {
                     return Ok(());
                 };
 
-                leg.add_hop(format, peer_id, outbound, inbound, binding, &params)?;
+                let crypto = HopCryptoInfo::new(HopHandshake::Virtual, protocol);
+                leg.add_hop(crypto, peer_id, outbound, inbound, binding, &params)?;
                 let _ = done.send(Ok(()));
 
                 Ok(())
@@ -616,6 +852,20 @@ impl<'a> ControlHandler<'a> {
 
                 Ok(())
             }
+            #[cfg(feature = "circ-padding")]
+            CtrlCmd::SetPadder { padder, done } => {
+                let ret = self
+                    .reactor
+                    .circuits
+                    .single_leg_mut()
+                    .map(|(_id, leg)| leg.set_padder(padder))
+                    .map_err(|_| {
+                        tor_error::bad_api_usage!("cannot set padder on multipath tunnel").into()
+                    });
+                // don't care if the corresponding receiver goes away.
+                let _ = done.send(ret);
+                Ok(())
+            }
             CtrlCmd::QueryLegs { done } => {
                 let ret = self
                     .reactor
@@ -627,6 +877,65 @@ impl<'a> ControlHandler<'a> {
                 let _ = done.send(Ok(ret));
                 Ok(())
             }
+            CtrlCmd::QueryLiveness { hop, done } => {
+                // Immediately invoked function means that errors will be sent to the channel.
+                let _ = done.send((|| {
+                    let (_id, leg) =
+                        self.reactor
+                            .circuits
+                            .single_leg()
+                            .map_err(into_bad_api_usage!(
+                                "cannot probe the liveness of a multipath tunnel"
+                            ))?;
+
+                    let now = leg.chan_sender.as_inner().time_provider().now();
+                    let hop = leg.hop(hop).ok_or(bad_api_usage!(
+                        "received QueryLiveness for unknown hop {}",
+                        hop.display()
+                    ))?;
+
+                    Ok(LivenessProbe::new(
+                        hop.since_last_received(now),
+                        hop.ccontrol_stats().ewma_rtt(),
+                    ))
+                })());
+
+                Ok(())
+            }
+            CtrlCmd::QueryCcStats { hop, done } => {
+                // Immediately invoked function means that errors will be sent to the channel.
+                let _ = done.send((|| {
+                    let (_id, leg) =
+                        self.reactor
+                            .circuits
+                            .single_leg()
+                            .map_err(into_bad_api_usage!(
+                                "cannot query congestion control state of multipath tunnel"
+                            ))?;
+
+                    let hop = leg.hop(hop).ok_or(bad_api_usage!(
+                        "received QueryCcStats for unknown hop {}",
+                        hop.display()
+                    ))?;
+
+                    Ok(hop.ccontrol_stats())
+                })());
+
+                Ok(())
+            }
+            CtrlCmd::QueryLegRtts { done } => {
+                let ret = self
+                    .reactor
+                    .circuits
+                    .legs()
+                    .map(|(_id, leg)| {
+                        let last_hop = HopNum::from(leg.num_hops().checked_sub(1)?);
+                        leg.hop(last_hop)?.ccontrol_stats().ewma_rtt()
+                    })
+                    .collect();
+                let _ = done.send(Ok(ret));
+                Ok(())
+            }
             #[cfg(test)]
             CtrlCmd::AddFakeHop {
                 relay_cell_format,
@@ -671,6 +980,24 @@ impl<'a> ControlHandler<'a> {
 
                 Ok(())
             }
+            #[cfg(all(test, feature = "crypto-offload"))]
+            CtrlCmd::SetCryptoOffload { lane, done } => {
+                // Immediately invoked function means that errors will be sent to the channel.
+                let _ = done.send((|| {
+                    let (_id, leg) =
+                        self.reactor
+                            .circuits
+                            .single_leg_mut()
+                            .map_err(into_bad_api_usage!(
+                                "cannot set crypto offload of multipath tunnel"
+                            ))?;
+
+                    leg.set_crypto_offload(lane);
+                    Ok(())
+                })());
+
+                Ok(())
+            }
             #[cfg(feature = "conflux")]
             CtrlCmd::ShutdownAndReturnCircuit { answer } => {
                 self.reactor.handle_shutdown_and_return_circuit(answer)
@@ -678,3 +1005,42 @@ impl<'a> ControlHandler<'a> {
         }
     }
 }
+
+/// Helper: start extending `circ` by one hop, using the handshake `H`, and
+/// the relay cell encryption `L` (described by `protocol`) with the new hop.
+///
+/// Returns the handler that will finish the extension when the reply arrives,
+/// and the EXTEND2 cell to send.
+#[allow(clippy::too_many_arguments)]
+fn begin_extend<H, L, FWD, REV>(
+    protocol: RelayCryptLayerProtocol,
+    peer_id: OwnedChanTarget,
+    handshake_id: HandshakeType,
+    public_key: &H::KeyType,
+    linkspecs: Vec<EncodedLinkSpec>,
+    params: CircParameters,
+    client_aux_data: &impl Borrow<H::ClientAuxData>,
+    circ: &mut Circuit,
+    done: ReactorResultChannel<()>,
+) -> Result<(Box<dyn MetaCellHandler + Send>, SendRelayCell)>
+where
+    H: ClientHandshake + HandshakeAuxDataHandler + 'static,
+    H::StateType: Send,
+    H::KeyGen: KeyGenerator,
+    L: CryptInit + ClientLayer<FWD, REV> + Send + 'static,
+    FWD: OutboundClientLayer + 'static + Send,
+    REV: InboundClientLayer + 'static + Send,
+{
+    let (extender, cell) = CircuitExtender::<H, L, FWD, REV>::begin(
+        protocol,
+        peer_id,
+        handshake_id,
+        public_key,
+        linkspecs,
+        params,
+        client_aux_data,
+        circ,
+        done,
+    )?;
+    Ok((Box::new(extender), cell))
+}
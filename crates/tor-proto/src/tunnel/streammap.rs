@@ -1,7 +1,8 @@
 //! Types and code for mapping StreamIDs to streams on a circuit.
 
 use crate::congestion::sendme;
-use crate::stream::{AnyCmdChecker, StreamSendFlowControl};
+use crate::stream::{AnyCmdChecker, StreamRecvQueue, StreamSendFlowControl};
+use crate::tunnel::circuit::traffic::TrafficCounters;
 use crate::tunnel::circuit::{StreamMpscReceiver, StreamMpscSender};
 use crate::tunnel::halfstream::HalfStream;
 use crate::tunnel::reactor::circuit::RECV_WINDOW_INIT;
@@ -10,13 +11,14 @@ use crate::{Error, Result};
 use pin_project::pin_project;
 use tor_async_utils::peekable_stream::{PeekableStream, UnobtrusivePeekableStream};
 use tor_async_utils::stream_peek::StreamUnobtrusivePeeker;
-use tor_cell::relaycell::{msg::AnyRelayMsg, StreamId};
-use tor_cell::relaycell::{RelayMsg, UnparsedRelayMsg};
+use tor_cell::relaycell::msg::{AnyRelayMsg, Xoff, Xon};
+use tor_cell::relaycell::{RelayCmd, RelayMsg, StreamId, UnparsedRelayMsg};
 
 use std::collections::hash_map;
 use std::collections::HashMap;
 use std::num::NonZeroU16;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Poll, Waker};
 use tor_error::{bad_api_usage, internal};
 
@@ -33,6 +35,10 @@ use tracing::debug;
 pub(super) struct OpenStreamEnt {
     /// Sink to send relay cells tagged for this stream into.
     pub(super) sink: StreamMpscSender<UnparsedRelayMsg>,
+    /// The amount of data in `sink` that the stream's reader has not yet read.
+    recv_queue: Arc<StreamRecvQueue>,
+    /// Traffic counters for this stream.
+    pub(super) traffic: Arc<TrafficCounters>,
     /// Number of cells dropped due to the stream disappearing before we can
     /// transform this into an `EndSent`.
     pub(super) dropped: u16,
@@ -74,6 +80,38 @@ impl OpenStreamEnt {
         Ok(())
     }
 
+    /// Handle an incoming XOFF.
+    ///
+    /// On failure, return an error: the caller should close the stream or
+    /// circuit with a protocol error.
+    pub(crate) fn handle_incoming_xoff(&mut self, xoff: Xoff) -> Result<()> {
+        self.flow_ctrl.handle_incoming_xoff(xoff)
+    }
+
+    /// Handle an incoming XON.
+    ///
+    /// On failure, return an error: the caller should close the stream or
+    /// circuit with a protocol error.
+    pub(crate) fn handle_incoming_xon(&mut self, xon: Xon) -> Result<()> {
+        self.flow_ctrl.handle_incoming_xon(xon)?;
+        // Wake the stream if it was blocked on flow control.
+        if let Some(waker) = self.flow_ctrl_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Note that we are about to queue `msg` for this stream's reader.
+    ///
+    /// Return true if the reader has fallen so far behind
+    /// that we should send an XOFF.
+    pub(crate) fn note_queued(&self, msg: &UnparsedRelayMsg) -> bool {
+        msg.cmd() == RelayCmd::DATA
+            && self
+                .recv_queue
+                .note_queued(msg.data_len().into(), self.flow_ctrl.is_xon_xoff_based())
+    }
+
     /// Take capacity to send `msg`. If there's insufficient capacity, returns
     /// an error. Should be called at the point we've fully committed to
     /// sending the message.
@@ -270,6 +308,8 @@ impl StreamMap {
     pub(super) fn add_ent(
         &mut self,
         sink: StreamMpscSender<UnparsedRelayMsg>,
+        recv_queue: Arc<StreamRecvQueue>,
+        traffic: Arc<TrafficCounters>,
         rx: StreamMpscReceiver<AnyRelayMsg>,
         flow_ctrl: StreamSendFlowControl,
         cmd_checker: AnyCmdChecker,
@@ -277,6 +317,8 @@ impl StreamMap {
         let mut stream_ent = OpenStreamEntStream {
             inner: OpenStreamEnt {
                 sink,
+                recv_queue,
+                traffic,
                 flow_ctrl,
                 dropped: 0,
                 cmd_checker,
@@ -310,6 +352,8 @@ impl StreamMap {
     pub(super) fn add_ent_with_id(
         &mut self,
         sink: StreamMpscSender<UnparsedRelayMsg>,
+        recv_queue: Arc<StreamRecvQueue>,
+        traffic: Arc<TrafficCounters>,
         rx: StreamMpscReceiver<AnyRelayMsg>,
         flow_ctrl: StreamSendFlowControl,
         id: StreamId,
@@ -318,6 +362,8 @@ impl StreamMap {
         let stream_ent = OpenStreamEntStream {
             inner: OpenStreamEnt {
                 sink,
+                recv_queue,
+                traffic,
                 flow_ctrl,
                 dropped: 0,
                 cmd_checker,
@@ -331,6 +377,11 @@ impl StreamMap {
             .map_err(|_| Error::IdUnavailable(id))
     }
 
+    /// Return true if `id` is an open stream in this map.
+    pub(super) fn is_open(&self, id: StreamId) -> bool {
+        self.open_streams.stream(&id).is_some()
+    }
+
     /// Return the entry for `id` in this map, if any.
     pub(super) fn get_mut(&mut self, id: StreamId) -> Option<StreamEntMut<'_>> {
         if let Some(e) = self.open_streams.stream_mut(&id) {
@@ -386,11 +437,12 @@ impl StreamMap {
             let OpenStreamEntStream {
                 inner:
                     OpenStreamEnt {
+                        sink,
                         flow_ctrl,
                         dropped,
                         cmd_checker,
-                        // notably absent: the channels for sink and stream, which will get dropped and
-                        // closed (meaning reads/writes from/to this stream will now fail)
+                        // notably absent: the channel for the stream, which will get dropped and
+                        // closed (meaning writes to this stream will now fail)
                         ..
                     },
             } = ent;
@@ -400,8 +452,12 @@ impl StreamMap {
             let mut recv_window = sendme::StreamRecvWindow::new(RECV_WINDOW_INIT);
             recv_window.decrement_n(dropped)?;
             // TODO: would be nice to avoid new_ref.
-            let half_stream = HalfStream::new(flow_ctrl, recv_window, cmd_checker);
             let explicitly_dropped = why == TR::StreamTargetClosed;
+            // If the stream was closed from our side, its reader may still be around,
+            // and want the data that the other side sent before it saw our END.
+            // (If the reader is gone too, the half-stream will notice and drop the sink.)
+            let sink = explicitly_dropped.then_some(sink);
+            let half_stream = HalfStream::new(flow_ctrl, recv_window, cmd_checker, sink);
             let prev = self.closed_streams.insert(
                 id,
                 ClosedStreamEnt::EndSent(EndSentStreamEnt {
@@ -541,6 +597,8 @@ mod test {
             let (_, rx) = fake_mpsc(2);
             let id = map.add_ent(
                 sink,
+                Default::default(),
+                Default::default(),
                 rx,
                 StreamSendFlowControl::new_window_based(StreamSendWindow::new(500)),
                 DataCmdChecker::new_any(),
@@ -1,6 +1,5 @@
 //! Utilities used for the tor protocol.
 
-pub(crate) mod ct;
 pub(crate) mod err;
 pub(crate) mod keyed_futures_unordered;
 pub(crate) mod oneshot_broadcast;
@@ -119,6 +119,9 @@ pub enum Error {
     /// target relay refused our request.
     #[error("Circuit extension refused: {0}")]
     CircRefused(&'static str),
+    /// A relay refused to start or stop the circuit padding machine with this index.
+    #[error("Relay refused our request for padding machine {0}")]
+    PaddingRefused(u8),
     /// Tried to make or use a stream to an invalid destination address.
     #[error("Invalid stream target address")]
     BadStreamAddress,
@@ -245,7 +248,9 @@ impl From<Error> for std::io::Error {
 
             Bug(ref e) if e.kind() == tor_error::ErrorKind::BadApiUsage => ErrorKind::InvalidData,
 
-            IdRangeFull | CircRefused(_) | ResolveError(_) | Bug(_) => ErrorKind::Other,
+            IdRangeFull | CircRefused(_) | PaddingRefused(_) | ResolveError(_) | Bug(_) => {
+                ErrorKind::Other
+            }
         };
         std::io::Error::new(kind, err)
     }
@@ -280,6 +285,7 @@ impl HasKind for Error {
             E::CircuitClosed => EK::CircuitCollapse,
             E::IdRangeFull => EK::BadApiUsage,
             E::CircRefused(_) => EK::CircuitRefused,
+            E::PaddingRefused(_) => EK::CircuitRefused,
             E::BadStreamAddress => EK::BadApiUsage,
             E::EndReceived(reason) => reason.kind(),
             E::NotConnected => EK::BadApiUsage,
@@ -122,6 +122,16 @@ impl NamedSubver {
         assert!((version as usize) <= MAX_VER);
         Self { kind, version }
     }
+
+    /// Return the protocol of this subversion.
+    pub fn kind(&self) -> ProtoKind {
+        self.kind
+    }
+
+    /// Return the version number of this subversion.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
 }
 
 /// Representation for a known or unknown protocol.
@@ -81,6 +81,12 @@ def_named! {
         ///
         /// [prop346]: https://spec.torproject.org/proposals/346-protovers-again.html
         NEGOTIATE_SUBPROTO = 5;
+
+        /// Support for the Counter Galois Onion (CGO) relay cell encryption,
+        /// negotiated with the ntorv3 protocol request extension.
+        ///
+        /// ([Proposal 359](https://spec.torproject.org/proposals/359-cgo-redux.html))
+        CRYPT_CGO = 6;
     }
 
     HSIntro {
@@ -107,6 +107,10 @@ pub struct PtMgr<R> {
     /// PtReactor channel when the `managed-pts` feature is enabled.
     #[cfg(feature = "managed-pts")]
     tx: UnboundedSender<PtReactorMessage>,
+    /// The limits that channels built through our transports must obey, if
+    /// any.
+    #[cfg(feature = "tor-channel-factory")]
+    bandwidth_limiter: Option<tor_chanmgr::BandwidthLimiter>,
 }
 
 impl<R: Runtime> PtMgr<R> {
@@ -177,9 +181,23 @@ impl<R: Runtime> PtMgr<R> {
             state,
             #[cfg(feature = "managed-pts")]
             tx,
+            #[cfg(feature = "tor-channel-factory")]
+            bandwidth_limiter: None,
         })
     }
 
+    /// Make the channels built through our transports obey `limiter`.
+    ///
+    /// Usually, `limiter` comes from
+    /// [`ChanMgr::bandwidth_limiter`](tor_chanmgr::ChanMgr::bandwidth_limiter),
+    /// so that these channels share the limits of all the others.
+    #[cfg(feature = "tor-channel-factory")]
+    #[must_use]
+    pub fn with_bandwidth_limiter(mut self, limiter: tor_chanmgr::BandwidthLimiter) -> Self {
+        self.bandwidth_limiter = Some(limiter);
+        self
+    }
+
     /// Reload the configuration
     pub fn reconfigure(
         &self,
@@ -346,7 +364,10 @@ impl<R: Runtime> tor_chanmgr::factory::AbstractPtMgr for PtMgr<R> {
         };
 
         let proxy = ExternalProxyPlugin::new(self.runtime.clone(), cmethod.endpoint, cmethod.kind);
-        let factory = ChanBuilder::new(self.runtime.clone(), proxy);
+        let mut factory = ChanBuilder::new(self.runtime.clone(), proxy);
+        if let Some(limiter) = &self.bandwidth_limiter {
+            factory = factory.with_bandwidth_limiter(limiter.clone());
+        }
         // FIXME(eta): Should we cache constructed factories? If no: should this still be an Arc?
         // FIXME(eta): Should we track what transports are live somehow, so we can shut them down?
         Ok(Some(Arc::new(factory)))
@@ -50,6 +50,8 @@ enum RestrictionInner<'a> {
     /// Require that the relay has a given country code.
     #[cfg(feature = "geoip")]
     RequireCountry(tor_geoip::CountryCode),
+    /// Require that the relay has at least one of the provided identities.
+    RequireIds(RelayIdSet),
 }
 
 impl<'a> RelayRestriction<'a> {
@@ -79,6 +81,16 @@ impl<'a> RelayRestriction<'a> {
         }
     }
 
+    /// Require a relay that has at least one identity in `ids`.
+    ///
+    /// This is how we honor a request to use only certain relays for a
+    /// given position in a path.
+    pub fn require_identities(ids: RelayIdSet) -> Self {
+        RelayRestriction {
+            inner: RestrictionInner::RequireIds(ids),
+        }
+    }
+
     /// Require that a relay has at least one address
     /// listed in `addr_patterns`.
     pub fn require_address(addr_patterns: Vec<AddrPortPattern>) -> Self {
@@ -123,6 +135,7 @@ impl<'a> RelayRestriction<'a> {
             HasAddrInSet(_) => Some("not reachable (according to address filter)"),
             #[cfg(feature = "geoip")]
             RequireCountry(_) => Some("not in correct country"),
+            RequireIds(_) => Some("not a requested relay"),
         }
     }
 }
@@ -137,6 +150,7 @@ impl<'a> LowLevelRelayPredicate for RelayRestriction<'a> {
             HasAddrInSet(patterns) => relay_has_addr_in_set(relay, patterns),
             #[cfg(feature = "geoip")]
             RequireCountry(cc) => relay.country_code() == Some(*cc),
+            RequireIds(ids) => relay.identities().any(|id| ids.contains(id)),
         }
     }
 }
@@ -189,6 +203,17 @@ pub struct RelayExclusion<'a> {
     subnet_config: SubnetConfig,
     /// The rules to use when deciding whether two relays are in the same family.
     family_rules: FamilyRules,
+    /// A list of identities that we have been asked never to use.
+    ///
+    /// Any relay with any one of these identities is rejected.
+    ///
+    /// (This is separate from `exclude_ids` only so that we can say why a
+    /// relay was rejected.)
+    unwanted_ids: RelayIdSet,
+    /// A list of address patterns that we have been asked never to use.
+    ///
+    /// Any relay with an address matching any one of these patterns is rejected.
+    unwanted_addrs: Vec<AddrPortPattern>,
 }
 
 /// Helper: wraps `Vec[Relay]`, but implements Debug.
@@ -219,6 +244,8 @@ impl<'a> RelayExclusion<'a> {
             exclude_relay_families: RelayList(Vec::new()),
             subnet_config: SubnetConfig::no_addresses_match(),
             family_rules: FamilyRules::ignore_declared_families(),
+            unwanted_ids: RelayIdSet::new(),
+            unwanted_addrs: Vec::new(),
         }
     }
 
@@ -297,6 +324,21 @@ impl<'a> RelayExclusion<'a> {
         }
     }
 
+    /// Exclude every relay that has an identity in `ids`, or an address
+    /// matching one of `addr_patterns`.
+    ///
+    /// Use this for relays that we have been told never to use, in
+    /// any position: for example, by the user's configuration.
+    /// (Use [`exclude_identities`](RelayExclusion::exclude_identities)
+    /// for relays that we avoid because we have already selected them.)
+    pub fn exclude_unwanted(ids: RelayIdSet, addr_patterns: Vec<AddrPortPattern>) -> Self {
+        RelayExclusion {
+            unwanted_ids: ids,
+            unwanted_addrs: addr_patterns,
+            ..RelayExclusion::no_relays_excluded()
+        }
+    }
+
     /// Modify this `RelayExclusion` by adding every exclusion from `other`.
     ///
     /// (Any subnet configuration becomes the _union_ of previous subnet
@@ -308,6 +350,8 @@ impl<'a> RelayExclusion<'a> {
             exclude_relay_families,
             subnet_config,
             family_rules,
+            unwanted_ids,
+            unwanted_addrs,
         } = other;
         self.exclude_ids
             .extend(exclude_ids.iter().map(|id_ref| id_ref.to_owned()));
@@ -318,17 +362,24 @@ impl<'a> RelayExclusion<'a> {
             .extend_from_slice(&exclude_relay_families.0[..]);
         self.subnet_config = self.subnet_config.union(subnet_config);
         self.family_rules = self.family_rules.union(family_rules);
+        self.unwanted_ids
+            .extend(unwanted_ids.iter().map(|id_ref| id_ref.to_owned()));
+        self.unwanted_addrs.extend_from_slice(&unwanted_addrs[..]);
     }
 
     /// Return a string describing why we rejected the relays that _don't_ match
     /// this exclusion.
     pub(crate) fn rejection_description(&self) -> Option<&'static str> {
+        let unwanted = !(self.unwanted_ids.is_empty() && self.unwanted_addrs.is_empty());
         if self.exclude_relay_families.0.is_empty() && self.exclude_subnets.is_empty() {
-            if self.exclude_ids.is_empty() {
-                None
-            } else {
-                Some("already selected")
+            match (self.exclude_ids.is_empty(), unwanted) {
+                (true, false) => None,
+                (false, false) => Some("already selected"),
+                (true, true) => Some("unwanted"),
+                (false, true) => Some("already selected or unwanted"),
             }
+        } else if unwanted {
+            Some("in same family as already selected, or unwanted")
         } else {
             Some("in same family as already selected")
         }
@@ -341,6 +392,12 @@ impl<'a> LowLevelRelayPredicate for RelayExclusion<'a> {
             return false;
         }
 
+        if relay.identities().any(|id| self.unwanted_ids.contains(id))
+            || relay_has_addr_in_set(relay, &self.unwanted_addrs)
+        {
+            return false;
+        }
+
         if relay.addrs().iter().any(|addr| {
             self.exclude_subnets
                 .iter()
@@ -564,5 +621,52 @@ mod test {
         assert!(no.iter().all(|r| !p(r)));
     }
 
+    #[test]
+    fn exclude_unwanted() {
+        let nd = testnet();
+        let id_0: RelayId = "$0000000000000000000000000000000000000000".parse().unwrap();
+        let ids: RelayIdSet = [id_0].into_iter().collect();
+        let unwanted = RelayExclusion::exclude_unwanted(ids, vec!["1.0.0.0/8:*".parse().unwrap()]);
+        assert_eq!(unwanted.rejection_description(), Some("unwanted"));
+
+        let (yes, no) = split_netdir(&nd, &unwanted);
+        // Relay 0 is at 0.0.0.3; 8 relays are at 1.0.0.3.
+        assert_eq!(yes.len(), 31);
+        assert_eq!(no.len(), 9);
+        let p = |r: &Relay<'_>| {
+            !(r.has_identity(id_0.as_ref())
+                || r.addrs()[0].ip() == "1.0.0.3".parse::<IpAddr>().unwrap())
+        };
+        assert!(yes.iter().all(p));
+        assert!(no.iter().all(|r| !p(r)));
+
+        // Extending an exclusion keeps the unwanted relays.
+        let mut excl = RelayExclusion::exclude_specific_relays(&[nd.by_id(&id_0).unwrap()]);
+        assert_eq!(excl.rejection_description(), Some("already selected"));
+        excl.extend(&unwanted);
+        assert_eq!(
+            excl.rejection_description(),
+            Some("already selected or unwanted")
+        );
+        assert_eq!(split_netdir(&nd, &excl).1.len(), 9);
+    }
+
+    #[test]
+    fn require_ids() {
+        let nd = testnet();
+        let id_0: RelayId = "$0000000000000000000000000000000000000000".parse().unwrap();
+        let id_5: RelayId = "ed25519:BQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQU"
+            .parse()
+            .unwrap();
+        let ids: RelayIdSet = [id_0, id_5].into_iter().collect();
+        let (yes, no) = split_netdir(&nd, &RelayRestriction::require_identities(ids));
+
+        let p = |r: &Relay<'_>| r.has_identity(id_0.as_ref()) || r.has_identity(id_5.as_ref());
+        assert_eq!(yes.len(), 2);
+        assert_eq!(no.len(), 38);
+        assert!(yes.iter().all(p));
+        assert!(no.iter().all(|r| !p(r)));
+    }
+
     // TODO: Write a geoip test?
 }
@@ -301,6 +301,59 @@ impl<'a> RelaySelector<'a> {
         )
     }
 
+    /// Try to pick a random relay from `netdir`,
+    /// according to the rules of this selector,
+    /// after multiplying the weight of each relay by `adjust(relay)`.
+    ///
+    /// A relay whose adjusted weight is zero is never picked:
+    /// so `adjust` can veto a relay by returning zero.
+    /// Negative or non-finite adjustments count as zero.
+    ///
+    /// Unlike the restrictions of this selector,
+    /// the adjustment is never relaxed.
+    pub fn select_relay_reweighted<'s, 'd, R, F>(
+        &'s self,
+        rng: &mut R,
+        netdir: &'d NetDir,
+        adjust: F,
+    ) -> (Option<Relay<'d>>, SelectionInfo<'s>)
+    where
+        R: rand::Rng,
+        F: Fn(&Relay<'d>) -> f64,
+    {
+        use rand::seq::IndexedRandom as _;
+
+        with_possible_relaxation(
+            self,
+            |selector| {
+                let role = selector.weight_role();
+                let total = netdir.total_weight(role, |_| true);
+                let mut fc = FilterCounts::new(selector);
+                let candidates: Vec<(Relay<'d>, f64)> = netdir
+                    .relays()
+                    .filter(|r| selector.relay_usable(r, &mut fc))
+                    .filter_map(|r| {
+                        let adjustment = adjust(&r);
+                        // (Written this way so that NaN counts as zero.)
+                        if !(adjustment.is_finite() && adjustment > 0.0) {
+                            return None;
+                        }
+                        let weight = netdir.relay_weight(&r, role).checked_div(total)?;
+                        Some((r, weight * adjustment))
+                    })
+                    .collect();
+                let relay = match candidates.choose_weighted(rng, |(_, weight)| *weight) {
+                    Ok((relay, _)) => Some(relay.clone()),
+                    // Every candidate had zero weight before we adjusted it:
+                    // as in `NetDir::pick_relay`, choose one at random.
+                    Err(_) => candidates.choose(rng).map(|(relay, _)| relay.clone()),
+                };
+                (relay, fc)
+            },
+            Option::is_some,
+        )
+    }
+
     /// Check whether a given relay `r` obeys the restrictions of this selector,
     /// updating `fc` according to which restrictions (if any) accepted or
     /// rejected it.
@@ -498,6 +551,52 @@ mod test {
         }
     }
 
+    #[test]
+    fn selector_pick_reweighted() {
+        let nd = testnet();
+        let usage = RelayUsage::middle_relay(None);
+        let sel = RelaySelector::new(usage, RelayExclusion::no_relays_excluded());
+
+        let (yes, _no) = split_netdir(&nd, &sel);
+        // Make sure that the relay we favor has some weight to begin with.
+        let favorite = yes
+            .iter()
+            .max_by_key(|r| nd.relay_weight(r, WeightRole::Middle))
+            .unwrap();
+        let vetoed = yes.iter().find(|r| !r.same_relay_ids(favorite)).unwrap();
+        let favorite = favorite.rsa_identity().unwrap().clone();
+        let vetoed = vetoed.rsa_identity().unwrap().clone();
+        let adjust = |r: &Relay<'_>| {
+            let id = r.rsa_identity().unwrap();
+            if *id == favorite {
+                1_000_000.0
+            } else if *id == vetoed {
+                0.0
+            } else {
+                1.0
+            }
+        };
+
+        let mut rng = testing_rng();
+        let mut n_favorite = 0;
+        for _ in 0..100 {
+            let (r, si) = sel.select_relay_reweighted(&mut rng, &nd, adjust);
+            assert!(si.success());
+            let r = r.unwrap();
+            assert!(sel.permits_relay(&r));
+            assert_ne!(r.rsa_identity().unwrap(), &vetoed);
+            if r.rsa_identity().unwrap() == &favorite {
+                n_favorite += 1;
+            }
+        }
+        assert!(n_favorite > 90);
+
+        // Vetoing every relay means that we can't pick one.
+        let (r, si) = sel.select_relay_reweighted(&mut rng, &nd, |_| 0.0);
+        assert!(r.is_none());
+        assert!(!si.success());
+    }
+
     #[test]
     fn selector_report() {
         let nd = testnet();
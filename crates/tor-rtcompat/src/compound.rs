@@ -3,6 +3,7 @@
 
 use std::{net, sync::Arc, time::Duration};
 
+use crate::dialer::{DialerTcpProvider, TcpDialer};
 use crate::traits::*;
 use crate::{CoarseInstant, CoarseTimeProvider};
 use async_trait::async_trait;
@@ -340,6 +341,14 @@ pub trait RuntimeSubstExt: sealed::Sealed + Sized {
         &self,
         new_coarse_time: T,
     ) -> CompoundRuntime<Self, Self, T, Self, Self, Self, Self>;
+    /// Return a new runtime wrapping this runtime, but opening its outbound TCP
+    /// connections with `dialer`.
+    ///
+    /// See [`TcpDialer`](crate::dialer::TcpDialer) for more information.
+    fn with_tcp_dialer<D: TcpDialer>(
+        &self,
+        dialer: D,
+    ) -> CompoundRuntime<Self, Self, Self, DialerTcpProvider<Self, D>, Self, Self, Self>;
 }
 impl<R: Runtime> sealed::Sealed for R {}
 impl<R: Runtime + Sized> RuntimeSubstExt for R {
@@ -387,4 +396,11 @@ impl<R: Runtime + Sized> RuntimeSubstExt for R {
             self.clone(),
         )
     }
+
+    fn with_tcp_dialer<D: TcpDialer>(
+        &self,
+        dialer: D,
+    ) -> CompoundRuntime<Self, Self, Self, DialerTcpProvider<Self, D>, Self, Self, Self> {
+        self.with_tcp_provider(DialerTcpProvider::new(self.clone(), dialer))
+    }
 }
@@ -0,0 +1,202 @@
+//! Making outbound TCP connections with a dialer supplied by the embedder.
+//!
+//! Some applications need to control how Arti's sockets are opened: an
+//! Android app that is also a `VpnService` must `protect()` each socket so
+//! that its traffic doesn't loop back through the VPN, and some
+//! environments must send every connection through a dialer of their own.
+//!
+//! Implementing a whole [`NetStreamProvider`] for that is more work than it
+//! needs to be.  Instead, implement [`TcpDialer`], and wrap your runtime with
+//! [`RuntimeSubstExt::with_tcp_dialer`](crate::RuntimeSubstExt::with_tcp_dialer).
+//! The resulting runtime opens every outbound TCP connection with your
+//! dialer: connections to relays and bridges, and connections to the local
+//! proxies of pluggable transports.  TLS is still negotiated by the runtime,
+//! on top of the streams that your dialer returns, and listening sockets are
+//! still opened by the runtime itself.
+//!
+//! (The connections that a pluggable transport makes for itself are made by
+//! the transport's own process, so they are outside of Arti's control.)
+
+use std::io::Result as IoResult;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use futures::future::Either;
+use futures::stream::{MapOk, TryStreamExt as _};
+use futures::{AsyncRead, AsyncWrite};
+
+use crate::traits::{NetStreamListener, NetStreamProvider, StreamOps};
+
+/// An object that can open outbound TCP connections.
+///
+/// See the [module documentation](self) for how to use one.
+#[async_trait]
+pub trait TcpDialer: Clone + Send + Sync + 'static {
+    /// The type of the connections that this dialer opens.
+    ///
+    /// If you can't support the operations in [`StreamOps`], implement it
+    /// with its default methods.
+    type Stream: AsyncRead + AsyncWrite + StreamOps + Send + Sync + Unpin + 'static;
+
+    /// Open a TCP connection to `addr`.
+    async fn dial(&self, addr: &SocketAddr) -> IoResult<Self::Stream>;
+}
+
+/// A [`NetStreamProvider`] that opens connections with a [`TcpDialer`], and
+/// listens with another `NetStreamProvider`.
+///
+/// Usually you won't need to name this type: use
+/// [`RuntimeSubstExt::with_tcp_dialer`](crate::RuntimeSubstExt::with_tcp_dialer)
+/// instead.
+#[derive(Clone, Debug)]
+pub struct DialerTcpProvider<T, D> {
+    /// The provider that we use for listening.
+    listener: T,
+    /// The dialer that we use for connecting.
+    dialer: D,
+}
+
+impl<T, D> DialerTcpProvider<T, D> {
+    /// Return a new provider that connects with `dialer`, and listens with
+    /// `listener`.
+    pub fn new(listener: T, dialer: D) -> Self {
+        DialerTcpProvider { listener, dialer }
+    }
+}
+
+/// A connection from a [`DialerTcpProvider`].
+///
+/// Outbound connections are `Left`; accepted ones are `Right`.
+pub type DialerTcpStream<D, S> = Either<<D as TcpDialer>::Stream, S>;
+
+/// The function that we use to wrap accepted connections.
+type AcceptFn<D, S> = fn((S, SocketAddr)) -> (DialerTcpStream<D, S>, SocketAddr);
+
+/// A listener from a [`DialerTcpProvider`].
+///
+/// This is the listener of the underlying provider, with its connections
+/// wrapped in [`DialerTcpStream`].
+pub struct DialerTcpListener<L, D> {
+    /// The underlying listener.
+    inner: L,
+    /// The dialer whose stream type we use.
+    _dialer: PhantomData<fn() -> D>,
+}
+
+impl<L, D> NetStreamListener for DialerTcpListener<L, D>
+where
+    L: NetStreamListener,
+    D: TcpDialer,
+{
+    type Stream = DialerTcpStream<D, L::Stream>;
+    type Incoming = MapOk<L::Incoming, AcceptFn<D, L::Stream>>;
+
+    fn incoming(self) -> Self::Incoming {
+        let accepted: AcceptFn<D, L::Stream> = |(stream, addr)| (Either::Right(stream), addr);
+        self.inner.incoming().map_ok(accepted)
+    }
+
+    fn local_addr(&self) -> IoResult<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[async_trait]
+impl<T, D> NetStreamProvider for DialerTcpProvider<T, D>
+where
+    T: NetStreamProvider,
+    D: TcpDialer,
+{
+    type Stream = DialerTcpStream<D, T::Stream>;
+    type Listener = DialerTcpListener<T::Listener, D>;
+
+    async fn connect(&self, addr: &SocketAddr) -> IoResult<Self::Stream> {
+        Ok(Either::Left(self.dialer.dial(addr).await?))
+    }
+
+    async fn listen(&self, addr: &SocketAddr) -> IoResult<Self::Listener> {
+        Ok(DialerTcpListener {
+            inner: self.listener.listen(addr).await?,
+            _dialer: PhantomData,
+        })
+    }
+}
+
+#[cfg(all(
+    test,
+    any(feature = "native-tls", feature = "rustls"),
+    any(feature = "async-std", feature = "tokio"),
+    not(miri), // Many of these tests use real sockets
+))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::{PreferredRuntime, RuntimeSubstExt as _};
+    use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use futures::StreamExt as _;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A dialer that counts the connections that it opens.
+    #[derive(Clone)]
+    struct CountingDialer {
+        runtime: PreferredRuntime,
+        count: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl TcpDialer for CountingDialer {
+        type Stream = <PreferredRuntime as NetStreamProvider>::Stream;
+
+        async fn dial(&self, addr: &SocketAddr) -> IoResult<Self::Stream> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            self.runtime.connect(addr).await
+        }
+    }
+
+    #[test]
+    fn dial_and_listen() {
+        crate::test_with_one_runtime!(|runtime| async move {
+            let count = Arc::new(AtomicUsize::new(0));
+            let rt = runtime.with_tcp_dialer(CountingDialer {
+                runtime: runtime.clone(),
+                count: count.clone(),
+            });
+
+            let localhost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+            let listener = rt.listen(&localhost.into()).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let accept = async {
+                let mut buf = vec![0_u8; 11];
+                let (mut con, _addr) = listener.incoming().next().await.unwrap().unwrap();
+                assert!(matches!(con, Either::Right(_)));
+                con.read_exact(&mut buf[..]).await.unwrap();
+                buf
+            };
+            let connect = async {
+                let mut con = rt.connect(&addr).await.unwrap();
+                assert!(matches!(con, Either::Left(_)));
+                con.write_all(b"Hello world").await.unwrap();
+                con.flush().await.unwrap();
+            };
+            let (data, ()) = futures::join!(accept, connect);
+            assert_eq!(&data[..], b"Hello world");
+            assert_eq!(count.load(Ordering::SeqCst), 1);
+        });
+    }
+}
@@ -55,6 +55,7 @@ pub mod task;
 
 mod coarse_time;
 mod compound;
+pub mod dialer;
 mod dyn_time;
 pub mod general;
 mod opaque;
@@ -63,6 +64,8 @@ mod timer;
 mod traits;
 pub mod unimpl;
 pub mod unix;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use std::io;
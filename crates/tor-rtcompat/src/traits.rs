@@ -433,6 +433,22 @@ impl<T: StreamOps, C> StreamOps for Framed<T, C> {
     }
 }
 
+impl<A: StreamOps, B: StreamOps> StreamOps for futures::future::Either<A, B> {
+    fn set_tcp_notsent_lowat(&self, notsent_lowat: u32) -> IoResult<()> {
+        match self {
+            futures::future::Either::Left(a) => a.set_tcp_notsent_lowat(notsent_lowat),
+            futures::future::Either::Right(b) => b.set_tcp_notsent_lowat(notsent_lowat),
+        }
+    }
+
+    fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
+        match self {
+            futures::future::Either::Left(a) => a.new_handle(),
+            futures::future::Either::Right(b) => b.new_handle(),
+        }
+    }
+}
+
 /// Error: Tried to perform a [`StreamOps`] operation on an unsupported stream type
 /// or on an unsupported platform.
 ///
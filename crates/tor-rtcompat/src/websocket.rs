@@ -0,0 +1,680 @@
+//! Making outbound connections through a WebSocket bridge.
+//!
+//! Browsers can't open TCP connections: the closest they can get is a
+//! WebSocket.  A Tor client running in one has to reach the network through a
+//! *bridge*: a server that accepts WebSocket connections, and relays the bytes
+//! of each one to and from a TCP connection of its own, much as `websockify`
+//! does.
+//!
+//! [`WebSocketDialer`] is a [`TcpDialer`] that makes every outbound
+//! connection through such a bridge.  Wrap your runtime with it, using
+//! [`RuntimeSubstExt::with_tcp_dialer`](crate::RuntimeSubstExt::with_tcp_dialer),
+//! and Arti's connections to relays go through the bridge, with TLS
+//! negotiated inside the tunnel as usual.
+//!
+//! We speak plain `ws://` to the bridge: the Tor protocol's own TLS is what
+//! keeps the connection confidential.  We tell the bridge where to connect
+//! with a `target` query parameter on the request, as in
+//! `GET /tor?target=192.0.2.7:443`.
+//!
+//! (This uses the runtime's own TCP connections to reach the bridge, so it
+//! works on any platform; a browser runtime would use the browser's
+//! `WebSocket` instead.)
+
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_trait::async_trait;
+use base64ct::{Base64, Encoding as _};
+use futures::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use sha1::{Digest as _, Sha1};
+
+use crate::dialer::TcpDialer;
+use crate::traits::{NetStreamProvider, StreamOps};
+
+/// The GUID that RFC 6455 uses to derive `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The longest response header that we accept from a bridge.
+const MAX_RESPONSE_LEN: usize = 8192;
+
+/// The most payload that we put in a single frame.
+const MAX_FRAME_PAYLOAD: usize = 16384;
+
+/// The most payload that a control frame can have.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Frame opcodes, from RFC 6455 section 5.2.
+mod opcode {
+    /// A continuation of a fragmented message.
+    pub(super) const CONTINUATION: u8 = 0x0;
+    /// A text message.
+    pub(super) const TEXT: u8 = 0x1;
+    /// A binary message.
+    pub(super) const BINARY: u8 = 0x2;
+    /// A request to close the connection.
+    pub(super) const CLOSE: u8 = 0x8;
+    /// A ping.
+    pub(super) const PING: u8 = 0x9;
+    /// A reply to a ping.
+    pub(super) const PONG: u8 = 0xA;
+}
+
+/// A [`TcpDialer`] that connects through a WebSocket bridge.
+///
+/// See the [module documentation](self) for more information.
+#[derive(Clone, Debug)]
+pub struct WebSocketDialer<P> {
+    /// The provider that we use to connect to the bridge.
+    provider: P,
+    /// The address of the bridge.
+    bridge: SocketAddr,
+    /// The path to request from the bridge, before the `target` parameter.
+    path: String,
+}
+
+impl<P> WebSocketDialer<P> {
+    /// Return a new dialer that connects to the bridge at `bridge` using
+    /// `provider`, and asks it for `path`.
+    ///
+    /// `path` must start with `/`; it may already have a query string.
+    pub fn new(provider: P, bridge: SocketAddr, path: impl Into<String>) -> Self {
+        WebSocketDialer {
+            provider,
+            bridge,
+            path: path.into(),
+        }
+    }
+
+    /// Return the request target to send to the bridge, to reach `addr`.
+    fn request_target(&self, addr: &SocketAddr) -> String {
+        let sep = if self.path.contains('?') { '&' } else { '?' };
+        // Brackets aren't allowed in a query string: escape those of an
+        // IPv6 address.
+        let addr = addr.to_string().replace('[', "%5B").replace(']', "%5D");
+        format!("{}{}target={}", self.path, sep, addr)
+    }
+}
+
+#[async_trait]
+impl<P: NetStreamProvider> TcpDialer for WebSocketDialer<P> {
+    type Stream = WebSocketStream<P::Stream>;
+
+    async fn dial(&self, addr: &SocketAddr) -> IoResult<Self::Stream> {
+        let mut stream = self.provider.connect(&self.bridge).await?;
+
+        let key = Base64::encode_string(&rand::random::<[u8; 16]>());
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             \r\n",
+            self.request_target(addr),
+            self.bridge,
+            key
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        // Read the response header.  Anything after it is the start of the
+        // first frame.
+        let mut buf = Vec::new();
+        let header_len = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if buf.len() >= MAX_RESPONSE_LEN {
+                return Err(handshake_error("response header too long"));
+            }
+            let mut chunk = [0_u8; 1024];
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(handshake_error("connection closed during handshake"));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+        check_response(&buf[..header_len], &key)?;
+        buf.drain(..header_len);
+
+        Ok(WebSocketStream {
+            inner: stream,
+            read_buf: buf,
+            payload_left: 0,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            close_received: false,
+            close_sent: false,
+        })
+    }
+}
+
+/// Return an error for a failed opening handshake.
+fn handshake_error(msg: &str) -> IoError {
+    IoError::new(
+        ErrorKind::ConnectionRefused,
+        format!("WebSocket handshake failed: {}", msg),
+    )
+}
+
+/// Return an error for a frame that breaks the protocol.
+fn protocol_error(msg: &str) -> IoError {
+    IoError::new(
+        ErrorKind::InvalidData,
+        format!("WebSocket protocol error: {}", msg),
+    )
+}
+
+/// Return the `Sec-WebSocket-Accept` value that a server must send in
+/// response to `key`.
+fn accept_key(key: &str) -> String {
+    let mut d = Sha1::new();
+    d.update(key.as_bytes());
+    d.update(ACCEPT_GUID.as_bytes());
+    Base64::encode_string(&d.finalize())
+}
+
+/// Check that `header` is a response that accepts our request to upgrade to
+/// a WebSocket, sent with `key`.
+fn check_response(header: &[u8], key: &str) -> IoResult<()> {
+    let header = std::str::from_utf8(header).map_err(|_| handshake_error("response not UTF-8"))?;
+    let mut lines = header.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    let mut status = status.split(' ');
+    if !status.next().is_some_and(|v| v.starts_with("HTTP/1.")) || status.next() != Some("101") {
+        return Err(handshake_error(&format!(
+            "unexpected status {:?}",
+            header.lines().next().unwrap_or_default()
+        )));
+    }
+
+    let mut upgrade = false;
+    let mut accepted = false;
+    let expected = accept_key(key);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("sec-websocket-accept") {
+            accepted = value == expected;
+        }
+    }
+    if !upgrade {
+        return Err(handshake_error("missing Upgrade: websocket"));
+    }
+    if !accepted {
+        return Err(handshake_error("bad or missing Sec-WebSocket-Accept"));
+    }
+    Ok(())
+}
+
+/// Append a frame with `opcode` and `payload` to `out`, masked, as every
+/// frame from a client must be.
+fn encode_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    // FIN, with no extensions.
+    out.push(0x80 | opcode);
+    let len = payload.len();
+    if len < 126 {
+        out.push(0x80 | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(0x80 | 126);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0x80 | 127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    let mask: [u8; 4] = rand::random();
+    out.extend_from_slice(&mask);
+    out.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+}
+
+/// The header of a frame that we've received.
+struct FrameHeader {
+    /// The frame's opcode.
+    opcode: u8,
+    /// True if this is the last frame of its message.
+    fin: bool,
+    /// The length of the header itself.
+    header_len: usize,
+    /// The length of the payload that follows it.
+    payload_len: u64,
+}
+
+/// Try to parse a frame header from the start of `buf`.
+///
+/// Return `Ok(None)` if `buf` doesn't hold a whole header yet.
+fn parse_frame_header(buf: &[u8]) -> IoResult<Option<FrameHeader>> {
+    let (b0, b1) = match buf {
+        [b0, b1, ..] => (*b0, *b1),
+        _ => return Ok(None),
+    };
+    if b0 & 0x70 != 0 {
+        return Err(protocol_error("unexpected extension bits"));
+    }
+    if b1 & 0x80 != 0 {
+        return Err(protocol_error("masked frame from server"));
+    }
+    let (header_len, payload_len) = match b1 & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(len) => (4, u64::from(u16::from_be_bytes([len[0], len[1]]))),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => {
+                let mut bytes = [0_u8; 8];
+                bytes.copy_from_slice(len);
+                (10, u64::from_be_bytes(bytes))
+            }
+            None => return Ok(None),
+        },
+        len => (2, u64::from(len)),
+    };
+    Ok(Some(FrameHeader {
+        opcode: b0 & 0x0f,
+        fin: b0 & 0x80 != 0,
+        header_len,
+        payload_len,
+    }))
+}
+
+/// A connection through a WebSocket bridge, from a [`WebSocketDialer`].
+///
+/// Everything written to it is sent in binary frames; the payload of every
+/// binary frame received from the bridge can be read from it.
+#[derive(Debug)]
+pub struct WebSocketStream<S> {
+    /// The connection to the bridge.
+    inner: S,
+    /// Bytes that we've read from `inner`, and haven't handled yet.
+    read_buf: Vec<u8>,
+    /// The number of payload bytes left in the data frame that we're reading.
+    payload_left: u64,
+    /// Frames that we haven't finished writing to `inner`.
+    write_buf: Vec<u8>,
+    /// The number of bytes of `write_buf` that we've written.
+    write_pos: usize,
+    /// True if the bridge has sent a close frame.
+    close_received: bool,
+    /// True if we have queued a close frame.
+    close_sent: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    /// Try to write everything in `write_buf` to `inner`.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queue a close frame, unless we already have.
+    fn queue_close(&mut self) {
+        if !self.close_sent {
+            encode_frame(&mut self.write_buf, opcode::CLOSE, &[]);
+            self.close_sent = true;
+        }
+    }
+
+    /// Handle the frame at the start of `read_buf`, whose header is `header`.
+    ///
+    /// Return `Ok(false)` if we need more data to handle it.
+    fn handle_frame(&mut self, header: &FrameHeader) -> IoResult<bool> {
+        match header.opcode {
+            opcode::BINARY | opcode::CONTINUATION => {
+                // We don't care where messages begin and end: all the data
+                // is part of one stream.
+                self.read_buf.drain(..header.header_len);
+                self.payload_left = header.payload_len;
+                Ok(true)
+            }
+            opcode::CLOSE | opcode::PING | opcode::PONG => {
+                let len = usize::try_from(header.payload_len)
+                    .ok()
+                    .filter(|len| *len <= MAX_CONTROL_PAYLOAD && header.fin)
+                    .ok_or_else(|| protocol_error("oversized or fragmented control frame"))?;
+                if self.read_buf.len() < header.header_len + len {
+                    return Ok(false);
+                }
+                let payload: Vec<u8> = self
+                    .read_buf
+                    .drain(..header.header_len + len)
+                    .skip(header.header_len)
+                    .collect();
+                match header.opcode {
+                    opcode::PING if !self.close_sent => {
+                        encode_frame(&mut self.write_buf, opcode::PONG, &payload);
+                    }
+                    opcode::CLOSE => {
+                        self.close_received = true;
+                        self.queue_close();
+                    }
+                    _ => {}
+                }
+                Ok(true)
+            }
+            opcode::TEXT => Err(protocol_error("unexpected text frame")),
+            _ => Err(protocol_error("unrecognized opcode")),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            // Send any replies to control frames that are still pending.
+            // (If we can't send them yet, we'll try again when we're next
+            // polled.)
+            if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            if this.payload_left > 0 && !this.read_buf.is_empty() {
+                let left = usize::try_from(this.payload_left).unwrap_or(usize::MAX);
+                let n = buf.len().min(left).min(this.read_buf.len());
+                buf[..n].copy_from_slice(&this.read_buf[..n]);
+                this.read_buf.drain(..n);
+                this.payload_left -= n as u64;
+                return Poll::Ready(Ok(n));
+            }
+            if this.payload_left == 0 {
+                if this.close_received {
+                    return Poll::Ready(Ok(0));
+                }
+                if let Some(header) = parse_frame_header(&this.read_buf)? {
+                    if this.handle_frame(&header)? {
+                        continue;
+                    }
+                }
+            }
+
+            let mut chunk = [0_u8; 4096];
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                return if this.payload_left == 0 && this.read_buf.is_empty() {
+                    Poll::Ready(Ok(0))
+                } else {
+                    Poll::Ready(Err(ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        // Keep at most one frame of ours buffered.
+        ready!(this.poll_write_buf(cx))?;
+        if this.close_sent {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_FRAME_PAYLOAD);
+        encode_frame(&mut this.write_buf, opcode::BINARY, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        this.queue_close();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl<S> StreamOps for WebSocketStream<S> {}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn accept() {
+        // From RFC 6455 section 1.3.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn target() {
+        let dialer = WebSocketDialer::new((), "192.0.2.1:80".parse().unwrap(), "/tor");
+        assert_eq!(
+            dialer.request_target(&"[2001:db8::1]:443".parse().unwrap()),
+            "/tor?target=%5B2001:db8::1%5D:443"
+        );
+        let dialer = WebSocketDialer::new((), "192.0.2.1:80".parse().unwrap(), "/tor?k=v");
+        assert_eq!(
+            dialer.request_target(&"192.0.2.7:9001".parse().unwrap()),
+            "/tor?k=v&target=192.0.2.7:9001"
+        );
+    }
+
+    #[test]
+    fn frames() {
+        let mut out = Vec::new();
+        encode_frame(&mut out, opcode::BINARY, b"hello");
+        assert_eq!(out[0], 0x82);
+        assert_eq!(out[1], 0x80 | 5);
+        let mask = &out[2..6];
+        let payload: Vec<u8> = out[6..]
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect();
+        assert_eq!(payload, b"hello");
+
+        let mut out = Vec::new();
+        encode_frame(&mut out, opcode::BINARY, &[0; 300]);
+        assert_eq!(&out[1..4], &[0x80 | 126, 0x01, 0x2c]);
+        assert_eq!(out.len(), 4 + 4 + 300);
+
+        // Headers from the server are unmasked.
+        let h = parse_frame_header(&[0x02, 126, 0x01]).unwrap();
+        assert!(h.is_none());
+        let h = parse_frame_header(&[0x02, 126, 0x01, 0x2c])
+            .unwrap()
+            .unwrap();
+        assert_eq!(h.opcode, opcode::BINARY);
+        assert!(!h.fin);
+        assert_eq!(h.header_len, 4);
+        assert_eq!(h.payload_len, 300);
+        assert!(parse_frame_header(&[0x82, 0x85]).is_err());
+        assert!(parse_frame_header(&[0xc2, 0x05]).is_err());
+    }
+
+    #[cfg(all(
+        any(feature = "native-tls", feature = "rustls"),
+        any(feature = "async-std", feature = "tokio"),
+        not(miri), // This test uses real sockets
+    ))]
+    mod bridge {
+        use super::*;
+        use crate::{NetStreamListener as _, RuntimeSubstExt as _};
+        use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        use futures::StreamExt as _;
+        use std::net::{Ipv4Addr, SocketAddrV4};
+
+        /// Read a frame from a client, and return its opcode, FIN bit, and
+        /// unmasked payload.
+        async fn read_client_frame<S: AsyncRead + Unpin>(s: &mut S) -> (u8, bool, Vec<u8>) {
+            let mut hdr = [0_u8; 2];
+            s.read_exact(&mut hdr).await.unwrap();
+            assert_eq!(hdr[1] & 0x80, 0x80, "client frames must be masked");
+            let len = match hdr[1] & 0x7f {
+                126 => {
+                    let mut len = [0_u8; 2];
+                    s.read_exact(&mut len).await.unwrap();
+                    usize::from(u16::from_be_bytes(len))
+                }
+                127 => panic!("unexpectedly long frame"),
+                len => usize::from(len),
+            };
+            let mut mask = [0_u8; 4];
+            s.read_exact(&mut mask).await.unwrap();
+            let mut payload = vec![0_u8; len];
+            s.read_exact(&mut payload).await.unwrap();
+            for (b, m) in payload.iter_mut().zip(mask.iter().cycle()) {
+                *b ^= m;
+            }
+            (hdr[0] & 0x0f, hdr[0] & 0x80 != 0, payload)
+        }
+
+        /// Return an unmasked frame, as a server would send it.
+        fn server_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+            assert!(payload.len() < 126);
+            let mut frame = vec![first_byte, payload.len() as u8];
+            frame.extend_from_slice(payload);
+            frame
+        }
+
+        #[test]
+        fn through_bridge() {
+            crate::test_with_one_runtime!(|runtime| async move {
+                let localhost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+                let listener = runtime.listen(&localhost.into()).await.unwrap();
+                let bridge_addr = listener.local_addr().unwrap();
+                let rt = runtime.with_tcp_dialer(WebSocketDialer::new(
+                    runtime.clone(),
+                    bridge_addr,
+                    "/tor",
+                ));
+                let target: SocketAddr = "192.0.2.7:9001".parse().unwrap();
+
+                let bridge = async {
+                    let (mut con, _addr) = listener.incoming().next().await.unwrap().unwrap();
+
+                    // Read the request, and accept it.
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0_u8; 1];
+                        con.read_exact(&mut byte).await.unwrap();
+                        request.push(byte[0]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    assert!(request.starts_with("GET /tor?target=192.0.2.7:9001 HTTP/1.1\r\n"));
+                    let key = request
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "))
+                        .unwrap();
+                    let mut reply = format!(
+                        "HTTP/1.1 101 Switching Protocols\r\n\
+                         Upgrade: websocket\r\n\
+                         Connection: Upgrade\r\n\
+                         Sec-WebSocket-Accept: {}\r\n\r\n",
+                        accept_key(key)
+                    )
+                    .into_bytes();
+                    // Ping the client in the same packet as the reply.
+                    reply.extend(server_frame(0x80 | opcode::PING, b"hi"));
+                    con.write_all(&reply).await.unwrap();
+
+                    // We should get the client's data, and a reply to our ping.
+                    let mut data = Vec::new();
+                    let mut ponged = false;
+                    while data.len() < 11 || !ponged {
+                        match read_client_frame(&mut con).await {
+                            (opcode::BINARY, true, payload) => data.extend(payload),
+                            (opcode::PONG, true, payload) => {
+                                assert_eq!(payload, b"hi");
+                                ponged = true;
+                            }
+                            other => panic!("{:?}", other),
+                        }
+                    }
+                    assert_eq!(data, b"Hello world");
+
+                    // Reply in a fragmented message, then close.
+                    let mut reply = server_frame(opcode::BINARY, b"HELLO ");
+                    reply.extend(server_frame(0x80 | opcode::CONTINUATION, b"WORLD"));
+                    reply.extend(server_frame(0x80 | opcode::CLOSE, &[]));
+                    con.write_all(&reply).await.unwrap();
+
+                    // The client should answer our close with its own.
+                    let (op, _, _) = read_client_frame(&mut con).await;
+                    assert_eq!(op, opcode::CLOSE);
+                };
+                let client = async {
+                    let mut con = rt.connect(&target).await.unwrap();
+                    con.write_all(b"Hello world").await.unwrap();
+                    con.flush().await.unwrap();
+                    let mut data = Vec::new();
+                    con.read_to_end(&mut data).await.unwrap();
+                    assert_eq!(data, b"HELLO WORLD");
+                    con.close().await.unwrap();
+                };
+                futures::join!(bridge, client);
+            });
+        }
+
+        #[test]
+        fn refused() {
+            crate::test_with_one_runtime!(|runtime| async move {
+                let localhost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+                let listener = runtime.listen(&localhost.into()).await.unwrap();
+                let bridge_addr = listener.local_addr().unwrap();
+                let dialer = WebSocketDialer::new(runtime.clone(), bridge_addr, "/tor");
+
+                let bridge = async {
+                    let (mut con, _addr) = listener.incoming().next().await.unwrap().unwrap();
+                    con.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                        .await
+                        .unwrap();
+                    con.flush().await.unwrap();
+                    con
+                };
+                let client = dialer.dial(&"192.0.2.7:9001".parse().unwrap());
+                let (_con, res) = futures::join!(bridge, client);
+                let err = res.unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+            });
+        }
+    }
+}
@@ -0,0 +1,76 @@
+#![no_main]
+// The Action-based API is the easiest one to drive both sides with here.
+#![allow(deprecated)]
+use libfuzzer_sys::fuzz_target;
+
+use tor_socksproto::{
+    Handshake, SocksClientHandshake, SocksProxyHandshake, SocksRequest, SocksStatus, SocksVersion,
+};
+
+/// Upper bound on the number of messages we'll pass back and forth.
+const MAX_ROUNDS: usize = 16;
+
+/// Run one side of a handshake on `input`, appending its reply to `output`.
+///
+/// Return true if that side is done, successfully or not.
+fn step<H: Handshake>(hs: &mut H, input: &mut Vec<u8>, output: &mut Vec<u8>) -> bool {
+    match hs.handshake(input) {
+        Err(_truncated) => false,
+        Ok(Err(_)) => true,
+        Ok(Ok(action)) => {
+            input.drain(..action.drain);
+            output.extend_from_slice(&action.reply);
+            action.finished
+        }
+    }
+}
+
+fuzz_target!(|data: (SocksRequest, u8)| {
+    let (request, status) = data;
+    let status = SocksStatus::from(status);
+
+    let mut client = SocksClientHandshake::new(request.clone());
+    let mut proxy = SocksProxyHandshake::new();
+    let mut to_client = Vec::new();
+    let mut to_proxy = Vec::new();
+
+    // Let the client and the proxy talk until the proxy has a request.
+    let mut client_done = false;
+    for _ in 0..MAX_ROUNDS {
+        if !client_done {
+            client_done = step(&mut client, &mut to_client, &mut to_proxy);
+        }
+        if step(&mut proxy, &mut to_proxy, &mut to_client) {
+            break;
+        }
+    }
+    let Some(got) = proxy.into_request() else {
+        return;
+    };
+
+    // Whatever the proxy accepted must be what the client asked for.
+    assert_eq!(got.version(), request.version());
+    assert_eq!(got.command(), request.command());
+    assert_eq!(got.port(), request.port());
+    if request.version() == SocksVersion::V5 {
+        // SOCKS4a sends hostnames as text, so only compare exactly for SOCKS5.
+        assert_eq!(got.addr(), request.addr());
+    }
+
+    // Now send a reply back, and make sure the client understands it.
+    let Ok(reply) = got.reply(status, None) else {
+        return;
+    };
+    to_client.extend_from_slice(&reply);
+    let mut nobody = Vec::new();
+    for _ in 0..MAX_ROUNDS {
+        if client_done || step(&mut client, &mut to_client, &mut nobody) {
+            break;
+        }
+    }
+    if let Some(reply) = client.into_reply() {
+        if request.version() == SocksVersion::V5 {
+            assert_eq!(reply.status(), status);
+        }
+    }
+});
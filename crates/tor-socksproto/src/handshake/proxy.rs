@@ -4,7 +4,7 @@ use super::framework::{HandshakeImpl, ImplNextStep};
 use crate::msg::{SocksAddr, SocksAuth, SocksCmd, SocksRequest, SocksStatus, SocksVersion};
 use crate::{Error, Result};
 
-use tor_bytes::{EncodeResult, Error as BytesError};
+use tor_bytes::{EncodeError, EncodeResult, Error as BytesError};
 use tor_bytes::{Reader, Writer};
 use tor_error::internal;
 
@@ -227,6 +227,31 @@ impl SocksRequest {
         }
     }
 
+    /// Format a successful reply to a SOCKS5 `UDP_ASSOCIATE` request.
+    ///
+    /// `relay_addr` and `relay_port` tell the client where to send its
+    /// datagrams: they should be the local address of the UDP socket that
+    /// will relay them.
+    pub fn udp_associate_reply(
+        &self,
+        relay_addr: &SocksAddr,
+        relay_port: u16,
+    ) -> EncodeResult<Vec<u8>> {
+        if self.version() != SocksVersion::V5 || self.command() != SocksCmd::UDP_ASSOCIATE {
+            return Err(EncodeError::from(tor_error::bad_api_usage!(
+                "udp_associate_reply() called on a {} request",
+                self.command()
+            )));
+        }
+        let mut w = Vec::new();
+        w.write_u8(5);
+        w.write_u8(SocksStatus::SUCCEEDED.into());
+        w.write_u8(0); // reserved.
+        w.write(relay_addr)?;
+        w.write_u16(relay_port);
+        Ok(w)
+    }
+
     /// Format a SOCKS4 reply.
     fn s4(&self, status: SocksStatus, addr: Option<&SocksAddr>) -> EncodeResult<Vec<u8>> {
         let mut w = Vec::new();
@@ -472,6 +497,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn socks5_udp_associate() {
+        let mut h = SocksProxyHandshake::new();
+        let _a = h.handshake_for_tests(&hex!("05 01 00")).unwrap().unwrap();
+        let a = h
+            .handshake_for_tests(&hex!("05 03 00 01 00000000 0000"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(a.drain, 10);
+        assert!(a.finished);
+
+        let req = h.into_request().unwrap();
+        assert_eq!(req.command(), SocksCmd::UDP_ASSOCIATE);
+        assert_eq!(req.port(), 0);
+
+        let relay = SocksAddr::Ip("127.0.0.1".parse().unwrap());
+        assert_eq!(
+            req.udp_associate_reply(&relay, 9150).unwrap(),
+            hex!("05 00 00 01 7f000001 23be")
+        );
+    }
+
     #[test]
     fn empty_handshake() {
         let r = SocksProxyHandshake::new().handshake_for_tests(&[]);
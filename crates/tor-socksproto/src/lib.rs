@@ -46,6 +46,7 @@
 mod err;
 mod handshake;
 mod msg;
+mod udp;
 
 pub use err::Error;
 pub use handshake::Action;
@@ -77,6 +78,7 @@ pub use msg::{
     SocksVersion,
 };
 pub use tor_error::Truncated;
+pub use udp::SocksUdpDatagram;
 
 /// A Result type for the tor_socksproto crate.
 pub type Result<T> = std::result::Result<T, Error>;
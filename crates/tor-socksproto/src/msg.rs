@@ -138,7 +138,8 @@ caret_int! {
         CONNECT = 1,
         /// Not supported in Tor.
         BIND = 2,
-        /// Not supported in Tor.
+        /// Relay UDP datagrams, as described in RFC 1928 section 7.
+        /// (SOCKS5 only.)
         UDP_ASSOCIATE = 3,
 
         /// Lookup a hostname, return an IP address. (Tor only.)
@@ -207,16 +208,16 @@ impl SocksCmd {
     fn recognized(self) -> bool {
         matches!(
             self,
-            SocksCmd::CONNECT | SocksCmd::RESOLVE | SocksCmd::RESOLVE_PTR
+            SocksCmd::CONNECT | SocksCmd::UDP_ASSOCIATE | SocksCmd::RESOLVE | SocksCmd::RESOLVE_PTR
         )
     }
 
     /// Return true if this is a command for which we require a port.
+    ///
+    /// (A `UDP_ASSOCIATE` request may have a zero port, since the client
+    /// might not know which port it will send its datagrams from.)
     fn requires_port(self) -> bool {
-        matches!(
-            self,
-            SocksCmd::CONNECT | SocksCmd::BIND | SocksCmd::UDP_ASSOCIATE
-        )
+        matches!(self, SocksCmd::CONNECT | SocksCmd::BIND)
     }
 }
 
@@ -317,6 +318,9 @@ impl SocksRequest {
                 format!("SOCKS command {}", cmd).into(),
             ));
         }
+        if cmd == SocksCmd::UDP_ASSOCIATE && version == SocksVersion::V4 {
+            return Err(Error::NotImplemented("UDP ASSOCIATE over SOCKS4".into()));
+        }
         if port == 0 && cmd.requires_port() {
             return Err(Error::Syntax);
         }
@@ -478,6 +482,27 @@ mod test {
             SocksAuth::NoAuth,
         );
         assert!(matches!(e, Err(Error::Syntax)));
+
+        let e = SocksRequest::new(
+            SocksVersion::V4,
+            SocksCmd::UDP_ASSOCIATE,
+            SocksAddr::Ip(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)),
+            0,
+            SocksAuth::NoAuth,
+        );
+        assert!(matches!(e, Err(Error::NotImplemented(_))));
+
+        // A UDP ASSOCIATE request doesn't need to say where the client's
+        // datagrams will come from.
+        let r = SocksRequest::new(
+            SocksVersion::V5,
+            SocksCmd::UDP_ASSOCIATE,
+            SocksAddr::Ip(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)),
+            0,
+            SocksAuth::NoAuth,
+        )
+        .unwrap();
+        assert_eq!(r.command(), SocksCmd::UDP_ASSOCIATE);
     }
 
     #[test]
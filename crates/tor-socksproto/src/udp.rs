@@ -0,0 +1,129 @@
+//! Encode and decode the datagrams that SOCKS5 relays for `UDP_ASSOCIATE`.
+
+use crate::msg::SocksAddr;
+use crate::{Error, Result};
+
+use tor_bytes::{EncodeResult, Reader, Writer};
+
+/// A UDP datagram, with the header that SOCKS5 adds to it.
+///
+/// After a successful `UDP_ASSOCIATE` handshake, the SOCKS client sends each
+/// of its datagrams to the proxy's relay port, prefixed with the address and
+/// port that it is meant for; the proxy prefixes each datagram that it relays
+/// back with the address and port that it came from.
+/// (See RFC 1928, section 7.)
+///
+/// We don't support fragmented datagrams.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocksUdpDatagram {
+    /// The address that this datagram is going to (or coming from).
+    addr: SocksAddr,
+    /// The port that this datagram is going to (or coming from).
+    port: u16,
+    /// The datagram itself.
+    payload: Vec<u8>,
+}
+
+impl SocksUdpDatagram {
+    /// Create a new datagram, going to (or coming from) `addr`:`port`.
+    pub fn new(addr: SocksAddr, port: u16, payload: Vec<u8>) -> Self {
+        SocksUdpDatagram {
+            addr,
+            port,
+            payload,
+        }
+    }
+
+    /// Decode a datagram that a SOCKS client sent to our relay port.
+    ///
+    /// Returns [`Error::NotImplemented`] if the datagram is a fragment.
+    pub fn parse(datagram: &[u8]) -> Result<Self> {
+        let mut r = Reader::from_slice(datagram);
+        let _reserved = r.take_u16()?;
+        let frag = r.take_u8()?;
+        if frag != 0 {
+            return Err(Error::NotImplemented("fragmented UDP datagrams".into()));
+        }
+        let addr = r.extract()?;
+        let port = r.take_u16()?;
+        let payload = r.take_rest().to_vec();
+        Ok(SocksUdpDatagram {
+            addr,
+            port,
+            payload,
+        })
+    }
+
+    /// Encode this datagram, with its SOCKS5 header, to send to a SOCKS client.
+    pub fn encode(&self) -> EncodeResult<Vec<u8>> {
+        let mut w = Vec::with_capacity(self.payload.len() + 22);
+        w.write_u16(0); // reserved.
+        w.write_u8(0); // fragment number.
+        w.write(&self.addr)?;
+        w.write_u16(self.port);
+        w.write_all(&self.payload);
+        Ok(w)
+    }
+
+    /// Return the address that this datagram is going to (or coming from).
+    pub fn addr(&self) -> &SocksAddr {
+        &self.addr
+    }
+
+    /// Return the port that this datagram is going to (or coming from).
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Return the contents of this datagram.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Consume this datagram, and return its contents.
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn roundtrip() {
+        let raw = hex!("0000 00 03 0b 6578616d706c652e636f6d 0035 68656c6c6f");
+        let d = SocksUdpDatagram::parse(&raw[..]).unwrap();
+        assert_eq!(d.addr().to_string(), "example.com");
+        assert_eq!(d.port(), 53);
+        assert_eq!(d.payload(), b"hello");
+        assert_eq!(d.encode().unwrap(), raw);
+
+        let d = SocksUdpDatagram::new(SocksAddr::Ip("10.0.0.1".parse().unwrap()), 443, vec![]);
+        assert_eq!(d.encode().unwrap(), hex!("0000 00 01 0a000001 01bb"));
+    }
+
+    #[test]
+    fn bad_datagrams() {
+        // Fragmented.
+        let e = SocksUdpDatagram::parse(&hex!("0000 01 01 0a000001 01bb 00"));
+        assert!(matches!(e, Err(Error::NotImplemented(_))));
+        // Truncated.
+        let e = SocksUdpDatagram::parse(&hex!("0000 00 01 0a00"));
+        assert!(matches!(e, Err(Error::Decode(_))));
+    }
+}
@@ -0,0 +1,129 @@
+"""
+An embedded Tor client, using the Arti Tor implementation.
+
+All the I/O methods of these classes return awaitables,
+and must be used from an `asyncio` event loop.
+"""
+
+from __future__ import annotations
+
+import asyncio
+from typing import Any, Optional
+
+from arti._arti import (
+    BootstrapEvents,
+    BootstrapStatus,
+    DataStream,
+    OnionService,
+    TorClient,
+    TorError,
+)
+
+__all__ = [
+    "BootstrapEvents",
+    "BootstrapStatus",
+    "DataStream",
+    "OnionService",
+    "StreamWriter",
+    "TorClient",
+    "TorError",
+    "open_connection",
+    "wrap_stream",
+]
+
+
+class StreamWriter:
+    """
+    The writing half of a `DataStream`,
+    with the same methods as an `asyncio.StreamWriter`.
+
+    Data passed to `write()` is buffered until `drain()` is awaited.
+    """
+
+    def __init__(self, stream: DataStream, pump: asyncio.Task[None]):
+        self._stream = stream
+        self._pump = pump
+        self._buf = bytearray()
+        self._closing = False
+        self._closed: Optional[asyncio.Task[None]] = None
+
+    def write(self, data: bytes) -> None:
+        """Queue `data` to be sent on the stream."""
+        if self._closing:
+            raise RuntimeError("StreamWriter is closed")
+        self._buf += data
+
+    def writelines(self, data: Any) -> None:
+        """Queue every element of `data` to be sent on the stream."""
+        for chunk in data:
+            self.write(chunk)
+
+    async def drain(self) -> None:
+        """Send all of the data that has been queued."""
+        if self._buf:
+            data, self._buf = bytes(self._buf), bytearray()
+            await self._stream.write(data)
+        await self._stream.flush()
+
+    def can_write_eof(self) -> bool:
+        return False
+
+    def close(self) -> None:
+        """Send any queued data, and close the stream."""
+        if self._closing:
+            return
+        self._closing = True
+        self._closed = asyncio.ensure_future(self._close())
+
+    async def _close(self) -> None:
+        try:
+            await self.drain()
+        finally:
+            await self._stream.close()
+
+    def is_closing(self) -> bool:
+        return self._closing
+
+    async def wait_closed(self) -> None:
+        """Wait until the stream has been closed by `close()`."""
+        if self._closed is not None:
+            await self._closed
+
+    def get_extra_info(self, name: str, default: Any = None) -> Any:
+        """Return `default`: Tor streams have no transport information."""
+        return default
+
+
+async def _pump(stream: DataStream, reader: asyncio.StreamReader) -> None:
+    """Copy everything that we read from `stream` into `reader`."""
+    try:
+        while data := await stream.read():
+            reader.feed_data(data)
+        reader.feed_eof()
+    except TorError as e:
+        reader.set_exception(e)
+
+
+def wrap_stream(
+    stream: DataStream,
+) -> tuple[asyncio.StreamReader, StreamWriter]:
+    """
+    Adapt `stream` to `asyncio`'s streams API.
+
+    Returns a `(reader, writer)` pair, like `asyncio.open_connection()`.
+    """
+    reader = asyncio.StreamReader()
+    pump = asyncio.ensure_future(_pump(stream, reader))
+    return reader, StreamWriter(stream, pump)
+
+
+async def open_connection(
+    client: TorClient, host: str, port: int
+) -> tuple[asyncio.StreamReader, StreamWriter]:
+    """
+    Open an anonymized connection to `host` and `port` with `client`.
+
+    Returns a `(reader, writer)` pair, like `asyncio.open_connection()`.
+    """
+    stream = await client.connect(host, port)
+    return wrap_stream(stream)